@@ -0,0 +1,118 @@
+//! Compares the writes a proxy makes for a small-frame workload with and without write
+//! coalescing.
+//!
+//! Run with `cargo bench --bench coalesce`. A client writes small frames through a proxy,
+//! pausing briefly between them, to a sink that counts the reads it takes to receive
+//! them. Over loopback, each of the proxy's writes is generally received by its own read,
+//! so the sink's reads approximate the proxy's write syscalls and packets.
+
+extern crate futures;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_timer;
+
+use futures::sync::oneshot;
+use linkerd_tcp::WeightedAddr;
+use linkerd_tcp::app::{self, App, AppBuilder, Interpreter, RouterBuilder, ServerConfig,
+                       WriteCoalescingConfig};
+use std::collections::HashMap;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Core;
+
+const DEFAULT_FRAMES: usize = 5_000;
+const FRAME_BYTES: usize = 20;
+const FRAME_INTERVAL_MICROS: u32 = 20;
+
+fn main() {
+    // `cargo bench` passes `--bench`; a numeric argument overrides the frame count.
+    let frames = env::args()
+        .skip(1)
+        .filter_map(|a| a.parse().ok())
+        .next()
+        .unwrap_or(DEFAULT_FRAMES);
+
+    let coalesced = WriteCoalescingConfig {
+        max_delay_micros: Some(200),
+        max_bytes: Some(16 * 1024),
+    };
+    for &(label, ref coalescing) in &[("uncoalesced", None), ("coalesced", Some(coalesced))] {
+        let (reads, elapsed) = run(frames, coalescing.clone());
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        println!(
+            "{}: {} frames of {}B in {} reads ({:.1} frames/read) in {:.3}s",
+            label,
+            frames,
+            FRAME_BYTES,
+            reads,
+            frames as f64 / reads as f64,
+            secs
+        );
+    }
+}
+
+/// Proxies `frames` frames to a sink, returning the number of reads the sink took to
+/// receive them and the time taken to send them.
+fn run(frames: usize, coalescing: Option<WriteCoalescingConfig>) -> (usize, Duration) {
+    let sink = TcpListener::bind("127.0.0.1:0").expect("failed to bind sink");
+    let sink_addr = sink.local_addr().unwrap();
+    let (received_tx, received_rx) = oneshot::channel();
+    thread::spawn(move || {
+        let (mut conn, _) = sink.accept().expect("failed to accept");
+        let mut buf = [0u8; 64 * 1024];
+        let (mut reads, mut received) = (0, 0);
+        while received < frames * FRAME_BYTES {
+            match conn.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(sz) => {
+                    reads += 1;
+                    received += sz;
+                }
+            }
+        }
+        let _ = received_tx.send(reads);
+    });
+
+    let mut core = Core::new().expect("failed to initialize reactor");
+    let handle = core.handle();
+    let timer = tokio_timer::Timer::default();
+
+    let mut names = HashMap::new();
+    names.insert("/svc/sink".to_owned(), vec![WeightedAddr::new(sink_addr, 1.0)]);
+    let server = ServerConfig {
+        dst_name: Some("/svc/sink".to_owned()),
+        write_coalescing: coalescing,
+        ..ServerConfig::default()
+    };
+    let App { mut routers, admin, .. } = AppBuilder::new()
+        .admin_addr("127.0.0.1:0".parse().unwrap())
+        .router(RouterBuilder::new("bench", Interpreter::Static(names)).server(server))
+        .build()
+        .expect("failed to build app");
+    let (closer, _closed) = app::closer();
+    admin.spawn(closer, &handle, &timer).expect("failed to spawn admin");
+    let router = routers.pop_front().expect("no router");
+    let proxy_addr = router.spawn(&handle, &timer).expect("failed to spawn router")[0];
+
+    let client = thread::spawn(move || {
+        let mut conn = TcpStream::connect(proxy_addr).expect("failed to connect");
+        conn.set_nodelay(true).unwrap();
+        let frame = [7u8; FRAME_BYTES];
+        let t0 = Instant::now();
+        for _ in 0..frames {
+            conn.write_all(&frame).expect("failed to write");
+            thread::sleep(Duration::new(0, FRAME_INTERVAL_MICROS * 1_000));
+        }
+        let elapsed = t0.elapsed();
+        let _ = conn.shutdown(Shutdown::Write);
+        (conn, elapsed)
+    });
+
+    // The proxy runs until the sink has received every frame.
+    let reads = core.run(received_rx).expect("sink failed");
+    let (_conn, elapsed) = client.join().expect("client failed");
+    (reads, elapsed)
+}
@@ -0,0 +1,63 @@
+//! Measures how quickly a balancer establishes connections to a local listener.
+//!
+//! Run with `cargo bench --bench connect`. Each connection is dropped as soon as it is
+//! dispatched, so the measurement is dominated by the connect path: dispatching,
+//! connecting, and recording endpoint state.
+
+extern crate futures;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_timer;
+
+use futures::{Future, Stream};
+use futures::unsync::mpsc;
+use linkerd_tcp::WeightedAddr;
+use linkerd_tcp::lb::{self, Scope};
+use std::env;
+use std::time::Instant;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+const DEFAULT_CONNECTS: usize = 10_000;
+const WARMUP_CONNECTS: usize = 100;
+
+fn main() {
+    // `cargo bench` passes `--bench`; a numeric argument overrides the connect count.
+    let connects = env::args()
+        .skip(1)
+        .filter_map(|a| a.parse().ok())
+        .next()
+        .unwrap_or(DEFAULT_CONNECTS);
+
+    let mut core = Core::new().expect("failed to initialize reactor");
+    let handle = core.handle();
+    let timer = tokio_timer::Timer::default();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle)
+        .expect("failed to bind listener");
+    let addr = listener.local_addr().unwrap();
+    handle.spawn(listener.incoming().for_each(|_| Ok(())).map_err(|_| {}));
+
+    // The sender is held so that the balancer's endpoints are never cleared.
+    let (addrs_tx, addrs_rx) = mpsc::unbounded();
+    addrs_tx.unbounded_send(vec![WeightedAddr::new(addr, 1.0)]).unwrap();
+    let balancer = lb::new(&handle, &timer, "/svc/bench", addrs_rx, &Scope::noop());
+
+    for _ in 0..WARMUP_CONNECTS {
+        core.run(balancer.connect()).expect("failed to connect");
+    }
+
+    let t0 = Instant::now();
+    for _ in 0..connects {
+        core.run(balancer.connect()).expect("failed to connect");
+    }
+    let elapsed = t0.elapsed();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!(
+        "{} connects in {:.3}s: {:.0} connects/sec, {:.1}us/connect",
+        connects,
+        secs,
+        connects as f64 / secs,
+        secs * 1e6 / connects as f64
+    );
+}
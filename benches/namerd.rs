@@ -0,0 +1,186 @@
+//! Measures how long a proxy's reactor stalls while it processes large namerd responses.
+//!
+//! Run with `cargo bench --bench namerd`. A fake namerd, on its own thread, resolves a
+//! destination to 10,000 addresses, and the proxy polls it every 100ms. Meanwhile, a
+//! probe task measures the time between consecutive turns of the proxy's reactor.
+
+extern crate futures;
+extern crate hyper;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_timer;
+
+use futures::{Async, Future, Poll, future, task};
+use hyper::header::ContentLength;
+use hyper::server::{Http, Request, Response, Service};
+use linkerd_tcp::app::{self, App, AppConfig};
+use std::env;
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Core;
+
+const DEFAULT_ADDRS: usize = 10_000;
+const RUN_SECS: u64 = 5;
+
+static CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: bench
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 100ms
+    servers:
+      - port: 0
+        dstName: /svc/bench
+        connectTimeoutMs: 100
+";
+
+fn main() {
+    // `cargo bench` passes `--bench`; a numeric argument overrides the address count.
+    let n_addrs = env::args()
+        .skip(1)
+        .filter_map(|a| a.parse().ok())
+        .next()
+        .unwrap_or(DEFAULT_ADDRS);
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let namerd = spawn_namerd(bound_json(n_addrs), requests.clone());
+
+    let mut core = Core::new().expect("failed to initialize reactor");
+    let handle = core.handle();
+    let timer = tokio_timer::Timer::default();
+
+    let config = CONFIG.replace("{namerd}", &format!("http://{}", namerd));
+    let config: AppConfig = config.parse().expect("failed to parse configuration");
+    let App { mut routers, admin, .. } = config.into_app().expect("failed to load configuration");
+    let mut addrs = Vec::new();
+    while let Some(r) = routers.pop_front() {
+        addrs.extend(r.spawn(&handle, &timer).expect("failed to spawn router"));
+    }
+    let (closer, _closed) = app::closer();
+    let _metrics = admin.spawn(closer, &handle, &timer).expect(
+        "failed to spawn admin",
+    );
+
+    // Connecting causes the destination to be resolved. None of its addresses accept
+    // connections, so the connection is not otherwise used.
+    let _conn = core.run(TcpStream::connect(&addrs[0], &handle)).expect(
+        "failed to connect",
+    );
+
+    let mut gaps = core.run(Probe::new(Duration::from_secs(RUN_SECS))).unwrap();
+    gaps.sort();
+    let micros = |d: Duration| d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1_000);
+    let pct = |p: f64| micros(gaps[((gaps.len() - 1) as f64 * p) as usize]);
+    println!(
+        "{} resolutions of {} addresses in {}s",
+        requests.load(Ordering::SeqCst),
+        n_addrs,
+        RUN_SECS
+    );
+    println!(
+        "reactor turns: {}, p50 {}us, p99 {}us, p99.9 {}us, max {}us",
+        gaps.len(),
+        pct(0.5),
+        pct(0.99),
+        pct(0.999),
+        micros(gaps[gaps.len() - 1])
+    );
+}
+
+/// Completes after `duration`, recording the time between each of its polls.
+///
+/// The probe notifies itself whenever it is polled, so that it is polled on every turn
+/// of the reactor. Long gaps between polls indicate that other work stalled the
+/// reactor.
+struct Probe {
+    until: Instant,
+    last: Option<Instant>,
+    gaps: Vec<Duration>,
+}
+
+impl Probe {
+    fn new(duration: Duration) -> Probe {
+        Probe {
+            until: Instant::now() + duration,
+            last: None,
+            gaps: Vec::new(),
+        }
+    }
+}
+
+impl Future for Probe {
+    type Item = Vec<Duration>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Vec<Duration>, ()> {
+        let now = Instant::now();
+        if let Some(last) = self.last {
+            self.gaps.push(now - last);
+        }
+        if now >= self.until {
+            return Ok(Async::Ready(mem::replace(&mut self.gaps, Vec::new())));
+        }
+        self.last = Some(now);
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
+
+/// Serves `body` for every request on a new thread, returning namerd's address.
+fn spawn_namerd(body: String, requests: Arc<AtomicUsize>) -> SocketAddr {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let body = Rc::new(body);
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = Http::<hyper::Chunk>::new()
+            .bind(&addr, move || Ok(Bound(body.clone(), requests.clone())))
+            .expect("failed to bind namerd");
+        tx.send(server.local_addr().unwrap()).unwrap();
+        server.run().expect("namerd failed");
+    });
+    rx.recv().expect("namerd failed to start")
+}
+
+struct Bound(Rc<String>, Arc<AtomicUsize>);
+
+impl Service for Bound {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, _req: Request) -> Self::Future {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        let rsp = Response::new()
+            .with_header(ContentLength(self.0.len() as u64))
+            .with_body((*self.0).clone());
+        future::ok(rsp)
+    }
+}
+
+/// A bound namerd response with `n` distinct addresses on which nothing listens.
+fn bound_json(n: usize) -> String {
+    let addrs: Vec<String> = (0..n)
+        .map(|i| {
+            format!(
+                r#"{{"ip":"127.1.{}.{}","port":9,"meta":{{"endpoint_addr_weight":1.0}}}}"#,
+                i / 250,
+                i % 250 + 1
+            )
+        })
+        .collect();
+    format!(
+        r#"{{"type":"bound","addrs":[{}],"meta":{{}}}}"#,
+        addrs.join(",")
+    )
+}
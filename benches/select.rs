@@ -0,0 +1,42 @@
+//! Measures how quickly a balancer selects endpoints as its endpoint set grows.
+//!
+//! Run with `cargo bench --bench select`. Selection is measured in isolation, without
+//! connecting, for each selection strategy over 10, 100, 1000, and 10000 endpoints.
+
+extern crate linkerd_tcp;
+
+use linkerd_tcp::lb::{SelectionStrategy, time_selections};
+use std::env;
+
+const DEFAULT_SELECTIONS: usize = 10_000;
+const WARMUP_SELECTIONS: usize = 100;
+const ENDPOINTS: [usize; 4] = [10, 100, 1_000, 10_000];
+const STRATEGIES: [SelectionStrategy; 3] = [
+    SelectionStrategy::LeastLoaded,
+    SelectionStrategy::Ewma,
+    SelectionStrategy::Locality,
+];
+
+fn main() {
+    // `cargo bench` passes `--bench`; a numeric argument overrides the selection count.
+    let selections = env::args()
+        .skip(1)
+        .filter_map(|a| a.parse().ok())
+        .next()
+        .unwrap_or(DEFAULT_SELECTIONS);
+
+    for strategy in &STRATEGIES {
+        for &endpoints in &ENDPOINTS {
+            time_selections(*strategy, endpoints, WARMUP_SELECTIONS);
+            let elapsed = time_selections(*strategy, endpoints, selections);
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            println!(
+                "{:?} over {} endpoints: {:.0} selections/sec, {:.2}us/selection",
+                strategy,
+                endpoints,
+                selections as f64 / secs,
+                secs * 1e6 / selections as f64
+            );
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Runs a balancer that reports its metrics into a `HashMap` rather than tacho.
+//!
+//! A local listener stands in for a destination's endpoint. The balancer connects to it a
+//! few times and then the collected metrics are printed.
+
+extern crate futures;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_timer;
+
+use futures::{Future, Stream};
+use futures::unsync::mpsc;
+use linkerd_tcp::lb::{self, Counter, Gauge, Key, Metrics, Scope, TimeUnit, Timer};
+use linkerd_tcp::WeightedAddr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+/// Collects the most recent value of each metric.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<HashMap<String, u64>>>);
+
+impl Collector {
+    fn entry(&self, key: &Key) -> Entry {
+        let labels = key.labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v))
+            .collect::<Vec<_>>();
+        Entry {
+            name: format!("{}{{{}}}", key.full_name(), labels.join(",")),
+            values: self.0.clone(),
+        }
+    }
+}
+
+impl Metrics for Collector {
+    fn counter(&self, key: &Key) -> Arc<Counter> {
+        Arc::new(Count(self.entry(key)))
+    }
+
+    fn gauge(&self, key: &Key) -> Arc<Gauge> {
+        Arc::new(Level(self.entry(key)))
+    }
+
+    fn timer(&self, key: &Key, unit: TimeUnit) -> Arc<Timer> {
+        Arc::new(Latency(self.entry(key), unit))
+    }
+}
+
+struct Entry {
+    name: String,
+    values: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Entry {
+    fn update<F: FnOnce(&mut u64)>(&self, f: F) {
+        let mut values = self.values.lock().expect("metrics lock poisoned");
+        f(values.entry(self.name.clone()).or_insert(0));
+    }
+}
+
+struct Count(Entry);
+impl Counter for Count {
+    fn incr(&self, n: usize) {
+        self.0.update(|v| *v += n as u64);
+    }
+}
+
+struct Level(Entry);
+impl Gauge for Level {
+    fn set(&self, n: usize) {
+        self.0.update(|v| *v = n as u64);
+    }
+
+    fn incr(&self, n: usize) {
+        self.0.update(|v| *v += n as u64);
+    }
+
+    fn decr(&self, n: usize) {
+        self.0.update(|v| *v = v.saturating_sub(n as u64));
+    }
+}
+
+/// Records the most recent duration.
+struct Latency(Entry, TimeUnit);
+impl Timer for Latency {
+    fn record_since(&self, t0: Instant) {
+        let d = t0.elapsed();
+        let micros = d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1_000) as u64;
+        let n = match self.1 {
+            TimeUnit::Micros => micros,
+            TimeUnit::Millis => micros / 1_000,
+        };
+        self.0.update(|v| *v = n);
+    }
+}
+
+fn main() {
+    let mut core = Core::new().expect("failed to initialize reactor");
+    let handle = core.handle();
+    let timer = tokio_timer::Timer::default();
+
+    // Accept connections and close them immediately.
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle)
+        .expect("failed to bind listener");
+    let addr = listener.local_addr().unwrap();
+    handle.spawn(listener.incoming().for_each(|_| Ok(())).map_err(|_| {}));
+
+    // The sender is held so that the balancer's endpoints are never cleared.
+    let (addrs_tx, addrs_rx) = mpsc::unbounded();
+    addrs_tx.unbounded_send(vec![WeightedAddr::new(addr, 1.0)]).unwrap();
+
+    let collector = Collector::default();
+    let metrics = Scope::new(collector.clone()).prefixed("balancer");
+    let balancer = lb::new(&handle, &timer, "/svc/echo", addrs_rx, &metrics);
+
+    for _ in 0..3 {
+        let conn = core.run(balancer.connect()).expect("failed to connect");
+        println!("connected to {}", conn.peer_addr());
+    }
+
+    // Give the balancer a chance to update its gauges.
+    core.run(timer.sleep(Duration::from_millis(100))).unwrap();
+
+    let values = collector.0.lock().unwrap();
+    let mut names = values.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        println!("{} {}", name, values[name]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Assembles a proxy in code, without a configuration file.
+//!
+//! A local echo server stands in for a destination's endpoint, and a static interpreter
+//! resolves `/svc/echo` to it. A message is then proxied through a single server, and a
+//! connection hook prints a summary of the connection once it closes.
+
+extern crate futures;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_timer;
+
+use futures::{Future, Stream};
+use linkerd_tcp::WeightedAddr;
+use linkerd_tcp::app::{self, App, AppBuilder, Interpreter, RouterBuilder, ServerConfig};
+use linkerd_tcp::lb::{ConnectionHook, ConnectionSummary};
+use std::collections::HashMap;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_io::AsyncRead;
+use tokio_io::io as aio;
+
+/// Prints each connection as it closes.
+struct PrintCloses;
+
+impl ConnectionHook for PrintCloses {
+    fn on_close(&self, summary: &ConnectionSummary) {
+        println!(
+            "closed {} to {:?}: {}B in, {}B out",
+            summary.client_addr,
+            summary.endpoint_addr,
+            summary.rx_bytes,
+            summary.tx_bytes
+        );
+    }
+}
+
+fn main() {
+    let mut core = Core::new().expect("failed to initialize reactor");
+    let handle = core.handle();
+    let timer = tokio_timer::Timer::default();
+
+    // Echo each connection's bytes back to it.
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle)
+        .expect("failed to bind echo server");
+    let echo_addr = listener.local_addr().unwrap();
+    let echo = {
+        let handle = handle.clone();
+        listener.incoming().for_each(move |(tcp, _)| {
+            let (rx, tx) = tcp.split();
+            handle.spawn(aio::copy(rx, tx).map(|_| {}).map_err(|_| {}));
+            Ok(())
+        })
+    };
+    handle.spawn(echo.map_err(|_| {}));
+
+    let mut names = HashMap::new();
+    names.insert("/svc/echo".to_owned(), vec![WeightedAddr::new(echo_addr, 1.0)]);
+    let server = ServerConfig {
+        dst_name: Some("/svc/echo".to_owned()),
+        ..ServerConfig::default()
+    };
+    let App { mut routers, admin, .. } = AppBuilder::new()
+        .admin_addr("127.0.0.1:0".parse().unwrap())
+        .router(RouterBuilder::new("embedded", Interpreter::Static(names)).server(server))
+        .connection_hook(PrintCloses)
+        .build()
+        .expect("failed to build app");
+
+    // The admin server also executes the resolver, so it runs on the same reactor here.
+    let (closer, _closed) = app::closer();
+    admin.spawn(closer, &handle, &timer).expect("failed to spawn admin");
+    let router = routers.pop_front().expect("no router");
+    let addrs = router.spawn(&handle, &timer).expect("failed to spawn router");
+    println!("proxying on {} to {}", addrs[0], echo_addr);
+
+    let msg = b"hello";
+    let roundtrip = TcpStream::connect(&addrs[0], &handle)
+        .and_then(|conn| aio::write_all(conn, msg.to_vec()))
+        .and_then(|(conn, _)| aio::read_exact(conn, vec![0u8; msg.len()]));
+    let (_, rsp) = core.run(roundtrip).expect("failed to proxy");
+    println!("received {:?}", String::from_utf8_lossy(&rsp));
+}
@@ -0,0 +1,406 @@
+//! Handles the errors a listener encounters as it accepts connections, according to
+//! their cause, so that a failing listener neither floods the log nor spins.
+//!
+//! - A connection that was aborted by its client before it was accepted
+//!   (`ECONNABORTED`) is harmless: it is counted, and accepting continues immediately.
+//! - When the process or system has run out of file descriptors (`EMFILE`, `ENFILE`),
+//!   accepting is paused, with a backoff that doubles while the exhaustion persists.
+//!   The exhaustion is logged once, as an error, when it begins, and `fd_exhaustion` is
+//!   set until a connection is accepted again.
+//! - When the kernel is briefly out of buffers or memory (`ENOBUFS`, `ENOMEM`),
+//!   accepting is paused briefly.
+//! - Other errors are logged, and accepting is paused briefly.
+//!
+//! Errors are counted as `accept_errors`, labeled by `cause` (`aborted`,
+//! `fd_exhaustion`, `transient`, or `other`). Paused listeners remain registered with the
+//! reactor; clients wait in the listen backlog until accepting resumes.
+
+use futures::{Async, Future, Poll, Stream};
+use std::{cmp, io, net};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use tacho;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_timer::{Sleep, Timer};
+
+const DEFAULT_EXHAUSTION_BACKOFF_MS: u64 = 100;
+const DEFAULT_MAX_EXHAUSTION_BACKOFF_SECS: u64 = 5;
+const DEFAULT_TRANSIENT_DELAY_MS: u64 = 10;
+
+/// A source of accepted connections, e.g. a listener.
+pub trait Accept {
+    /// An accepted connection.
+    type Item;
+
+    /// Accepts a connection, if one is ready. When none is, the current task is notified
+    /// once one may be.
+    fn accept(&mut self) -> Poll<Self::Item, io::Error>;
+}
+
+impl Accept for TcpListener {
+    type Item = (TcpStream, net::SocketAddr);
+
+    fn accept(&mut self) -> Poll<Self::Item, io::Error> {
+        match TcpListener::accept(self) {
+            Ok(accepted) => Ok(Async::Ready(accepted)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A listener that is shared, so that it outlives a listener task that is rebuilt.
+impl<A: Accept> Accept for Rc<RefCell<A>> {
+    type Item = A::Item;
+
+    fn accept(&mut self) -> Poll<Self::Item, io::Error> {
+        self.borrow_mut().accept()
+    }
+}
+
+/// The cause of an accept error, which determines how it is handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cause {
+    /// The client aborted the connection before it was accepted.
+    Aborted,
+    /// The process or system has no file descriptors left.
+    FdExhaustion,
+    /// The kernel is briefly out of buffers or memory.
+    Transient,
+    /// Any other error.
+    Other,
+}
+
+impl Cause {
+    /// Classifies an accept error.
+    pub fn of(e: &io::Error) -> Cause {
+        if let Some(errno) = e.raw_os_error() {
+            if let Some(cause) = errno_cause(errno) {
+                return cause;
+            }
+        }
+        match e.kind() {
+            io::ErrorKind::ConnectionAborted => Cause::Aborted,
+            _ => Cause::Other,
+        }
+    }
+
+    /// A bounded name for the cause, suitable as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Cause::Aborted => "aborted",
+            Cause::FdExhaustion => "fd_exhaustion",
+            Cause::Transient => "transient",
+            Cause::Other => "other",
+        }
+    }
+}
+
+#[cfg(unix)]
+fn errno_cause(errno: i32) -> Option<Cause> {
+    use libc;
+    match errno {
+        libc::ECONNABORTED => Some(Cause::Aborted),
+        libc::EMFILE | libc::ENFILE => Some(Cause::FdExhaustion),
+        libc::ENOBUFS | libc::ENOMEM => Some(Cause::Transient),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn errno_cause(_errno: i32) -> Option<Cause> {
+    None
+}
+
+/// How long accepting is paused after each kind of error.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    /// The first pause once file descriptors are exhausted. Each consecutive exhaustion
+    /// doubles the pause.
+    pub exhaustion_backoff: Duration,
+    /// Bounds the pause while file descriptors remain exhausted.
+    pub max_exhaustion_backoff: Duration,
+    /// The pause after a transient (or unknown) error.
+    pub transient_delay: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            exhaustion_backoff: Duration::from_millis(DEFAULT_EXHAUSTION_BACKOFF_MS),
+            max_exhaustion_backoff: Duration::from_secs(DEFAULT_MAX_EXHAUSTION_BACKOFF_SECS),
+            transient_delay: Duration::from_millis(DEFAULT_TRANSIENT_DELAY_MS),
+        }
+    }
+}
+
+/// Accepts connections from `source`, handling its errors according to `policy`.
+/// Errors are recorded in `metrics`, which should describe the listener.
+pub fn accepting<A: Accept>(
+    source: A,
+    policy: Policy,
+    timer: &Timer,
+    metrics: &tacho::Scope,
+) -> Accepting<A> {
+    let errors = |cause: Cause| {
+        metrics.clone().labeled("cause", cause.as_str()).counter("accept_errors")
+    };
+    Accepting {
+        source,
+        policy,
+        timer: timer.clone(),
+        paused: None,
+        exhaustions: 0,
+        metrics: Metrics {
+            aborted: errors(Cause::Aborted),
+            fd_exhaustion: errors(Cause::FdExhaustion),
+            transient: errors(Cause::Transient),
+            other: errors(Cause::Other),
+            exhausted: metrics.gauge("fd_exhaustion"),
+        },
+    }
+}
+
+/// A stream of accepted connections, which never fails.
+pub struct Accepting<A> {
+    source: A,
+    policy: Policy,
+    timer: Timer,
+    /// Set while accepting is paused.
+    paused: Option<Sleep>,
+    /// The consecutive errors since file descriptors were exhausted, if they are.
+    exhaustions: u32,
+    metrics: Metrics,
+}
+
+struct Metrics {
+    aborted: tacho::Counter,
+    fd_exhaustion: tacho::Counter,
+    transient: tacho::Counter,
+    other: tacho::Counter,
+    exhausted: tacho::Gauge,
+}
+
+impl<A> Accepting<A> {
+    /// The pause after the `exhaustions`th consecutive exhaustion.
+    fn exhaustion_backoff(&self) -> Duration {
+        let exp = cmp::min(self.exhaustions.saturating_sub(1), 16);
+        cmp::min(
+            self.policy.exhaustion_backoff * (1u32 << exp),
+            self.policy.max_exhaustion_backoff,
+        )
+    }
+
+    /// Records an accept error, returning how long accepting should be paused.
+    fn failed(&mut self, e: &io::Error) -> Option<Duration> {
+        match Cause::of(e) {
+            Cause::Aborted => {
+                trace!("connection aborted before it was accepted: {}", e);
+                self.metrics.aborted.incr(1);
+                None
+            }
+            Cause::FdExhaustion => {
+                self.metrics.fd_exhaustion.incr(1);
+                self.exhaustions += 1;
+                let backoff = self.exhaustion_backoff();
+                if self.exhaustions == 1 {
+                    error!("file descriptors exhausted; pausing accepts: {}", e);
+                    self.metrics.exhausted.set(1);
+                } else {
+                    debug!("file descriptors still exhausted; pausing for {:?}", backoff);
+                }
+                Some(backoff)
+            }
+            Cause::Transient => {
+                debug!("transient accept error: {}", e);
+                self.metrics.transient.incr(1);
+                Some(self.policy.transient_delay)
+            }
+            Cause::Other => {
+                warn!("failed to accept a connection: {}", e);
+                self.metrics.other.incr(1);
+                Some(self.policy.transient_delay)
+            }
+        }
+    }
+}
+
+impl<A: Accept> Stream for Accepting<A> {
+    type Item = A::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<A::Item>, io::Error> {
+        loop {
+            if let Some(mut paused) = self.paused.take() {
+                // If the timer fails, accepting resumes immediately.
+                if let Ok(Async::NotReady) = paused.poll() {
+                    self.paused = Some(paused);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            let e = match self.source.accept() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(accepted)) => {
+                    if self.exhaustions > 0 {
+                        info!("file descriptors available; accepting connections");
+                        self.exhaustions = 0;
+                        self.metrics.exhausted.set(0);
+                    }
+                    return Ok(Async::Ready(Some(accepted)));
+                }
+                Err(e) => e,
+            };
+            if let Some(pause) = self.failed(&e) {
+                self.paused = Some(self.timer.sleep(pause));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accept, Cause, Policy, accepting};
+    use futures::{Async, Future, Poll, Stream};
+    use futures::future::Either;
+    use libc;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::time::{Duration, Instant};
+    use tacho;
+    use tokio_core::reactor::Core;
+    use tokio_timer::{self, Timer};
+
+    /// Accepts a scripted sequence of connections (numbered) and errors, and then nothing.
+    struct Script(VecDeque<Result<u32, i32>>);
+
+    impl Accept for Script {
+        type Item = u32;
+        fn accept(&mut self) -> Poll<u32, io::Error> {
+            match self.0.pop_front() {
+                None => Ok(Async::NotReady),
+                Some(Ok(n)) => Ok(Async::Ready(n)),
+                Some(Err(errno)) => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            exhaustion_backoff: Duration::from_millis(20),
+            max_exhaustion_backoff: Duration::from_millis(50),
+            transient_delay: Duration::from_millis(20),
+        }
+    }
+
+    fn timer() -> Timer {
+        tokio_timer::wheel().tick_duration(Duration::from_millis(1)).build()
+    }
+
+    fn report(reporter: &mut tacho::Reporter) -> String {
+        let mut prometheus = String::new();
+        tacho::prometheus::write(&mut prometheus, &reporter.take()).unwrap();
+        prometheus
+    }
+
+    /// Accepts `n` connections from `script`, returning them, how long that took, and the
+    /// listener's metrics.
+    fn accept_n(script: Vec<Result<u32, i32>>, n: usize) -> (Vec<u32>, Duration, String) {
+        let mut core = Core::new().unwrap();
+        let timer = timer();
+        let (metrics, mut reporter) = tacho::new();
+        let script = Script(script.into_iter().collect());
+        let accepting = accepting(script, policy(), &timer, &metrics);
+        let start = Instant::now();
+        let accepted = core.run(accepting.take(n as u64).collect()).expect("accept failed");
+        let elapsed = start.elapsed();
+        (accepted, elapsed, report(&mut reporter))
+    }
+
+    /// Finds the value of the metric described by `prefix`, e.g.
+    /// `accept_errors{cause="other"}`.
+    fn metric(prometheus: &str, prefix: &str) -> Option<u64> {
+        prometheus
+            .lines()
+            .find(|l| l.starts_with(prefix))
+            .and_then(|l| l.rsplit(' ').next())
+            .and_then(|v| v.parse().ok())
+    }
+
+    fn errors(prometheus: &str, cause: &str) -> u64 {
+        metric(prometheus, &format!("accept_errors{{cause=\"{}\"}}", cause)).unwrap_or(0)
+    }
+
+    #[test]
+    fn classifies_accept_errors() {
+        let cause = |errno| Cause::of(&io::Error::from_raw_os_error(errno));
+        assert_eq!(cause(libc::ECONNABORTED), Cause::Aborted);
+        assert_eq!(cause(libc::EMFILE), Cause::FdExhaustion);
+        assert_eq!(cause(libc::ENFILE), Cause::FdExhaustion);
+        assert_eq!(cause(libc::ENOBUFS), Cause::Transient);
+        assert_eq!(cause(libc::ENOMEM), Cause::Transient);
+        assert_eq!(cause(libc::EPERM), Cause::Other);
+        let aborted = io::Error::new(io::ErrorKind::ConnectionAborted, "aborted");
+        assert_eq!(Cause::of(&aborted), Cause::Aborted);
+    }
+
+    #[test]
+    fn ignores_aborted_connections() {
+        let script = vec![Err(libc::ECONNABORTED), Ok(1), Err(libc::ECONNABORTED), Ok(2)];
+        let (accepted, elapsed, metrics) = accept_n(script, 2);
+        assert_eq!(accepted, vec![1, 2]);
+        assert!(elapsed < Duration::from_millis(20), "paused for {:?}", elapsed);
+        assert_eq!(errors(&metrics, "aborted"), 2);
+        assert_eq!(errors(&metrics, "fd_exhaustion"), 0);
+    }
+
+    #[test]
+    fn backs_off_while_file_descriptors_are_exhausted() {
+        let script = vec![
+            Ok(1),
+            Err(libc::EMFILE),
+            Err(libc::EMFILE),
+            Err(libc::ENFILE),
+            Err(libc::EMFILE),
+            Ok(2),
+        ];
+        let (accepted, elapsed, metrics) = accept_n(script, 2);
+        assert_eq!(accepted, vec![1, 2]);
+        // Pauses of 20ms, 40ms, and 50ms (twice).
+        assert!(elapsed >= Duration::from_millis(160), "paused for {:?}", elapsed);
+        assert_eq!(errors(&metrics, "fd_exhaustion"), 4);
+        // Exhaustion ends once a connection is accepted.
+        assert_eq!(metric(&metrics, "fd_exhaustion"), Some(0));
+    }
+
+    #[test]
+    fn reports_file_descriptor_exhaustion_while_paused() {
+        let mut core = Core::new().unwrap();
+        let timer = timer();
+        let (metrics, mut reporter) = tacho::new();
+        let script = Script(vec![Err(libc::EMFILE), Err(libc::EMFILE)].into_iter().collect());
+        let accepting = accepting(script, policy(), &timer, &metrics);
+
+        // Nothing is accepted; the listener is still paused when the wait ends.
+        let wait = timer.sleep(Duration::from_millis(40));
+        match core.run(accepting.into_future().select2(wait)) {
+            Ok(Either::B(_)) => {}
+            _ => panic!("accepted a connection"),
+        }
+        let metrics = report(&mut reporter);
+        assert_eq!(metric(&metrics, "fd_exhaustion"), Some(1));
+        assert_eq!(errors(&metrics, "fd_exhaustion"), 2);
+    }
+
+    #[test]
+    fn pauses_briefly_after_transient_errors() {
+        let script = vec![Err(libc::ENOBUFS), Err(libc::ENOMEM), Err(libc::EPERM), Ok(1)];
+        let (accepted, elapsed, metrics) = accept_n(script, 1);
+        assert_eq!(accepted, vec![1]);
+        assert!(elapsed >= Duration::from_millis(60), "paused for {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "paused for {:?}", elapsed);
+        assert_eq!(errors(&metrics, "transient"), 2);
+        assert_eq!(errors(&metrics, "other"), 1);
+    }
+}
@@ -0,0 +1,256 @@
+//! The versioned responses of the admin server's JSON endpoints, `/state` and
+//! `/admin/summary`, so that tools may depend on their shapes.
+//!
+//! Each response carries its `apiVersion`. Clients may pin a version with a `v` query
+//! parameter (e.g. `/state?v=1`) or by accepting its media type
+//! (`application/vnd.linkerd-tcp.v1+json`); otherwise, the latest version is served.
+//! Requests for versions that are not supported are refused with `406 Not Acceptable`.
+//!
+//! Fields may be added to a version, so clients should ignore fields they do not know.
+//! Removing or renaming a field requires a new version: the prior version's types are
+//! kept, and responses requested at that version are translated from the latest types.
+//!
+//! These types are serialized by the admin server and may be deserialized by clients;
+//! they are independent of the proxy's internal state, which is translated into them.
+
+use serde::Serialize;
+use serde_json;
+use std::{error, fmt, net};
+use std::collections::BTreeMap;
+use url::form_urlencoded;
+
+/// The latest version of the admin API.
+pub const VERSION: u32 = 1;
+
+/// The versions that may be requested.
+pub const SUPPORTED_VERSIONS: &'static [u32] = &[1];
+
+const MEDIA_TYPE_PREFIX: &'static str = "application/vnd.linkerd-tcp.v";
+const MEDIA_TYPE_SUFFIX: &'static str = "+json";
+
+/// The media type of responses at `version`, e.g. `application/vnd.linkerd-tcp.v1+json`.
+pub fn media_type(version: u32) -> String {
+    format!("{}{}{}", MEDIA_TYPE_PREFIX, version, MEDIA_TYPE_SUFFIX)
+}
+
+/// Determines the version of a response from a request's query string and `Accept`
+/// header.
+///
+/// A `v` query parameter takes precedence. Otherwise, the latest supported version
+/// among the versioned media types that are accepted is chosen. Requests that accept
+/// no versioned media type are served the latest version.
+pub fn negotiate(query: Option<&str>, accept: Option<&str>) -> Result<u32, Unsupported> {
+    let pinned = query.and_then(|q| {
+        form_urlencoded::parse(q.as_bytes())
+            .find(|&(ref k, _)| k == "v")
+            .map(|(_, v)| v.into_owned())
+    });
+    if let Some(v) = pinned {
+        return match v.parse::<u32>() {
+            Ok(v) if SUPPORTED_VERSIONS.contains(&v) => Ok(v),
+            _ => Err(Unsupported(v)),
+        };
+    }
+
+    let accept = match accept {
+        None => return Ok(VERSION),
+        Some(a) => a,
+    };
+    let mut requested = Vec::new();
+    for media in accept.split(',') {
+        let media = media.split(';').next().unwrap_or("").trim();
+        if media.starts_with(MEDIA_TYPE_PREFIX) && media.ends_with(MEDIA_TYPE_SUFFIX) {
+            let v = &media[MEDIA_TYPE_PREFIX.len()..media.len() - MEDIA_TYPE_SUFFIX.len()];
+            requested.push(v.to_owned());
+        }
+    }
+    if requested.is_empty() {
+        return Ok(VERSION);
+    }
+    requested
+        .iter()
+        .filter_map(|v| v.parse::<u32>().ok())
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+        .ok_or_else(|| Unsupported(requested.join(", ")))
+}
+
+/// Renders a response as JSON.
+pub fn to_json<T: Serialize>(rsp: &T) -> String {
+    serde_json::to_string_pretty(rsp).expect("failed to serialize admin response")
+}
+
+/// Indicates that a requested version is not supported.
+#[derive(Debug, PartialEq)]
+pub struct Unsupported(pub String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let supported: Vec<String> = SUPPORTED_VERSIONS.iter().map(|v| v.to_string()).collect();
+        write!(
+            f,
+            "unsupported API version {}; supported versions: {}",
+            self.0,
+            supported.join(", ")
+        )
+    }
+}
+
+impl error::Error for Unsupported {
+    fn description(&self) -> &str {
+        "unsupported API version"
+    }
+}
+
+/// The `/state` response: the most recent state published by each balancer, by router
+/// and destination.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    /// The version of this response.
+    pub api_version: u32,
+    /// Each router's balancers, by destination.
+    pub routers: BTreeMap<String, BTreeMap<String, Balancer>>,
+}
+
+/// A balancer's state.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Balancer {
+    /// The destination's circuit breaker state, when it has one: `closed`, `open`, or
+    /// `half-open`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<String>,
+    /// The destination's fallback state, when it has one: `standby`, `pending`, or
+    /// `active`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    /// The number of connections waiting for an endpoint.
+    pub waiters: u64,
+    /// The most recent failure to resolve the destination, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution_error: Option<ResolutionError>,
+    /// The overrides applied from the destination's remote policy, by namerd metadata
+    /// key (e.g. `loadBalancer`), when any are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_policy: Option<BTreeMap<String, String>>,
+    /// The destination's endpoints.
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// A failure to resolve a destination.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolutionError {
+    /// The category by which the failure was counted (e.g. `http_5xx`).
+    pub category: String,
+    /// Describes the failure.
+    pub error: String,
+    /// When the failure occurred, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+}
+
+/// An endpoint's state.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoint {
+    /// The endpoint's address.
+    pub addr: net::SocketAddr,
+    /// Whether the endpoint is `available`, `failed`, `retired`, `ejected`, a
+    /// `fallback`, or on `probation`.
+    pub status: String,
+    /// The weight given to the endpoint by service discovery.
+    pub weight: f64,
+    /// The factor applied to `weight` by an operator, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight_multiplier: Option<f64>,
+    /// The weight used to balance connections, after any override and while the
+    /// endpoint is warming up or on probation.
+    pub effective_weight: f64,
+    /// Connections being established.
+    pub pending_conns: u64,
+    /// Connections that are open.
+    pub open_conns: u64,
+    /// Connection attempts that have failed since the last success.
+    pub consecutive_failures: u64,
+    /// Bytes received from the endpoint.
+    pub rx_bytes: u64,
+    /// Bytes sent to the endpoint.
+    pub tx_bytes: u64,
+    /// The current reconnect delay, while the endpoint is backing off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_ms: Option<u64>,
+    /// Connection attempts within the connector's stats window.
+    pub connect_attempts: u64,
+    /// The fraction of those attempts that succeeded, if any were made.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_success_rate: Option<f64>,
+    /// The most recent failed connection attempt, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<EndpointFailure>,
+    /// The 95th percentile of the time from each connection's establishment to the first
+    /// byte sent by the endpoint, as the upper bound of a histogram bucket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte_p95_ms: Option<u64>,
+    /// Whether the endpoint is `healthy`, `failing` (i.e. has failed since its last
+    /// success), `failed`, or on `probation`.
+    pub failure_accrual: String,
+}
+
+/// A failed connection attempt.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointFailure {
+    /// Describes the failure.
+    pub error: String,
+    /// When the attempt failed, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+}
+
+/// The `/admin/summary` response: each router's golden signals.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    /// The version of this response.
+    pub api_version: u32,
+    /// The window over which rates are averaged.
+    pub window_secs: u64,
+    /// Each router's signals.
+    pub routers: BTreeMap<String, RouterSummary>,
+}
+
+/// A router's signals. Rates are averaged over the window.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouterSummary {
+    /// Connections that are open.
+    pub open_connections: u64,
+    /// Connections accepted per second.
+    pub connects_per_sec: f64,
+    /// The fraction of connections closed within the window that failed, if any closed.
+    pub failure_rate: Option<f64>,
+    /// The 95th percentile of the time to connect accepted connections to endpoints, as
+    /// the upper bound of a histogram bucket.
+    pub dispatch_p95_ms: Option<u64>,
+    /// The time since namerd last resolved any of the router's names, if it has.
+    pub namerd_staleness_secs: Option<u64>,
+    /// The router's endpoints, by failure accrual state.
+    pub endpoints: EndpointHealth,
+    /// Bytes received from clients per second.
+    pub rx_bytes_per_sec: f64,
+    /// Bytes sent to clients per second.
+    pub tx_bytes_per_sec: f64,
+}
+
+/// The number of a router's endpoints in each failure accrual state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EndpointHealth {
+    /// Endpoints that have not failed since their last success.
+    pub healthy: u64,
+    /// Endpoints that have failed since their last success.
+    pub failing: u64,
+    /// Endpoints that are not available.
+    pub failed: u64,
+    /// Endpoints that are on probation after recovering.
+    pub probation: u64,
+}
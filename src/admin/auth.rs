@@ -0,0 +1,375 @@
+//! Authorizes requests to the admin server.
+//!
+//! When `admin.auth` is configured, each request must present the bearer token named by
+//! `bearerTokenEnv` in its `Authorization` header, or must arrive over a TLS connection
+//! whose client presented a certificate issued by one of `tls.clientAuth.caCertPaths`.
+//! Requests without credentials are refused with 401, and requests with a wrong token
+//! with 403. `GET` requests (e.g. `/metrics` and `/ready`) may be exempted with
+//! `allowUnauthenticatedReads`.
+//!
+//! Refused requests are counted as `admin_auth_failures`, by `cause`. A client that
+//! presents `maxFailuresPerMinute` wrong tokens is refused with 429, without its
+//! credentials being checked, for the rest of the minute.
+
+use super::RspFuture;
+use futures::future;
+use hyper::{Get, Head, StatusCode};
+use hyper::header::ContentLength;
+use hyper::server::{Request, Response};
+use std::{env, fmt, net};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tacho;
+
+/// The number of wrong tokens a client may present each minute, by default.
+pub const DEFAULT_MAX_FAILURES_PER_MINUTE: u32 = 10;
+
+const FAILURE_WINDOW_SECS: u64 = 60;
+
+/// Bounds the clients whose failures are tracked, so that the table cannot grow without
+/// bound. Clients whose windows have expired are forgotten first.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// The authorization schemes that carry bearer tokens. Schemes are case-insensitive, but
+/// clients send one of these in practice.
+const BEARER: [&'static [u8]; 2] = [b"Bearer ", b"bearer "];
+
+/// Configures how requests to the admin server are authorized.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AdminAuthConfig {
+    /// The environment variable holding the token that requests must present as
+    /// `Authorization: Bearer <token>`. The token itself is never configured in a file.
+    pub bearer_token_env: Option<String>,
+
+    /// Serves the admin server over TLS, optionally authorizing clients by their
+    /// certificates.
+    pub tls: Option<AdminTlsConfig>,
+
+    /// When set, `GET` requests are served without credentials.
+    pub allow_unauthenticated_reads: Option<bool>,
+
+    /// The number of wrong tokens a client may present each minute before it is
+    /// refused outright (10 by default).
+    pub max_failures_per_minute: Option<u32>,
+}
+
+/// Configures the admin server's TLS handshakes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AdminTlsConfig {
+    /// Paths to the certificates of the admin server's chain, beginning with its own.
+    pub cert_paths: Vec<String>,
+
+    /// The path to the admin server's RSA private key.
+    pub key_path: String,
+
+    /// When set, clients that present a certificate issued by a trusted CA are
+    /// authorized without a token.
+    pub client_auth: Option<AdminClientAuthConfig>,
+}
+
+/// Configures the certificates by which admin clients may be authorized.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AdminClientAuthConfig {
+    /// Paths to the certificates of the CAs that issue clients' certificates.
+    pub ca_cert_paths: Vec<String>,
+}
+
+/// Describes an invalid admin auth configuration.
+#[derive(Debug)]
+pub enum Error {
+    /// Neither a bearer token nor client certificates are configured.
+    NoCredentials,
+    /// Holds the environment variable that should hold the bearer token.
+    TokenUnset(String),
+    /// `maxFailuresPerMinute` is 0.
+    InvalidMaxFailures,
+    /// TLS is configured, but the process was built without the `tls` feature.
+    BuiltWithoutTlsSupport,
+    /// Holds the path of a certificate file that could not be read.
+    InvalidCertificates(String),
+    /// Holds the path of a private key file that could not be read.
+    InvalidPrivateKey(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoCredentials => {
+                f.write_str("auth requires bearerTokenEnv or tls.clientAuth")
+            }
+            Error::TokenUnset(ref var) => write!(f, "bearer token {} is not set", var),
+            Error::InvalidMaxFailures => f.write_str("invalid maxFailuresPerMinute: 0"),
+            Error::BuiltWithoutTlsSupport => f.write_str("built without TLS support"),
+            Error::InvalidCertificates(ref p) => write!(f, "invalid certificates: {}", p),
+            Error::InvalidPrivateKey(ref p) => write!(f, "invalid private key: {}", p),
+        }
+    }
+}
+
+impl AdminAuthConfig {
+    /// Reads the bearer token from the environment and loads any TLS credentials.
+    pub fn mk_policy(&self) -> Result<Policy, Error> {
+        let client_auth = self.tls.as_ref().map(|t| t.client_auth.is_some()).unwrap_or(false);
+        if self.bearer_token_env.is_none() && !client_auth {
+            return Err(Error::NoCredentials);
+        }
+        let token = match self.bearer_token_env {
+            None => None,
+            Some(ref var) => {
+                match env::var(var) {
+                    Ok(ref t) if !t.is_empty() => Some(t.as_bytes().to_vec()),
+                    _ => return Err(Error::TokenUnset(var.clone())),
+                }
+            }
+        };
+        let max_failures = self.max_failures_per_minute.unwrap_or(
+            DEFAULT_MAX_FAILURES_PER_MINUTE,
+        );
+        if max_failures == 0 {
+            return Err(Error::InvalidMaxFailures);
+        }
+        Ok(Policy {
+            token,
+            allow_unauthenticated_reads: self.allow_unauthenticated_reads.unwrap_or(false),
+            max_failures,
+            tls: match self.tls {
+                None => None,
+                Some(ref tls) => Some(tls.mk_tls()?),
+            },
+        })
+    }
+}
+
+impl AdminTlsConfig {
+    #[cfg(feature = "tls")]
+    fn mk_tls(&self) -> Result<Arc<::rustls::ServerConfig>, Error> {
+        use rustls::{self, sign};
+
+        let mut certs = Vec::new();
+        for p in &self.cert_paths {
+            certs.append(&mut tls::load_certs(p)?);
+        }
+        let key = tls::load_private_key(&self.key_path)?;
+        // The key is checked here, since rustls panics on keys it cannot use.
+        if sign::RSASigningKey::new(&key).is_err() {
+            return Err(Error::InvalidPrivateKey(self.key_path.clone()));
+        }
+        let mut config = rustls::ServerConfig::new();
+        config.set_single_cert(certs, key);
+        if let Some(ref client_auth) = self.client_auth {
+            let mut roots = Vec::new();
+            for p in &client_auth.ca_cert_paths {
+                roots.append(&mut tls::load_certs(p)?);
+            }
+            // Clients need not present certificates, so that they may present tokens
+            // instead. Certificates that are presented must be valid.
+            config.set_client_auth_roots(roots, false);
+        }
+        Ok(Arc::new(config))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn mk_tls(&self) -> Result<(), Error> {
+        Err(Error::BuiltWithoutTlsSupport)
+    }
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::Error;
+    use rustls::{Certificate, PrivateKey};
+    use rustls::internal::pemfile;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    pub fn load_certs(path: &str) -> Result<Vec<Certificate>, Error> {
+        let invalid = || Error::InvalidCertificates(path.to_owned());
+        let file = File::open(path).map_err(|_| invalid())?;
+        let certs = pemfile::certs(&mut BufReader::new(file)).map_err(|()| invalid())?;
+        if certs.is_empty() {
+            return Err(invalid());
+        }
+        Ok(certs)
+    }
+
+    pub fn load_private_key(path: &str) -> Result<PrivateKey, Error> {
+        let invalid = || Error::InvalidPrivateKey(path.to_owned());
+        let file = File::open(path).map_err(|_| invalid())?;
+        let mut keys = pemfile::rsa_private_keys(&mut BufReader::new(file))
+            .map_err(|()| invalid())?;
+        if keys.len() != 1 {
+            return Err(invalid());
+        }
+        Ok(keys.remove(0))
+    }
+}
+
+/// How requests to the admin server are authorized.
+pub struct Policy {
+    token: Option<Vec<u8>>,
+    allow_unauthenticated_reads: bool,
+    max_failures: u32,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<::rustls::ServerConfig>>,
+    #[cfg(not(feature = "tls"))]
+    tls: Option<()>,
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The token is never formatted.
+        f.debug_struct("Policy")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("allow_unauthenticated_reads", &self.allow_unauthenticated_reads)
+            .field("max_failures", &self.max_failures)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl Policy {
+    /// The configuration of the admin server's TLS handshakes, if it serves TLS.
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&Arc<::rustls::ServerConfig>> {
+        self.tls.as_ref()
+    }
+
+    /// Authorizes requests, counting those that are refused in `metrics`.
+    pub fn bind(self, metrics: &tacho::Scope) -> Auth {
+        let failure = |cause: &'static str| {
+            metrics.clone().labeled("cause", cause).counter("admin_auth_failures")
+        };
+        Auth(Rc::new(Inner {
+            token: self.token,
+            allow_unauthenticated_reads: self.allow_unauthenticated_reads,
+            max_failures: self.max_failures,
+            failures: RefCell::new(HashMap::new()),
+            missing: failure("missing"),
+            invalid: failure("invalid"),
+            rate_limited: failure("rate_limited"),
+        }))
+    }
+}
+
+/// Identifies the client of an admin connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Client {
+    /// The client's address.
+    pub ip: net::IpAddr,
+    /// Set when the client presented a certificate issued by a trusted CA.
+    pub certified: bool,
+}
+
+/// Authorizes the admin server's requests.
+#[derive(Clone)]
+pub struct Auth(Rc<Inner>);
+
+struct Inner {
+    token: Option<Vec<u8>>,
+    allow_unauthenticated_reads: bool,
+    max_failures: u32,
+    /// The wrong tokens presented by each client in its current window, and when the
+    /// window began.
+    failures: RefCell<HashMap<net::IpAddr, (Instant, u32)>>,
+    missing: tacho::Counter,
+    invalid: tacho::Counter,
+    rate_limited: tacho::Counter,
+}
+
+impl Auth {
+    /// Returns a response refusing `req`, if `client` may not make it.
+    pub fn refuse(&self, client: &Client, req: &Request) -> Option<RspFuture> {
+        let inner = &self.0;
+        let read = *req.method() == Get || *req.method() == Head;
+        if client.certified || (read && inner.allow_unauthenticated_reads) {
+            return None;
+        }
+        if self.is_limited(client.ip) {
+            debug!("refusing admin request from {}: too many failures", client.ip);
+            inner.rate_limited.incr(1);
+            return Some(refusal(StatusCode::TooManyRequests, "too many failed attempts\n"));
+        }
+        let token = req.headers()
+            .get_raw("Authorization")
+            .and_then(|raw| raw.one())
+            .and_then(|a| bearer_token(a));
+        match (token, inner.token.as_ref()) {
+            (Some(token), Some(expected)) if constant_time_eq(token, expected) => None,
+            (None, _) => {
+                inner.missing.incr(1);
+                let mut rsp = Response::new().with_status(StatusCode::Unauthorized);
+                rsp.headers_mut().set_raw("WWW-Authenticate", "Bearer");
+                let body = "credentials required\n";
+                rsp.headers_mut().set(ContentLength(body.len() as u64));
+                Some(Box::new(future::ok(rsp.with_body(body))))
+            }
+            (Some(_), _) => {
+                info!("refusing admin request from {}: invalid token", client.ip);
+                inner.invalid.incr(1);
+                self.record_failure(client.ip);
+                Some(refusal(StatusCode::Forbidden, "invalid credentials\n"))
+            }
+        }
+    }
+
+    fn is_limited(&self, ip: net::IpAddr) -> bool {
+        let failures = self.0.failures.borrow();
+        match failures.get(&ip) {
+            Some(&(since, n)) => !expired(since) && n >= self.0.max_failures,
+            None => false,
+        }
+    }
+
+    fn record_failure(&self, ip: net::IpAddr) {
+        let mut failures = self.0.failures.borrow_mut();
+        if failures.len() >= MAX_TRACKED_CLIENTS && !failures.contains_key(&ip) {
+            failures.retain(|_, &mut (since, _)| !expired(since));
+            if failures.len() >= MAX_TRACKED_CLIENTS {
+                return;
+            }
+        }
+        let window = failures.entry(ip).or_insert_with(|| (Instant::now(), 0));
+        if expired(window.0) {
+            *window = (Instant::now(), 0);
+        }
+        window.1 += 1;
+    }
+}
+
+fn expired(since: Instant) -> bool {
+    since.elapsed() >= Duration::from_secs(FAILURE_WINDOW_SECS)
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header.
+fn bearer_token(header: &[u8]) -> Option<&[u8]> {
+    for scheme in &BEARER {
+        if header.len() > scheme.len() && header.starts_with(scheme) {
+            return Some(&header[scheme.len()..]);
+        }
+    }
+    None
+}
+
+/// Compares `a` and `b` in time that depends only on their lengths, so that a token
+/// cannot be guessed a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn refusal(status: StatusCode, body: &'static str) -> RspFuture {
+    let rsp = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body);
+    Box::new(future::ok(rsp))
+}
@@ -0,0 +1,411 @@
+//! Serves metrics, readiness, and the state of the proxy's balancers over HTTP, and
+//! accepts operators' overrides.
+//!
+//! The JSON responses of `/state` and `/admin/summary` are versioned as described in
+//! `api`. Requests may be required to authenticate, as described in `auth`.
+
+use super::app::Closer;
+use super::fd::FdLimit;
+use super::info::Info;
+use super::notify::Notifier;
+use super::startup::Startup;
+use super::state;
+use futures::{Future, Stream, future, unsync};
+use hyper::{self, Delete, Get, Post, Put, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Service, Request, Response};
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::net;
+use std::process;
+use std::rc::Rc;
+use std::str;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+use url::form_urlencoded;
+
+pub mod api;
+mod auth;
+
+pub use self::auth::{AdminAuthConfig, AdminClientAuthConfig, AdminTlsConfig, Auth, Client,
+                     DEFAULT_MAX_FAILURES_PER_MINUTE, Error as AuthError, Policy as AuthPolicy};
+
+const ENDPOINTS_PREFIX: &'static str = "/admin/endpoints/";
+const WEIGHT_MULTIPLIER: &'static str = "weight-multiplier";
+
+/// Handles admin requests.
+#[derive(Clone)]
+pub struct Admin {
+    prometheus: Rc<RefCell<String>>,
+    closer: Rc<RefCell<Option<Closer>>>,
+    draining: Rc<RefCell<Option<unsync::oneshot::Sender<()>>>>,
+    grace: Duration,
+    fd_limit: FdLimit,
+    state: state::Registry,
+    reactor: Handle,
+    timer: Timer,
+    info: Info,
+    /// Notified when the process begins to drain.
+    notifier: Option<Notifier>,
+    startup: Startup,
+    /// Set when requests must be authorized.
+    auth: Option<Auth>,
+    /// The client of the connection being served, once it is known.
+    client: Option<Client>,
+}
+
+type RspFuture = Box<Future<Item = Response, Error = hyper::Error>>;
+
+impl Admin {
+    /// Serves the metrics most recently rendered into `prometheus` and the state in
+    /// `state`. Shutting down closes `closer`, allowing `grace` for connections to
+    /// drain.
+    pub fn new(
+        prometheus: Rc<RefCell<String>>,
+        closer: Closer,
+        draining: unsync::oneshot::Sender<()>,
+        grace: Duration,
+        fd_limit: FdLimit,
+        state: state::Registry,
+        reactor: Handle,
+        timer: Timer,
+        info: Info,
+        notifier: Option<Notifier>,
+        startup: Startup,
+        auth: Option<Auth>,
+    ) -> Admin {
+        Admin {
+            closer: Rc::new(RefCell::new(Some(closer))),
+            draining: Rc::new(RefCell::new(Some(draining))),
+            prometheus,
+            grace,
+            fd_limit,
+            state,
+            reactor,
+            timer,
+            info,
+            notifier,
+            startup,
+            auth,
+            client: None,
+        }
+    }
+
+    /// Serves a connection from `client`.
+    pub fn for_client(&self, client: Client) -> Admin {
+        Admin {
+            client: Some(client),
+            ..self.clone()
+        }
+    }
+
+    fn metrics(&self) -> RspFuture {
+        let body = self.prometheus.borrow();
+        let rsp = Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body.clone());
+        Box::new(future::ok(rsp))
+    }
+
+    /// Indicates whether the process is able to accept new connections, whether its
+    /// proxies' destinations have been resolved, whether any proxies were skipped because
+    /// they failed to start, and whether any names are being served from a resolution
+    /// cache rather than by namerd.
+    fn ready(&self) -> RspFuture {
+        let cached = self.state.cached_resolutions();
+        let failed = self.state.proxy_errors();
+        let (status, body) = if self.fd_limit.is_exhausted() {
+            let limit = self.fd_limit.limit().unwrap_or(0);
+            (
+                StatusCode::ServiceUnavailable,
+                format!("fd limit exhausted ({})\n", limit),
+            )
+        } else if !self.startup.is_ready() {
+            let (ready, total) = self.startup.progress();
+            (
+                StatusCode::ServiceUnavailable,
+                format!("starting ({} of {} proxies ready)\n", ready, total),
+            )
+        } else if !failed.is_empty() {
+            (
+                StatusCode::Ok,
+                format!("partially ready ({} failed proxies)\n", failed.len()),
+            )
+        } else if !cached.is_empty() {
+            (
+                StatusCode::Ok,
+                format!("ready via cache ({} cached resolutions)\n", cached.len()),
+            )
+        } else {
+            (StatusCode::Ok, "ready\n".to_string())
+        };
+        let rsp = Response::new()
+            .with_status(status)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        Box::new(future::ok(rsp))
+    }
+
+    /// Describes the state of each router's balancers as JSON, at the API version
+    /// requested.
+    fn state(&self, req: &Request) -> RspFuture {
+        match negotiate(req) {
+            Err(e) => text(StatusCode::NotAcceptable, format!("{}\n", e)),
+            Ok(version) => versioned(version, api::to_json(&self.state.to_api())),
+        }
+    }
+
+    /// Summarizes each router's golden signals as JSON, at the API version requested.
+    /// The summary is rendered each second, so serving it is cheap.
+    fn summary(&self, req: &Request) -> RspFuture {
+        match negotiate(req) {
+            Err(e) => text(StatusCode::NotAcceptable, format!("{}\n", e)),
+            Ok(version) => versioned(version, self.state.summary().to_json()),
+        }
+    }
+
+    /// Lists the proxies that were skipped because they failed to start, as JSON.
+    fn config_errors(&self) -> RspFuture {
+        let body = self.state.proxy_errors().to_json();
+        let rsp = Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType::json())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        Box::new(future::ok(rsp))
+    }
+
+    /// Lists the most recently sampled endpoint selections, with their inputs, as JSON.
+    fn selection_trace(&self) -> RspFuture {
+        let body = self.state.selection_traces().to_json();
+        let rsp = Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType::json())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        Box::new(future::ok(rsp))
+    }
+
+    /// Describes the process's build, uptime, and configuration as JSON.
+    fn info(&self) -> RspFuture {
+        let body = self.info.to_json();
+        let rsp = Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(ContentType::json())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        Box::new(future::ok(rsp))
+    }
+
+    /// Ejects or reinstates an endpoint, e.g. `/admin/endpoints/10.1.2.3:8080/eject`.
+    ///
+    /// A `router` query parameter limits the override to a single router's balancers.
+    fn override_endpoint(&self, path: &str, query: Option<&str>) -> RspFuture {
+        let (addr, action, router) = endpoint_target(path, query);
+        let router = router.as_ref().map(|r| r.as_str());
+
+        let ejections = self.state.ejections();
+        let (status, body) = match (addr, action) {
+            (None, _) => (StatusCode::BadRequest, "invalid endpoint address\n".to_string()),
+            (Some(addr), Some("eject")) => {
+                if ejections.eject(router, addr) {
+                    info!("{}: ejected via admin API", addr);
+                    (StatusCode::Ok, format!("ejected {}\n", addr))
+                } else {
+                    (StatusCode::Ok, format!("{} already ejected\n", addr))
+                }
+            }
+            (Some(addr), Some("reinstate")) => {
+                if ejections.reinstate(router, addr) {
+                    info!("{}: reinstated via admin API", addr);
+                    (StatusCode::Ok, format!("reinstated {}\n", addr))
+                } else {
+                    (StatusCode::Ok, format!("{} not ejected\n", addr))
+                }
+            }
+            (Some(_), _) => return self.not_found(),
+        };
+        let rsp = Response::new()
+            .with_status(status)
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        Box::new(future::ok(rsp))
+    }
+
+    /// Scales an endpoint's resolved weight by the multiplier in the request body, e.g.
+    /// `PUT /admin/endpoints/10.1.2.3:8080/weight-multiplier` with `0.1`.
+    ///
+    /// A `router` query parameter limits the override to a single router's balancers.
+    fn set_weight_multiplier(&self, req: Request) -> RspFuture {
+        let (addr, action, router) = endpoint_target(req.path(), req.query());
+        let addr = match (addr, action) {
+            (None, _) => return text(StatusCode::BadRequest, "invalid endpoint address\n".into()),
+            (Some(addr), Some(WEIGHT_MULTIPLIER)) => addr,
+            (Some(_), _) => return self.not_found(),
+        };
+        let overrides = self.state.weight_overrides().clone();
+        let rsp = req.body().concat2().and_then(move |body| {
+            let multiplier = str::from_utf8(&body)
+                .ok()
+                .and_then(|b| b.trim().parse::<f64>().ok());
+            let router = router.as_ref().map(|r| r.as_str());
+            match multiplier {
+                Some(m) if overrides.set(router, addr, m) => {
+                    info!("{}: weight multiplier set to {} via admin API", addr, m);
+                    text(StatusCode::Ok, format!("{} weight multiplier set to {}\n", addr, m))
+                }
+                _ => {
+                    text(
+                        StatusCode::BadRequest,
+                        "weight multiplier must be a non-negative number\n".into(),
+                    )
+                }
+            }
+        });
+        Box::new(rsp)
+    }
+
+    /// Clears an endpoint's weight override, e.g.
+    /// `DELETE /admin/endpoints/10.1.2.3:8080/weight-multiplier`.
+    ///
+    /// A `router` query parameter clears only that router's override; otherwise, all of
+    /// the endpoint's overrides are cleared.
+    fn clear_weight_multiplier(&self, path: &str, query: Option<&str>) -> RspFuture {
+        let (addr, action, router) = endpoint_target(path, query);
+        let router = router.as_ref().map(|r| r.as_str());
+        match (addr, action) {
+            (None, _) => text(StatusCode::BadRequest, "invalid endpoint address\n".into()),
+            (Some(addr), Some(WEIGHT_MULTIPLIER)) => {
+                if self.state.weight_overrides().clear(router, addr) {
+                    info!("{}: weight multiplier cleared via admin API", addr);
+                    text(StatusCode::Ok, format!("{} weight multiplier cleared\n", addr))
+                } else {
+                    text(StatusCode::Ok, format!("{} weight not overridden\n", addr))
+                }
+            }
+            (Some(_), _) => self.not_found(),
+        }
+    }
+
+    /// Tell the serving thread to stop what it's doing.
+    // TODO offer a `force` param?
+    fn shutdown(&self) -> RspFuture {
+        let mut closer = self.closer.borrow_mut();
+        if let Some(c) = closer.take() {
+            info!("shutting down via admin API");
+            if let Some(ref n) = self.notifier {
+                n.notify("STOPPING=1");
+            }
+            let _ = c.send(Instant::now() + self.grace);
+        }
+        if let Some(d) = self.draining.borrow_mut().take() {
+            let _ = d.send(());
+        }
+        let rsp = Response::new().with_status(StatusCode::Ok);
+        Box::new(future::ok(rsp))
+    }
+
+    fn abort(&self) -> RspFuture {
+        process::exit(1);
+    }
+
+    fn not_found(&self) -> RspFuture {
+        let rsp = Response::new().with_status(StatusCode::NotFound);
+        Box::new(future::ok(rsp))
+    }
+}
+
+impl Service for Admin {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = RspFuture;
+    fn call(&self, req: Request) -> RspFuture {
+        if let Some(ref auth) = self.auth {
+            // Clients of connections that were not identified are never certified.
+            let client = self.client.unwrap_or(Client {
+                ip: net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)),
+                certified: false,
+            });
+            if let Some(rsp) = auth.refuse(&client, &req) {
+                return rsp;
+            }
+        }
+        // Requests with bodies are handled before the request is borrowed below.
+        if *req.method() == Put && req.path().starts_with(ENDPOINTS_PREFIX) {
+            return self.set_weight_multiplier(req);
+        }
+        match (req.method(), req.path()) {
+            (&Get, "/metrics") => self.metrics(),
+            (&Get, "/ready") => self.ready(),
+            (&Get, "/state") => self.state(&req),
+            (&Get, "/admin/info") => self.info(),
+            (&Get, "/admin/summary") => self.summary(&req),
+            (&Get, "/admin/config/errors") => self.config_errors(),
+            (&Get, "/admin/selection-trace") => self.selection_trace(),
+            (&Post, "/shutdown") => self.shutdown(),
+            (&Post, "/abort") => self.abort(),
+            (&Post, path) if path.starts_with(ENDPOINTS_PREFIX) => {
+                self.override_endpoint(path, req.query())
+            }
+            (&Delete, path) if path.starts_with(ENDPOINTS_PREFIX) => {
+                self.clear_weight_multiplier(path, req.query())
+            }
+            _ => self.not_found(),
+        }
+    }
+}
+
+/// Parses an `/admin/endpoints/{addr}/{action}` path and its optional `router` query
+/// parameter.
+fn endpoint_target(
+    path: &str,
+    query: Option<&str>,
+) -> (Option<net::SocketAddr>, Option<&'static str>, Option<String>) {
+    let mut parts = path[ENDPOINTS_PREFIX.len()..].splitn(2, '/');
+    let addr = parts.next().and_then(|a| a.parse::<net::SocketAddr>().ok());
+    let action = match parts.next() {
+        Some("eject") => Some("eject"),
+        Some("reinstate") => Some("reinstate"),
+        Some(WEIGHT_MULTIPLIER) => Some(WEIGHT_MULTIPLIER),
+        _ => None,
+    };
+    let router = query.and_then(|q| {
+        form_urlencoded::parse(q.as_bytes())
+            .find(|&(ref k, _)| k == "router")
+            .map(|(_, v)| v.into_owned())
+    });
+    (addr, action, router)
+}
+
+/// Determines the API version requested by a `v` query parameter or `Accept` header.
+fn negotiate(req: &Request) -> Result<u32, api::Unsupported> {
+    let accept = req.headers().get_raw("Accept").and_then(|raw| raw.one()).and_then(
+        |a| str::from_utf8(a).ok(),
+    );
+    api::negotiate(req.query(), accept)
+}
+
+/// Serves a JSON response, labeled with the media type of `version`.
+///
+/// Responses are rendered at the latest version. Once a version is superseded,
+/// responses requested at it are translated before they are rendered.
+fn versioned(version: u32, body: String) -> RspFuture {
+    let media_type = api::media_type(version).parse().expect("invalid media type");
+    let rsp = Response::new()
+        .with_status(StatusCode::Ok)
+        .with_header(ContentType(media_type))
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body);
+    Box::new(future::ok(rsp))
+}
+
+fn text(status: StatusCode, body: String) -> RspFuture {
+    let rsp = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body);
+    Box::new(future::ok(rsp))
+}
@@ -1,37 +1,94 @@
 //! Provides all of the utilities needed to load a configuration and run a process.
 
-use super::{admin, resolver, router, server};
-use super::balancer::BalancerFactory;
-use super::connector::{ConfigError as ConnectorConfigError, ConnectorFactoryConfig};
-use super::resolver::{ConfigError as ResolverConfigError, NamerdConfig};
+use super::{Path, WeightedAddr, admin, fd, handoff, info, metrics, metrics_log, notify,
+            resolver, router, security, server, state, tracing};
+use super::hook::{ConnectionHook, Hooks};
+use super::schema::Schema;
+use super::balancer::{BalancerFactory, GlobalLimit};
+use super::connection::{BufferBudget, Buffers};
+use super::dns::Dns;
+use super::duration;
+#[cfg(feature = "tls")]
+use super::connection::secure;
+#[cfg(feature = "tls")]
+use super::timeout::timeout;
+use super::connector::ConfigError as ConnectorConfigError;
+use super::resolver::ConfigError as ResolverConfigError;
 use super::server::ConfigError as ServerConfigError;
-use futures::{Future, Stream, sync};
+use futures::{Future, Stream, future, sync, unsync};
 use hyper;
 use hyper::server::Http;
+use rand;
 use serde_json;
 use serde_yaml;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::{env, error, fmt, fs, io};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
 use std::net;
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::{Path as FsPath, PathBuf};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 use tacho;
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::{Core, Handle};
 use tokio_timer::Timer;
+use tokio_uds::UnixListener;
 
 const DEFAULT_ADMIN_PORT: u16 = 9989;
 const DEFAULT_BUFFER_SIZE_BYTES: usize = 16 * 1024;
 const DEFAULT_GRACE_SECS: u64 = 10;
 const DEFAULT_METRICS_INTERVAL_SECS: u64 = 60;
+const DEFAULT_METRICS_LOG_INTERVAL_SECS: u64 = 60;
+/// How often startup progress is checked when readiness is logged.
+const READINESS_LOG_INTERVAL_SECS: u64 = 1;
+/// Bounds the time admin clients may take to complete a TLS handshake.
+#[cfg(feature = "tls")]
+const ADMIN_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
 
-/// An app-specific Result type.
-pub type Result<T> = ::std::result::Result<T, Error>;
+/// The latest `configVersion` understood by this release.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// When set, overrides the configured `rngSeed`.
+pub const RNG_SEED_ENV: &'static str = "LINKERD_TCP_RNG_SEED";
+
+/// Prefixes the names of the process's metrics.
+const METRICS_PREFIX: &'static str = "l5d";
+
+pub use super::connector::{ChaosConfig, CircuitBreakerConfig, ConnectBackoffConfig,
+                           ConnectPreambleConfig, ConnectorConfig, ConnectorFactoryConfig,
+                           EndpointFilterConfig,
+                           FailFastConfig, FallbackConfig, FlapDetectionConfig,
+                           LoadBalancerConfig, LoadBalancerKind,
+                           LocalityAwareConfig, PoolConfig, ReadinessProbeConfig,
+                           RebalanceConfig, SelectionTraceConfig, SlowStartConfig,
+                           StickinessConfig, StickinessKey, SubsetSeed, SubsettingConfig,
+                           TlsConnectorFactoryConfig, TlsNameFrom, TlsVerification,
+                           UpdateDampingConfig, WeightMode};
+pub use super::admin::{AdminAuthConfig, AdminClientAuthConfig, AdminTlsConfig};
+pub use super::duration::{Millis, Secs};
+pub use super::notify::{Notifier, Readiness};
+pub use super::resolver::{AcceptRemotePolicyConfig, LocalResolverConfig, NamerdConfig,
+                          ResolutionCacheConfig};
+pub use super::security::{Privileges, SecurityConfig};
+pub use super::startup::{DEFAULT_STARTUP_CONCURRENCY, DEFAULT_STARTUP_QUORUM_PERCENT, Initial,
+                         Startup};
+pub use super::server::{AgentIdentityConfig, DispatchQueueConfig, IdentitySourceConfig,
+                        IntegrityAlgorithm, IntegrityCheckConfig, MirrorConfig, MisdirectedTls,
+                        ServerConfig, ServerKind, ShedPolicy, SourcePortReuseConfig,
+                        TcpInfoConfig, TlsPolicyConfig, TlsServerConfig,
+                        TlsServerIdentityConfig, TlsSessionResumptionConfig,
+                        TransparentRetryConfig, WriteCoalescingConfig};
+pub use super::tracing::{TraceExportConfig, TracingConfig};
+
+/// A Result type for loading a configuration and running a process.
+pub type Result<T> = ::std::result::Result<T, super::Error>;
 
 /// Describes a configuration error.
 #[derive(Debug)]
 pub enum Error {
-    /// A JSON syntax error.
+    /// A JSON syntax error, or a configuration value of the wrong type.
     Json(serde_json::Error),
 
     /// A Yaml syntax error.
@@ -45,6 +102,187 @@ pub enum Error {
 
     /// Indicats a misconfigured server.
     Server(ServerConfigError),
+
+    /// Indicates a file descriptor watermark outside of (0, 100].
+    InvalidFdHighWatermark(usize),
+
+    /// Indicates a startup concurrency of 0.
+    InvalidStartupConcurrency,
+
+    /// Indicates a startup quorum outside of (0, 100].
+    InvalidStartupQuorum(usize),
+
+    /// Indicates a metrics log interval of 0.
+    InvalidMetricsLogInterval,
+
+    /// Indicates a value of `LINKERD_TCP_RNG_SEED` that is not an unsigned integer.
+    InvalidRngSeed(String),
+
+    /// Indicates misconfigured connection tracing.
+    Tracing(tracing::Error),
+
+    /// Indicates misconfigured privilege reduction.
+    Security(security::Error),
+
+    /// Indicates misconfigured admin authorization.
+    AdminAuth(admin::AuthError),
+
+    /// Indicates admin listener settings that may not be combined.
+    InvalidAdminListener(&'static str),
+
+    /// Indicates a `configVersion` that is newer than this release understands.
+    UnsupportedConfigVersion(String),
+
+    /// Lists the JSON pointers to unknown fields in a strict configuration.
+    UnknownFields(Vec<String>),
+
+    /// Indicates a duration that cannot be parsed, by the JSON pointer to its field.
+    InvalidDuration(String, String),
+
+    /// Indicates a transfer buffer size of 0.
+    InvalidBufferSize,
+
+    /// Indicates a buffered data budget of 0.
+    InvalidMaxBufferedBytes,
+
+    /// Indicates an upstream connection limit of 0.
+    InvalidMaxUpstreamConnections,
+
+    /// Indicates a name given to a static interpreter that does not begin with `/`.
+    InvalidStaticName(String),
+
+    /// Indicates a server's `dstName` that is empty, does not begin with `/`, or has
+    /// leading or trailing whitespace. Servers are identified by their indices.
+    InvalidDstName {
+        /// The index of the server's router.
+        router: usize,
+        /// The index of the server within its router.
+        server: usize,
+        /// The invalid name.
+        dst_name: String,
+    },
+
+    /// Indicates an address range given to a `RejectCidrs` hook that cannot be parsed.
+    InvalidHookCidr(String),
+
+    /// Indicates a connection hook timeout of 0.
+    InvalidHookTimeout,
+
+    /// Indicates a resolution cache file shared by more than one interpreter.
+    DuplicateResolutionCache(String),
+
+    /// Indicates a local resolver socket shared by more than one interpreter.
+    DuplicateLocalResolver(String),
+
+    /// Indicates an address on which more than one server of the same kind listens.
+    DuplicateListener(net::SocketAddr),
+
+    /// Indicates a `weightOverrides` entry whose key is not a socket address or whose
+    /// multiplier is negative or not finite.
+    InvalidWeightOverride(String, f64),
+
+    /// Indicates that chaos was enabled for a configuration that does not set
+    /// `allowChaos: true`.
+    ChaosNotAllowed,
+
+    /// Indicates a router's `metricsScope` that is empty or is not made of letters,
+    /// digits, and underscores, beginning with a letter or underscore.
+    InvalidMetricsScope(String),
+
+    /// Indicates a router's `sizeBuckets` that are not positive and strictly
+    /// increasing.
+    InvalidSizeBuckets(Vec<u64>),
+
+    /// Indicates `experimental.connectionHandoff: true` without a `handoffSocket`.
+    MissingHandoffSocket,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Json(ref e) => write!(f, "invalid json: {}", e),
+            Error::Yaml(ref e) => write!(f, "invalid yaml: {}", e),
+            Error::Connector(ref e) => write!(f, "invalid client: {:?}", e),
+            Error::Interpreter(ref e) => write!(f, "invalid interpreter: {:?}", e),
+            Error::Server(ref e) => write!(f, "invalid server: {:?}", e),
+            Error::InvalidFdHighWatermark(pct) => {
+                write!(f, "invalid fdHighWatermarkPercent: {}", pct)
+            }
+            Error::InvalidStartupConcurrency => f.write_str("invalid startupConcurrency: 0"),
+            Error::InvalidStartupQuorum(pct) => {
+                write!(f, "invalid startupQuorumPercent: {}", pct)
+            }
+            Error::InvalidMetricsLogInterval => f.write_str("invalid metrics logIntervalSecs: 0"),
+            Error::InvalidRngSeed(ref s) => write!(f, "invalid {}: {}", RNG_SEED_ENV, s),
+            Error::Tracing(ref e) => write!(f, "invalid tracing: {:?}", e),
+            Error::Security(ref e) => write!(f, "invalid security: {}", e),
+            Error::AdminAuth(ref e) => write!(f, "invalid admin auth: {}", e),
+            Error::InvalidAdminListener(e) => write!(f, "invalid admin: {}", e),
+            Error::UnsupportedConfigVersion(ref v) => {
+                write!(f, "unsupported configVersion: {}", v)
+            }
+            Error::UnknownFields(ref paths) => {
+                write!(f, "unknown fields: {}", paths.join(", "))
+            }
+            Error::InvalidDuration(ref path, ref e) => write!(f, "{}: {}", path, e),
+            Error::InvalidBufferSize => f.write_str("invalid buffer size: 0"),
+            Error::InvalidMaxBufferedBytes => f.write_str("invalid maxBufferedBytes: 0"),
+            Error::InvalidMaxUpstreamConnections => {
+                f.write_str("invalid maxUpstreamConnections: 0")
+            }
+            Error::InvalidStaticName(ref n) => write!(f, "invalid static name: {}", n),
+            Error::InvalidDstName {
+                router,
+                server,
+                ref dst_name,
+            } => {
+                write!(
+                    f,
+                    "routers[{}].servers[{}]: dstName {:?} must begin with '/' and may not \
+                     have surrounding whitespace",
+                    router,
+                    server,
+                    dst_name
+                )
+            }
+            Error::InvalidHookCidr(ref c) => write!(f, "invalid hook cidr: {}", c),
+            Error::InvalidHookTimeout => f.write_str("invalid hook timeout: 0"),
+            Error::DuplicateResolutionCache(ref p) => {
+                write!(f, "resolution cache {} is used by more than one interpreter", p)
+            }
+            Error::DuplicateLocalResolver(ref p) => {
+                write!(f, "local resolver {} is used by more than one interpreter", p)
+            }
+            Error::DuplicateListener(ref a) => {
+                write!(f, "more than one server listens on {}", a)
+            }
+            Error::InvalidWeightOverride(ref a, m) => {
+                write!(f, "invalid weight override for {}: {}", a, m)
+            }
+            Error::ChaosNotAllowed => {
+                f.write_str("chaos may only be enabled when allowChaos is true")
+            }
+            Error::InvalidMetricsScope(ref s) => write!(f, "invalid metricsScope: {:?}", s),
+            Error::InvalidSizeBuckets(ref b) => write!(f, "invalid sizeBuckets: {:?}", b),
+            Error::MissingHandoffSocket => {
+                f.write_str("experimental.connectionHandoff requires a handoffSocket")
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "invalid configuration"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Json(ref e) => Some(e),
+            Error::Yaml(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 /// Signals a receiver to shutdown by the provided deadline.
@@ -62,100 +300,969 @@ pub fn closer() -> (Closer, Closed) {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct AppConfig {
+    /// The version of the configuration format. Configurations without a version are
+    /// treated as version 1.
+    pub config_version: Option<u64>,
+
+    /// When false, unknown fields are logged and ignored rather than rejected, so that
+    /// a configuration written for a newer release may be loaded by an older one.
+    /// Defaults to true.
+    pub strict: Option<bool>,
+
     /// Configures the processes's admin server.
     pub admin: Option<AdminConfig>,
 
     /// Configures one or more routers.
     pub routers: Vec<RouterConfig>,
 
-    /// Configures the shared buffer used for transferring data.
+    /// Configures the shared buffers used for transferring data in both directions.
     pub buffer_size_bytes: Option<usize>,
+
+    /// Configures the shared buffer used for transferring data from clients to servers,
+    /// overriding `bufferSizeBytes`.
+    pub client_to_server_buffer_bytes: Option<usize>,
+
+    /// Configures the shared buffer used for transferring data from servers to clients,
+    /// overriding `bufferSizeBytes`.
+    pub server_to_client_buffer_bytes: Option<usize>,
+
+    /// Bounds the data held, across all connections, because a peer was not ready to
+    /// accept it. While this is exhausted, reads are deferred. Unbounded by default.
+    pub max_buffered_bytes: Option<usize>,
+
+    /// Bounds the upstream connections held by all routers. Connections beyond the limit
+    /// wait in their destination's queue until another upstream connection closes.
+    /// Unbounded by default.
+    pub max_upstream_connections: Option<usize>,
+
+    /// The percentage of the process's file descriptor limit above which new connections
+    /// are refused.
+    pub fd_high_watermark_percent: Option<usize>,
+
+    /// Bounds the number of proxies whose destinations are initially resolved by namerd
+    /// at once, so that a process with many proxies does not exceed namerd's rate
+    /// limits as it starts. Defaults to 5.
+    pub startup_concurrency: Option<usize>,
+
+    /// The percentage of proxies whose destinations must be resolved before the process
+    /// reports that it is ready via `/ready`. Defaults to 100.
+    pub startup_quorum_percent: Option<usize>,
+
+    /// Configures metrics reporting outside of the admin server.
+    pub metrics: Option<MetricsConfig>,
+
+    /// Seeds the randomness used by balancers so that their decisions may be
+    /// reproduced. By default, a seed is chosen randomly. In either case, the seed is
+    /// logged at startup.
+    pub rng_seed: Option<u64>,
+
+    /// Samples connections to be traced, exporting a span describing each traced
+    /// connection once it closes. By default, connections are not traced.
+    pub tracing: Option<TracingConfig>,
+
+    /// Reduces the process's privileges once its listeners are bound. Unix only.
+    pub security: Option<SecurityConfig>,
+
+    /// Permits routers' `chaos` to be enabled by starting the process with `--chaos`.
+    /// Defaults to false, so that faults are never injected by accident.
+    pub allow_chaos: Option<bool>,
+
+    /// Determines whether a proxy that cannot be built or bound prevents the process
+    /// from starting (`abort`, the default) or is skipped (`skip`).
+    pub on_proxy_error: Option<OnProxyError>,
+
+    /// Enables features that are not yet stable. Their configuration may change between
+    /// releases.
+    pub experimental: Option<ExperimentalConfig>,
+}
+
+/// Configures features that are not yet stable.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ExperimentalConfig {
+    /// Hands established plaintext streams to the process that replaces this one, over
+    /// `handoffSocket`, as this process shuts down, and resumes the streams handed to
+    /// it. Streams that cannot be handed off drain. Defaults to false.
+    pub connection_handoff: Option<bool>,
+
+    /// The unix socket on which streams are received, and to which they are handed off.
+    /// Required when `connectionHandoff` is true.
+    pub handoff_socket: Option<PathBuf>,
+}
+
+/// Determines what happens when a proxy (a router's server) fails to start, e.g.
+/// because its certificates cannot be loaded or its port cannot be bound.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnProxyError {
+    /// The process fails to start.
+    Abort,
+    /// The proxy is logged, counted as `failed_proxies`, and reported by the admin
+    /// server's `/admin/config/errors` endpoint, and the process serves its other
+    /// proxies.
+    Skip,
+}
+
+impl Default for OnProxyError {
+    fn default() -> OnProxyError {
+        OnProxyError::Abort
+    }
 }
 
 impl ::std::str::FromStr for AppConfig {
-    type Err = Error;
+    type Err = super::Error;
 
     /// Parses a JSON- or YAML-formatted configuration file.
+    ///
+    /// Unknown fields ignored by a configuration that is not strict are logged.
     fn from_str(txt: &str) -> Result<AppConfig> {
-        let txt = txt.trim_left();
-        if txt.starts_with('{') {
-            serde_json::from_str(txt).map_err(Error::Json)
-        } else {
-            serde_yaml::from_str(txt).map_err(Error::Yaml)
+        let (config, ignored) = AppConfig::parse(txt)?;
+        for path in &ignored {
+            warn!("ignoring unknown configuration field: {}", path);
         }
+        Ok(config)
     }
 }
 
 impl AppConfig {
+    /// Parses a JSON- or YAML-formatted configuration file, returning the configuration
+    /// and a JSON pointer to each unknown field that was ignored.
+    ///
+    /// Unknown fields are only ignored when `strict` is false. Otherwise, they are
+    /// reported as `app::Error::UnknownFields`.
+    pub fn parse(txt: &str) -> Result<(AppConfig, Vec<String>)> {
+        // The configuration is first read as a generic value so that it may be checked
+        // against the schema before it is deserialized.
+        let txt = txt.trim_left();
+        let mut value: serde_json::Value = if txt.starts_with('{') {
+            serde_json::from_str(txt).map_err(Error::Json)?
+        } else {
+            serde_yaml::from_str(txt).map_err(Error::Yaml)?
+        };
+
+        // Versions are checked first, since an unsupported version may explain any
+        // unknown fields.
+        if let Some(v) = value.get("configVersion") {
+            if v.as_u64().map(|v| v == 0 || v > CONFIG_VERSION).unwrap_or(true) {
+                return Err(Error::UnsupportedConfigVersion(v.to_string()).into());
+            }
+        }
+        let strict = value.get("strict").and_then(|s| s.as_bool()).unwrap_or(true);
+
+        let ignored = AppConfig::schema().strip_unknown(&mut value);
+        if strict && !ignored.is_empty() {
+            return Err(Error::UnknownFields(ignored).into());
+        }
+        if let Some((path, e)) = duration::find_invalid(&value) {
+            return Err(Error::InvalidDuration(path, e).into());
+        }
+        let config = serde_json::from_value(value).map_err(Error::Json)?;
+        Ok((config, ignored))
+    }
+
+    fn schema() -> Schema {
+        Schema::of::<AppConfig>(vec![
+            ("admin", AdminConfig::schema()),
+            ("routers", Schema::list(RouterConfig::schema())),
+            ("metrics", Schema::of::<MetricsConfig>(vec![])),
+            ("tracing", TracingConfig::schema()),
+            ("security", Schema::of::<SecurityConfig>(vec![])),
+            ("experimental", Schema::of::<ExperimentalConfig>(vec![])),
+        ])
+    }
+
+    /// Combines `other`, loaded from `path`, into this configuration.
+    ///
+    /// Routers are appended. Each global setting that `other` sets replaces this
+    /// configuration's.
+    fn merge(mut self, mut other: AppConfig, path: &FsPath) -> AppConfig {
+        override_global(path, "configVersion", &mut self.config_version, other.config_version);
+        override_global(path, "strict", &mut self.strict, other.strict);
+        override_global(path, "admin", &mut self.admin, other.admin);
+        override_global(
+            path,
+            "bufferSizeBytes",
+            &mut self.buffer_size_bytes,
+            other.buffer_size_bytes,
+        );
+        override_global(
+            path,
+            "clientToServerBufferBytes",
+            &mut self.client_to_server_buffer_bytes,
+            other.client_to_server_buffer_bytes,
+        );
+        override_global(
+            path,
+            "serverToClientBufferBytes",
+            &mut self.server_to_client_buffer_bytes,
+            other.server_to_client_buffer_bytes,
+        );
+        override_global(
+            path,
+            "maxBufferedBytes",
+            &mut self.max_buffered_bytes,
+            other.max_buffered_bytes,
+        );
+        override_global(
+            path,
+            "maxUpstreamConnections",
+            &mut self.max_upstream_connections,
+            other.max_upstream_connections,
+        );
+        override_global(
+            path,
+            "fdHighWatermarkPercent",
+            &mut self.fd_high_watermark_percent,
+            other.fd_high_watermark_percent,
+        );
+        override_global(
+            path,
+            "startupConcurrency",
+            &mut self.startup_concurrency,
+            other.startup_concurrency,
+        );
+        override_global(
+            path,
+            "startupQuorumPercent",
+            &mut self.startup_quorum_percent,
+            other.startup_quorum_percent,
+        );
+        override_global(path, "metrics", &mut self.metrics, other.metrics);
+        override_global(path, "rngSeed", &mut self.rng_seed, other.rng_seed);
+        override_global(path, "tracing", &mut self.tracing, other.tracing);
+        override_global(path, "security", &mut self.security, other.security);
+        override_global(path, "allowChaos", &mut self.allow_chaos, other.allow_chaos);
+        override_global(
+            path,
+            "onProxyError",
+            &mut self.on_proxy_error,
+            other.on_proxy_error,
+        );
+        override_global(
+            path,
+            "experimental",
+            &mut self.experimental,
+            other.experimental,
+        );
+        self.routers.extend(other.routers.drain(..));
+        self
+    }
+
+    /// Identifies this configuration by a hash of its canonical serialization, which
+    /// does not depend on how the configuration was formatted or on the order of its
+    /// fields.
+    pub fn hash(&self) -> Result<String> {
+        Ok(info::canonical_hash(self).map_err(Error::Json)?)
+    }
+
     /// Build an App from a configuration.
-    pub fn into_app(mut self) -> Result<App> {
-        // Create a shared transfer buffer to be used for all stream proxying.
-        let buf = {
+    pub fn into_app(self) -> Result<App> {
+        self.into_builder().build()
+    }
+
+    /// Converts this configuration into a builder, so that it may be amended in code
+    /// before an App is built.
+    pub fn into_builder(mut self) -> AppBuilder {
+        let mut builder = AppBuilder::new();
+        builder.config_hash = self.hash().ok();
+        if let Some(admin) = self.admin {
+            if admin.addr.is_some() {
+                builder.admin_addr = admin.addr;
+            } else if admin.ip.is_some() || admin.port.is_some() {
+                let ip = admin.ip.unwrap_or_else(localhost_addr);
+                let port = admin.port.unwrap_or(DEFAULT_ADMIN_PORT);
+                builder.admin_addr = Some(net::SocketAddr::new(ip, port));
+            }
+            builder.grace = admin.grace_secs.map(Duration::from);
+            builder.metrics_interval = admin.metrics_interval_secs.map(Duration::from);
+            builder.admin_auth = admin.auth;
+            builder.admin_disabled = admin.enabled == Some(false);
+            builder.admin_port_file = admin.port_file;
+            builder.admin_unix_socket = admin.unix_socket;
+        }
+        if let Some(m) = self.metrics {
+            let interval = m.log_interval_secs.map(Duration::from).unwrap_or_else(|| {
+                Duration::from_secs(DEFAULT_METRICS_LOG_INTERVAL_SECS)
+            });
+            builder.metrics_log_interval = Some(interval);
+        }
+        builder.buffer_size_bytes = self.buffer_size_bytes;
+        builder.client_to_server_buffer_bytes = self.client_to_server_buffer_bytes;
+        builder.server_to_client_buffer_bytes = self.server_to_client_buffer_bytes;
+        builder.max_buffered_bytes = self.max_buffered_bytes;
+        builder.max_upstream_connections = self.max_upstream_connections;
+        builder.fd_high_watermark_percent = self.fd_high_watermark_percent;
+        builder.startup_concurrency = self.startup_concurrency;
+        builder.startup_quorum_percent = self.startup_quorum_percent;
+        builder.rng_seed = self.rng_seed;
+        builder.tracing = self.tracing;
+        builder.security = self.security;
+        builder.allow_chaos = self.allow_chaos.unwrap_or(false);
+        builder.on_proxy_error = self.on_proxy_error.unwrap_or_default();
+        if let Some(x) = self.experimental {
+            builder.connection_handoff = x.connection_handoff.unwrap_or(false);
+            builder.handoff_socket = x.handoff_socket;
+        }
+        for config in self.routers.drain(..) {
+            builder.routers.push(config.into_builder());
+        }
+        builder
+    }
+}
+
+/// Loads a configuration from one or more files and directories, in order.
+///
+/// Each directory contributes its `*.yml`, `*.yaml`, and `*.json` files in lexical order.
+/// The routers from every file are combined. Global settings (e.g. `admin`) are taken
+/// from the last file that sets them, so that later files override earlier ones; a
+/// global that is overridden with a different value is logged. Servers are validated
+/// across the combined configuration, so that no two files may configure the same
+/// listener.
+///
+/// It is an error for no configuration files to be found.
+pub fn load_paths(paths: &[PathBuf]) -> io::Result<AppConfig> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(path).map_err(|e| path_error(path, e))? {
+                let p = entry.map_err(|e| path_error(path, e))?.path();
+                if p.is_file() && is_config_file(&p) {
+                    entries.push(p);
+                }
+            }
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let mut merged: Option<AppConfig> = None;
+    for file in &files {
+        let mut txt = String::new();
+        fs::File::open(file)
+            .and_then(|mut f| f.read_to_string(&mut txt))
+            .map_err(|e| path_error(file, e))?;
+        let config: AppConfig = txt.parse().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file.display(), e))
+        })?;
+        debug!("loaded configuration from {}", file.display());
+        merged = Some(match merged {
+            None => config,
+            Some(m) => m.merge(config, file),
+        });
+    }
+
+    let config = merged.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "no configuration files found")
+    })?;
+    let servers = config.routers.iter().flat_map(|r| r.servers.iter());
+    if let Some(addr) = duplicate_listener(servers) {
+        let e = Error::DuplicateListener(addr);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
+    Ok(config)
+}
+
+fn is_config_file(path: &FsPath) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yml") | Some("yaml") | Some("json") => true,
+        _ => false,
+    }
+}
+
+fn path_error(path: &FsPath, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// Replaces a global setting with the value set by a later file, if any.
+fn override_global<T>(path: &FsPath, name: &str, global: &mut Option<T>, v: Option<T>)
+where
+    T: ::serde::Serialize,
+{
+    if let Some(v) = v {
+        if let Some(ref prior) = *global {
+            if serde_json::to_value(prior).ok() != serde_json::to_value(&v).ok() {
+                warn!("{}: overriding {}", path.display(), name);
+            }
+        }
+        *global = Some(v);
+    }
+}
+
+/// Finds an address on which more than one of `servers` would listen. Servers of
+/// different kinds may share a port, and servers on ephemeral ports never conflict.
+fn duplicate_listener<'a, I>(servers: I) -> Option<net::SocketAddr>
+where
+    I: Iterator<Item = &'a ServerConfig>,
+{
+    let mut listeners: Vec<(ServerKind, net::SocketAddr)> = Vec::new();
+    for server in servers {
+        if server.port == 0 {
+            continue;
+        }
+        let kind = server.kind.unwrap_or(ServerKind::Tcp);
+        let ip = server.ip.unwrap_or_else(localhost_addr);
+        let addr = net::SocketAddr::new(ip, server.port);
+        let conflicts = listeners.iter().any(|&(k, a)| {
+            k == kind && a.port() == addr.port() &&
+                (a.ip() == addr.ip() || a.ip().is_unspecified() || addr.ip().is_unspecified())
+        });
+        if conflicts {
+            return Some(addr);
+        }
+        listeners.push((kind, addr));
+    }
+    None
+}
+
+/// Assembles an App from values constructed in code, rather than from a configuration
+/// file.
+///
+/// Each setting that is not set defaults as it would if it were omitted from a
+/// configuration.
+#[derive(Debug, Default)]
+pub struct AppBuilder {
+    admin_addr: Option<net::SocketAddr>,
+    grace: Option<Duration>,
+    metrics_interval: Option<Duration>,
+    metrics_log_interval: Option<Duration>,
+    admin_auth: Option<AdminAuthConfig>,
+    admin_disabled: bool,
+    admin_port_file: Option<PathBuf>,
+    admin_unix_socket: Option<PathBuf>,
+    buffer_size_bytes: Option<usize>,
+    client_to_server_buffer_bytes: Option<usize>,
+    server_to_client_buffer_bytes: Option<usize>,
+    max_buffered_bytes: Option<usize>,
+    max_upstream_connections: Option<usize>,
+    fd_high_watermark_percent: Option<usize>,
+    startup_concurrency: Option<usize>,
+    startup_quorum_percent: Option<usize>,
+    rng_seed: Option<u64>,
+    tracing: Option<TracingConfig>,
+    security: Option<SecurityConfig>,
+    allow_chaos: bool,
+    chaos: bool,
+    on_proxy_error: OnProxyError,
+    connection_handoff: bool,
+    handoff_socket: Option<PathBuf>,
+    hooks: Hooks,
+    notifier: Option<Notifier>,
+    routers: Vec<RouterBuilder>,
+    /// Set when the builder was created from a configuration.
+    config_hash: Option<String>,
+}
+
+impl AppBuilder {
+    /// Creates a builder without any routers.
+    pub fn new() -> AppBuilder {
+        AppBuilder::default()
+    }
+
+    /// Sets the address on which the admin server listens.
+    pub fn admin_addr(mut self, addr: net::SocketAddr) -> AppBuilder {
+        self.admin_addr = Some(addr);
+        self
+    }
+
+    /// Sets the time to wait for connections to complete once shutdown is triggered.
+    pub fn grace(mut self, grace: Duration) -> AppBuilder {
+        self.grace = Some(grace);
+        self
+    }
+
+    /// Sets the interval at which metrics are snapshot (and reset) for export.
+    pub fn metrics_interval(mut self, interval: Duration) -> AppBuilder {
+        self.metrics_interval = Some(interval);
+        self
+    }
+
+    /// Logs a snapshot of key metrics as JSON at the given interval.
+    pub fn metrics_log_interval(mut self, interval: Duration) -> AppBuilder {
+        self.metrics_log_interval = Some(interval);
+        self
+    }
+
+    /// Requires requests to the admin server to be authorized. See `AdminAuthConfig`.
+    pub fn admin_auth(mut self, config: AdminAuthConfig) -> AppBuilder {
+        self.admin_auth = Some(config);
+        self
+    }
+
+    /// Serves no admin server. Resolvers and metrics reporting still run, and readiness
+    /// is logged.
+    pub fn disable_admin(mut self) -> AppBuilder {
+        self.admin_disabled = true;
+        self
+    }
+
+    /// Writes the admin server's port to `path` once it is bound, so that an ephemeral
+    /// port may be discovered.
+    pub fn admin_port_file(mut self, path: PathBuf) -> AppBuilder {
+        self.admin_port_file = Some(path);
+        self
+    }
+
+    /// Serves the admin server over the unix socket at `path`, rather than over TCP.
+    pub fn admin_unix_socket(mut self, path: PathBuf) -> AppBuilder {
+        self.admin_unix_socket = Some(path);
+        self
+    }
+
+    /// Sizes the shared buffers used for transferring data in both directions.
+    pub fn buffer_size_bytes(mut self, bytes: usize) -> AppBuilder {
+        self.buffer_size_bytes = Some(bytes);
+        self
+    }
+
+    /// Sizes the shared buffer used for transferring data from clients to servers.
+    pub fn client_to_server_buffer_bytes(mut self, bytes: usize) -> AppBuilder {
+        self.client_to_server_buffer_bytes = Some(bytes);
+        self
+    }
+
+    /// Sizes the shared buffer used for transferring data from servers to clients.
+    pub fn server_to_client_buffer_bytes(mut self, bytes: usize) -> AppBuilder {
+        self.server_to_client_buffer_bytes = Some(bytes);
+        self
+    }
+
+    /// Bounds the data held, across all connections, because a peer was not ready to
+    /// accept it.
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> AppBuilder {
+        self.max_buffered_bytes = Some(bytes);
+        self
+    }
+
+    /// Bounds the upstream connections held by all routers. Connections beyond the
+    /// limit wait for another upstream connection to close.
+    pub fn max_upstream_connections(mut self, max: usize) -> AppBuilder {
+        self.max_upstream_connections = Some(max);
+        self
+    }
+
+    /// Sets the percentage of the process's file descriptor limit above which new
+    /// connections are refused.
+    pub fn fd_high_watermark_percent(mut self, pct: usize) -> AppBuilder {
+        self.fd_high_watermark_percent = Some(pct);
+        self
+    }
+
+    /// Bounds the number of proxies whose destinations are initially resolved at once.
+    pub fn startup_concurrency(mut self, n: usize) -> AppBuilder {
+        self.startup_concurrency = Some(n);
+        self
+    }
+
+    /// Sets the percentage of proxies whose destinations must be resolved before the
+    /// process is ready.
+    pub fn startup_quorum_percent(mut self, pct: usize) -> AppBuilder {
+        self.startup_quorum_percent = Some(pct);
+        self
+    }
+
+    /// Seeds the randomness used by balancers. `LINKERD_TCP_RNG_SEED` still takes
+    /// precedence.
+    pub fn rng_seed(mut self, seed: u64) -> AppBuilder {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Samples connections to be traced.
+    pub fn tracing(mut self, config: TracingConfig) -> AppBuilder {
+        self.tracing = Some(config);
+        self
+    }
+
+    /// Reduces the process's privileges once its listeners are bound. See `App`.
+    pub fn security(mut self, config: SecurityConfig) -> AppBuilder {
+        self.security = Some(config);
+        self
+    }
+
+    /// Injects the faults configured by each router's `chaos`, as `--chaos` does.
+    ///
+    /// Building fails unless the builder was created from a configuration that sets
+    /// `allowChaos: true`.
+    pub fn chaos(mut self) -> AppBuilder {
+        self.chaos = true;
+        self
+    }
+
+    /// Determines whether a proxy that fails to start prevents the app from starting
+    /// (the default) or is skipped. See `OnProxyError`.
+    pub fn on_proxy_error(mut self, policy: OnProxyError) -> AppBuilder {
+        self.on_proxy_error = policy;
+        self
+    }
+
+    /// Hands established plaintext streams to the process listening on `socket` as this
+    /// one shuts down, and resumes the streams handed to this one. Experimental. See
+    /// `ConnectionHandoff`.
+    pub fn connection_handoff(mut self, socket: PathBuf) -> AppBuilder {
+        self.connection_handoff = true;
+        self.handoff_socket = Some(socket);
+        self
+    }
+
+    /// Runs `hook` as each server's connections are accepted, dispatched, and closed.
+    ///
+    /// Hooks are run in the order in which they are added. See `lb::ConnectionHook`.
+    pub fn connection_hook<H: ConnectionHook + 'static>(mut self, hook: H) -> AppBuilder {
+        self.hooks.push(Rc::new(hook));
+        self
+    }
+
+    /// Bounds the time each connection hook may take to accept a connection before it
+    /// is rejected (1s by default).
+    pub fn hook_timeout(mut self, timeout: Duration) -> AppBuilder {
+        self.hooks.set_timeout(timeout);
+        self
+    }
+
+    /// Notifies systemd of the process's readiness and shutdown, and pings its watchdog
+    /// (see `Notifier::from_env`).
+    pub fn notifier(mut self, notifier: Notifier) -> AppBuilder {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Adds a router.
+    pub fn router(mut self, router: RouterBuilder) -> AppBuilder {
+        self.routers.push(router);
+        self
+    }
+
+    /// Validates all settings to build an App.
+    pub fn build(mut self) -> Result<App> {
+        let (root, reporter) = tacho::new();
+        let metrics = root.clone().prefixed(METRICS_PREFIX);
+
+        // Create shared transfer buffers to be used for all stream proxying. Traffic is
+        // often asymmetric, so each direction may be sized independently.
+        let bufs = {
             let sz = self.buffer_size_bytes.unwrap_or(DEFAULT_BUFFER_SIZE_BYTES);
-            Rc::new(RefCell::new(vec![0 as u8; sz]))
+            let to_server = self.client_to_server_buffer_bytes.unwrap_or(sz);
+            let to_client = self.server_to_client_buffer_bytes.unwrap_or(sz);
+            // An empty buffer would read nothing, which is indistinguishable from EOF.
+            if to_server == 0 || to_client == 0 {
+                return Err(Error::InvalidBufferSize.into());
+            }
+            // Usage is reported even when it is not bounded.
+            let max_buffered = self.max_buffered_bytes.unwrap_or(usize::max_value());
+            if max_buffered == 0 {
+                return Err(Error::InvalidMaxBufferedBytes.into());
+            }
+            let budget = BufferBudget::new(max_buffered, &metrics.clone().prefixed("process"));
+            Buffers::new(to_server, to_client, budget)
+        };
+
+        // Track file descriptor usage so that servers stop accepting connections before
+        // the process runs out of descriptors for upstream connections.
+        let fd_limit = {
+            let pct = self.fd_high_watermark_percent.unwrap_or(
+                fd::DEFAULT_HIGH_WATERMARK_PERCENT,
+            );
+            if pct == 0 || pct > 100 {
+                return Err(Error::InvalidFdHighWatermark(pct).into());
+            }
+            fd::FdLimit::new(pct)
         };
 
-        let (metrics, reporter) = tacho::new();
-        let metrics = metrics.prefixed("l5d");
+        // Upstream connections are counted across all routers, so that they may be bounded
+        // by a single limit. Usage is reported even when it is not bounded.
+        let global_limit = {
+            let max = self.max_upstream_connections.unwrap_or(usize::max_value());
+            if max == 0 {
+                return Err(Error::InvalidMaxUpstreamConnections.into());
+            }
+            let metrics = metrics::Scope::from(metrics.clone().prefixed("process"));
+            GlobalLimit::new(max, &metrics)
+        };
 
-        // Load all router configurations.
+        // Namerd-backed proxies' destinations are resolved as the process starts, a few
+        // at a time, so that its readiness may be reported.
+        let startup = {
+            let n = self.startup_concurrency.unwrap_or(DEFAULT_STARTUP_CONCURRENCY);
+            if n == 0 {
+                return Err(Error::InvalidStartupConcurrency.into());
+            }
+            let pct = self.startup_quorum_percent.unwrap_or(
+                DEFAULT_STARTUP_QUORUM_PERCENT,
+            );
+            if pct == 0 || pct > 100 {
+                return Err(Error::InvalidStartupQuorum(pct).into());
+            }
+            let metrics = metrics::Scope::from(metrics.clone().prefixed("process"));
+            Startup::new(n, pct, &metrics)
+        };
+
+        // Balancers publish their state here so that it may be inspected via the admin
+        // server.
+        let state = state::Registry::default();
+
+        // Describe the process for the admin server's `/admin/info` endpoint, and export
+        // its build as a constant gauge.
+        let info = {
+            let proxies: usize = self.routers.iter().map(|r| r.servers.len()).sum();
+            info::Info::new(proxies, self.config_hash.take())
+        };
+        let build_info = {
+            let mut scope = metrics.clone().labeled("version", info::VERSION);
+            if let Some(sha) = info::GIT_SHA {
+                scope = scope.labeled("git_sha", sha);
+            }
+            scope.gauge("build_info")
+        };
+
+        let rng_seed = match env::var(RNG_SEED_ENV) {
+            Ok(s) => s.parse::<u64>().map_err(|_| Error::InvalidRngSeed(s.clone()))?,
+            Err(_) => self.rng_seed.unwrap_or_else(rand::random),
+        };
+        info!("balancer rng seed: {}", rng_seed);
+
+        let tracer = match self.tracing {
+            None => None,
+            Some(ref t) => Some(t.mk_tracer().map_err(Error::Tracing)?),
+        };
+
+        let privileges = match self.security {
+            None => None,
+            Some(ref s) => s.mk_privileges().map_err(Error::Security)?,
+        };
+        if self.security.as_ref().map_or(false, |s| s.chroot_dir.is_some()) {
+            // Agents' sockets and reloaded certificates are opened by their configured
+            // paths while serving, which would not resolve once the root has changed.
+            let agents = self.routers.iter().flat_map(|r| r.servers.iter()).any(|s| {
+                s.tls.as_ref().map_or(false, |t| t.has_agent_identities())
+            });
+            let reloads = self.routers.iter().any(|r| {
+                r.client.as_ref().map_or(false, |c| c.reloads_tls())
+            });
+            let unsupported = if agents {
+                Some("agent identities")
+            } else if reloads {
+                Some("reloadIntervalSecs")
+            } else {
+                None
+            };
+            if let Some(u) = unsupported {
+                let e = security::Error::UnsupportedInChroot(u);
+                return Err(Error::Security(e).into());
+            }
+        }
+
+        if self.chaos && !self.allow_chaos {
+            return Err(Error::ChaosNotAllowed.into());
+        }
+
+        if self.hooks.timeout() == Duration::from_secs(0) {
+            return Err(Error::InvalidHookTimeout.into());
+        }
+        let hooks = if self.hooks.is_empty() {
+            None
+        } else {
+            Some(self.hooks.clone())
+        };
+
+        // Streams that may be handed off are registered with the process's handoffs, and
+        // resumed streams share its transfer buffers.
+        let handoffs = if self.connection_handoff {
+            if self.handoff_socket.is_none() {
+                return Err(Error::MissingHandoffSocket.into());
+            }
+            Some(handoff::Handoffs::new(bufs.clone(), &metrics.clone().prefixed("process")))
+        } else {
+            None
+        };
+
+        // Proxies that fail to start are recorded here when they are skipped.
+        let proxy_errors = ProxyErrorPolicy {
+            on_error: self.on_proxy_error,
+            errors: state.proxy_errors().clone(),
+        };
+
+        // Names are validated before anything is built, so that errors identify the
+        // server by its position in the configuration. Skipped servers are validated as
+        // they are built, instead.
+        if !proxy_errors.skips() {
+            for (i, router) in self.routers.iter().enumerate() {
+                for (j, server) in router.servers.iter().enumerate() {
+                    validate_dst_name(i, j, server)?;
+                }
+            }
+        }
+
+        let servers = self.routers.iter().flat_map(|r| r.servers.iter());
+        if let Some(addr) = duplicate_listener(servers) {
+            return Err(Error::DuplicateListener(addr).into());
+        }
+
+        // Each interpreter rewrites its whole resolution cache file, so files may not be
+        // shared. Likewise, a local resolver shares a single interpreter's resolutions.
+        let mut cache_paths = Vec::new();
+        let mut socket_paths = Vec::new();
+        for router in &self.routers {
+            if let Interpreter::Namerd(ref config) = router.interpreter {
+                if let Some(ref cache) = config.resolution_cache {
+                    if cache_paths.contains(&cache.path) {
+                        return Err(Error::DuplicateResolutionCache(cache.path.clone()).into());
+                    }
+                    cache_paths.push(cache.path.clone());
+                }
+                if let Some(ref local) = config.local_resolver {
+                    if socket_paths.contains(&local.socket_path) {
+                        let path = local.socket_path.clone();
+                        return Err(Error::DuplicateLocalResolver(path).into());
+                    }
+                    socket_paths.push(local.socket_path.clone());
+                }
+            }
+        }
+
+        // Build all routers.
         //
         // Separate resolver tasks are created to be executed in the admin thread's
         // reactor so that service discovery lookups are performed out of the serving
         // thread.
+        //
+        // Hostnames in client configurations are resolved on a pool of DNS threads that
+        // is shared by all routers, and that is only started if a hostname is resolved.
+        let dns = Dns::system();
         let mut routers = VecDeque::with_capacity(self.routers.len());
         let mut resolvers = VecDeque::with_capacity(self.routers.len());
-        for config in self.routers.drain(..) {
-            let mut r = config.into_router(buf.clone(), &metrics)?;
-            let e = r.resolver_executor.take().expect(
+        let mut readiness = self.notifier.clone().map(Readiness::new);
+        for (i, builder) in self.routers.drain(..).enumerate() {
+            // If a router cannot be built, none of its servers can be started.
+            let label = builder.label.clone();
+            let n_servers = builder.servers.len();
+            let bootstrap_timeout = match builder.interpreter {
+                Interpreter::Namerd(ref config) => Some(config.bootstrap_timeout()),
+                Interpreter::Static(_) => None,
+            };
+            let r = builder.build(
+                i,
+                bufs.clone(),
+                &fd_limit,
+                &state,
+                rng_seed,
+                tracer.clone(),
+                hooks.clone(),
+                &root,
+                &metrics,
+                &dns,
+                self.chaos,
+                &global_limit,
+                &proxy_errors,
+                handoffs.clone(),
+            );
+            let mut r = match r {
+                Ok(r) => r,
+                Err(e) => {
+                    if !proxy_errors.skips() {
+                        return Err(e);
+                    }
+                    for j in 0..n_servers {
+                        proxy_errors.record(&label, j, &e);
+                    }
+                    continue;
+                }
+            };
+            let mut e = r.resolver_executor.take().expect(
                 "router missing resolver executor",
             );
+            // The process is not ready until namerd has resolved the router's names, so
+            // they are resolved, a few at a time, before any connections are accepted.
+            if let Some(timeout) = bootstrap_timeout {
+                if let Some(readiness) = readiness.as_mut() {
+                    readiness.await_router(&label, state.summary().router(&label), timeout);
+                }
+                for &(_, ref server) in &r.servers {
+                    startup.expect_proxy(&label, server.dst_name().as_str());
+                }
+                e = e.with_startup(&startup, &label);
+                r.resolve_eagerly = true;
+            }
             routers.push_back(r);
             resolvers.push_back(e);
         }
 
-        // Read the admin server configuration and bundle it an AdminRunner.
+        // Bundle the admin server's settings in an AdminRunner.
         let admin = {
-            let addr = {
-                let ip = self.admin.as_ref().and_then(|a| a.ip).unwrap_or_else(
-                    localhost_addr,
-                );
-                let port = self.admin.as_ref().and_then(|a| a.port).unwrap_or(
-                    DEFAULT_ADMIN_PORT,
-                );
-                net::SocketAddr::new(ip, port)
-            };
-            let grace = {
-                let s = self.admin
-                    .as_ref()
-                    .and_then(|admin| admin.grace_secs)
-                    .unwrap_or(DEFAULT_GRACE_SECS);
-                Duration::from_secs(s)
+            let listen = if self.admin_disabled {
+                None
+            } else if let Some(ref path) = self.admin_unix_socket {
+                if self.admin_addr.is_some() {
+                    let e = "unixSocket may not be combined with addr, ip, or port";
+                    return Err(Error::InvalidAdminListener(e).into());
+                }
+                if self.admin_port_file.is_some() {
+                    let e = "portFile may not be combined with unixSocket";
+                    return Err(Error::InvalidAdminListener(e).into());
+                }
+                if self.admin_auth.as_ref().map_or(false, |a| a.tls.is_some()) {
+                    let e = "auth.tls may not be combined with unixSocket";
+                    return Err(Error::InvalidAdminListener(e).into());
+                }
+                Some(AdminListen::Unix(path.clone()))
+            } else {
+                let addr = self.admin_addr.unwrap_or_else(|| {
+                    net::SocketAddr::new(localhost_addr(), DEFAULT_ADMIN_PORT)
+                });
+                Some(AdminListen::Tcp(addr))
             };
-            let metrics_interval = {
-                let s = self.admin
-                    .as_ref()
-                    .and_then(|admin| admin.metrics_interval_secs)
-                    .unwrap_or(DEFAULT_METRICS_INTERVAL_SECS);
-                Duration::from_secs(s)
+            let grace = self.grace.unwrap_or_else(
+                || Duration::from_secs(DEFAULT_GRACE_SECS),
+            );
+            let metrics_interval = self.metrics_interval.unwrap_or_else(|| {
+                Duration::from_secs(DEFAULT_METRICS_INTERVAL_SECS)
+            });
+            if self.metrics_log_interval == Some(Duration::from_secs(0)) {
+                return Err(Error::InvalidMetricsLogInterval.into());
+            }
+            // Tokens and certificates are read before the process drops its privileges.
+            let auth = match self.admin_auth {
+                None => None,
+                Some(ref a) => Some(a.mk_policy().map_err(Error::AdminAuth)?),
             };
             AdminRunner {
-                addr,
+                listen,
+                port_file: self.admin_port_file,
                 reporter,
                 resolvers,
                 grace,
                 metrics_interval,
+                metrics_log_interval: self.metrics_log_interval,
+                fd_limit,
+                state,
+                transfer_buffer_bytes: bufs.total_bytes(),
+                metrics: metrics.clone().prefixed("process"),
+                info,
+                build_info,
+                listener: None,
+                notifier: self.notifier,
+                startup,
+                auth,
             }
         };
 
+        let handoff = match (handoffs, self.handoff_socket) {
+            (Some(handoffs), Some(socket)) => Some(ConnectionHandoff { socket, handoffs }),
+            _ => None,
+        };
+
         Ok(App {
             routers: routers,
             admin: admin,
+            privileges,
+            readiness,
+            handoff,
         })
     }
 }
 
-
 fn localhost_addr() -> net::IpAddr {
     net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1))
 }
@@ -164,8 +1271,64 @@ fn localhost_addr() -> net::IpAddr {
 pub struct App {
     /// Executes configured routers.
     pub routers: VecDeque<RouterSpawner>,
-    /// Executes the admin server.
+    /// Executes the admin server (unless it is disabled), resolvers, and metrics
+    /// reporting.
     pub admin: AdminRunner,
+    /// When set, should be dropped once the routers have been spawned and the admin
+    /// server is bound (see `AdminRunner::bind`), but before the admin server is run.
+    /// Everything read from files at startup has already been read.
+    pub privileges: Option<Privileges>,
+    /// Set when systemd is notified (see `AppBuilder::notifier`). Should be spawned on
+    /// the routers' reactor once the routers have been spawned and the admin server is
+    /// bound.
+    pub readiness: Option<Readiness>,
+    /// Set when streams are handed off between processes (see
+    /// `AppBuilder::connection_handoff`). Should receive streams on the routers'
+    /// reactor once the routers have been spawned, and hand them off as the process
+    /// shuts down.
+    pub handoff: Option<ConnectionHandoff>,
+}
+
+/// Hands established streams between the process that is shutting down and the process
+/// that replaces it, over a unix socket. Experimental.
+///
+/// Only plaintext streams are handed off. Others, and every stream when no process is
+/// receiving, drain as usual.
+pub struct ConnectionHandoff {
+    socket: PathBuf,
+    handoffs: handoff::Handoffs,
+}
+
+impl ConnectionHandoff {
+    /// The unix socket on which streams are received, and to which they are handed off.
+    pub fn socket(&self) -> &FsPath {
+        &self.socket
+    }
+
+    /// Receives the streams handed off by the process that this one replaces, resuming
+    /// each on `reactor`.
+    ///
+    /// Takes over the socket, replacing any that the process being replaced bound, so
+    /// that it hands its streams to this process.
+    pub fn receive(&self, reactor: &Handle, timer: &Timer) -> Result<()> {
+        if let Err(e) = fs::remove_file(&self.socket) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        let listener = StdUnixListener::bind(&self.socket)?;
+        info!("receiving streams on {}", self.socket.display());
+        self.handoffs.receive(listener, reactor, timer)?;
+        Ok(())
+    }
+
+    /// Hands this process's streams off to the process receiving on the socket. The
+    /// returned future completes once every stream has been handed off or refused.
+    ///
+    /// Fails if no process is receiving, in which case streams should drain.
+    pub fn hand_off(&self) -> Result<handoff::HandingOff> {
+        Ok(self.handoffs.hand_off(&self.socket)?)
+    }
 }
 
 /// Holds the configuration for a single stream router.
@@ -176,7 +1339,7 @@ pub struct RouterConfig {
     pub label: String,
 
     /// The configuration for one or more servers.
-    pub servers: Vec<server::ServerConfig>,
+    pub servers: Vec<ServerConfig>,
 
     /// Determines how outbound connections are initiated.
     ///
@@ -185,74 +1348,400 @@ pub struct RouterConfig {
 
     /// Interprets request destinations into a stream of address pool updates.
     pub interpreter: InterpreterConfig,
+
+    /// Scales the weights that service discovery gives endpoints, by address (e.g.
+    /// `10.0.0.5:8080: 0.1`). These may be changed or cleared via the admin server.
+    pub weight_overrides: Option<HashMap<String, f64>>,
+
+    /// Faults to inject into upstream connections when the process is started with
+    /// `--chaos`. Otherwise, this is inert.
+    pub chaos: Option<ChaosConfig>,
+
+    /// Prefixes the names of the router's metrics (its servers', balancers', and
+    /// resolver's), e.g. `team_a` for `team_a_l5d_...`. By default, the router's metrics
+    /// share the process's `l5d` namespace and are distinguished by their `rt` label.
+    pub metrics_scope: Option<String>,
+
+    /// Bounds the buckets in which each connection's transfers are counted, by
+    /// direction, as it closes (e.g. `[1024, 65536]` for transfers of less than 1KB,
+    /// less than 64KB, and more). By default, 1KB, 64KB, 1MB, and 100MB. Empty to
+    /// disable.
+    pub size_buckets: Option<Vec<u64>>,
 }
 
 impl RouterConfig {
-    /// Consumes and validates this configuration to produce a router initializer.
-    fn into_router(
+    fn schema() -> Schema {
+        let interpreter = Schema::Tagged(
+            "kind",
+            vec![
+                (
+                    "io.l5d.namerd.http",
+                    Schema::of::<NamerdConfig>(vec![
+                        ("resolutionCache", Schema::of::<ResolutionCacheConfig>(vec![])),
+                        ("localResolver", Schema::of::<LocalResolverConfig>(vec![])),
+                        (
+                            "acceptRemotePolicy",
+                            Schema::of::<AcceptRemotePolicyConfig>(vec![]),
+                        ),
+                    ]),
+                ),
+            ],
+        );
+        Schema::of::<RouterConfig>(vec![
+            ("servers", Schema::list(ServerConfig::schema())),
+            ("client", ConnectorFactoryConfig::schema()),
+            ("interpreter", interpreter),
+            ("chaos", Schema::of::<ChaosConfig>(vec![])),
+        ])
+    }
+
+    /// Converts this configuration into a builder.
+    fn into_builder(self) -> RouterBuilder {
+        let interpreter = match self.interpreter {
+            InterpreterConfig::NamerdHttp(config) => Interpreter::Namerd(config),
+        };
+        RouterBuilder {
+            label: self.label,
+            servers: self.servers,
+            client: self.client,
+            interpreter,
+            weight_overrides: self.weight_overrides.unwrap_or_default(),
+            chaos: self.chaos,
+            metrics_scope: self.metrics_scope,
+            size_buckets: self.size_buckets,
+        }
+    }
+}
+
+/// Resolves the names to which a router routes.
+#[derive(Clone, Debug)]
+pub enum Interpreter {
+    /// Polls namerd for updates, as `io.l5d.namerd.http` does.
+    Namerd(NamerdConfig),
+
+    /// Resolves each name (e.g. `/svc/web`) to a fixed set of addresses. Other names are
+    /// not bound.
+    Static(HashMap<String, Vec<WeightedAddr>>),
+}
+
+/// Assembles a router from values constructed in code.
+#[derive(Clone, Debug)]
+pub struct RouterBuilder {
+    label: String,
+    servers: Vec<ServerConfig>,
+    client: Option<ConnectorFactoryConfig>,
+    interpreter: Interpreter,
+    weight_overrides: HashMap<String, f64>,
+    chaos: Option<ChaosConfig>,
+    metrics_scope: Option<String>,
+    size_buckets: Option<Vec<u64>>,
+}
+
+impl RouterBuilder {
+    /// Creates a builder for a router without any servers. The label is used for stats
+    /// reporting.
+    pub fn new(label: &str, interpreter: Interpreter) -> RouterBuilder {
+        RouterBuilder {
+            label: label.to_owned(),
+            servers: Vec::new(),
+            client: None,
+            interpreter,
+            weight_overrides: HashMap::new(),
+            chaos: None,
+            metrics_scope: None,
+            size_buckets: None,
+        }
+    }
+
+    /// Adds a server that routes through this router.
+    pub fn server(mut self, server: ServerConfig) -> RouterBuilder {
+        self.servers.push(server);
+        self
+    }
+
+    /// Determines how outbound connections are initiated. By default, connections are
+    /// clear TCP.
+    pub fn client(mut self, client: ConnectorFactoryConfig) -> RouterBuilder {
+        self.client = Some(client);
+        self
+    }
+
+    /// Scales the weight that service discovery gives the endpoint at `addr` by
+    /// `multiplier`, as `weightOverrides` does.
+    pub fn weight_override(mut self, addr: net::SocketAddr, multiplier: f64) -> RouterBuilder {
+        self.weight_overrides.insert(addr.to_string(), multiplier);
+        self
+    }
+
+    /// Injects faults into upstream connections when the app is built with chaos
+    /// enabled (see `AppBuilder::chaos`).
+    pub fn chaos(mut self, chaos: ChaosConfig) -> RouterBuilder {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Prefixes the names of the router's metrics with `scope`, as `metricsScope` does.
+    pub fn metrics_scope(mut self, scope: &str) -> RouterBuilder {
+        self.metrics_scope = Some(scope.to_owned());
+        self
+    }
+
+    /// Counts connections' transfers in buckets bounded by `bounds`, as `sizeBuckets`
+    /// does.
+    pub fn size_buckets(mut self, bounds: Vec<u64>) -> RouterBuilder {
+        self.size_buckets = Some(bounds);
+        self
+    }
+
+    /// Validates all settings to produce a router initializer.
+    ///
+    /// The router's metrics are reported in `metrics`, unless it has a scope, in which
+    /// case they are reported in `root` under its scope.
+    fn build(
         mut self,
-        buf: Rc<RefCell<Vec<u8>>>,
+        index: usize,
+        bufs: Buffers,
+        fd_limit: &fd::FdLimit,
+        state: &state::Registry,
+        rng_seed: u64,
+        tracer: Option<tracing::Tracer>,
+        hooks: Option<Hooks>,
+        root: &tacho::Scope,
         metrics: &tacho::Scope,
+        dns: &Dns,
+        chaos: bool,
+        global_limit: &Rc<GlobalLimit>,
+        proxy_errors: &ProxyErrorPolicy,
+        handoffs: Option<handoff::Handoffs>,
     ) -> Result<RouterSpawner> {
-        let metrics = metrics.clone().labeled("rt", self.label);
+        let metrics = match self.metrics_scope {
+            None => metrics.clone(),
+            Some(ref scope) => {
+                if !is_metrics_scope(scope) {
+                    return Err(Error::InvalidMetricsScope(scope.clone()).into());
+                }
+                root.clone().prefixed(scope.clone()).prefixed(METRICS_PREFIX)
+            }
+        };
+        let metrics = metrics.labeled("rt", self.label.clone());
+        let signals = state.summary().router(&self.label);
+
+        let transfer_sizes = {
+            let bounds = self.size_buckets.as_ref().map(|b| b.as_slice()).unwrap_or(
+                server::DEFAULT_SIZE_BUCKETS,
+            );
+            if bounds.is_empty() {
+                None
+            } else {
+                let sizes = server::TransferSizes::new(bounds, &metrics);
+                Some(sizes.ok_or_else(|| Error::InvalidSizeBuckets(bounds.to_vec()))?)
+            }
+        };
+
+        // Overrides are held with those set via the admin server, which may later change
+        // or clear them.
+        for (addr, &m) in &self.weight_overrides {
+            let invalid = || Error::InvalidWeightOverride(addr.clone(), m);
+            let a = addr.parse::<net::SocketAddr>().map_err(|_| invalid())?;
+            if !state.weight_overrides().set(Some(&self.label), a, m) {
+                return Err(invalid().into());
+            }
+        }
 
         // Each router has its own resolver/executor pair. The resolver is used by the
         // router. The resolver executor is used to drive execution in another thread.
         let (resolver, resolver_exec) = match self.interpreter {
-            InterpreterConfig::NamerdHttp(config) => {
+            Interpreter::Namerd(config) => {
+                let metrics = metrics::Scope::from(metrics.clone());
+                let local = config.local_policy().map_err(Error::Interpreter)?;
+                let policy_keys = config.remote_policy_keys().map_err(Error::Interpreter)?;
                 let namerd = config.into_namerd(&metrics).map_err(Error::Interpreter)?;
-                resolver::new(namerd)
+                let mut namerd = namerd
+                    .with_cached_resolutions(state.cached_resolutions())
+                    .with_signals(&signals);
+                if let Some(keys) = policy_keys {
+                    namerd = namerd.with_remote_policy(&self.label, keys, state.remote_policies());
+                }
+                match local {
+                    None => resolver::new(namerd),
+                    Some(local) => resolver::new_local(namerd, local),
+                }
+            }
+            Interpreter::Static(names) => {
+                let mut addrs = HashMap::with_capacity(names.len());
+                for (name, a) in names {
+                    if !name.starts_with('/') {
+                        return Err(Error::InvalidStaticName(name).into());
+                    }
+                    addrs.insert(Path::from(name), a);
+                }
+                resolver::new_static(addrs)
             }
         };
 
         let balancer = {
-            let metrics = metrics.clone().prefixed("balancer");
-            let client = self.client
+            let metrics = metrics::Scope::from(metrics.clone()).prefixed("balancer");
+            // Data read ahead on pooled connections is written to clients through the
+            // server-to-client buffer, so no more is read than it holds.
+            let mut client = self.client
                 .unwrap_or_default()
-                .mk_connector_factory()
-                .map_err(Error::Connector)?;
-            BalancerFactory::new(client, &metrics)
+                .mk_connector_factory(&metrics)
+                .map_err(Error::Connector)?
+                .with_read_ahead_limit(bufs.server_to_client.borrow().len());
+            // Chaos is validated even while it is inert.
+            if let Some(ref config) = self.chaos {
+                let c = config
+                    .mk_chaos(&metrics::Scope::from(metrics.clone()))
+                    .map_err(Error::Connector)?;
+                if chaos {
+                    warn!("{}: injecting faults into upstream connections", self.label);
+                    client = client.with_chaos(c);
+                }
+            }
+            BalancerFactory::new(
+                client,
+                &self.label,
+                state,
+                rng_seed,
+                &metrics,
+                dns,
+                global_limit,
+            )
         };
         let router = router::new(resolver, balancer, &metrics);
 
         let mut servers = VecDeque::with_capacity(self.servers.len());
-        for config in self.servers.drain(..) {
-            // The router and transfer buffer are shareable across servers.
-            let server = config
-                .mk_server(router.clone(), buf.clone(), &metrics)
-                .map_err(Error::Server)?;
-            servers.push_back(server);
+        for (j, config) in self.servers.drain(..).enumerate() {
+            // The router and transfer buffers are shareable across servers.
+            let server = validate_dst_name(index, j, &config).and_then(|_| {
+                config
+                    .mk_server(
+                        router.clone(),
+                        bufs.clone(),
+                        fd_limit,
+                        tracer.clone(),
+                        hooks.clone(),
+                        &metrics,
+                        transfer_sizes.clone(),
+                        handoffs.clone(),
+                        signals.clone(),
+                    )
+                    .map_err(|e| Error::Server(e).into())
+            });
+            match server {
+                Ok(server) => servers.push_back((j, server)),
+                Err(e) => proxy_errors.skip(&self.label, j, e)?,
+            }
         }
 
         Ok(RouterSpawner {
+            label: self.label,
             servers: servers,
             resolver_executor: Some(resolver_exec),
+            proxy_errors: proxy_errors.clone(),
+            resolve_eagerly: false,
         })
     }
 }
 
 /// Spawns a router by spawning all of its serving interfaces.
 pub struct RouterSpawner {
-    servers: VecDeque<server::Unbound>,
+    label: String,
+    /// Each server, with its index in the router's configuration.
+    servers: VecDeque<(usize, server::Unbound)>,
     resolver_executor: Option<resolver::Executor>,
+    proxy_errors: ProxyErrorPolicy,
+    /// Set when each server's destination is resolved as it is spawned, rather than
+    /// when its first connection is accepted.
+    resolve_eagerly: bool,
 }
 
 impl RouterSpawner {
     /// Spawns a router by spawning all of its serving interfaces.
     ///
-    /// Returns successfully if all servers have been bound and spawned correctly.
-    pub fn spawn(mut self, reactor: &Handle, timer: &Timer) -> Result<()> {
-        while let Some(unbound) = self.servers.pop_front() {
+    /// Returns the bound address of each server if all servers have been bound and
+    /// spawned correctly, or `Error::Io` if a server could not be bound. With
+    /// `onProxyError: skip`, servers that cannot be bound are skipped instead, and only
+    /// the addresses of bound servers are returned.
+    pub fn spawn(mut self, reactor: &Handle, timer: &Timer) -> Result<Vec<net::SocketAddr>> {
+        let mut addrs = Vec::with_capacity(self.servers.len());
+        while let Some((j, unbound)) = self.servers.pop_front() {
             info!(
                 "routing on {} to {}",
                 unbound.listen_addr(),
                 unbound.dst_name()
             );
-            let bound = unbound.bind(reactor, timer).expect("failed to bind server");
-            reactor.spawn(bound.map_err(|_| {}));
+            if self.resolve_eagerly {
+                unbound.resolve(reactor, timer);
+            }
+            match unbound.bind(reactor, timer) {
+                Ok(bound) => {
+                    addrs.push(bound.local_addr());
+                    reactor.spawn(bound.map_err(|_| {}));
+                }
+                Err(e) => self.proxy_errors.skip(&self.label, j, super::Error::Io(e))?,
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+/// Applies the `onProxyError` policy to proxies that fail to start.
+#[derive(Clone)]
+struct ProxyErrorPolicy {
+    on_error: OnProxyError,
+    errors: state::ProxyErrors,
+}
+
+impl ProxyErrorPolicy {
+    fn skips(&self) -> bool {
+        self.on_error == OnProxyError::Skip
+    }
+
+    /// Fails with `e`, unless failed proxies are skipped, in which case `e` is recorded.
+    fn skip(&self, router: &str, server: usize, e: super::Error) -> Result<()> {
+        if !self.skips() {
+            return Err(e);
         }
+        self.record(router, server, &e);
         Ok(())
     }
+
+    fn record(&self, router: &str, server: usize, e: &super::Error) {
+        error!(
+            "{}: skipping server {}, which failed to start: {}",
+            router,
+            server,
+            e
+        );
+        self.errors.record(router, server, e.to_string());
+    }
+}
+
+/// Determines whether `scope` may prefix metric names.
+fn is_metrics_scope(scope: &str) -> bool {
+    let word = |c: char| c == '_' || (c.is_ascii() && c.is_alphanumeric());
+    match scope.chars().next() {
+        Some(c) if word(c) && !c.is_digit(10) => scope.chars().all(word),
+        _ => false,
+    }
+}
+
+/// Fails unless the server's `dstName`, if it has one, begins with `/` and has no
+/// leading or trailing whitespace.
+fn validate_dst_name(router: usize, server: usize, config: &ServerConfig) -> Result<()> {
+    if let Some(ref name) = config.dst_name {
+        if !name.starts_with('/') || name.trim() != name {
+            return Err(
+                Error::InvalidDstName {
+                    router,
+                    server,
+                    dst_name: name.clone(),
+                }.into(),
+            );
+        }
+    }
+    Ok(())
 }
 
 /// Configures an interpreter.
@@ -270,78 +1759,361 @@ pub enum InterpreterConfig {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct AdminConfig {
-    /// The port on which the admin server listens.
+    /// When false, no admin server is served. Defaults to true.
+    pub enabled: Option<bool>,
+
+    /// The port on which the admin server listens. When 0, an ephemeral port is bound.
     pub port: Option<u16>,
 
     /// The IP address on which the admin server listens.
     pub ip: Option<net::IpAddr>,
 
+    /// The address on which the admin server listens, overriding `ip` and `port`.
+    pub addr: Option<net::SocketAddr>,
+
+    /// When set, the admin server's port is written to this file once it is bound.
+    pub port_file: Option<PathBuf>,
+
+    /// When set, the admin server is served over the unix socket at this path rather
+    /// than over TCP.
+    pub unix_socket: Option<PathBuf>,
+
     /// The interval at which metrics should be snapshot (and reset) for export.
-    pub metrics_interval_secs: Option<u64>,
+    pub metrics_interval_secs: Option<Secs>,
 
     /// The amount of time to wait for connections to complete between the /admin/shutdown
     /// endpoint being triggered and the process exiting.
-    pub grace_secs: Option<u64>,
+    pub grace_secs: Option<Secs>,
+
+    /// When set, requests to the admin server must be authorized.
+    pub auth: Option<AdminAuthConfig>,
+}
+
+impl AdminConfig {
+    fn schema() -> Schema {
+        let tls = Schema::of::<AdminTlsConfig>(
+            vec![("clientAuth", Schema::of::<AdminClientAuthConfig>(vec![]))],
+        );
+        Schema::of::<AdminConfig>(vec![("auth", Schema::of::<AdminAuthConfig>(vec![("tls", tls)]))])
+    }
+}
+
+/// Configures metrics reporting outside of the admin server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct MetricsConfig {
+    /// The interval at which a snapshot of key metrics is logged as JSON.
+    ///
+    /// Each log reflects the most recent metrics snapshot (see `metricsIntervalSecs`).
+    pub log_interval_secs: Option<Secs>,
+}
+
+/// Where the admin server is served.
+#[derive(Clone, Debug)]
+enum AdminListen {
+    Tcp(net::SocketAddr),
+    Unix(PathBuf),
+}
+
+/// The admin server's bound listener.
+enum AdminListener {
+    Tcp(net::TcpListener),
+    Unix(StdUnixListener),
 }
 
 /// Spawns resolvers before running .
 pub struct AdminRunner {
-    addr: net::SocketAddr,
+    /// Unset when the admin server is disabled.
+    listen: Option<AdminListen>,
+    port_file: Option<PathBuf>,
     reporter: tacho::Reporter,
     resolvers: VecDeque<resolver::Executor>,
     grace: Duration,
     metrics_interval: Duration,
+    metrics_log_interval: Option<Duration>,
+    fd_limit: fd::FdLimit,
+    state: state::Registry,
+    transfer_buffer_bytes: usize,
+    metrics: tacho::Scope,
+    info: info::Info,
+    build_info: tacho::Gauge,
+    /// Set when the admin server has been bound before it is spawned.
+    listener: Option<AdminListener>,
+    /// Notified when the process begins to drain.
+    notifier: Option<Notifier>,
+    startup: Startup,
+    /// Set when requests to the admin server must be authorized.
+    auth: Option<admin::AuthPolicy>,
 }
 
 impl AdminRunner {
-    /// Runs the admin server on the provided reactor.
+    /// Binds the admin server's listener without serving it, so that the listener may
+    /// be bound before the process drops its privileges. Otherwise, the listener is
+    /// bound when the admin server is spawned.
+    ///
+    /// Returns the address of a TCP listener, so that an ephemeral port may be
+    /// discovered. Nothing is bound when the admin server is disabled, and no address is
+    /// returned when it is served over a unix socket.
+    pub fn bind(&mut self) -> Result<Option<net::SocketAddr>> {
+        if self.listener.is_none() {
+            self.listener = match self.listen {
+                None => return Ok(None),
+                Some(AdminListen::Tcp(addr)) => {
+                    let listener = net::TcpListener::bind(addr).map_err(super::Error::Io)?;
+                    let bound = listener.local_addr().map_err(super::Error::Io)?;
+                    info!("admin listening on http://{}.", bound);
+                    if let Some(ref path) = self.port_file {
+                        write_port_file(path, bound.port()).map_err(super::Error::Io)?;
+                    }
+                    Some(AdminListener::Tcp(listener))
+                }
+                Some(AdminListen::Unix(ref path)) => {
+                    let listener = bind_unix(path).map_err(super::Error::Io)?;
+                    info!("admin listening on {}.", path.display());
+                    Some(AdminListener::Unix(listener))
+                }
+            };
+        }
+        match self.listener {
+            Some(AdminListener::Tcp(ref l)) => l.local_addr().map(Some).map_err(super::Error::Io),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns a handle to the endpoints ejected by operators, as are managed via the
+    /// admin server's `/admin/endpoints/{addr}/{eject,reinstate}` endpoints.
+    pub fn ejections(&self) -> state::Ejections {
+        self.state.ejections().clone()
+    }
+
+    /// Returns a handle to the endpoint weight overrides set by configuration or by
+    /// operators, as are managed via the admin server's
+    /// `/admin/endpoints/{addr}/weight-multiplier` endpoint.
+    pub fn weight_overrides(&self) -> state::WeightOverrides {
+        self.state.weight_overrides().clone()
+    }
+
+    /// Returns a handle to the balancers' states, as are served by the admin server's
+    /// `/state` endpoint.
+    pub fn state(&self) -> state::Registry {
+        self.state.clone()
+    }
+
+    /// Returns the process's description, as is served by the admin server's
+    /// `/admin/info` endpoint.
+    pub fn info(&self) -> info::Info {
+        self.info.clone()
+    }
+
+    /// Returns a handle to the progress of the proxies' startup, as is reported by the
+    /// admin server's `/ready` endpoint.
+    pub fn startup(&self) -> Startup {
+        self.startup.clone()
+    }
+
+    /// Runs the admin server on the provided reactor, failing if its listener fails.
     ///
     /// When the _shutdown_ endpoint is triggered, a shutdown deadline is sent on
     /// `closer`.
     pub fn run(self, closer: Closer, reactor: &mut Core, timer: &Timer) -> Result<()> {
+        let handle = reactor.handle();
+        let (_, serving) = self.start(closer, &handle, timer)?;
+        reactor.run(serving).map_err(super::Error::Io)
+    }
+
+    /// Spawns the admin server, resolvers, and metrics reporting on the provided
+    /// reactor. A failure of the admin server's listener is logged.
+    ///
+    /// The returned `MetricsExporter` may be used to read metrics without going through
+    /// the admin server.
+    pub fn spawn(self, closer: Closer, handle: &Handle, timer: &Timer) -> Result<MetricsExporter> {
+        let (exporter, serving) = self.start(closer, handle, timer)?;
+        handle.spawn(serving.map_err(|err| {
+            error!("admin server failed: {}", err);
+        }));
+        Ok(exporter)
+    }
+
+    /// Spawns resolvers and metrics reporting on the provided reactor, returning the
+    /// admin server's listener, which completes only if it fails. Without an admin
+    /// server, the listener never completes.
+    fn start(
+        mut self,
+        closer: Closer,
+        handle: &Handle,
+        timer: &Timer,
+    ) -> Result<(MetricsExporter, AdminServing)> {
+        self.bind()?;
         let AdminRunner {
-            addr,
             grace,
             metrics_interval,
-            mut reporter,
+            metrics_log_interval,
+            reporter,
             mut resolvers,
+            fd_limit,
+            state,
+            transfer_buffer_bytes,
+            metrics,
+            info,
+            build_info,
+            listener,
+            notifier,
+            startup,
+            auth,
+            ..
         } = self;
 
-        let handle = reactor.handle();
         while let Some(resolver) = resolvers.pop_front() {
-            handle.spawn(resolver.execute(&handle, timer));
+            handle.spawn(resolver.execute(handle, timer));
         }
 
-        let prom_export = Rc::new(RefCell::new(String::with_capacity(8 * 1024)));
+        handle.spawn(fd_limit.clone().monitor(timer, &metrics));
+        handle.spawn(state.summary().reporter(&state).run(timer));
+
+        let exporter = MetricsExporter::new(reporter);
         let reporting = {
-            let prom_export = prom_export.clone();
+            let exporter = exporter.clone();
+            // The transfer buffers are shared by all streams in both directions. Their
+            // size is fixed, but it is reported with each snapshot.
+            let buffer_bytes = metrics.gauge("transfer_buffer_bytes");
+            buffer_bytes.set(transfer_buffer_bytes);
+            // Proxies are skipped before the admin server is spawned, but the count is
+            // reported with each snapshot, as the transfer buffers' size is.
+            let proxy_errors = state.proxy_errors().clone();
+            let failed_proxies = metrics.gauge("failed_proxies");
+            failed_proxies.set(proxy_errors.len());
+            build_info.set(1);
             timer.interval(metrics_interval).map_err(|_| {}).for_each(
                 move |_| {
-                    let report = reporter.take();
-                    let mut prom_export = prom_export.borrow_mut();
-                    prom_export.clear();
-                    tacho::prometheus::write(&mut *prom_export, &report)
-                        .expect("error foramtting metrics for prometheus");
+                    buffer_bytes.set(transfer_buffer_bytes);
+                    failed_proxies.set(proxy_errors.len());
+                    build_info.set(1);
+                    exporter.export();
                     Ok(())
                 },
             )
         };
-
         handle.spawn(reporting);
 
+        // Signaled when the admin server initiates shutdown.
+        let (draining_tx, draining_rx) = unsync::oneshot::channel();
+
+        if let Some(interval) = metrics_log_interval {
+            let exporter = exporter.clone();
+            let mut log = metrics_log::MetricsLog::default();
+            let logging = timer.interval(interval).map_err(|_| {}).for_each(move |_| {
+                info!("metrics {}", log.snapshot(&exporter.prometheus()));
+                Ok(())
+            });
+            // Stop logging once the process begins to drain. Without an admin server, the
+            // process never drains.
+            let draining = draining_rx.or_else(|_| future::empty());
+            let logging = logging.select(draining).then(|_| Ok(()));
+            handle.spawn(logging);
+        }
+
+        let listener = match listener {
+            Some(listener) => listener,
+            None => {
+                info!("admin server disabled; readiness is logged");
+                handle.spawn(log_readiness(startup, timer));
+                // Nothing initiates shutdown without an admin server, so the process runs
+                // until it is killed.
+                handle.spawn(future::empty().then(move |_: ::std::result::Result<(), ()>| {
+                    drop(closer);
+                    Ok(())
+                }));
+                return Ok((exporter, Box::new(future::empty::<(), io::Error>())));
+            }
+        };
+
         let serving = {
-            let listener = {
-                info!("admin listening on http://{}.", addr);
-                TcpListener::bind(&addr, &handle).expect("unable to listen")
-            };
+            // The admin server speaks TLS when its auth configures it.
+            #[cfg(feature = "tls")]
+            let tls = auth.as_ref().and_then(|a| a.tls().cloned());
+            let auth = auth.map(|a| a.bind(&metrics));
 
             let serve_handle = handle.clone();
-            let server =
-                admin::Admin::new(prom_export, closer, grace, handle.clone(), timer.clone());
-            let http = Http::<hyper::Chunk>::new();
+            let timer = timer.clone();
+            let server = admin::Admin::new(
+                exporter.prometheus.clone(),
+                closer,
+                draining_tx,
+                grace,
+                fd_limit,
+                state,
+                handle.clone(),
+                timer.clone(),
+                info,
+                notifier,
+                startup,
+                auth,
+            );
+            let listener = match listener {
+                AdminListener::Tcp(l) => {
+                    let addr = l.local_addr().map_err(super::Error::Io)?;
+                    TcpListener::from_listener(l, &addr, handle).map_err(super::Error::Io)?
+                }
+                AdminListener::Unix(l) => {
+                    let listener = UnixListener::from_listener(l, handle)
+                        .map_err(super::Error::Io)?;
+                    let serving = listener.incoming()
+                        .for_each(move |(conn, _)| {
+                            // Unix clients are local; the socket's permissions determine
+                            // which may connect.
+                            let client = admin::Client {
+                                ip: localhost_addr(),
+                                certified: false,
+                            };
+                            let serve = Http::<hyper::Chunk>::new()
+                                .serve_connection(conn, server.for_client(client))
+                                .map_err(|err| {
+                                    error!("error serving admin: {:?}", err);
+                                })
+                                .map(|_| ());
+                            serve_handle.spawn(serve);
+                            Ok(())
+                        });
+                    return Ok((exporter, Box::new(serving)));
+                }
+            };
             listener.incoming()
-                .for_each(move |(tcp, _)| {
-                    let serve = http.serve_connection(tcp, server.clone())
+                .for_each(move |(tcp, peer)| {
+                    let client = admin::Client {
+                        ip: peer.ip(),
+                        certified: false,
+                    };
+                    #[cfg(feature = "tls")]
+                    {
+                        if let Some(ref tls) = tls {
+                            let server = server.clone();
+                            let handshake = secure::server_handshake(tcp, tls);
+                            let handshake = timeout(
+                                handshake,
+                                Some(Duration::from_secs(ADMIN_HANDSHAKE_TIMEOUT_SECS)),
+                                &timer,
+                            );
+                            let serve = handshake
+                                .map_err(move |e| debug!("admin handshake with {}: {}", peer, e))
+                                .and_then(move |tls| {
+                                    let client = admin::Client {
+                                        certified: tls.has_client_certificate(),
+                                        ..client
+                                    };
+                                    let server = server.for_client(client);
+                                    Http::<hyper::Chunk>::new()
+                                        .serve_connection(tls, server)
+                                        .map_err(|err| {
+                                            error!("error serving admin: {:?}", err);
+                                        })
+                                        .map(|_| ())
+                                });
+                            serve_handle.spawn(serve);
+                            return Ok(());
+                        }
+                    }
+                    let serve = Http::<hyper::Chunk>::new()
+                        .serve_connection(tcp, server.for_client(client))
                         .map_err(|err| {
                             error!("error serving admin: {:?}", err);
                         })
@@ -351,8 +2123,82 @@ impl AdminRunner {
                 })
         };
 
-        reactor.run(serving).unwrap();
+        Ok((exporter, Box::new(serving)))
+    }
+}
 
-        Ok(())
+/// Serves the admin server's listener, failing if it fails.
+type AdminServing = Box<Future<Item = (), Error = io::Error>>;
+
+/// Binds the unix socket at `path`, replacing a socket left behind by a process that
+/// has exited.
+fn bind_unix(path: &FsPath) -> io::Result<StdUnixListener> {
+    match StdUnixListener::bind(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+            match StdUnixStream::connect(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    fs::remove_file(path)?;
+                    StdUnixListener::bind(path)
+                }
+                _ => {
+                    let msg = format!("{} is in use", path.display());
+                    Err(io::Error::new(io::ErrorKind::AddrInUse, msg))
+                }
+            }
+        }
+        res => res,
+    }
+}
+
+/// Writes an admin server's port to `path`, followed by a newline.
+fn write_port_file(path: &FsPath, port: u16) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    writeln!(file, "{}", port)
+}
+
+/// Logs once the process is ready, in place of the admin server's `/ready` endpoint.
+fn log_readiness(startup: Startup, timer: &Timer) -> Box<Future<Item = (), Error = ()>> {
+    let ready = timer
+        .interval(Duration::from_secs(READINESS_LOG_INTERVAL_SECS))
+        .map_err(|_| {})
+        .take_while(move |_| {
+            if !startup.is_ready() {
+                return Ok(true);
+            }
+            let (ready, proxies) = startup.progress();
+            info!("ready: {} of {} proxies resolved", ready, proxies);
+            Ok(false)
+        })
+        .for_each(|_| Ok(()));
+    Box::new(ready)
+}
+
+/// Exports snapshots of the process's metrics.
+#[derive(Clone)]
+pub struct MetricsExporter {
+    reporter: Rc<RefCell<tacho::Reporter>>,
+    prometheus: Rc<RefCell<String>>,
+}
+
+impl MetricsExporter {
+    fn new(reporter: tacho::Reporter) -> MetricsExporter {
+        MetricsExporter {
+            reporter: Rc::new(RefCell::new(reporter)),
+            prometheus: Rc::new(RefCell::new(String::with_capacity(8 * 1024))),
+        }
+    }
+
+    /// Snapshots (and resets) metrics, formatting them for prometheus.
+    pub fn export(&self) {
+        let report = self.reporter.borrow_mut().take();
+        let mut prometheus = self.prometheus.borrow_mut();
+        prometheus.clear();
+        tacho::prometheus::write(&mut *prometheus, &report)
+            .expect("error foramtting metrics for prometheus");
+    }
+
+    /// Returns the most recent snapshot, formatted for prometheus.
+    pub fn prometheus(&self) -> String {
+        self.prometheus.borrow().clone()
     }
 }
@@ -0,0 +1,195 @@
+//! A destination-level circuit breaker.
+//!
+//! The failure rate of upstream connection establishment is tracked over a sliding
+//! window. When it crosses a threshold, the circuit opens and new connections are
+//! rejected without dialing an endpoint. After a cooling-off period the circuit
+//! half-opens, allowing a fraction of connections through to probe the destination: a
+//! successful probe closes the circuit and a failed probe opens it again.
+
+use super::SharedRng;
+use super::super::Path;
+use super::super::connector::CircuitBreakerPolicy;
+use super::super::metrics;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub struct CircuitBreaker {
+    dst_name: Path,
+    policy: CircuitBreakerPolicy,
+    state: State,
+    window: Window,
+    rng: SharedRng,
+    state_gauge: Arc<metrics::Gauge>,
+    rejections: Arc<metrics::Counter>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Closed,
+    Open(Instant),
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        dst_name: Path,
+        policy: CircuitBreakerPolicy,
+        rng: SharedRng,
+        metrics: &metrics::Scope,
+    ) -> CircuitBreaker {
+        let metrics = metrics.clone().prefixed("circuit");
+        CircuitBreaker {
+            dst_name,
+            window: Window::new(policy.window),
+            policy,
+            state: State::Closed,
+            rng,
+            state_gauge: metrics.gauge("state"),
+            rejections: metrics.counter("rejections"),
+        }
+    }
+
+    /// Determines whether a new connection may be dispatched.
+    pub fn allow(&mut self) -> bool {
+        if let State::Open(until) = self.state {
+            if until <= Instant::now() {
+                self.transition(State::HalfOpen);
+            }
+        }
+
+        let allowed = match self.state {
+            State::Closed => true,
+            State::Open(_) => false,
+            State::HalfOpen => self.rng.borrow_mut().gen::<f64>() < self.policy.probe_ratio,
+        };
+        if !allowed {
+            self.rejections.incr(1);
+        }
+        allowed
+    }
+
+    /// Records the outcome of a connection attempt.
+    pub fn record(&mut self, success: bool) {
+        let now = Instant::now();
+        match self.state {
+            State::HalfOpen => {
+                if success {
+                    self.window.clear();
+                    self.transition(State::Closed);
+                } else {
+                    self.transition(State::Open(now + self.policy.open_duration));
+                }
+            }
+
+            // Attempts initiated before the circuit opened may still complete.
+            State::Open(_) => {}
+
+            State::Closed => {
+                self.window.record(now, success);
+                let (successes, failures) = self.window.totals(now);
+                let total = successes + failures;
+                if total >= self.policy.min_requests &&
+                    failures as f64 / total as f64 >= self.policy.failure_rate_threshold
+                {
+                    self.transition(State::Open(now + self.policy.open_duration));
+                }
+            }
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            State::Closed => "closed",
+            State::Open(_) => "open",
+            State::HalfOpen => "half-open",
+        }
+    }
+
+    fn transition(&mut self, state: State) {
+        self.state = state;
+        match state {
+            State::Closed => {
+                info!("{}: circuit closed", self.dst_name);
+                self.state_gauge.set(0);
+            }
+            State::HalfOpen => {
+                info!("{}: circuit half-open", self.dst_name);
+                self.state_gauge.set(1);
+            }
+            State::Open(_) => {
+                warn!(
+                    "{}: circuit open for {}s",
+                    self.dst_name,
+                    self.policy.open_duration.as_secs()
+                );
+                self.state_gauge.set(2);
+            }
+        }
+    }
+}
+
+/// Counts successes and failures over a sliding window of one-second buckets.
+struct Window {
+    width: Duration,
+    buckets: VecDeque<Bucket>,
+}
+
+struct Bucket {
+    start: Instant,
+    successes: usize,
+    failures: usize,
+}
+
+impl Window {
+    fn new(width: Duration) -> Window {
+        Window {
+            width,
+            buckets: VecDeque::with_capacity(width.as_secs() as usize + 1),
+        }
+    }
+
+    fn record(&mut self, now: Instant, success: bool) {
+        let current = match self.buckets.back() {
+            Some(b) => now < b.start + Duration::from_secs(1),
+            None => false,
+        };
+        if !current {
+            self.buckets.push_back(Bucket {
+                start: now,
+                successes: 0,
+                failures: 0,
+            });
+        }
+
+        let bucket = self.buckets.back_mut().unwrap();
+        if success {
+            bucket.successes += 1;
+        } else {
+            bucket.failures += 1;
+        }
+    }
+
+    fn totals(&mut self, now: Instant) -> (usize, usize) {
+        while self.buckets
+            .front()
+            .map(|b| b.start + self.width <= now)
+            .unwrap_or(false)
+        {
+            self.buckets.pop_front();
+        }
+
+        let mut successes = 0;
+        let mut failures = 0;
+        for b in &self.buckets {
+            successes += b.successes;
+            failures += b.failures;
+        }
+        (successes, failures)
+    }
+
+    fn clear(&mut self) {
+        self.buckets.clear();
+    }
+}
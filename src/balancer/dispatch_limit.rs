@@ -0,0 +1,56 @@
+//! Bounds the connections to a destination that may be waiting for or establishing an
+//! upstream connection at once.
+//!
+//! Each destination has its own limit, so that a destination whose endpoints are slow
+//! to accept connections sheds its own excess connections rather than holding up
+//! connections to other destinations.
+
+use super::super::metrics;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub struct DispatchLimit {
+    max: usize,
+    dispatching: Cell<usize>,
+    gauge: Arc<metrics::Gauge>,
+    shed: Arc<metrics::Counter>,
+}
+
+impl DispatchLimit {
+    /// Reports the number of connections being dispatched as `dispatching`, and counts
+    /// shed connections as `dispatch_shed`.
+    pub fn new(max: usize, metrics: &metrics::Scope) -> Rc<DispatchLimit> {
+        Rc::new(DispatchLimit {
+            max,
+            dispatching: Cell::new(0),
+            gauge: metrics.gauge("dispatching"),
+            shed: metrics.counter("dispatch_shed"),
+        })
+    }
+
+    /// Admits a connection to be dispatched, unless the destination is at its limit.
+    ///
+    /// The connection counts toward the limit until the returned permit is dropped.
+    pub fn acquire(limit: &Rc<DispatchLimit>) -> Option<Permit> {
+        let n = limit.dispatching.get();
+        if n >= limit.max {
+            limit.shed.incr(1);
+            return None;
+        }
+        limit.dispatching.set(n + 1);
+        limit.gauge.incr(1);
+        Some(Permit(limit.clone()))
+    }
+}
+
+/// Holds a connection's place within its destination's dispatch limit.
+pub struct Permit(Rc<DispatchLimit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let n = self.0.dispatching.get();
+        self.0.dispatching.set(n - 1);
+        self.0.gauge.decr(1);
+    }
+}
@@ -1,17 +1,71 @@
-use super::{Endpoints, EndpointMap, Waiter, WeightedAddr};
+use super::{EndpointMap, Endpoints, Request, SharedRng, Waiter, WeightedAddr};
+use super::circuit::CircuitBreaker;
 use super::endpoint::{self, Endpoint};
+use super::ewma::{self, Scorer};
+use super::fallback::Fallback;
+use super::generation::Generations;
+use super::global_limit::GlobalLimit;
+use super::sticky::{Outcome, StickyTable};
 use super::super::Path;
-use super::super::connection::Connection;
-use super::super::connector::Connector;
+use super::super::damping::Damper;
+use super::super::connector::{Connector, EndpointFilter, Ewma, FailFast, Locality,
+                               PoolPolicy, Rebalance, RemotePolicy, SlowStart, Subsetting,
+                               WeightMode};
+use super::super::dns::Dns;
+use super::super::log_limit::LogLimit;
+use super::super::metrics;
 use super::super::resolver::Resolve;
-use futures::{Future, Stream, Poll, Async};
-use rand::{self, Rng};
-use std::collections::VecDeque;
-use std::io;
-use std::time::{Duration, Instant};
-use tacho;
+use super::super::state;
+use futures::{Future, Stream, Poll, Async, unsync};
+use rand::{self, Rng, SeedableRng, StdRng};
+use std::{cmp, io, net};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio_core::reactor::Handle;
-use tokio_timer::Timer;
+use tokio_timer::{Interval, Sleep, Timer};
+
+/// Limits how often balancer state is published to the admin server.
+const STATE_REPORT_INTERVAL_SECS: u64 = 1;
+
+/// Determines how often idle pooled connections are reaped.
+const POOL_SWEEP_INTERVAL_SECS: u64 = 1;
+
+/// Limits how often changes to the set of filtered addresses are logged.
+const FILTER_LOG_INTERVAL_SECS: u64 = 10;
+
+/// Determines how often summaries of suppressed connection failures are logged.
+const FAILURE_LOG_FLUSH_INTERVAL_SECS: u64 = 1;
+
+/// Bounds the random delay before a destination's `min_connections` are first
+/// established, so that the destinations of a process that is starting do not all
+/// connect at once.
+const MAX_PREWARM_JITTER_MS: u64 = 1_000;
+
+/// Bounds the endpoints described by each traced selection.
+const MAX_TRACED_CANDIDATES: usize = 64;
+
+/// Bounds the distinct `meta` labels of each destination's endpoint metrics.
+const MAX_META_LABELS: usize = 100;
+
+/// Labels the endpoint metrics of endpoints beyond `MAX_META_LABELS`.
+const OVERFLOW_META_LABEL: &'static str = "other";
+
+/// What a destination's balancer shares with its dispatcher, and with each dispatcher
+/// that replaces one that panicked.
+#[derive(Clone)]
+pub struct Shared {
+    /// When set, tracks connection failures across all endpoints.
+    pub breaker: Option<Rc<RefCell<CircuitBreaker>>>,
+    /// Provides all of the balancer's randomness.
+    pub rng: SharedRng,
+    pub dns: Dns,
+    /// When set, bounds the upstream connections held across all of the process's
+    /// dispatchers.
+    pub global_limit: Option<Rc<GlobalLimit>>,
+}
 
 pub fn new<S>(
     reactor: Handle,
@@ -21,11 +75,47 @@ pub fn new<S>(
     resolve: Resolve,
     waiters_rx: S,
     endpoints: Endpoints,
-    metrics: &tacho::Scope,
+    shared: &Shared,
+    state: state::Reporter,
+    metrics: &metrics::Scope,
 ) -> Dispatcher<S>
 where
-    S: Stream<Item = Waiter>,
+    S: Stream<Item = Request>,
 {
+    let rng = shared.rng.clone();
+    let pool = connector.pool().clone();
+    let endpoint_metrics = connector.endpoint_metrics();
+    let fallback = connector.fallback().cloned().map(|policy| {
+        Fallback::new(dst_name.clone(), policy, timer.clone(), &shared.dns, metrics)
+    });
+    let accounting = {
+        let conn = metrics.clone().prefixed("connection");
+        endpoint::Accounting {
+            duration: conn.timer_ms("duration_ms"),
+            stats_window: connector.stats_window(),
+            backoff: connector.connect_backoff().cloned(),
+            rng: rng.clone(),
+            failure_log: Rc::new(RefCell::new(LogLimit::new(1, connector.log_suppress()))),
+            errors: metrics.counter("state_accounting_errors"),
+            first_byte: endpoint::FirstByteMetrics {
+                latency: conn.timer_ms("first_byte_ms"),
+                no_response: conn.counter("no_response"),
+            },
+        }
+    };
+    let pool_sweep = pool.idle_timeout.map(|_| {
+        timer.interval(Duration::from_secs(POOL_SWEEP_INTERVAL_SECS))
+    });
+    let rebalance = connector.rebalance().cloned();
+    let rebalance_check = rebalance.map(|r| timer.interval(r.check_interval));
+    let sticky = connector.stickiness().map(|s| StickyTable::new(s, metrics));
+    let selection_trace = connector.selection_trace();
+    let prewarm_delay = if connector.min_connections() > 0 {
+        let jitter = rng.borrow_mut().gen_range(0, MAX_PREWARM_JITTER_MS);
+        Some(timer.sleep(Duration::from_millis(jitter)))
+    } else {
+        None
+    };
     Dispatcher {
         reactor,
         timer,
@@ -34,13 +124,54 @@ where
         resolve,
         waiters_rx,
         max_waiters: connector.max_waiters(),
+        propagate_sni: connector.propagates_sni(),
         min_connections: connector.min_connections(),
-        fail_limit: connector.failure_limit(),
-        fail_penalty: connector.failure_penalty(),
+        prewarm_delay,
+        fail_fast: connector.fail_fast().clone(),
+        backoff_wakeup: None,
+        all_backing_off: false,
+        damper: connector.update_damping().map(|d| Damper::new(*d)),
+        damping_wakeup: None,
+        locality: connector.locality().cloned(),
+        endpoint_filter: connector.endpoint_filter().cloned(),
+        filtered: HashSet::new(),
+        next_filter_log: Instant::now(),
+        subsetting: connector.subsetting().cloned(),
+        subset: None,
+        slow_start: connector.slow_start().cloned(),
+        weight_mode: connector.weight_mode(),
+        ewma: connector.ewma().cloned(),
+        accounting,
+        next_failure_flush: Instant::now(),
+        rebalance,
+        rebalance_check,
+        resolution_error: None,
+        generations: Generations::default(),
+        breaker: shared.breaker.clone(),
+        fallback,
+        global_limit: shared.global_limit.clone(),
+        sticky,
+        selection_trace,
+        pool,
+        pool_sweep,
+        read_ahead: connector.read_ahead(),
+        configured: connector.clone(),
+        remote_policy: RemotePolicy::default(),
         connector,
         connecting: VecDeque::default(),
         connected: VecDeque::default(),
         waiters: VecDeque::default(),
+        sessions: VecDeque::default(),
+        ejected: HashSet::new(),
+        weight_multipliers: HashMap::new(),
+        state,
+        next_state_report: Instant::now(),
+        rng,
+        endpoint_metrics: if endpoint_metrics {
+            Some(EndpointMetrics::new(metrics, connector.meta_labels()))
+        } else {
+            None
+        },
         metrics: Metrics::new(metrics),
     }
 }
@@ -56,29 +187,80 @@ pub struct Dispatcher<W> {
     /// Handles destination-specific connection policy.
     connector: Connector,
 
+    /// The destination's connection policy as it is configured, over which remote policy
+    /// overrides are applied.
+    configured: Connector,
+
+    /// The overrides most recently applied from the destination's remote policy.
+    remote_policy: RemotePolicy,
+
     /// Provides new service discovery resolutions as a Stream.
     resolve: Resolve,
 
     /// Holds the state of all available/failed/retired endpoints.
     endpoints: Endpoints,
 
-    /// Limits the number of consecutive failures allowed before an endpoint is marked as
-    /// failed.
-    fail_limit: usize,
+    /// Controls when endpoints are marked as failed, how long they are penalized, and how
+    /// many successful connections are required before they are fully reinstated.
+    fail_fast: FailFast,
 
-    /// Controls how long an endpoint will be marked as failed before being considered for
-    /// new connections.s
-    fail_penalty: Duration,
+    /// Wakes the dispatcher when waiters are pending and all endpoints are backing off.
+    backoff_wakeup: Option<Sleep>,
+
+    /// Set while waiters are pending and all endpoints are backing off, so that each such
+    /// period is counted as `unavailable` once, however often the dispatcher is polled.
+    all_backing_off: bool,
+
+    /// When set, coalesces resolutions, and freezes endpoints while they flap.
+    damper: Option<Damper>,
+
+    /// Wakes the dispatcher when a damped resolution is due to be applied.
+    damping_wakeup: Option<Sleep>,
 
     /// Controls the minimum number of connecting/connected connections to be maintained
     /// at all times.
     min_connections: usize,
 
+    /// Set until `min_connections` are first established, after a random delay. Waiters
+    /// are served in the meantime.
+    prewarm_delay: Option<Sleep>,
+
+    /// When set, endpoints in the local zone are preferred.
+    locality: Option<Locality>,
+
+    /// When set, tracks connection failures across all endpoints.
+    breaker: Option<Rc<RefCell<CircuitBreaker>>>,
+
+    /// When set, static endpoints are used while no resolved endpoints are available.
+    fallback: Option<Fallback>,
+
+    /// When set, bounds the upstream connections held across all of the process's
+    /// dispatchers. Waiters remain queued while the limit is reached.
+    global_limit: Option<Rc<GlobalLimit>>,
+
+    /// When set, clients are preferably dispatched to the endpoints to which they were
+    /// last dispatched.
+    sticky: Option<StickyTable>,
+
+    /// The fraction of endpoint selections whose inputs are published, if any are.
+    selection_trace: Option<f64>,
+
     /// A queue of pending connections.
-    connecting: VecDeque<tacho::Timed<endpoint::Connecting>>,
+    connecting: VecDeque<Pending>,
+
+    /// Limits how long ready connections may be held before they are dispatched.
+    pool: PoolPolicy,
+
+    /// When the pool has an idle timeout, periodically wakes the dispatcher to reap idle
+    /// connections.
+    pool_sweep: Option<Interval>,
+
+    /// When set, bounds the bytes read ahead on each ready connection, so that data
+    /// sent by endpoints that speak first is written to clients when they are dispatched.
+    read_ahead: Option<usize>,
 
     /// A queue of ready connections to be dispatched ot waiters.
-    connected: VecDeque<Connection<endpoint::Ctx>>,
+    connected: VecDeque<Pooled>,
 
     /// Provides new connection requests as a Stream..
     waiters_rx: W,
@@ -86,34 +268,116 @@ pub struct Dispatcher<W> {
     /// A queue of waiters that have not yet received a connection.
     waiters: VecDeque<Waiter>,
 
-    /// Limits the size of `waiters`.
+    /// Requests for datagram sessions that have not yet been assigned an endpoint.
+    sessions: VecDeque<unsync::oneshot::Sender<endpoint::Session>>,
+
+    /// Limits the combined size of `waiters` and `sessions`.
     max_waiters: usize,
 
+    /// When set, connections are established with the TLS server name requested by the
+    /// waiter's downstream client, and are only dispatched to waiters that requested
+    /// the same name.
+    propagate_sni: bool,
+
+    /// Endpoints that have been taken out of service by an operator.
+    ejected: HashSet<net::SocketAddr>,
+
+    /// Factors by which an operator scales endpoints' resolved weights.
+    weight_multipliers: HashMap<net::SocketAddr, f64>,
+
+    /// Drops resolved addresses before endpoints are created for them.
+    endpoint_filter: Option<EndpointFilter>,
+
+    /// The addresses most recently logged as filtered.
+    filtered: HashSet<net::SocketAddr>,
+    next_filter_log: Instant,
+
+    /// When set, only a subset of the resolved addresses is used.
+    subsetting: Option<Subsetting>,
+
+    /// The addresses in the subset chosen from the most recent resolution, if any.
+    subset: Option<HashSet<net::SocketAddr>>,
+
+    /// Ramps up the weights of newly-added endpoints.
+    slow_start: Option<SlowStart>,
+
+    /// Determines whether resolved weights are normalized.
+    weight_mode: WeightMode,
+
+    /// When set, endpoints are chosen by their connect latencies as well as their loads.
+    ewma: Option<Ewma>,
+
+    /// Accounts connections and sessions to their endpoints. Its failure log suppresses
+    /// repeated connection failures to each endpoint, which are summarized instead.
+    accounting: endpoint::Accounting,
+    next_failure_flush: Instant,
+
+    /// When set, connections to endpoints that hold too many of the destination's open
+    /// connections are closed at each `rebalance_check`.
+    rebalance: Option<Rebalance>,
+    rebalance_check: Option<Interval>,
+
+    /// The most recent failure to resolve the destination, as reported to the admin
+    /// server. It is retained after resolutions succeed again.
+    resolution_error: Option<state::ResolutionErrorState>,
+
+    /// Counts the resolutions that changed the endpoints, so that dispatched connections
+    /// may be stamped with the generation from which they were selected.
+    generations: Generations,
+
+    /// Publishes snapshots of the balancer's state to the admin server.
+    state: state::Reporter,
+    next_state_report: Instant,
+
+    /// Chooses endpoints.
+    rng: SharedRng,
+
     metrics: Metrics,
+
+    /// Set when connection gauges are reported for each endpoint.
+    endpoint_metrics: Option<EndpointMetrics>,
 }
 
 impl<W> Dispatcher<W>
 where
-    W: Stream<Item = Waiter>,
+    W: Stream<Item = Request>,
 {
     /// Receives and attempts to dispatch new waiters.
     ///
     /// If there are no available connections to be dispatched, up to `max_waiters` are
-    /// buffered.
+    /// buffered. Session requests are buffered until `open_sessions()` assigns them.
+    ///
+    /// When the buffer is already full, the dispatcher is counted as busy: further requests
+    /// wait in the channel until a later poll.
     fn recv_waiters(&mut self) {
-        while self.waiters.len() < self.max_waiters {
+        if self.waiters.len() + self.sessions.len() >= self.max_waiters {
+            self.metrics.busy.incr(1);
+            return;
+        }
+        while self.waiters.len() + self.sessions.len() < self.max_waiters {
             match self.waiters_rx.poll() {
                 Ok(Async::Ready(None)) |
                 Ok(Async::NotReady) => return,
                 Err(_) => {
                     error!("{}: error from waiters channel", self.dst_name);
                 }
-                Ok(Async::Ready(Some(w))) => {
-                    match self.connected.pop_front() {
+                Ok(Async::Ready(Some(Request::Session(tx)))) => self.sessions.push_back(tx),
+                Ok(Async::Ready(Some(Request::Connect(mut w)))) => {
+                    if !self.propagate_sni {
+                        w.sni = None;
+                    }
+                    w.target = self.sticky_target(w.client, w.avoid);
+                    if w.target.is_none() {
+                        w.target = w.avoid.and_then(|addr| self.retry_target(addr));
+                    }
+                    match self.checkout(w.sni.as_ref().map(|s| s.as_str()), w.target) {
                         None => self.waiters.push_back(w),
-                        Some(conn) => {
-                            if let Err(conn) = w.send(conn) {
-                                self.connected.push_front(conn);
+                        Some(Pooled { mut conn, since, sni }) => {
+                            let addr = conn.peer_addr();
+                            conn.ctx.set_generation(self.generations.current());
+                            match w.tx.send(conn) {
+                                Ok(()) => self.stick(w.client, addr),
+                                Err(conn) => self.connected.push_front(Pooled { conn, since, sni }),
                             }
                         }
                     }
@@ -126,11 +390,12 @@ where
         debug!("polling {} pending connections", self.connecting.len());
         for _ in 0..self.connecting.len() {
             let mut connecting = self.connecting.pop_front().unwrap();
-            match connecting.poll() {
+            match connecting.conn.poll() {
                 Err(e) => {
                     debug!("connection failed: {}", e);
                     self.metrics.pending.decr(1);
                     self.metrics.failure(&e);
+                    record_connect(&self.breaker, false);
                 }
                 Ok(Async::NotReady) => {
                     trace!("connection pending");
@@ -141,28 +406,147 @@ where
                     self.metrics.connects.incr(1);
                     self.metrics.pending.decr(1);
                     self.metrics.open.incr(1);
-                    self.connected.push_back(connected)
+                    record_connect(&self.breaker, true);
+                    self.connected.push_back(Pooled::new(connected, connecting.sni))
                 }
             }
         }
     }
 
     fn update_endpoints(&mut self) {
-        if let Some(addrs) = self.poll_resolve() {
-            self.endpoints.update_resolved(&addrs);
-            debug!(
-                "balancer updated: available={} failed={}, retired={}",
-                self.endpoints.available().len(),
-                self.endpoints.failed().len(),
-                self.endpoints.retired().len()
-            );
-        }
-
-        self.endpoints.update_failed(
-            self.fail_limit,
-            self.fail_penalty,
-        );
+        let resolved = match self.poll_damped() {
+            None => false,
+            Some(mut addrs) => {
+                let invalid = weigh(&mut addrs, self.weight_mode);
+                if invalid > 0 {
+                    warn!(
+                        "{}: skipped {} addresses with negative or non-finite weights",
+                        self.dst_name,
+                        invalid
+                    );
+                }
+                let addrs = self.filter_resolved(addrs);
+                let addrs = self.subset_resolved(addrs);
+                if self.generations.update(&addrs) {
+                    let generation = self.generations.number();
+                    debug!("{}: endpoints changed (generation {})", self.dst_name, generation);
+                    self.metrics.generation.set(generation as usize);
+                }
+                self.endpoints.update_resolved(
+                    &addrs,
+                    self.slow_start.as_ref(),
+                    self.weight_mode,
+                );
+                self.metrics.updates.incr(1);
+                debug!(
+                    "balancer updated: available={} failed={}, retired={}",
+                    self.endpoints.available().len(),
+                    self.endpoints.failed().len(),
+                    self.endpoints.retired().len()
+                );
+                true
+            }
+        };
+
+        // Operator weight overrides outlive resolutions, so they are reapplied whenever
+        // either changes.
+        let overridden = match self.state.poll_weight_multipliers() {
+            None => false,
+            Some(multipliers) => {
+                self.weight_multipliers = multipliers;
+                true
+            }
+        };
+        if resolved || overridden {
+            self.endpoints.update_weight_multipliers(&self.weight_multipliers);
+        }
+
+        // Operator ejections take precedence over endpoint health, so they are applied
+        // before failed endpoints are considered for reinstatement.
+        if let Some(ejected) = self.state.poll_ejected() {
+            self.ejected = ejected;
+        }
+        self.endpoints.update_ejected(&self.ejected);
+
+        self.update_remote_policy();
+        self.endpoints.update_failed(&self.fail_fast, Instant::now());
+
+        if let Some(ref mut fallback) = self.fallback {
+            if fallback.update(!self.endpoints.available().is_empty()) {
+                // Connections to fallback endpoints that have not yet been dispatched are
+                // closed; dispatched connections drain.
+                self.connected.retain(|p| !fallback.contains(&p.conn.peer_addr()));
+            }
+        }
+    }
+
+    /// Applies the destination's remote policy over its configured connection policy, if
+    /// the policy has changed. Overrides that are no longer published revert to their
+    /// configured settings.
+    fn update_remote_policy(&mut self) {
+        let policy = match self.state.poll_remote_policy() {
+            Some(policy) => policy,
+            None => return,
+        };
+        if policy == self.remote_policy {
+            return;
+        }
+        for (k, v) in policy.entries() {
+            if self.remote_policy.entries().get(k) != Some(v) {
+                info!("{}: applying remote policy {}: {}", self.dst_name, k, v);
+            }
+        }
+        for k in self.remote_policy.entries().keys() {
+            if !policy.entries().contains_key(k) {
+                info!("{}: reverting remote policy {}", self.dst_name, k);
+            }
+        }
+        let mut connector = self.configured.clone();
+        policy.apply(&mut connector);
+        self.fail_fast = connector.fail_fast().clone();
+        self.ewma = connector.ewma().cloned();
+        self.connector = connector;
+        self.remote_policy = policy;
+        self.metrics.remote_policy_updates.incr(1);
+    }
 
+    /// Takes the resolution to be applied, if update damping allows one to be.
+    ///
+    /// When a damped resolution is not yet due, this task is woken when it is.
+    fn poll_damped(&mut self) -> Option<Vec<WeightedAddr>> {
+        let resolved = self.poll_resolve();
+        let damper = match self.damper {
+            None => return resolved,
+            Some(ref mut damper) => damper,
+        };
+        let now = Instant::now();
+        let was_flapping = damper.is_flapping();
+        if let Some(addrs) = resolved {
+            damper.update(addrs, now);
+        }
+        let addrs = damper.poll(now);
+        match (was_flapping, damper.is_flapping()) {
+            (false, true) => {
+                warn!(
+                    "{}: resolutions are flapping; freezing endpoints on their union",
+                    self.dst_name
+                );
+                self.metrics.flap_detected.set(1);
+            }
+            (true, false) => {
+                info!("{}: resolutions have stabilized", self.dst_name);
+                self.metrics.flap_detected.set(0);
+            }
+            _ => {}
+        }
+        let timer = &self.timer;
+        self.damping_wakeup = damper.next_poll().map(|at| {
+            let delay = if at > now { at - now } else { Duration::from_secs(0) };
+            let mut wakeup = timer.sleep(delay);
+            let _ = wakeup.poll();
+            wakeup
+        });
+        addrs
     }
 
     fn poll_resolve(&mut self) -> Option<Vec<WeightedAddr>> {
@@ -183,6 +567,12 @@ where
                 }
                 Ok(Async::Ready(Some(Err(e)))) => {
                     error!("{}: resolver error: {:?}", self.dst_name, e);
+                    let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                    self.resolution_error = Some(state::ResolutionErrorState {
+                        category: e.category(),
+                        error: e.to_string(),
+                        at_ms: at.as_secs() * 1_000 + u64::from(at.subsec_nanos() / 1_000_000),
+                    });
                 }
                 Ok(Async::Ready(Some(Ok(a)))) => {
                     addrs = Some(a);
@@ -191,27 +581,207 @@ where
         }
     }
 
+    /// Removes addresses that are not permitted by the endpoint filter, by their IP or
+    /// their metadata.
+    ///
+    /// Filtered addresses are logged when they change, at most once per
+    /// `FILTER_LOG_INTERVAL_SECS`.
+    fn filter_resolved(&mut self, addrs: Vec<WeightedAddr>) -> Vec<WeightedAddr> {
+        let mut permitted = Vec::with_capacity(addrs.len());
+        let mut filtered = HashSet::new();
+        match self.endpoint_filter {
+            None => return addrs,
+            Some(ref filter) => {
+                for wa in addrs {
+                    if !filter.permits(&wa.addr.ip()) {
+                        self.metrics.filtered_cidr.incr(1);
+                        filtered.insert(wa.addr);
+                    } else if !filter.permits_meta(&wa.meta) {
+                        self.metrics.filtered_meta.incr(1);
+                        filtered.insert(wa.addr);
+                    } else {
+                        permitted.push(wa);
+                    }
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if filtered != self.filtered && now >= self.next_filter_log {
+            if !filtered.is_empty() {
+                let mut addrs: Vec<String> = filtered.iter().map(|a| a.to_string()).collect();
+                addrs.sort();
+                info!(
+                    "{}: filtered {} resolved addresses: {}",
+                    self.dst_name,
+                    addrs.len(),
+                    addrs.join(", ")
+                );
+            }
+            self.filtered = filtered;
+            self.next_filter_log = now + Duration::from_secs(FILTER_LOG_INTERVAL_SECS);
+        }
+        permitted
+    }
+
+    /// Limits the resolved addresses to this proxy's subset, if subsetting is
+    /// configured.
+    ///
+    /// The subset's size is reported as `endpoint_subset_size`. Addresses that join or
+    /// leave the subset after the first resolution are counted as
+    /// `endpoint_subset_additions` and `endpoint_subset_removals`.
+    fn subset_resolved(&mut self, addrs: Vec<WeightedAddr>) -> Vec<WeightedAddr> {
+        let subsetting = match self.subsetting {
+            None => return addrs,
+            Some(s) => s,
+        };
+
+        let mut seen = HashSet::with_capacity(addrs.len());
+        let mut ranked: Vec<(u64, WeightedAddr)> = addrs
+            .into_iter()
+            .filter(|wa| seen.insert(wa.addr))
+            .map(|wa| (subsetting.rank(&wa.addr), wa))
+            .collect();
+        ranked.sort_by(|a, b| b.0.cmp(&a.0));
+        ranked.truncate(subsetting.size);
+        let subset: Vec<WeightedAddr> = ranked.into_iter().map(|(_, wa)| wa).collect();
+
+        let members: HashSet<net::SocketAddr> = subset.iter().map(|wa| wa.addr).collect();
+        if let Some(ref prior) = self.subset {
+            let added = members.difference(prior).count();
+            let removed = prior.difference(&members).count();
+            if added + removed > 0 {
+                debug!(
+                    "{}: subset changed: {} added, {} removed",
+                    self.dst_name,
+                    added,
+                    removed
+                );
+            }
+            self.metrics.subset_additions.incr(added);
+            self.metrics.subset_removals.incr(removed);
+        }
+        self.metrics.subset_size.set(members.len());
+        self.subset = Some(members);
+        subset
+    }
+
+    /// The number of connections to be maintained ahead of demand, which is 0 until the
+    /// destination's pre-warming delay has elapsed.
+    fn prewarm_target(&mut self) -> usize {
+        if let Some(mut delay) = self.prewarm_delay.take() {
+            if let Ok(Async::NotReady) = delay.poll() {
+                self.prewarm_delay = Some(delay);
+                return 0;
+            }
+        }
+        self.min_connections
+    }
+
     fn init_connecting(&mut self) {
-        let available = self.endpoints.available();
+        // The fallback is only active while no resolved endpoints are available.
+        let available = match self.fallback.as_ref().and_then(|f| f.active_endpoints()) {
+            Some(fallback) => fallback,
+            None => self.endpoints.available(),
+        };
         if available.is_empty() {
             trace!("no available endpoints");
             return;
         }
 
+        let mut targets = self.unserved_targets();
+        let mut snis = self.unserved_snis();
         let needed = {
-            let needed = self.min_connections + self.waiters.len();
+            let needed = self.prewarm_target() + self.waiters.len();
             let pending = self.connecting.len() + self.connected.len();
-            if needed < pending {
-                0
-            } else {
-                needed - pending
-            }
+            let needed = if needed < pending { 0 } else { needed - pending };
+            cmp::max(needed, targets.len() + snis.len())
         };
+        if needed == 0 {
+            return;
+        }
         debug!("initiating {} connections", needed);
 
-        let mut rng = rand::thread_rng();
+        // Endpoints that are backing off after connection failures are not considered.
+        let now = Instant::now();
+        let mut candidates = Vec::with_capacity(available.len());
+        let mut next_attempt = None;
+        for ep in available.values() {
+            match ep.backoff_until(now) {
+                None => candidates.push(ep),
+                Some(until) => {
+                    next_attempt = Some(next_attempt.map_or(until, |t| cmp::min(t, until)));
+                }
+            }
+        }
+        if let Some(until) = next_attempt {
+            if candidates.is_empty() {
+                // Ensure that pending waiters are served once the first backoff elapses.
+                trace!("all endpoints backing off");
+                if !self.all_backing_off {
+                    self.all_backing_off = true;
+                    self.metrics.unavailable.incr(1);
+                }
+                let mut wakeup = self.timer.sleep(until - now);
+                let _ = wakeup.poll();
+                self.backoff_wakeup = Some(wakeup);
+                return;
+            }
+        }
+        self.all_backing_off = false;
+
+        let scorer = self.ewma.as_ref().map(|e| Scorer::new(e, &candidates));
         for _ in 0..needed {
-            match select_endpoint(&mut rng, available) {
+            // The permit is held by the connection until it is closed.
+            let permit = match self.global_limit {
+                None => None,
+                Some(ref limit) => {
+                    match GlobalLimit::acquire(limit) {
+                        None => {
+                            debug!("upstream connection limit reached");
+                            return;
+                        }
+                        permit => permit,
+                    }
+                }
+            };
+            // Connections are made for targeted waiters' endpoints, and then for waiters'
+            // names, first.
+            let (target, sni) = match targets.pop_front() {
+                Some((addr, sni)) => (Some(addr), sni),
+                None => (None, snis.pop_front()),
+            };
+            let mut explain = if self.sample_selection() {
+                Some(explain_candidates(available, now, scorer.is_some()))
+            } else {
+                None
+            };
+            let targeted = target.and_then(|addr| {
+                candidates.iter().find(|ep| ep.peer_addr() == addr).cloned()
+            });
+            let selected = match targeted {
+                Some(ep) => {
+                    if let Some(ref mut e) = explain {
+                        e.strategy = "targeted";
+                        e.chose(ep.peer_addr(), None);
+                    }
+                    Some(ep)
+                }
+                None => {
+                    select(
+                        &self.rng,
+                        &candidates,
+                        self.locality.as_ref(),
+                        scorer.as_ref(),
+                        &self.metrics,
+                        explain.as_mut(),
+                    )
+                }
+            };
+            if let Some(e) = explain {
+                self.state.trace_selection(e);
+            }
+            match selected {
                 None => {
                     trace!("no endpoints ready");
                     self.metrics.unavailable.incr(1);
@@ -219,31 +789,37 @@ where
                 }
                 Some(ep) => {
                     self.metrics.attempts.incr(1);
+                    let target = target.and_then(|addr| {
+                        if ep.peer_addr() == addr { Some(addr) } else { None }
+                    });
                     let mut conn = {
                         let sock = self.connector.connect(
                             &ep.peer_addr(),
                             &self.reactor,
                             &self.timer,
+                            sni.as_ref().map(|s| s.as_str()),
                         );
-                        let c = ep.connect(sock, &self.metrics.connection_duration);
-                        self.metrics.connect_latency.time(c)
+                        let c = ep.connect(sock, &self.accounting, self.ewma, permit);
+                        metrics::timed(&self.metrics.connect_latency, c)
                     };
                     match conn.poll() {
                         Err(e) => {
                             debug!("connection failed: {}", e);
                             self.metrics.failure(&e);
+                            record_connect(&self.breaker, false);
                         }
                         Ok(Async::NotReady) => {
                             trace!("connection pending");
                             self.metrics.pending.incr(1);
-                            self.connecting.push_back(conn);
+                            self.connecting.push_back(Pending { conn, sni, target });
                         }
                         Ok(Async::Ready(conn)) => {
                             debug!("connected");
                             self.metrics.connects.incr(1);
                             self.metrics.pending.decr(1);
                             self.metrics.open.incr(1);
-                            self.connected.push_back(conn);
+                            record_connect(&self.breaker, true);
+                            self.connected.push_back(Pooled::new(conn, sni));
                         }
                     }
                 }
@@ -251,70 +827,553 @@ where
         }
     }
 
+    /// Assigns pending session requests to endpoints.
+    ///
+    /// Nothing is connected on behalf of a session, so sessions are assigned as soon as
+    /// an endpoint that is not backing off is available.
+    fn open_sessions(&mut self) {
+        if self.sessions.is_empty() {
+            return;
+        }
+
+        let next_attempt = {
+            let available = match self.fallback.as_ref().and_then(|f| f.active_endpoints()) {
+                Some(fallback) => fallback,
+                None => self.endpoints.available(),
+            };
+            let now = Instant::now();
+            let mut candidates = Vec::with_capacity(available.len());
+            let mut next_attempt = None;
+            for ep in available.values() {
+                match ep.backoff_until(now) {
+                    None => candidates.push(ep),
+                    Some(until) => {
+                        next_attempt = Some(next_attempt.map_or(until, |t| cmp::min(t, until)));
+                    }
+                }
+            }
+
+            let scorer = self.ewma.as_ref().map(|e| Scorer::new(e, &candidates));
+            while let Some(tx) = self.sessions.pop_front() {
+                let mut explain = if self.sample_selection() {
+                    Some(explain_candidates(available, now, scorer.is_some()))
+                } else {
+                    None
+                };
+                let selected = select(
+                    &self.rng,
+                    &candidates,
+                    self.locality.as_ref(),
+                    scorer.as_ref(),
+                    &self.metrics,
+                    explain.as_mut(),
+                );
+                if let Some(e) = explain {
+                    self.state.trace_selection(e);
+                }
+                match selected {
+                    None => {
+                        trace!("no endpoints ready for sessions");
+                        self.metrics.unavailable.incr(1);
+                        self.sessions.push_front(tx);
+                        break;
+                    }
+                    Some(ep) => {
+                        self.metrics.sessions.incr(1);
+                        let session = ep.open_session(&self.accounting, self.breaker.clone());
+                        // If the requester has gone away, the session is closed.
+                        let _ = tx.send(session);
+                    }
+                }
+            }
+            next_attempt
+        };
+
+        // Ensure that pending sessions are assigned once the first backoff elapses.
+        if let Some(until) = next_attempt {
+            if !self.sessions.is_empty() {
+                let now = Instant::now();
+                let delay = if now < until { until - now } else { Duration::from_secs(0) };
+                let mut wakeup = self.timer.sleep(delay);
+                let _ = wakeup.poll();
+                self.backoff_wakeup = Some(wakeup);
+            }
+        }
+    }
+
     fn dispatch_connected_to_waiters(&mut self) {
         debug!(
             "dispatching {} connections to {} waiters",
             self.connected.len(),
             self.waiters.len()
         );
-        while let Some(conn) = self.connected.pop_front() {
-            if let Err(conn) = self.dispatch_to_next_waiter(conn) {
-                self.connected.push_front(conn);
+        // Waiters that can't be served yet are returned to the queue in order.
+        for _ in 0..self.waiters.len() {
+            if self.connected.is_empty() {
                 return;
             }
+            let waiter = self.waiters.pop_front().unwrap();
+            match self.checkout(waiter.sni.as_ref().map(|s| s.as_str()), waiter.target) {
+                None => self.waiters.push_back(waiter),
+                Some(Pooled { mut conn, since, sni }) => {
+                    // If the waiter has gone away, the connection may be dispatched to
+                    // another waiter.
+                    let addr = conn.peer_addr();
+                    conn.ctx.set_generation(self.generations.current());
+                    match waiter.tx.send(conn) {
+                        Ok(()) => self.stick(waiter.client, addr),
+                        Err(conn) => self.connected.push_front(Pooled { conn, since, sni }),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Determines the names of connections needed by waiters, when SNI is propagated.
+    ///
+    /// Each name is included once for each waiter that requested it and for which a
+    /// connection is not already pending or ready.
+    fn unserved_snis(&self) -> VecDeque<String> {
+        let mut unserved = VecDeque::new();
+        if !self.propagate_sni {
+            return unserved;
+        }
+
+        let mut supply = HashMap::new();
+        let pending = self.connecting.iter().map(|p| &p.sni);
+        for sni in pending.chain(self.connected.iter().map(|p| &p.sni)) {
+            if let Some(ref sni) = *sni {
+                *supply.entry(sni.as_str()).or_insert(0) += 1;
+            }
+        }
+        // Targeted waiters' connections are made by `unserved_targets()`.
+        for w in self.waiters.iter().filter(|w| w.target.is_none()) {
+            if let Some(ref sni) = w.sni {
+                let served = match supply.get_mut(sni.as_str()) {
+                    Some(n) => {
+                        if *n > 0 {
+                            *n -= 1;
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    None => false,
+                };
+                if !served {
+                    unserved.push_back(sni.clone());
+                }
+            }
+        }
+        unserved
+    }
+
+    /// Determines the endpoints to which targeted waiters need connections, with each
+    /// waiter's TLS server name.
+    ///
+    /// Each endpoint is included once for each waiter that targets it and for which a
+    /// connection to it is not already pending or ready.
+    fn unserved_targets(&self) -> VecDeque<(net::SocketAddr, Option<String>)> {
+        let mut unserved = VecDeque::new();
+        let mut supply = HashMap::new();
+        let pending = self.connecting.iter().filter_map(|p| p.target);
+        for addr in pending.chain(self.connected.iter().map(|p| p.conn.peer_addr())) {
+            *supply.entry(addr).or_insert(0) += 1;
+        }
+        for w in &self.waiters {
+            if let Some(addr) = w.target {
+                let served = match supply.get_mut(&addr) {
+                    Some(n) => {
+                        if *n > 0 {
+                            *n -= 1;
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    None => false,
+                };
+                if !served {
+                    unserved.push_back((addr, w.sni.clone()));
+                }
+            }
         }
+        unserved
     }
 
-    fn dispatch_to_next_waiter(
+    /// Determines whether the next endpoint selection is traced.
+    fn sample_selection(&self) -> bool {
+        match self.selection_trace {
+            None => false,
+            Some(rate) => rand::thread_rng().next_f64() < rate,
+        }
+    }
+
+    /// Determines the endpoint to which a sticky client should be dispatched: the
+    /// endpoint to which it was last dispatched, while that endpoint is available, is
+    /// not backing off, would not be loaded beyond the policy's factor of the average
+    /// load, and is not the endpoint that failed a retried client's previous attempt.
+    fn sticky_target(
         &mut self,
-        conn: endpoint::Connection,
-    ) -> Result<(), endpoint::Connection> {
-        match self.waiters.pop_front() {
-            None => Err(conn),
-            Some(waiter) => {
-                match waiter.send(conn) {
-                    Ok(()) => Ok(()),
-                    Err(conn) => self.dispatch_to_next_waiter(conn),
+        client: Option<net::IpAddr>,
+        avoid: Option<net::SocketAddr>,
+    ) -> Option<net::SocketAddr> {
+        let client = match client {
+            Some(client) => client,
+            None => return None,
+        };
+        let sticky = match self.sticky.as_mut() {
+            Some(sticky) => sticky,
+            None => return None,
+        };
+        let now = Instant::now();
+        let addr = match sticky.get(client, now) {
+            Some(addr) => addr,
+            None => {
+                sticky.record(Outcome::Miss);
+                return None;
+            }
+        };
+        let available = self.endpoints.available();
+        if avoid == Some(addr) || !is_usable(available, &addr, now) ||
+            is_overloaded(available, &addr, sticky.max_load_factor())
+        {
+            debug!("{}: not reusing {} for {}", self.dst_name, addr, client);
+            sticky.record(Outcome::Fallback);
+            return None;
+        }
+        sticky.record(Outcome::Hit);
+        Some(addr)
+    }
+
+    /// Chooses another endpoint than `avoid`, which failed a retried waiter's previous
+    /// attempt, among the available endpoints that are not backing off. Unset when there
+    /// is no other such endpoint, so that the waiter is balanced normally.
+    fn retry_target(&self, avoid: net::SocketAddr) -> Option<net::SocketAddr> {
+        let now = Instant::now();
+        let candidates = self.endpoints
+            .available()
+            .values()
+            .filter(|ep| {
+                ep.peer_addr() != avoid && ep.backoff_until(now).is_none()
+            })
+            .collect::<Vec<_>>();
+        let scorer = self.ewma.as_ref().map(|e| Scorer::new(e, &candidates));
+        let selected = select(
+            &self.rng,
+            &candidates,
+            self.locality.as_ref(),
+            scorer.as_ref(),
+            &self.metrics,
+            None,
+        );
+        selected.map(|ep| ep.peer_addr())
+    }
+
+    /// Balances targeted waiters normally once their endpoints become unavailable. A
+    /// retried waiter is instead targeted at another endpoint than the one that failed,
+    /// if there is one.
+    fn retarget_waiters(&mut self) {
+        let available = self.endpoints.available();
+        let now = Instant::now();
+        let mut retried = Vec::new();
+        for (i, w) in self.waiters.iter_mut().enumerate() {
+            let stale = match w.target {
+                Some(ref addr) => !is_usable(available, addr, now),
+                None => false,
+            };
+            if stale {
+                w.target = None;
+                match (w.avoid, self.sticky.as_ref()) {
+                    (Some(avoid), _) => retried.push((i, avoid)),
+                    (None, Some(sticky)) => sticky.record(Outcome::Fallback),
+                    (None, None) => {}
+                }
+            }
+        }
+        for (i, avoid) in retried {
+            let target = self.retry_target(avoid);
+            self.waiters[i].target = target;
+        }
+    }
+
+    /// Remembers the endpoint to which a sticky client was dispatched, refreshing its
+    /// entry.
+    fn stick(&mut self, client: Option<net::IpAddr>, addr: net::SocketAddr) {
+        if let (Some(sticky), Some(client)) = (self.sticky.as_mut(), client) {
+            sticky.assign(client, addr, Instant::now());
+        }
+    }
+
+    /// Takes the next ready connection for the TLS server name `sni`, and to `target` if
+    /// one is given, that may be dispatched, closing connections that have exceeded
+    /// their maximum lifetime or that fail validation.
+    fn checkout(&mut self, sni: Option<&str>, target: Option<net::SocketAddr>) -> Option<Pooled> {
+        let mut i = 0;
+        while i < self.connected.len() {
+            let matches = self.connected[i].sni.as_ref().map(|s| s.as_str()) == sni &&
+                target.map_or(true, |t| self.connected[i].conn.peer_addr() == t);
+            if !matches {
+                i += 1;
+                continue;
+            }
+            let pooled = self.connected.remove(i).unwrap();
+            if pooled.conn.ctx.is_evicted() {
+                trace!("{}: closing evicted connection", self.dst_name);
+                continue;
+            }
+            if let Some(max) = self.pool.max_lifetime {
+                if max <= pooled.since.elapsed() {
+                    trace!("{}: closing expired connection", self.dst_name);
+                    self.metrics.pool_expired.incr(1);
+                    continue;
+                }
+            }
+            if self.pool.validate_before_reuse {
+                if pooled.conn.socket.is_peer_closed() {
+                    debug!(
+                        "{}: closing connection closed by {}",
+                        self.dst_name,
+                        pooled.conn.peer_addr()
+                    );
+                    self.metrics.pool_invalid.incr(1);
+                    continue;
+                }
+                self.metrics.pool_valid.incr(1);
+            }
+            return Some(pooled);
+        }
+        None
+    }
+
+    /// Closes ready connections that have been idle for longer than the pool's idle
+    /// timeout.
+    fn reap_idle(&mut self) {
+        let idle_timeout = match self.pool.idle_timeout {
+            None => return,
+            Some(t) => t,
+        };
+        if let Some(ref mut sweep) = self.pool_sweep {
+            // Drain the interval so that the task is notified of the next sweep.
+            loop {
+                match sweep.poll() {
+                    Ok(Async::Ready(Some(_))) => {}
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        error!("{}: pool sweep timer error: {}", self.dst_name, e);
+                        break;
+                    }
                 }
             }
         }
+
+        let sz = self.connected.len();
+        self.connected.retain(|p| p.since.elapsed() < idle_timeout);
+        let reaped = sz - self.connected.len();
+        if reaped > 0 {
+            debug!("{}: reaped {} idle connections", self.dst_name, reaped);
+            self.metrics.pool_reaped.incr(reaped);
+        }
     }
 
-    fn record(&self, t0: Instant) {
+    /// Reads the data that endpoints have sent on ready connections, up to the read-ahead
+    /// limit, closing connections that have been closed by their endpoints.
+    ///
+    /// Each read that finds no data registers this task to be notified when there is,
+    /// so data is read as soon as it arrives. Data read from connections that are later
+    /// closed without being dispatched is discarded along with them.
+    fn read_ahead(&mut self) {
+        let limit = match self.read_ahead {
+            None => return,
+            Some(limit) => limit,
+        };
+        for _ in 0..self.connected.len() {
+            let mut pooled = self.connected.pop_front().unwrap();
+            match pooled.conn.socket.read_ahead(limit) {
+                Ok(sz) => {
+                    self.metrics.pool_read_ahead.incr(sz);
+                    self.connected.push_back(pooled);
+                }
+                Err(e) => {
+                    debug!(
+                        "{}: closing ready connection to {}: {}",
+                        self.dst_name,
+                        pooled.conn.peer_addr(),
+                        e
+                    );
+                    self.metrics.pool_peer_closed.incr(1);
+                }
+            }
+        }
+    }
+
+    /// Evicts the oldest connections of endpoints whose share of open connections
+    /// exceeds their share of the total weight by more than the maximum skew.
+    ///
+    /// Each endpoint is brought back to its weighted share, but no more than
+    /// `max_close_ratio` of all open connections are evicted per check. Connections
+    /// that have already been evicted are not counted, since they are closing.
+    fn rebalance(&mut self) {
+        let policy = match self.rebalance {
+            None => return,
+            Some(r) => r,
+        };
+        let mut checks = 0;
+        if let Some(ref mut check) = self.rebalance_check {
+            // Drain the interval so that the task is notified of the next check.
+            loop {
+                match check.poll() {
+                    Ok(Async::Ready(Some(_))) => checks += 1,
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        error!("{}: rebalance timer error: {}", self.dst_name, e);
+                        break;
+                    }
+                }
+            }
+        }
+        if checks == 0 {
+            return;
+        }
+
+        let available = self.endpoints.available();
+        let mut total_conns = 0;
+        let mut total_weight = 0.0;
+        let mut shares = Vec::with_capacity(available.len());
+        for ep in available.values() {
+            let conns = ep.state().unevicted_conns();
+            let weight = ep.weight();
+            total_conns += conns;
+            total_weight += weight;
+            shares.push((ep, conns, weight));
+        }
+        if total_conns == 0 || total_weight <= 0.0 {
+            return;
+        }
+
+        // The most overloaded endpoints are rebalanced first.
+        let excess = |conns: usize, weight: f64| {
+            let fair = (weight / total_weight * total_conns as f64).ceil() as usize;
+            if conns as f64 > fair as f64 * policy.max_skew_ratio {
+                conns.saturating_sub(fair)
+            } else {
+                0
+            }
+        };
+        let mut skewed: Vec<_> = shares
+            .into_iter()
+            .map(|(ep, conns, weight)| (ep, excess(conns, weight)))
+            .filter(|&(_, excess)| excess > 0)
+            .collect();
+        skewed.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let mut budget = (total_conns as f64 * policy.max_close_ratio) as usize;
+        for (ep, excess) in skewed {
+            if budget == 0 {
+                break;
+            }
+            let evicted = ep.evict_oldest(cmp::min(excess, budget), policy.close_gracefully);
+            budget -= evicted;
+            self.metrics.rebalance_closures.incr(evicted);
+        }
+    }
+
+    /// Logs summaries of connection failures that have been suppressed for a whole
+    /// window without being followed by another failure.
+    fn flush_failure_log(&mut self) {
+        let now = Instant::now();
+        if now < self.next_failure_flush {
+            return;
+        }
+        self.next_failure_flush = now + Duration::from_secs(FAILURE_LOG_FLUSH_INTERVAL_SECS);
+        for (addr, s) in self.accounting.failure_log.borrow_mut().flush(now) {
+            error!("{}: connection failed {}", addr, s);
+        }
+    }
+
+    fn report_state(&mut self) {
+        let now = Instant::now();
+        if now < self.next_state_report {
+            return;
+        }
+        self.next_state_report = now + Duration::from_secs(STATE_REPORT_INTERVAL_SECS);
+
+        let window = self.accounting.stats_window;
+        let mut endpoints = Vec::with_capacity(
+            self.endpoints.available().len() + self.endpoints.failed().len() +
+                self.endpoints.retired().len() + self.endpoints.ejected().len(),
+        );
+        for ep in self.endpoints.available().values() {
+            endpoints.push(ep.snapshot("available", window));
+        }
+        for &(_, ref ep) in self.endpoints.failed().values() {
+            endpoints.push(ep.snapshot("failed", window));
+        }
+        for ep in self.endpoints.retired().values() {
+            endpoints.push(ep.snapshot("retired", window));
+        }
+        for ep in self.endpoints.ejected().values() {
+            endpoints.push(ep.snapshot("ejected", window));
+        }
+        if let Some(ref fallback) = self.fallback {
+            for ep in fallback.endpoints().values() {
+                endpoints.push(ep.snapshot("fallback", window));
+            }
+        }
+        self.state.report(state::BalancerState {
+            circuit: self.breaker.as_ref().map(|b| b.borrow().state_name()),
+            fallback: self.fallback.as_ref().map(|f| f.state_name()),
+            waiters: self.waiters.len() + self.sessions.len(),
+            resolution_error: self.resolution_error.clone(),
+            remote_policy: self.remote_policy.entries().clone(),
+            endpoints,
+        });
+    }
+
+    fn record(&mut self, t0: Instant) {
         {
-            let mut open = 0;
-            let mut pending = 0;
+            let meta_keys = self.endpoint_metrics.as_ref().map(|m| m.meta_keys.clone());
+            let mut conns = Conns::new(meta_keys);
             {
                 let available = self.endpoints.available();
                 self.metrics.available.set(available.len());
                 for ep in available.values() {
-                    let state = ep.state();
-                    open += state.open_conns;
-                    pending += state.pending_conns;
+                    conns.add(ep);
                 }
             }
             {
                 let failed = self.endpoints.failed();
                 self.metrics.failed.set(failed.len());
                 for &(_, ref ep) in failed.values() {
-                    let state = ep.state();
-                    open += state.open_conns;
-                    pending += state.pending_conns;
+                    conns.add(ep);
                 }
             }
             {
                 let retired = self.endpoints.retired();
                 self.metrics.retired.set(retired.len());
                 for ep in retired.values() {
-                    let state = ep.state();
-                    open += state.open_conns;
-                    pending += state.pending_conns;
+                    conns.add(ep);
+                }
+            }
+            {
+                let ejected = self.endpoints.ejected();
+                self.metrics.ejected.set(ejected.len());
+                for ep in ejected.values() {
+                    conns.add(ep);
+                }
+            }
+            if let Some(ref fallback) = self.fallback {
+                for ep in fallback.endpoints().values() {
+                    conns.add(ep);
                 }
             }
-            self.metrics.open.set(open);
-            self.metrics.pending.set(pending);
+            self.metrics.open.set(conns.open);
+            self.metrics.pending.set(conns.pending);
+            if let (Some(m), Some(by_addr)) = (self.endpoint_metrics.as_mut(), conns.by_addr) {
+                m.report(&by_addr);
+            }
         }
-        self.metrics.waiters.set(self.waiters.len());
+        self.metrics.waiters.set(self.waiters.len() + self.sessions.len());
         self.metrics.poll_time.record_since(t0);
     }
 }
@@ -323,7 +1382,7 @@ where
 /// connection attempts.
 impl<S> Future for Dispatcher<S>
 where
-    S: Stream<Item = Waiter>,
+    S: Stream<Item = Request>,
 {
     type Item = ();
     type Error = io::Error;
@@ -331,6 +1390,10 @@ where
     fn poll(&mut self) -> Poll<(), io::Error> {
         let t0 = Instant::now();
 
+        // Close idle connections before dispatching, so that they may be replaced by
+        // `init_connecting()`.
+        self.reap_idle();
+
         // Poll all pending connections. Newly established connections are added to the
         // `connected` queue, to be dispatched.
         self.poll_connecting();
@@ -350,7 +1413,10 @@ where
         // Update our lists of endpoints from service discovery before initiating new
         // connections for pending waiters.
         self.update_endpoints();
+        self.rebalance();
+        self.retarget_waiters();
         self.init_connecting();
+        self.open_sessions();
 
         // Dispatch any remaining available connections to any remaining waiters. This is
         // necessary because `init_connecting()` can technically satisfy connections
@@ -362,7 +1428,13 @@ where
         // refill it to ensure that this task is polled again.
         self.recv_waiters();
 
+        // Read ahead on connections that remain ready, so that this task is also polled
+        // when their endpoints send data.
+        self.read_ahead();
+
         // Update gauges & record the time it took to poll.
+        self.flush_failure_log();
+        self.report_state();
         self.record(t0);
 
         // This Future never completes.
@@ -371,19 +1443,339 @@ where
     }
 }
 
+/// A connection being established.
+struct Pending {
+    conn: metrics::Timed<endpoint::Connecting>,
+    /// The TLS server name used for the upstream handshake, if propagated from a
+    /// downstream client.
+    sni: Option<String>,
+    /// The endpoint, when the connection was made for a sticky waiter.
+    target: Option<net::SocketAddr>,
+}
+
+/// A ready connection, held until it is dispatched to a waiter.
+struct Pooled {
+    conn: endpoint::Connection,
+    since: Instant,
+    sni: Option<String>,
+}
+
+impl Pooled {
+    fn new(conn: endpoint::Connection, sni: Option<String>) -> Pooled {
+        Pooled {
+            conn,
+            since: Instant::now(),
+            sni,
+        }
+    }
+}
+
+/// Determines whether `addr` is an available endpoint that is not backing off.
+fn is_usable(available: &EndpointMap, addr: &net::SocketAddr, now: Instant) -> bool {
+    available.get(addr).map_or(
+        false,
+        |ep| ep.backoff_until(now).is_none(),
+    )
+}
+
+/// Determines whether connecting to `addr` would load it beyond `factor` times the
+/// average load of the available endpoints.
+fn is_overloaded(available: &EndpointMap, addr: &net::SocketAddr, factor: f64) -> bool {
+    let load = match available.get(addr) {
+        None => return true,
+        Some(ep) => ep.load() + 1,
+    };
+    let total = available.values().map(|ep| ep.load()).sum::<usize>() + 1;
+    let avg = total as f64 / available.len() as f64;
+    load as f64 > factor * avg
+}
+
+/// Records the outcome of a connection attempt with the circuit breaker, if any.
+fn record_connect(breaker: &Option<Rc<RefCell<CircuitBreaker>>>, success: bool) {
+    if let Some(ref b) = *breaker {
+        b.borrow_mut().record(success);
+    }
+}
+
+/// Describes the available endpoints for a traced selection, before the selection's
+/// strategy reports how it scored them.
+fn explain_candidates(
+    available: &EndpointMap,
+    now: Instant,
+    ewma: bool,
+) -> state::SelectionExplain {
+    let candidates = available
+        .values()
+        .take(MAX_TRACED_CANDIDATES)
+        .map(|ep| {
+            let ep_state = ep.state();
+            let ewma_ms = match (ep_state.latency, ep_state.first_byte) {
+                (None, None) => None,
+                (l, f) => {
+                    let ms = |l: Option<ewma::Latency>| l.map_or(0.0, |l| l.estimate_ms());
+                    Some(ms(l) + ms(f))
+                }
+            };
+            state::CandidateExplain {
+                addr: ep.peer_addr(),
+                load: ep_state.load(),
+                weight: ep.weight(),
+                ewma_ms,
+                backing_off: ep.backoff_until(now).is_some(),
+                score: None,
+            }
+        })
+        .collect();
+    state::SelectionExplain {
+        strategy: if ewma { "ewma" } else { "leastLoaded" },
+        locality: None,
+        candidates,
+        omitted_candidates: available.len().saturating_sub(MAX_TRACED_CANDIDATES),
+        chosen: None,
+        chosen_score: None,
+    }
+}
+
+/// Selects an endpoint from `candidates`, preferring endpoints in the local zone if
+/// locality is configured. When `explain` is given, the selection's inputs are recorded
+/// in it.
+///
+/// The RNG is released before an endpoint is returned, since failed connections use it
+/// to jitter their backoff.
+fn select<'e>(
+    rng: &SharedRng,
+    candidates: &[&'e Endpoint],
+    locality: Option<&Locality>,
+    scorer: Option<&Scorer>,
+    metrics: &Metrics,
+    explain: Option<&mut state::SelectionExplain>,
+) -> Option<&'e Endpoint> {
+    let t0 = Instant::now();
+    let ep = select_timed(rng, candidates, locality, scorer, metrics, explain);
+    metrics.selection_time.record_since(t0);
+    ep
+}
+
+fn select_timed<'e>(
+    rng: &SharedRng,
+    candidates: &[&'e Endpoint],
+    locality: Option<&Locality>,
+    scorer: Option<&Scorer>,
+    metrics: &Metrics,
+    explain: Option<&mut state::SelectionExplain>,
+) -> Option<&'e Endpoint> {
+    match locality {
+        None => select_endpoint(&mut *rng.borrow_mut(), candidates, scorer, explain),
+        Some(locality) => {
+            let ep = {
+                let mut rng = rng.borrow_mut();
+                select_local_endpoint(&mut *rng, candidates, locality, scorer, explain)
+            };
+            if let Some(ep) = ep {
+                if is_local(ep, locality) {
+                    metrics.local_selections.incr(1);
+                } else {
+                    metrics.remote_selections.incr(1);
+                }
+            }
+            ep
+        }
+    }
+}
+
+/// The ways in which a dispatcher may select endpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    /// The lesser-loaded of two random endpoints.
+    LeastLoaded,
+    /// The better EWMA-scored of two random endpoints.
+    Ewma,
+    /// The lesser-loaded of two random endpoints in the local zone, spilling over to all
+    /// endpoints when the local zone is overloaded.
+    Locality,
+}
+
+/// Makes `selections` endpoint selections among `endpoints` endpoints with `strategy`,
+/// as the dispatcher makes them, and returns the time taken.
+///
+/// Each selection is made as though it were the only one in a dispatcher poll, so the EWMA
+/// scorer is built for every selection. Half of the endpoints are in the local zone.
+pub fn time_selections(
+    strategy: SelectionStrategy,
+    endpoints: usize,
+    selections: usize,
+) -> Duration {
+    let zone_key = "zone".to_owned();
+    let eps = (0..endpoints)
+        .map(|i| {
+            let ip = net::Ipv4Addr::new(10, (i >> 16) as u8, (i >> 8) as u8, i as u8);
+            let addr = net::SocketAddr::new(net::IpAddr::V4(ip), 8080);
+            let zone = if i % 2 == 0 { "local" } else { "remote" };
+            let mut meta = BTreeMap::new();
+            meta.insert(zone_key.clone(), zone.to_owned());
+            endpoint::new(addr, 1.0, meta)
+        })
+        .collect::<Vec<_>>();
+    let candidates = eps.iter().collect::<Vec<_>>();
+
+    let ewma = Ewma { decay: Duration::from_secs(10) };
+    let locality = Locality {
+        zone: "local".to_owned(),
+        meta_key: zone_key,
+        spillover_load_factor: 2.0,
+    };
+    let locality = if strategy == SelectionStrategy::Locality {
+        Some(&locality)
+    } else {
+        None
+    };
+    let rng = Rc::new(RefCell::new(StdRng::from_seed(&[endpoints][..])));
+    let noop = Metrics::new(&metrics::Scope::noop());
+
+    let t0 = Instant::now();
+    for _ in 0..selections {
+        let scorer = if strategy == SelectionStrategy::Ewma {
+            Some(Scorer::new(&ewma, &candidates))
+        } else {
+            None
+        };
+        select(&rng, &candidates, locality, scorer.as_ref(), &noop, None);
+    }
+    t0.elapsed()
+}
+
 /// Selects an endpoint using the power of two choices.
 ///
-/// Two endpoints are chosen randomly and return the lesser-loaded endpoint.
+/// Two endpoints are chosen randomly and return the lesser-loaded endpoint, or the
+/// better-scored endpoint when a `scorer` is given.
 /// If no endpoints are available, `None` is retruned.
-fn select_endpoint<'r, 'e, R: Rng>(
+pub(super) fn select_endpoint<'r, 'e, R: Rng>(
+    rng: &'r mut R,
+    candidates: &[&'e Endpoint],
+    scorer: Option<&Scorer>,
+    explain: Option<&mut state::SelectionExplain>,
+) -> Option<&'e Endpoint> {
+    p2c(rng, candidates.len(), |i| candidates[i], scorer, explain)
+}
+
+/// Selects an endpoint, preferring endpoints in the local zone.
+///
+/// Local endpoints are used unless there are none or their average load exceeds the
+/// global average load by more than the spillover factor, in which case all candidate
+/// endpoints are considered.
+fn select_local_endpoint<'r, 'e, R: Rng>(
     rng: &'r mut R,
-    available: &'e EndpointMap,
+    candidates: &[&'e Endpoint],
+    locality: &Locality,
+    scorer: Option<&Scorer>,
+    mut explain: Option<&mut state::SelectionExplain>,
 ) -> Option<&'e Endpoint> {
-    match available.len() {
+    let mut local = Vec::new();
+    let mut local_load = 0;
+    let mut total_load = 0;
+    for &ep in candidates {
+        let load = ep.load();
+        total_load += load;
+        if is_local(ep, locality) {
+            local_load += load;
+            local.push(ep);
+        }
+    }
+
+    let local_avg = if local.is_empty() {
+        0.0
+    } else {
+        local_load as f64 / local.len() as f64
+    };
+    let global_avg = if candidates.is_empty() {
+        0.0
+    } else {
+        total_load as f64 / candidates.len() as f64
+    };
+    let prefer_local = !local.is_empty() &&
+        local_avg <= locality.spillover_load_factor * global_avg;
+    if let Some(ref mut e) = explain {
+        e.locality = Some(state::LocalityExplain {
+            zone: locality.zone.clone(),
+            local_candidates: local.len(),
+            local_avg_load: local_avg,
+            global_avg_load: global_avg,
+            spilled_over: !prefer_local,
+        });
+    }
+    if prefer_local {
+        return p2c(rng, local.len(), |i| local[i], scorer, explain);
+    }
+    if !local.is_empty() {
+        trace!(
+            "spilling over from {}: local load {} exceeds {}*{}",
+            locality.zone,
+            local_avg,
+            locality.spillover_load_factor,
+            global_avg
+        );
+    }
+
+    select_endpoint(rng, candidates, scorer, explain)
+}
+
+/// Prepares resolved weights for `mode`, returning the number of addresses dropped for
+/// having negative or non-finite weights.
+///
+/// Normalized weights are scaled to sum to 1.0. Absolute weights are left as resolved.
+fn weigh(addrs: &mut Vec<WeightedAddr>, mode: WeightMode) -> usize {
+    let resolved = addrs.len();
+    addrs.retain(|wa| wa.weight.is_finite() && wa.weight >= 0.0);
+    let invalid = resolved - addrs.len();
+    if mode == WeightMode::Normalized {
+        let sum = addrs.iter().fold(0.0, |sum, wa| sum + wa.weight);
+        if sum > 0.0 {
+            for wa in addrs.iter_mut() {
+                wa.weight /= sum;
+            }
+        }
+    }
+    invalid
+}
+
+/// Scores an endpoint for `io.l5d.leastLoaded`; lower scores are preferred.
+///
+/// A normalized weight is the endpoint's share of the destination, and so is
+/// discounted from its load; an absolute weight divides its load, so that connections are
+/// spread in proportion to weights.
+fn least_loaded_score(mode: WeightMode, load: usize, weight: f64) -> f64 {
+    match mode {
+        WeightMode::Normalized => (load + 1) as f64 * (1.0 - weight),
+        WeightMode::Absolute => (load + 1) as f64 / weight,
+    }
+}
+
+fn is_local(ep: &Endpoint, locality: &Locality) -> bool {
+    ep.meta().get(&locality.meta_key) == Some(&locality.zone)
+}
+
+/// Chooses the lesser-loaded (or, given a `scorer`, better-scored) of two random
+/// endpoints from `sz` candidates, recording both scores in `explain`, if it is given.
+fn p2c<'r, 'e, R, F>(
+    rng: &'r mut R,
+    sz: usize,
+    get: F,
+    scorer: Option<&Scorer>,
+    explain: Option<&mut state::SelectionExplain>,
+) -> Option<&'e Endpoint>
+where
+    R: Rng,
+    F: Fn(usize) -> &'e Endpoint,
+{
+    match sz {
         0 => None,
         1 => {
             // One endpoint, use it.
-            available.get_index(0).map(|(_, ep)| ep)
+            let ep = get(0);
+            if let Some(e) = explain {
+                e.chose(ep.peer_addr(), None);
+            }
+            Some(ep)
         }
         sz => {
             // Pick 2 candidate indices.
@@ -400,21 +1792,37 @@ fn select_endpoint<'r, 'e, R: Rng>(
             };
 
             // Determine the the scores of each endpoint
-            let (addr0, ep0) = available.get_index(i0).unwrap();
+            let ep0 = get(i0);
             let (load0, weight0) = (ep0.load(), ep0.weight());
-            let score0 = (load0 + 1) as f64 * (1.0 - weight0);
+            let score0 = match scorer {
+                None => least_loaded_score(ep0.weight_mode(), load0, weight0),
+                Some(s) => s.score(ep0),
+            };
 
-            let (addr1, ep1) = available.get_index(i1).unwrap();
+            let ep1 = get(i1);
             let (load1, weight1) = (ep1.load(), ep1.weight());
-            let score1 = (load1 + 1) as f64 * (1.0 - weight1);
+            let score1 = match scorer {
+                None => least_loaded_score(ep1.weight_mode(), load1, weight1),
+                Some(s) => s.score(ep1),
+            };
+
+            if let Some(e) = explain {
+                e.scored(ep0.peer_addr(), score0);
+                e.scored(ep1.peer_addr(), score1);
+                if score0 <= score1 {
+                    e.chose(ep0.peer_addr(), Some(score0));
+                } else {
+                    e.chose(ep1.peer_addr(), Some(score1));
+                }
+            }
 
             if score0 <= score1 {
                 trace!(
                     "dst: {} {}*{} (not {} {}*{})",
-                    addr0,
+                    ep0.peer_addr(),
                     load0,
                     weight0,
-                    addr1,
+                    ep1.peer_addr(),
                     load1,
                     weight1
                 );
@@ -422,10 +1830,10 @@ fn select_endpoint<'r, 'e, R: Rng>(
             } else {
                 trace!(
                     "dst: {} {}*{} (not {} {}*{})",
-                    addr1,
+                    ep1.peer_addr(),
                     load1,
                     weight1,
-                    addr0,
+                    ep0.peer_addr(),
                     load0,
                     weight0
                 );
@@ -435,44 +1843,234 @@ fn select_endpoint<'r, 'e, R: Rng>(
     }
 }
 
+/// Identifies an endpoint's connection gauges by its address and its `meta` label.
+type EndpointKey = (net::SocketAddr, Option<String>);
+
+/// Sums the open and pending connections of a destination's endpoints.
+struct Conns {
+    open: usize,
+    pending: usize,
+    /// Open and pending connections for each endpoint, if endpoint metrics are reported.
+    by_addr: Option<HashMap<EndpointKey, (usize, usize)>>,
+    /// The metadata keys from which endpoints' `meta` labels are formed.
+    meta_keys: Rc<Vec<String>>,
+}
+
+impl Conns {
+    /// Counts connections for each endpoint when `meta_keys` are given.
+    fn new(meta_keys: Option<Rc<Vec<String>>>) -> Conns {
+        Conns {
+            open: 0,
+            pending: 0,
+            by_addr: meta_keys.as_ref().map(|_| HashMap::new()),
+            meta_keys: meta_keys.unwrap_or_default(),
+        }
+    }
+
+    fn add(&mut self, ep: &Endpoint) {
+        let state = ep.state();
+        self.open += state.open_conns;
+        self.pending += state.pending_conns;
+        if let Some(ref mut by_addr) = self.by_addr {
+            let key = (ep.peer_addr(), meta_label(ep, &self.meta_keys));
+            let conns = by_addr.entry(key).or_insert((0, 0));
+            conns.0 += state.open_conns;
+            conns.1 += state.pending_conns;
+        }
+    }
+}
+
+/// Describes `ep`'s values for each of `keys`, e.g. `nodeName=n1,tier=ssd`, or `None`
+/// when there are no keys. Missing values are empty.
+fn meta_label(ep: &Endpoint, keys: &[String]) -> Option<String> {
+    if keys.is_empty() {
+        return None;
+    }
+    let meta = ep.meta();
+    let values: Vec<String> = keys.iter()
+        .map(|k| {
+            let v = meta.get(k).map(|v| v.as_str()).unwrap_or("");
+            format!("{}={}", k, v)
+        })
+        .collect();
+    Some(values.join(","))
+}
+
+/// Reports open and pending connections for each endpoint, labeled by `addr` and, when
+/// metadata keys are configured, by `meta`.
+///
+/// No more than `MAX_META_LABELS` distinct `meta` labels are reported at once; endpoints
+/// whose labels would exceed this are labeled `other`.
+struct EndpointMetrics {
+    scope: metrics::Scope,
+    meta_keys: Rc<Vec<String>>,
+    gauges: HashMap<EndpointKey, EndpointGauges>,
+    /// The number of endpoints labeled `other`.
+    meta_overflow: Arc<metrics::Gauge>,
+}
+
+struct EndpointGauges {
+    open: Arc<metrics::Gauge>,
+    pending: Arc<metrics::Gauge>,
+}
+
+impl EndpointMetrics {
+    fn new(base: &metrics::Scope, meta_keys: &[String]) -> EndpointMetrics {
+        let scope = base.clone().prefixed("endpoint");
+        EndpointMetrics {
+            meta_overflow: scope.gauge("meta_label_overflow"),
+            scope,
+            meta_keys: Rc::new(meta_keys.to_vec()),
+            gauges: HashMap::new(),
+        }
+    }
+
+    fn report(&mut self, conns: &HashMap<EndpointKey, (usize, usize)>) {
+        // Labels that are already reported are kept, so that endpoints are only labeled
+        // `other` when their labels are new.
+        let mut labels: HashSet<String> = {
+            let reported: HashSet<&String> =
+                self.gauges.keys().filter_map(|k| k.1.as_ref()).collect();
+            conns
+                .keys()
+                .filter_map(|k| k.1.as_ref())
+                .filter(|l| reported.contains(l))
+                .cloned()
+                .collect()
+        };
+        let mut overflowed = 0;
+        let mut by_key: HashMap<EndpointKey, (usize, usize)> = HashMap::new();
+        for (&(addr, ref label), &(open, pending)) in conns {
+            let label = label.as_ref().map(|l| {
+                if labels.contains(l) || labels.len() < MAX_META_LABELS {
+                    labels.insert(l.clone());
+                    l.clone()
+                } else {
+                    overflowed += 1;
+                    OVERFLOW_META_LABEL.to_owned()
+                }
+            });
+            let conns = by_key.entry((addr, label)).or_insert((0, 0));
+            conns.0 += open;
+            conns.1 += pending;
+        }
+        self.meta_overflow.set(overflowed);
+
+        for (key, &(open, pending)) in &by_key {
+            let scope = &self.scope;
+            let gauges = self.gauges.entry(key.clone()).or_insert_with(|| {
+                let mut scope = scope.clone().labeled("addr", key.0);
+                if let Some(ref label) = key.1 {
+                    scope = scope.labeled("meta", label);
+                }
+                EndpointGauges {
+                    open: scope.gauge("open_conns"),
+                    pending: scope.gauge("pending_conns"),
+                }
+            });
+            gauges.open.set(open);
+            gauges.pending.set(pending);
+        }
+
+        // Endpoints that have been removed (or relabeled) no longer have any connections.
+        self.gauges.retain(|key, gauges| if by_key.contains_key(key) {
+            true
+        } else {
+            gauges.open.set(0);
+            gauges.pending.set(0);
+            false
+        });
+    }
+}
+
 struct Metrics {
-    available: tacho::Gauge,
-    failed: tacho::Gauge,
-    retired: tacho::Gauge,
-    pending: tacho::Gauge,
-    open: tacho::Gauge,
-    waiters: tacho::Gauge,
-    poll_time: tacho::Timer,
-    attempts: tacho::Counter,
-    unavailable: tacho::Counter,
-    connects: tacho::Counter,
-    timeouts: tacho::Counter,
-    refused: tacho::Counter,
-    failures: tacho::Counter,
-    connect_latency: tacho::Timer,
-    connection_duration: tacho::Timer,
+    available: Arc<metrics::Gauge>,
+    failed: Arc<metrics::Gauge>,
+    retired: Arc<metrics::Gauge>,
+    ejected: Arc<metrics::Gauge>,
+    /// Counts resolutions applied to the endpoints.
+    updates: Arc<metrics::Counter>,
+    /// Set while resolutions flap and the endpoints are frozen.
+    flap_detected: Arc<metrics::Gauge>,
+    /// The number of resolutions that changed the endpoints.
+    generation: Arc<metrics::Gauge>,
+    filtered_cidr: Arc<metrics::Counter>,
+    filtered_meta: Arc<metrics::Counter>,
+    subset_size: Arc<metrics::Gauge>,
+    subset_additions: Arc<metrics::Counter>,
+    subset_removals: Arc<metrics::Counter>,
+    pending: Arc<metrics::Gauge>,
+    open: Arc<metrics::Gauge>,
+    waiters: Arc<metrics::Gauge>,
+    poll_time: Arc<metrics::Timer>,
+    /// The time taken to select each endpoint.
+    selection_time: Arc<metrics::Timer>,
+    /// Counts polls that found `max_waiters` requests already buffered.
+    busy: Arc<metrics::Counter>,
+    attempts: Arc<metrics::Counter>,
+    unavailable: Arc<metrics::Counter>,
+    local_selections: Arc<metrics::Counter>,
+    remote_selections: Arc<metrics::Counter>,
+    connects: Arc<metrics::Counter>,
+    sessions: Arc<metrics::Counter>,
+    timeouts: Arc<metrics::Counter>,
+    refused: Arc<metrics::Counter>,
+    failures: Arc<metrics::Counter>,
+    connect_latency: Arc<metrics::Timer>,
+    pool_reaped: Arc<metrics::Counter>,
+    pool_expired: Arc<metrics::Counter>,
+    pool_invalid: Arc<metrics::Counter>,
+    pool_valid: Arc<metrics::Counter>,
+    /// Counts bytes read ahead on ready connections.
+    pool_read_ahead: Arc<metrics::Counter>,
+    pool_peer_closed: Arc<metrics::Counter>,
+    rebalance_closures: Arc<metrics::Counter>,
+    /// Counts changes to the remote policy applied to the destination.
+    remote_policy_updates: Arc<metrics::Counter>,
 }
 
 impl Metrics {
-    fn new(base: &tacho::Scope) -> Metrics {
+    fn new(base: &metrics::Scope) -> Metrics {
         let ep = base.clone().prefixed("endpoint");
         let conn = base.clone().prefixed("connection");
+        let pool = base.clone().prefixed("pool");
         Metrics {
             available: ep.gauge("available"),
             failed: ep.gauge("failed"),
             retired: ep.gauge("retired"),
+            ejected: ep.gauge("ejected"),
+            updates: ep.counter("updates"),
+            flap_detected: base.gauge("flap_detected"),
+            generation: ep.gauge("generation"),
+            filtered_cidr: ep.clone().labeled("cause", "cidr").counter("filtered"),
+            filtered_meta: ep.clone().labeled("cause", "meta").counter("filtered"),
+            subset_size: ep.gauge("subset_size"),
+            subset_additions: ep.counter("subset_additions"),
+            subset_removals: ep.counter("subset_removals"),
             pending: conn.gauge("pending"),
             open: conn.gauge("open"),
             waiters: base.gauge("waiters"),
             poll_time: base.timer_us("poll_time_us"),
+            selection_time: base.timer_us("selection_time_us"),
+            busy: base.counter("dispatch_busy"),
             unavailable: base.counter("unavailable"),
+            local_selections: base.clone().labeled("locality", "local").counter("selections"),
+            remote_selections: base.clone().labeled("locality", "remote").counter("selections"),
             attempts: conn.counter("attempts"),
             connects: conn.counter("connects"),
+            sessions: base.counter("session_assignments"),
             timeouts: conn.clone().labeled("cause", "timeout").counter("failure"),
             refused: conn.clone().labeled("cause", "refused").counter("failure"),
             failures: conn.clone().labeled("cause", "other").counter("failure"),
             connect_latency: conn.timer_us("latency_us"),
-            connection_duration: conn.timer_ms("duration_ms"),
+            pool_reaped: pool.clone().labeled("cause", "idle_timeout").counter("closes"),
+            pool_expired: pool.clone().labeled("cause", "max_lifetime").counter("closes"),
+            pool_invalid: pool.clone().labeled("result", "closed").counter("validations"),
+            pool_valid: pool.clone().labeled("result", "ok").counter("validations"),
+            pool_read_ahead: pool.counter("read_ahead_bytes"),
+            pool_peer_closed: pool.clone().labeled("cause", "peer_closed").counter("closes"),
+            rebalance_closures: base.counter("rebalance_closures"),
+            remote_policy_updates: base.counter("remote_policy_updates"),
         }
     }
 
@@ -484,3 +2082,87 @@ impl Metrics {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::select_local_endpoint;
+    use super::super::endpoint::{self, Endpoint};
+    use super::super::super::connector::ConnectorConfig;
+    use super::super::super::state;
+    use rand::{SeedableRng, StdRng};
+    use serde_json;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    /// Selects among two endpoints in the local zone, `a`, each with `local_load` open
+    /// connections, and two in zone `b`, each with 10. Returns the selected endpoint and
+    /// whether selection spilled over from the local zone.
+    fn select_two_zones(local_load: usize, seed: usize) -> (SocketAddr, bool) {
+        let config: ConnectorConfig = serde_json::from_str(
+            "{\"localityAware\": {\"localZone\": \"a\", \"spilloverLoadFactor\": 1.5}}",
+        ).expect("failed to parse connector config");
+        let connector = config.mk_connector().expect("invalid connector config");
+        let locality = connector.locality().expect("localityAware is not configured");
+        let zones = [
+            (addr(1), "a", local_load),
+            (addr(2), "a", local_load),
+            (addr(3), "b", 10),
+            (addr(4), "b", 10),
+        ];
+        let endpoints: Vec<Endpoint> = zones
+            .iter()
+            .map(|&(addr, zone, load)| {
+                let mut meta = BTreeMap::new();
+                meta.insert(locality.meta_key.clone(), zone.to_owned());
+                let ep = endpoint::new(addr, 1.0, meta);
+                ep.state_mut().open_conns = load;
+                ep
+            })
+            .collect();
+        let candidates: Vec<&Endpoint> = endpoints.iter().collect();
+        let mut explain = state::SelectionExplain {
+            strategy: "leastLoaded",
+            locality: None,
+            candidates: Vec::new(),
+            omitted_candidates: 0,
+            chosen: None,
+            chosen_score: None,
+        };
+        let mut rng = StdRng::from_seed(&[seed][..]);
+        let chosen =
+            select_local_endpoint(&mut rng, &candidates, locality, None, Some(&mut explain))
+                .map(|ep| ep.peer_addr())
+                .expect("no endpoint selected");
+        let spilled_over = explain.locality.map(|l| l.spilled_over).unwrap_or(false);
+        (chosen, spilled_over)
+    }
+
+    #[test]
+    fn spills_over_once_the_local_zone_exceeds_the_load_factor() {
+        // The local average load, L, is compared with 1.5 times the global average,
+        // (L + 10) / 2. At 30 they are equal, so the local zone is still preferred; at 31 the
+        // local load first exceeds the factor.
+        for local_load in 0..31 {
+            for seed in 0..10 {
+                let (chosen, spilled_over) = select_two_zones(local_load, seed);
+                assert!(!spilled_over, "spilled over with a local load of {}", local_load);
+                assert!(chosen == addr(1) || chosen == addr(2), "selected {}", chosen);
+            }
+        }
+
+        // Once saturated, the local zone's endpoints compete with the less-loaded remote ones.
+        let mut remote = 0;
+        for seed in 0..10 {
+            let (chosen, spilled_over) = select_two_zones(31, seed);
+            assert!(spilled_over, "did not spill over with a local load of 31");
+            if chosen == addr(3) || chosen == addr(4) {
+                remote += 1;
+            }
+        }
+        assert!(remote > 0, "never selected a remote endpoint");
+    }
+}
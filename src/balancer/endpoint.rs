@@ -1,18 +1,39 @@
-use super::super::connection::{Connection as _Connection, ctx};
-use super::super::connector;
-use futures::{Future, Poll};
-use std::{io, net};
-use std::cell::{Ref, RefCell};
+use super::super::connection::{Connection as _Connection, Eviction, ctx};
+use super::super::connector::{self, WeightMode};
+use super::super::log_limit::{LogLimit, Verdict};
+use super::super::metrics;
+use super::super::state::{EndpointFailureState, EndpointState};
+use super::SharedRng;
+use super::circuit::CircuitBreaker;
+use super::global_limit;
+use super::ewma::Latency;
+use super::generation::Generation;
+use super::histogram::Histogram;
+use super::stats::ConnectWindow;
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use std::{cmp, io, net};
+use std::collections::BTreeMap;
+use std::cell::{Ref, RefCell, RefMut};
 use std::rc::Rc;
-use std::time::Instant;
-use tacho;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub type Connection = _Connection<Ctx>;
 
-pub fn new(peer_addr: net::SocketAddr, weight: f64) -> Endpoint {
+/// The fraction of its weight given to an endpoint as soon as it is added during slow
+/// start.
+const SLOW_START_MIN_WEIGHT: f64 = 0.1;
+
+pub fn new(peer_addr: net::SocketAddr, weight: f64, meta: BTreeMap<String, String>) -> Endpoint {
     Endpoint {
         peer_addr,
         weight,
+        weight_mode: WeightMode::Normalized,
+        weight_multiplier: None,
+        meta,
+        penalty: None,
+        warming: None,
         state: Rc::new(RefCell::new(State::default())),
     }
 }
@@ -24,6 +45,50 @@ pub struct State {
     pub consecutive_failures: usize,
     pub rx_bytes: usize,
     pub tx_bytes: usize,
+
+    /// Set while an endpoint is on probation after being failed.
+    pub probation: Option<Probation>,
+
+    /// Set after a connection attempt fails, until a connection succeeds.
+    pub backoff: Option<Backoff>,
+
+    /// Set once a connection has been established, when latencies are measured.
+    pub latency: Option<Latency>,
+
+    /// Set once an endpoint has sent its first byte on a connection, when latencies are
+    /// measured.
+    pub first_byte: Option<Latency>,
+
+    /// The time from each connection's establishment to the first byte read from it.
+    first_bytes: Histogram,
+
+    /// Connection attempts within the connector's stats window.
+    connects: ConnectWindow,
+
+    /// The most recent failed connection attempt, if any.
+    last_failure: Option<EndpointFailureState>,
+
+    /// Open connections, oldest first, so that they may be evicted when rebalancing.
+    evictions: BTreeMap<u64, Eviction>,
+    next_conn_id: u64,
+}
+
+/// Delays new connections to an endpoint after connection failures.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// The number of consecutive failed connection attempts.
+    pub failures: u32,
+    pub delay: Duration,
+    pub until: Instant,
+}
+
+/// Tracks the connections made by an endpoint that was recently failed.
+#[derive(Clone, Copy, Debug)]
+pub struct Probation {
+    /// The number of consecutive successes required for reinstatement.
+    pub required: usize,
+    pub successes: usize,
+    pub failed: bool,
 }
 
 impl State {
@@ -33,12 +98,174 @@ impl State {
     pub fn is_idle(&self) -> bool {
         self.open_conns == 0
     }
+
+    /// The number of open connections that have not been evicted.
+    pub fn unevicted_conns(&self) -> usize {
+        self.evictions.values().filter(|e| !e.is_evicted()).count()
+    }
+
+    /// Records an attempt to reach the endpoint that failed at `now`, backing it off if
+    /// configured.
+    pub(crate) fn failed(
+        &mut self,
+        now: Instant,
+        peer_addr: net::SocketAddr,
+        e: &io::Error,
+        stats_window: Duration,
+        backoff: Option<connector::ConnectBackoff>,
+        rng: &SharedRng,
+    ) {
+        self.consecutive_failures += 1;
+        self.connects.record(now, stats_window, false);
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_failure = Some(EndpointFailureState {
+            error: e.to_string(),
+            at_ms: at.as_secs() * 1_000 + u64::from(at.subsec_nanos() / 1_000_000),
+        });
+        if let Some(ref mut p) = self.probation {
+            p.failed = true;
+        }
+        if let Some(policy) = backoff {
+            let failures = self.backoff.map(|b| b.failures).unwrap_or(0) + 1;
+            let delay = policy.jittered_delay(failures, &mut *rng.borrow_mut());
+            debug!("{}: backing off for {:?}", peer_addr, delay);
+            self.backoff = Some(Backoff {
+                failures,
+                delay,
+                until: now + delay,
+            });
+        }
+    }
+
+    /// Records a successful attempt to reach the endpoint.
+    pub(crate) fn succeeded(&mut self, stats_window: Duration) {
+        self.connects.record(Instant::now(), stats_window, true);
+        // The failure count is only reset once an endpoint on probation has been
+        // fully reinstated.
+        match self.probation {
+            Some(ref mut p) => p.successes += 1,
+            None => self.consecutive_failures = 0,
+        }
+        self.backoff = None;
+    }
+}
+
+/// Measures the time from each connection's establishment to the first byte that its
+/// endpoint sends.
+#[derive(Clone)]
+pub struct FirstByteMetrics {
+    pub latency: Arc<metrics::Timer>,
+    /// Counts connections that were written to but closed before their endpoint sent
+    /// anything. These are not reflected in `latency`.
+    pub no_response: Arc<metrics::Counter>,
+}
+
+/// How a dispatcher's connections and sessions are accounted to their endpoints.
+#[derive(Clone)]
+pub struct Accounting {
+    /// Measures how long connections and sessions are open.
+    pub duration: Arc<metrics::Timer>,
+    /// The window over which endpoints' connection attempts are summarized.
+    pub stats_window: Duration,
+    /// When set, endpoints are skipped for a time after their connection attempts fail.
+    pub backoff: Option<connector::ConnectBackoff>,
+    /// Jitters backoffs.
+    pub rng: SharedRng,
+    pub failure_log: FailureLog,
+    /// Counts connection counts that would have been released more than once.
+    pub errors: Arc<metrics::Counter>,
+    pub first_byte: FirstByteMetrics,
+}
+
+/// Counts a connection attempt as pending on its endpoint until it is dropped.
+///
+/// Connection counts are only changed by tokens, so that each increment is undone exactly
+/// once, however a connection attempt or connection ends (including by being dropped
+/// before it completes).
+pub struct PendingToken {
+    state: Rc<RefCell<State>>,
+    accounting_errors: Arc<metrics::Counter>,
+}
+
+impl PendingToken {
+    fn new(state: &Rc<RefCell<State>>, accounting_errors: &Arc<metrics::Counter>) -> PendingToken {
+        state.borrow_mut().pending_conns += 1;
+        PendingToken {
+            state: state.clone(),
+            accounting_errors: accounting_errors.clone(),
+        }
+    }
+
+    /// Counts the attempt's connection as open, rather than pending.
+    fn open(self) -> OpenToken {
+        OpenToken::new(&self.state, &self.accounting_errors)
+    }
+}
+
+impl Drop for PendingToken {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        release(&mut state.pending_conns, "pending", &self.accounting_errors);
+    }
+}
+
+/// Counts a connection (or a datagram session) as open on its endpoint until it is
+/// dropped.
+pub struct OpenToken {
+    state: Rc<RefCell<State>>,
+    accounting_errors: Arc<metrics::Counter>,
+}
+
+impl OpenToken {
+    fn new(state: &Rc<RefCell<State>>, accounting_errors: &Arc<metrics::Counter>) -> OpenToken {
+        state.borrow_mut().open_conns += 1;
+        OpenToken {
+            state: state.clone(),
+            accounting_errors: accounting_errors.clone(),
+        }
+    }
+}
+
+impl Drop for OpenToken {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        release(&mut state.open_conns, "open", &self.accounting_errors);
+    }
+}
+
+/// Decrements a connection count. A count that would underflow indicates an accounting
+/// bug; it is counted and logged, rather than panicking, in release builds.
+fn release(count: &mut usize, name: &str, accounting_errors: &Arc<metrics::Counter>) {
+    debug_assert!(*count > 0, "{} connection count underflow", name);
+    match count.checked_sub(1) {
+        Some(c) => *count = c,
+        None => {
+            error!("{} connection count underflow", name);
+            accounting_errors.incr(1);
+        }
+    }
 }
 
 /// Represents a single concrete traffic destination
 pub struct Endpoint {
     peer_addr: net::SocketAddr,
     weight: f64,
+
+    /// Determines the range of `weight` and how it is scored.
+    weight_mode: WeightMode,
+
+    /// Scales `weight` while an operator overrides it.
+    weight_multiplier: Option<f64>,
+
+    meta: BTreeMap<String, String>,
+
+    /// The penalty most recently applied to this endpoint, until it is fully reinstated.
+    penalty: Option<Duration>,
+
+    /// Set when the endpoint was added during slow start: the time at which it was
+    /// added and the window over which its weight is ramped up.
+    warming: Option<(Instant, Duration)>,
+
     state: Rc<RefCell<State>>,
 }
 
@@ -51,91 +278,735 @@ impl Endpoint {
         self.state.borrow()
     }
 
+    pub(crate) fn state_mut(&self) -> RefMut<State> {
+        self.state.borrow_mut()
+    }
+
     // TODO we should be able to use throughput/bandwidth as well.
     pub fn load(&self) -> usize {
         self.state.borrow().load()
     }
 
-    pub fn set_weight(&mut self, w: f64) {
-        assert!(0.0 <= w && w <= 1.0);
+    /// Sets the endpoint's resolved weight, which is within [0.0, 1.0] when weights are
+    /// normalized, and is finite and non-negative when they are absolute.
+    pub fn set_weight(&mut self, w: f64, mode: WeightMode) {
+        match mode {
+            WeightMode::Normalized => assert!(0.0 <= w && w <= 1.0),
+            WeightMode::Absolute => assert!(w.is_finite() && 0.0 <= w),
+        }
         self.weight = w;
+        self.weight_mode = mode;
     }
 
+    pub fn weight_mode(&self) -> WeightMode {
+        self.weight_mode
+    }
+
+    /// Scales the endpoint's resolved weight, or stops doing so if `m` is `None`.
+    pub fn set_weight_multiplier(&mut self, m: Option<f64>) {
+        if m != self.weight_multiplier {
+            match m {
+                Some(m) => info!("{}: weight multiplier set to {}", self.peer_addr, m),
+                None => info!("{}: weight multiplier cleared", self.peer_addr),
+            }
+            self.weight_multiplier = m;
+        }
+    }
+
+    /// The endpoint's weight, scaled by any operator override (up to 1.0, when weights
+    /// are normalized) and reduced while it is on probation or warming up.
+    ///
+    /// The weight ramps up as the endpoint completes successful connections, so that it
+    /// only receives its full share of traffic once fully reinstated. A warming endpoint's
+    /// weight ramps up linearly from `SLOW_START_MIN_WEIGHT` over its window.
     pub fn weight(&self) -> f64 {
-        self.weight
-    }
-
-    pub fn connect(&self, sock: connector::Connecting, duration: &tacho::Timer) -> Connecting {
-        let conn = {
-            let peer_addr = self.peer_addr;
-            let state = self.state.clone();
-            let duration = duration.clone();
-            debug!("{}: connecting", peer_addr);
-            sock.then(move |res| match res {
-                Err(e) => {
-                    error!("{}: connection failed: {}", peer_addr, e);
-                    let mut s = state.borrow_mut();
-                    s.consecutive_failures += 1;
-                    s.pending_conns -= 1;
-                    Err(e)
-                }
-                Ok(sock) => {
-                    debug!("{}: connected", peer_addr);
-                    {
-                        let mut s = state.borrow_mut();
-                        s.consecutive_failures = 0;
-                        s.pending_conns -= 1;
-                        s.open_conns += 1;
-                    }
-                    let ctx = Ctx {
-                        state,
-                        duration,
-                        start: Instant::now(),
-                    };
-                    Ok(Connection::new(sock, ctx))
-                }
-            })
+        let base = match (self.weight_multiplier, self.weight_mode) {
+            (None, _) => self.weight,
+            (Some(m), WeightMode::Normalized) => (self.weight * m).min(1.0),
+            (Some(m), WeightMode::Absolute) => self.weight * m,
+        };
+        let weight = match self.state.borrow().probation {
+            None => base,
+            // An endpoint that must succeed once to be reinstated has its full weight.
+            Some(p) => base * ((p.successes + 1) as f64 / p.required as f64).min(1.0),
         };
+        match self.warming {
+            None => weight,
+            Some((since, window)) => weight * warmup_ratio(since.elapsed(), window),
+        }
+    }
 
+    /// Ramps up the endpoint's weight over `window`, starting now.
+    pub fn start_warming(&mut self, window: Duration) {
+        self.warming = Some((Instant::now(), window));
+    }
+
+    /// Determines whether this endpoint should be failed.
+    ///
+    /// Endpoints on probation are failed as soon as any connection fails.
+    pub fn should_fail(&self, max_consecutive_failures: usize) -> bool {
+        let state = self.state.borrow();
+        match state.probation {
+            Some(p) => p.failed,
+            None => state.consecutive_failures >= max_consecutive_failures,
+        }
+    }
+
+    /// Determines the penalty for failing this endpoint.
+    ///
+    /// The penalty doubles, up to `max`, each time the endpoint fails on probation.
+    pub fn fail(&mut self, base: Duration, max: Duration) -> Duration {
+        let penalty = match self.penalty {
+            Some(p) if self.state.borrow().probation.is_some() => cmp::min(p * 2, max),
+            _ => base,
+        };
+        self.state.borrow_mut().probation = None;
+        self.penalty = Some(penalty);
+        penalty
+    }
+
+    pub fn penalty(&self) -> Duration {
+        self.penalty.unwrap_or_default()
+    }
+
+    /// Returns a failed endpoint to service on probation.
+    pub fn start_probation(&mut self, success_threshold: usize) {
+        self.state.borrow_mut().probation = Some(Probation {
+            required: success_threshold,
+            successes: 0,
+            failed: false,
+        });
+    }
+
+    /// Fully reinstates an endpoint once it has completed enough consecutive successful
+    /// connections on probation.
+    pub fn check_probation(&mut self) {
         let mut state = self.state.borrow_mut();
-        state.pending_conns += 1;
-        Connecting(Box::new(conn))
+        let reinstated = match state.probation {
+            Some(p) => !p.failed && p.successes >= p.required,
+            None => false,
+        };
+        if reinstated {
+            debug!("{}: reinstated", self.peer_addr);
+            state.probation = None;
+            state.consecutive_failures = 0;
+            self.penalty = None;
+        }
+    }
+
+    /// If the endpoint is backing off after connection failures, returns the time at
+    /// which it may be connected to again.
+    pub fn backoff_until(&self, now: Instant) -> Option<Instant> {
+        match self.state.borrow().backoff {
+            Some(b) if now < b.until => Some(b.until),
+            _ => None,
+        }
+    }
+
+    /// Metadata provided by service discovery.
+    pub fn meta(&self) -> &BTreeMap<String, String> {
+        &self.meta
+    }
+
+    pub fn set_meta(&mut self, meta: BTreeMap<String, String>) {
+        self.meta = meta;
+    }
+
+    pub fn connect(
+        &self,
+        sock: connector::Connecting,
+        accounting: &Accounting,
+        ewma: Option<connector::Ewma>,
+        permit: Option<global_limit::Permit>,
+    ) -> Connecting {
+        debug!("{}: connecting", self.peer_addr);
+        Connecting {
+            sock,
+            peer_addr: self.peer_addr,
+            state: self.state.clone(),
+            pending: Some(PendingToken::new(&self.state, &accounting.errors)),
+            duration: accounting.duration.clone(),
+            stats_window: accounting.stats_window,
+            backoff: accounting.backoff,
+            rng: accounting.rng.clone(),
+            start: Instant::now(),
+            ewma,
+            failure_log: accounting.failure_log.clone(),
+            first_byte: accounting.first_byte.clone(),
+            permit,
+        }
+    }
+
+    /// Assigns a datagram session to this endpoint. The session counts as an open
+    /// connection until it is dropped.
+    pub fn open_session(
+        &self,
+        accounting: &Accounting,
+        breaker: Option<Rc<RefCell<CircuitBreaker>>>,
+    ) -> Session {
+        debug!("{}: session opened", self.peer_addr);
+        Session {
+            peer_addr: self.peer_addr,
+            state: self.state.clone(),
+            _open: OpenToken::new(&self.state, &accounting.errors),
+            duration: accounting.duration.clone(),
+            start: Instant::now(),
+            stats_window: accounting.stats_window,
+            backoff: accounting.backoff,
+            rng: accounting.rng.clone(),
+            breaker,
+            failure_log: accounting.failure_log.clone(),
+            established: false,
+            dispatcher: task::current(),
+        }
     }
 
     pub fn is_idle(&self) -> bool {
         self.state.borrow().is_idle()
     }
+
+    /// Evicts up to `n` of the endpoint's oldest open connections, returning the number
+    /// evicted.
+    pub fn evict_oldest(&self, n: usize, graceful: bool) -> usize {
+        let state = self.state.borrow();
+        let mut evicted = 0;
+        for e in state.evictions.values() {
+            if evicted == n {
+                break;
+            }
+            if !e.is_evicted() {
+                e.evict(graceful);
+                evicted += 1;
+            }
+        }
+        if evicted > 0 {
+            debug!("{}: evicted {} connections", self.peer_addr, evicted);
+        }
+        evicted
+    }
+
+    /// Describes the endpoint for the admin server. Connection attempts are summarized
+    /// over the preceding `stats_window`.
+    pub fn snapshot(&self, status: &'static str, stats_window: Duration) -> EndpointState {
+        let state = self.state.borrow();
+        let now = Instant::now();
+        let backoff_ms = match state.backoff {
+            Some(b) if now < b.until => {
+                Some(b.delay.as_secs() * 1_000 + (b.delay.subsec_nanos() / 1_000_000) as u64)
+            }
+            _ => None,
+        };
+        let (successes, failures) = state.connects.totals(now, stats_window);
+        let connect_attempts = successes + failures;
+        let failure_accrual = if status == "failed" {
+            "failed"
+        } else if state.probation.is_some() {
+            "probation"
+        } else if state.consecutive_failures > 0 {
+            "failing"
+        } else {
+            "healthy"
+        };
+        EndpointState {
+            addr: self.peer_addr,
+            status: if state.probation.is_some() { "probation" } else { status },
+            weight: self.weight,
+            weight_multiplier: self.weight_multiplier,
+            effective_weight: self.weight(),
+            pending_conns: state.pending_conns,
+            open_conns: state.open_conns,
+            consecutive_failures: state.consecutive_failures,
+            rx_bytes: state.rx_bytes,
+            tx_bytes: state.tx_bytes,
+            backoff_ms,
+            connect_attempts,
+            connect_success_rate: if connect_attempts == 0 {
+                None
+            } else {
+                Some(successes as f64 / connect_attempts as f64)
+            },
+            last_failure: state.last_failure.clone(),
+            first_byte_p95_ms: state.first_bytes.percentile_ms(95.0),
+            failure_accrual,
+        }
+    }
+}
+
+/// The fraction of its weight given to an endpoint that has been warming up for
+/// `elapsed`.
+fn warmup_ratio(elapsed: Duration, window: Duration) -> f64 {
+    if elapsed >= window {
+        return 1.0;
+    }
+    let secs = |d: Duration| d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+    SLOW_START_MIN_WEIGHT + (1.0 - SLOW_START_MIN_WEIGHT) * secs(elapsed) / secs(window)
+}
+
+/// Limits the logging of a dispatcher's repeated connection failures to each endpoint.
+pub type FailureLog = Rc<RefCell<LogLimit<net::SocketAddr>>>;
+
+/// Logs a failure to connect to `addr`, unless identical failures are being suppressed.
+fn log_failure(log: &FailureLog, addr: net::SocketAddr, what: &str, e: &io::Error) {
+    match log.borrow_mut().record(addr, &e.to_string(), Instant::now()) {
+        Verdict::Log(prior) => {
+            if let Some(s) = prior {
+                error!("{}: {} failed {}", addr, what, s);
+            }
+            error!("{}: {} failed: {}", addr, what, e);
+        }
+        Verdict::Summarize(s) => error!("{}: {} failed {}", addr, what, s),
+        Verdict::Suppress => debug!("{}: {} failed: {}", addr, what, e),
+    }
+}
+
+/// Notes that `addr` has recovered, logging a summary of its suppressed failures.
+fn log_recovery(log: &FailureLog, addr: net::SocketAddr, what: &str) {
+    if let Some(s) = log.borrow_mut().reset(&addr, Instant::now()) {
+        error!("{}: {} failed {}", addr, what, s);
+    }
+}
+
+/// Establishes a connection to an endpoint, updating the endpoint's state when it
+/// completes.
+pub struct Connecting {
+    sock: connector::Connecting,
+    peer_addr: net::SocketAddr,
+    state: Rc<RefCell<State>>,
+    /// Counts the attempt as pending until it completes or is dropped.
+    pending: Option<PendingToken>,
+    duration: Arc<metrics::Timer>,
+    stats_window: Duration,
+    backoff: Option<connector::ConnectBackoff>,
+    rng: SharedRng,
+    start: Instant,
+    /// When set, the time taken to connect is recorded in the endpoint's latency.
+    ewma: Option<connector::Ewma>,
+    failure_log: FailureLog,
+    first_byte: FirstByteMetrics,
+    /// Holds the attempt's place within the process's upstream connection limit, which
+    /// is passed to the connection once it is established.
+    permit: Option<global_limit::Permit>,
+}
+
+impl Connecting {
+    fn failed(&mut self, e: &io::Error) {
+        log_failure(&self.failure_log, self.peer_addr, "connection", e);
+        drop(self.pending.take());
+        drop(self.permit.take());
+        let mut s = self.state.borrow_mut();
+        let now = Instant::now();
+        s.failed(now, self.peer_addr, e, self.stats_window, self.backoff, &self.rng);
+    }
+
+    /// Records the connection as open until the returned token is dropped.
+    fn connected(&mut self) -> OpenToken {
+        debug!("{}: connected", self.peer_addr);
+        log_recovery(&self.failure_log, self.peer_addr, "connection");
+        let open = self.pending.take().expect("connection completed twice").open();
+        let mut s = self.state.borrow_mut();
+        s.succeeded(self.stats_window);
+        if let Some(ref policy) = self.ewma {
+            Latency::observe(&mut s.latency, self.start.elapsed(), policy);
+        }
+        open
+    }
 }
 
-pub struct Connecting(Box<Future<Item = Connection, Error = io::Error> + 'static>);
 impl Future for Connecting {
     type Item = Connection;
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Connection, io::Error> {
-        self.0.poll()
+        match self.sock.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.failed(&e);
+                Err(e)
+            }
+            Ok(Async::Ready(sock)) => {
+                let open = self.connected();
+                let eviction = Eviction::default();
+                let id = {
+                    let mut s = self.state.borrow_mut();
+                    let id = s.next_conn_id;
+                    s.next_conn_id += 1;
+                    s.evictions.insert(id, eviction.clone());
+                    id
+                };
+                let ctx = Ctx {
+                    state: self.state.clone(),
+                    _open: open,
+                    duration: self.duration.clone(),
+                    start: Instant::now(),
+                    dispatcher: task::current(),
+                    id,
+                    eviction,
+                    ewma: self.ewma,
+                    first_byte: self.first_byte.clone(),
+                    responded: false,
+                    written: false,
+                    _permit: self.permit.take(),
+                    generation: None,
+                };
+                Ok(Async::Ready(Connection::new(sock, ctx)))
+            }
+        }
     }
 }
 
 pub struct Ctx {
     state: Rc<RefCell<State>>,
-    duration: tacho::Timer,
+    /// Counts the connection as open until it is dropped.
+    _open: OpenToken,
+    duration: Arc<metrics::Timer>,
     start: Instant,
+
+    /// The dispatcher that established the connection, notified when the connection is
+    /// closed so that its connection gauges are updated.
+    dispatcher: Task,
+
+    /// Identifies the connection among the endpoint's open connections.
+    id: u64,
+    eviction: Eviction,
+
+    /// When set, the time to the first byte is recorded in the endpoint's latency.
+    ewma: Option<connector::Ewma>,
+    first_byte: FirstByteMetrics,
+
+    /// Latches once the endpoint has sent a byte, so that only the first read is timed.
+    responded: bool,
+    written: bool,
+
+    /// Counts the connection toward the process's upstream connection limit until it is
+    /// dropped.
+    _permit: Option<global_limit::Permit>,
+
+    /// The generation of the endpoints from which the connection was selected, set as
+    /// it is dispatched.
+    generation: Option<Generation>,
+}
+impl Ctx {
+    /// Signals that the balancer has closed the connection to rebalance load.
+    pub fn eviction(&self) -> Eviction {
+        self.eviction.clone()
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.eviction.is_evicted()
+    }
+
+    /// The generation of the endpoints from which the connection was selected, if it
+    /// has been dispatched after a resolution was applied.
+    pub fn generation(&self) -> Option<Generation> {
+        self.generation
+    }
+
+    pub fn set_generation(&mut self, generation: Option<Generation>) {
+        self.generation = generation;
+    }
 }
 impl ctx::Ctx for Ctx {
     fn read(&mut self, sz: usize) {
         let mut state = self.state.borrow_mut();
         state.rx_bytes += sz;
+        if sz > 0 && !self.responded {
+            self.responded = true;
+            let elapsed = self.start.elapsed();
+            self.first_byte.latency.record_since(self.start);
+            state.first_bytes.record(elapsed);
+            if let Some(ref policy) = self.ewma {
+                Latency::observe(&mut state.first_byte, elapsed, policy);
+            }
+        }
     }
 
     fn wrote(&mut self, sz: usize) {
         let mut state = self.state.borrow_mut();
         state.tx_bytes += sz;
+        self.written = self.written || sz > 0;
     }
 }
 impl Drop for Ctx {
     fn drop(&mut self) {
+        if self.written && !self.responded {
+            self.first_byte.no_response.incr(1);
+        }
         let mut state = self.state.borrow_mut();
-        state.open_conns -= 1;
-        self.duration.record_since(self.start)
+        state.evictions.remove(&self.id);
+        self.duration.record_since(self.start);
+        self.dispatcher.notify();
+    }
+}
+
+/// A datagram session assigned to an endpoint.
+///
+/// Nothing is connected before a session is used, so the first datagram sent on a
+/// session stands in for a connection attempt: its outcome is recorded with the endpoint
+/// and the circuit breaker as a connection's would be.
+pub struct Session {
+    peer_addr: net::SocketAddr,
+    state: Rc<RefCell<State>>,
+    /// Counts the session as an open connection until it is dropped.
+    _open: OpenToken,
+    duration: Arc<metrics::Timer>,
+    start: Instant,
+    stats_window: Duration,
+    backoff: Option<connector::ConnectBackoff>,
+    rng: SharedRng,
+    breaker: Option<Rc<RefCell<CircuitBreaker>>>,
+    failure_log: FailureLog,
+
+    /// Set once a datagram has been sent, so that only the first send is recorded as a
+    /// successful connection.
+    established: bool,
+
+    /// The dispatcher that assigned the session, notified when the session fails or is
+    /// closed so that the endpoint's state is updated.
+    dispatcher: Task,
+}
+
+impl Session {
+    /// The address of the endpoint to which the session's datagrams are sent.
+    pub fn peer_addr(&self) -> net::SocketAddr {
+        self.peer_addr
+    }
+
+    /// Records a datagram of `sz` bytes sent to the endpoint.
+    pub fn sent(&mut self, sz: usize) {
+        let mut state = self.state.borrow_mut();
+        state.tx_bytes += sz;
+        if !self.established {
+            self.established = true;
+            state.succeeded(self.stats_window);
+            if let Some(ref b) = self.breaker {
+                b.borrow_mut().record(true);
+            }
+            log_recovery(&self.failure_log, self.peer_addr, "session");
+        }
+    }
+
+    /// Records a datagram of `sz` bytes received from the endpoint.
+    pub fn received(&mut self, sz: usize) {
+        self.state.borrow_mut().rx_bytes += sz;
+    }
+
+    /// Records an error sending datagrams to, or receiving them from, the endpoint as a
+    /// failed connection attempt, so that the endpoint may be backed off or failed.
+    ///
+    /// The session should be closed once it has failed.
+    pub fn failed(&mut self, e: &io::Error) {
+        log_failure(&self.failure_log, self.peer_addr, "session", e);
+        self.state.borrow_mut().failed(
+            Instant::now(),
+            self.peer_addr,
+            e,
+            self.stats_window,
+            self.backoff,
+            &self.rng,
+        );
+        if let Some(ref b) = self.breaker {
+            b.borrow_mut().record(false);
+        }
+        self.dispatcher.notify();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        debug!("{}: session closed", self.peer_addr);
+        self.duration.record_since(self.start);
+        self.dispatcher.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accounting, Connecting, Endpoint, FirstByteMetrics, new};
+    use super::super::super::connector::{Connector, ConnectorConfig};
+    use super::super::super::log_limit::LogLimit;
+    use super::super::super::metrics::Scope;
+    use futures::{Future, Stream};
+    use futures::future::Either;
+    use rand::{self, SeedableRng, StdRng};
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::net::{self, SocketAddr};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::{Core, Handle};
+    use tokio_io::{AsyncRead, io as aio};
+    use tokio_timer::{self, Timer};
+
+    /// An endpoint, outside of any balancer, so that the connections it counts may be
+    /// observed as its connection attempts complete, fail, or are dropped.
+    struct Standalone {
+        endpoint: Endpoint,
+        connector: Connector,
+        accounting: Accounting,
+    }
+
+    impl Standalone {
+        fn new(addr: SocketAddr) -> Standalone {
+            let connector = ConnectorConfig::default()
+                .mk_connector()
+                .expect("invalid connector config");
+            let failure_log = Rc::new(RefCell::new(LogLimit::new(1, connector.log_suppress())));
+            let metrics = Scope::noop();
+            let accounting = Accounting {
+                duration: metrics.timer_ms("duration_ms"),
+                stats_window: connector.stats_window(),
+                backoff: None,
+                rng: Rc::new(RefCell::new(StdRng::from_seed(&[rand::random::<usize>()][..]))),
+                failure_log,
+                errors: metrics.counter("state_accounting_errors"),
+                first_byte: FirstByteMetrics {
+                    latency: metrics.timer_ms("first_byte_ms"),
+                    no_response: metrics.counter("no_response"),
+                },
+            };
+            Standalone {
+                endpoint: new(addr, 1.0, BTreeMap::new()),
+                connector,
+                accounting,
+            }
+        }
+
+        /// Begins a connection attempt, which is counted as pending until it completes or is
+        /// dropped. Its connection is counted as open until it is dropped.
+        fn connect(&self, reactor: &Handle, timer: &Timer) -> Connecting {
+            let addr = self.endpoint.peer_addr();
+            let sock = self.connector.connect(&addr, reactor, timer, None);
+            self.endpoint.connect(sock, &self.accounting, None, None)
+        }
+
+        fn pending_conns(&self) -> usize {
+            self.endpoint.state().pending_conns
+        }
+
+        fn open_conns(&self) -> usize {
+            self.endpoint.state().open_conns
+        }
+    }
+
+    fn timer() -> Timer {
+        tokio_timer::wheel().tick_duration(Duration::from_millis(10)).build()
+    }
+
+    /// Binds a listener that never completes connections.
+    ///
+    /// Its accept queue is filled by the returned stream, so that the kernel drops further
+    /// connection attempts rather than refusing them.
+    #[cfg(target_os = "linux")]
+    fn blackhole() -> (net::TcpListener, net::TcpStream) {
+        use libc;
+        use std::os::unix::io::AsRawFd;
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let queued = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener, queued)
+    }
+
+    /// An address on which nothing listens.
+    fn unused_addr() -> SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        listener.local_addr().unwrap()
+    }
+
+    /// Spawns a server that resets each connection it accepts.
+    fn resetting_server(handle: &Handle) -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serve = listener
+            .incoming()
+            .for_each(|(tcp, _)| tcp.set_linger(Some(Duration::from_secs(0))))
+            .map_err(|_| ());
+        handle.spawn(serve);
+        addr
+    }
+
+    /// Spawns a server that echoes each connection it accepts.
+    fn echo_server(handle: &Handle) -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serve = {
+            let handle = handle.clone();
+            listener
+                .incoming()
+                .for_each(move |(tcp, _)| {
+                    let (r, w) = tcp.split();
+                    handle.spawn(aio::copy(r, w).map(|_| ()).map_err(|_| ()));
+                    Ok(())
+                })
+                .map_err(|_| ())
+        };
+        handle.spawn(serve);
+        addr
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn releases_pending_connections_dropped_before_connecting() {
+        let mut core = Core::new().unwrap();
+        let timer = timer();
+        let (listener, _queued) = blackhole();
+        let endpoint = Standalone::new(listener.local_addr().unwrap());
+        let connecting = endpoint.connect(&core.handle(), &timer);
+        assert_eq!(endpoint.pending_conns(), 1);
+
+        // The attempt is polled until it waits on the endpoint, and is then abandoned.
+        let sleep = timer.sleep(Duration::from_millis(100));
+        let connecting = match core.run(connecting.select2(sleep)) {
+            Ok(Either::B((_, connecting))) => connecting,
+            _ => panic!("connection attempt completed"),
+        };
+        assert_eq!(endpoint.pending_conns(), 1);
+        drop(connecting);
+        assert_eq!(endpoint.pending_conns(), 0);
+        assert_eq!(endpoint.open_conns(), 0);
+    }
+
+    #[test]
+    fn releases_pending_connections_that_fail() {
+        let mut core = Core::new().unwrap();
+        let timer = timer();
+        let endpoint = Standalone::new(unused_addr());
+        let connecting = endpoint.connect(&core.handle(), &timer);
+        assert!(core.run(connecting).is_err());
+        assert_eq!(endpoint.pending_conns(), 0);
+        assert_eq!(endpoint.open_conns(), 0);
+    }
+
+    #[test]
+    fn releases_connections_reset_after_connecting() {
+        let mut core = Core::new().unwrap();
+        let timer = timer();
+        let endpoint = Standalone::new(resetting_server(&core.handle()));
+        let connecting = endpoint.connect(&core.handle(), &timer);
+        let conn = core.run(connecting).expect("failed to connect");
+        assert_eq!(endpoint.pending_conns(), 0);
+        assert_eq!(endpoint.open_conns(), 1);
+
+        // The connection remains counted, once the endpoint has reset it, until it is
+        // dropped.
+        core.run(timer.sleep(Duration::from_millis(100))).unwrap();
+        assert_eq!(endpoint.open_conns(), 1);
+        drop(conn);
+        assert_eq!(endpoint.open_conns(), 0);
+    }
+
+    #[test]
+    fn releases_connections_that_are_closed() {
+        let mut core = Core::new().unwrap();
+        let timer = timer();
+        let endpoint = Standalone::new(echo_server(&core.handle()));
+        for _ in 0..3 {
+            let connecting = endpoint.connect(&core.handle(), &timer);
+            let conn = core.run(connecting).expect("failed to connect");
+            assert_eq!(endpoint.open_conns(), 1);
+            drop(conn);
+        }
+        assert_eq!(endpoint.pending_conns(), 0);
+        assert_eq!(endpoint.open_conns(), 0);
     }
 }
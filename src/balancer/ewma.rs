@@ -0,0 +1,124 @@
+//! Scores endpoints by their connect latencies and times to first byte.
+//!
+//! Each endpoint's connect latency, and separately the time its connections take to
+//! receive their first byte from it, is estimated by a peak exponentially-weighted moving
+//! average: a sample greater than the estimate replaces it outright, so that an endpoint
+//! that slows down is avoided immediately, while lesser samples are averaged in
+//! according to how long it has been since the estimate was last updated. An endpoint's
+//! latency is the sum of its two estimates.
+//!
+//! Estimates that are not updated decay toward the average of all estimates, so that an
+//! endpoint that was avoided while slow is eventually tried again rather than being
+//! judged by a stale estimate forever.
+
+use super::super::connector::Ewma;
+use super::endpoint::Endpoint;
+use std::time::{Duration, Instant};
+
+/// Keeps scores of endpoints with negligible latencies proportional to their loads.
+const MIN_LATENCY_MS: f64 = 0.001;
+
+/// Keeps scores of endpoints with no weight finite.
+const MIN_WEIGHT: f64 = 1e-6;
+
+/// An endpoint's estimated connect latency or time to first byte.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency {
+    estimate_ms: f64,
+    updated: Instant,
+}
+
+impl Latency {
+    pub fn estimate_ms(&self) -> f64 {
+        self.estimate_ms
+    }
+
+    /// Updates `latency` with a connection that took `sample` to establish (or to receive
+    /// its first byte).
+    pub fn observe(latency: &mut Option<Latency>, sample: Duration, policy: &Ewma) {
+        let sample_ms = as_ms(sample);
+        let now = Instant::now();
+        let estimate_ms = match *latency {
+            None => sample_ms,
+            Some(ref l) if sample_ms > l.estimate_ms => sample_ms,
+            Some(ref l) => {
+                let w = decay(now - l.updated, policy.decay);
+                l.estimate_ms * w + sample_ms * (1.0 - w)
+            }
+        };
+        *latency = Some(Latency {
+            estimate_ms,
+            updated: now,
+        });
+    }
+
+    /// The estimate, decayed toward `mean_ms` for the time since it was last updated.
+    fn aged_ms(&self, now: Instant, policy: &Ewma, mean_ms: f64) -> f64 {
+        let age = if now > self.updated {
+            now - self.updated
+        } else {
+            Duration::from_secs(0)
+        };
+        mean_ms + (self.estimate_ms - mean_ms) * decay(age, policy.decay)
+    }
+}
+
+/// Scores a set of candidate endpoints, lower scores being better.
+pub struct Scorer {
+    policy: Ewma,
+    now: Instant,
+    /// The averages of the candidates' connect latency and first byte estimates, which
+    /// are also used for endpoints that have not yet been measured.
+    mean_ms: f64,
+    mean_first_byte_ms: f64,
+}
+
+impl Scorer {
+    pub fn new(policy: &Ewma, candidates: &[&Endpoint]) -> Scorer {
+        let mean = |estimate: &Fn(&Endpoint) -> Option<Latency>| {
+            let mut sum = 0.0;
+            let mut n = 0;
+            for ep in candidates {
+                if let Some(l) = estimate(ep) {
+                    sum += l.estimate_ms;
+                    n += 1;
+                }
+            }
+            if n == 0 { 0.0 } else { sum / f64::from(n) }
+        };
+        Scorer {
+            policy: *policy,
+            now: Instant::now(),
+            mean_ms: mean(&|ep: &Endpoint| ep.state().latency),
+            mean_first_byte_ms: mean(&|ep: &Endpoint| ep.state().first_byte),
+        }
+    }
+
+    /// Scores `ep` as `latency * (load + 1) / weight`, where `latency` is the sum of its
+    /// connect latency and time to first byte.
+    pub fn score(&self, ep: &Endpoint) -> f64 {
+        let (latency_ms, load) = {
+            let state = ep.state();
+            let connect_ms = match state.latency {
+                None => self.mean_ms,
+                Some(ref l) => l.aged_ms(self.now, &self.policy, self.mean_ms),
+            };
+            let first_byte_ms = match state.first_byte {
+                None => self.mean_first_byte_ms,
+                Some(ref l) => l.aged_ms(self.now, &self.policy, self.mean_first_byte_ms),
+            };
+            (connect_ms + first_byte_ms, state.load())
+        };
+        let latency_ms = latency_ms.max(MIN_LATENCY_MS);
+        latency_ms * (load + 1) as f64 / ep.weight().max(MIN_WEIGHT)
+    }
+}
+
+/// The weight retained by an estimate that is `age` old.
+fn decay(age: Duration, decay: Duration) -> f64 {
+    (-as_ms(age) / as_ms(decay)).exp()
+}
+
+fn as_ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1_000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
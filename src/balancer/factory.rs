@@ -1,24 +1,45 @@
-use super::Balancer;
+use super::{Balancer, GlobalLimit};
 use super::super::Path;
-use super::super::connector::{ConfigError, ConnectorFactory};
+use super::super::connector::{ConfigError, ConnectorFactory, stable_hash};
+use super::super::dns::Dns;
+use super::super::metrics;
 use super::super::resolver::Resolve;
+use super::super::state;
+use rand::{SeedableRng, StdRng};
 use std::cell::RefCell;
 use std::rc::Rc;
-use tacho;
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 
 #[derive(Clone)]
 pub struct BalancerFactory {
     connector_factory: Rc<RefCell<ConnectorFactory>>,
-    metrics: tacho::Scope,
+    router: String,
+    state: state::Registry,
+    rng_seed: u64,
+    metrics: metrics::Scope,
+    dns: Dns,
+    global_limit: Rc<GlobalLimit>,
 }
 
 impl BalancerFactory {
-    pub fn new(cf: ConnectorFactory, metrics: &tacho::Scope) -> BalancerFactory {
+    pub fn new(
+        cf: ConnectorFactory,
+        router: &str,
+        state: &state::Registry,
+        rng_seed: u64,
+        metrics: &metrics::Scope,
+        dns: &Dns,
+        global_limit: &Rc<GlobalLimit>,
+    ) -> BalancerFactory {
         BalancerFactory {
             connector_factory: Rc::new(RefCell::new(cf)),
+            router: router.into(),
+            state: state.clone(),
+            rng_seed,
             metrics: metrics.clone(),
+            dns: dns.clone(),
+            global_limit: global_limit.clone(),
         }
     }
 
@@ -37,7 +58,88 @@ impl BalancerFactory {
             dst_name,
             connector,
             resolve,
+            self.state.reporter(&self.router, dst_name),
+            mk_rng(self.rng_seed, &self.router, dst_name.as_str()),
             &metrics,
+            &self.dns,
+            Some(self.global_limit.clone()),
         ))
     }
 }
+
+/// Seeds a balancer's randomness from the router, destination, and process-wide seed, so
+/// that a balancer's decisions do not depend on the order in which balancers are created.
+///
+/// The seed is hashed with a stable hash, so that a configured seed reproduces the same
+/// decisions in every release and on every platform (of the same word size).
+fn mk_rng(rng_seed: u64, router: &str, dst_name: &str) -> StdRng {
+    let mut bytes = Vec::with_capacity(8 + router.len() + 1 + dst_name.len());
+    for i in 0..8 {
+        bytes.push((rng_seed >> (8 * i)) as u8);
+    }
+    bytes.extend_from_slice(router.as_bytes());
+    // Router labels never contain NUL, so no two routers and destinations run together.
+    bytes.push(0);
+    bytes.extend_from_slice(dst_name.as_bytes());
+    let seed = stable_hash(&bytes);
+    StdRng::from_seed(&[seed as usize, (seed >> 32) as usize][..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mk_rng;
+    use super::super::dispatcher::select_endpoint;
+    use super::super::endpoint::{self, Endpoint};
+    use std::collections::BTreeMap;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    /// Makes `selections` selections among `endpoints` idle endpoints, with the randomness a
+    /// balancer for `dst_name` on `router` is given when the process is seeded with
+    /// `rng_seed`. Returns the index of each selected endpoint.
+    fn seeded_selections(
+        rng_seed: u64,
+        router: &str,
+        dst_name: &str,
+        endpoints: usize,
+        selections: usize,
+    ) -> Vec<usize> {
+        let endpoints: Vec<Endpoint> = (0..endpoints)
+            .map(|i| {
+                let addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, i as u8).into(), 8080);
+                endpoint::new(addr, 1.0, BTreeMap::new())
+            })
+            .collect();
+        let candidates: Vec<&Endpoint> = endpoints.iter().collect();
+        let mut rng = mk_rng(rng_seed, router, dst_name);
+        (0..selections)
+            .map(|_| {
+                let ep = select_endpoint(&mut rng, &candidates, None, None)
+                    .expect("no endpoint selected");
+                endpoints
+                    .iter()
+                    .position(|e| e.peer_addr() == ep.peer_addr())
+                    .expect("unknown endpoint selected")
+            })
+            .collect()
+    }
+
+    // Balancers' randomness is seeded by word, so seeded decisions differ by word size.
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn seeded_selections_are_stable() {
+        assert_eq!(
+            seeded_selections(7, "test", "/svc/echo", 3, 12),
+            vec![1, 1, 0, 1, 1, 0, 2, 2, 2, 2, 0, 2]
+        );
+
+        // Other seeds, and other routers, make other selections.
+        assert_eq!(
+            seeded_selections(8, "test", "/svc/echo", 3, 12),
+            vec![0, 0, 2, 2, 0, 0, 2, 2, 2, 0, 0, 2]
+        );
+        assert_eq!(
+            seeded_selections(7, "other", "/svc/echo", 3, 12),
+            vec![2, 0, 1, 0, 0, 1, 0, 1, 0, 2, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Static endpoints used while none of a destination's resolved endpoints are available.
+//!
+//! Once no resolved endpoint has been available for the policy's `activate_after`, the
+//! fallback is activated and connections are dispatched to the fallback endpoints. As
+//! soon as a resolved endpoint becomes available again, the fallback is deactivated: new
+//! connections are no longer made to the fallback endpoints, but connections that have
+//! already been dispatched are left to drain.
+//!
+//! Fallback endpoints named by hostnames are resolved without blocking, and are
+//! re-resolved as their addresses expire. Until a hostname has been resolved, it
+//! contributes no endpoints; if re-resolving it fails, its previous addresses continue to
+//! be used.
+
+use super::EndpointMap;
+use super::endpoint;
+use super::super::Path;
+use super::super::connector::FallbackPolicy;
+use super::super::dns::{Dns, Name};
+use super::super::metrics;
+use futures::{Async, Future, Stream};
+use std::collections::BTreeMap;
+use std::net;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_timer::{Sleep, Timer};
+
+pub struct Fallback {
+    dst_name: Path,
+    state: State,
+    endpoints: EndpointMap,
+    names: Vec<Name>,
+    policy: FallbackPolicy,
+    timer: Timer,
+
+    /// Wakes the dispatcher when the fallback is due to be activated.
+    wakeup: Option<Sleep>,
+
+    active_gauge: Arc<metrics::Gauge>,
+    activations: Arc<metrics::Counter>,
+    deactivations: Arc<metrics::Counter>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Standby,
+    /// No resolved endpoints have been available since the given time.
+    Pending(Instant),
+    Active,
+}
+
+impl Fallback {
+    pub fn new(
+        dst_name: Path,
+        policy: FallbackPolicy,
+        timer: Timer,
+        dns: &Dns,
+        metrics: &metrics::Scope,
+    ) -> Fallback {
+        let metrics = metrics.clone().prefixed("fallback");
+        let names = policy.addrs.iter().map(|a| dns.name(a, &timer)).collect();
+        Fallback {
+            dst_name,
+            state: State::Standby,
+            endpoints: EndpointMap::default(),
+            names,
+            policy,
+            timer,
+            wakeup: None,
+            active_gauge: metrics.gauge("active"),
+            activations: metrics
+                .clone()
+                .labeled("transition", "activate")
+                .counter("transitions"),
+            deactivations: metrics
+                .clone()
+                .labeled("transition", "deactivate")
+                .counter("transitions"),
+        }
+    }
+
+    /// The fallback endpoints, if connections should be dispatched to them.
+    pub fn active_endpoints(&self) -> Option<&EndpointMap> {
+        match self.state {
+            State::Active => Some(&self.endpoints),
+            _ => None,
+        }
+    }
+
+    pub fn endpoints(&self) -> &EndpointMap {
+        &self.endpoints
+    }
+
+    pub fn contains(&self, addr: &net::SocketAddr) -> bool {
+        self.endpoints.contains_key(addr)
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            State::Standby => "standby",
+            State::Pending(_) => "pending",
+            State::Active => "active",
+        }
+    }
+
+    /// Activates or deactivates the fallback, depending on whether any of the
+    /// destination's resolved endpoints are available.
+    ///
+    /// Returns true if the fallback was deactivated.
+    pub fn update(&mut self, primary_available: bool) -> bool {
+        self.poll_names();
+        match (self.state, primary_available) {
+            (State::Active, true) => {
+                info!("{}: resolved endpoints available; deactivating fallback", self.dst_name);
+                self.state = State::Standby;
+                self.deactivations.incr(1);
+                self.active_gauge.set(0);
+                true
+            }
+            (_, true) => {
+                self.state = State::Standby;
+                self.wakeup = None;
+                false
+            }
+            (State::Active, false) => false,
+            (State::Standby, false) => {
+                debug!("{}: no resolved endpoints available", self.dst_name);
+                let mut wakeup = self.timer.sleep(self.policy.activate_after);
+                self.state = State::Pending(Instant::now());
+                if let Ok(Async::Ready(_)) = wakeup.poll() {
+                    self.activate();
+                } else {
+                    self.wakeup = Some(wakeup);
+                }
+                false
+            }
+            (State::Pending(since), false) => {
+                let elapsed = since.elapsed() >= self.policy.activate_after;
+                let woken = match self.wakeup {
+                    None => true,
+                    Some(ref mut w) => {
+                        match w.poll() {
+                            Ok(Async::NotReady) => false,
+                            Ok(Async::Ready(_)) => true,
+                            Err(e) => {
+                                error!("{}: fallback timer error: {}", self.dst_name, e);
+                                true
+                            }
+                        }
+                    }
+                };
+                if elapsed || woken {
+                    self.activate();
+                }
+                false
+            }
+        }
+    }
+
+    /// Updates the fallback endpoints as their names' addresses change. Endpoints whose
+    /// addresses remain are kept, along with their state.
+    fn poll_names(&mut self) {
+        let mut changed = false;
+        for name in &mut self.names {
+            while let Ok(Async::Ready(Some(_))) = name.poll() {
+                changed = true;
+            }
+        }
+        if !changed {
+            return;
+        }
+
+        let mut endpoints = EndpointMap::default();
+        for name in &self.names {
+            for addr in name.addrs().unwrap_or(&[]) {
+                if endpoints.contains_key(addr) {
+                    continue;
+                }
+                let ep = self.endpoints.swap_remove(addr).unwrap_or_else(|| {
+                    endpoint::new(*addr, 1.0, BTreeMap::new())
+                });
+                endpoints.insert(*addr, ep);
+            }
+        }
+        debug!("{}: {} fallback endpoints", self.dst_name, endpoints.len());
+        self.endpoints = endpoints;
+    }
+
+    fn activate(&mut self) {
+        info!(
+            "{}: no resolved endpoints available for {:?}; activating fallback",
+            self.dst_name,
+            self.policy.activate_after
+        );
+        self.state = State::Active;
+        self.wakeup = None;
+        self.activations.incr(1);
+        self.active_gauge.set(1);
+    }
+}
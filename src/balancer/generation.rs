@@ -0,0 +1,73 @@
+use super::WeightedAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Identifies the set of a destination's endpoints from which a connection's endpoint
+/// was selected, so that a connection may be attributed to the resolution it was
+/// dispatched under.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Generation {
+    /// Incremented, from 1, each time a resolution changes the destination's endpoints.
+    pub number: u64,
+    /// When the endpoints were last changed.
+    pub updated_at: SystemTime,
+    /// How long the endpoints had been unchanged when the connection was dispatched.
+    pub age: Duration,
+}
+
+impl Generation {
+    /// The time at which the endpoints were last changed, in milliseconds since the Unix
+    /// epoch.
+    pub fn updated_at_ms(&self) -> u64 {
+        let at = self.updated_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        at.as_secs() * 1_000 + u64::from(at.subsec_nanos() / 1_000_000)
+    }
+
+    /// The age of the endpoints at dispatch, in milliseconds.
+    pub fn age_ms(&self) -> u64 {
+        self.age.as_secs() * 1_000 + u64::from(self.age.subsec_nanos() / 1_000_000)
+    }
+}
+
+/// Counts the resolutions that change a destination's endpoints.
+///
+/// Resolutions that only repeat or reorder the applied addresses do not begin a new
+/// generation.
+#[derive(Default)]
+pub struct Generations {
+    /// The addresses most recently applied, ordered by address.
+    applied: Option<Vec<WeightedAddr>>,
+    number: u64,
+    /// When the current generation began, by wall clock and monotonic time.
+    updated: Option<(SystemTime, Instant)>,
+}
+
+impl Generations {
+    /// Records the addresses applied from a resolution. Returns true if they begin a new
+    /// generation.
+    pub fn update(&mut self, addrs: &[WeightedAddr]) -> bool {
+        let mut addrs = addrs.to_vec();
+        addrs.sort_by(|a, b| a.addr.cmp(&b.addr));
+        if self.applied.as_ref() == Some(&addrs) {
+            return false;
+        }
+        self.applied = Some(addrs);
+        self.number += 1;
+        self.updated = Some((SystemTime::now(), Instant::now()));
+        true
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// The current generation, as of a dispatch now, if any resolution has been applied.
+    pub fn current(&self) -> Option<Generation> {
+        self.updated.map(|(updated_at, since)| {
+            Generation {
+                number: self.number,
+                updated_at,
+                age: since.elapsed(),
+            }
+        })
+    }
+}
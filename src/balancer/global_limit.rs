@@ -0,0 +1,71 @@
+//! Bounds the upstream connections held by the whole process, across all routers.
+//!
+//! Unlike a destination's dispatch limit, connections beyond the limit are not shed.
+//! Instead, a dispatcher that cannot connect leaves its waiters queued, and is notified
+//! once a connection held anywhere in the process is closed. All routers are served by
+//! a single reactor, so the count need not be atomic.
+
+use super::super::metrics;
+use futures::task::{self, Task};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub struct GlobalLimit {
+    max: usize,
+    open: Cell<usize>,
+    /// Dispatchers that could not connect, notified once a connection is released.
+    blocked: RefCell<Vec<Task>>,
+    gauge: Arc<metrics::Gauge>,
+    waits: Arc<metrics::Counter>,
+}
+
+impl GlobalLimit {
+    /// Reports the number of upstream connections as `upstream_connections`, and counts
+    /// the times that dispatchers waited for the limit as `global_limit_waits`.
+    pub fn new(max: usize, metrics: &metrics::Scope) -> Rc<GlobalLimit> {
+        Rc::new(GlobalLimit {
+            max,
+            open: Cell::new(0),
+            blocked: RefCell::new(Vec::new()),
+            gauge: metrics.gauge("upstream_connections"),
+            waits: metrics.counter("global_limit_waits"),
+        })
+    }
+
+    /// Admits an upstream connection, unless the process is at its limit.
+    ///
+    /// The connection counts toward the limit until the returned permit is dropped. If
+    /// the process is at its limit, the current task is notified when a permit is
+    /// dropped.
+    pub fn acquire(limit: &Rc<GlobalLimit>) -> Option<Permit> {
+        let n = limit.open.get();
+        if n >= limit.max {
+            limit.waits.incr(1);
+            let mut blocked = limit.blocked.borrow_mut();
+            if !blocked.iter().any(|t| t.will_notify_current()) {
+                blocked.push(task::current());
+            }
+            return None;
+        }
+        limit.open.set(n + 1);
+        limit.gauge.incr(1);
+        Some(Permit(limit.clone()))
+    }
+}
+
+/// Holds an upstream connection's place within the process's limit.
+pub struct Permit(Rc<GlobalLimit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let n = self.0.open.get();
+        self.0.open.set(n - 1);
+        self.0.gauge.decr(1);
+        let blocked = ::std::mem::replace(&mut *self.0.blocked.borrow_mut(), Vec::new());
+        // Blocked dispatchers recheck the limit, waiting again if it is still reached.
+        for t in blocked {
+            t.notify();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Summarizes an endpoint's latencies in a fixed number of buckets.
+//!
+//! Buckets are bounded roughly exponentially, so that an endpoint's memory use does not
+//! grow with its connection rate, and percentiles are reported as the upper bound of
+//! the bucket in which they fall.
+
+use std::time::Duration;
+
+/// The inclusive upper bound of each bucket, in milliseconds. Longer latencies are
+/// counted in a final, unbounded bucket.
+const BOUNDS_MS: [u64; 16] = [
+    1,
+    2,
+    5,
+    10,
+    20,
+    50,
+    100,
+    200,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    30_000,
+    60_000,
+    300_000,
+];
+
+#[derive(Default)]
+pub struct Histogram {
+    counts: [usize; 17],
+    total: usize,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_secs() * 1_000 + u64::from(latency.subsec_nanos() / 1_000_000);
+        let i = BOUNDS_MS.iter().position(|&b| ms <= b).unwrap_or(BOUNDS_MS.len());
+        self.counts[i] += 1;
+        self.total += 1;
+    }
+
+    /// The upper bound, in milliseconds, of the bucket holding the `p`th percentile, if
+    /// any latencies have been recorded. Latencies beyond the largest bound are reported
+    /// as `u64::MAX`.
+    pub fn percentile_ms(&self, p: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0) * self.total as f64).ceil().max(1.0) as usize;
+        let mut seen = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(BOUNDS_MS.get(i).cloned().unwrap_or(u64::max_value()));
+            }
+        }
+        Some(u64::max_value())
+    }
+}
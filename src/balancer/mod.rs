@@ -1,35 +1,110 @@
-use super::Path;
-use super::connector::Connector;
-use super::resolver::Resolve;
-use futures::{Async, Future, Poll, unsync};
+use super::{Error, Path};
+use super::connector::{Connector, FailFast, SlowStart, WeightMode};
+use super::dns::Dns;
+use super::error::{ConnectErrorKind, connect_error};
+use super::metrics;
+use super::resolver::{self, Resolve};
+use super::state;
+use super::supervise;
+use futures::{Async, Future, Poll, Stream, unsync};
 use ordermap::OrderMap;
-use std::{cmp, io, net};
-use std::collections::VecDeque;
-use std::time::{Duration, Instant};
-use tacho;
+use rand::StdRng;
+use std::{cmp, net};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::time::Instant;
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 
+mod circuit;
+mod dispatch_limit;
 mod dispatcher;
 mod endpoint;
+mod ewma;
 mod factory;
+mod fallback;
+mod generation;
+mod global_limit;
+mod histogram;
+mod stats;
+mod sticky;
 
-pub use self::endpoint::{Connection as EndpointConnection, Ctx as EndpointCtx};
+pub use self::endpoint::{Connection as EndpointConnection, Ctx as EndpointCtx, Session};
+use self::circuit::CircuitBreaker;
+use self::dispatch_limit::{DispatchLimit, Permit};
 use self::endpoint::Endpoint;
+pub use self::dispatcher::{SelectionStrategy, time_selections};
 pub use self::factory::BalancerFactory;
+pub use self::generation::Generation;
+pub use self::global_limit::GlobalLimit;
 
-type Waiter = unsync::oneshot::Sender<endpoint::Connection>;
+/// A request made of a balancer's dispatcher.
+pub enum Request {
+    /// Requests a connection to an endpoint.
+    Connect(Waiter),
+    /// Requests that a datagram session be assigned to an endpoint.
+    Session(unsync::oneshot::Sender<endpoint::Session>),
+}
+
+/// A request for a connection.
+pub struct Waiter {
+    /// The server name requested by the downstream client's TLS handshake, to be used
+    /// for the upstream handshake when the client is configured to propagate it.
+    sni: Option<String>,
+    /// The downstream client's address, by which its endpoint may be remembered.
+    client: Option<net::IpAddr>,
+    /// The endpoint to which the waiter is to be connected: the endpoint to which the
+    /// client was last dispatched, when it is to be reused, or, for a retry, another
+    /// endpoint than the one that failed. Set by the dispatcher.
+    target: Option<net::SocketAddr>,
+    /// The endpoint that failed the client's previous attempt, when it is retried.
+    avoid: Option<net::SocketAddr>,
+    tx: unsync::oneshot::Sender<endpoint::Connection>,
+}
+
+/// Provides all of a balancer's randomness, so that a seeded balancer behaves
+/// deterministically.
+type SharedRng = Rc<RefCell<StdRng>>;
 
 /// A weighted concrete destination address.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct WeightedAddr {
-    pub addr: ::std::net::SocketAddr,
+    /// The address of the destination.
+    pub addr: net::SocketAddr,
+
+    /// The relative weight of this destination.
     pub weight: f64,
+
+    /// Arbitrary metadata provided by service discovery (e.g. `nodeName`).
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
 }
 
 impl WeightedAddr {
+    /// Creates a weighted address without metadata.
     pub fn new(addr: net::SocketAddr, weight: f64) -> WeightedAddr {
-        WeightedAddr { addr, weight }
+        WeightedAddr {
+            addr,
+            weight,
+            meta: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a metadata entry to this address.
+    pub fn with_meta<K, V>(mut self, key: K, value: V) -> WeightedAddr
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.meta.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl From<(net::SocketAddr, f64)> for WeightedAddr {
+    fn from((addr, weight): (net::SocketAddr, f64)) -> WeightedAddr {
+        WeightedAddr::new(addr, weight)
     }
 }
 
@@ -39,51 +114,264 @@ pub fn new(
     dst: &Path,
     connector: Connector,
     resolve: Resolve,
-    metrics: &tacho::Scope,
+    state: state::Reporter,
+    rng: StdRng,
+    metrics: &metrics::Scope,
+    dns: &Dns,
+    global_limit: Option<Rc<GlobalLimit>>,
 ) -> Balancer {
     let (tx, rx) = unsync::mpsc::unbounded();
-    let dispatcher = dispatcher::new(
-        reactor.clone(),
-        timer.clone(),
-        dst.clone(),
-        connector,
-        resolve,
-        rx,
-        Endpoints::default(),
-        metrics,
+    let rng = Rc::new(RefCell::new(rng));
+    let breaker = connector.circuit_breaker().cloned().map(|policy| {
+        let breaker = CircuitBreaker::new(dst.clone(), policy, rng.clone(), metrics);
+        Rc::new(RefCell::new(breaker))
+    });
+    let dispatch_limit = connector.max_concurrent_dispatches().map(
+        |max| DispatchLimit::new(max, metrics),
     );
-    reactor.spawn(dispatcher.map_err(|_| {}));
-    Balancer(tx)
+    // A dispatcher that panics is torn down, with its endpoints and their connections,
+    // since its state may be inconsistent. It is rebuilt after a backoff, and receives
+    // the requests and resolutions that its predecessor had not.
+    let inputs = Rc::new(RefCell::new(Inputs {
+        resolve,
+        last: None,
+        requests: rx,
+    }));
+    let shared = dispatcher::Shared {
+        breaker: breaker.clone(),
+        rng,
+        dns: dns.clone(),
+        global_limit,
+    };
+    let mk_dispatcher = {
+        let (reactor, timer, dst) = (reactor.clone(), timer.clone(), dst.clone());
+        let metrics = metrics.clone();
+        move || {
+            let resolutions = Resolutions {
+                replay: inputs.borrow().last.clone(),
+                inputs: inputs.clone(),
+            };
+            dispatcher::new(
+                reactor.clone(),
+                timer.clone(),
+                dst.clone(),
+                connector.clone(),
+                Resolve::from_results(resolutions),
+                Requests(inputs.clone()),
+                Endpoints::default(),
+                &shared,
+                state.renew(),
+                &metrics,
+            ).map_err(|_| {})
+        }
+    };
+    reactor.spawn(supervise::restart(
+        format!("balancer for {}", dst),
+        mk_dispatcher,
+        timer,
+        metrics.counter("dispatcher_panics"),
+    ));
+    Balancer {
+        tx,
+        breaker,
+        dispatch_limit,
+    }
 }
 
+/// The inputs of a destination's dispatcher, which outlive it so that a dispatcher that
+/// panics may be rebuilt.
+struct Inputs {
+    resolve: Resolve,
+    /// The most recent resolution, with which a rebuilt dispatcher begins.
+    last: Option<Vec<WeightedAddr>>,
+    requests: unsync::mpsc::UnboundedReceiver<Request>,
+}
+
+/// The resolutions received by a dispatcher, beginning with its predecessor's last.
+struct Resolutions {
+    inputs: Rc<RefCell<Inputs>>,
+    replay: Option<Vec<WeightedAddr>>,
+}
+
+impl Stream for Resolutions {
+    type Item = resolver::Result<Vec<WeightedAddr>>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        if let Some(addrs) = self.replay.take() {
+            return Ok(Async::Ready(Some(Ok(addrs))));
+        }
+        let mut inputs = self.inputs.borrow_mut();
+        let resolution = inputs.resolve.poll()?;
+        if let Async::Ready(Some(Ok(ref addrs))) = resolution {
+            inputs.last = Some(addrs.clone());
+        }
+        Ok(resolution)
+    }
+}
+
+/// The requests received by a dispatcher.
+struct Requests(Rc<RefCell<Inputs>>);
+
+impl Stream for Requests {
+    type Item = Request;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Request>, ()> {
+        self.0.borrow_mut().requests.poll()
+    }
+}
+
+/// Dispatches connections to a destination's endpoints.
 #[derive(Clone)]
-pub struct Balancer(unsync::mpsc::UnboundedSender<Waiter>);
+pub struct Balancer {
+    tx: unsync::mpsc::UnboundedSender<Request>,
+
+    /// When set, connections are rejected while the destination's circuit is open.
+    breaker: Option<Rc<RefCell<CircuitBreaker>>>,
+
+    /// When set, connections are shed while too many others are being dispatched.
+    dispatch_limit: Option<Rc<DispatchLimit>>,
+}
+
 impl Balancer {
     /// Obtains a connection to the destination.
     pub fn connect(&self) -> Connect {
+        self.connect_with_sni(None)
+    }
+
+    /// Obtains a connection to the destination on behalf of a downstream client that
+    /// requested the TLS server name `sni`.
+    pub fn connect_with_sni(&self, sni: Option<String>) -> Connect {
+        self.dispatch(sni, None, None)
+    }
+
+    /// Obtains a connection to the destination on behalf of the downstream client at
+    /// `client`, which requested the TLS server name `sni`. When the destination is
+    /// sticky, the client is preferably connected to the endpoint to which it was last
+    /// dispatched.
+    pub fn connect_from(&self, client: &net::SocketAddr, sni: Option<String>) -> Connect {
+        self.dispatch(sni, Some(client.ip()), None)
+    }
+
+    /// Obtains a connection for the downstream client at `client` whose stream failed
+    /// on the endpoint at `failed`, e.g. because it was reset. The client is connected
+    /// to another endpoint, unless no other endpoint is usable.
+    pub fn retry_from(
+        &self,
+        client: &net::SocketAddr,
+        sni: Option<String>,
+        failed: &net::SocketAddr,
+    ) -> Connect {
+        self.dispatch(sni, Some(client.ip()), Some(*failed))
+    }
+
+    fn dispatch(
+        &self,
+        sni: Option<String>,
+        client: Option<net::IpAddr>,
+        avoid: Option<net::SocketAddr>,
+    ) -> Connect {
+        let permit = match self.dispatch_limit {
+            None => None,
+            Some(ref limit) => {
+                match DispatchLimit::acquire(limit) {
+                    Some(permit) => Some(permit),
+                    None => {
+                        let e = connect_error(ConnectErrorKind::Shed, "too many dispatches");
+                        return Connect(Some(Err(e)), None);
+                    }
+                }
+            }
+        };
+        let waiter = |tx| {
+            Request::Connect(Waiter {
+                sni,
+                client,
+                target: None,
+                avoid,
+                tx,
+            })
+        };
+        Connect(self.request(waiter), permit)
+    }
+
+    /// Assigns a datagram session to one of the destination's endpoints.
+    ///
+    /// Sessions are balanced, and count toward their endpoints' load, as connections
+    /// are.
+    pub fn open_session(&self) -> OpenSession {
+        OpenSession(self.request(Request::Session))
+    }
+
+    fn request<T, F>(&self, mk: F) -> Option<Result<unsync::oneshot::Receiver<T>, Error>>
+    where
+        F: FnOnce(unsync::oneshot::Sender<T>) -> Request,
+    {
+        if let Some(ref breaker) = self.breaker {
+            if !breaker.borrow_mut().allow() {
+                let e = connect_error(ConnectErrorKind::CircuitOpen, "circuit open");
+                return Some(Err(e));
+            }
+        }
+
         let (tx, rx) = unsync::oneshot::channel();
-        let result = unsync::mpsc::UnboundedSender::unbounded_send(&self.0, tx)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "lost dispatcher"))
+        let result = unsync::mpsc::UnboundedSender::unbounded_send(&self.tx, mk(tx))
+            .map_err(|_| connect_error(ConnectErrorKind::Unavailable, "lost dispatcher"))
             .map(|_| rx);
-        Connect(Some(result))
+        Some(result)
     }
 }
 
-pub struct Connect(Option<io::Result<unsync::oneshot::Receiver<endpoint::Connection>>>);
+/// A pending connection to one of a destination's endpoints.
+///
+/// Fails with `Error::Connect`, e.g. when the destination's circuit is open.
+pub struct Connect(
+    Option<Result<unsync::oneshot::Receiver<endpoint::Connection>, Error>>,
+    /// Holds the connection's place within the destination's dispatch limit, if any,
+    /// until it completes.
+    Option<Permit>
+);
 impl Future for Connect {
     type Item = endpoint::Connection;
-    type Error = io::Error;
+    type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut recv = self.0.take().expect(
+        let recv = self.0.take().expect(
             "connect must not be polled after completion",
-        )?;
-        match recv.poll() {
-            Err(_) => Err(io::Error::new(io::ErrorKind::Interrupted, "canceled")),
-            Ok(Async::Ready(conn)) => Ok(Async::Ready(conn)),
-            Ok(Async::NotReady) => {
-                self.0 = Some(Ok(recv));
-                Ok(Async::NotReady)
-            }
+        );
+        let reply = poll_reply(recv, &mut self.0);
+        match reply {
+            Ok(Async::NotReady) => {}
+            _ => drop(self.1.take()),
+        }
+        reply
+    }
+}
+
+/// A pending datagram session.
+pub struct OpenSession(Option<Result<unsync::oneshot::Receiver<endpoint::Session>, Error>>);
+impl Future for OpenSession {
+    type Item = endpoint::Session;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let recv = self.0.take().expect(
+            "session must not be polled after completion",
+        );
+        poll_reply(recv, &mut self.0)
+    }
+}
+
+/// Polls the dispatcher's reply to a request, restoring the receiver if the reply is
+/// not yet ready.
+fn poll_reply<T>(
+    recv: Result<unsync::oneshot::Receiver<T>, Error>,
+    slot: &mut Option<Result<unsync::oneshot::Receiver<T>, Error>>,
+) -> Poll<T, Error> {
+    let mut recv = recv?;
+    match recv.poll() {
+        Err(_) => Err(connect_error(ConnectErrorKind::Unavailable, "canceled")),
+        Ok(Async::Ready(item)) => Ok(Async::Ready(item)),
+        Ok(Async::NotReady) => {
+            *slot = Some(Ok(recv));
+            Ok(Async::NotReady)
         }
     }
 }
@@ -101,6 +389,14 @@ pub struct Endpoints {
     retired: EndpointMap,
 
     failed: FailedMap,
+
+    /// Endpoints that have been taken out of service by an operator. These take
+    /// precedence over the endpoints' health.
+    ejected: EndpointMap,
+
+    /// When endpoints were recently dropped, so that they may skip slow start if they
+    /// return.
+    removed: HashMap<net::SocketAddr, Instant>,
 }
 
 impl Endpoints {
@@ -116,20 +412,90 @@ impl Endpoints {
         &self.retired
     }
 
-    pub fn update_failed(&mut self, max_failures: usize, penalty: Duration) {
+    pub fn ejected(&self) -> &EndpointMap {
+        &self.ejected
+    }
+
+    /// Moves endpoints in `ejected` out of service and returns previously-ejected
+    /// endpoints that are no longer in `ejected` to service.
+    pub fn update_ejected(&mut self, ejected: &HashSet<net::SocketAddr>) {
+        for addr in ejected {
+            let ep = match self.available.swap_remove(addr) {
+                Some(ep) => Some(ep),
+                None => self.failed.swap_remove(addr).map(|(_, ep)| ep),
+            };
+            if let Some(ep) = ep {
+                info!("{}: ejected", addr);
+                self.ejected.insert(*addr, ep);
+            }
+        }
+
+        let reinstated: Vec<net::SocketAddr> = self.ejected
+            .keys()
+            .filter(|addr| !ejected.contains(*addr))
+            .cloned()
+            .collect();
+        for addr in reinstated {
+            info!("{}: reinstated", addr);
+            let ep = self.ejected.swap_remove(&addr).unwrap();
+            self.available.insert(addr, ep);
+        }
+    }
+
+    /// Applies operator weight overrides to all endpoints, clearing the overrides of
+    /// endpoints that are not in `multipliers`.
+    pub fn update_weight_multipliers(&mut self, multipliers: &HashMap<net::SocketAddr, f64>) {
+        let addrs: Vec<net::SocketAddr> = self.available
+            .keys()
+            .chain(self.retired.keys())
+            .chain(self.failed.keys())
+            .chain(self.ejected.keys())
+            .cloned()
+            .collect();
+        for addr in addrs {
+            let m = multipliers.get(&addr).cloned();
+            if let Some(ep) = self.get_mut(&addr) {
+                ep.set_weight_multiplier(m);
+            }
+        }
+    }
+
+    fn get_mut(&mut self, addr: &net::SocketAddr) -> Option<&mut Endpoint> {
+        if self.available.contains_key(addr) {
+            return self.available.get_mut(addr);
+        }
+        if self.retired.contains_key(addr) {
+            return self.retired.get_mut(addr);
+        }
+        if self.ejected.contains_key(addr) {
+            return self.ejected.get_mut(addr);
+        }
+        match self.failed.get_mut(addr) {
+            Some(&mut (_, ref mut ep)) => Some(ep),
+            None => None,
+        }
+    }
+
+    /// Fails endpoints that have exceeded the failure policy and returns failed
+    /// endpoints to service, on probation, once their penalty has elapsed.
+    pub fn update_failed(&mut self, fail_fast: &FailFast, now: Instant) {
         let mut available = VecDeque::with_capacity(self.failed.len());
         let mut failed = VecDeque::with_capacity(self.failed.len());
 
-        for (_, ep) in self.available.drain(..) {
-            if ep.state().consecutive_failures < max_failures {
-                available.push_back(ep);
+        for (_, mut ep) in self.available.drain(..) {
+            if ep.should_fail(fail_fast.max_consecutive_failures) {
+                let penalty = ep.fail(fail_fast.penalty, fail_fast.max_penalty);
+                debug!("{}: failed for {}s", ep.peer_addr(), penalty.as_secs());
+                failed.push_back((now, ep));
             } else {
-                failed.push_back((Instant::now(), ep));
+                ep.check_probation();
+                available.push_back(ep);
             }
         }
 
-        for (_, (start, ep)) in self.failed.drain(..) {
-            if start + penalty <= Instant::now() {
+        for (_, (start, mut ep)) in self.failed.drain(..) {
+            if start + ep.penalty() <= now {
+                ep.start_probation(fail_fast.success_threshold);
                 available.push_back(ep);
             } else {
                 failed.push_back((start, ep));
@@ -137,7 +503,8 @@ impl Endpoints {
         }
 
         if available.is_empty() {
-            while let Some((_, ep)) = failed.pop_front() {
+            while let Some((_, mut ep)) = failed.pop_front() {
+                ep.start_probation(fail_fast.success_threshold);
                 self.available.insert(ep.peer_addr(), ep);
             }
         } else {
@@ -152,7 +519,20 @@ impl Endpoints {
 
     // TODO: we need to do some sort of probation deal to manage endpoints that are
     // retired.
-    pub fn update_resolved(&mut self, resolved: &[WeightedAddr]) {
+    pub fn update_resolved(
+        &mut self,
+        resolved: &[WeightedAddr],
+        slow_start: Option<&SlowStart>,
+        weight_mode: WeightMode,
+    ) {
+        match slow_start {
+            None => self.removed.clear(),
+            Some(s) => {
+                let now = Instant::now();
+                self.removed.retain(|_, t| now < *t + s.endpoint_memory);
+            }
+        }
+
         let mut temp = {
             let sz = cmp::max(self.available.len(), self.retired.len());
             VecDeque::with_capacity(sz)
@@ -161,13 +541,14 @@ impl Endpoints {
         self.check_retired(&dsts, &mut temp);
         self.check_available(&dsts, &mut temp);
         self.check_failed(&dsts);
-        self.update_available_from_new(dsts);
+        self.check_ejected(&dsts, &mut temp);
+        self.update_available_from_new(dsts, slow_start, weight_mode);
     }
 
     /// Checks active endpoints.
     fn check_available(
         &mut self,
-        dsts: &OrderMap<net::SocketAddr, f64>,
+        dsts: &OrderMap<net::SocketAddr, WeightedAddr>,
         temp: &mut VecDeque<Endpoint>,
     ) {
         for (addr, ep) in self.available.drain(..) {
@@ -175,6 +556,7 @@ impl Endpoints {
                 temp.push_back(ep);
             } else if ep.is_idle() {
                 drop(ep);
+                self.removed.insert(addr, Instant::now());
             } else {
                 self.retired.insert(addr, ep);
             }
@@ -192,7 +574,7 @@ impl Endpoints {
     /// retired if still active, or dropped if inactive.
     fn check_retired(
         &mut self,
-        dsts: &OrderMap<net::SocketAddr, f64>,
+        dsts: &OrderMap<net::SocketAddr, WeightedAddr>,
         temp: &mut VecDeque<Endpoint>,
     ) {
         for (addr, ep) in self.retired.drain(..) {
@@ -200,6 +582,7 @@ impl Endpoints {
                 self.available.insert(addr, ep);
             } else if ep.is_idle() {
                 drop(ep);
+                self.removed.insert(addr, Instant::now());
             } else {
                 temp.push_back(ep);
             }
@@ -212,13 +595,14 @@ impl Endpoints {
     }
 
     /// Checks failed endpoints.
-    fn check_failed(&mut self, dsts: &OrderMap<net::SocketAddr, f64>) {
+    fn check_failed(&mut self, dsts: &OrderMap<net::SocketAddr, WeightedAddr>) {
         let mut temp = VecDeque::with_capacity(self.failed.len());
         for (addr, (since, ep)) in self.failed.drain(..) {
             if dsts.contains_key(&addr) {
                 temp.push_back((since, ep));
             } else if ep.is_idle() {
                 drop(ep);
+                self.removed.insert(addr, Instant::now());
             } else {
                 self.retired.insert(addr, ep);
             }
@@ -230,29 +614,362 @@ impl Endpoints {
         }
     }
 
-    fn update_available_from_new(&mut self, mut dsts: OrderMap<net::SocketAddr, f64>) {
+    /// Checks ejected endpoints.
+    ///
+    /// Ejected endpoints that are no longer resolved are retired if still active, or
+    /// dropped if inactive.
+    fn check_ejected(
+        &mut self,
+        dsts: &OrderMap<net::SocketAddr, WeightedAddr>,
+        temp: &mut VecDeque<Endpoint>,
+    ) {
+        for (addr, ep) in self.ejected.drain(..) {
+            if dsts.contains_key(&addr) {
+                temp.push_back(ep);
+            } else if ep.is_idle() {
+                drop(ep);
+                self.removed.insert(addr, Instant::now());
+            } else {
+                self.retired.insert(addr, ep);
+            }
+        }
+
+        for _ in 0..temp.len() {
+            let ep = temp.pop_front().unwrap();
+            self.ejected.insert(ep.peer_addr(), ep);
+        }
+    }
+
+    fn update_available_from_new(
+        &mut self,
+        mut dsts: OrderMap<net::SocketAddr, WeightedAddr>,
+        slow_start: Option<&SlowStart>,
+        weight_mode: WeightMode,
+    ) {
+        // New endpoints only warm up when there are other endpoints to share their load.
+        let warm_up = !self.available.is_empty();
+
         // Add new endpoints or update the base weights of existing endpoints.
         //let metrics = self.endpoint_metrics.clone();
-        for (addr, weight) in dsts.drain(..) {
+        for (addr, dst) in dsts.drain(..) {
             if let Some(&mut (_, ref mut ep)) = self.failed.get_mut(&addr) {
-                ep.set_weight(weight);
+                ep.set_weight(dst.weight, weight_mode);
+                ep.set_meta(dst.meta);
                 continue;
             }
 
             if let Some(ep) = self.available.get_mut(&addr) {
-                ep.set_weight(weight);
+                ep.set_weight(dst.weight, weight_mode);
+                ep.set_meta(dst.meta);
+                continue;
+            }
+
+            if let Some(ep) = self.ejected.get_mut(&addr) {
+                ep.set_weight(dst.weight, weight_mode);
+                ep.set_meta(dst.meta);
                 continue;
             }
 
-            self.available.insert(addr, endpoint::new(addr, weight));
+            let mut ep = endpoint::new(addr, dst.weight, dst.meta);
+            ep.set_weight(dst.weight, weight_mode);
+            if let Some(s) = slow_start {
+                if self.removed.remove(&addr).is_none() && warm_up {
+                    debug!("{}: warming up for {}s", addr, s.window.as_secs());
+                    ep.start_warming(s.window);
+                }
+            }
+            self.available.insert(addr, ep);
         }
     }
 
-    fn dsts_by_addr(dsts: &[WeightedAddr]) -> OrderMap<net::SocketAddr, f64> {
+    fn dsts_by_addr(dsts: &[WeightedAddr]) -> OrderMap<net::SocketAddr, WeightedAddr> {
         let mut by_addr = OrderMap::with_capacity(dsts.len());
-        for &WeightedAddr { addr, weight } in dsts {
-            by_addr.insert(addr, weight);
+        for dst in dsts {
+            by_addr.insert(dst.addr, dst.clone());
         }
         by_addr
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Endpoints, SharedRng, WeightedAddr};
+    use super::endpoint::Endpoint;
+    use connector::{Connector, ConnectorConfig, WeightMode};
+    use rand::{self, SeedableRng, StdRng};
+    use serde_json;
+    use std::cell::RefCell;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    fn connector(json: &str) -> ConnectorConfig {
+        serde_json::from_str(json).expect("failed to parse connector config")
+    }
+
+    /// A balancer's endpoints, whose connection attempts are recorded by the test rather than
+    /// made, so that they may be failed and returned to service on probation at chosen times.
+    struct FailFastEndpoints {
+        endpoints: Endpoints,
+        connector: Connector,
+        rng: SharedRng,
+    }
+
+    impl FailFastEndpoints {
+        /// Resolves `addrs`, which are failed as `config`'s `failFast` describes.
+        fn new(addrs: &[SocketAddr], config: &ConnectorConfig) -> FailFastEndpoints {
+            let connector = config.mk_connector().expect("invalid connector config");
+            let resolved: Vec<WeightedAddr> =
+                addrs.iter().map(|a| WeightedAddr::new(*a, 1.0)).collect();
+            let mut endpoints = Endpoints::default();
+            endpoints.update_resolved(&resolved, None, WeightMode::Normalized);
+            FailFastEndpoints {
+                endpoints,
+                connector,
+                rng: Rc::new(RefCell::new(StdRng::from_seed(&[rand::random::<usize>()][..]))),
+            }
+        }
+
+        fn endpoint(&self, addr: &SocketAddr) -> &Endpoint {
+            match self.endpoints.available().get(addr) {
+                Some(ep) => ep,
+                None => &self.endpoints.failed().get(addr).expect("unknown endpoint").1,
+            }
+        }
+
+        /// Records a connection attempt to `addr` that succeeded.
+        fn connected(&self, addr: &SocketAddr) {
+            let window = self.connector.stats_window();
+            self.endpoint(addr).state_mut().succeeded(window);
+        }
+
+        /// Records a connection attempt to `addr` that failed.
+        fn failed(&self, addr: &SocketAddr) {
+            self.failed_at(addr, Instant::now());
+        }
+
+        /// Records a connection attempt to `addr` that failed at `now`, backing the endpoint
+        /// off if `connectBackoff` is configured.
+        fn failed_at(&self, addr: &SocketAddr, now: Instant) {
+            let e = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+            let window = self.connector.stats_window();
+            let backoff = self.connector.connect_backoff().cloned();
+            let mut state = self.endpoint(addr).state_mut();
+            state.failed(now, *addr, &e, window, backoff, &self.rng);
+        }
+
+        /// When `addr` may be connected to again, if it is backing off at `now`.
+        fn backoff_until(&self, addr: &SocketAddr, now: Instant) -> Option<Instant> {
+            self.endpoint(addr).backoff_until(now)
+        }
+
+        /// Fails endpoints, and returns them to service, as a dispatcher would at `now`.
+        fn update(&mut self, now: Instant) {
+            let fail_fast = self.connector.fail_fast().clone();
+            self.endpoints.update_failed(&fail_fast, now);
+        }
+
+        /// Describes `addr` as `available`, `probation`, or `failed`.
+        fn status(&self, addr: &SocketAddr) -> &'static str {
+            match self.endpoints.available().get(addr) {
+                None => "failed",
+                Some(ep) if ep.state().probation.is_some() => "probation",
+                Some(_) => "available",
+            }
+        }
+
+        /// The weight with which `addr` is selected.
+        fn weight(&self, addr: &SocketAddr) -> f64 {
+            self.endpoint(addr).weight()
+        }
+
+        /// The penalty most recently applied to `addr`, if it has not been reinstated.
+        fn penalty(&self, addr: &SocketAddr) -> Duration {
+            self.endpoint(addr).penalty()
+        }
+    }
+
+    /// Resolves two endpoints, so that failing the first never leaves the balancer without
+    /// available endpoints.
+    fn fail_fast(fail_fast: &str) -> FailFastEndpoints {
+        let config = connector(&format!("{{\"failFast\": {}}}", fail_fast));
+        FailFastEndpoints::new(&[addr(1), addr(2)], &config)
+    }
+
+    /// The weight of the first endpoint relative to the second, healthy, one.
+    fn relative_weight(endpoints: &FailFastEndpoints) -> f64 {
+        endpoints.weight(&addr(1)) / endpoints.weight(&addr(2))
+    }
+
+    fn assert_weight(endpoints: &FailFastEndpoints, expected: f64) {
+        let weight = relative_weight(endpoints);
+        assert!(
+            (weight - expected).abs() < 1e-9,
+            "weight {} is not {}",
+            weight,
+            expected
+        );
+    }
+
+    #[test]
+    fn reinstates_endpoints_at_full_weight_by_default() {
+        let mut endpoints =
+            fail_fast("{\"maxConsecutiveFailures\": 2, \"failurePenaltySecs\": 10}");
+        let a = addr(1);
+        let t0 = Instant::now();
+
+        endpoints.failed(&a);
+        endpoints.update(t0);
+        assert_eq!(endpoints.status(&a), "available");
+        endpoints.failed(&a);
+        endpoints.update(t0);
+        assert_eq!(endpoints.status(&a), "failed");
+        assert_eq!(endpoints.penalty(&a), secs(10));
+
+        endpoints.update(t0 + secs(9));
+        assert_eq!(endpoints.status(&a), "failed");
+
+        // An endpoint that must only succeed once is not penalized further once it returns.
+        endpoints.update(t0 + secs(10));
+        assert_eq!(endpoints.status(&a), "probation");
+        assert_weight(&endpoints, 1.0);
+
+        endpoints.connected(&a);
+        endpoints.update(t0 + secs(11));
+        assert_eq!(endpoints.status(&a), "available");
+        assert_weight(&endpoints, 1.0);
+        assert_eq!(endpoints.penalty(&a), secs(0));
+
+        // Once reinstated, the endpoint is failed as it was before it failed.
+        endpoints.failed(&a);
+        endpoints.update(t0 + secs(12));
+        assert_eq!(endpoints.status(&a), "available");
+        endpoints.failed(&a);
+        endpoints.update(t0 + secs(12));
+        assert_eq!(endpoints.status(&a), "failed");
+        assert_eq!(endpoints.penalty(&a), secs(10));
+    }
+
+    #[test]
+    fn does_not_grow_penalties_by_default() {
+        let mut endpoints =
+            fail_fast("{\"maxConsecutiveFailures\": 1, \"failurePenaltySecs\": 10}");
+        let a = addr(1);
+        let mut now = Instant::now();
+        for _ in 0..3 {
+            endpoints.failed(&a);
+            endpoints.update(now);
+            assert_eq!(endpoints.status(&a), "failed");
+            assert_eq!(endpoints.penalty(&a), secs(10));
+            now += secs(10);
+            endpoints.update(now);
+            assert_eq!(endpoints.status(&a), "probation");
+        }
+    }
+
+    #[test]
+    fn fails_flapping_endpoints_on_probation_with_growing_penalties() {
+        let mut endpoints = fail_fast(
+            "{\"maxConsecutiveFailures\": 2, \"failurePenaltySecs\": 10, \
+             \"maxPenaltySecs\": 25, \"successThreshold\": 3}",
+        );
+        let a = addr(1);
+        let t0 = Instant::now();
+        endpoints.failed(&a);
+        endpoints.failed(&a);
+        endpoints.update(t0);
+        assert_eq!(endpoints.penalty(&a), secs(10));
+
+        // On probation, the weight ramps up with each success.
+        endpoints.update(t0 + secs(10));
+        assert_eq!(endpoints.status(&a), "probation");
+        assert_weight(&endpoints, 1.0 / 3.0);
+        endpoints.connected(&a);
+        endpoints.update(t0 + secs(11));
+        assert_eq!(endpoints.status(&a), "probation");
+        assert_weight(&endpoints, 2.0 / 3.0);
+
+        // A single failure on probation fails the endpoint again, for twice as long.
+        endpoints.failed(&a);
+        endpoints.update(t0 + secs(12));
+        assert_eq!(endpoints.status(&a), "failed");
+        assert_eq!(endpoints.penalty(&a), secs(20));
+        endpoints.update(t0 + secs(31));
+        assert_eq!(endpoints.status(&a), "failed");
+        endpoints.update(t0 + secs(32));
+        assert_eq!(endpoints.status(&a), "probation");
+        assert_weight(&endpoints, 1.0 / 3.0);
+
+        // The penalty is bounded by maxPenaltySecs.
+        endpoints.failed(&a);
+        endpoints.update(t0 + secs(33));
+        assert_eq!(endpoints.status(&a), "failed");
+        assert_eq!(endpoints.penalty(&a), secs(25));
+        endpoints.update(t0 + secs(58));
+        assert_eq!(endpoints.status(&a), "probation");
+
+        // Enough consecutive successes fully reinstate the endpoint and reset its penalty.
+        for _ in 0..3 {
+            endpoints.connected(&a);
+        }
+        endpoints.update(t0 + secs(59));
+        assert_eq!(endpoints.status(&a), "available");
+        assert_weight(&endpoints, 1.0);
+        assert_eq!(endpoints.penalty(&a), secs(0));
+        endpoints.failed(&a);
+        endpoints.failed(&a);
+        endpoints.update(t0 + secs(60));
+        assert_eq!(endpoints.penalty(&a), secs(10));
+    }
+
+    #[test]
+    fn resets_connect_backoff_once_an_endpoint_connects() {
+        let config = connector(
+            "{\"connectBackoff\": {\"baseBackoffMs\": 100, \"maxBackoffMs\": 1000}}",
+        );
+        let endpoints = FailFastEndpoints::new(&[addr(1), addr(2)], &config);
+        let a = addr(1);
+        let t0 = Instant::now();
+        assert_eq!(endpoints.backoff_until(&a, t0), None);
+
+        // Asserts that the endpoint backs off, from `now`, for between half and all of
+        // `delay`.
+        let assert_backoff = |now: Instant, delay: Duration| {
+            let until = endpoints.backoff_until(&a, now).expect("not backing off");
+            assert!(
+                now + delay / 2 <= until && until <= now + delay,
+                "backing off for {:?}, not up to {:?}",
+                until - now,
+                delay
+            );
+            until
+        };
+
+        endpoints.failed_at(&a, t0);
+        assert_backoff(t0, ms(100));
+        endpoints.failed_at(&a, t0);
+        assert_backoff(t0, ms(200));
+        endpoints.failed_at(&a, t0);
+        let until = assert_backoff(t0, ms(400));
+        assert_eq!(endpoints.backoff_until(&a, until), None);
+
+        // A successful connection resets the delay.
+        endpoints.connected(&a);
+        assert_eq!(endpoints.backoff_until(&a, until - ms(1)), None);
+        endpoints.failed_at(&a, until);
+        assert_backoff(until, ms(100));
+    }
+}
@@ -0,0 +1,136 @@
+//! Counts an endpoint's connection attempts over a rolling window.
+//!
+//! The window is divided into a fixed number of buckets, each of which counts the
+//! attempts made during its slice of the window, so that the memory used by each
+//! endpoint does not grow with its connection rate. Buckets are reused as time passes:
+//! a bucket that was last used a full window ago is cleared before it is reused.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// The number of slices into which the window is divided. Attempts age out of the
+/// window one slice at a time.
+const BUCKETS: usize = 10;
+
+/// An endpoint's connection attempts, counted over a rolling window.
+#[derive(Default)]
+pub struct ConnectWindow {
+    /// The time from which slices are numbered, set by the first attempt.
+    origin: Option<Instant>,
+    buckets: [Bucket; BUCKETS],
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    /// The slice of time counted by this bucket.
+    slice: u64,
+    successes: usize,
+    failures: usize,
+}
+
+impl ConnectWindow {
+    /// Records an attempt made at `now`.
+    pub fn record(&mut self, now: Instant, window: Duration, success: bool) {
+        let origin = *self.origin.get_or_insert(now);
+        let slice = slice(now, origin, window);
+        let bucket = &mut self.buckets[(slice % BUCKETS as u64) as usize];
+        if bucket.slice != slice {
+            *bucket = Bucket {
+                slice,
+                successes: 0,
+                failures: 0,
+            };
+        }
+        if success {
+            bucket.successes += 1;
+        } else {
+            bucket.failures += 1;
+        }
+    }
+
+    /// Returns the number of successful and failed attempts within `window` of `now`.
+    pub fn totals(&self, now: Instant, window: Duration) -> (usize, usize) {
+        let origin = match self.origin {
+            None => return (0, 0),
+            Some(origin) => origin,
+        };
+        let current = slice(now, origin, window);
+        let mut successes = 0;
+        let mut failures = 0;
+        for b in &self.buckets {
+            if b.slice <= current && current - b.slice < BUCKETS as u64 {
+                successes += b.successes;
+                failures += b.failures;
+            }
+        }
+        (successes, failures)
+    }
+}
+
+/// Numbers the slice of the window in which `now` falls.
+fn slice(now: Instant, origin: Instant, window: Duration) -> u64 {
+    let nanos = |d: Duration| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos());
+    let width = cmp::max(nanos(window) / BUCKETS as u64, 1);
+    let elapsed = if now > origin { now - origin } else { Duration::default() };
+    nanos(elapsed) / width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectWindow;
+    use std::time::{Duration, Instant};
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn counts_connect_attempts_until_their_slice_leaves_the_window() {
+        // A 10s window is counted in 1s slices, numbered from the first attempt.
+        let window = secs(10);
+        let mut connects = ConnectWindow::default();
+        let t0 = Instant::now();
+        assert_eq!(connects.totals(t0, window), (0, 0));
+
+        connects.record(t0, window, true);
+        connects.record(t0 + ms(999), window, false);
+        connects.record(t0 + secs(1), window, true);
+        assert_eq!(connects.totals(t0 + secs(1), window), (2, 1));
+
+        // The first slice is counted until a full window has passed since it began, even
+        // though its last attempt was made later.
+        assert_eq!(connects.totals(t0 + ms(9_999), window), (2, 1));
+        assert_eq!(connects.totals(t0 + secs(10), window), (1, 0));
+        assert_eq!(connects.totals(t0 + ms(10_999), window), (1, 0));
+        assert_eq!(connects.totals(t0 + secs(11), window), (0, 0));
+
+        // Times before the first attempt are counted in its slice.
+        assert_eq!(connects.totals(t0 - secs(1), window), (1, 1));
+    }
+
+    #[test]
+    fn clears_reused_connect_window_buckets() {
+        let window = secs(10);
+        let mut connects = ConnectWindow::default();
+        let t0 = Instant::now();
+        connects.record(t0, window, false);
+        connects.record(t0 + ms(500), window, false);
+
+        // The 11th slice reuses the first slice's bucket, which no longer counts the
+        // attempts made a window ago.
+        connects.record(t0 + secs(10), window, true);
+        assert_eq!(connects.totals(t0 + secs(10), window), (1, 0));
+        connects.record(t0 + ms(10_500), window, false);
+        assert_eq!(connects.totals(t0 + ms(10_999), window), (1, 1));
+
+        // Buckets that were not reused age out, however long the window was idle.
+        connects.record(t0 + secs(15), window, true);
+        assert_eq!(connects.totals(t0 + secs(19), window), (2, 1));
+        assert_eq!(connects.totals(t0 + secs(20), window), (1, 0));
+        assert_eq!(connects.totals(t0 + secs(1_000), window), (0, 0));
+    }
+}
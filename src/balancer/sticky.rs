@@ -0,0 +1,132 @@
+//! Remembers the endpoint to which each client was last dispatched, so that a client
+//! that reconnects may be sent to the same endpoint.
+//!
+//! Clients are identified by their IP addresses. Each client's entry expires once it has
+//! not been used for the policy's TTL, and the least recently used entries are evicted
+//! once the table holds `max_entries`.
+
+use super::super::connector::Stickiness;
+use super::super::metrics;
+use std::collections::{BTreeMap, HashMap};
+use std::net;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub struct StickyTable {
+    policy: Stickiness,
+    entries: HashMap<net::IpAddr, Entry>,
+    /// Orders clients by their entries' most recent use, oldest first.
+    lru: BTreeMap<u64, net::IpAddr>,
+    next_use: u64,
+    metrics: Metrics,
+}
+
+struct Entry {
+    addr: net::SocketAddr,
+    used_at: Instant,
+    /// The entry's key in `lru`.
+    use_id: u64,
+}
+
+/// How a client's endpoint was chosen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The client's remembered endpoint was used.
+    Hit,
+    /// No endpoint was remembered for the client.
+    Miss,
+    /// The client's remembered endpoint was unavailable or overloaded.
+    Fallback,
+}
+
+impl StickyTable {
+    /// Counts lookups as `sticky_lookups`, labeled by their `outcome`, and reports the
+    /// number of clients remembered as `sticky_entries`.
+    pub fn new(policy: &Stickiness, metrics: &metrics::Scope) -> StickyTable {
+        StickyTable {
+            policy: policy.clone(),
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_use: 0,
+            metrics: Metrics::new(&metrics.clone().prefixed("sticky")),
+        }
+    }
+
+    pub fn max_load_factor(&self) -> f64 {
+        self.policy.max_load_factor
+    }
+
+    /// The endpoint to which `client` was last dispatched, unless its entry has expired.
+    pub fn get(&mut self, client: net::IpAddr, now: Instant) -> Option<net::SocketAddr> {
+        let expired = match self.entries.get(&client) {
+            None => return None,
+            Some(e) => now.duration_since(e.used_at) >= self.policy.ttl,
+        };
+        if expired {
+            trace!("{}: sticky entry expired", client);
+            self.remove(client);
+            return None;
+        }
+        self.entries.get(&client).map(|e| e.addr)
+    }
+
+    /// Remembers that `client` was dispatched to `addr`, refreshing its entry's TTL.
+    pub fn assign(&mut self, client: net::IpAddr, addr: net::SocketAddr, now: Instant) {
+        self.remove(client);
+        while self.entries.len() >= self.policy.max_entries {
+            let oldest = match self.lru.keys().next() {
+                None => break,
+                Some(&id) => self.lru[&id],
+            };
+            self.remove(oldest);
+            self.metrics.evictions.incr(1);
+        }
+        let use_id = self.next_use;
+        self.next_use += 1;
+        self.lru.insert(use_id, client);
+        self.entries.insert(
+            client,
+            Entry {
+                addr,
+                used_at: now,
+                use_id,
+            },
+        );
+        self.metrics.entries.set(self.entries.len());
+    }
+
+    pub fn record(&self, outcome: Outcome) {
+        match outcome {
+            Outcome::Hit => self.metrics.hits.incr(1),
+            Outcome::Miss => self.metrics.misses.incr(1),
+            Outcome::Fallback => self.metrics.fallbacks.incr(1),
+        }
+    }
+
+    fn remove(&mut self, client: net::IpAddr) {
+        if let Some(e) = self.entries.remove(&client) {
+            self.lru.remove(&e.use_id);
+            self.metrics.entries.set(self.entries.len());
+        }
+    }
+}
+
+struct Metrics {
+    hits: Arc<metrics::Counter>,
+    misses: Arc<metrics::Counter>,
+    fallbacks: Arc<metrics::Counter>,
+    evictions: Arc<metrics::Counter>,
+    entries: Arc<metrics::Gauge>,
+}
+
+impl Metrics {
+    fn new(metrics: &metrics::Scope) -> Metrics {
+        Metrics {
+            hits: metrics.clone().labeled("outcome", "hit").counter("lookups"),
+            misses: metrics.clone().labeled("outcome", "miss").counter("lookups"),
+            fallbacks: metrics.clone().labeled("outcome", "fallback").counter("lookups"),
+            evictions: metrics.counter("evictions"),
+            entries: metrics.gauge("entries"),
+        }
+    }
+}
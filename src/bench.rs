@@ -0,0 +1,456 @@
+//! Generates load against a listener, for capacity testing.
+//!
+//! Connections are opened at a fixed rate with the same connector that routers use, so
+//! that a client's TLS configuration applies. Each connection writes a payload, reads
+//! it back from the target (which is expected to echo it, e.g. a proxy routing to an
+//! echo server), and closes. Connections beyond the concurrency limit are skipped, so
+//! that a slow target does not cause an unbounded backlog.
+//!
+//! The load runs on its own reactor and timer, independently of any proxy.
+
+use super::Result;
+use super::app;
+use super::connection::socket::Socket;
+use super::connector::{Connector, ConnectorConfig};
+use futures::{Async, Future, Poll, Stream};
+use std::{cmp, fmt, io, net};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Core, Handle};
+use tokio_timer::{self, Interval, Timer};
+
+/// How often connections are launched. Each tick launches the connections that are due.
+const TICK_MS: u64 = 10;
+
+/// How long connections that are still open when the load stops may take to finish.
+const DRAIN_SECS: u64 = 10;
+
+/// Describes the load to generate.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The listener to which connections are made.
+    pub target: net::SocketAddr,
+    /// The most connections that may be open at once.
+    pub connections: usize,
+    /// The number of connections opened each second.
+    pub rate: u64,
+    /// The bytes written, and read back, on each connection.
+    pub payload_bytes: usize,
+    /// How long connections are opened for.
+    pub duration: Duration,
+    /// Configures how connections are established, including TLS.
+    pub client: ConnectorConfig,
+}
+
+/// Generates the load described by `config`, returning its statistics once every
+/// connection has finished.
+pub fn run(config: &Config) -> Result<Report> {
+    let connector = config.client.mk_connector().map_err(app::Error::Connector)?;
+    let mut core = Core::new()?;
+    let timer = tokio_timer::wheel()
+        .tick_duration(Duration::from_millis(TICK_MS))
+        .num_slots(64 * 1024)
+        .build();
+    let load = Load {
+        config: config.clone(),
+        connector,
+        payload: Rc::new((0..config.payload_bytes).map(|i| i as u8).collect()),
+        handle: core.handle(),
+        timer: timer.clone(),
+        ticks: timer.interval(Duration::from_millis(TICK_MS)),
+        started: Instant::now(),
+        launched: 0,
+        open: Rc::new(Cell::new(0)),
+        stats: Rc::new(RefCell::new(Stats::new())),
+    };
+    Ok(core.run(load)?)
+}
+
+/// Launches connections as they become due, until the load's duration has elapsed and
+/// its connections have finished.
+struct Load {
+    config: Config,
+    connector: Connector,
+    payload: Rc<Vec<u8>>,
+    handle: Handle,
+    timer: Timer,
+    ticks: Interval,
+    started: Instant,
+    /// The number of connections that have been due, including skipped connections.
+    launched: u64,
+    open: Rc<Cell<usize>>,
+    stats: Rc<RefCell<Stats>>,
+}
+
+impl Future for Load {
+    type Item = Report;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Report, io::Error> {
+        loop {
+            let elapsed = self.started.elapsed();
+            if elapsed < self.config.duration {
+                let due = self.config.rate as f64 * secs(elapsed);
+                while (self.launched as f64) < due {
+                    self.launched += 1;
+                    if self.open.get() < self.config.connections {
+                        self.launch();
+                    } else {
+                        self.stats.borrow_mut().record_skip();
+                    }
+                }
+            } else {
+                let drained = elapsed >= self.config.duration + Duration::from_secs(DRAIN_SECS);
+                if self.open.get() == 0 || drained {
+                    let mut stats = self.stats.borrow_mut();
+                    for _ in 0..self.open.get() {
+                        stats.record_error(Phase::Drain, io::ErrorKind::TimedOut);
+                    }
+                    let elapsed = cmp::min(elapsed, self.config.duration);
+                    return Ok(Async::Ready(stats.report(elapsed)));
+                }
+            }
+
+            match self.ticks.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "timer stopped"));
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+    }
+}
+
+impl Load {
+    fn launch(&mut self) {
+        self.open.set(self.open.get() + 1);
+        let start = Instant::now();
+        let payload = self.payload.clone();
+        let stats = self.stats.clone();
+        let open = self.open.clone();
+        let conn = self.connector
+            .connect(&self.config.target, &self.handle, &self.timer, None)
+            .then(move |res| match res {
+                Err(e) => Err((Phase::Connect, e.kind())),
+                Ok(socket) => {
+                    stats.borrow_mut().record_connect(start.elapsed());
+                    Ok((socket, stats))
+                }
+            })
+            .and_then(move |(socket, stats)| {
+                Roundtrip::new(socket, payload).then(move |res| match res {
+                    Err(e) => Err(e),
+                    Ok(rtt) => {
+                        stats.borrow_mut().record_roundtrip(rtt.latency, rtt.bytes);
+                        Ok(())
+                    }
+                })
+            });
+        let stats = self.stats.clone();
+        self.handle.spawn(conn.then(move |res| {
+            if let Err((phase, kind)) = res {
+                stats.borrow_mut().record_error(phase, kind);
+            }
+            open.set(open.get() - 1);
+            Ok(())
+        }));
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+/// The part of a connection's lifecycle in which an error occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Establishing the connection, including any TLS handshake.
+    Connect,
+    /// Writing the payload.
+    Write,
+    /// Reading the payload back.
+    Read,
+    /// Still open once the load stopped and its connections were given time to finish.
+    Drain,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Phase::Connect => "connect",
+            Phase::Write => "write",
+            Phase::Read => "read",
+            Phase::Drain => "drain",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Writes a payload and reads it back.
+struct Roundtrip {
+    socket: Socket,
+    payload: Rc<Vec<u8>>,
+    written: usize,
+    flushed: bool,
+    read: usize,
+    started: Instant,
+}
+
+struct Rtt {
+    latency: Duration,
+    /// The bytes written and read.
+    bytes: usize,
+}
+
+impl Roundtrip {
+    fn new(socket: Socket, payload: Rc<Vec<u8>>) -> Roundtrip {
+        Roundtrip {
+            socket,
+            payload,
+            written: 0,
+            flushed: false,
+            read: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Future for Roundtrip {
+    type Item = Rtt;
+    type Error = (Phase, io::ErrorKind);
+
+    fn poll(&mut self) -> Poll<Rtt, (Phase, io::ErrorKind)> {
+        let len = self.payload.len();
+        while self.written < len {
+            match self.socket.write(&self.payload[self.written..]) {
+                Ok(0) => return Err((Phase::Write, io::ErrorKind::WriteZero)),
+                Ok(sz) => self.written += sz,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err((Phase::Write, e.kind())),
+            }
+        }
+        if !self.flushed {
+            match self.socket.flush() {
+                Ok(()) => self.flushed = true,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err((Phase::Write, e.kind())),
+            }
+        }
+
+        let mut buf = [0u8; 4 * 1024];
+        while self.read < len {
+            let want = cmp::min(buf.len(), len - self.read);
+            match self.socket.read(&mut buf[..want]) {
+                Ok(0) => return Err((Phase::Read, io::ErrorKind::UnexpectedEof)),
+                Ok(sz) => {
+                    if buf[..sz] != self.payload[self.read..self.read + sz] {
+                        return Err((Phase::Read, io::ErrorKind::InvalidData));
+                    }
+                    self.read += sz;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err((Phase::Read, e.kind())),
+            }
+        }
+
+        Ok(Async::Ready(Rtt {
+            latency: self.started.elapsed(),
+            bytes: 2 * len,
+        }))
+    }
+}
+
+/// Aggregates the outcomes of a load's connections.
+#[derive(Debug, Default)]
+pub struct Stats {
+    connect_us: Vec<u64>,
+    roundtrip_us: Vec<u64>,
+    bytes: u64,
+    skipped: u64,
+    errors: BTreeMap<(Phase, String), u64>,
+}
+
+impl Stats {
+    /// Aggregates no outcomes.
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Records a connection that was established in `latency`.
+    pub fn record_connect(&mut self, latency: Duration) {
+        self.connect_us.push(micros(latency));
+    }
+
+    /// Records a payload that was written and read back in `latency`, transferring
+    /// `bytes` in both directions.
+    pub fn record_roundtrip(&mut self, latency: Duration, bytes: usize) {
+        self.roundtrip_us.push(micros(latency));
+        self.bytes += bytes as u64;
+    }
+
+    /// Records a connection that failed.
+    pub fn record_error(&mut self, phase: Phase, kind: io::ErrorKind) {
+        *self.errors.entry((phase, format!("{:?}", kind))).or_insert(0) += 1;
+    }
+
+    /// Records a connection that was not opened, since the concurrency limit had been
+    /// reached.
+    pub fn record_skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Summarizes the outcomes recorded over `elapsed`.
+    pub fn report(&mut self, elapsed: Duration) -> Report {
+        self.connect_us.sort();
+        self.roundtrip_us.sort();
+        Report {
+            elapsed,
+            connects: self.connect_us.len() as u64,
+            roundtrips: self.roundtrip_us.len() as u64,
+            bytes: self.bytes,
+            skipped: self.skipped,
+            connect_latency: Percentiles::of(&self.connect_us),
+            roundtrip_latency: Percentiles::of(&self.roundtrip_us),
+            errors: self.errors
+                .iter()
+                .map(|(&(phase, ref kind), &n)| (format!("{} {}", phase, kind), n))
+                .collect(),
+        }
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1_000)
+}
+
+/// Summarizes a load.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// The time over which connections were opened.
+    pub elapsed: Duration,
+    /// The connections established.
+    pub connects: u64,
+    /// The payloads written and read back.
+    pub roundtrips: u64,
+    /// The bytes written and read.
+    pub bytes: u64,
+    /// The connections not opened because the concurrency limit had been reached.
+    pub skipped: u64,
+    /// The time taken to establish connections, if any were.
+    pub connect_latency: Option<Percentiles>,
+    /// The time taken to write and read back payloads, if any were.
+    pub roundtrip_latency: Option<Percentiles>,
+    /// Counts failed connections by the phase in which they failed and their error,
+    /// e.g. `connect ConnectionRefused`.
+    pub errors: BTreeMap<String, u64>,
+}
+
+impl Report {
+    /// The connections established per second.
+    pub fn connects_per_sec(&self) -> f64 {
+        self.per_sec(self.connects)
+    }
+
+    /// The bytes written and read per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.per_sec(self.bytes)
+    }
+
+    fn per_sec(&self, n: u64) -> f64 {
+        let secs = secs(self.elapsed);
+        if secs > 0.0 { n as f64 / secs } else { 0.0 }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "elapsed: {:.1}s", secs(self.elapsed))?;
+        writeln!(f, "connects: {} ({:.1}/s)", self.connects, self.connects_per_sec())?;
+        writeln!(
+            f,
+            "roundtrips: {} ({} bytes, {:.1} KB/s)",
+            self.roundtrips,
+            self.bytes,
+            self.bytes_per_sec() / 1024.0
+        )?;
+        writeln!(f, "skipped: {}", self.skipped)?;
+        for &(name, latency) in &[
+            ("connect", &self.connect_latency),
+            ("roundtrip", &self.roundtrip_latency),
+        ]
+        {
+            match *latency {
+                None => writeln!(f, "{} latency: -", name)?,
+                Some(ref p) => writeln!(f, "{} latency: {}", name, p)?,
+            }
+        }
+        let errors: u64 = self.errors.values().sum();
+        writeln!(f, "errors: {}", errors)?;
+        for (error, n) in &self.errors {
+            writeln!(f, "  {}: {}", error, n)?;
+        }
+        Ok(())
+    }
+}
+
+/// The distribution of a set of latencies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Percentiles {
+    /// The median.
+    pub p50: Duration,
+    /// The 90th percentile.
+    pub p90: Duration,
+    /// The 99th percentile.
+    pub p99: Duration,
+    /// The 99.9th percentile.
+    pub p999: Duration,
+    /// The slowest.
+    pub max: Duration,
+}
+
+impl Percentiles {
+    /// Computes the nearest-rank percentiles of `sorted` microseconds, if there are any.
+    fn of(sorted: &[u64]) -> Option<Percentiles> {
+        if sorted.is_empty() {
+            return None;
+        }
+        let at = |q: f64| {
+            let rank = (q * sorted.len() as f64).ceil() as usize;
+            let us = sorted[cmp::max(rank, 1) - 1];
+            Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1_000)
+        };
+        Some(Percentiles {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            p999: at(0.999),
+            max: at(1.0),
+        })
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| secs(d) * 1_000.0;
+        write!(
+            f,
+            "p50={:.3}ms p90={:.3}ms p99={:.3}ms p999={:.3}ms max={:.3}ms",
+            ms(self.p50),
+            ms(self.p90),
+            ms(self.p99),
+            ms(self.p999),
+            ms(self.max)
+        )
+    }
+}
@@ -0,0 +1,99 @@
+//! Bounds the memory used to hold data that could not yet be written.
+//!
+//! Data is copied between peers through the shared transfer buffers. When a writer
+//! blocks, the bytes it did not accept are copied into a buffer held by that half of
+//! the stream until they are written. Each such buffer is granted from a budget shared
+//! by the process, and is returned when it is dropped, whether or not its bytes were
+//! ever written. While the budget is exhausted, reads are deferred, so that slow peers
+//! exert backpressure rather than growing the process's memory.
+
+use futures::Async;
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tacho;
+
+/// Tracks the bytes held by streams' pending buffers.
+#[derive(Clone)]
+pub struct BufferBudget(Rc<RefCell<Inner>>);
+
+struct Inner {
+    max_bytes: usize,
+    used_bytes: usize,
+    /// Tasks whose reads were deferred, notified once bytes are returned.
+    waiters: Vec<Task>,
+    buffered_bytes: tacho::Gauge,
+    exhausted: tacho::Counter,
+}
+
+impl BufferBudget {
+    /// Reports usage as `buffered_bytes`, and counts deferred reads as
+    /// `buffer_budget_exhausted`.
+    pub fn new(max_bytes: usize, metrics: &tacho::Scope) -> BufferBudget {
+        let buffered_bytes = metrics.gauge("buffered_bytes");
+        buffered_bytes.set(0);
+        BufferBudget(Rc::new(RefCell::new(Inner {
+            max_bytes,
+            used_bytes: 0,
+            waiters: Vec::new(),
+            buffered_bytes,
+            exhausted: metrics.counter("buffer_budget_exhausted"),
+        })))
+    }
+
+    /// Returns the number of bytes that may be read, since each of them may need to be
+    /// held if the writer blocks.
+    ///
+    /// If the budget is exhausted, the current task is notified when bytes are
+    /// returned.
+    pub fn poll_available(&self) -> Async<usize> {
+        let mut inner = self.0.borrow_mut();
+        if inner.used_bytes < inner.max_bytes {
+            return Async::Ready(inner.max_bytes - inner.used_bytes);
+        }
+        inner.exhausted.incr(1);
+        if !inner.waiters.iter().any(|t| t.will_notify_current()) {
+            inner.waiters.push(task::current());
+        }
+        Async::NotReady
+    }
+
+    /// Grants `bytes` to a pending buffer until the returned `Grant` is dropped.
+    pub fn grant(&self, bytes: usize) -> Grant {
+        let mut inner = self.0.borrow_mut();
+        inner.used_bytes += bytes;
+        inner.buffered_bytes.set(inner.used_bytes);
+        Grant {
+            budget: self.clone(),
+            bytes,
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        let waiters = {
+            let mut inner = self.0.borrow_mut();
+            inner.used_bytes -= bytes;
+            inner.buffered_bytes.set(inner.used_bytes);
+            if bytes == 0 {
+                return;
+            }
+            ::std::mem::replace(&mut inner.waiters, Vec::new())
+        };
+        // Deferred readers recheck the budget, waiting again if it is still exhausted.
+        for t in waiters {
+            t.notify();
+        }
+    }
+}
+
+/// Holds a pending buffer's share of the budget.
+pub struct Grant {
+    budget: BufferBudget,
+    bytes: usize,
+}
+
+impl Drop for Grant {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
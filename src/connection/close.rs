@@ -0,0 +1,230 @@
+use super::half_duplex::WriteTimeout;
+use std::cell::Cell;
+use std::{error, fmt};
+use std::io;
+use std::rc::Rc;
+
+/// Identifies a peer of a proxied connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    /// The downstream client, connected to a server.
+    Client,
+    /// The upstream endpoint to which the client's connection is proxied.
+    Server,
+}
+
+impl Peer {
+    /// The other peer of the connection.
+    pub fn other(&self) -> Peer {
+        match *self {
+            Peer::Client => Peer::Server,
+            Peer::Server => Peer::Client,
+        }
+    }
+
+    /// Names the direction in which data read from this peer flows, e.g. as a metric
+    /// label.
+    pub fn direction(&self) -> &'static str {
+        match *self {
+            Peer::Client => "client_to_server",
+            Peer::Server => "server_to_client",
+        }
+    }
+}
+
+/// Describes why a proxied connection was torn down.
+///
+/// A connection's reason is the first event observed while tearing it down. When both
+/// peers close at once, whichever close is read first determines the reason. Each
+/// direction of the connection also records the reason it finished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    ClientEof,
+    ServerEof,
+    ClientReset,
+    ServerReset,
+    /// The connection exceeded its server's `connectionLifetimeSecs`.
+    MaxAge,
+    /// No upstream connection was obtained within the server's `connectTimeoutMs`.
+    DispatchTimeout,
+    /// A peer accepted no written bytes within the server's `writeTimeoutSecs`.
+    WriteTimeout,
+    /// The balancer closed the connection so that clients reconnect to endpoints
+    /// according to their current weights.
+    Rebalanced,
+    /// The connection was shed from its server's full dispatch queue.
+    Shed,
+    /// The connection was handed off to another process (`connectionHandoff`).
+    HandedOff,
+    /// A TLS client of a plaintext server was refused (`detectMisdirectedTls: reject`).
+    MisdirectedTls,
+    Error(io::ErrorKind),
+}
+
+impl CloseReason {
+    /// One of each reason distinguished by `as_str`.
+    pub fn distinct() -> [CloseReason; 12] {
+        [
+            CloseReason::ClientEof,
+            CloseReason::ServerEof,
+            CloseReason::ClientReset,
+            CloseReason::ServerReset,
+            CloseReason::MaxAge,
+            CloseReason::DispatchTimeout,
+            CloseReason::WriteTimeout,
+            CloseReason::Rebalanced,
+            CloseReason::Shed,
+            CloseReason::HandedOff,
+            CloseReason::MisdirectedTls,
+            CloseReason::Error(io::ErrorKind::Other),
+        ]
+    }
+
+    /// The reason a peer's end of the stream closed.
+    pub fn eof(peer: Peer) -> CloseReason {
+        match peer {
+            Peer::Client => CloseReason::ClientEof,
+            Peer::Server => CloseReason::ServerEof,
+        }
+    }
+
+    /// Classifies an error encountered while reading from or writing to `peer`.
+    pub fn from_error(peer: Peer, e: &io::Error) -> CloseReason {
+        if WriteTimeout::is(e) {
+            return CloseReason::WriteTimeout;
+        }
+        if Misdirected::is(e) {
+            return CloseReason::MisdirectedTls;
+        }
+        match (e.kind(), peer) {
+            (io::ErrorKind::ConnectionReset, Peer::Client) |
+            (io::ErrorKind::ConnectionAborted, Peer::Client) |
+            (io::ErrorKind::BrokenPipe, Peer::Client) => CloseReason::ClientReset,
+            (io::ErrorKind::ConnectionReset, Peer::Server) |
+            (io::ErrorKind::ConnectionAborted, Peer::Server) |
+            (io::ErrorKind::BrokenPipe, Peer::Server) => CloseReason::ServerReset,
+            (kind, _) => CloseReason::Error(kind),
+        }
+    }
+
+    /// The peer that reset the connection, if it was reset.
+    ///
+    /// A reset ends only the direction in which it was encountered. Other errors are
+    /// fatal to the whole connection.
+    pub fn reset_peer(&self) -> Option<Peer> {
+        match *self {
+            CloseReason::ClientReset => Some(Peer::Client),
+            CloseReason::ServerReset => Some(Peer::Server),
+            _ => None,
+        }
+    }
+
+    /// A bounded name for the reason, suitable as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::ServerEof => "server_eof",
+            CloseReason::ClientReset => "client_reset",
+            CloseReason::ServerReset => "server_reset",
+            CloseReason::MaxAge => "max_age",
+            CloseReason::DispatchTimeout => "dispatch_timeout",
+            CloseReason::WriteTimeout => "write_timeout",
+            CloseReason::Rebalanced => "rebalanced",
+            CloseReason::Shed => "shed",
+            CloseReason::HandedOff => "handed_off",
+            CloseReason::MisdirectedTls => "misdirected_tls",
+            CloseReason::Error(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::Error(kind) => write!(f, "error ({:?})", kind),
+            reason => f.write_str(reason.as_str()),
+        }
+    }
+}
+
+/// Indicates that a TLS client of a plaintext server was refused.
+#[derive(Debug)]
+pub struct Misdirected;
+
+impl Misdirected {
+    /// Determines whether an error was caused by refusing a misdirected TLS client.
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().map(|e| e.is::<Misdirected>()).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Misdirected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("refusing TLS stream on plaintext server")
+    }
+}
+
+impl error::Error for Misdirected {
+    fn description(&self) -> &str {
+        "misdirected TLS"
+    }
+}
+
+/// Holds the first close reason observed by either half of a duplex stream, and the
+/// reason that each half finished.
+#[derive(Clone, Default)]
+pub struct CloseReasonCell(Rc<Cell<Reasons>>);
+
+#[derive(Clone, Copy, Default)]
+struct Reasons {
+    first: Option<CloseReason>,
+    client_to_server: Option<CloseReason>,
+    server_to_client: Option<CloseReason>,
+}
+
+impl CloseReasonCell {
+    /// Records `reason` unless a reason has already been recorded.
+    pub fn observe(&self, reason: CloseReason) {
+        let mut r = self.0.get();
+        if r.first.is_none() {
+            r.first = Some(reason);
+            self.0.set(r);
+        }
+    }
+
+    /// Records `reason` as the reason the direction carrying `reader`'s data finished,
+    /// unless it has already finished, and as the connection's reason unless one has
+    /// already been recorded.
+    pub fn observe_direction(&self, reader: Peer, reason: CloseReason) {
+        self.observe(reason);
+        let mut r = self.0.get();
+        {
+            let dir = match reader {
+                Peer::Client => &mut r.client_to_server,
+                Peer::Server => &mut r.server_to_client,
+            };
+            if dir.is_none() {
+                *dir = Some(reason);
+            }
+        }
+        self.0.set(r);
+    }
+
+    pub fn get(&self) -> Option<CloseReason> {
+        self.0.get().first
+    }
+
+    /// Forgets the reasons recorded, e.g. when a stream is retried.
+    pub fn clear(&self) {
+        self.0.set(Reasons::default());
+    }
+
+    /// The reason the direction carrying `reader`'s data finished, if it has.
+    pub fn direction(&self, reader: Peer) -> Option<CloseReason> {
+        let r = self.0.get();
+        match reader {
+            Peer::Client => r.client_to_server,
+            Peer::Server => r.server_to_client,
+        }
+    }
+}
@@ -1,38 +1,137 @@
-use super::Connection;
+use super::{Buffers, Connection};
+use super::super::handoff::{self, Handoff, Refusal};
 use super::Ctx;
-use super::half_duplex::{self, HalfDuplex};
+use super::close::{CloseReason, CloseReasonCell, Peer};
+use super::eviction::Eviction;
+use super::half_duplex::{self, HalfDuplex, WriteCoalescing};
+use super::integrity::IntegrityCheck;
+use super::tcp_info::Sampler;
+use super::tee::Tee;
 use futures::{Async, Future, Poll};
+use std::{error, fmt, io};
 use std::cell::RefCell;
-use std::io;
-use std::net;
+use std::net::{self, Shutdown};
 use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
 
 pub struct Summary {
     pub to_dst_bytes: usize,
     pub to_src_bytes: usize,
 }
 
-pub fn new<S, D>(src: Connection<S>, dst: Connection<D>, buf: Rc<RefCell<Vec<u8>>>) -> Duplex<S, D>
+pub fn new<S, D>(
+    src: Connection<S>,
+    dst: Connection<D>,
+    bufs: Buffers,
+    write_timeout: Option<Duration>,
+    integrity: Option<&IntegrityCheck>,
+    eviction: Option<Eviction>,
+    timer: &Timer,
+    coalescing: Option<(WriteCoalescing, Handle)>,
+    mirror: Option<Tee>,
+    sampler: Option<Sampler>,
+) -> Duplex<S, D>
 where
     S: Ctx,
     D: Ctx,
 {
-    let src_addr = src.peer_addr();
+    resume(
+        Rc::new(RefCell::new(src)),
+        dst,
+        bufs,
+        write_timeout,
+        integrity,
+        eviction,
+        timer,
+        coalescing,
+        mirror,
+        sampler,
+        CloseReasonCell::default(),
+        false,
+    )
+}
+
+/// Like `new`, transferring data from a client connection that may already have been
+/// proxied to another endpoint, and recording the stream's close reason in `close`.
+///
+/// If `retryable` is set, and the endpoint resets the stream before any bytes have been
+/// written to either peer, the stream fails with a `Retryable` error, without either
+/// connection being shut down, so long as the client's socket has retained every byte
+/// read from it. Once any bytes are written, the client's socket stops retaining them.
+pub fn resume<S, D>(
+    src: Rc<RefCell<Connection<S>>>,
+    dst: Connection<D>,
+    bufs: Buffers,
+    write_timeout: Option<Duration>,
+    integrity: Option<&IntegrityCheck>,
+    eviction: Option<Eviction>,
+    timer: &Timer,
+    coalescing: Option<(WriteCoalescing, Handle)>,
+    mirror: Option<Tee>,
+    sampler: Option<Sampler>,
+    close: CloseReasonCell,
+    retryable: bool,
+) -> Duplex<S, D>
+where
+    S: Ctx,
+    D: Ctx,
+{
+    let src_addr = src.borrow().peer_addr();
     let dst_addr = dst.peer_addr();
-    let src = Rc::new(RefCell::new(src));
     let dst = Rc::new(RefCell::new(dst));
     Duplex {
         dst_addr,
-        to_dst: Some(half_duplex::new(src.clone(), dst.clone(), buf.clone())),
+        to_dst: Some(half_duplex::new(
+            src.clone(),
+            dst.clone(),
+            bufs.client_to_server,
+            bufs.budget.clone(),
+            write_timeout,
+            integrity.map(|i| i.checksums(src_addr, dst_addr, Peer::Client)),
+            timer.clone(),
+            Peer::Client,
+            close.clone(),
+            coalescing.clone(),
+            mirror,
+        )),
         to_dst_bytes: 0,
 
         src_addr,
-        to_src: Some(half_duplex::new(dst.clone(), src.clone(), buf)),
+        to_src: Some(half_duplex::new(
+            dst.clone(),
+            src.clone(),
+            bufs.server_to_client,
+            bufs.budget,
+            write_timeout,
+            integrity.map(|i| i.checksums(dst_addr, src_addr, Peer::Server)),
+            timer.clone(),
+            Peer::Server,
+            close.clone(),
+            coalescing,
+            None,
+        )),
         to_src_bytes: 0,
+        src,
+        dst,
+        error: None,
+        close,
+        eviction,
+        sampler,
+        retryable,
+        handoff: None,
+        sending: None,
     }
 }
 
 /// Joins src and dst transfers into a single Future.
+///
+/// Each direction is transferred independently. When a peer resets the connection, only
+/// the direction in which the reset was encountered ends: if its reader was reset, its
+/// writer is shut down, as if the reader had closed; if its writer was reset, its reader
+/// is shut down. The other direction continues until it finishes in turn. Other errors
+/// (e.g. write timeouts) are fatal, and tear down both directions immediately.
 pub struct Duplex<S, D> {
     dst_addr: net::SocketAddr,
     src_addr: net::SocketAddr,
@@ -40,20 +139,266 @@ pub struct Duplex<S, D> {
     to_src: Option<HalfDuplex<D, S>>,
     to_dst_bytes: usize,
     to_src_bytes: usize,
+    src: Rc<RefCell<Connection<S>>>,
+    dst: Rc<RefCell<Connection<D>>>,
+    /// The first error that ended a direction, returned once both have finished.
+    error: Option<io::Error>,
+    close: CloseReasonCell,
+    /// Set until the connection is evicted.
+    eviction: Option<Eviction>,
+    sampler: Option<Sampler>,
+    /// Set until any bytes are written, while the stream may be retried.
+    retryable: bool,
+    /// Set when the stream may be handed off to another process, until it is asked to be.
+    handoff: Option<handoff::Request>,
+    /// Set while the stream is being handed off.
+    sending: Option<handoff::Sending>,
+}
+
+impl<S, D> Duplex<S, D> {
+    /// Holds the reason the stream is torn down, once it is known.
+    ///
+    /// The reason remains accessible after the duplex is consumed.
+    pub fn close_reason(&self) -> CloseReasonCell {
+        self.close.clone()
+    }
+
+    /// Takes the stream's sampler, e.g. to sample a retried stream, so that the sockets
+    /// are not sampled as the stream is torn down.
+    pub fn take_sampler(&mut self) -> Option<Sampler> {
+        self.sampler.take()
+    }
+
+    /// Allows the stream to be handed off to another process once `request` asks.
+    pub fn hand_off_with(&mut self, request: handoff::Request) {
+        self.handoff = Some(request);
+    }
+}
+
+impl<S: Ctx, D: Ctx> Duplex<S, D> {
+    /// Determines whether the stream may be retried after the direction carrying
+    /// `reader`'s data failed, having written `written` bytes: the endpoint must have
+    /// reset the stream before any bytes were written in either direction, and every byte
+    /// read from the client must have been retained.
+    fn may_retry(&self, reader: Peer, written: usize) -> bool {
+        if !self.retryable || written > 0 {
+            return false;
+        }
+        let other_written = match reader {
+            Peer::Client => self.to_src.as_ref().map(|h| h.bytes_total()),
+            Peer::Server => self.to_dst.as_ref().map(|h| h.bytes_total()),
+        };
+        // If the other direction has finished, a peer has already been shut down.
+        other_written == Some(0) &&
+            self.close.direction(reader).and_then(|r| r.reset_peer()) == Some(Peer::Server) &&
+            self.src.borrow().socket.is_retaining()
+    }
+
+    /// Stops retaining the client's bytes once any bytes have been written, since the
+    /// stream may no longer be retried.
+    fn poll_retryable(&mut self) {
+        if !self.retryable {
+            return;
+        }
+        let written = self.to_dst.as_ref().map_or(0, |h| h.bytes_total()) +
+            self.to_src.as_ref().map_or(0, |h| h.bytes_total());
+        if written > 0 {
+            self.retryable = false;
+            self.src.borrow_mut().socket.release();
+        }
+    }
+
+    /// Hands the stream off once its request asks, becoming ready with true once it has
+    /// been sent. The stream is released between reads and writes, so the bytes each
+    /// direction has read but not yet written are sent with it, and it is neither read
+    /// nor written until the handoff completes. Otherwise, the stream continues.
+    fn poll_handoff(&mut self) -> Async<bool> {
+        if self.sending.is_none() {
+            let requested = self.handoff.as_ref().map(|r| r.poll_requested()).unwrap_or(false);
+            if !requested {
+                return Async::Ready(false);
+            }
+            let request = self.handoff.take().unwrap();
+            let handoff = match self.prepare_handoff() {
+                Ok(handoff) => handoff,
+                Err(Ok(why)) => {
+                    request.refuse(why);
+                    return Async::Ready(false);
+                }
+                Err(Err(e)) => {
+                    request.fail(&e);
+                    return Async::Ready(false);
+                }
+            };
+            self.sending = Some(request.send(handoff));
+        }
+        let sent = self.sending.as_mut().unwrap().poll();
+        match sent {
+            Ok(Async::NotReady) => Async::NotReady,
+            Ok(Async::Ready(())) => {
+                self.sending = None;
+                Async::Ready(true)
+            }
+            Err(_) => {
+                // The receiver only resumes streams that were committed, so this one
+                // continues here.
+                self.sending = None;
+                Async::Ready(false)
+            }
+        }
+    }
+
+    /// Duplicates the stream's sockets and captures its state, without changing it.
+    fn prepare_handoff(&self) -> Result<Handoff, io::Result<Refusal>> {
+        let (to_dst, to_src) = match (self.to_dst.as_ref(), self.to_src.as_ref()) {
+            (Some(to_dst), Some(to_src)) => (to_dst, to_src),
+            _ => return Err(Ok(Refusal::Closing)),
+        };
+        let (to_endpoint, to_client) = match (to_dst.unwritten(), to_src.unwritten()) {
+            (Some(to_endpoint), Some(to_client)) => (to_endpoint, to_client),
+            _ => return Err(Ok(Refusal::Closing)),
+        };
+        let src = self.src.borrow();
+        let dst = self.dst.borrow();
+        let (client, endpoint) = match (src.socket.dup_plain(), dst.socket.dup_plain()) {
+            (Some(client), Some(endpoint)) => (client, endpoint),
+            _ => return Err(Ok(Refusal::Tls)),
+        };
+        let client = client.map_err(Err::<Refusal, io::Error>)?;
+        let endpoint = endpoint.map_err(Err::<Refusal, io::Error>)?;
+
+        // Bytes that the peers' sockets hold to be read again have not yet been written.
+        let mut to_endpoint = to_endpoint.to_vec();
+        to_endpoint.extend_from_slice(src.socket.replayed());
+        let mut to_client = to_client.to_vec();
+        to_client.extend_from_slice(dst.socket.replayed());
+        Ok(Handoff {
+            state: handoff::State {
+                dst_name: String::new(),
+                client_addr: self.src_addr,
+                endpoint_addr: self.dst_addr,
+                to_endpoint_bytes: to_dst.bytes_total() as u64,
+                to_client_bytes: to_src.bytes_total() as u64,
+                to_endpoint,
+                to_client,
+            },
+            client,
+            endpoint,
+        })
+    }
+
+    /// Ends the direction carrying `reader`'s data after it failed with `e`, propagating
+    /// its end to the peers, unless the error is fatal to the whole connection.
+    fn direction_failed(&mut self, reader: Peer, e: io::Error) -> io::Result<()> {
+        let reset = self.close.direction(reader).and_then(|r| r.reset_peer());
+        let reset = match reset {
+            Some(peer) => peer,
+            None => return Err(e),
+        };
+        debug!(
+            "{} from {} to {} ended: {}",
+            reader.direction(),
+            self.src_addr,
+            self.dst_addr,
+            e
+        );
+        match (reader, reset == reader) {
+            (Peer::Client, true) => half_close(&self.dst, Shutdown::Write),
+            (Peer::Client, false) => half_close(&self.src, Shutdown::Read),
+            (Peer::Server, true) => half_close(&self.src, Shutdown::Write),
+            (Peer::Server, false) => half_close(&self.dst, Shutdown::Read),
+        }
+        if self.error.is_none() {
+            self.error = Some(e);
+        }
+        Ok(())
+    }
+}
+
+/// Shuts down one side of a peer's connection once a direction has ended. The peer may
+/// already have closed the connection, so errors are ignored.
+fn half_close<C: Ctx>(conn: &Rc<RefCell<Connection<C>>>, how: Shutdown) {
+    if let Err(e) = conn.borrow_mut().socket.tcp_shutdown(how) {
+        trace!("failed to shut down {:?}: {}", how, e);
+    }
+}
+
+impl<S, D> Duplex<S, D> {
+    fn sample(&self, closing: bool) {
+        if let Some(ref sampler) = self.sampler {
+            sampler.sample(Peer::Client, &self.src.borrow().socket, closing);
+            sampler.sample(Peer::Server, &self.dst.borrow().socket, closing);
+        }
+    }
+}
+
+/// Both sockets are sampled once more as the stream is torn down, however it ends.
+impl<S, D> Drop for Duplex<S, D> {
+    fn drop(&mut self) {
+        self.sample(true);
+    }
 }
 
 impl<S: Ctx, D: Ctx> Future for Duplex<S, D> {
     type Item = Summary;
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Summary, io::Error> {
+        let due = self.sampler.as_mut().map(|s| s.poll_due()).unwrap_or(false);
+        if due {
+            self.sample(false);
+        }
+
+        // A stream that is handed off ends in this process without shutting down its
+        // sockets, which remain open in the other. Until the handoff completes, the
+        // stream is not evicted, read, or written.
+        match self.poll_handoff() {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(false) => {}
+            Async::Ready(true) => {
+                debug!("handed off {} to {}", self.src_addr, self.dst_addr);
+                self.close.observe(CloseReason::HandedOff);
+                let to_dst_bytes = self.to_dst.as_ref().map_or(0, |h| h.bytes_total());
+                let to_src_bytes = self.to_src.as_ref().map_or(0, |h| h.bytes_total());
+                self.to_dst = None;
+                self.to_src = None;
+                return Ok(Async::Ready(Summary {
+                    to_dst_bytes,
+                    to_src_bytes,
+                }));
+            }
+        }
+
+        let evicted = self.eviction.as_ref().and_then(|e| e.poll_evicted());
+        if let Some(graceful) = evicted {
+            debug!("evicting {} to {}", self.src_addr, self.dst_addr);
+            self.eviction = None;
+            self.close.observe(CloseReason::Rebalanced);
+            if !graceful {
+                return Err(io::Error::new(io::ErrorKind::Other, "connection evicted"));
+            }
+            if let Some(ref mut to_dst) = self.to_dst {
+                to_dst.drain();
+            }
+            if let Some(ref mut to_src) = self.to_src {
+                to_src.drain();
+            }
+        }
+
         if let Some(mut to_dst) = self.to_dst.take() {
             trace!(
                 "polling dstward from {} to {}",
                 self.src_addr,
                 self.dst_addr
             );
-            match to_dst.poll()? {
-                Async::Ready(sz) => {
+            match to_dst.poll() {
+                Err(e) => {
+                    if self.may_retry(Peer::Client, to_dst.bytes_total()) {
+                        return Err(Retryable::error(e));
+                    }
+                    self.to_dst_bytes = to_dst.bytes_total();
+                    self.direction_failed(Peer::Client, e)?;
+                }
+                Ok(Async::Ready(sz)) => {
                     trace!(
                         "dstward complete from {} to {}",
                         self.src_addr,
@@ -61,7 +406,7 @@ impl<S: Ctx, D: Ctx> Future for Duplex<S, D> {
                     );
                     self.to_dst_bytes = sz;
                 }
-                Async::NotReady => {
+                Ok(Async::NotReady) => {
                     trace!("dstward not ready");
                     self.to_dst = Some(to_dst);
                 }
@@ -74,8 +419,15 @@ impl<S: Ctx, D: Ctx> Future for Duplex<S, D> {
                 self.dst_addr,
                 self.src_addr
             );
-            match to_src.poll()? {
-                Async::Ready(sz) => {
+            match to_src.poll() {
+                Err(e) => {
+                    if self.may_retry(Peer::Server, to_src.bytes_total()) {
+                        return Err(Retryable::error(e));
+                    }
+                    self.to_src_bytes = to_src.bytes_total();
+                    self.direction_failed(Peer::Server, e)?;
+                }
+                Ok(Async::Ready(sz)) => {
                     trace!(
                         "srcward complete from {} to {}",
                         self.dst_addr,
@@ -83,15 +435,20 @@ impl<S: Ctx, D: Ctx> Future for Duplex<S, D> {
                     );
                     self.to_src_bytes = sz;
                 }
-                Async::NotReady => {
+                Ok(Async::NotReady) => {
                     trace!("srcward not ready");
                     self.to_src = Some(to_src);
                 }
             }
         }
 
+        self.poll_retryable();
+
         if self.to_dst.is_none() && self.to_src.is_none() {
             trace!("complete");
+            if let Some(e) = self.error.take() {
+                return Err(e);
+            }
             // self.tx_bytes_stat.add(self.tx_bytes);
             // self.rx_bytes_stat.add(self.rx_bytes)
             let summary = Summary {
@@ -105,3 +462,31 @@ impl<S: Ctx, D: Ctx> Future for Duplex<S, D> {
         }
     }
 }
+
+/// Indicates that an endpoint reset a stream before any bytes were written, so that the
+/// stream may be retried against another endpoint.
+#[derive(Debug)]
+pub struct Retryable(io::Error);
+
+impl Retryable {
+    fn error(e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), Retryable(e))
+    }
+
+    /// Determines whether a stream failed such that it may be retried.
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().map(|e| e.is::<Retryable>()).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Retryable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "reset before any bytes were forwarded: {}", self.0)
+    }
+}
+
+impl error::Error for Retryable {
+    fn description(&self) -> &str {
+        "reset before any bytes were forwarded"
+    }
+}
@@ -0,0 +1,51 @@
+//! Allows a balancer to close connections that it has already dispatched.
+//!
+//! Each upstream connection carries an `Eviction`, shared by the balancer and the stream
+//! that proxies it. When the balancer evicts the connection, the stream's task is
+//! notified, and the stream is either torn down immediately or drained: data that has
+//! already been read is written, and then both peers' writes are shut down.
+
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Clone, Default)]
+pub struct Eviction(Rc<RefCell<Inner>>);
+
+#[derive(Default)]
+struct Inner {
+    /// Set once the connection is evicted, indicating whether it should be drained.
+    graceful: Option<bool>,
+    /// The task proxying the connection, once it has been polled.
+    task: Option<Task>,
+}
+
+impl Eviction {
+    /// Requests that the connection be closed, draining it first if `graceful`.
+    ///
+    /// Connections that have already been evicted are unaffected.
+    pub fn evict(&self, graceful: bool) {
+        let mut inner = self.0.borrow_mut();
+        if inner.graceful.is_some() {
+            return;
+        }
+        inner.graceful = Some(graceful);
+        if let Some(task) = inner.task.take() {
+            task.notify();
+        }
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.0.borrow().graceful.is_some()
+    }
+
+    /// Indicates whether the connection has been evicted and, if so, whether it should
+    /// be drained. Otherwise, the current task is notified when it is evicted.
+    pub fn poll_evicted(&self) -> Option<bool> {
+        let mut inner = self.0.borrow_mut();
+        if inner.graceful.is_none() {
+            inner.task = Some(task::current());
+        }
+        inner.graceful
+    }
+}
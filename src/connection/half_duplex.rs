@@ -1,17 +1,33 @@
 use super::Connection;
 use super::Ctx;
+use super::budget::{BufferBudget, Grant};
+use super::close::{CloseReason, CloseReasonCell, Peer};
+use super::integrity::Checksums;
+use super::tee::Tee;
 use futures::{Async, Future, Poll};
+use std::{cmp, error, fmt};
 use std::cell::RefCell;
 use std::io::{self, Read, Write};
 use std::net::Shutdown;
 use std::rc::Rc;
+use std::time::Duration;
 //use tacho;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::AsyncWrite;
+use tokio_timer::{Sleep, Timer};
 
 pub fn new<R, W>(
     reader: Rc<RefCell<Connection<R>>>,
     writer: Rc<RefCell<Connection<W>>>,
     buf: Rc<RefCell<Vec<u8>>>,
+    budget: BufferBudget,
+    write_timeout: Option<Duration>,
+    checksums: Option<Checksums>,
+    timer: Timer,
+    reader_peer: Peer,
+    close: CloseReasonCell,
+    coalescing: Option<(WriteCoalescing, Handle)>,
+    tee: Option<Tee>,
 ) -> HalfDuplex<R, W>
 where
     R: Ctx,
@@ -20,10 +36,23 @@ where
     HalfDuplex {
         reader,
         writer,
+        reader_peer,
+        close,
         buf,
+        budget,
         pending: None,
+        pending_grant: None,
         bytes_total: 0,
         should_shutdown: false,
+        draining: false,
+        write_timeout,
+        write_deadline: None,
+        checksums,
+        timer,
+        coalescing,
+        holding: false,
+        coalesce_deadline: None,
+        tee,
         // bytes_total_count: metrics.counter("bytes_total".into()),
         // allocs_count: metrics.counter("allocs_count".into()),
     }
@@ -37,23 +66,152 @@ where
 pub struct HalfDuplex<R, W> {
     reader: Rc<RefCell<Connection<R>>>,
     writer: Rc<RefCell<Connection<W>>>,
+    // Identifies the peer from which data is read; data is written to the other peer.
+    reader_peer: Peer,
+
+    // Records the first event to tear down the stream, and how this half finished.
+    close: CloseReasonCell,
 
     // Holds transient data when copying between the reader and writer.
     buf: Rc<RefCell<Vec<u8>>>,
 
+    // Limits the data held in `pending` buffers by all streams.
+    budget: BufferBudget,
+
     // Holds data that can't be fully written.
     pending: Option<Vec<u8>>,
 
+    // Holds `pending`'s share of the budget, returned once it has been written or the
+    // stream is dropped.
+    pending_grant: Option<Grant>,
+
     // The number of bytes we've written so far.
     bytes_total: usize,
 
     // Indicates that that the reader has returned 0 and the writer should be shut down.
     should_shutdown: bool,
 
+    // Indicates that no more data should be read, and that the writer should be shut
+    // down once pending data has been written.
+    draining: bool,
+
+    // Limits how long the writer may go without making progress.
+    write_timeout: Option<Duration>,
+
+    // Set while the writer is blocked. Reset whenever any bytes are written.
+    write_deadline: Option<Sleep>,
+
+    // When the stream's integrity is checked, tracks the bytes read and written.
+    checksums: Option<Checksums>,
+
+    timer: Timer,
+
+    // When set, small writes are held briefly so that they may be written together.
+    coalescing: Option<(WriteCoalescing, Handle)>,
+
+    // Indicates that `pending` holds data that is deliberately not yet written.
+    holding: bool,
+
+    // Set while data is held; the held data is written once it fires.
+    coalesce_deadline: Option<Timeout>,
+
+    // When set, the data read is also copied to a mirror. The copy is finished when
+    // this half is dropped.
+    tee: Option<Tee>,
+
     // bytes_total_count: tacho::Counter,
     // allocs_count: tacho::Counter,
 }
 
+impl<R, W> HalfDuplex<R, W> {
+    /// Stops reading, so that the writer is shut down once pending data is written.
+    pub fn drain(&mut self) {
+        self.draining = true;
+    }
+
+    /// The number of bytes written so far.
+    pub fn bytes_total(&self) -> usize {
+        self.bytes_total
+    }
+
+    /// The data read but not yet written, including any data held to be coalesced, or
+    /// `None` once the writer is being shut down.
+    pub fn unwritten(&self) -> Option<&[u8]> {
+        if self.should_shutdown || self.draining {
+            return None;
+        }
+        Some(self.pending.as_ref().map(|p| p.as_slice()).unwrap_or(&[]))
+    }
+
+    /// Adds `bytes` to the data held for coalescing.
+    fn hold(&mut self, bytes: &[u8]) {
+        let mut held = self.pending.take().unwrap_or_else(Vec::new);
+        held.extend_from_slice(bytes);
+        self.pending_grant = Some(self.budget.grant(held.len()));
+        self.pending = Some(held);
+        self.holding = true;
+    }
+
+    /// Stops holding data, so that it is written.
+    fn release_held(&mut self) {
+        self.holding = false;
+        self.coalesce_deadline = None;
+    }
+
+    /// Determines whether held data has been held for long enough, arming the deadline
+    /// when data is first held.
+    fn poll_coalesce_deadline(&mut self) -> io::Result<bool> {
+        if self.coalesce_deadline.is_none() {
+            let deadline = match self.coalescing {
+                None => return Ok(true),
+                Some((ref policy, ref reactor)) => Timeout::new(policy.max_delay, reactor)?,
+            };
+            self.coalesce_deadline = Some(deadline);
+        }
+        let deadline = self.coalesce_deadline.as_mut().unwrap();
+        Ok(deadline.poll()?.is_ready())
+    }
+}
+
+impl<R, W: Ctx> HalfDuplex<R, W> {
+    /// Writes pending data until the write would block.
+    fn write_pending(&mut self, writer: &mut Connection<W>) -> Poll<(), io::Error> {
+        if let Some(mut pending) = self.pending.take() {
+            trace!("writing {} pending bytes", pending.len());
+            let mut progressed = false;
+            while !pending.is_empty() {
+                match writer.socket.write(&pending) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.pending = Some(pending);
+                        poll_write_deadline(
+                            &mut self.write_deadline,
+                            self.write_timeout,
+                            &self.timer,
+                            progressed,
+                        ).map_err(|e| write_failed(&self.close, self.reader_peer, e))?;
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(write_failed(&self.close, self.reader_peer, e)),
+                    Ok(wsz) => {
+                        if let Some(ref mut c) = self.checksums {
+                            c.wrote(&pending[..wsz]);
+                        }
+                        // Drop the portion of the buffer that we've already written.
+                        // There may or may not be more pending data remaining.
+                        pending.drain(0..wsz);
+                        self.bytes_total += wsz;
+                        writer.ctx.wrote(wsz);
+                        progressed = progressed || wsz > 0;
+                    }
+                }
+            }
+            self.pending_grant = None;
+            self.write_deadline = None;
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
 impl<R, W> Future for HalfDuplex<R, W>
 where
     R: Ctx,
@@ -66,39 +224,33 @@ where
     ///
     /// If all data cannot be written, the unwritten data is stored in a newly-allocated
     /// buffer. This pending data is flushed before any more data is read.
+    ///
+    /// When writes are coalesced, data that is read is held in the pending buffer, and
+    /// reading continues, until the coalescing delay elapses or enough data is held.
     fn poll(&mut self) -> Poll<usize, io::Error> {
         trace!("poll");
-        let mut writer = self.writer.borrow_mut();
-        let mut reader = self.reader.borrow_mut();
+        // The connections and buffer are borrowed through their own handles, so that
+        // `self` may be borrowed mutably while they are held.
+        let (writer, reader, buf) = (self.writer.clone(), self.reader.clone(), self.buf.clone());
+        let mut writer = writer.borrow_mut();
+        let mut reader = reader.borrow_mut();
 
         // Because writer.socket.shutdown may return WouldBlock, we may already be
         // shutting down and need to resume graceful shutdown.
         if self.should_shutdown {
-            try_nb!(writer.socket.shutdown());
-            writer.socket.tcp_shutdown(Shutdown::Write)?;
-            return Ok(Async::Ready(self.bytes_total));
+            return shutdown(&mut writer, &self.close, self.reader_peer, self.bytes_total);
+        }
+
+        // Held data is written once it has been held for long enough, or once no more
+        // data will be read.
+        if self.holding && (self.draining || self.poll_coalesce_deadline()?) {
+            self.release_held();
         }
 
         // If we've read more than we were able to write previously, then write all of it
         // until the write would block.
-        if let Some(mut pending) = self.pending.take() {
-            trace!("writing {} pending bytes", pending.len());
-            while !pending.is_empty() {
-                match writer.socket.write(&pending) {
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        self.pending = Some(pending);
-                        return Ok(Async::NotReady);
-                    }
-                    Err(e) => return Err(e),
-                    Ok(wsz) => {
-                        // Drop the portion of the buffer that we've already written.
-                        // There may or may not be more pending data remaining.
-                        pending.drain(0..wsz);
-                        self.bytes_total += wsz;
-                        writer.ctx.wrote(wsz);
-                    }
-                }
-            }
+        if !self.holding {
+            try_ready!(self.write_pending(&mut writer));
         }
 
         // Read and write data until one of the endpoints is not ready. All data is read
@@ -106,29 +258,89 @@ where
         // data cannot be written, it is copied into a newly-allocated local buffer to be
         // flushed later.
         loop {
-            assert!(self.pending.is_none());
+            assert!(self.pending.is_none() || self.holding);
+
+            if self.draining {
+                self.should_shutdown = true;
+                return shutdown(&mut writer, &self.close, self.reader_peer, self.bytes_total);
+            }
 
-            let mut rbuf = self.buf.borrow_mut();
-            let rsz = try_nb!(reader.socket.read(&mut rbuf));
+            // Only as many bytes are read as could be held if the writer blocks.
+            let limit = match self.budget.poll_available() {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(n) => n,
+            };
+            let mut rbuf = buf.borrow_mut();
+            let limit = cmp::min(limit, rbuf.len());
+            let rsz = match reader.socket.read(&mut rbuf[..limit]) {
+                Ok(sz) => sz,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.holding && self.poll_coalesce_deadline()? {
+                        self.release_held();
+                        try_ready!(self.write_pending(&mut writer));
+                        continue;
+                    }
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(read_failed(&self.close, self.reader_peer, e)),
+            };
             reader.ctx.read(rsz);
+            if let Some(ref mut c) = self.checksums {
+                if rsz == 0 {
+                    c.verify();
+                } else {
+                    c.read(&mut rbuf[..rsz]);
+                }
+            }
+            if let Some(ref tee) = self.tee {
+                tee.copy(&rbuf[..rsz]);
+            }
             if rsz == 0 {
+                self.close.observe_direction(self.reader_peer, CloseReason::eof(self.reader_peer));
+                // Held data is written before the writer is shut down.
+                if self.holding {
+                    self.release_held();
+                    self.draining = true;
+                    try_ready!(self.write_pending(&mut writer));
+                }
                 self.should_shutdown = true;
-                try_nb!(writer.socket.shutdown());
-                writer.socket.tcp_shutdown(Shutdown::Write)?;
-                return Ok(Async::Ready(self.bytes_total));
+                return shutdown(&mut writer, &self.close, self.reader_peer, self.bytes_total);
+            }
+
+            // Small reads are held, to be written together. Once enough data is held, it
+            // is written immediately, along with any held data.
+            if let Some((policy, _)) = self.coalescing {
+                let held = self.pending.as_ref().map(|p| p.len()).unwrap_or(0);
+                self.hold(&rbuf[..rsz]);
+                if held + rsz < policy.max_bytes {
+                    continue;
+                }
+                self.release_held();
+                try_ready!(self.write_pending(&mut writer));
+                continue;
             }
 
             let mut wbuf = &rbuf[..rsz];
             while !wbuf.is_empty() {
                 match writer.socket.write(wbuf) {
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        let progressed = wbuf.len() < rsz;
                         let mut p = vec![0; wbuf.len()];
                         p.copy_from_slice(wbuf);
+                        self.pending_grant = Some(self.budget.grant(p.len()));
                         self.pending = Some(p);
-                        return Ok(Async::NotReady);
+                        return poll_write_deadline(
+                            &mut self.write_deadline,
+                            self.write_timeout,
+                            &self.timer,
+                            progressed,
+                        ).map_err(|e| write_failed(&self.close, self.reader_peer, e));
                     }
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(write_failed(&self.close, self.reader_peer, e)),
                     Ok(wsz) => {
+                        if let Some(ref mut c) = self.checksums {
+                            c.wrote(&wbuf[..wsz]);
+                        }
                         self.bytes_total += wsz;
                         writer.ctx.wrote(wsz);
                         wbuf = &wbuf[wsz..];
@@ -138,3 +350,192 @@ where
         }
     }
 }
+
+/// Shuts down the writer once the reader has closed, resuming if shutdown would block.
+fn shutdown<W: Ctx>(
+    writer: &mut Connection<W>,
+    close: &CloseReasonCell,
+    reader: Peer,
+    bytes_total: usize,
+) -> Poll<usize, io::Error> {
+    // A TLS close_notify may not be fully flushed before the TCP stream is shut down.
+    match writer.socket.shutdown() {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+        Err(e) => return Err(write_failed(close, reader, e)),
+    }
+    match writer.socket.tcp_shutdown(Shutdown::Write) {
+        Ok(()) => Ok(Async::Ready(bytes_total)),
+        Err(e) => Err(write_failed(close, reader, e)),
+    }
+}
+
+/// Records the close reason for an error reading from `reader`'s socket, which ends the
+/// direction carrying `reader`'s data.
+fn read_failed(close: &CloseReasonCell, reader: Peer, e: io::Error) -> io::Error {
+    close.observe_direction(reader, CloseReason::from_error(reader, &e));
+    e
+}
+
+/// Records the close reason for an error writing `reader`'s data to the other peer's
+/// socket, which ends the direction carrying `reader`'s data.
+fn write_failed(close: &CloseReasonCell, reader: Peer, e: io::Error) -> io::Error {
+    close.observe_direction(reader, CloseReason::from_error(reader.other(), &e));
+    e
+}
+
+/// Arms the write deadline when a write blocks, re-arming it if any bytes have been
+/// written since it was armed, so that only intervals without progress are limited.
+fn poll_write_deadline(
+    deadline: &mut Option<Sleep>,
+    timeout: Option<Duration>,
+    timer: &Timer,
+    progressed: bool,
+) -> Poll<usize, io::Error> {
+    let timeout = match timeout {
+        None => return Ok(Async::NotReady),
+        Some(t) => t,
+    };
+    if progressed || deadline.is_none() {
+        *deadline = Some(timer.sleep(timeout));
+    }
+    match deadline.as_mut().unwrap().poll() {
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Ok(Async::Ready(())) => {
+            let e = WriteTimeout(timeout);
+            Err(io::Error::new(io::ErrorKind::TimedOut, e))
+        }
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+    }
+}
+
+/// Holds small writes for up to `max_delay`, or until `max_bytes` have accumulated, so that
+/// they are written to the peer together. The delay is rounded up to the reactor's timer
+/// resolution.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteCoalescing {
+    /// How long data may be held before it is written.
+    pub max_delay: Duration,
+    /// Data is written as soon as this much is held.
+    pub max_bytes: usize,
+}
+
+/// Indicates that a writer made no progress within the write timeout.
+#[derive(Debug)]
+pub struct WriteTimeout(Duration);
+
+impl WriteTimeout {
+    /// Determines whether an error was caused by a write timeout.
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().map(|e| e.is::<WriteTimeout>()).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for WriteTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no write progress in {}s", self.0.as_secs())
+    }
+}
+
+impl error::Error for WriteTimeout {
+    fn description(&self) -> &str {
+        "write timeout"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WriteTimeout, poll_write_deadline};
+    use futures::{Async, Poll, Stream, future};
+    use std::io;
+    use std::time::{Duration, Instant};
+    use tokio_core::reactor::Core;
+    use tokio_timer::{self, Sleep, Timer};
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn timer() -> Timer {
+        tokio_timer::wheel().tick_duration(ms(1)).build()
+    }
+
+    /// The deadline by which a blocked write to a stream's peer must make progress.
+    struct WriteDeadline {
+        deadline: Option<Sleep>,
+        timeout: Duration,
+        timer: Timer,
+    }
+
+    impl WriteDeadline {
+        fn new(timeout: Duration, timer: &Timer) -> WriteDeadline {
+            WriteDeadline {
+                deadline: None,
+                timeout,
+                timer: timer.clone(),
+            }
+        }
+
+        /// Polls the deadline as a blocked write does, noting whether any bytes were written
+        /// since it was last polled. Fails once the timeout passes without progress.
+        fn poll(&mut self, progressed: bool) -> Poll<usize, io::Error> {
+            poll_write_deadline(&mut self.deadline, Some(self.timeout), &self.timer, progressed)
+        }
+    }
+
+    /// Polls `deadline` as a blocked write would, where the peer reads some of the stream
+    /// every `interval`, `reads` times, and then stops reading.
+    fn write_to_peer(
+        deadline: &mut WriteDeadline,
+        interval: Duration,
+        reads: u64,
+        timer: &Timer,
+    ) -> Poll<(), io::Error> {
+        let mut reads = timer.interval(interval).take(reads);
+        let mut core = Core::new().unwrap();
+        let writing = future::poll_fn(move || -> Poll<(), io::Error> {
+            let mut progressed = false;
+            loop {
+                match reads.poll() {
+                    Ok(Async::Ready(Some(()))) => progressed = true,
+                    Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                    Ok(Async::NotReady) => break,
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                }
+            }
+            deadline.poll(progressed)?;
+            Ok(Async::NotReady)
+        });
+        core.run(writing).map(Async::Ready)
+    }
+
+    #[test]
+    fn does_not_time_out_peers_that_read_slowly() {
+        let timer = timer();
+        let mut deadline = WriteDeadline::new(ms(100), &timer);
+
+        // The peer reads less often than the stream could write, but well within the
+        // timeout, for several times the timeout.
+        let start = Instant::now();
+        let written = write_to_peer(&mut deadline, ms(40), 15, &timer);
+        assert!(written.is_ok(), "timed out a progressing peer");
+        assert!(start.elapsed() >= ms(500), "finished in {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn times_out_peers_that_stop_reading() {
+        let timer = timer();
+        let mut deadline = WriteDeadline::new(ms(100), &timer);
+
+        // The peer reads a few times, over about 200ms, and then stalls. The timeout is
+        // measured from its last read, rather than from when the write first blocked.
+        let start = Instant::now();
+        let e = write_to_peer(&mut deadline, ms(40), 5, &timer)
+            .and_then(|_| write_to_peer(&mut deadline, ms(1_000), 1, &timer))
+            .expect_err("did not time out a stalled peer");
+        assert!(WriteTimeout::is(&e), "unexpected error: {}", e);
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= ms(200) && elapsed < ms(1_000), "timed out after {:?}", elapsed);
+    }
+}
@@ -0,0 +1,143 @@
+//! Verifies that streams are proxied without modification.
+//!
+//! Each half of a checked stream computes a CRC-32 of the bytes it reads and of the
+//! bytes it writes. Once the reader closes, every byte read has been written, so the
+//! checksums must match.
+
+use super::close::Peer;
+use std::net;
+use std::rc::Rc;
+use tacho;
+
+/// The IEEE 802.3 polynomial, reversed.
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// Configures the integrity checks of a server's streams.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Policy {
+    /// Alters the first byte each client sends, so that checks may be tested.
+    pub corrupt_for_testing: bool,
+}
+
+impl Policy {
+    /// Counts mismatched streams as `integrity_mismatch`.
+    pub fn bind(self, metrics: &tacho::Scope) -> IntegrityCheck {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ CRC32_POLYNOMIAL
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        IntegrityCheck {
+            table: Rc::new(table),
+            corrupt_for_testing: self.corrupt_for_testing,
+            mismatches: metrics.counter("integrity_mismatch"),
+        }
+    }
+}
+
+/// Checks the streams of a server.
+#[derive(Clone)]
+pub struct IntegrityCheck {
+    table: Rc<[u32; 256]>,
+    corrupt_for_testing: bool,
+    mismatches: tacho::Counter,
+}
+
+impl IntegrityCheck {
+    /// Checks one half of a stream, copying bytes from `src`, the `reader` peer, to
+    /// `dst`.
+    pub fn checksums(
+        &self,
+        src: net::SocketAddr,
+        dst: net::SocketAddr,
+        reader: Peer,
+    ) -> Checksums {
+        Checksums {
+            src,
+            dst,
+            read: Crc32::new(&self.table),
+            written: Crc32::new(&self.table),
+            corrupt: self.corrupt_for_testing && reader == Peer::Client,
+            mismatches: self.mismatches.clone(),
+        }
+    }
+}
+
+/// Tracks the bytes read and written by one half of a stream.
+pub struct Checksums {
+    src: net::SocketAddr,
+    dst: net::SocketAddr,
+    read: Crc32,
+    written: Crc32,
+    corrupt: bool,
+    mismatches: tacho::Counter,
+}
+
+impl Checksums {
+    /// Records bytes read, before they are written.
+    pub fn read(&mut self, buf: &mut [u8]) {
+        self.read.update(buf);
+        if self.corrupt && !buf.is_empty() {
+            buf[0] ^= 0xff;
+            self.corrupt = false;
+        }
+    }
+
+    pub fn wrote(&mut self, buf: &[u8]) {
+        self.written.update(buf);
+    }
+
+    /// Compares the checksums once all bytes read have been written.
+    pub fn verify(&self) {
+        if self.read.sum() != self.written.sum() || self.read.len != self.written.len {
+            error!(
+                "stream from {} to {} failed integrity check: read {} bytes (crc32 {:08x}), \
+                 wrote {} bytes (crc32 {:08x})",
+                self.src,
+                self.dst,
+                self.read.len,
+                self.read.sum(),
+                self.written.len,
+                self.written.sum()
+            );
+            self.mismatches.incr(1);
+        }
+    }
+}
+
+struct Crc32 {
+    table: Rc<[u32; 256]>,
+    /// The running checksum, inverted.
+    crc: u32,
+    len: usize,
+}
+
+impl Crc32 {
+    fn new(table: &Rc<[u32; 256]>) -> Crc32 {
+        Crc32 {
+            table: table.clone(),
+            crc: !0,
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        let mut crc = self.crc;
+        for &b in buf {
+            crc = self.table[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+        self.len += buf.len();
+    }
+
+    fn sum(&self) -> u32 {
+        !self.crc
+    }
+}
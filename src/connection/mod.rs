@@ -1,16 +1,63 @@
 use std::cell::RefCell;
 use std::net;
 use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
 
+mod budget;
+mod close;
 pub mod ctx;
-mod duplex;
+pub mod duplex;
+mod eviction;
 mod half_duplex;
+pub mod integrity;
+#[cfg(feature = "tls")]
 pub mod secure;
 pub mod socket;
+pub mod tcp_info;
+pub mod tee;
 
+pub use self::budget::BufferBudget;
+pub use self::close::{CloseReason, CloseReasonCell, Misdirected, Peer};
 pub use self::ctx::Ctx;
 pub use self::duplex::Duplex;
+pub use self::eviction::Eviction;
+pub use self::half_duplex::{WriteCoalescing, WriteTimeout};
+pub use self::integrity::IntegrityCheck;
 pub use self::socket::Socket;
+pub use self::tcp_info::{Sampler, TcpInfo};
+pub use self::tee::Tee;
+
+/// Transfer buffers shared by all streams, one for each direction of a stream.
+///
+/// Each read from a peer is limited by the size of its direction's buffer, and by what
+/// remains of the budget for data that could not yet be written.
+#[derive(Clone)]
+pub struct Buffers {
+    pub client_to_server: Rc<RefCell<Vec<u8>>>,
+    pub server_to_client: Rc<RefCell<Vec<u8>>>,
+    pub budget: BufferBudget,
+}
+
+impl Buffers {
+    pub fn new(
+        client_to_server_bytes: usize,
+        server_to_client_bytes: usize,
+        budget: BufferBudget,
+    ) -> Buffers {
+        Buffers {
+            client_to_server: Rc::new(RefCell::new(vec![0; client_to_server_bytes])),
+            server_to_client: Rc::new(RefCell::new(vec![0; server_to_client_bytes])),
+            budget,
+        }
+    }
+
+    /// The total size of both buffers.
+    pub fn total_bytes(&self) -> usize {
+        self.client_to_server.borrow().len() + self.server_to_client.borrow().len()
+    }
+}
 
 /// A src or dst connection with server or client context.
 pub struct Connection<C> {
@@ -36,11 +83,37 @@ impl<C: Ctx> Connection<C> {
     }
 
     /// Transfers data between connections bidirectionally.
+    ///
+    /// If `write_timeout` is set, the transfer fails when either side goes that long
+    /// without accepting any written bytes. If `integrity` is set, each direction is
+    /// checked for modified bytes. If `eviction` is set, the transfer ends when the
+    /// connection is evicted. If `coalescing` is set, small writes in each direction are
+    /// held briefly so that they are written together. If `mirror` is set, the data this
+    /// connection's peer sends is also copied to it. If `sampler` is set, both sockets'
+    /// TCP statistics are sampled periodically and as the transfer ends.
     pub fn into_duplex<D: Ctx>(
         self,
         other: Connection<D>,
-        buf: Rc<RefCell<Vec<u8>>>,
+        bufs: Buffers,
+        write_timeout: Option<Duration>,
+        integrity: Option<&IntegrityCheck>,
+        eviction: Option<Eviction>,
+        timer: &Timer,
+        coalescing: Option<(WriteCoalescing, Handle)>,
+        mirror: Option<Tee>,
+        sampler: Option<Sampler>,
     ) -> Duplex<C, D> {
-        duplex::new(self, other, buf)
+        duplex::new(
+            self,
+            other,
+            bufs,
+            write_timeout,
+            integrity,
+            eviction,
+            timer,
+            coalescing,
+            mirror,
+            sampler,
+        )
     }
 }
@@ -1,11 +1,18 @@
 use futures::{Async, Future, Poll};
-use rustls::{Session, ClientConfig, ServerConfig, ClientSession, ServerSession};
-use std::fmt;
+use super::tcp_info::TcpInfo;
+use rustls::{Certificate, ProtocolVersion, Session, ClientConfig, ServerConfig, ClientSession,
+             ServerSession};
+use rustls::internal::msgs::enums::CipherSuite;
+use std::{cmp, fmt};
 use std::io::{self, Read, Write};
 use std::net::{Shutdown, SocketAddr};
 use std::sync::Arc;
 use tokio_core::net::TcpStream;
-use tokio_io::AsyncWrite;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Limits how much of what a client sends during a server handshake is retained to
+/// describe its ClientHello if the handshake fails.
+const MAX_HELLO_BYTES: usize = 4096;
 
 pub fn client_handshake(tcp: TcpStream, config: &Arc<ClientConfig>, name: &str) -> ClientHandshake {
     let ss = SecureStream::new(tcp, ClientSession::new(config, name));
@@ -14,7 +21,11 @@ pub fn client_handshake(tcp: TcpStream, config: &Arc<ClientConfig>, name: &str)
 
 pub fn server_handshake(tcp: TcpStream, config: &Arc<ServerConfig>) -> ServerHandshake {
     let ss = SecureStream::new(tcp, ServerSession::new(config));
-    ServerHandshake(Some(ss))
+    ServerHandshake {
+        stream: Some(ss),
+        first_byte: None,
+        hello: Vec::new(),
+    }
 }
 
 /// Securely transmits data.
@@ -36,6 +47,27 @@ impl<S: Session> fmt::Debug for SecureStream<S> {
     }
 }
 
+impl SecureStream<ServerSession> {
+    /// The server name requested by the client, if any.
+    pub fn sni_hostname(&self) -> Option<&str> {
+        self.session.get_sni_hostname()
+    }
+
+    /// Indicates whether the client presented a certificate. Clients' certificates are
+    /// only requested, and verified, when the server is configured to authenticate
+    /// clients.
+    pub fn has_client_certificate(&self) -> bool {
+        self.session.get_peer_certificates().map(|c| !c.is_empty()).unwrap_or(false)
+    }
+
+    /// The certificate that the client presented, if any.
+    pub fn client_certificate(&self) -> Option<Certificate> {
+        self.session.get_peer_certificates().and_then(
+            |certs| certs.into_iter().next(),
+        )
+    }
+}
+
 impl<S> SecureStream<S>
 where
     S: Session,
@@ -57,19 +89,66 @@ where
         self.local
     }
 
+    /// The protocol negotiated via ALPN, if any.
+    pub fn alpn_protocol(&self) -> Option<String> {
+        self.session.get_alpn_protocol()
+    }
+
+    /// The negotiated protocol version, once the handshake has completed.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.session.get_protocol_version()
+    }
+
+    /// The negotiated cipher suite, once the handshake has completed.
+    pub fn cipher_suite(&self) -> Option<CipherSuite> {
+        self.session.get_negotiated_ciphersuite().map(|s| s.suite)
+    }
+
     pub fn tcp_shutdown(&mut self, how: Shutdown) -> io::Result<()> {
         trace!("tcp_shutdown: {:?}", self);
         self.tcp.shutdown(how)
     }
 
+    /// Reads available encrypted bytes without consuming them.
+    pub fn tcp_peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tcp.peek(buf)
+    }
+
+    /// Samples the kernel's statistics for the underlying TCP connection.
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        TcpInfo::sample(&self.tcp)
+    }
+
     fn read_tcp_to_session(&mut self) -> Option<io::Result<usize>> {
+        self.read_tcp_to_session_into(None)
+    }
+
+    /// Reads encrypted bytes into the session, copying them into `capture` (up to
+    /// `MAX_HELLO_BYTES`) if it is set.
+    fn read_tcp_to_session_into(
+        &mut self,
+        capture: Option<&mut Vec<u8>>,
+    ) -> Option<io::Result<usize>> {
         if !self.session.wants_read() {
             trace!("read_tcp_to_session: no read needed: {}", self.peer);
             return None;
         }
 
         trace!("read_tcp_to_session: read_tls: {}", self.peer);
-        match self.session.read_tls(&mut self.tcp) {
+        let read = match capture {
+            Some(buf) => {
+                if buf.len() < MAX_HELLO_BYTES {
+                    self.session.read_tls(&mut Capture {
+                        tcp: &mut self.tcp,
+                        buf,
+                    })
+                } else {
+                    self.session.read_tls(&mut self.tcp)
+                }
+            }
+            None => self.session.read_tls(&mut self.tcp),
+        };
+        match read {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     trace!("read_tcp_to_session: read_tls: {}: {}", self.peer, e);
@@ -160,6 +239,12 @@ where
     }
 }
 
+impl<S> AsyncRead for SecureStream<S>
+where
+    S: Session,
+{
+}
+
 impl<S> AsyncWrite for SecureStream<S>
 where
     S: Session,
@@ -173,25 +258,88 @@ where
 }
 
 /// A future that completes when a server's TLS handshake is complete.
-#[derive(Debug)]
-pub struct ServerHandshake(Option<SecureStream<ServerSession>>);
+pub struct ServerHandshake {
+    stream: Option<SecureStream<ServerSession>>,
+    first_byte: Option<u8>,
+    /// The first bytes the client sent, which begin with its ClientHello.
+    hello: Vec<u8>,
+}
+
+impl ServerHandshake {
+    /// The first byte sent by the client, once one has been received. TLS clients
+    /// begin with a handshake record, whose content type is 22.
+    pub fn first_byte(&self) -> Option<u8> {
+        self.first_byte
+    }
+
+    /// The first bytes the client sent during the handshake (at most
+    /// `MAX_HELLO_BYTES`), which begin with its ClientHello if the client speaks TLS.
+    pub fn client_hello(&self) -> &[u8] {
+        &self.hello
+    }
+}
+
+impl fmt::Debug for ServerHandshake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerHandshake")
+            .field("stream", &self.stream)
+            .field("first_byte", &self.first_byte)
+            .field("hello_bytes", &self.hello.len())
+            .finish()
+    }
+}
+
+/// Reads from a socket, retaining a copy of what is read.
+struct Capture<'a> {
+    tcp: &'a mut TcpStream,
+    buf: &'a mut Vec<u8>,
+}
+
+impl<'a> Read for Capture<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sz = self.tcp.read(buf)?;
+        let keep = cmp::min(sz, MAX_HELLO_BYTES.saturating_sub(self.buf.len()));
+        self.buf.extend_from_slice(&buf[..keep]);
+        Ok(sz)
+    }
+}
+
 impl Future for ServerHandshake {
     type Item = SecureStream<ServerSession>;
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         trace!("{:?}.poll()", self);
-        let mut ss = self.0.take().expect(
+        let mut ss = self.stream.take().expect(
             "poll must not be called after completion",
         );
 
+        // Note what the client sent first, so that failed handshakes with clients that
+        // don't speak TLS may be told apart from others.
+        if self.first_byte.is_none() {
+            let mut b = [0u8; 1];
+            if let Ok(1) = ss.tcp_peek(&mut b) {
+                self.first_byte = Some(b[0]);
+            }
+        }
+
         // Read and write the handshake.
         {
             let mut wrote = true;
             while ss.session.is_handshaking() && wrote {
-                if let Some(Err(e)) = ss.read_tcp_to_session() {
-                    trace!("server handshake: {}: error: {}", ss.peer, e);
-                    return Err(e);
-                };
+                match ss.read_tcp_to_session_into(Some(&mut self.hello)) {
+                    Some(Err(e)) => {
+                        trace!("server handshake: {}: error: {}", ss.peer, e);
+                        return Err(e);
+                    }
+                    Some(Ok(0)) => {
+                        trace!("server handshake: {}: closed by client", ss.peer);
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "closed during TLS handshake",
+                        ));
+                    }
+                    _ => {}
+                }
                 trace!("server handshake: write_session_to_tcp: {}", ss.peer);
                 wrote = ss.session.wants_write() &&
                     match ss.write_session_to_tcp() {
@@ -217,7 +365,7 @@ impl Future for ServerHandshake {
         // If the remote hasn't read everything yet, resume later.
         if ss.session.is_handshaking() {
             trace!("server handshake: {}: not complete", ss.peer);
-            self.0 = Some(ss);
+            self.stream = Some(ss);
             return Ok(Async::NotReady);
         }
 
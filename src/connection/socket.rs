@@ -1,9 +1,16 @@
+#[cfg(feature = "tls")]
 use super::secure::SecureStream;
+use super::super::metrics;
+use super::tcp_info::TcpInfo;
 use futures::Poll;
+use libc;
+#[cfg(feature = "tls")]
 use rustls::{ClientSession, ServerSession};
-use std::fmt;
+use std::{cmp, fmt};
 use std::io::{self, Read, Write};
-use std::net::{Shutdown, SocketAddr};
+use std::net::{self, Shutdown, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
 use tokio_core::net::TcpStream;
 use tokio_io::AsyncWrite;
 
@@ -12,22 +19,36 @@ pub fn plain(tcp: TcpStream) -> Socket {
         local_addr: tcp.local_addr().expect("tcp stream has no local address"),
         peer_addr: tcp.peer_addr().expect("tcp stream has no peer address"),
         kind: Kind::Plain(tcp),
+        replay: Vec::new(),
+        reset: None,
+        retained: None,
+        first_read: None,
     }
 }
 
+#[cfg(feature = "tls")]
 pub fn secure_client(tls: SecureStream<ClientSession>) -> Socket {
     Socket {
         local_addr: tls.local_addr(),
         peer_addr: tls.peer_addr(),
         kind: Kind::SecureClient(Box::new(tls)),
+        replay: Vec::new(),
+        reset: None,
+        retained: None,
+        first_read: None,
     }
 }
 
+#[cfg(feature = "tls")]
 pub fn secure_server(tls: SecureStream<ServerSession>) -> Socket {
     Socket {
         local_addr: tls.local_addr(),
         peer_addr: tls.peer_addr(),
         kind: Kind::SecureServer(Box::new(tls)),
+        replay: Vec::new(),
+        reset: None,
+        retained: None,
+        first_read: None,
     }
 }
 
@@ -40,13 +61,40 @@ pub struct Socket {
     local_addr: SocketAddr,
     peer_addr: SocketAddr,
     kind: Kind,
+    /// Bytes that were read before the socket was handed out, returned by the next
+    /// reads.
+    replay: Vec<u8>,
+    /// Set when chaos has chosen to reset the connection.
+    reset: Option<Reset>,
+    /// Set while the bytes read are retained so that they may be read again.
+    retained: Option<Retained>,
+    /// Taken when bytes are first read from the socket.
+    first_read: Option<FirstRead>,
+}
+
+/// Inspects the first bytes read from a socket, failing to refuse the stream.
+pub type FirstRead = Box<FnMut(&[u8]) -> io::Result<()>>;
+
+/// The bytes read since retention began, up to a limit.
+struct Retained {
+    bytes: Vec<u8>,
+    limit: usize,
+}
+
+/// Resets a connection once it has transferred a number of bytes.
+struct Reset {
+    remaining: usize,
+    /// Taken when the connection is reset.
+    resets: Option<Arc<metrics::Counter>>,
 }
 
 // Since the rustls types are much larger than the plain type, they are boxed. Because
 // clippy says so.
 enum Kind {
     Plain(TcpStream),
+    #[cfg(feature = "tls")]
     SecureClient(Box<SecureStream<ClientSession>>),
+    #[cfg(feature = "tls")]
     SecureServer(Box<SecureStream<ServerSession>>),
 }
 
@@ -59,12 +107,14 @@ impl fmt::Debug for Socket {
                     .field("local", &self.local_addr)
                     .finish()
             }
+            #[cfg(feature = "tls")]
             Kind::SecureClient(_) => {
                 f.debug_struct("SecureClient")
                     .field("peer", &self.peer_addr)
                     .field("local", &self.local_addr)
                     .finish()
             }
+            #[cfg(feature = "tls")]
             Kind::SecureServer(_) => {
                 f.debug_struct("SecureServer")
                     .field("peer", &self.peer_addr)
@@ -80,7 +130,9 @@ impl Socket {
         trace!("{:?}.tcp_shutdown({:?})", self, how);
         match self.kind {
             Kind::Plain(ref mut stream) => TcpStream::shutdown(stream, how),
+            #[cfg(feature = "tls")]
             Kind::SecureClient(ref mut stream) => stream.tcp_shutdown(how),
+            #[cfg(feature = "tls")]
             Kind::SecureServer(ref mut stream) => stream.tcp_shutdown(how),
         }
     }
@@ -89,20 +141,262 @@ impl Socket {
         self.local_addr
     }
 
+    /// Resets the connection once `bytes` have been read or written, counting the reset
+    /// in `resets`.
+    pub fn reset_after(&mut self, bytes: usize, resets: Arc<metrics::Counter>) {
+        self.reset = Some(Reset {
+            remaining: bytes,
+            resets: Some(resets),
+        });
+    }
+
+    /// Fails once the connection has transferred the bytes allowed before a reset,
+    /// closing it the first time.
+    fn check_reset(&mut self) -> io::Result<()> {
+        let due = self.reset.as_ref().map(|r| r.remaining == 0).unwrap_or(false);
+        if !due {
+            return Ok(());
+        }
+        let resets = self.reset.as_mut().and_then(|r| r.resets.take());
+        if let Some(resets) = resets {
+            debug!("{:?}: injecting a reset", self);
+            resets.incr(1);
+            let _ = self.tcp_shutdown(Shutdown::Both);
+        }
+        Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset by chaos"))
+    }
+
+    fn transferred(&mut self, sz: usize) {
+        if let Some(ref mut r) = self.reset {
+            r.remaining = r.remaining.saturating_sub(sz);
+        }
+    }
+
+    /// Passes the first bytes subsequently read from the socket to `inspect`, which may
+    /// fail to refuse the stream; the bytes are then discarded and the read fails.
+    /// Reads are never delayed, so a stream whose peer waits for the other to speak
+    /// first is inspected once its peer sends data, if ever.
+    pub fn on_first_read(&mut self, inspect: FirstRead) {
+        self.first_read = Some(inspect);
+    }
+
+    /// Passes `bytes`, which were just read from the socket, to the first-read
+    /// inspection, if it has not yet seen any.
+    fn inspect_first_read(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        match self.first_read.take() {
+            None => Ok(()),
+            Some(mut inspect) => inspect(bytes),
+        }
+    }
+
+    /// Returns `bytes`, which were already read from the socket, before any further data.
+    pub fn replay(&mut self, mut bytes: Vec<u8>) {
+        bytes.extend_from_slice(&self.replay);
+        self.replay = bytes;
+    }
+
+    /// The bytes that were read before the socket was handed out and have not yet been
+    /// returned.
+    pub fn replayed(&self) -> &[u8] {
+        &self.replay
+    }
+
+    /// Duplicates a plaintext socket's descriptor, e.g. so that it may be passed to
+    /// another process. Encrypted sockets cannot be duplicated, since their sessions are
+    /// held by this process.
+    pub fn dup_plain(&self) -> Option<io::Result<net::TcpStream>> {
+        match self.kind {
+            Kind::Plain(ref stream) => {
+                let fd = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+                if fd < 0 {
+                    return Some(Err(io::Error::last_os_error()));
+                }
+                Some(Ok(unsafe { net::TcpStream::from_raw_fd(fd) }))
+            }
+            #[cfg(feature = "tls")]
+            Kind::SecureClient(_) |
+            Kind::SecureServer(_) => None,
+        }
+    }
+
+    /// Retains the bytes subsequently read, up to `limit`, so that they may be read again
+    /// by `rewind`. Retention stops, and the bytes are discarded, once more than `limit`
+    /// bytes have been read.
+    pub fn retain(&mut self, limit: usize) {
+        self.retained = Some(Retained {
+            bytes: Vec::new(),
+            limit,
+        });
+    }
+
+    /// Stops retaining the bytes read, discarding those retained.
+    pub fn release(&mut self) {
+        self.retained = None;
+    }
+
+    /// Determines whether every byte read since retention began has been retained.
+    pub fn is_retaining(&self) -> bool {
+        self.retained.is_some()
+    }
+
+    /// Returns the retained bytes before any further data, so that they are read again,
+    /// and retains anew. Returns false, doing nothing, if the bytes are not retained.
+    pub fn rewind(&mut self) -> bool {
+        let (bytes, limit) = match self.retained.take() {
+            None => return false,
+            Some(r) => (r.bytes, r.limit),
+        };
+        self.replay(bytes);
+        self.retain(limit);
+        true
+    }
+
+    /// Retains `bytes`, which were just read, if bytes are retained.
+    fn retain_read(&mut self, bytes: &[u8]) {
+        let overflow = match self.retained {
+            None => return,
+            Some(ref r) => r.limit < r.bytes.len() + bytes.len(),
+        };
+        if overflow {
+            trace!("{:?}: too many bytes to retain", self);
+            self.retained = None;
+        } else if let Some(ref mut r) = self.retained {
+            r.bytes.extend_from_slice(bytes);
+        }
+    }
+
+    /// Reads available bytes, up to `limit` buffered bytes, to be returned by subsequent
+    /// reads, so that data sent by the peer before the socket is handed out is already
+    /// in hand when it is. Returns the number of bytes read.
+    ///
+    /// Fails with `UnexpectedEof` if the peer has closed the stream.
+    pub fn read_ahead(&mut self, limit: usize) -> io::Result<usize> {
+        let mut read = 0;
+        while self.replay.len() < limit {
+            let start = self.replay.len();
+            self.replay.resize(limit, 0);
+            let res = match self.kind {
+                Kind::Plain(ref mut stream) => stream.read(&mut self.replay[start..]),
+                #[cfg(feature = "tls")]
+                Kind::SecureClient(ref mut stream) => stream.read(&mut self.replay[start..]),
+                #[cfg(feature = "tls")]
+                Kind::SecureServer(ref mut stream) => stream.read(&mut self.replay[start..]),
+            };
+            let sz = match res {
+                Ok(sz) => sz,
+                Err(e) => {
+                    self.replay.truncate(start);
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(read);
+                    }
+                    return Err(e);
+                }
+            };
+            self.replay.truncate(start + sz);
+            if sz == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "closed by peer before it was used",
+                ));
+            }
+            let inspected = match self.first_read.take() {
+                None => Ok(()),
+                Some(mut inspect) => inspect(&self.replay[start..]),
+            };
+            if let Err(e) = inspected {
+                self.replay.truncate(start);
+                return Err(e);
+            }
+            self.transferred(sz);
+            read += sz;
+        }
+        Ok(read)
+    }
+
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
+
+    /// The protocol negotiated via ALPN by an encrypted socket, if any.
+    pub fn alpn_protocol(&self) -> Option<String> {
+        match self.kind {
+            #[cfg(feature = "tls")]
+            Kind::SecureClient(ref stream) => stream.alpn_protocol(),
+            #[cfg(feature = "tls")]
+            Kind::SecureServer(ref stream) => stream.alpn_protocol(),
+            _ => None,
+        }
+    }
+
+    /// The server name requested by the client of an encrypted server socket, if any.
+    pub fn sni_hostname(&self) -> Option<&str> {
+        match self.kind {
+            #[cfg(feature = "tls")]
+            Kind::SecureServer(ref stream) => stream.sni_hostname(),
+            _ => None,
+        }
+    }
+
+    /// Determines, without consuming any bytes, whether the peer has closed the stream
+    /// or the stream has otherwise failed.
+    pub fn is_peer_closed(&self) -> bool {
+        if !self.replay.is_empty() {
+            return false;
+        }
+        let mut buf = [0u8; 1];
+        let res = match self.kind {
+            Kind::Plain(ref stream) => stream.peek(&mut buf),
+            #[cfg(feature = "tls")]
+            Kind::SecureClient(ref stream) => stream.tcp_peek(&mut buf),
+            #[cfg(feature = "tls")]
+            Kind::SecureServer(ref stream) => stream.tcp_peek(&mut buf),
+        };
+        match res {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(_) => true,
+        }
+    }
+
+    /// Samples the kernel's statistics for the underlying TCP connection.
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        match self.kind {
+            Kind::Plain(ref stream) => TcpInfo::sample(stream),
+            #[cfg(feature = "tls")]
+            Kind::SecureClient(ref stream) => stream.tcp_info(),
+            #[cfg(feature = "tls")]
+            Kind::SecureServer(ref stream) => stream.tcp_info(),
+        }
+    }
 }
 
 /// Reads the socket without blocking.
 impl Read for Socket {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         trace!("{:?}.read({})", self, buf.len());
-        match self.kind {
+        if !self.replay.is_empty() {
+            let sz = cmp::min(buf.len(), self.replay.len());
+            buf[..sz].copy_from_slice(&self.replay[..sz]);
+            self.replay.drain(..sz);
+            self.retain_read(&buf[..sz]);
+            return Ok(sz);
+        }
+        self.check_reset()?;
+        let sz = match self.kind {
             Kind::Plain(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
             Kind::SecureClient(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
             Kind::SecureServer(ref mut stream) => stream.read(buf),
-        }
+        }?;
+        self.inspect_first_read(&buf[..sz])?;
+        self.transferred(sz);
+        self.retain_read(&buf[..sz]);
+        Ok(sz)
     }
 }
 
@@ -110,18 +404,25 @@ impl Read for Socket {
 impl Write for Socket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         trace!("{:?}.write({})", self, buf.len());
-        match self.kind {
+        self.check_reset()?;
+        let sz = match self.kind {
             Kind::Plain(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
             Kind::SecureClient(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
             Kind::SecureServer(ref mut stream) => stream.write(buf),
-        }
+        }?;
+        self.transferred(sz);
+        Ok(sz)
     }
 
     fn flush(&mut self) -> io::Result<()> {
         trace!("{:?}.flush()", self);
         match self.kind {
             Kind::Plain(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
             Kind::SecureClient(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
             Kind::SecureServer(ref mut stream) => stream.flush(),
         }
     }
@@ -133,7 +434,9 @@ impl AsyncWrite for Socket {
         trace!("{:?}.shutdown()", self);
         match self.kind {
             Kind::Plain(ref mut stream) => AsyncWrite::shutdown(stream),
+            #[cfg(feature = "tls")]
             Kind::SecureClient(ref mut stream) => stream.shutdown(),
+            #[cfg(feature = "tls")]
             Kind::SecureServer(ref mut stream) => stream.shutdown(),
         }
     }
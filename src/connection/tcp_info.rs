@@ -0,0 +1,256 @@
+//! Samples the kernel's view of TCP connections (`TCP_INFO`), so that trouble in the
+//! network (e.g. high round-trip times or retransmissions) may be told apart from slow
+//! peers.
+//!
+//! Sampling is only supported on Linux. Elsewhere, each sample fails, and streams are
+//! proxied as they would be without sampling.
+
+use super::Peer;
+use super::socket::Socket;
+use futures::{Async, Stream};
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::sync::{ONCE_INIT, Once};
+use std::time::Duration;
+use tacho;
+use tokio_timer::{Interval, Timer};
+
+/// The kernel's statistics for a TCP connection, as of a sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpInfo {
+    /// The smoothed round-trip time, in microseconds.
+    pub rtt_us: u32,
+    /// The round-trip time's mean deviation, in microseconds.
+    pub rtt_var_us: u32,
+    /// Segments retransmitted over the connection's lifetime.
+    pub total_retransmits: u32,
+    /// The most recent goodput, in bytes per second. Only reported by Linux 4.9 and
+    /// later.
+    pub delivery_rate_bps: Option<u64>,
+}
+
+impl TcpInfo {
+    /// Samples the statistics of the TCP connection underlying `socket`.
+    pub fn sample<S: sys::AsFd>(socket: &S) -> io::Result<TcpInfo> {
+        sys::sample(socket)
+    }
+}
+
+/// Indicates whether connections may be sampled on this platform.
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Logs, once, that sampling was configured on a platform that does not support it.
+pub fn warn_unsupported() {
+    static WARN: Once = ONCE_INIT;
+    WARN.call_once(|| {
+        warn!("tcpInfo is ignored: TCP_INFO may only be sampled on Linux");
+    });
+}
+
+/// Samples each stream's sockets every `interval`, and once more as it closes.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub interval: Duration,
+}
+
+impl Policy {
+    pub fn bind(&self, metrics: &tacho::Scope) -> Recorder {
+        let metrics = metrics.clone().prefixed("tcp");
+        Recorder {
+            interval: self.interval,
+            client: Metrics::new(&metrics.clone().labeled("peer", "client")),
+            endpoint: Metrics::new(&metrics.labeled("peer", "endpoint")),
+        }
+    }
+}
+
+/// Records a server's samples.
+#[derive(Clone)]
+pub struct Recorder {
+    interval: Duration,
+    client: Metrics,
+    endpoint: Metrics,
+}
+
+impl Recorder {
+    /// Samples a stream's sockets.
+    pub fn sampler(&self, timer: &Timer) -> Sampler {
+        Sampler {
+            interval: timer.interval(self.interval),
+            recorder: self.clone(),
+            last: Rc::new(Cell::new(Samples::default())),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    rtt_us: tacho::Stat,
+    rtt_var_us: tacho::Stat,
+    retransmits: tacho::Stat,
+    delivery_rate_bps: tacho::Stat,
+}
+
+impl Metrics {
+    fn new(metrics: &tacho::Scope) -> Metrics {
+        Metrics {
+            rtt_us: metrics.stat("rtt_us"),
+            rtt_var_us: metrics.stat("rtt_var_us"),
+            retransmits: metrics.stat("retransmits"),
+            delivery_rate_bps: metrics.stat("delivery_rate_bps"),
+        }
+    }
+
+    fn record(&self, info: &TcpInfo, closing: bool) {
+        self.rtt_us.add(u64::from(info.rtt_us));
+        self.rtt_var_us.add(u64::from(info.rtt_var_us));
+        if let Some(rate) = info.delivery_rate_bps {
+            self.delivery_rate_bps.add(rate);
+        }
+        // Retransmissions are counted over the connection's lifetime, so they are
+        // recorded once per connection.
+        if closing {
+            self.retransmits.add(u64::from(info.total_retransmits));
+        }
+    }
+}
+
+/// The most recent samples of a stream's sockets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Samples {
+    pub client: Option<TcpInfo>,
+    pub endpoint: Option<TcpInfo>,
+}
+
+/// Samples a stream's sockets.
+pub struct Sampler {
+    interval: Interval,
+    recorder: Recorder,
+    last: Rc<Cell<Samples>>,
+}
+
+impl Sampler {
+    /// Holds the most recent samples, which remain accessible after the stream closes.
+    pub fn samples(&self) -> Rc<Cell<Samples>> {
+        self.last.clone()
+    }
+
+    /// Indicates whether a periodic sample is due.
+    pub fn poll_due(&mut self) -> bool {
+        let mut due = false;
+        // The interval is drained so that the task is woken for the next sample.
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            due = true;
+        }
+        due
+    }
+
+    /// Samples `socket`, held by `peer`. Sockets that cannot be sampled (e.g. because
+    /// they have been closed) are skipped.
+    pub fn sample(&self, peer: Peer, socket: &Socket, closing: bool) {
+        let info = match socket.tcp_info() {
+            Ok(info) => info,
+            Err(e) => {
+                trace!("failed to sample {:?}: {}", socket, e);
+                return;
+            }
+        };
+        let mut last = self.last.get();
+        match peer {
+            Peer::Client => {
+                self.recorder.client.record(&info, closing);
+                last.client = Some(info);
+            }
+            Peer::Server => {
+                self.recorder.endpoint.record(&info, closing);
+                last.endpoint = Some(info);
+            }
+        }
+        self.last.set(last);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::TcpInfo;
+    use libc;
+    use std::{io, mem, ptr};
+    pub use std::os::unix::io::AsRawFd as AsFd;
+
+    /// The size of `struct tcp_info` through `tcpi_delivery_rate` (see linux/tcp.h).
+    /// Newer kernels have more fields, which are not read; older kernels have fewer,
+    /// and copy only as much of the struct as they have.
+    const TCP_INFO_LEN: usize = 168;
+
+    /// The offsets of the fields that are read. Each is available if the kernel copied
+    /// the whole field.
+    const RTT: usize = 68;
+    const RTTVAR: usize = 72;
+    const TOTAL_RETRANS: usize = 100;
+    const DELIVERY_RATE: usize = 160;
+
+    pub fn sample<S: AsFd>(socket: &S) -> io::Result<TcpInfo> {
+        let mut buf = [0u8; TCP_INFO_LEN];
+        let mut len = TCP_INFO_LEN as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = len as usize;
+        if len < TOTAL_RETRANS + mem::size_of::<u32>() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("tcp_info is too short: {} bytes", len),
+            ));
+        }
+        Ok(TcpInfo {
+            rtt_us: read_u32(&buf, RTT),
+            rtt_var_us: read_u32(&buf, RTTVAR),
+            total_retransmits: read_u32(&buf, TOTAL_RETRANS),
+            delivery_rate_bps: if len >= DELIVERY_RATE + mem::size_of::<u64>() {
+                Some(read_u64(&buf, DELIVERY_RATE))
+            } else {
+                None
+            },
+        })
+    }
+
+    // Fields are in the host's byte order.
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        assert!(offset + mem::size_of::<u32>() <= buf.len());
+        unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const u32) }
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        assert!(offset + mem::size_of::<u64>() <= buf.len());
+        unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const u64) }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::TcpInfo;
+    use std::io;
+
+    /// Any socket, since none may be sampled.
+    pub trait AsFd {}
+    impl<S> AsFd for S {}
+
+    pub fn sample<S: AsFd>(_socket: &S) -> io::Result<TcpInfo> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "tcp_info may only be sampled on Linux",
+        ))
+    }
+}
@@ -0,0 +1,238 @@
+//! Copies the bytes a client sends to a secondary, best-effort connection.
+//!
+//! The primary stream never waits for the copy: bytes are buffered for the secondary
+//! connection, and the copy is abandoned once it falls more than `max_buffered_bytes`
+//! behind. Bytes that the secondary connection sends are read and discarded.
+
+use super::Connection;
+use super::Ctx;
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use std::{error, fmt};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_io::AsyncWrite;
+use tokio_timer::{Sleep, Timer};
+
+/// The size of the buffer into which the secondary connection's bytes are discarded.
+const DISCARD_BUF_BYTES: usize = 4 * 1024;
+
+/// Creates a tee that holds up to `max_buffered_bytes` that have not yet been copied.
+pub fn new(max_buffered_bytes: usize) -> (Tee, Copied) {
+    let shared = Rc::new(RefCell::new(Shared {
+        buf: Vec::new(),
+        max_buffered_bytes,
+        overflowed: false,
+        abandoned: false,
+        closed: false,
+        task: None,
+    }));
+    (Tee(shared.clone()), Copied(shared))
+}
+
+struct Shared {
+    /// Bytes that have been copied but not yet written.
+    buf: Vec<u8>,
+    max_buffered_bytes: usize,
+    /// Set once the copy has fallen too far behind.
+    overflowed: bool,
+    /// Set once the copy is abandoned, so that nothing more is buffered.
+    abandoned: bool,
+    /// Set once the primary stream will copy no more bytes.
+    closed: bool,
+    /// The task writing the copy, notified as bytes are buffered.
+    task: Option<Task>,
+}
+
+impl Shared {
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+/// Copies bytes from the primary stream. When dropped, the copy is finished.
+pub struct Tee(Rc<RefCell<Shared>>);
+
+impl Tee {
+    /// Buffers `bytes` to be copied, unless the copy has been abandoned or would fall
+    /// too far behind.
+    pub fn copy(&self, bytes: &[u8]) {
+        let mut shared = self.0.borrow_mut();
+        if shared.abandoned {
+            return;
+        }
+        if shared.buf.len() + bytes.len() > shared.max_buffered_bytes {
+            shared.overflowed = true;
+            shared.abandoned = true;
+            shared.buf = Vec::new();
+        } else {
+            shared.buf.extend_from_slice(bytes);
+        }
+        shared.notify();
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        let mut shared = self.0.borrow_mut();
+        shared.closed = true;
+        shared.notify();
+    }
+}
+
+/// The bytes copied by a `Tee`. When dropped, the copy is abandoned.
+pub struct Copied(Rc<RefCell<Shared>>);
+
+impl Copied {
+    /// Writes the copied bytes to `conn`.
+    ///
+    /// Once the primary stream has finished, `conn` is shut down after the remaining
+    /// bytes are written, which may take at most `drain_timeout`.
+    pub fn write_to<C: Ctx>(
+        self,
+        conn: Connection<C>,
+        drain_timeout: Duration,
+        timer: &Timer,
+    ) -> Shadow<C> {
+        Shadow {
+            copied: self,
+            conn,
+            bytes_total: 0,
+            read_eof: false,
+            drain_timeout,
+            drain_deadline: None,
+            timer: timer.clone(),
+        }
+    }
+}
+
+impl Drop for Copied {
+    fn drop(&mut self) {
+        let mut shared = self.0.borrow_mut();
+        shared.abandoned = true;
+        shared.buf = Vec::new();
+    }
+}
+
+/// Writes copied bytes to a secondary connection, completing with the number of bytes
+/// written once the primary stream has finished and the connection has been shut down.
+///
+/// Fails with `Overflow` if the copy falls too far behind.
+pub struct Shadow<C> {
+    copied: Copied,
+    conn: Connection<C>,
+    bytes_total: usize,
+    /// Set once the secondary connection has stopped sending.
+    read_eof: bool,
+    drain_timeout: Duration,
+    /// Set once the primary stream has finished while copied bytes remain unwritten.
+    drain_deadline: Option<Sleep>,
+    timer: Timer,
+}
+
+impl<C: Ctx> Shadow<C> {
+    /// Reads and discards whatever the secondary connection has sent.
+    fn discard(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; DISCARD_BUF_BYTES];
+        while !self.read_eof {
+            match self.conn.socket.read(&mut buf) {
+                Ok(0) => self.read_eof = true,
+                Ok(sz) => self.conn.ctx.read(sz),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Bounds the time spent writing once the primary stream has finished.
+    fn poll_drain_deadline(&mut self) -> Poll<usize, io::Error> {
+        if self.drain_deadline.is_none() {
+            self.drain_deadline = Some(self.timer.sleep(self.drain_timeout));
+        }
+        match self.drain_deadline.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "mirror did not drain"))
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+impl<C: Ctx> Future for Shadow<C> {
+    type Item = usize;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<usize, io::Error> {
+        self.discard()?;
+
+        let shared = self.copied.0.clone();
+        let mut shared = shared.borrow_mut();
+        if shared.overflowed {
+            let e = Overflow(shared.max_buffered_bytes);
+            return Err(io::Error::new(io::ErrorKind::Other, e));
+        }
+
+        while !shared.buf.is_empty() {
+            match self.conn.socket.write(&shared.buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    shared.task = Some(task::current());
+                    if shared.closed {
+                        return self.poll_drain_deadline();
+                    }
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e),
+                Ok(wsz) => {
+                    shared.buf.drain(0..wsz);
+                    self.bytes_total += wsz;
+                    self.conn.ctx.wrote(wsz);
+                }
+            }
+        }
+
+        if !shared.closed {
+            shared.task = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+        match self.conn.socket.shutdown() {
+            Ok(Async::Ready(())) => {}
+            Ok(Async::NotReady) => return self.poll_drain_deadline(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return self.poll_drain_deadline();
+            }
+            Err(e) => return Err(e),
+        }
+        self.conn.socket.tcp_shutdown(Shutdown::Write)?;
+        Ok(Async::Ready(self.bytes_total))
+    }
+}
+
+/// Indicates that a copy fell more than the given number of bytes behind.
+#[derive(Debug)]
+pub struct Overflow(usize);
+
+impl Overflow {
+    /// Determines whether an error was caused by an overflowed copy.
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().map(|e| e.is::<Overflow>()).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "more than {} bytes behind", self.0)
+    }
+}
+
+impl error::Error for Overflow {
+    fn description(&self) -> &str {
+        "mirror overflow"
+    }
+}
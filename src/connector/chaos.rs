@@ -0,0 +1,109 @@
+//! Injects faults into upstream connections so that a proxy's resilience features may be
+//! exercised under load.
+//!
+//! A router's chaos configuration is inert unless the process is started with `--chaos`
+//! and its configuration sets `allowChaos: true`. Each injected fault is counted as
+//! `chaos_faults`, labeled by the `fault` that was injected.
+
+use super::config::{Error, Result};
+use super::super::duration::Millis;
+use super::super::metrics;
+use rand::{self, Rng};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Faults to inject into a router's upstream connections.
+///
+/// Each percentage is the chance, from 0 to 100, that a connection is affected.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ChaosConfig {
+    /// Connection attempts that fail without connecting.
+    pub drop_connect_percent: Option<f64>,
+
+    /// Connections that are established `connectLatencyMs` late.
+    pub connect_latency_percent: Option<f64>,
+    /// How late delayed connections are established (0 by default).
+    pub connect_latency_ms: Option<Millis>,
+
+    /// Established connections that are reset after transferring `resetAfterBytes`.
+    pub reset_percent: Option<f64>,
+    /// The bytes a connection transfers before it is reset (0 by default, so that it is
+    /// reset on its first read or write).
+    pub reset_after_bytes: Option<usize>,
+}
+
+impl ChaosConfig {
+    /// Validates this configuration to produce a fault injector whose faults are counted
+    /// in `metrics`.
+    pub(crate) fn mk_chaos(&self, metrics: &metrics::Scope) -> Result<Chaos> {
+        let drop_connect = ratio(self.drop_connect_percent)?;
+        let delay_connect = ratio(self.connect_latency_percent)?;
+        let reset = ratio(self.reset_percent)?;
+        let faults = metrics.clone().prefixed("chaos");
+        Ok(Chaos {
+            drop_connect,
+            delay_connect,
+            connect_latency: self.connect_latency_ms.map(Duration::from).unwrap_or_default(),
+            reset,
+            reset_after_bytes: self.reset_after_bytes.unwrap_or(0),
+            dropped: faults.clone().labeled("fault", "drop").counter("faults"),
+            delayed: faults.clone().labeled("fault", "delay").counter("faults"),
+            resets: faults.labeled("fault", "reset").counter("faults"),
+        })
+    }
+}
+
+fn ratio(percent: Option<f64>) -> Result<f64> {
+    match percent {
+        None => Ok(0.0),
+        Some(p) if 0.0 <= p && p <= 100.0 => Ok(p / 100.0),
+        Some(p) => Err(Error::InvalidChaosPercent(p)),
+    }
+}
+
+/// Decides which connections are faulted.
+#[derive(Clone)]
+pub struct Chaos {
+    drop_connect: f64,
+    delay_connect: f64,
+    connect_latency: Duration,
+    reset: f64,
+    reset_after_bytes: usize,
+    dropped: Arc<metrics::Counter>,
+    delayed: Arc<metrics::Counter>,
+    resets: Arc<metrics::Counter>,
+}
+
+impl Chaos {
+    /// Determines whether a connection attempt should fail without connecting.
+    pub fn drop_connect(&self) -> bool {
+        let drop = chance(self.drop_connect);
+        if drop {
+            self.dropped.incr(1);
+        }
+        drop
+    }
+
+    /// The latency to add to a connection attempt, if it is to be delayed.
+    pub fn connect_latency(&self) -> Option<Duration> {
+        if !chance(self.delay_connect) {
+            return None;
+        }
+        self.delayed.incr(1);
+        Some(self.connect_latency)
+    }
+
+    /// The number of bytes after which an established connection should be reset, and
+    /// the counter to record the reset in, if it is to be reset.
+    pub fn reset_after(&self) -> Option<(usize, Arc<metrics::Counter>)> {
+        if !chance(self.reset) {
+            return None;
+        }
+        Some((self.reset_after_bytes, self.resets.clone()))
+    }
+}
+
+fn chance(ratio: f64) -> bool {
+    ratio > 0.0 && rand::thread_rng().next_f64() < ratio
+}
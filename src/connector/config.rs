@@ -1,13 +1,55 @@
-use super::{Connector, ConnectorFactory, Tls};
-use rustls;
-use std::fs::File;
-use std::io::BufReader;
-use std::sync::Arc;
-use std::time;
+use super::{CircuitBreakerPolicy, ConnectBackoff, Connector, ConnectorFactory, EndpointFilter,
+            Ewma, FailFast, FallbackPolicy, Locality, PoolPolicy, Preamble, ReadinessProbe,
+            Rebalance, SlowStart, Stickiness, Subsetting, Tls};
+use super::super::damping::{FlapDetection, UpdateDamping};
+use super::super::dns::HostPort;
+use super::super::duration::{Millis, Secs};
+use super::super::metrics;
+use super::super::schema::Schema;
+use super::filter::Cidr;
+use super::marking::{self, Marking};
+use super::subset;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+use std::{cmp, fmt, io, time};
+use std::collections::BTreeMap;
 
+/// Bounds connection attempts to unresponsive endpoints, which would otherwise wait for
+/// the kernel to give up on them. A `connectTimeoutMs` of 0 disables the timeout.
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 10_000;
 const DEFAULT_MAX_WAITERS: usize = 1_000_000;
 const DEFAULT_MAX_CONSECUTIVE_FAILURES: usize = 5;
 const DEFAULT_FAILURE_PENALTY_SECS: u64 = 60;
+const DEFAULT_SUCCESS_THRESHOLD: usize = 1;
+const DEFAULT_SPILLOVER_LOAD_FACTOR: f64 = 1.5;
+const DEFAULT_ZONE_META_KEY: &'static str = "zone";
+const DEFAULT_CIRCUIT_WINDOW_SECS: u64 = 10;
+const DEFAULT_CIRCUIT_MIN_REQUESTS: usize = 20;
+const DEFAULT_CIRCUIT_FAILURE_RATE_THRESHOLD: f64 = 0.5;
+const DEFAULT_CIRCUIT_OPEN_SECS: u64 = 10;
+const DEFAULT_CIRCUIT_PROBE_RATIO: f64 = 0.1;
+const DEFAULT_BASE_BACKOFF_MS: u64 = 100;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 10_000;
+const DEFAULT_FALLBACK_ACTIVATE_AFTER_SECS: u64 = 10;
+const DEFAULT_SLOW_START_WINDOW_SECS: u64 = 30;
+const DEFAULT_ENDPOINT_MEMORY_SECS: u64 = 60;
+pub const DEFAULT_EWMA_DECAY_SECS: u64 = 10;
+const DEFAULT_REBALANCE_CHECK_INTERVAL_SECS: u64 = 60;
+const DEFAULT_REBALANCE_MAX_SKEW_RATIO: f64 = 2.0;
+const DEFAULT_REBALANCE_MAX_CLOSE_RATIO: f64 = 0.1;
+const DEFAULT_STATS_WINDOW_SECS: u64 = 60;
+const DEFAULT_LOG_SUPPRESS_SECS: u64 = 60;
+const DEFAULT_READINESS_PROBE_TIMEOUT_MS: u64 = 500;
+const DEFAULT_CONNECT_PREAMBLE_TIMEOUT_MS: u64 = 500;
+const DEFAULT_STICKINESS_TTL_SECS: u64 = 300;
+const DEFAULT_STICKINESS_MAX_ENTRIES: usize = 100_000;
+const DEFAULT_STICKINESS_MAX_LOAD_FACTOR: f64 = 2.0;
+const DEFAULT_SELECTION_TRACE_SAMPLE_RATE: f64 = 0.001;
+const DEFAULT_MIN_APPLY_INTERVAL_SECS: u64 = 5;
+const DEFAULT_FLAP_WINDOW_SECS: u64 = 60;
+const DEFAULT_FLAP_MAX_TRANSITIONS: usize = 6;
+/// Bounds the metadata keys from which endpoint metrics are labeled.
+const MAX_METRIC_LABELS_FROM_META: usize = 4;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -15,16 +57,66 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 pub enum Error {
     GlobalWithPrefix,
     StaticWithoutPrefix,
+    InvalidSpilloverLoadFactor(f64),
+    InvalidCircuitWindow,
+    InvalidFailureRateThreshold(f64),
+    InvalidProbeRatio(f64),
+    BuiltWithoutTlsSupport,
+    InvalidSuccessThreshold,
+    InvalidBaseBackoff,
+    NoFallbackAddrs,
+    InvalidFallbackAddr(String),
+    InvalidCidr(String),
+    InvalidSlowStartWindow,
+    InvalidEwmaDecay,
+    InvalidDscp(u8),
+    MarkingUnsupported,
+    InvalidRebalanceInterval,
+    InvalidMaxSkewRatio(f64),
+    InvalidMaxCloseRatio(f64),
+    InvalidStatsWindow,
+    InvalidSubsetSize,
+    UnknownHostname(io::ErrorKind),
+    UnreadableTrustCerts(String, io::ErrorKind),
+    InvalidTrustCerts(String),
+    UnreadableClientCerts(String, io::ErrorKind),
+    InvalidClientCerts(String),
+    /// The client key file could not be read, or did not hold exactly one RSA key.
+    InvalidClientKey(String),
+    /// `clientCerts` and `clientKey` must be set together.
+    ClientCertsWithoutKey,
+    InvalidTlsReloadInterval,
+    InvalidReadinessProbe,
+    InvalidReadinessProbeTimeout,
+    /// `hexBytes` is empty, or is not an even number of hex digits.
+    InvalidConnectPreamble(String),
+    InvalidConnectPreambleTimeout,
+    InvalidMaxConcurrentDispatches,
+    InvalidChaosPercent(f64),
+    InvalidStickinessTtl,
+    InvalidStickinessMaxEntries,
+    InvalidStickinessMaxLoadFactor(f64),
+    InvalidSelectionTraceSampleRate(f64),
+    InvalidMetricLabelsFromMeta(String),
+    InvalidFlapWindow,
+    InvalidFlapMaxTransitions,
 }
 
+/// Determines how outbound connections are initiated for each destination.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, tag = "kind")]
 pub enum ConnectorFactoryConfig {
+    /// Configures all destinations alike.
     #[serde(rename = "io.l5d.global")]
     Global(ConnectorConfig),
 
+    /// Configures destinations by prefix. Each destination is configured by every
+    /// config whose prefix it starts with, in order.
     #[serde(rename = "io.l5d.static")]
-    Static { configs: Vec<ConnectorConfig> },
+    Static {
+        /// Configurations, each of which must have a `prefix`.
+        configs: Vec<ConnectorConfig>,
+    },
 }
 
 impl Default for ConnectorFactoryConfig {
@@ -34,18 +126,51 @@ impl Default for ConnectorFactoryConfig {
 }
 
 impl ConnectorFactoryConfig {
-    pub fn mk_connector_factory(&self) -> Result<ConnectorFactory> {
+    /// Describes the fields of a client configuration.
+    pub fn schema() -> Schema {
+        // `io.l5d.static`'s fields are not those of a struct.
+        static STATIC_FIELDS: &'static [&'static str] = &["configs"];
+        let statics = Schema::Struct(
+            STATIC_FIELDS,
+            vec![("configs", Schema::list(ConnectorConfig::schema()))],
+        );
+        Schema::Tagged(
+            "kind",
+            vec![
+                ("io.l5d.global", ConnectorConfig::schema()),
+                ("io.l5d.static", statics),
+            ],
+        )
+    }
+
+    /// Determines whether any destination's TLS files are reloaded as they change.
+    pub fn reloads_tls(&self) -> bool {
+        let reloads = |c: &ConnectorConfig| {
+            c.tls.as_ref().map_or(false, |t| t.reload_interval_secs.is_some())
+        };
+        match *self {
+            ConnectorFactoryConfig::Global(ref c) => reloads(c),
+            ConnectorFactoryConfig::Static { ref configs } => configs.iter().any(reloads),
+        }
+    }
+
+    /// Validates this configuration to produce a connector factory. TLS reloads are
+    /// counted in `metrics`.
+    pub fn mk_connector_factory(&self, metrics: &metrics::Scope) -> Result<ConnectorFactory> {
         match *self {
             ConnectorFactoryConfig::Global(ref cfg) => {
                 if cfg.prefix.is_some() {
                     return Err(Error::GlobalWithPrefix);
                 }
-                let conn = cfg.mk_connector()?;
+                let conn = cfg.mk_connector_with_metrics(metrics)?;
                 Ok(ConnectorFactory::new_global(conn))
             }
             ConnectorFactoryConfig::Static { ref configs } => {
                 let mut pfx_configs = Vec::with_capacity(configs.len());
                 for cfg in configs {
+                    // Prefixed connectors are built as destinations are first routed,
+                    // so their marks are checked up front.
+                    cfg.mk_marking()?;
                     match cfg.prefix {
                         None => {
                             return Err(Error::StaticWithoutPrefix);
@@ -55,64 +180,987 @@ impl ConnectorFactoryConfig {
                         }
                     }
                 }
-                Ok(ConnectorFactory::new_prefixed(pfx_configs))
+                Ok(ConnectorFactory::new_prefixed(pfx_configs, metrics))
             }
         }
     }
 }
 
+/// Configures the connections made to a destination's endpoints.
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct ConnectorConfig {
+    /// The destinations configured by an `io.l5d.static` client, e.g. `/svc/web`.
     pub prefix: Option<String>,
+    /// When set, upstream connections complete a TLS handshake.
     pub tls: Option<TlsConnectorFactoryConfig>,
-    pub connect_timeout_ms: Option<u64>,
+    /// Bounds the time spent establishing each upstream connection (10s by default).
+    /// When 0, connections are not timed out.
+    pub connect_timeout_ms: Option<Millis>,
 
+    /// Limits the number of connections that may wait for an endpoint.
     pub max_waiters: Option<usize>,
+    /// Limits the number of connections to each destination that may be waiting for or
+    /// establishing an upstream connection at once. Further connections to the
+    /// destination are shed, without affecting other destinations. Unlimited by default.
+    pub max_concurrent_dispatches: Option<usize>,
+    /// The number of connections established ahead of demand.
     pub min_connections: Option<usize>,
 
+    /// Fails endpoints after consecutive connection failures.
     pub fail_fast: Option<FailFastConfig>,
 
+    /// Prefers endpoints in the local zone.
+    pub locality_aware: Option<LocalityAwareConfig>,
+
+    /// Rejects connections while a destination's failure rate is high.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// Limits how long connections established ahead of demand may be held.
+    pub pool: Option<PoolConfig>,
+
+    /// Skips endpoints for a time after failed connection attempts.
+    pub connect_backoff: Option<ConnectBackoffConfig>,
+
+    /// Sends connections to static endpoints while no resolved endpoint is available.
+    pub fallback: Option<FallbackConfig>,
+
+    /// When set, connection gauges are reported for each endpoint, labeled by `addr`.
+    /// Disabled by default, since each endpoint adds to the number of exported metrics.
+    pub endpoint_metrics: Option<bool>,
+
+    /// Labels each endpoint's connection gauges by these metadata keys (e.g.
+    /// `nodeName`), as `meta`, reporting them as `endpointMetrics` does.
+    pub metric_labels_from_meta: Option<Vec<String>>,
+
+    /// Drops resolved addresses outside of the permitted ranges.
+    pub endpoint_filter: Option<EndpointFilterConfig>,
+
+    /// Ramps up the weight of newly-added endpoints.
+    pub slow_start: Option<SlowStartConfig>,
+
+    /// Determines how endpoints are chosen for new connections.
+    pub load_balancer: Option<LoadBalancerConfig>,
+
+    /// Determines whether resolved weights are normalized to sum to 1.0 (the default),
+    /// or are used as they are resolved.
+    pub weight_mode: Option<WeightMode>,
+
+    /// Sets the firewall mark (`SO_MARK`) of upstream sockets. Requires
+    /// `CAP_NET_ADMIN`; Linux only.
+    pub so_mark: Option<u32>,
+
+    /// Sets the DSCP, within [0, 63], of upstream sockets' packets. Linux only.
+    pub dscp: Option<u8>,
+
+    /// Closes connections to endpoints that hold far more than their weighted share of
+    /// open connections.
+    pub rebalance: Option<RebalanceConfig>,
+
+    /// The window over which each endpoint's connection attempts are summarized by the
+    /// admin server's `/state` (60s by default).
+    pub stats_window_secs: Option<Secs>,
+
+    /// Limits each destination to a subset of its endpoints chosen for this proxy.
+    pub subsetting: Option<SubsettingConfig>,
+
+    /// After an endpoint's first failed connection is logged, identical failures are
+    /// summarized once per this window (60s by default). 0 logs every failure.
+    pub log_suppress_secs: Option<Secs>,
+
+    /// Waits for endpoints to send data on each new connection before it is used. Only
+    /// suitable for protocols in which the server speaks first.
+    pub readiness_probe: Option<ReadinessProbeConfig>,
+
+    /// Prefers the endpoint to which each client was last dispatched when it
+    /// reconnects.
+    pub stickiness: Option<StickinessConfig>,
+
+    /// Records why endpoints were chosen for a sample of dispatches, as served by the
+    /// admin server's `/admin/selection-trace` endpoint.
+    pub selection_trace: Option<SelectionTraceConfig>,
+
+    /// Writes bytes to each new connection, after any TLS handshake, before client
+    /// data is sent on it.
+    pub connect_preamble: Option<ConnectPreambleConfig>,
+
+    /// Coalesces changes to each destination's endpoints, and freezes them while its
+    /// resolutions flap.
+    pub update_damping: Option<UpdateDampingConfig>,
+
+    /// Reads the first bytes sent by endpoints on connections established ahead of
+    /// demand, so that they are written to clients as soon as the connections are
+    /// dispatched. Only suitable for protocols in which the server speaks first.
+    pub preconnect_read_ahead: Option<bool>,
+
     // TODO requeue_budget: Option<RequeueBudget>
 }
 
+/// Fails endpoints after consecutive connection failures, returning them on probation
+/// once their penalty expires.
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct FailFastConfig {
+    /// The number of consecutive failures after which an endpoint is failed.
     pub max_consecutive_failures: Option<usize>,
-    pub failure_penalty_secs: Option<u64>,
+    /// How long a failed endpoint is avoided.
+    pub failure_penalty_secs: Option<Secs>,
+    /// Bounds the penalty, which doubles with each failure on probation. Unless this is
+    /// set, the penalty does not grow.
+    pub max_penalty_secs: Option<Secs>,
+    /// The number of consecutive successes that end an endpoint's probation.
+    pub success_threshold: Option<usize>,
+}
+
+impl FailFastConfig {
+    fn mk_fail_fast(&self) -> Result<FailFast> {
+        let penalty = self.failure_penalty_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_FAILURE_PENALTY_SECS),
+        );
+        // Penalties only grow when they are bounded explicitly.
+        let max_penalty = self.max_penalty_secs.map(time::Duration::from).unwrap_or(penalty);
+        let success_threshold = self.success_threshold.unwrap_or(DEFAULT_SUCCESS_THRESHOLD);
+        if success_threshold == 0 {
+            return Err(Error::InvalidSuccessThreshold);
+        }
+        Ok(FailFast {
+            max_consecutive_failures: self.max_consecutive_failures.unwrap_or(
+                DEFAULT_MAX_CONSECUTIVE_FAILURES,
+            ),
+            penalty,
+            max_penalty: cmp::max(penalty, max_penalty),
+            success_threshold,
+        })
+    }
+}
+
+/// Prefers endpoints in the local zone, spilling over to other zones when the local
+/// endpoints are comparatively overloaded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct LocalityAwareConfig {
+    /// The zone in which this process runs.
+    pub local_zone: String,
+    /// How much more loaded than the average endpoint local endpoints may be before
+    /// connections spill over to other zones.
+    pub spillover_load_factor: Option<f64>,
+    /// The resolution metadata key that names each endpoint's zone.
+    pub zone_meta_key: Option<String>,
+}
+
+impl LocalityAwareConfig {
+    fn mk_locality(&self) -> Result<Locality> {
+        let factor = self.spillover_load_factor.unwrap_or(
+            DEFAULT_SPILLOVER_LOAD_FACTOR,
+        );
+        if !(factor >= 1.0) {
+            return Err(Error::InvalidSpilloverLoadFactor(factor));
+        }
+        Ok(Locality {
+            zone: self.local_zone.clone(),
+            meta_key: self.zone_meta_key.clone().unwrap_or_else(
+                || DEFAULT_ZONE_META_KEY.into(),
+            ),
+            spillover_load_factor: factor,
+        })
+    }
+}
+
+/// Sends clients that reconnect within `ttlSecs` to the endpoint to which they were last
+/// dispatched, while it is available and not overloaded.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct StickinessConfig {
+    /// Identifies clients (`sourceIp` by default).
+    pub key: Option<StickinessKey>,
+    /// How long a client's endpoint is remembered after it was last used.
+    pub ttl_secs: Option<Secs>,
+    /// Bounds the number of clients remembered, beyond which the least recently used
+    /// are forgotten.
+    pub max_entries: Option<usize>,
+    /// How much more loaded than the average endpoint a client's endpoint may be before
+    /// the client is balanced normally instead.
+    pub max_load_factor: Option<f64>,
+}
+
+/// Identifies the clients whose endpoints are remembered.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StickinessKey {
+    /// The client's IP address, regardless of its port.
+    SourceIp,
+}
+
+impl StickinessConfig {
+    fn mk_stickiness(&self) -> Result<Stickiness> {
+        let ttl = self.ttl_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_STICKINESS_TTL_SECS),
+        );
+        if ttl == time::Duration::from_secs(0) {
+            return Err(Error::InvalidStickinessTtl);
+        }
+        let max_entries = self.max_entries.unwrap_or(DEFAULT_STICKINESS_MAX_ENTRIES);
+        if max_entries == 0 {
+            return Err(Error::InvalidStickinessMaxEntries);
+        }
+        let factor = self.max_load_factor.unwrap_or(
+            DEFAULT_STICKINESS_MAX_LOAD_FACTOR,
+        );
+        if !(factor >= 1.0) {
+            return Err(Error::InvalidStickinessMaxLoadFactor(factor));
+        }
+        Ok(Stickiness {
+            ttl,
+            max_entries,
+            max_load_factor: factor,
+        })
+    }
+}
+
+/// Samples endpoint selections so that their inputs may be inspected.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SelectionTraceConfig {
+    /// The fraction of selections that are recorded, greater than 0 and at most 1
+    /// (0.001 by default).
+    pub sample_rate: Option<f64>,
+}
+
+impl SelectionTraceConfig {
+    fn mk_sample_rate(&self) -> Result<f64> {
+        let rate = self.sample_rate.unwrap_or(DEFAULT_SELECTION_TRACE_SAMPLE_RATE);
+        if !(0.0 < rate && rate <= 1.0) {
+            return Err(Error::InvalidSelectionTraceSampleRate(rate));
+        }
+        Ok(rate)
+    }
+}
+
+/// Rejects connections to a destination while its connection failure rate is high.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct CircuitBreakerConfig {
+    /// The window over which the failure rate is measured.
+    pub window_secs: Option<Secs>,
+    /// The number of attempts in a window below which the circuit never opens.
+    pub min_requests: Option<usize>,
+    /// The failure rate, within (0.0, 1.0], at which the circuit opens.
+    pub failure_rate_threshold: Option<f64>,
+    /// How long the circuit stays open before it is probed.
+    pub open_secs: Option<Secs>,
+    /// The fraction of connections admitted to probe a destination.
+    pub probe_ratio: Option<f64>,
+}
+
+impl CircuitBreakerConfig {
+    fn mk_policy(&self) -> Result<CircuitBreakerPolicy> {
+        let window = self.window_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_CIRCUIT_WINDOW_SECS),
+        );
+        if window < time::Duration::from_secs(1) {
+            return Err(Error::InvalidCircuitWindow);
+        }
+        let threshold = self.failure_rate_threshold.unwrap_or(
+            DEFAULT_CIRCUIT_FAILURE_RATE_THRESHOLD,
+        );
+        if !(0.0 < threshold && threshold <= 1.0) {
+            return Err(Error::InvalidFailureRateThreshold(threshold));
+        }
+        let probe_ratio = self.probe_ratio.unwrap_or(DEFAULT_CIRCUIT_PROBE_RATIO);
+        if !(0.0 < probe_ratio && probe_ratio <= 1.0) {
+            return Err(Error::InvalidProbeRatio(probe_ratio));
+        }
+        Ok(CircuitBreakerPolicy {
+            window,
+            min_requests: self.min_requests.unwrap_or(DEFAULT_CIRCUIT_MIN_REQUESTS),
+            failure_rate_threshold: threshold,
+            open_duration: self.open_secs.map(time::Duration::from).unwrap_or_else(
+                || time::Duration::from_secs(DEFAULT_CIRCUIT_OPEN_SECS),
+            ),
+            probe_ratio,
+        })
+    }
+}
+
+/// Limits how long connections established ahead of demand may be held.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct PoolConfig {
+    /// Closes connections that have been held, undispatched, for this long.
+    pub idle_timeout_secs: Option<Secs>,
+    /// Closes connections that were established this long ago, undispatched.
+    pub max_lifetime_secs: Option<Secs>,
+    /// Never dispatches connections that have been closed by their peer.
+    pub validate_before_reuse: Option<bool>,
+}
+
+impl PoolConfig {
+    fn mk_policy(&self) -> PoolPolicy {
+        PoolPolicy {
+            idle_timeout: self.idle_timeout_secs.map(time::Duration::from),
+            max_lifetime: self.max_lifetime_secs.map(time::Duration::from),
+            validate_before_reuse: self.validate_before_reuse.unwrap_or(false),
+        }
+    }
+}
+
+/// Skips endpoints for a jittered, exponentially-increasing delay after their
+/// connection attempts fail.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ConnectBackoffConfig {
+    /// The delay after an endpoint's first failure.
+    pub base_backoff_ms: Option<Millis>,
+    /// Bounds the delay, which doubles with each consecutive failure.
+    pub max_backoff_ms: Option<Millis>,
+}
+
+impl ConnectBackoffConfig {
+    fn mk_backoff(&self) -> Result<ConnectBackoff> {
+        let base = self.base_backoff_ms.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_millis(DEFAULT_BASE_BACKOFF_MS),
+        );
+        if base < time::Duration::from_millis(1) {
+            return Err(Error::InvalidBaseBackoff);
+        }
+        let max = self.max_backoff_ms.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_millis(DEFAULT_MAX_BACKOFF_MS),
+        );
+        Ok(ConnectBackoff {
+            base,
+            max: cmp::max(base, max),
+        })
+    }
+}
+
+/// Sends connections to static endpoints while none of a destination's resolved
+/// endpoints are available.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct FallbackConfig {
+    /// `host:port` pairs. Hostnames are resolved via DNS without blocking, and are
+    /// re-resolved periodically; if re-resolution fails, their previous addresses
+    /// continue to be used.
+    pub addrs: Vec<String>,
+    /// How long no resolved endpoint must be available before fallback is used.
+    pub activate_after_secs: Option<Secs>,
+}
+
+impl FallbackConfig {
+    fn mk_policy(&self) -> Result<FallbackPolicy> {
+        if self.addrs.is_empty() {
+            return Err(Error::NoFallbackAddrs);
+        }
+        let mut addrs = Vec::with_capacity(self.addrs.len());
+        for a in &self.addrs {
+            let addr = HostPort::parse(a).ok_or_else(|| Error::InvalidFallbackAddr(a.clone()))?;
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        Ok(FallbackPolicy {
+            addrs,
+            activate_after: self.activate_after_secs
+                .map(time::Duration::from)
+                .unwrap_or_else(|| {
+                    time::Duration::from_secs(DEFAULT_FALLBACK_ACTIVATE_AFTER_SECS)
+                }),
+        })
+    }
+}
+
+/// Drops resolved addresses in `denyCidrs`, or outside of `allowCidrs` when it is set,
+/// before endpoints are created for them.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct EndpointFilterConfig {
+    /// Ranges, e.g. `10.9.0.0/16`, or single addresses that are never used.
+    pub deny_cidrs: Option<Vec<String>>,
+    /// When set, only addresses in these ranges are used.
+    pub allow_cidrs: Option<Vec<String>>,
+    /// Only addresses whose metadata has each of these entries (e.g. `tier: ssd`) are
+    /// used.
+    pub require_meta: Option<BTreeMap<String, String>>,
+}
+
+impl EndpointFilterConfig {
+    fn mk_filter(&self) -> Result<EndpointFilter> {
+        fn parse(cidrs: &[String]) -> Result<Vec<Cidr>> {
+            cidrs
+                .iter()
+                .map(|c| c.parse().map_err(|_| Error::InvalidCidr(c.clone())))
+                .collect()
+        }
+        let deny = match self.deny_cidrs {
+            None => vec![],
+            Some(ref cidrs) => parse(cidrs)?,
+        };
+        let allow = match self.allow_cidrs {
+            None => None,
+            Some(ref cidrs) => Some(parse(cidrs)?),
+        };
+        Ok(EndpointFilter {
+            deny,
+            allow,
+            require_meta: self.require_meta.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/// Ramps up the weight of newly-added endpoints over `windowSecs`.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SlowStartConfig {
+    /// How long new endpoints take to reach their full weight.
+    pub window_secs: Option<Secs>,
+    /// Endpoints that return within this long of being removed skip slow start.
+    pub endpoint_memory_secs: Option<Secs>,
+}
+
+impl SlowStartConfig {
+    fn mk_slow_start(&self) -> Result<SlowStart> {
+        let window = self.window_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_SLOW_START_WINDOW_SECS),
+        );
+        if window == time::Duration::from_secs(0) {
+            return Err(Error::InvalidSlowStartWindow);
+        }
+        Ok(SlowStart {
+            window,
+            endpoint_memory: self.endpoint_memory_secs
+                .map(time::Duration::from)
+                .unwrap_or_else(|| time::Duration::from_secs(DEFAULT_ENDPOINT_MEMORY_SECS)),
+        })
+    }
+}
+
+/// Coalesces changes to a destination's endpoints.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct UpdateDampingConfig {
+    /// Changes are applied no more often than this (5s by default); the latest
+    /// resolution received in the meantime wins. 0 applies each change immediately.
+    pub min_apply_interval_secs: Option<Secs>,
+    /// When set, the union of recent resolutions is applied while they flap.
+    pub flap_detection: Option<FlapDetectionConfig>,
+}
+
+/// Determines when a destination's resolutions are flapping.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct FlapDetectionConfig {
+    /// The window over which changes to the set of resolved addresses are counted (60s
+    /// by default).
+    pub window_secs: Option<Secs>,
+    /// Resolutions flap once more changes than this (6 by default) occur within the
+    /// window.
+    pub max_transitions: Option<usize>,
+}
+
+impl UpdateDampingConfig {
+    fn mk_damping(&self) -> Result<UpdateDamping> {
+        let flap_detection = match self.flap_detection {
+            None => None,
+            Some(ref f) => {
+                let window = f.window_secs.map(time::Duration::from).unwrap_or_else(
+                    || time::Duration::from_secs(DEFAULT_FLAP_WINDOW_SECS),
+                );
+                if window == time::Duration::from_secs(0) {
+                    return Err(Error::InvalidFlapWindow);
+                }
+                let max_transitions = f.max_transitions.unwrap_or(DEFAULT_FLAP_MAX_TRANSITIONS);
+                if max_transitions == 0 {
+                    return Err(Error::InvalidFlapMaxTransitions);
+                }
+                Some(FlapDetection {
+                    window,
+                    max_transitions,
+                })
+            }
+        };
+        Ok(UpdateDamping {
+            min_apply_interval: self.min_apply_interval_secs
+                .map(time::Duration::from)
+                .unwrap_or_else(|| time::Duration::from_secs(DEFAULT_MIN_APPLY_INTERVAL_SECS)),
+            flap_detection,
+        })
+    }
+}
+
+/// Determines how endpoints are chosen for new connections.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct LoadBalancerConfig {
+    /// Defaults to `io.l5d.leastLoaded`.
+    pub kind: Option<LoadBalancerKind>,
+    /// How quickly `io.l5d.ewma` forgets latencies: an estimate that has not been
+    /// updated in this long has mostly decayed toward the average of its peers.
+    pub decay_secs: Option<Secs>,
+}
+
+/// The strategies by which endpoints may be chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoadBalancerKind {
+    /// Chooses the lesser-loaded of two random endpoints, relative to their weights.
+    #[serde(rename = "io.l5d.leastLoaded")]
+    LeastLoaded,
+    /// Chooses the better of two random endpoints, scored by the peak
+    /// exponentially-weighted moving averages of their connect latencies and times to
+    /// first byte, their loads, and their weights.
+    #[serde(rename = "io.l5d.ewma")]
+    Ewma,
+}
+
+/// Determines how resolved weights are interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WeightMode {
+    /// Weights are scaled to sum to 1.0 across each resolution, so that each is its
+    /// endpoint's share of the destination. `io.l5d.leastLoaded` scores endpoints by
+    /// `(load + 1) * (1 - weight)`, and weight multipliers may not raise a weight above
+    /// 1.0.
+    Normalized,
+    /// Weights are used as they are resolved, e.g. as each endpoint's capacity.
+    /// `io.l5d.leastLoaded` scores endpoints by `(load + 1) / weight`, so that
+    /// connections are spread in proportion to weights, and weight multipliers are not
+    /// capped. `io.l5d.ewma` and `rebalance` are proportional in either mode.
+    Absolute,
+}
+
+impl Default for WeightMode {
+    fn default() -> WeightMode {
+        WeightMode::Normalized
+    }
+}
+
+impl LoadBalancerConfig {
+    fn mk_ewma(&self) -> Result<Option<Ewma>> {
+        match self.kind.unwrap_or(LoadBalancerKind::LeastLoaded) {
+            LoadBalancerKind::LeastLoaded => Ok(None),
+            LoadBalancerKind::Ewma => {
+                let decay = self.decay_secs.map(time::Duration::from).unwrap_or_else(|| {
+                    time::Duration::from_secs(DEFAULT_EWMA_DECAY_SECS)
+                });
+                if decay == time::Duration::from_secs(0) {
+                    return Err(Error::InvalidEwmaDecay);
+                }
+                Ok(Some(Ewma { decay }))
+            }
+        }
+    }
+}
+
+/// Periodically closes the oldest connections to endpoints whose share of open
+/// connections exceeds their share of the destination's weight by `maxSkewRatio`.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct RebalanceConfig {
+    /// How often shares are checked (60s by default).
+    pub check_interval_secs: Option<Secs>,
+    /// Must be greater than 1 (2 by default).
+    pub max_skew_ratio: Option<f64>,
+    /// Bounds the proportion of open connections, in (0, 1], that are closed per check
+    /// (0.1 by default).
+    pub max_close_ratio: Option<f64>,
+    /// When set (the default), data that has already been read is written before
+    /// connections are shut down.
+    pub close_gracefully: Option<bool>,
+}
+
+impl RebalanceConfig {
+    fn mk_rebalance(&self) -> Result<Rebalance> {
+        let check_interval = self.check_interval_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_REBALANCE_CHECK_INTERVAL_SECS),
+        );
+        if check_interval == time::Duration::from_secs(0) {
+            return Err(Error::InvalidRebalanceInterval);
+        }
+        let max_skew_ratio = self.max_skew_ratio.unwrap_or(DEFAULT_REBALANCE_MAX_SKEW_RATIO);
+        if !(max_skew_ratio > 1.0) || max_skew_ratio.is_infinite() {
+            return Err(Error::InvalidMaxSkewRatio(max_skew_ratio));
+        }
+        let max_close_ratio = self.max_close_ratio.unwrap_or(DEFAULT_REBALANCE_MAX_CLOSE_RATIO);
+        if !(0.0 < max_close_ratio && max_close_ratio <= 1.0) {
+            return Err(Error::InvalidMaxCloseRatio(max_close_ratio));
+        }
+        Ok(Rebalance {
+            check_interval,
+            max_skew_ratio,
+            max_close_ratio,
+            close_gracefully: self.close_gracefully.unwrap_or(true),
+        })
+    }
+}
+
+/// Requires that endpoints send data on each new connection before it is used, so that
+/// endpoints that accept connections before they are able to serve them are treated as
+/// failing. The data read is sent on to the client once the connection is dispatched.
+///
+/// Exactly one of `expectFirstByte` or `expectBanner` must be set.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ReadinessProbeConfig {
+    /// When true, endpoints must send at least one byte.
+    pub expect_first_byte: Option<bool>,
+    /// Endpoints' data must begin with these bytes, e.g. `+OK`. This is a literal
+    /// prefix, not a pattern.
+    pub expect_banner: Option<String>,
+    /// How long endpoints have to send data once connected (500ms by default). Probes
+    /// that time out count as connection failures.
+    pub timeout_ms: Option<Millis>,
+}
+
+impl ReadinessProbeConfig {
+    fn mk_probe(&self) -> Result<ReadinessProbe> {
+        let banner = match (self.expect_first_byte.unwrap_or(false), &self.expect_banner) {
+            (true, &None) => Vec::new(),
+            (false, &Some(ref b)) if !b.is_empty() => b.as_bytes().to_vec(),
+            _ => return Err(Error::InvalidReadinessProbe),
+        };
+        let timeout = self.timeout_ms.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_millis(DEFAULT_READINESS_PROBE_TIMEOUT_MS),
+        );
+        if timeout == time::Duration::from_secs(0) {
+            return Err(Error::InvalidReadinessProbeTimeout);
+        }
+        Ok(ReadinessProbe { banner, timeout })
+    }
+}
+
+/// Writes bytes to each new connection before it is used, for endpoints that require an
+/// application-level hello that clients do not know to send because they are proxied.
+/// The bytes are opaque; they are neither generated nor parsed per connection.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ConnectPreambleConfig {
+    /// The bytes to write, as hex digits with an optional `0x` prefix, e.g.
+    /// `0xCAFE0001`.
+    pub hex_bytes: String,
+    /// The number of bytes the endpoint must send in response before the connection is
+    /// used (0 by default, in which case the connection is used once the preamble is
+    /// written).
+    pub await_response_bytes: Option<usize>,
+    /// When true, the response is sent to the client ahead of the endpoint's other
+    /// data. Otherwise, it is discarded (the default).
+    pub forward_response: Option<bool>,
+    /// How long endpoints have to accept the preamble and respond (500ms by default).
+    /// Preambles that fail or time out count as connection failures.
+    pub timeout_ms: Option<Millis>,
+}
+
+impl ConnectPreambleConfig {
+    fn mk_preamble(&self) -> Result<Preamble> {
+        let bytes = parse_hex(&self.hex_bytes).ok_or_else(|| {
+            Error::InvalidConnectPreamble(self.hex_bytes.clone())
+        })?;
+        let timeout = self.timeout_ms.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_millis(DEFAULT_CONNECT_PREAMBLE_TIMEOUT_MS),
+        );
+        if timeout == time::Duration::from_secs(0) {
+            return Err(Error::InvalidConnectPreambleTimeout);
+        }
+        Ok(Preamble {
+            bytes,
+            await_response_bytes: self.await_response_bytes.unwrap_or(0),
+            forward_response: self.forward_response.unwrap_or(false),
+            timeout,
+        })
+    }
+}
+
+/// Parses a non-empty string of hex digit pairs, with an optional `0x` prefix.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    let digits = if hex.starts_with("0x") || hex.starts_with("0X") {
+        &hex[2..]
+    } else {
+        hex
+    };
+    if digits.is_empty() || digits.len() % 2 != 0 ||
+        !digits.chars().all(|c| c.is_digit(16))
+    {
+        return None;
+    }
+    let bytes = (0..digits.len() / 2)
+        .map(|i| u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).unwrap())
+        .collect();
+    Some(bytes)
+}
+
+/// Limits a destination to the `size` endpoints ranked highest for this proxy's `seed`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SubsettingConfig {
+    /// The number of endpoints used. Destinations with fewer endpoints use all of them.
+    pub size: usize,
+    /// Determines which endpoints this proxy uses (`fromHostname` by default).
+    pub seed: Option<SubsetSeed>,
+}
+
+/// The seed that determines which endpoints a proxy uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubsetSeed {
+    /// Derives the seed from the host's name, so that each host uses its own subset.
+    FromHostname,
+    /// Uses a fixed seed.
+    Value(u64),
+}
+
+impl SubsettingConfig {
+    fn mk_subsetting(&self) -> Result<Subsetting> {
+        if self.size == 0 {
+            return Err(Error::InvalidSubsetSize);
+        }
+        let seed = match self.seed.unwrap_or(SubsetSeed::FromHostname) {
+            SubsetSeed::Value(seed) => seed,
+            SubsetSeed::FromHostname => {
+                subset::hostname_seed().map_err(|e| Error::UnknownHostname(e.kind()))?
+            }
+        };
+        Ok(Subsetting {
+            size: self.size,
+            seed,
+        })
+    }
+}
+
+const FROM_HOSTNAME: &'static str = "fromHostname";
+
+impl Serialize for SubsetSeed {
+    fn serialize<S: Serializer>(&self, s: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match *self {
+            SubsetSeed::FromHostname => s.serialize_str(FROM_HOSTNAME),
+            SubsetSeed::Value(seed) => s.serialize_u64(seed),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SubsetSeed {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> ::std::result::Result<SubsetSeed, D::Error> {
+        d.deserialize_any(SubsetSeedVisitor)
+    }
+}
+
+/// Accepts `fromHostname` or an unsigned integer.
+struct SubsetSeedVisitor;
+
+impl<'de> Visitor<'de> for SubsetSeedVisitor {
+    type Value = SubsetSeed;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\" or an unsigned integer", FROM_HOSTNAME)
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> ::std::result::Result<SubsetSeed, E> {
+        Ok(SubsetSeed::Value(n))
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> ::std::result::Result<SubsetSeed, E> {
+        if n < 0 {
+            return Err(E::invalid_value(de::Unexpected::Signed(n), &self));
+        }
+        Ok(SubsetSeed::Value(n as u64))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> ::std::result::Result<SubsetSeed, E> {
+        if s == FROM_HOSTNAME {
+            return Ok(SubsetSeed::FromHostname);
+        }
+        s.trim().parse::<u64>().map(SubsetSeed::Value).map_err(|_| {
+            E::invalid_value(de::Unexpected::Str(s), &self)
+        })
+    }
 }
 
 impl ConnectorConfig {
+    fn schema() -> Schema {
+        Schema::of::<ConnectorConfig>(vec![
+            ("tls", Schema::of::<TlsConnectorFactoryConfig>(vec![])),
+            ("failFast", Schema::of::<FailFastConfig>(vec![])),
+            ("localityAware", Schema::of::<LocalityAwareConfig>(vec![])),
+            ("circuitBreaker", Schema::of::<CircuitBreakerConfig>(vec![])),
+            ("pool", Schema::of::<PoolConfig>(vec![])),
+            ("connectBackoff", Schema::of::<ConnectBackoffConfig>(vec![])),
+            ("fallback", Schema::of::<FallbackConfig>(vec![])),
+            ("endpointFilter", Schema::of::<EndpointFilterConfig>(vec![])),
+            ("slowStart", Schema::of::<SlowStartConfig>(vec![])),
+            ("loadBalancer", Schema::of::<LoadBalancerConfig>(vec![])),
+            ("rebalance", Schema::of::<RebalanceConfig>(vec![])),
+            ("subsetting", Schema::of::<SubsettingConfig>(vec![])),
+            ("readinessProbe", Schema::of::<ReadinessProbeConfig>(vec![])),
+            ("stickiness", Schema::of::<StickinessConfig>(vec![])),
+            ("selectionTrace", Schema::of::<SelectionTraceConfig>(vec![])),
+            ("connectPreamble", Schema::of::<ConnectPreambleConfig>(vec![])),
+            (
+                "updateDamping",
+                Schema::of::<UpdateDampingConfig>(vec![
+                    ("flapDetection", Schema::of::<FlapDetectionConfig>(vec![])),
+                ]),
+            ),
+        ])
+    }
+
+    /// Validates this configuration to produce a connector.
     pub fn mk_connector(&self) -> Result<Connector> {
+        self.mk_connector_with_metrics(&metrics::Scope::noop())
+    }
+
+    /// Validates this configuration to produce a connector whose TLS reloads are counted
+    /// in `metrics`.
+    pub fn mk_connector_with_metrics(&self, metrics: &metrics::Scope) -> Result<Connector> {
         let tls = match self.tls {
             None => None,
-            Some(ref tls) => Some(tls.mk_tls()?),
+            Some(ref tls) => Some(tls.mk_tls(&metrics.clone().prefixed("tls"))?),
+        };
+        let connect_timeout = match self.connect_timeout_ms.map(time::Duration::from) {
+            None => Some(time::Duration::from_millis(DEFAULT_CONNECT_TIMEOUT_MS)),
+            Some(t) if t == time::Duration::from_secs(0) => None,
+            Some(t) => Some(t),
         };
-        let connect_timeout = self.connect_timeout_ms.map(time::Duration::from_millis);
         let max_waiters = self.max_waiters.unwrap_or(DEFAULT_MAX_WAITERS);
+        if self.max_concurrent_dispatches == Some(0) {
+            return Err(Error::InvalidMaxConcurrentDispatches);
+        }
         let min_conns = self.min_connections.unwrap_or(0);
-        let max_fails = self.fail_fast
-            .as_ref()
-            .and_then(|c| c.max_consecutive_failures)
-            .unwrap_or(DEFAULT_MAX_CONSECUTIVE_FAILURES);
-        let fail_penalty = {
-            let s = self.fail_fast
-                .as_ref()
-                .and_then(|c| c.failure_penalty_secs)
-                .unwrap_or(DEFAULT_FAILURE_PENALTY_SECS);
-            time::Duration::from_secs(s)
+        let fail_fast = self.fail_fast.clone().unwrap_or_default().mk_fail_fast()?;
+        let locality = match self.locality_aware {
+            None => None,
+            Some(ref l) => Some(l.mk_locality()?),
+        };
+        let circuit_breaker = match self.circuit_breaker {
+            None => None,
+            Some(ref c) => Some(c.mk_policy()?),
+        };
+        let pool = self.pool.clone().unwrap_or_default().mk_policy();
+        let connect_backoff = match self.connect_backoff {
+            None => None,
+            Some(ref b) => Some(b.mk_backoff()?),
+        };
+        let fallback = match self.fallback {
+            None => None,
+            Some(ref f) => Some(f.mk_policy()?),
+        };
+        let endpoint_filter = match self.endpoint_filter {
+            None => None,
+            Some(ref f) => Some(f.mk_filter()?),
+        };
+        let slow_start = match self.slow_start {
+            None => None,
+            Some(ref s) => Some(s.mk_slow_start()?),
+        };
+        let ewma = match self.load_balancer {
+            None => None,
+            Some(ref lb) => lb.mk_ewma()?,
+        };
+        let rebalance = match self.rebalance {
+            None => None,
+            Some(ref r) => Some(r.mk_rebalance()?),
+        };
+        let stats_window = self.stats_window_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_STATS_WINDOW_SECS),
+        );
+        if stats_window == time::Duration::from_secs(0) {
+            return Err(Error::InvalidStatsWindow);
+        }
+        let subsetting = match self.subsetting {
+            None => None,
+            Some(ref s) => Some(s.mk_subsetting()?),
+        };
+        let log_suppress = self.log_suppress_secs.map(time::Duration::from).unwrap_or_else(
+            || time::Duration::from_secs(DEFAULT_LOG_SUPPRESS_SECS),
+        );
+        let readiness_probe = match self.readiness_probe {
+            None => None,
+            Some(ref p) => Some(p.mk_probe()?),
         };
+        let stickiness = match self.stickiness {
+            None => None,
+            Some(ref s) => Some(s.mk_stickiness()?),
+        };
+        let selection_trace = match self.selection_trace {
+            None => None,
+            Some(ref t) => Some(t.mk_sample_rate()?),
+        };
+        let update_damping = match self.update_damping {
+            None => None,
+            Some(ref d) => Some(d.mk_damping()?),
+        };
+        let meta_labels = self.metric_labels_from_meta.clone().unwrap_or_default();
+        if meta_labels.len() > MAX_METRIC_LABELS_FROM_META {
+            let msg = format!("at most {} keys", MAX_METRIC_LABELS_FROM_META);
+            return Err(Error::InvalidMetricLabelsFromMeta(msg));
+        }
+        for (i, k) in meta_labels.iter().enumerate() {
+            if k.is_empty() || k.contains(',') || k.contains('=') {
+                return Err(Error::InvalidMetricLabelsFromMeta(format!("invalid key {:?}", k)));
+            }
+            if meta_labels[..i].contains(k) {
+                return Err(Error::InvalidMetricLabelsFromMeta(format!("duplicate key {}", k)));
+            }
+        }
+        let endpoint_metrics = self.endpoint_metrics.unwrap_or(false) || !meta_labels.is_empty();
+        let preamble = match self.connect_preamble {
+            None => None,
+            Some(ref p) => Some(p.mk_preamble()?),
+        };
+        let marking = self.mk_marking()?;
         Ok(super::new(
             connect_timeout,
             tls,
             max_waiters,
             min_conns,
-            max_fails,
-            fail_penalty,
+            fail_fast,
+            locality,
+            circuit_breaker,
+            pool,
+            connect_backoff,
+            fallback,
+            endpoint_metrics,
+            meta_labels,
+            endpoint_filter,
+            slow_start,
+            marking,
+            ewma,
+            rebalance,
+            stats_window,
+            subsetting,
+            log_suppress,
+            readiness_probe,
+            self.max_concurrent_dispatches,
+            stickiness,
+            selection_trace,
+            preamble,
+            self.preconnect_read_ahead.unwrap_or(false),
+            update_damping,
+            self.weight_mode.unwrap_or_default(),
         ))
     }
 
+    fn mk_marking(&self) -> Result<Marking> {
+        let mut marking = Marking {
+            so_mark: self.so_mark,
+            dscp: self.dscp,
+        };
+        if marking.is_empty() {
+            return Ok(marking);
+        }
+        if !marking::is_supported() {
+            return Err(Error::MarkingUnsupported);
+        }
+        if let Some(dscp) = marking.dscp {
+            if dscp > marking::MAX_DSCP {
+                return Err(Error::InvalidDscp(dscp));
+            }
+        }
+        if let Some(mark) = marking.so_mark {
+            if !marking::so_mark_permitted(mark) {
+                marking.so_mark = None;
+            }
+        }
+        Ok(marking)
+    }
+
+    /// Overrides this configuration with each field that is set by `other`.
     pub fn update(&mut self, other: &ConnectorConfig) {
         if let Some(ref otls) = other.tls {
             self.tls = Some(otls.clone());
@@ -120,32 +1168,228 @@ impl ConnectorConfig {
         if let Some(ct) = other.connect_timeout_ms {
             self.connect_timeout_ms = Some(ct);
         }
+        if let Some(ref f) = other.fail_fast {
+            self.fail_fast = Some(f.clone());
+        }
+        if let Some(ref l) = other.locality_aware {
+            self.locality_aware = Some(l.clone());
+        }
+        if let Some(ref c) = other.circuit_breaker {
+            self.circuit_breaker = Some(c.clone());
+        }
+        if let Some(ref p) = other.pool {
+            self.pool = Some(p.clone());
+        }
+        if let Some(ref b) = other.connect_backoff {
+            self.connect_backoff = Some(b.clone());
+        }
+        if let Some(ref f) = other.fallback {
+            self.fallback = Some(f.clone());
+        }
+        if let Some(e) = other.endpoint_metrics {
+            self.endpoint_metrics = Some(e);
+        }
+        if let Some(ref m) = other.metric_labels_from_meta {
+            self.metric_labels_from_meta = Some(m.clone());
+        }
+        if let Some(ref f) = other.endpoint_filter {
+            self.endpoint_filter = Some(f.clone());
+        }
+        if let Some(ref s) = other.slow_start {
+            self.slow_start = Some(s.clone());
+        }
+        if let Some(ref lb) = other.load_balancer {
+            self.load_balancer = Some(lb.clone());
+        }
+        if let Some(m) = other.so_mark {
+            self.so_mark = Some(m);
+        }
+        if let Some(d) = other.dscp {
+            self.dscp = Some(d);
+        }
+        if let Some(ref r) = other.rebalance {
+            self.rebalance = Some(r.clone());
+        }
+        if let Some(w) = other.stats_window_secs {
+            self.stats_window_secs = Some(w);
+        }
+        if let Some(ref s) = other.subsetting {
+            self.subsetting = Some(s.clone());
+        }
+        if let Some(s) = other.log_suppress_secs {
+            self.log_suppress_secs = Some(s);
+        }
+        if let Some(ref p) = other.readiness_probe {
+            self.readiness_probe = Some(p.clone());
+        }
+        if let Some(n) = other.max_concurrent_dispatches {
+            self.max_concurrent_dispatches = Some(n);
+        }
+        if let Some(ref s) = other.stickiness {
+            self.stickiness = Some(s.clone());
+        }
+        if let Some(ref t) = other.selection_trace {
+            self.selection_trace = Some(t.clone());
+        }
+        if let Some(ref p) = other.connect_preamble {
+            self.connect_preamble = Some(p.clone());
+        }
+        if let Some(r) = other.preconnect_read_ahead {
+            self.preconnect_read_ahead = Some(r);
+        }
+        if let Some(ref d) = other.update_damping {
+            self.update_damping = Some(d.clone());
+        }
+        if let Some(m) = other.weight_mode {
+            self.weight_mode = Some(m);
+        }
     }
 }
 
+/// Configures the TLS handshakes of upstream connections.
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TlsConnectorFactoryConfig {
+    /// The server name used for handshakes.
     pub dns_name: String,
+    /// Paths to PEM files of trusted root certificates.
     pub trust_certs: Option<Vec<String>>,
+    /// Determines the server name used for handshakes.
+    pub tls_name_from: Option<TlsNameFrom>,
+    /// Determines how servers' certificates are verified.
+    pub verification: Option<TlsVerification>,
+    /// Paths to PEM files of the certificate chain presented to servers that request a
+    /// client certificate.
+    pub client_certs: Option<Vec<String>>,
+    /// The path to a PEM file of the RSA private key for `clientCerts`.
+    pub client_key: Option<String>,
+    /// When set, the files above are checked for changes this often, as connections are
+    /// established, and are reloaded when they change. Disabled by default.
+    pub reload_interval_secs: Option<Secs>,
+}
+
+/// Determines the server name used for upstream TLS handshakes.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TlsNameFrom {
+    /// Always use `dnsName`.
+    DnsName,
+    /// Use the server name requested by the downstream client's TLS handshake, or
+    /// `dnsName` if the client requested none.
+    DownstreamSni,
+}
+
+/// Determines how upstream servers' certificates are verified.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TlsVerification {
+    /// Certificates must chain to a trusted root and be valid for the server name used
+    /// in the handshake.
+    Name,
+    /// Certificates must chain to a trusted root, regardless of the server name.
+    CaOnly,
 }
 
 impl TlsConnectorFactoryConfig {
-    pub fn mk_tls(&self) -> Result<Tls> {
+    /// Loads the trusted certificates to produce a TLS client configuration, reloading
+    /// them as they change if `reloadIntervalSecs` is set.
+    #[cfg(feature = "tls")]
+    pub fn mk_tls(&self, metrics: &metrics::Scope) -> Result<Tls> {
+        use super::reload::{Reload, SharedConfig};
+        use std::sync::Arc;
+
+        let config = self.load()?;
+        let reload = match self.reload_interval_secs.map(time::Duration::from) {
+            None => None,
+            Some(i) if i == time::Duration::from_secs(0) => {
+                return Err(Error::InvalidTlsReloadInterval);
+            }
+            Some(i) => Some(Reload::new(self, i, metrics)),
+        };
+        let tls = Tls {
+            name: self.dns_name.clone(),
+            config: Arc::new(SharedConfig::new(config, reload)),
+            propagate_sni: self.tls_name_from == Some(TlsNameFrom::DownstreamSni),
+        };
+        Ok(tls)
+    }
+
+    /// The files from which the configuration is loaded.
+    pub fn paths(&self) -> Vec<&str> {
+        let mut paths = Vec::new();
+        if let Some(ref certs) = self.trust_certs {
+            paths.extend(certs.iter().map(|p| p.as_str()));
+        }
+        if let Some(ref certs) = self.client_certs {
+            paths.extend(certs.iter().map(|p| p.as_str()));
+        }
+        if let Some(ref key) = self.client_key {
+            paths.push(key.as_str());
+        }
+        paths
+    }
+
+    /// Reads the configuration's files to produce a rustls configuration.
+    #[cfg(feature = "tls")]
+    pub fn load(&self) -> Result<::rustls::ClientConfig> {
+        use super::CaOnlyVerifier;
+        use rustls;
+        use rustls::internal::pemfile;
+        use std::fs::File;
+        use std::io::BufReader;
+        use std::sync::Arc;
+
         let mut config = rustls::ClientConfig::new();
         if let Some(ref certs) = self.trust_certs {
             for p in certs {
-                let f = File::open(p).expect("cannot open certificate file");
-                config
+                let f = File::open(p).map_err(
+                    |e| Error::UnreadableTrustCerts(p.clone(), e.kind()),
+                )?;
+                let (added, _) = config
                     .root_store
                     .add_pem_file(&mut BufReader::new(f))
-                    .expect("certificate error");
+                    .map_err(|_| Error::InvalidTrustCerts(p.clone()))?;
+                // A file without certificates (e.g. one being rewritten) trusts nothing.
+                if added == 0 {
+                    return Err(Error::InvalidTrustCerts(p.clone()));
+                }
             }
         };
-        let tls = Tls {
-            name: self.dns_name.clone(),
-            config: Arc::new(config),
-        };
-        Ok(tls)
+        if self.verification == Some(TlsVerification::CaOnly) {
+            config.dangerous().set_certificate_verifier(Arc::new(CaOnlyVerifier));
+        }
+
+        match (&self.client_certs, &self.client_key) {
+            (&None, &None) => {}
+            (&Some(ref paths), &Some(ref key)) => {
+                let mut certs = Vec::new();
+                for p in paths {
+                    let f = File::open(p).map_err(
+                        |e| Error::UnreadableClientCerts(p.clone(), e.kind()),
+                    )?;
+                    let mut c = pemfile::certs(&mut BufReader::new(f)).map_err(|()| {
+                        Error::InvalidClientCerts(p.clone())
+                    })?;
+                    certs.append(&mut c);
+                }
+                let invalid_key = || Error::InvalidClientKey(key.clone());
+                let f = File::open(key).map_err(|_| invalid_key())?;
+                let mut keys = pemfile::rsa_private_keys(&mut BufReader::new(f)).map_err(
+                    |()| invalid_key(),
+                )?;
+                if keys.len() != 1 {
+                    return Err(invalid_key());
+                }
+                config.set_single_client_cert(certs, keys.remove(0));
+            }
+            _ => return Err(Error::ClientCertsWithoutKey),
+        }
+        Ok(config)
+    }
+
+    /// Fails, since TLS is not supported by this build.
+    #[cfg(not(feature = "tls"))]
+    pub fn mk_tls(&self, _metrics: &metrics::Scope) -> Result<Tls> {
+        Err(Error::BuiltWithoutTlsSupport)
     }
 }
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Restricts the resolved addresses to which connections may be dispatched.
+///
+/// Addresses in a denied range are never used. When allowed ranges are configured,
+/// addresses outside of all of them are never used either. Nor are addresses whose
+/// metadata lacks any of the required entries.
+#[derive(Clone, Debug)]
+pub struct EndpointFilter {
+    pub deny: Vec<Cidr>,
+    pub allow: Option<Vec<Cidr>>,
+    pub require_meta: BTreeMap<String, String>,
+}
+
+impl EndpointFilter {
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        match self.allow {
+            None => true,
+            Some(ref allow) => allow.iter().any(|c| c.contains(ip)),
+        }
+    }
+
+    pub fn permits_meta(&self, meta: &BTreeMap<String, String>) -> bool {
+        self.require_meta.iter().all(|(k, v)| meta.get(k) == Some(v))
+    }
+}
+
+/// An IPv4 or IPv6 address range, e.g. `10.9.0.0/16` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Addresses of one family are never contained by ranges of the other.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Determines whether the first `len` bits of `a` and `b` are equal.
+fn prefix_matches(a: &[u8], b: &[u8], len: u8) -> bool {
+    let len = len as usize;
+    let (bytes, bits) = (len / 8, len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = !(0xffu8 >> bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    /// Parses `addr/len`. An address without a prefix length is a range of one address.
+    fn from_str(s: &str) -> Result<Cidr, ()> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or("").parse::<IpAddr>().map_err(|_| ())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            None => max_len,
+            Some(len) => len.parse::<u8>().map_err(|_| ())?,
+        };
+        if prefix_len > max_len {
+            return Err(());
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
@@ -0,0 +1,152 @@
+//! Marks upstream sockets for traffic engineering.
+//!
+//! `soMark` sets a socket's firewall mark (`SO_MARK`), which may select routing tables
+//! and netfilter rules, and `dscp` sets the Differentiated Services codepoint carried
+//! by each of its packets. Both are applied before the socket connects, so that the
+//! handshake is marked as well. Marking is only supported on Linux.
+//!
+//! Setting `SO_MARK` requires `CAP_NET_ADMIN`. Without it, a warning is logged once and
+//! sockets are not given a mark, rather than every connection failing.
+
+use std::{io, net};
+use std::sync::{ONCE_INIT, Once};
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+
+/// The largest codepoint that fits in the six bits of the DS field.
+pub const MAX_DSCP: u8 = 63;
+
+/// The marks applied to each upstream socket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Marking {
+    pub so_mark: Option<u32>,
+    pub dscp: Option<u8>,
+}
+
+impl Marking {
+    pub fn is_empty(&self) -> bool {
+        self.so_mark.is_none() && self.dscp.is_none()
+    }
+
+    /// Opens an unconnected TCP socket for `addr`'s address family, marked as
+    /// configured.
+    pub fn socket(&self, addr: &net::SocketAddr) -> io::Result<net::TcpStream> {
+        sys::socket(addr, self.so_mark, self.dscp)
+    }
+}
+
+/// Indicates whether sockets may be marked on this platform.
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Determines whether this process may set `SO_MARK`, logging a warning the first time
+/// it may not.
+pub fn so_mark_permitted(mark: u32) -> bool {
+    static CHECK: Once = ONCE_INIT;
+    static PERMITTED: AtomicBool = ATOMIC_BOOL_INIT;
+    CHECK.call_once(|| {
+        let permitted = match sys::probe_so_mark(mark) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    "upstream connections will not be marked: soMark could not be set \
+                     (CAP_NET_ADMIN is required): {}",
+                    e
+                );
+                false
+            }
+        };
+        PERMITTED.store(permitted, Ordering::Release);
+    });
+    PERMITTED.load(Ordering::Acquire)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use std::{io, mem, net};
+    use std::os::unix::io::FromRawFd;
+
+    /// Not exported by the version of libc in use.
+    const IPV6_TCLASS: libc::c_int = 67;
+
+    pub fn socket(
+        addr: &net::SocketAddr,
+        so_mark: Option<u32>,
+        dscp: Option<u8>,
+    ) -> io::Result<net::TcpStream> {
+        let domain = match *addr {
+            net::SocketAddr::V4(_) => libc::AF_INET,
+            net::SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The stream owns the descriptor, closing it if marking fails.
+        let sock = unsafe { net::TcpStream::from_raw_fd(fd) };
+        if let Some(mark) = so_mark {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)?;
+        }
+        if let Some(dscp) = dscp {
+            // The codepoint occupies the upper six bits of the TOS/traffic class byte.
+            let tos = libc::c_int::from(dscp) << 2;
+            match *addr {
+                net::SocketAddr::V4(_) => setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)?,
+                net::SocketAddr::V6(_) => setsockopt(fd, libc::IPPROTO_IPV6, IPV6_TCLASS, tos)?,
+            }
+        }
+        Ok(sock)
+    }
+
+    pub fn probe_so_mark(mark: u32) -> io::Result<()> {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let _sock = unsafe { net::TcpStream::from_raw_fd(fd) };
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+    }
+
+    fn setsockopt(
+        fd: libc::c_int,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{io, net};
+
+    pub fn socket(
+        _addr: &net::SocketAddr,
+        _so_mark: Option<u32>,
+        _dscp: Option<u8>,
+    ) -> io::Result<net::TcpStream> {
+        Err(unsupported())
+    }
+
+    pub fn probe_so_mark(_mark: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "sockets may only be marked on Linux")
+    }
+}
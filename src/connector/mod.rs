@@ -1,21 +1,53 @@
 use super::Path;
-use super::connection::secure;
 use super::connection::socket::{self, Socket};
-use futures::{Future, Poll};
-use rustls::ClientConfig as RustlsClientConfig;
-use std::{io, net, time};
+use super::damping::UpdateDamping;
+use super::dns::HostPort;
+use super::metrics;
+use super::timeout::{Timeout, timeout};
+use futures::{Async, Future, Poll, future};
+use rand::Rng;
+use std::{cmp, io, net, time};
 use std::sync::Arc;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 
+use self::marking::Marking;
+
+mod chaos;
 mod config;
+mod filter;
+mod marking;
+mod preamble;
+mod readiness;
+#[cfg(feature = "tls")]
+mod reload;
+mod remote_policy;
+mod subset;
 
-pub use self::config::{ConnectorFactoryConfig, ConnectorConfig, TlsConnectorFactoryConfig,
+pub use self::chaos::{Chaos, ChaosConfig};
+pub use self::config::{CircuitBreakerConfig, ConnectBackoffConfig, ConnectorFactoryConfig,
+                       ConnectorConfig, ConnectPreambleConfig, EndpointFilterConfig,
+                       FailFastConfig, FallbackConfig, FlapDetectionConfig,
+                       LoadBalancerConfig, LoadBalancerKind,
+                       LocalityAwareConfig, PoolConfig,
+                       ReadinessProbeConfig, RebalanceConfig, SelectionTraceConfig,
+                       SlowStartConfig, StickinessConfig, StickinessKey, SubsetSeed,
+                       SubsettingConfig, TlsConnectorFactoryConfig, TlsNameFrom,
+                       TlsVerification, UpdateDampingConfig, WeightMode,
                        Error as ConfigError};
+pub use self::filter::{Cidr, EndpointFilter};
+pub use self::preamble::{Preamble, Sending};
+pub use self::readiness::{Probing, ReadinessProbe};
+pub use self::remote_policy::{REMOTE_POLICY_KEYS, RemotePolicy};
+pub use self::subset::{Subsetting, hostname, stable_hash};
+
+/// Bounds the bytes read ahead on each pooled connection unless the proxy's transfer
+/// buffer size is applied.
+const DEFAULT_READ_AHEAD_BYTES: usize = 16 * 1024;
 
 /// Builds a connector for each name.
-pub struct ConnectorFactory(ConnectorFactoryInner);
+pub struct ConnectorFactory(ConnectorFactoryInner, Option<Chaos>, Option<usize>);
 
 enum ConnectorFactoryInner {
     /// Uses a single connector for all names.
@@ -28,23 +60,46 @@ enum ConnectorFactoryInner {
 
 impl ConnectorFactory {
     pub fn new_global(conn: Connector) -> ConnectorFactory {
-        ConnectorFactory(ConnectorFactoryInner::StaticGlobal(conn))
+        ConnectorFactory(ConnectorFactoryInner::StaticGlobal(conn), None, None)
     }
 
-    pub fn new_prefixed(prefixed_configs: Vec<(Path, ConnectorConfig)>) -> ConnectorFactory {
-        let f = StaticPrefixConnectorFactory(prefixed_configs);
-        ConnectorFactory(ConnectorFactoryInner::StaticPrefixed(f))
+    /// Builds connectors for prefixed configurations, counting their TLS reloads in
+    /// `metrics`.
+    pub fn new_prefixed(
+        prefixed_configs: Vec<(Path, ConnectorConfig)>,
+        metrics: &metrics::Scope,
+    ) -> ConnectorFactory {
+        let f = StaticPrefixConnectorFactory(prefixed_configs, metrics.clone());
+        ConnectorFactory(ConnectorFactoryInner::StaticPrefixed(f), None, None)
+    }
+
+    /// Injects faults into all connections made by this factory's connectors.
+    pub fn with_chaos(mut self, chaos: Chaos) -> ConnectorFactory {
+        self.1 = Some(chaos);
+        self
+    }
+
+    /// Bounds the bytes read ahead on each pooled connection by connectors that read
+    /// ahead, typically to the size of the buffer to which they are transferred.
+    pub fn with_read_ahead_limit(mut self, bytes: usize) -> ConnectorFactory {
+        self.2 = Some(bytes);
+        self
     }
 
     pub fn mk_connector(&self, dst_name: &Path) -> config::Result<Connector> {
-        match self.0 {
-            ConnectorFactoryInner::StaticGlobal(ref c) => Ok(c.clone()),
-            ConnectorFactoryInner::StaticPrefixed(ref f) => f.mk_connector(dst_name),
+        let mut connector = match self.0 {
+            ConnectorFactoryInner::StaticGlobal(ref c) => c.clone(),
+            ConnectorFactoryInner::StaticPrefixed(ref f) => f.mk_connector(dst_name)?,
+        };
+        connector.chaos = self.1.clone();
+        if let Some(bytes) = self.2 {
+            connector.read_ahead_limit = bytes;
         }
+        Ok(connector)
     }
 }
 
-struct StaticPrefixConnectorFactory(Vec<(Path, ConnectorConfig)>);
+struct StaticPrefixConnectorFactory(Vec<(Path, ConnectorConfig)>, metrics::Scope);
 impl StaticPrefixConnectorFactory {
     /// Builds a new connector by applying all configurations with a matching prefix.
     fn mk_connector(&self, dst_name: &Path) -> config::Result<Connector> {
@@ -54,37 +109,339 @@ impl StaticPrefixConnectorFactory {
                 config.update(c);
             }
         }
-        config.mk_connector()
+        config.mk_connector_with_metrics(&self.1)
     }
 }
 
-#[derive(Clone)]
-pub struct Tls {
-    name: String,
-    config: Arc<RustlsClientConfig>,
+#[cfg(feature = "tls")]
+pub use self::tls::{CaOnlyVerifier, Handshake, Tls};
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::reload::SharedConfig;
+    use super::super::connection::secure;
+    use super::super::connection::socket::{self, Socket};
+    use futures::{Async, Future, Poll};
+    use rustls;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use tokio_core::net::TcpStream;
+    use untrusted;
+    use webpki;
+
+    #[derive(Clone)]
+    pub struct Tls {
+        /// The server name used for handshakes, unless a downstream client's server name
+        /// is propagated.
+        pub name: String,
+        /// Shared by each clone, so that reloads apply to every destination.
+        pub config: Arc<SharedConfig>,
+        /// When set, the server name requested by the downstream client, if any, is used
+        /// for the upstream handshake.
+        pub propagate_sni: bool,
+    }
+
+    impl Tls {
+        pub fn propagates_sni(&self) -> bool {
+            self.propagate_sni
+        }
+
+        pub fn handshake(&self, tcp: TcpStream, sni: Option<&str>) -> Handshake {
+            let name = match sni {
+                Some(sni) if self.propagate_sni => sni,
+                _ => &self.name,
+            };
+            Handshake(secure::client_handshake(tcp, &self.config.get(), name))
+        }
+    }
+
+    pub struct Handshake(secure::ClientHandshake);
+    impl Future for Handshake {
+        type Item = Socket;
+        type Error = io::Error;
+        fn poll(&mut self) -> Poll<Socket, io::Error> {
+            let tls = try_ready!(self.0.poll());
+            Ok(Async::Ready(socket::secure_client(tls)))
+        }
+    }
+
+    static SUPPORTED_SIG_ALGS: &'static [&'static webpki::SignatureAlgorithm] = &[
+        &webpki::ECDSA_P256_SHA256,
+        &webpki::ECDSA_P256_SHA384,
+        &webpki::ECDSA_P384_SHA256,
+        &webpki::ECDSA_P384_SHA384,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA384,
+        &webpki::RSA_PKCS1_2048_8192_SHA512,
+        &webpki::RSA_PKCS1_3072_8192_SHA384,
+    ];
+
+    /// Verifies that a server's certificate chains to a trusted root, without requiring
+    /// that it be valid for the server name used in the handshake.
+    pub struct CaOnlyVerifier;
+
+    impl rustls::ServerCertVerifier for CaOnlyVerifier {
+        fn verify_server_cert(
+            &self,
+            roots: &rustls::RootCertStore,
+            presented_certs: &[rustls::Certificate],
+            _dns_name: &str,
+        ) -> Result<(), rustls::TLSError> {
+            let (end_entity, intermediates) = match presented_certs.split_first() {
+                Some(certs) => certs,
+                None => return Err(rustls::TLSError::NoCertificatesPresented),
+            };
+            let cert = webpki::EndEntityCert::from(untrusted::Input::from(&end_entity.0))
+                .map_err(rustls::TLSError::WebPKIError)?;
+            let chain = intermediates
+                .iter()
+                .map(|c| untrusted::Input::from(&c.0))
+                .collect::<Vec<_>>();
+            let anchors = roots
+                .roots
+                .iter()
+                .map(|r| r.to_trust_anchor())
+                .collect::<Vec<_>>();
+            let now = webpki::Time::try_from(SystemTime::now()).map_err(|_| {
+                rustls::TLSError::FailedToGetCurrentTime
+            })?;
+            cert.verify_is_valid_tls_server_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TLSServerTrustAnchors(&anchors),
+                &chain,
+                now,
+            ).map_err(rustls::TLSError::WebPKIError)
+        }
+    }
 }
 
+/// Stands in for client TLS configuration when built without TLS support.
+///
+/// Configurations including TLS fail to load, so this can never be constructed.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub enum Tls {}
+
+#[cfg(not(feature = "tls"))]
 impl Tls {
-    fn handshake(&self, tcp: TcpStream) -> secure::ClientHandshake {
-        secure::client_handshake(tcp, &self.config, &self.name)
+    fn propagates_sni(&self) -> bool {
+        match *self {}
+    }
+
+    fn handshake(&self, _tcp: TcpStream, _sni: Option<&str>) -> Handshake {
+        match *self {}
+    }
+}
+
+/// Stands in for a client TLS handshake when built without TLS support.
+#[cfg(not(feature = "tls"))]
+pub enum Handshake {}
+
+#[cfg(not(feature = "tls"))]
+impl Future for Handshake {
+    type Item = Socket;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Socket, io::Error> {
+        match *self {}
     }
 }
 
+/// Prefers endpoints whose `meta_key` metadata matches `zone`.
+#[derive(Clone, Debug)]
+pub struct Locality {
+    pub zone: String,
+    pub meta_key: String,
+    /// Spill over to other zones when the local endpoints' average load exceeds the
+    /// global average load by this factor.
+    pub spillover_load_factor: f64,
+}
+
+/// Remembers the endpoint to which each client was last dispatched.
+#[derive(Clone, Debug)]
+pub struct Stickiness {
+    /// A client's endpoint is forgotten once it has not been used for this long.
+    pub ttl: time::Duration,
+    /// Bounds the number of clients remembered.
+    pub max_entries: usize,
+    /// A client's endpoint is not used while its load exceeds the average load by this
+    /// factor.
+    pub max_load_factor: f64,
+}
+
+/// Controls how endpoints are removed from, and returned to, service after failing.
+#[derive(Clone, Debug)]
+pub struct FailFast {
+    /// Endpoints are failed after this many consecutive connection failures.
+    pub max_consecutive_failures: usize,
+    /// How long an endpoint is initially failed before being tried again.
+    pub penalty: time::Duration,
+    /// Bounds the penalty, which doubles each time an endpoint fails while on probation.
+    pub max_penalty: time::Duration,
+    /// The number of consecutive successful connections required of an endpoint on
+    /// probation before it is fully reinstated.
+    pub success_threshold: usize,
+}
+
+/// Ramps up the weight of endpoints newly added to a destination, so that they are not
+/// immediately sent their full share of connections while cold.
+#[derive(Clone, Copy, Debug)]
+pub struct SlowStart {
+    /// How long a new endpoint's weight takes to reach its full value.
+    pub window: time::Duration,
+    /// Endpoints that return within this long of being removed skip slow start.
+    pub endpoint_memory: time::Duration,
+}
+
+/// Chooses endpoints by the peak EWMA of their connect latencies.
+#[derive(Clone, Copy, Debug)]
+pub struct Ewma {
+    /// The time constant over which latency estimates decay.
+    pub decay: time::Duration,
+}
+
+/// Closes the oldest connections to endpoints that hold far more than their weighted
+/// share of a destination's open connections, so that clients reconnect according to
+/// the endpoints' current weights.
+#[derive(Clone, Copy, Debug)]
+pub struct Rebalance {
+    /// How often endpoints' shares of open connections are checked.
+    pub check_interval: time::Duration,
+    /// An endpoint is rebalanced when its share of open connections exceeds its share
+    /// of the total weight by this factor.
+    pub max_skew_ratio: f64,
+    /// The proportion of open connections, in (0, 1], that may be closed per check.
+    pub max_close_ratio: f64,
+    /// When set, data that has already been read is written before both peers' writes
+    /// are shut down. Otherwise, connections are closed immediately.
+    pub close_gracefully: bool,
+}
+
+/// Stops dispatching connections to a destination whose connection attempts are
+/// failing.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerPolicy {
+    /// The period over which connection attempts are counted.
+    pub window: time::Duration,
+    /// The circuit may only open once this many attempts have been made in the window.
+    pub min_requests: usize,
+    /// The proportion of failed attempts, in (0, 1], at which the circuit opens.
+    pub failure_rate_threshold: f64,
+    /// How long the circuit stays open before half-opening.
+    pub open_duration: time::Duration,
+    /// The proportion of connections, in (0, 1], admitted while half-open.
+    pub probe_ratio: f64,
+}
+
+/// Delays reconnecting to an endpoint after its connection attempts fail.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectBackoff {
+    /// The delay after a single failure.
+    pub base: time::Duration,
+    /// Bounds the delay, which doubles with each consecutive failure.
+    pub max: time::Duration,
+}
+
+impl ConnectBackoff {
+    /// The delay after `failures` consecutive failures, before jitter is applied.
+    pub fn delay(&self, failures: u32) -> time::Duration {
+        // The delay reaches any reasonable maximum well before the multiplier overflows.
+        let exp = cmp::min(failures.saturating_sub(1), 16);
+        cmp::min(self.base * (1u32 << exp), self.max)
+    }
+
+    /// The delay after `failures` consecutive failures, uniformly jittered between half
+    /// of and the full delay so that proxies do not reconnect in lockstep.
+    pub fn jittered_delay<R: Rng>(&self, failures: u32, rng: &mut R) -> time::Duration {
+        let delay = self.delay(failures);
+        let ms = delay.as_secs() * 1_000 + (delay.subsec_nanos() / 1_000_000) as u64;
+        time::Duration::from_millis(ms / 2 + rng.gen_range(0, ms / 2 + 1))
+    }
+}
+
+/// Static endpoints to which connections are dispatched while none of a destination's
+/// resolved endpoints are available.
+#[derive(Clone, Debug)]
+pub struct FallbackPolicy {
+    /// Hostnames are resolved by the balancer.
+    pub addrs: Vec<HostPort>,
+    /// How long no resolved endpoints must be available before the fallback is used.
+    pub activate_after: time::Duration,
+}
+
+/// Governs connections that are established ahead of demand (see `minConnections`) and
+/// held until they are dispatched.
+#[derive(Clone, Debug, Default)]
+pub struct PoolPolicy {
+    /// Idle connections are closed after this long.
+    pub idle_timeout: Option<time::Duration>,
+    /// Connections older than this are closed instead of being dispatched.
+    pub max_lifetime: Option<time::Duration>,
+    /// When set, connections that have been closed by their peer are not dispatched.
+    pub validate_before_reuse: bool,
+}
+
 fn new(
     connect_timeout: Option<time::Duration>,
     tls: Option<Tls>,
     max_waiters: usize,
     min_connections: usize,
-    fail_limit: usize,
-    fail_penalty: time::Duration,
+    fail_fast: FailFast,
+    locality: Option<Locality>,
+    circuit_breaker: Option<CircuitBreakerPolicy>,
+    pool: PoolPolicy,
+    connect_backoff: Option<ConnectBackoff>,
+    fallback: Option<FallbackPolicy>,
+    endpoint_metrics: bool,
+    meta_labels: Vec<String>,
+    endpoint_filter: Option<EndpointFilter>,
+    slow_start: Option<SlowStart>,
+    marking: Marking,
+    ewma: Option<Ewma>,
+    rebalance: Option<Rebalance>,
+    stats_window: time::Duration,
+    subsetting: Option<Subsetting>,
+    log_suppress: time::Duration,
+    readiness_probe: Option<ReadinessProbe>,
+    max_concurrent_dispatches: Option<usize>,
+    stickiness: Option<Stickiness>,
+    selection_trace: Option<f64>,
+    preamble: Option<Preamble>,
+    read_ahead: bool,
+    update_damping: Option<UpdateDamping>,
+    weight_mode: WeightMode,
 ) -> Connector {
     Connector {
         connect_timeout,
         tls,
         max_waiters,
         min_connections,
-        fail_limit,
-        fail_penalty,
+        fail_fast,
+        locality,
+        circuit_breaker,
+        pool,
+        connect_backoff,
+        fallback,
+        endpoint_metrics,
+        meta_labels,
+        endpoint_filter,
+        slow_start,
+        marking,
+        ewma,
+        rebalance,
+        stats_window,
+        subsetting,
+        log_suppress,
+        readiness_probe,
+        max_concurrent_dispatches,
+        stickiness,
+        selection_trace,
+        preamble,
+        read_ahead,
+        read_ahead_limit: DEFAULT_READ_AHEAD_BYTES,
+        update_damping,
+        weight_mode,
+        chaos: None,
     }
 }
 
@@ -94,60 +451,357 @@ pub struct Connector {
     tls: Option<Tls>,
     max_waiters: usize,
     min_connections: usize,
-    fail_limit: usize,
-    fail_penalty: time::Duration,
+    fail_fast: FailFast,
+    locality: Option<Locality>,
+    circuit_breaker: Option<CircuitBreakerPolicy>,
+    pool: PoolPolicy,
+    connect_backoff: Option<ConnectBackoff>,
+    fallback: Option<FallbackPolicy>,
+    endpoint_metrics: bool,
+    /// The metadata keys by which endpoint metrics are labeled.
+    meta_labels: Vec<String>,
+    endpoint_filter: Option<EndpointFilter>,
+    slow_start: Option<SlowStart>,
+    marking: Marking,
+    ewma: Option<Ewma>,
+    rebalance: Option<Rebalance>,
+    stats_window: time::Duration,
+    subsetting: Option<Subsetting>,
+    log_suppress: time::Duration,
+    readiness_probe: Option<ReadinessProbe>,
+    max_concurrent_dispatches: Option<usize>,
+    stickiness: Option<Stickiness>,
+    /// The fraction of endpoint selections that are traced, if any are.
+    selection_trace: Option<f64>,
+    preamble: Option<Preamble>,
+    /// When set, the first bytes sent by endpoints on pooled connections are read and
+    /// buffered before the connections are dispatched.
+    read_ahead: bool,
+    read_ahead_limit: usize,
+    update_damping: Option<UpdateDamping>,
+    weight_mode: WeightMode,
+    chaos: Option<Chaos>,
 }
 
 impl Connector {
+    pub fn connect_timeout(&self) -> Option<time::Duration> {
+        self.connect_timeout
+    }
+
     pub fn max_waiters(&self) -> usize {
         self.max_waiters
     }
 
+    /// Limits the connections that may be waiting for or establishing an upstream
+    /// connection at once, if set.
+    pub fn max_concurrent_dispatches(&self) -> Option<usize> {
+        self.max_concurrent_dispatches
+    }
+
     pub fn min_connections(&self) -> usize {
         self.min_connections
     }
 
-    pub fn failure_limit(&self) -> usize {
-        self.fail_limit
+    pub fn fail_fast(&self) -> &FailFast {
+        &self.fail_fast
+    }
+
+    pub fn locality(&self) -> Option<&Locality> {
+        self.locality.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreakerPolicy> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub fn pool(&self) -> &PoolPolicy {
+        &self.pool
     }
-    pub fn failure_penalty(&self) -> time::Duration {
-        self.fail_penalty
+
+    pub fn connect_backoff(&self) -> Option<&ConnectBackoff> {
+        self.connect_backoff.as_ref()
+    }
+
+    pub fn fallback(&self) -> Option<&FallbackPolicy> {
+        self.fallback.as_ref()
+    }
+
+    pub fn stickiness(&self) -> Option<&Stickiness> {
+        self.stickiness.as_ref()
     }
 
-    fn timeout<F>(&self, fut: F, timer: &Timer) -> Box<Future<Item = F::Item, Error = io::Error>>
-    where
-        F: Future<Error = io::Error> + 'static,
-    {
-        match self.connect_timeout {
-            None => Box::new(fut),
-            Some(t) => Box::new(timer.timeout(fut, t).map_err(|e| e.into())),
+    pub fn selection_trace(&self) -> Option<f64> {
+        self.selection_trace
+    }
+
+    /// Bounds the bytes read ahead on each pooled connection, if connections are read
+    /// ahead.
+    pub fn read_ahead(&self) -> Option<usize> {
+        if self.read_ahead {
+            Some(self.read_ahead_limit)
+        } else {
+            None
         }
     }
 
-    pub fn connect(&self, addr: &net::SocketAddr, reactor: &Handle, timer: &Timer) -> Connecting {
-        let tcp = TcpStream::connect(addr, reactor);
-        let socket: Box<Future<Item = Socket, Error = io::Error>> = match self.tls {
-            None => {
-                let f = tcp.map(socket::plain);
-                Box::new(self.timeout(f, timer))
-            }
-            Some(ref tls) => {
-                let tls = tls.clone();
-                let f = tcp.and_then(move |tcp| tls.handshake(tcp)).map(
-                    socket::secure_client,
-                );
-                Box::new(self.timeout(f, timer))
+    pub fn endpoint_metrics(&self) -> bool {
+        self.endpoint_metrics
+    }
+
+    pub fn update_damping(&self) -> Option<&UpdateDamping> {
+        self.update_damping.as_ref()
+    }
+
+    pub fn weight_mode(&self) -> WeightMode {
+        self.weight_mode
+    }
+
+    pub fn meta_labels(&self) -> &[String] {
+        &self.meta_labels
+    }
+
+    pub fn endpoint_filter(&self) -> Option<&EndpointFilter> {
+        self.endpoint_filter.as_ref()
+    }
+
+    pub fn slow_start(&self) -> Option<&SlowStart> {
+        self.slow_start.as_ref()
+    }
+
+    pub fn ewma(&self) -> Option<&Ewma> {
+        self.ewma.as_ref()
+    }
+
+    pub fn rebalance(&self) -> Option<&Rebalance> {
+        self.rebalance.as_ref()
+    }
+
+    pub fn stats_window(&self) -> time::Duration {
+        self.stats_window
+    }
+
+    pub fn subsetting(&self) -> Option<&Subsetting> {
+        self.subsetting.as_ref()
+    }
+
+    /// The window over which each endpoint's repeated failures are summarized.
+    pub fn log_suppress(&self) -> time::Duration {
+        self.log_suppress
+    }
+
+    /// Determines whether connections should be established with the TLS server name
+    /// requested by downstream clients.
+    pub fn propagates_sni(&self) -> bool {
+        self.tls.as_ref().map(|tls| tls.propagates_sni()).unwrap_or(false)
+    }
+
+    /// Connects to `addr`, using `sni` as the TLS server name if the downstream client's
+    /// server name is propagated.
+    ///
+    /// When a preamble is configured, it is written once the connection (and any TLS
+    /// handshake) is established, and the connection only completes once the endpoint
+    /// has responded to it. When a readiness probe is configured, the connection only
+    /// completes once the endpoint has then sent the expected bytes.
+    ///
+    /// When chaos is enabled, the connection may fail without connecting, be delayed, or
+    /// be reset once established.
+    pub fn connect(
+        &self,
+        addr: &net::SocketAddr,
+        reactor: &Handle,
+        timer: &Timer,
+        sni: Option<&str>,
+    ) -> Connecting {
+        let dropped = self.chaos.as_ref().map(|c| c.drop_connect()).unwrap_or(false);
+        let mut tcp: TcpConnect = if dropped {
+            let e = io::Error::new(io::ErrorKind::ConnectionRefused, "dropped by chaos");
+            Box::new(future::err(e))
+        } else if self.marking.is_empty() {
+            Box::new(TcpStream::connect(addr, reactor))
+        } else {
+            // The socket is marked before it connects, so that the handshake is marked too.
+            match self.marking.socket(addr) {
+                Ok(sock) => Box::new(TcpStream::connect_stream(sock, addr, reactor)),
+                Err(e) => Box::new(future::err(e)),
             }
         };
-        Connecting(socket)
+        let mut reset = None;
+        match self.chaos {
+            Some(ref chaos) if !dropped => {
+                if let Some(latency) = chaos.connect_latency() {
+                    let timer = timer.clone();
+                    tcp = Box::new(tcp.and_then(move |tcp| {
+                        timer.sleep(latency).then(move |_| Ok(tcp))
+                    }));
+                }
+                reset = chaos.reset_after();
+            }
+            _ => {}
+        }
+        let tls = self.tls
+            .as_ref()
+            .map(|tls| (tls.clone(), sni.map(|s| s.to_owned())));
+        Connecting {
+            connect: timeout(ConnectState::Tcp(tcp, tls), self.connect_timeout, timer),
+            preamble: self.preamble.clone(),
+            sending: None,
+            probe: self.readiness_probe.clone(),
+            probing: None,
+            timer: timer.clone(),
+            reset,
+        }
     }
 }
 
-pub struct Connecting(Box<Future<Item = Socket, Error = io::Error>>);
+/// Establishes a connection, including a TLS handshake if one is configured, sends a
+/// preamble if one is configured, and waits for the endpoint to become ready if a
+/// readiness probe is configured.
+pub struct Connecting {
+    connect: Timeout<ConnectState>,
+    /// Taken once the connection is established.
+    preamble: Option<Preamble>,
+    sending: Option<Timeout<Sending>>,
+    /// Taken once the connection is established and any preamble has been sent.
+    probe: Option<ReadinessProbe>,
+    probing: Option<Timeout<Probing>>,
+    timer: Timer,
+    /// Set when chaos has chosen to reset the connection after some bytes.
+    reset: Option<(usize, Arc<metrics::Counter>)>,
+}
+
 impl Future for Connecting {
     type Item = Socket;
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+        let mut socket = try_ready!(self.poll_ready());
+        if let Some((bytes, resets)) = self.reset.take() {
+            socket.reset_after(bytes, resets);
+        }
+        Ok(Async::Ready(socket))
+    }
+}
+
+impl Connecting {
+    fn poll_ready(&mut self) -> Poll<Socket, io::Error> {
+        loop {
+            if let Some(ref mut probing) = self.probing {
+                return probing.poll();
+            }
+            let socket = if self.sending.is_some() {
+                let sending = self.sending.as_mut().expect("preamble must be started");
+                try_ready!(sending.poll())
+            } else {
+                let socket = try_ready!(self.connect.poll());
+                if let Some(preamble) = self.preamble.take() {
+                    let sending = preamble.send(socket);
+                    self.sending = Some(timeout(sending, Some(preamble.timeout), &self.timer));
+                    continue;
+                }
+                socket
+            };
+            match self.probe.take() {
+                None => return Ok(Async::Ready(socket)),
+                Some(probe) => {
+                    let probing = probe.probe(socket);
+                    self.probing = Some(timeout(probing, Some(probe.timeout), &self.timer));
+                }
+            }
+        }
+    }
+}
+
+type TcpConnect = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+enum ConnectState {
+    /// Establishing a TCP connection, to be followed by a TLS handshake with the given
+    /// configuration and server name.
+    Tcp(TcpConnect, Option<(Tls, Option<String>)>),
+    Handshaking(Handshake),
+    Done,
+}
+
+impl Future for ConnectState {
+    type Item = Socket;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Socket, io::Error> {
+        loop {
+            // Either the connection is established or the handshake must begin.
+            let next = match *self {
+                ConnectState::Tcp(ref mut tcp, ref mut tls) => {
+                    let tcp = try_ready!(tcp.poll());
+                    match tls.take() {
+                        None => Ok(socket::plain(tcp)),
+                        Some((tls, sni)) => {
+                            let hs = tls.handshake(tcp, sni.as_ref().map(|s| s.as_str()));
+                            Err(ConnectState::Handshaking(hs))
+                        }
+                    }
+                }
+                ConnectState::Handshaking(ref mut hs) => Ok(try_ready!(hs.poll())),
+                ConnectState::Done => panic!("poll must not be called after completion"),
+            };
+            match next {
+                Ok(socket) => {
+                    *self = ConnectState::Done;
+                    return Ok(Async::Ready(socket));
+                }
+                Err(state) => *self = state,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectBackoff;
+    use rand::{SeedableRng, StdRng};
+    use std::cmp;
+    use std::time::Duration;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn connect_backoff(base_ms: u64, max_ms: u64) -> ConnectBackoff {
+        ConnectBackoff {
+            base: ms(base_ms),
+            max: ms(max_ms),
+        }
+    }
+
+    #[test]
+    fn doubles_connect_backoff_up_to_its_maximum() {
+        let backoff = connect_backoff(100, 1_000);
+        let delays: Vec<Duration> = (1..7).map(|n| backoff.delay(n)).collect();
+        assert_eq!(
+            delays,
+            vec![ms(100), ms(200), ms(400), ms(800), ms(1_000), ms(1_000)]
+        );
+        // The delay does not overflow, however many failures there have been.
+        assert_eq!(backoff.delay(u32::max_value()), ms(1_000));
+    }
+
+    #[test]
+    fn jitters_connect_backoff_between_half_and_all_of_the_delay() {
+        let backoff = connect_backoff(100, 1_000);
+        let mut rng = StdRng::from_seed(&[7][..]);
+        for failures in 1..6 {
+            let delay = backoff.delay(failures);
+            let (mut shortest, mut longest) = (delay, ms(0));
+            for _ in 0..1_000 {
+                let jittered = backoff.jittered_delay(failures, &mut rng);
+                assert!(
+                    delay / 2 <= jittered && jittered <= delay,
+                    "{:?} is not within {:?}",
+                    jittered,
+                    delay
+                );
+                shortest = cmp::min(shortest, jittered);
+                longest = cmp::max(longest, jittered);
+            }
+            // The whole range is used.
+            assert!(shortest < delay * 6 / 10, "{:?} jittered no lower than {:?}", delay, shortest);
+            assert!(longest > delay * 9 / 10, "{:?} jittered no higher than {:?}", delay, longest);
+        }
     }
 }
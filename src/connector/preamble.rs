@@ -0,0 +1,109 @@
+use super::super::connection::socket::Socket;
+use futures::{Async, Future, Poll};
+use std::{io, time};
+use std::io::{Read, Write};
+
+/// Sends bytes on each new connection before it is used, for endpoints that expect an
+/// application-level hello that proxied clients do not know to send.
+///
+/// The preamble is opaque: bytes are written and, optionally, a fixed number of bytes
+/// are read in response, without being interpreted.
+#[derive(Clone, Debug)]
+pub struct Preamble {
+    /// The bytes written once the connection is established.
+    pub bytes: Vec<u8>,
+    /// The number of bytes the endpoint must send in response before the connection is
+    /// used. When 0, the connection is used as soon as the preamble is written.
+    pub await_response_bytes: usize,
+    /// When set, the response is sent on to the client. Otherwise, it is discarded.
+    pub forward_response: bool,
+    /// How long the endpoint has to accept the preamble and respond.
+    pub timeout: time::Duration,
+}
+
+impl Preamble {
+    /// Writes the preamble to `socket` and awaits the endpoint's response.
+    pub fn send(&self, socket: Socket) -> Sending {
+        Sending {
+            socket: Some(socket),
+            bytes: self.bytes.clone(),
+            written: 0,
+            flushed: false,
+            response: vec![0u8; self.await_response_bytes],
+            read: 0,
+            forward_response: self.forward_response,
+        }
+    }
+}
+
+/// Writes a preamble to a new connection and reads the endpoint's response.
+pub struct Sending {
+    socket: Option<Socket>,
+    bytes: Vec<u8>,
+    written: usize,
+    flushed: bool,
+    /// Sized to the expected response, so that nothing the endpoint sends after it is
+    /// read.
+    response: Vec<u8>,
+    read: usize,
+    forward_response: bool,
+}
+
+impl Future for Sending {
+    type Item = Socket;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Socket, io::Error> {
+        {
+            let socket = self.socket.as_mut().expect(
+                "poll must not be called after completion",
+            );
+            while self.written < self.bytes.len() {
+                match socket.write(&self.bytes[self.written..]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "endpoint did not accept the preamble",
+                        ));
+                    }
+                    Ok(sz) => self.written += sz,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if !self.flushed {
+                match socket.flush() {
+                    Ok(()) => self.flushed = true,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            while self.read < self.response.len() {
+                match socket.read(&mut self.response[self.read..]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "endpoint closed the connection before responding to the preamble",
+                        ));
+                    }
+                    Ok(sz) => self.read += sz,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        let mut socket = self.socket.take().expect(
+            "poll must not be called after completion",
+        );
+        if self.forward_response && !self.response.is_empty() {
+            socket.replay(self.response.split_off(0));
+        }
+        Ok(Async::Ready(socket))
+    }
+}
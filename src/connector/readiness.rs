@@ -0,0 +1,82 @@
+use super::super::connection::socket::Socket;
+use futures::{Async, Future, Poll};
+use std::{cmp, io, time};
+use std::io::Read;
+
+/// The most bytes read from an endpoint while waiting for it to become ready.
+const PROBE_READ_BYTES: usize = 4 * 1024;
+
+/// Requires that endpoints send data on each new connection before it is used, so that
+/// endpoints that accept connections without serving them are treated as failing.
+///
+/// Only suitable for protocols in which the server speaks first.
+#[derive(Clone, Debug)]
+pub struct ReadinessProbe {
+    /// The bytes with which each connection's data must begin. When empty, any byte
+    /// suffices.
+    pub banner: Vec<u8>,
+    /// How long an endpoint has to send its first bytes once connected.
+    pub timeout: time::Duration,
+}
+
+impl ReadinessProbe {
+    /// Waits for `socket`'s endpoint to become ready. The bytes read are replayed by the
+    /// returned socket.
+    pub fn probe(&self, socket: Socket) -> Probing {
+        Probing {
+            socket: Some(socket),
+            banner: self.banner.clone(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Reads from a new connection until its endpoint has sent the expected bytes.
+pub struct Probing {
+    socket: Option<Socket>,
+    banner: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl Future for Probing {
+    type Item = Socket;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Socket, io::Error> {
+        let want = cmp::max(self.banner.len(), 1);
+        while self.buf.len() < want {
+            let mut chunk = [0u8; PROBE_READ_BYTES];
+            let sz = {
+                let socket = self.socket.as_mut().expect(
+                    "poll must not be called after completion",
+                );
+                match socket.read(&mut chunk) {
+                    Ok(sz) => sz,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            if sz == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "endpoint closed the connection before it was ready",
+                ));
+            }
+            self.buf.extend_from_slice(&chunk[..sz]);
+            let n = cmp::min(self.buf.len(), self.banner.len());
+            if self.buf[..n] != self.banner[..n] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "endpoint did not send the expected banner",
+                ));
+            }
+        }
+
+        let mut socket = self.socket.take().expect(
+            "poll must not be called after completion",
+        );
+        socket.replay(self.buf.split_off(0));
+        Ok(Async::Ready(socket))
+    }
+}
@@ -0,0 +1,122 @@
+//! Reloads the TLS configuration of upstream connections as its files change, so that
+//! trust roots and client certificates may be rotated without a restart.
+//!
+//! Files are checked as connections are established, at most once per reload interval:
+//! when the modification time or length of any file differs from when it was last
+//! loaded, the configuration is reloaded and later handshakes use it. Handshakes that
+//! have begun complete with the configuration they began with. A configuration that
+//! fails to load is logged and counted as `tls_reload_failures`, and the previous
+//! configuration remains in use; loading is retried at each interval until it succeeds.
+//! Successful reloads are counted as `tls_reloads`.
+//!
+//! Connectors are cloned for each destination, so each clone shares its configuration
+//! through a `SharedConfig`.
+
+use super::config::TlsConnectorFactoryConfig;
+use super::super::metrics;
+use rustls::ClientConfig;
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A client configuration that may be replaced as its files change.
+pub struct SharedConfig {
+    current: RwLock<Arc<ClientConfig>>,
+    /// Unset when the configuration is not reloaded.
+    reload: Option<Mutex<Reload>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: ClientConfig, reload: Option<Reload>) -> SharedConfig {
+        SharedConfig {
+            current: RwLock::new(Arc::new(config)),
+            reload: reload.map(Mutex::new),
+        }
+    }
+
+    /// The configuration for a new handshake, reloading it first if its files are due
+    /// to be checked and have changed.
+    pub fn get(&self) -> Arc<ClientConfig> {
+        if let Some(ref reload) = self.reload {
+            let reloaded = reload.lock().expect("tls reload lock poisoned").poll(
+                Instant::now(),
+            );
+            if let Some(config) = reloaded {
+                *self.current.write().expect("tls config lock poisoned") = Arc::new(config);
+            }
+        }
+        self.current.read().expect("tls config lock poisoned").clone()
+    }
+}
+
+/// The modification time and length of a file.
+type Stamp = (SystemTime, u64);
+
+/// Checks a configuration's files for changes.
+pub struct Reload {
+    config: TlsConnectorFactoryConfig,
+    interval: Duration,
+    next_check: Instant,
+    /// The stamps of the configuration's files when they were last loaded.
+    stamps: Vec<Option<Stamp>>,
+    reloads: Arc<metrics::Counter>,
+    failures: Arc<metrics::Counter>,
+}
+
+impl Reload {
+    /// Checks the files of `config`, which has just been loaded, every `interval`.
+    pub fn new(
+        config: &TlsConnectorFactoryConfig,
+        interval: Duration,
+        metrics: &metrics::Scope,
+    ) -> Reload {
+        Reload {
+            config: config.clone(),
+            interval,
+            next_check: Instant::now() + interval,
+            stamps: stamps(config),
+            reloads: metrics.counter("reloads"),
+            failures: metrics.counter("reload_failures"),
+        }
+    }
+
+    /// Loads the configuration if it is due to be checked and its files have changed.
+    fn poll(&mut self, now: Instant) -> Option<ClientConfig> {
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + self.interval;
+
+        let stamps = stamps(&self.config);
+        if stamps == self.stamps {
+            return None;
+        }
+        match self.config.load() {
+            Ok(config) => {
+                info!("reloaded TLS configuration for {}", self.config.dns_name);
+                self.stamps = stamps;
+                self.reloads.incr(1);
+                Some(config)
+            }
+            Err(e) => {
+                warn!(
+                    "failed to reload TLS configuration for {}: {:?}",
+                    self.config.dns_name,
+                    e
+                );
+                self.failures.incr(1);
+                None
+            }
+        }
+    }
+}
+
+fn stamps(config: &TlsConnectorFactoryConfig) -> Vec<Option<Stamp>> {
+    config
+        .paths()
+        .iter()
+        .map(|p| {
+            fs::metadata(p).ok().and_then(|m| m.modified().ok().map(|t| (t, m.len())))
+        })
+        .collect()
+}
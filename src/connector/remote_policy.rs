@@ -0,0 +1,149 @@
+//! Overrides a destination's balancer and connector settings with policy that the
+//! control plane publishes in the metadata of namerd's responses.
+//!
+//! A router honors only the keys it allows with `acceptRemotePolicy`. Each value is
+//! validated as its configured counterpart would be, and keys that are not allowed, not
+//! known, or not valid are ignored. An override holds until namerd stops sending it, at
+//! which point the configured setting is restored.
+
+use super::{Connector, Ewma, LoadBalancerKind};
+use super::config::DEFAULT_EWMA_DECAY_SECS;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+/// The metadata keys that may carry remote policy.
+pub const REMOTE_POLICY_KEYS: &'static [&'static str] = &[
+    "loadBalancer",
+    "loadBalancer.decaySecs",
+    "failureAccrual.maxFailures",
+    "failureAccrual.penaltySecs",
+    "failureAccrual.successThreshold",
+    "connectTimeoutMs",
+];
+
+/// The overrides that namerd publishes for a destination.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RemotePolicy {
+    load_balancer: Option<LoadBalancerKind>,
+    ewma_decay: Option<Duration>,
+    max_failures: Option<usize>,
+    failure_penalty: Option<Duration>,
+    success_threshold: Option<usize>,
+    /// A timeout of 0 disables the connect timeout, as `connectTimeoutMs: 0` does.
+    connect_timeout: Option<Duration>,
+    /// The entries from which the overrides were parsed, as namerd sent them.
+    entries: BTreeMap<String, String>,
+}
+
+impl RemotePolicy {
+    /// Parses the entries of `meta` whose keys are `allowed`, returning the policy along
+    /// with the keys that were ignored, in order.
+    pub fn parse(
+        meta: &HashMap<String, String>,
+        allowed: &BTreeSet<String>,
+    ) -> (RemotePolicy, Vec<String>) {
+        let mut policy = RemotePolicy::default();
+        let mut ignored = Vec::new();
+        let meta: BTreeMap<&String, &String> = meta.iter().collect();
+        for (k, v) in meta {
+            if !allowed.contains(k) || !policy.set(k, v) {
+                ignored.push(k.clone());
+                continue;
+            }
+            policy.entries.insert(k.clone(), v.clone());
+        }
+        (policy, ignored)
+    }
+
+    /// Sets the override named by `key`, returning false if the key is not known or its
+    /// value is not valid.
+    fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "loadBalancer" => {
+                self.load_balancer = match value {
+                    "io.l5d.leastLoaded" => Some(LoadBalancerKind::LeastLoaded),
+                    "io.l5d.ewma" => Some(LoadBalancerKind::Ewma),
+                    _ => return false,
+                };
+            }
+            "loadBalancer.decaySecs" => {
+                self.ewma_decay = match value.parse::<u64>() {
+                    Ok(s) if s > 0 => Some(Duration::from_secs(s)),
+                    _ => return false,
+                };
+            }
+            "failureAccrual.maxFailures" => {
+                self.max_failures = match value.parse::<usize>() {
+                    Ok(n) => Some(n),
+                    Err(_) => return false,
+                };
+            }
+            "failureAccrual.penaltySecs" => {
+                self.failure_penalty = match value.parse::<u64>() {
+                    Ok(s) => Some(Duration::from_secs(s)),
+                    Err(_) => return false,
+                };
+            }
+            "failureAccrual.successThreshold" => {
+                self.success_threshold = match value.parse::<usize>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return false,
+                };
+            }
+            "connectTimeoutMs" => {
+                self.connect_timeout = match value.parse::<u64>() {
+                    Ok(ms) => Some(Duration::from_millis(ms)),
+                    Err(_) => return false,
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The entries from which the overrides were parsed, by key.
+    pub fn entries(&self) -> &BTreeMap<String, String> {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Overrides the settings of `connector`, which should be configured as the
+    /// destination's connector is, so that overrides that are no longer sent revert.
+    pub fn apply(&self, connector: &mut Connector) {
+        match self.load_balancer {
+            Some(LoadBalancerKind::LeastLoaded) => connector.ewma = None,
+            Some(LoadBalancerKind::Ewma) => {
+                let decay = self.ewma_decay
+                    .or_else(|| connector.ewma.map(|e| e.decay))
+                    .unwrap_or_else(|| Duration::from_secs(DEFAULT_EWMA_DECAY_SECS));
+                connector.ewma = Some(Ewma { decay });
+            }
+            None => {
+                if let (Some(decay), Some(ewma)) = (self.ewma_decay, connector.ewma.as_mut()) {
+                    ewma.decay = decay;
+                }
+            }
+        }
+        if let Some(n) = self.max_failures {
+            connector.fail_fast.max_consecutive_failures = n;
+        }
+        if let Some(penalty) = self.failure_penalty {
+            connector.fail_fast.penalty = penalty;
+            connector.fail_fast.max_penalty = cmp::max(penalty, connector.fail_fast.max_penalty);
+        }
+        if let Some(n) = self.success_threshold {
+            connector.fail_fast.success_threshold = n;
+        }
+        if let Some(timeout) = self.connect_timeout {
+            connector.connect_timeout = if timeout == Duration::from_secs(0) {
+                None
+            } else {
+                Some(timeout)
+            };
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Deterministically chooses a subset of a destination's endpoints for each proxy.
+//!
+//! When a destination has many endpoints, each proxy need only balance over a few of
+//! them. Addresses are ranked by rendezvous hashing: each is hashed together with the
+//! proxy's seed, and the `size` highest-ranked addresses form the subset. An address's
+//! rank depends only on the address and the seed, so resolutions with the same members
+//! always produce the same subset, and an address removed from the subset is replaced
+//! by the next-ranked address without disturbing the others. Proxies with different
+//! seeds (by default, different hostnames) choose different subsets, spreading load
+//! across all endpoints.
+
+use std::{io, net};
+
+/// Limits a destination's endpoints to the `size` addresses ranked highest for `seed`.
+#[derive(Clone, Copy, Debug)]
+pub struct Subsetting {
+    pub size: usize,
+    pub seed: u64,
+}
+
+impl Subsetting {
+    /// Ranks `addr` for this proxy. The highest-ranked addresses form the subset.
+    pub fn rank(&self, addr: &net::SocketAddr) -> u64 {
+        let h = match *addr {
+            net::SocketAddr::V4(ref a) => fnv1a(FNV_OFFSET, &a.ip().octets()),
+            net::SocketAddr::V6(ref a) => fnv1a(FNV_OFFSET, &a.ip().octets()),
+        };
+        let port = addr.port();
+        let h = fnv1a(h, &[(port >> 8) as u8, port as u8]);
+        mix(h ^ mix(self.seed))
+    }
+}
+
+/// Derives a seed from this host's name, so that each host chooses its own subset.
+pub fn hostname_seed() -> io::Result<u64> {
+    let name = hostname()?;
+    Ok(stable_hash(name.as_bytes()))
+}
+
+/// Hashes `bytes` such that the hash is the same on every host and in every release.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, bytes)
+}
+
+/// This host's name.
+#[cfg(unix)]
+pub fn hostname() -> io::Result<String> {
+    use libc;
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> io::Result<String> {
+    use std::env;
+    env::var("COMPUTERNAME").map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes `bytes` with 64-bit FNV-1a, which is stable across releases and platforms so
+/// that every proxy ranks addresses alike.
+fn fnv1a(mut h: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+    h
+}
+
+/// Scrambles the bits of `z` (splitmix64's finalizer), so that similar addresses and
+/// seeds produce unrelated ranks.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,287 @@
+//! Damps changes to a destination's endpoints when its resolutions change rapidly.
+//!
+//! Each resolution replaces the previous one as the update to be applied, but updates are
+//! applied no more often than once per `min_apply_interval`; resolutions received in
+//! between are coalesced, and the latest wins.
+//!
+//! With flap detection, each resolution whose addresses differ from the previous
+//! resolution's is a transition. Once more than `max_transitions` occur within `window`,
+//! the destination is flapping: the union of the addresses resolved within the window is
+//! applied (so that endpoints are neither retired nor re-added as the resolutions
+//! alternate), and the latest resolution is applied only once no transitions have
+//! occurred for a whole window.
+
+use super::WeightedAddr;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How a destination's resolutions are damped.
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateDamping {
+    /// Updates are applied no more often than this.
+    pub min_apply_interval: Duration,
+    /// When set, the union of recent resolutions is applied while they flap.
+    pub flap_detection: Option<FlapDetection>,
+}
+
+/// Determines when a destination's resolutions are flapping.
+#[derive(Clone, Copy, Debug)]
+pub struct FlapDetection {
+    /// The time over which transitions are counted.
+    pub window: Duration,
+    /// Resolutions flap once more transitions than this occur within the window.
+    pub max_transitions: usize,
+}
+
+/// Decides which resolutions are applied to a destination's endpoints.
+pub struct Damper {
+    policy: UpdateDamping,
+    /// The most recent resolution.
+    latest: Option<Vec<WeightedAddr>>,
+    /// The addresses of the most recent resolution.
+    latest_addrs: HashSet<SocketAddr>,
+    /// The resolutions that were transitions within the flap window, oldest first.
+    transitions: VecDeque<(Instant, Vec<WeightedAddr>)>,
+    flapping: bool,
+    /// The most recently applied update, and when it was applied.
+    applied: Option<(Instant, Vec<WeightedAddr>)>,
+}
+
+impl Damper {
+    /// Damps resolutions according to `policy`.
+    pub fn new(policy: UpdateDamping) -> Damper {
+        Damper {
+            policy,
+            latest: None,
+            latest_addrs: HashSet::new(),
+            transitions: VecDeque::new(),
+            flapping: false,
+            applied: None,
+        }
+    }
+
+    /// Records a resolution received at `now`.
+    pub fn update(&mut self, addrs: Vec<WeightedAddr>, now: Instant) {
+        let members: HashSet<SocketAddr> = addrs.iter().map(|wa| wa.addr).collect();
+        let transition = self.latest.is_some() && members != self.latest_addrs;
+        self.latest_addrs = members;
+        if let Some(flap) = self.policy.flap_detection {
+            self.expire(now);
+            if transition {
+                self.transitions.push_back((now, addrs.clone()));
+                if self.transitions.len() > flap.max_transitions {
+                    self.flapping = true;
+                }
+            }
+        }
+        self.latest = Some(addrs);
+    }
+
+    /// Takes the update to be applied at `now`, if there is one.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<WeightedAddr>> {
+        self.expire(now);
+        if let Some(due) = self.due() {
+            if now < due {
+                return None;
+            }
+        }
+        let target = match self.target() {
+            None => return None,
+            Some(target) => target,
+        };
+        if self.applied.as_ref().map(|&(_, ref a)| a) == Some(&target) {
+            return None;
+        }
+        self.applied = Some((now, target.clone()));
+        Some(target)
+    }
+
+    /// Determines whether resolutions are flapping, as of the last update or poll.
+    pub fn is_flapping(&self) -> bool {
+        self.flapping
+    }
+
+    /// The time by which `poll` should be called again: when a coalesced update is due,
+    /// or when flapping may end.
+    pub fn next_poll(&self) -> Option<Instant> {
+        let pending = self.target().map_or(false, |t| {
+            self.applied.as_ref().map(|&(_, ref a)| a) != Some(&t)
+        });
+        let due = if pending { self.due() } else { None };
+        let stable = match (self.flapping, self.policy.flap_detection) {
+            (true, Some(flap)) => self.transitions.front().map(|&(t, _)| t + flap.window),
+            _ => None,
+        };
+        match (due, stable) {
+            (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// The time before which no further update may be applied.
+    fn due(&self) -> Option<Instant> {
+        self.applied.as_ref().map(|&(t, _)| t + self.policy.min_apply_interval)
+    }
+
+    /// Forgets transitions that have left the flap window, which ends flapping once
+    /// there are none.
+    fn expire(&mut self, now: Instant) {
+        let window = match self.policy.flap_detection {
+            None => return,
+            Some(flap) => flap.window,
+        };
+        while self.transitions.front().map_or(false, |&(t, _)| t + window <= now) {
+            self.transitions.pop_front();
+        }
+        if self.transitions.is_empty() {
+            self.flapping = false;
+        }
+    }
+
+    /// The update that should be applied: while flapping, the union of the applied
+    /// update and the resolutions within the window (with the latest weights);
+    /// otherwise, the latest resolution.
+    fn target(&self) -> Option<Vec<WeightedAddr>> {
+        let latest = match self.latest {
+            None => return None,
+            Some(ref latest) => latest,
+        };
+        if !self.flapping {
+            return Some(latest.clone());
+        }
+        let mut union = BTreeMap::new();
+        let applied = self.applied.iter().map(|&(_, ref a)| a);
+        let recent = self.transitions.iter().map(|&(_, ref a)| a);
+        for addrs in applied.chain(recent).chain(Some(latest)) {
+            for wa in addrs {
+                union.insert(wa.addr, wa.clone());
+            }
+        }
+        Some(union.into_iter().map(|(_, wa)| wa).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Damper, FlapDetection, UpdateDamping};
+    use WeightedAddr;
+    use std::time::{Duration, Instant};
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn addrs(ports: &[u16]) -> Vec<WeightedAddr> {
+        ports
+            .iter()
+            .map(|p| WeightedAddr::new(([127, 0, 0, 1], *p).into(), 1.0))
+            .collect()
+    }
+
+    fn ports(addrs: Option<Vec<WeightedAddr>>) -> Option<Vec<u16>> {
+        addrs.map(|addrs| {
+            let mut ports: Vec<u16> = addrs.iter().map(|wa| wa.addr.port()).collect();
+            ports.sort();
+            ports
+        })
+    }
+
+    fn damper(flap_detection: bool) -> Damper {
+        Damper::new(UpdateDamping {
+            min_apply_interval: secs(5),
+            flap_detection: if flap_detection {
+                Some(FlapDetection {
+                    window: secs(60),
+                    max_transitions: 3,
+                })
+            } else {
+                None
+            },
+        })
+    }
+
+    #[test]
+    fn coalesces_updates_within_the_apply_interval() {
+        let t0 = Instant::now();
+        let mut damper = damper(false);
+
+        damper.update(addrs(&[1]), t0);
+        assert_eq!(ports(damper.poll(t0)), Some(vec![1]));
+
+        damper.update(addrs(&[1, 2]), t0 + secs(1));
+        damper.update(addrs(&[1, 2, 3]), t0 + secs(2));
+        assert_eq!(ports(damper.poll(t0 + secs(2))), None);
+        assert_eq!(damper.next_poll(), Some(t0 + secs(5)));
+
+        // The latest resolution wins.
+        assert_eq!(ports(damper.poll(t0 + secs(5))), Some(vec![1, 2, 3]));
+        assert_eq!(ports(damper.poll(t0 + secs(20))), None);
+        assert_eq!(damper.next_poll(), None);
+    }
+
+    #[test]
+    fn freezes_on_the_union_while_flapping() {
+        let t0 = Instant::now();
+        let mut damper = damper(true);
+
+        damper.update(addrs(&[1, 2]), t0);
+        assert_eq!(ports(damper.poll(t0)), Some(vec![1, 2]));
+
+        // Endpoint 2 disappears and reappears.
+        let script: &[(u64, &[u16])] = &[(10, &[1]), (15, &[1, 2]), (20, &[1])];
+        for &(at, resolved) in script {
+            damper.update(addrs(resolved), t0 + secs(at));
+            assert_eq!(ports(damper.poll(t0 + secs(at))), Some(resolved.to_vec()));
+        }
+        assert!(!damper.is_flapping());
+
+        // Nothing is retired while flapping; new endpoints are added.
+        damper.update(addrs(&[1, 3]), t0 + secs(25));
+        assert!(damper.is_flapping());
+        assert_eq!(ports(damper.poll(t0 + secs(25))), Some(vec![1, 2, 3]));
+        damper.update(addrs(&[3]), t0 + secs(40));
+        assert_eq!(ports(damper.poll(t0 + secs(40))), None);
+        assert!(damper.is_flapping());
+    }
+
+    #[test]
+    fn applies_the_latest_resolution_once_stable() {
+        let t0 = Instant::now();
+        let mut damper = damper(true);
+
+        damper.update(addrs(&[1]), t0);
+        damper.poll(t0);
+        for (i, at) in (1..5).enumerate() {
+            let resolved = if i % 2 == 0 { addrs(&[1, 2]) } else { addrs(&[1]) };
+            damper.update(resolved, t0 + secs(at * 10));
+            damper.poll(t0 + secs(at * 10));
+        }
+        assert!(damper.is_flapping());
+        // Endpoints stay frozen on the union, [1, 2].
+        assert_eq!(ports(damper.poll(t0 + secs(45))), None);
+
+        // The last transition, at 40s, leaves the window at 100s.
+        assert_eq!(damper.next_poll(), Some(t0 + secs(70)));
+        assert_eq!(ports(damper.poll(t0 + secs(70))), None);
+        assert!(damper.is_flapping());
+        assert_eq!(ports(damper.poll(t0 + secs(100))), Some(vec![1]));
+        assert!(!damper.is_flapping());
+    }
+
+    #[test]
+    fn ignores_reweighting_when_counting_transitions() {
+        let t0 = Instant::now();
+        let mut damper = damper(true);
+
+        damper.update(addrs(&[1, 2]), t0);
+        damper.poll(t0);
+        for at in 1..10 {
+            let mut resolved = addrs(&[1, 2]);
+            resolved[0].weight = at as f64;
+            damper.update(resolved, t0 + secs(at * 5));
+            assert!(damper.poll(t0 + secs(at * 5)).is_some());
+        }
+        assert!(!damper.is_flapping());
+    }
+}
@@ -0,0 +1,323 @@
+//! Resolves hostnames in configuration without blocking the reactor.
+//!
+//! Lookups are performed on a small pool of worker threads, which is started when the
+//! first hostname is resolved. A `Name` tracks the addresses of a `host:port`,
+//! re-resolving it as its addresses expire: after the TTL reported by the lookup, or
+//! after the `Dns`'s default TTL when the lookup (like the system's resolver) does not
+//! report one. When a lookup fails, the name's last good addresses continue to be used,
+//! and the lookup is retried with exponential backoff.
+//!
+//! Lookups are abstracted by `Lookup`, so that other resolvers (or tests) may provide
+//! addresses and TTLs.
+
+use futures::{Async, Future, Poll, Stream};
+use futures_cpupool::{Builder, CpuFuture, CpuPool};
+use std::{cmp, fmt, io};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_timer::{Sleep, Timer};
+
+/// How long a name's addresses are used when its lookup does not report a TTL.
+pub const DEFAULT_TTL_SECS: u64 = 30;
+
+/// Names are re-resolved at least this often, however long their TTLs.
+const MAX_TTL_SECS: u64 = 300;
+
+const POOL_SIZE: usize = 2;
+
+const BASE_RETRY_MS: u64 = 500;
+
+/// The addresses to which a name resolved.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Resolution {
+    /// The name's addresses.
+    pub addrs: Vec<SocketAddr>,
+    /// How long the addresses may be used, if known.
+    pub ttl: Option<Duration>,
+}
+
+/// Looks up the addresses of a host.
+///
+/// Lookups are performed on worker threads, so they may block.
+pub trait Lookup: Send + Sync {
+    /// Resolves `host`, producing addresses with the given `port`.
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Resolution>;
+}
+
+impl<F> Lookup for F
+where
+    F: Fn(&str, u16) -> io::Result<Resolution> + Send + Sync,
+{
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Resolution> {
+        (self)(host, port)
+    }
+}
+
+/// Looks up hosts with the system's resolver, which does not report TTLs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Resolution> {
+        let addrs = (host, port).to_socket_addrs()?.collect();
+        Ok(Resolution { addrs, ttl: None })
+    }
+}
+
+/// A `host:port` to be resolved, e.g. `web.example.com:8080` or `[::1]:8080`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HostPort {
+    /// A hostname or IP address.
+    pub host: String,
+    /// The port of each of the host's addresses.
+    pub port: u16,
+}
+
+impl HostPort {
+    /// Parses a `host:port`. IPv6 addresses must be enclosed in brackets.
+    pub fn parse(s: &str) -> Option<HostPort> {
+        let colon = s.rfind(':')?;
+        let port = s[colon + 1..].parse::<u16>().ok()?;
+        let host = &s[..colon];
+        let host = if host.starts_with('[') && host.ends_with(']') && host.len() > 2 {
+            let ip = &host[1..host.len() - 1];
+            if ip.parse::<IpAddr>().is_err() {
+                return None;
+            }
+            ip
+        } else {
+            host
+        };
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '[' || c == ']') ||
+            (host.contains(':') && !s.starts_with('['))
+        {
+            return None;
+        }
+        Some(HostPort {
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    /// The address, if the host is an IP address rather than a hostname.
+    pub fn literal(&self) -> Option<SocketAddr> {
+        self.host.parse::<IpAddr>().ok().map(
+            |ip| SocketAddr::new(ip, self.port),
+        )
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Resolves names on worker threads.
+#[derive(Clone)]
+pub struct Dns {
+    lookup: Arc<Lookup>,
+    default_ttl: Duration,
+    /// Started when the first lookup is performed.
+    pool: Arc<Mutex<Option<CpuPool>>>,
+}
+
+impl Dns {
+    /// Resolves names with the system's resolver.
+    pub fn system() -> Dns {
+        Dns::new(SystemLookup)
+    }
+
+    /// Resolves names with `lookup`.
+    pub fn new<L: Lookup + 'static>(lookup: L) -> Dns {
+        Dns {
+            lookup: Arc::new(lookup),
+            default_ttl: Duration::from_secs(DEFAULT_TTL_SECS),
+            pool: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Uses addresses for `ttl` when their lookup does not report a TTL.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Dns {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Resolves `host` once.
+    pub fn resolve(&self, host: &str, port: u16) -> CpuFuture<Resolution, io::Error> {
+        let lookup = self.lookup.clone();
+        let host = host.to_owned();
+        self.pool().spawn_fn(move || lookup.lookup(&host, port))
+    }
+
+    /// Tracks the addresses of `name`, re-resolving it as they expire.
+    pub fn name(&self, name: &HostPort, timer: &Timer) -> Name {
+        let literal = name.literal();
+        let state = match literal {
+            Some(addr) => State::Literal(Some(addr)),
+            None => State::Expired,
+        };
+        Name {
+            name: name.clone(),
+            dns: self.clone(),
+            timer: timer.clone(),
+            state,
+            addrs: literal.map(|a| vec![a]),
+            failures: 0,
+        }
+    }
+
+    fn pool(&self) -> CpuPool {
+        let mut pool = self.pool.lock().expect("dns pool lock poisoned");
+        pool.get_or_insert_with(|| {
+            Builder::new()
+                .pool_size(POOL_SIZE)
+                .name_prefix("dns-")
+                .create()
+        }).clone()
+    }
+}
+
+/// The addresses of a name, which are produced as a stream each time they change.
+///
+/// Addresses are deduplicated, and are only produced when the set of addresses changes.
+/// An IP address is produced once, and is never resolved. The stream never ends.
+pub struct Name {
+    name: HostPort,
+    dns: Dns,
+    timer: Timer,
+    state: State,
+    /// The most recent successful resolution's addresses, if any.
+    addrs: Option<Vec<SocketAddr>>,
+    /// The number of consecutive failed lookups.
+    failures: u32,
+}
+
+enum State {
+    /// An IP address, until it has been produced.
+    Literal(Option<SocketAddr>),
+    /// The name must be resolved.
+    Expired,
+    Resolving(CpuFuture<Resolution, io::Error>),
+    /// The name's addresses are current, or its lookup is being retried, until the
+    /// timer fires.
+    Waiting(Sleep),
+}
+
+impl Name {
+    /// The name's current addresses, if it is an IP address or has ever been resolved.
+    pub fn addrs(&self) -> Option<&[SocketAddr]> {
+        self.addrs.as_ref().map(|a| a.as_slice())
+    }
+
+    /// The number of consecutive failed lookups, which is reset when a lookup succeeds.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Notes a successful lookup, returning its addresses if they have changed.
+    fn resolved(&mut self, rsp: Resolution) -> Option<Vec<SocketAddr>> {
+        self.failures = 0;
+        let ttl = rsp.ttl.unwrap_or(self.dns.default_ttl);
+        let ttl = cmp::min(ttl, Duration::from_secs(MAX_TTL_SECS));
+        self.state = State::Waiting(self.timer.sleep(ttl));
+
+        let mut addrs = Vec::with_capacity(rsp.addrs.len());
+        for addr in rsp.addrs {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        let unchanged = self.addrs.as_ref().map_or(false, |prior| {
+            let prior: HashSet<&SocketAddr> = prior.iter().collect();
+            prior.len() == addrs.len() && addrs.iter().all(|a| prior.contains(a))
+        });
+        if unchanged {
+            return None;
+        }
+        debug!("{}: resolved {} addresses", self.name, addrs.len());
+        self.addrs = Some(addrs.clone());
+        Some(addrs)
+    }
+
+    /// Notes a failed lookup, which is retried with exponential backoff, up to the
+    /// default TTL.
+    fn failed(&mut self, e: &io::Error) {
+        self.failures += 1;
+        let exp = cmp::min(self.failures - 1, 16);
+        let retry = cmp::min(
+            Duration::from_millis(BASE_RETRY_MS << exp),
+            self.dns.default_ttl,
+        );
+        match self.addrs {
+            Some(ref addrs) => {
+                warn!(
+                    "{}: DNS lookup failed: {}; using {} previous addresses, retrying in {:?}",
+                    self.name,
+                    e,
+                    addrs.len(),
+                    retry
+                )
+            }
+            None => warn!("{}: DNS lookup failed: {}; retrying in {:?}", self.name, e, retry),
+        }
+        self.state = State::Waiting(self.timer.sleep(retry));
+    }
+}
+
+impl Stream for Name {
+    type Item = Vec<SocketAddr>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Vec<SocketAddr>>, ()> {
+        loop {
+            let looked_up = match self.state {
+                State::Literal(ref mut addr) => {
+                    return Ok(match addr.take() {
+                        Some(addr) => Async::Ready(Some(vec![addr])),
+                        None => Async::NotReady,
+                    });
+                }
+                State::Expired => None,
+                State::Resolving(ref mut lookup) => {
+                    match lookup.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(rsp)) => Some(Ok(rsp)),
+                        Err(e) => Some(Err(e)),
+                    }
+                }
+                State::Waiting(ref mut sleep) => {
+                    match sleep.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(())) => {}
+                        Err(e) => warn!("{}: DNS timer failed: {}", self.name, e),
+                    }
+                    None
+                }
+            };
+
+            match looked_up {
+                None => {
+                    trace!("{}: resolving", self.name);
+                    let lookup = self.dns.resolve(&self.name.host, self.name.port);
+                    self.state = State::Resolving(lookup);
+                }
+                Some(Ok(ref rsp)) if rsp.addrs.is_empty() => {
+                    self.failed(&io::Error::new(io::ErrorKind::NotFound, "no addresses"));
+                }
+                Some(Ok(rsp)) => {
+                    if let Some(addrs) = self.resolved(rsp) {
+                        return Ok(Async::Ready(Some(addrs)));
+                    }
+                }
+                Some(Err(e)) => self.failed(&e),
+            }
+        }
+    }
+}
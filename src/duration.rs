@@ -0,0 +1,195 @@
+//! Durations in configuration.
+//!
+//! Durations may be written with units, e.g. `500ms`, `10s`, `2m`, or `1h30m`. For
+//! compatibility with configurations that predate units, bare numbers are interpreted
+//! in the unit named by the field (e.g. `periodSecs: 10` or `connectTimeoutMs: 500`).
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+const EXPECTED: &'static str = "a duration like \"500ms\", \"10s\", \"2m\", or \"1h30m\"";
+
+/// A configured duration for which bare numbers are interpreted as seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Secs(pub Duration);
+
+/// A configured duration for which bare numbers are interpreted as milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Millis(pub Duration);
+
+impl From<Secs> for Duration {
+    fn from(Secs(d): Secs) -> Duration {
+        d
+    }
+}
+
+impl From<Millis> for Duration {
+    fn from(Millis(d): Millis) -> Duration {
+        d
+    }
+}
+
+/// Parses a duration with units, e.g. `1h30m`.
+///
+/// Each component is an integer followed by one of `ms`, `s`, `m`, or `h`.
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("empty duration".into());
+    }
+
+    let mut total = Duration::from_secs(0);
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_digit(10)).unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("expected a number at \"{}\"", rest));
+        }
+        let n = rest[..digits].parse::<u64>().map_err(|e| {
+            format!("invalid number \"{}\": {}", &rest[..digits], e)
+        })?;
+        rest = &rest[digits..];
+
+        let units = rest.find(|c: char| c.is_digit(10)).unwrap_or(rest.len());
+        let d = match &rest[..units] {
+            "ms" => Duration::from_millis(n),
+            "s" => Duration::from_secs(n),
+            "m" => n.checked_mul(60).map(Duration::from_secs).ok_or_else(too_large)?,
+            "h" => n.checked_mul(60 * 60).map(Duration::from_secs).ok_or_else(too_large)?,
+            "" => return Err(format!("missing units after \"{}\"", n)),
+            u => return Err(format!("unknown units \"{}\"", u)),
+        };
+        rest = &rest[units..];
+        total = total.checked_add(d).ok_or_else(too_large)?;
+    }
+    Ok(total)
+}
+
+fn too_large() -> String {
+    "duration is too large".into()
+}
+
+/// Finds the first duration with units in a configuration value that cannot be parsed,
+/// returning a JSON pointer to its field along with the reason.
+///
+/// Duration fields are those whose names end in `Secs` or `Ms`. Since serde does not
+/// report the field in which a value failed to deserialize, configurations are checked
+/// before they are deserialized so that errors can name the field.
+pub fn find_invalid(value: &Value) -> Option<(String, String)> {
+    find_invalid_at(value, "")
+}
+
+fn find_invalid_at(value: &Value, pointer: &str) -> Option<(String, String)> {
+    match *value {
+        Value::Object(ref fields) => {
+            for (k, v) in fields {
+                let pointer = format!("{}/{}", pointer, k.replace('~', "~0").replace('/', "~1"));
+                if let Value::String(ref s) = *v {
+                    if (k.ends_with("Secs") || k.ends_with("Ms")) &&
+                        s.trim().parse::<u64>().is_err()
+                    {
+                        if let Err(e) = parse(s) {
+                            return Some((pointer, invalid(s, &e)));
+                        }
+                    }
+                }
+                if let Some(found) = find_invalid_at(v, &pointer) {
+                    return Some(found);
+                }
+            }
+            None
+        }
+        Value::Array(ref items) => {
+            items
+                .iter()
+                .enumerate()
+                .filter_map(|(i, v)| find_invalid_at(v, &format!("{}/{}", pointer, i)))
+                .next()
+        }
+        _ => None,
+    }
+}
+
+fn invalid(s: &str, e: &str) -> String {
+    format!("invalid duration \"{}\" ({}); expected {}", s, e, EXPECTED)
+}
+
+/// Formats a duration in seconds, or in milliseconds if it has a fractional part.
+fn fmt_duration(d: &Duration) -> String {
+    if d.subsec_nanos() == 0 {
+        format!("{}s", d.as_secs())
+    } else {
+        format!("{}ms", d.as_secs() * 1_000 + (d.subsec_nanos() / 1_000_000) as u64)
+    }
+}
+
+impl fmt::Display for Secs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&fmt_duration(&self.0))
+    }
+}
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&fmt_duration(&self.0))
+    }
+}
+
+impl Serialize for Secs {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&fmt_duration(&self.0))
+    }
+}
+
+impl Serialize for Millis {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&fmt_duration(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Secs {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Secs, D::Error> {
+        d.deserialize_any(DurationVisitor(Duration::from_secs))
+            .map(Secs)
+    }
+}
+
+impl<'de> Deserialize<'de> for Millis {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Millis, D::Error> {
+        d.deserialize_any(DurationVisitor(Duration::from_millis))
+            .map(Millis)
+    }
+}
+
+/// Accepts either a duration with units or a bare number, which is converted with
+/// the provided function.
+struct DurationVisitor(fn(u64) -> Duration);
+
+impl<'de> Visitor<'de> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(EXPECTED)
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<Duration, E> {
+        Ok((self.0)(n))
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<Duration, E> {
+        if n < 0 {
+            return Err(E::invalid_value(de::Unexpected::Signed(n), &self));
+        }
+        Ok((self.0)(n as u64))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Duration, E> {
+        if let Ok(n) = s.trim().parse::<u64>() {
+            return Ok((self.0)(n));
+        }
+        parse(s).map_err(|e| E::custom(invalid(s, &e)))
+    }
+}
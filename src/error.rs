@@ -0,0 +1,177 @@
+//! The errors returned by the library's public entry points.
+//!
+//! Configuration, resolution, and connection errors are each distinguished so that
+//! embedders may react to them without inspecting messages. Internally, streams are
+//! driven by tokio, whose traits require `io::Error`; conversions in both directions
+//! preserve the original error as the cause.
+
+use super::app;
+use super::resolver;
+use std::{error, fmt, io};
+
+/// A Result type for the library's public entry points.
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+pub use super::resolver::Error as ResolveError;
+
+/// Describes why an operation failed.
+#[derive(Debug)]
+pub enum Error {
+    /// Indicates an invalid configuration.
+    Config(app::Error),
+
+    /// Indicates that a destination could not be resolved.
+    Resolve(ResolveError),
+
+    /// Indicates that a connection to a destination could not be obtained.
+    Connect {
+        /// Why the connection could not be obtained.
+        kind: ConnectErrorKind,
+        /// The underlying error.
+        cause: io::Error,
+    },
+
+    /// Indicates a failed TLS handshake or session.
+    Tls(io::Error),
+
+    /// Indicates any other I/O failure, e.g. a listener that could not be bound.
+    Io(io::Error),
+}
+
+/// Describes why a connection could not be obtained.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectErrorKind {
+    /// The connection was not established in time.
+    Timeout,
+
+    /// The endpoint refused the connection.
+    Refused,
+
+    /// The destination's circuit breaker is open, so the connection was not attempted.
+    CircuitOpen,
+
+    /// Too many connections to the destination were already being dispatched, so the
+    /// connection was shed.
+    Shed,
+
+    /// The destination's balancer stopped before the connection could be obtained.
+    Unavailable,
+
+    /// The connection failed for another reason.
+    Other,
+}
+
+/// Creates a connection error that did not originate from an I/O operation.
+pub fn connect_error(kind: ConnectErrorKind, msg: &str) -> Error {
+    let io_kind = match kind {
+        ConnectErrorKind::Timeout => io::ErrorKind::TimedOut,
+        ConnectErrorKind::Refused |
+        ConnectErrorKind::CircuitOpen |
+        ConnectErrorKind::Shed => io::ErrorKind::ConnectionRefused,
+        ConnectErrorKind::Unavailable => io::ErrorKind::Interrupted,
+        ConnectErrorKind::Other => io::ErrorKind::Other,
+    };
+    Error::Connect {
+        kind,
+        cause: io::Error::new(io_kind, msg),
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref e) => write!(f, "invalid configuration: {}", e),
+            Error::Resolve(ref e) => write!(f, "resolution failed: {}", e),
+            Error::Connect { ref kind, ref cause } => {
+                write!(f, "connection failed ({}): {}", kind, cause)
+            }
+            Error::Tls(ref e) => write!(f, "tls failed: {}", e),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for ConnectErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ConnectErrorKind::Timeout => "timeout",
+            ConnectErrorKind::Refused => "refused",
+            ConnectErrorKind::CircuitOpen => "circuit open",
+            ConnectErrorKind::Shed => "shed",
+            ConnectErrorKind::Unavailable => "unavailable",
+            ConnectErrorKind::Other => "other",
+        })
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Config(_) => "invalid configuration",
+            Error::Resolve(_) => "resolution failed",
+            Error::Connect { .. } => "connection failed",
+            Error::Tls(_) => "tls failed",
+            Error::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Config(ref e) => Some(e),
+            Error::Resolve(ref e) => Some(e),
+            Error::Connect { ref cause, .. } => Some(cause),
+            Error::Tls(ref e) => Some(e),
+            Error::Io(ref e) => e.cause(),
+        }
+    }
+}
+
+impl From<app::Error> for Error {
+    fn from(e: app::Error) -> Error {
+        Error::Config(e)
+    }
+}
+
+impl From<resolver::Error> for Error {
+    fn from(e: resolver::Error) -> Error {
+        Error::Resolve(e)
+    }
+}
+
+/// Classifies an error produced by tokio plumbing.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if is_tls(&e) {
+            return Error::Tls(e);
+        }
+        let kind = match e.kind() {
+            io::ErrorKind::TimedOut => ConnectErrorKind::Timeout,
+            io::ErrorKind::ConnectionRefused => ConnectErrorKind::Refused,
+            _ => return Error::Io(e),
+        };
+        Error::Connect { kind, cause: e }
+    }
+}
+
+/// Recovers the underlying error where tokio's traits require an `io::Error`.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Connect { cause, .. } |
+            Error::Tls(cause) |
+            Error::Io(cause) => cause,
+            e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn is_tls(e: &io::Error) -> bool {
+    use rustls::TLSError;
+    e.get_ref().map(|e| e.is::<TLSError>()).unwrap_or(false)
+}
+
+#[cfg(not(feature = "tls"))]
+fn is_tls(_e: &io::Error) -> bool {
+    false
+}
@@ -0,0 +1,246 @@
+//! Tracks the process's file descriptor usage against `RLIMIT_NOFILE`.
+//!
+//! When usage crosses a high watermark, servers stop accepting new connections until
+//! usage drops again; new connections wait in their listeners' backlogs, and servers
+//! check again each time usage is sampled. Connections that have already been accepted
+//! are unaffected, so that they may still obtain upstream sockets.
+
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tacho;
+use tokio_timer::Timer;
+
+pub const DEFAULT_HIGH_WATERMARK_PERCENT: usize = 90;
+
+const SAMPLE_INTERVAL_SECS: u64 = 1;
+
+/// Shares file descriptor exhaustion state between the admin and serving threads.
+#[derive(Clone)]
+pub struct FdLimit {
+    limit: Option<usize>,
+    high_watermark: Option<usize>,
+    exhausted: Arc<AtomicBool>,
+    /// The tasks waiting to accept connections, which are notified at each sample.
+    waiters: Arc<Mutex<Vec<Task>>>,
+}
+
+impl FdLimit {
+    pub fn new(high_watermark_percent: usize) -> FdLimit {
+        FdLimit::with_limit(nofile_limit(), high_watermark_percent)
+    }
+
+    fn with_limit(limit: Option<usize>, high_watermark_percent: usize) -> FdLimit {
+        let high_watermark = limit.map(|l| l * high_watermark_percent / 100);
+        match limit {
+            Some(l) => info!("file descriptor limit: {}", l),
+            None => info!("file descriptor limit: unlimited"),
+        }
+        FdLimit {
+            limit,
+            high_watermark,
+            exhausted: Arc::new(AtomicBool::new(false)),
+            waiters: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Indicates whether new connections should be refused.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Stops polling `incoming` while file descriptors are exhausted.
+    pub fn gate<S: Stream>(self, incoming: S) -> Gated<S> {
+        Gated {
+            incoming,
+            limit: self,
+        }
+    }
+
+    /// Determines whether new connections may be accepted, and if not, arranges for the
+    /// current task to be notified when usage is next sampled.
+    fn poll_ready(&self) -> bool {
+        if !self.is_exhausted() {
+            return true;
+        }
+        let mut waiters = self.waiters.lock().expect("fd waiters poisoned");
+        if !waiters.iter().any(|t| t.will_notify_current()) {
+            waiters.push(task::current());
+        }
+        // Usage may have been sampled since it was checked.
+        !self.is_exhausted()
+    }
+
+    /// Counts the file descriptors currently open, updating the exhaustion state.
+    fn sample(&self) -> Option<usize> {
+        let open = open_fds()?;
+        self.record(open);
+        Some(open)
+    }
+
+    /// Updates the exhaustion state for `open` file descriptors, and notifies the tasks
+    /// waiting to accept connections so that they check it again.
+    fn record(&self, open: usize) {
+        if let Some(hwm) = self.high_watermark {
+            let exhausted = open >= hwm;
+            if self.exhausted.swap(exhausted, Ordering::AcqRel) != exhausted {
+                if exhausted {
+                    warn!(
+                        "{} of {:?} file descriptors in use; deferring new connections",
+                        open,
+                        self.limit
+                    );
+                } else {
+                    info!(
+                        "{} of {:?} file descriptors in use; accepting new connections",
+                        open,
+                        self.limit
+                    );
+                }
+            }
+        }
+        let waiters = {
+            let mut waiters = self.waiters.lock().expect("fd waiters poisoned");
+            waiters.drain(..).collect::<Vec<_>>()
+        };
+        for task in waiters {
+            task.notify();
+        }
+    }
+
+    /// Periodically samples file descriptor usage and records it as gauges.
+    pub fn monitor(
+        self,
+        timer: &Timer,
+        metrics: &tacho::Scope,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let limit = metrics.gauge("fd_limit");
+        let open = metrics.gauge("fds_open");
+        let exhausted = metrics.gauge("fd_exhausted");
+        if let Some(l) = self.limit {
+            limit.set(l);
+        }
+        let interval = timer.interval(Duration::from_secs(SAMPLE_INTERVAL_SECS));
+        let f = interval.map_err(|_| {}).for_each(move |_| {
+            if let Some(n) = self.sample() {
+                open.set(n);
+            }
+            exhausted.set(if self.is_exhausted() { 1 } else { 0 });
+            Ok(())
+        });
+        Box::new(f)
+    }
+}
+
+#[cfg(unix)]
+fn nofile_limit() -> Option<usize> {
+    use libc;
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return None;
+    }
+    if rlim.rlim_cur == libc::RLIM_INFINITY {
+        None
+    } else {
+        Some(rlim.rlim_cur as usize)
+    }
+}
+
+#[cfg(not(unix))]
+fn nofile_limit() -> Option<usize> {
+    None
+}
+
+/// Counts open file descriptors via procfs, where available.
+fn open_fds() -> Option<usize> {
+    fs::read_dir("/proc/self/fd").ok().map(|d| d.count())
+}
+
+/// A stream of connections that is not polled while file descriptors are exhausted.
+pub struct Gated<S> {
+    incoming: S,
+    limit: FdLimit,
+}
+
+impl<S: Stream> Stream for Gated<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if !self.limit.poll_ready() {
+            return Ok(Async::NotReady);
+        }
+        self.incoming.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FdLimit;
+    use futures::{Async, Future, Poll, Stream};
+    use futures::future::Either;
+    use std::thread;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+    use tokio_timer;
+
+    /// Connections, numbered from 1, that wait in a listener's backlog.
+    struct Backlog(u32, u32);
+
+    impl Stream for Backlog {
+        type Item = u32;
+        type Error = ();
+        fn poll(&mut self) -> Poll<Option<u32>, ()> {
+            if self.0 == self.1 {
+                return Ok(Async::NotReady);
+            }
+            self.0 += 1;
+            Ok(Async::Ready(Some(self.0)))
+        }
+    }
+
+    #[test]
+    fn defers_accepts_until_file_descriptors_are_sampled_below_the_watermark() {
+        let mut core = Core::new().unwrap();
+        let timer = tokio_timer::wheel().tick_duration(Duration::from_millis(1)).build();
+        let fds = FdLimit::with_limit(Some(100), 90);
+        fds.record(95);
+        assert!(fds.is_exhausted());
+        let accepting = fds.clone().gate(Backlog(0, 2));
+
+        // Connections are left in the backlog, rather than being accepted and dropped.
+        let wait = timer.sleep(Duration::from_millis(40));
+        let accepting = match core.run(accepting.into_future().select2(wait)) {
+            Ok(Either::B((_, accepting))) => accepting.into_inner().unwrap(),
+            _ => panic!("accepted a connection while exhausted"),
+        };
+
+        // The next sample below the watermark wakes the server, which accepts every
+        // connection that was waiting.
+        let sampler = {
+            let fds = fds.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                fds.record(50);
+            })
+        };
+        let accepted = accepting.take(2).collect();
+        let wait = timer.sleep(Duration::from_secs(2)).map_err(|_| {});
+        let accepted = match core.run(accepted.select2(wait)) {
+            Ok(Either::A((accepted, _))) => accepted,
+            _ => panic!("not woken by the sample"),
+        };
+        assert_eq!(accepted, vec![1, 2]);
+        assert!(!fds.is_exhausted());
+        sampler.join().unwrap();
+    }
+}
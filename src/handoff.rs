@@ -0,0 +1,831 @@
+//! Hands established streams from one process to another, so that even long-lived
+//! streams survive a binary upgrade (`experimental.connectionHandoff`). Experimental.
+//!
+//! The new process listens on the handoff socket, a unix socket, replacing the socket
+//! of the process it replaces. As the old process shuts down, it connects to the socket
+//! and hands over each of its plaintext streams: the stream's client and endpoint
+//! sockets are passed with `SCM_RIGHTS`, along with its state (its destination, its
+//! peers' addresses, the bytes written in each direction, and the bytes read but not
+//! yet written). The new process registers the sockets with its reactor and resumes
+//! proxying the stream, writing the unwritten bytes first.
+//!
+//! Each handoff is acknowledged once the new process holds the stream's sockets. The old
+//! process then commits it and releases the stream, and the new process only resumes the
+//! stream once it is committed; if the old process gives up before committing, e.g.
+//! because the acknowledgement is late, the new process drops its copies of the sockets
+//! and the stream continues in the old process. A stream is handed off between reads and
+//! writes, so no byte is lost or duplicated. Handoffs are
+//! written, and their acknowledgements awaited, on a dedicated thread so that a slow
+//! receiver does not stall the reactor; a stream is neither read nor written while it
+//! is being sent.
+//! Streams that cannot be handed off are refused, and drain in the old process as other
+//! connections do: TLS sessions are held by the old process's rustls state, and streams
+//! that are already closing are not worth moving. Streams are also left to drain when
+//! no process is listening on the handoff socket.
+//!
+//! The old process counts streams as `handoff_sent`, `handoff_refused` (labeled by
+//! `reason`), and `handoff_failures`. The new process counts `handoff_received`, and
+//! reports the resumed streams that remain open as `handoff_resumed`. Resumed streams
+//! are proxied outside of their routers, so they are not reflected in their servers' or
+//! balancers' metrics, but they may be handed off again.
+
+use super::connection::{Buffers, CloseReason, Connection, ctx, socket};
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::{mpsc, oneshot};
+use futures::task::{self, Task};
+use libc;
+use serde_json;
+use std::{cmp, fmt, io, mem, net, thread};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tacho;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+/// Bounds the state sent with each stream, which is mostly its unwritten bytes.
+const MAX_STATE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Bounds the time a handoff may block the sending thread while it is written to the
+/// socket and acknowledged.
+const SEND_TIMEOUT_SECS: u64 = 5;
+
+/// Sent by the receiver once it holds a handoff's sockets.
+const ACK: u8 = 1;
+
+/// Sent by the sender once it has released a handoff's stream, so that the receiver may
+/// resume it.
+const COMMIT: u8 = 2;
+
+/// Describes a stream as it is handed off.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    /// The destination to which the stream was routed.
+    pub dst_name: String,
+    /// The address of the downstream client.
+    pub client_addr: net::SocketAddr,
+    /// The address of the upstream endpoint.
+    pub endpoint_addr: net::SocketAddr,
+    /// The bytes written to the endpoint so far, across all handoffs.
+    pub to_endpoint_bytes: u64,
+    /// The bytes written to the client so far, across all handoffs.
+    pub to_client_bytes: u64,
+    /// Bytes read from the client that have not yet been written to the endpoint.
+    pub to_endpoint: Vec<u8>,
+    /// Bytes read from the endpoint that have not yet been written to the client.
+    pub to_client: Vec<u8>,
+}
+
+/// A stream's sockets and state, as they are passed between processes.
+#[derive(Debug)]
+pub struct Handoff {
+    /// Describes the stream.
+    pub state: State,
+    /// The downstream client's socket.
+    pub client: net::TcpStream,
+    /// The upstream endpoint's socket.
+    pub endpoint: net::TcpStream,
+}
+
+/// Why a stream was not handed off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The stream's TLS session is held by this process.
+    Tls,
+    /// A peer has closed its half of the stream, or the stream is being drained.
+    Closing,
+}
+
+impl Refusal {
+    /// A bounded name for the reason, suitable as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Refusal::Tls => "tls",
+            Refusal::Closing => "closing",
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Refusal::Tls => f.write_str("TLS sessions cannot be handed off"),
+            Refusal::Closing => f.write_str("the stream is closing"),
+        }
+    }
+}
+
+/// Sends `handoff` on `sock`, passing its sockets with `SCM_RIGHTS`, waits for the
+/// receiver to acknowledge it, and commits it.
+///
+/// Each handoff is framed by the length of its JSON-encoded state. The receiver only
+/// resumes the stream once it is committed, so if this fails the stream may continue in
+/// this process. The sockets remain open in this process until `handoff` is dropped.
+pub fn send(sock: &UnixStream, handoff: &Handoff) -> io::Result<()> {
+    let state = serde_json::to_vec(&handoff.state).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    })?;
+    if state.len() > MAX_STATE_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "stream state is too large"));
+    }
+    let mut frame = Vec::with_capacity(4 + state.len());
+    frame.extend_from_slice(&encode_len(state.len()));
+    frame.extend_from_slice(&state);
+
+    // The sockets accompany the first bytes sent; the rest of the frame follows.
+    let fds = [handoff.client.as_raw_fd(), handoff.endpoint.as_raw_fd()];
+    let sent = send_rights(sock.as_raw_fd(), &frame, fds)?;
+    let mut sock = sock;
+    sock.write_all(&frame[sent..])?;
+    let mut ack = [0u8; 1];
+    sock.read_exact(&mut ack)?;
+    sock.write_all(&[COMMIT])
+}
+
+/// Receives and acknowledges a handoff sent by `send`, or `None` once `sock` is closed
+/// between handoffs.
+///
+/// The handoff is returned only once the sender commits it. If the sender gives up
+/// first, the received sockets are closed, without being shut down, and this fails.
+pub fn recv(sock: &UnixStream) -> io::Result<Option<Handoff>> {
+    let mut len = [0u8; 4];
+    let (read, fds) = recv_rights(sock.as_raw_fd(), &mut len)?;
+    // Sockets that are received are closed if the handoff is invalid.
+    let sockets = fds.into_iter()
+        .map(|fd| unsafe { net::TcpStream::from_raw_fd(fd) })
+        .collect::<Vec<_>>();
+    if read == 0 {
+        return Ok(None);
+    }
+    let mut sockets = sockets.into_iter();
+    let (client, endpoint) = match (sockets.next(), sockets.next(), sockets.next()) {
+        (Some(client), Some(endpoint), None) => (client, endpoint),
+        _ => return Err(invalid_data("a handoff must pass two sockets")),
+    };
+
+    let mut sock = sock;
+    sock.read_exact(&mut len[read..])?;
+    let len = decode_len(&len);
+    if len > MAX_STATE_BYTES {
+        return Err(invalid_data("stream state is too large"));
+    }
+    let mut state = vec![0; len];
+    sock.read_exact(&mut state)?;
+    let state = serde_json::from_slice(&state).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    })?;
+    sock.write_all(&[ACK])?;
+    let mut commit = [0u8; 1];
+    sock.read_exact(&mut commit)?;
+    if commit[0] != COMMIT {
+        return Err(invalid_data("a handoff was not committed"));
+    }
+    Ok(Some(Handoff {
+        state,
+        client,
+        endpoint,
+    }))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn encode_len(len: usize) -> [u8; 4] {
+    let len = len as u32;
+    [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+}
+
+fn decode_len(b: &[u8; 4]) -> usize {
+    (b[0] as usize) << 24 | (b[1] as usize) << 16 | (b[2] as usize) << 8 | b[3] as usize
+}
+
+/// The control message that passes a stream's client and endpoint sockets.
+#[repr(C)]
+struct Rights {
+    hdr: libc::cmsghdr,
+    fds: [RawFd; 2],
+}
+
+impl Rights {
+    fn new(fds: [RawFd; 2]) -> Rights {
+        let mut rights: Rights = unsafe { mem::zeroed() };
+        rights.hdr.cmsg_len = mem::size_of::<Rights>() as _;
+        rights.hdr.cmsg_level = libc::SOL_SOCKET;
+        rights.hdr.cmsg_type = libc::SCM_RIGHTS;
+        rights.fds = fds;
+        rights
+    }
+
+    /// The descriptors actually carried by a received message.
+    fn received(&self, controllen: usize) -> Vec<RawFd> {
+        let hdr_len = mem::size_of::<Rights>() - mem::size_of::<[RawFd; 2]>();
+        if controllen < hdr_len || self.hdr.cmsg_level != libc::SOL_SOCKET ||
+            self.hdr.cmsg_type != libc::SCM_RIGHTS
+        {
+            return vec![];
+        }
+        let n = (self.hdr.cmsg_len as usize).saturating_sub(hdr_len) /
+            mem::size_of::<RawFd>();
+        self.fds[..cmp::min(n, self.fds.len())].to_vec()
+    }
+}
+
+/// Sends bytes from `buf` on `sock` with `fds`, returning the number of bytes sent.
+fn send_rights(sock: RawFd, buf: &[u8], fds: [RawFd; 2]) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut rights = Rights::new(fds);
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = &mut rights as *mut Rights as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of::<Rights>() as _;
+    loop {
+        let sent = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+        if sent >= 0 {
+            return Ok(sent as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Receives bytes into `buf` from `sock`, along with any descriptors passed with them.
+fn recv_rights(sock: RawFd, buf: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut rights: Rights = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = &mut rights as *mut Rights as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of::<Rights>() as _;
+    let read = loop {
+        let read = unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if read >= 0 {
+            break read as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    let fds = rights.received(msg.msg_controllen as usize);
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+        return Err(invalid_data("too many sockets were passed"));
+    }
+    Ok((read, fds))
+}
+
+/// Hands a process's streams off to another process, and resumes the streams handed
+/// off to it.
+///
+/// Each stream that may be handed off holds a `Request`. Once `hand_off` is called,
+/// every stream is asked to release its sockets, between reads and writes, and each is
+/// either sent or refused.
+#[derive(Clone)]
+pub struct Handoffs(Rc<RefCell<Inner>>);
+
+struct Inner {
+    /// Set once streams are handed off. Handoffs are sent to the socket by a dedicated
+    /// thread.
+    sender: Option<mpsc::UnboundedSender<Outgoing>>,
+    handing_off: bool,
+    /// Set once this process hands its streams off, so that it stops receiving them.
+    stopped: Arc<AtomicBool>,
+    streams: HashMap<u64, Option<Task>>,
+    next_id: u64,
+    /// Notified as streams answer, while streams are handed off.
+    task: Option<Task>,
+    bufs: Buffers,
+    metrics: Metrics,
+}
+
+struct Metrics {
+    sent: tacho::Counter,
+    tls: tacho::Counter,
+    closing: tacho::Counter,
+    failures: tacho::Counter,
+    received: tacho::Counter,
+    resumed: tacho::Gauge,
+}
+
+impl Handoffs {
+    /// Resumed streams are proxied through `bufs`. Handoffs are recorded in `metrics`,
+    /// which should describe the process.
+    pub fn new(bufs: Buffers, metrics: &tacho::Scope) -> Handoffs {
+        let metrics = metrics.clone().prefixed("handoff");
+        let refused = |r: Refusal| {
+            metrics.clone().labeled("reason", r.as_str()).counter("refused")
+        };
+        Handoffs(Rc::new(RefCell::new(Inner {
+            sender: None,
+            handing_off: false,
+            stopped: Arc::new(AtomicBool::new(false)),
+            streams: HashMap::new(),
+            next_id: 0,
+            task: None,
+            bufs,
+            metrics: Metrics {
+                sent: metrics.counter("sent"),
+                tls: refused(Refusal::Tls),
+                closing: refused(Refusal::Closing),
+                failures: metrics.counter("failures"),
+                received: metrics.counter("received"),
+                resumed: metrics.gauge("resumed"),
+            },
+        })))
+    }
+
+    /// Registers a stream to `dst_name`, so that it may be handed off.
+    pub fn request(&self, dst_name: &str) -> Request {
+        self.request_resumed(dst_name, 0, 0)
+    }
+
+    /// Registers a stream that has already written bytes in another process.
+    fn request_resumed(&self, dst_name: &str, to_endpoint: u64, to_client: u64) -> Request {
+        let mut inner = self.0.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.streams.insert(id, None);
+        Request {
+            id,
+            dst_name: dst_name.to_owned(),
+            to_endpoint_bytes: to_endpoint,
+            to_client_bytes: to_client,
+            handoffs: self.clone(),
+        }
+    }
+
+    /// Hands every stream off to the process receiving on the handoff socket at `path`,
+    /// which replaces this one. This process stops receiving streams first, even if no
+    /// process is receiving.
+    ///
+    /// Streams registered afterwards are handed off as they are registered. The returned
+    /// future completes once every stream has been handed off or refused. Refused
+    /// streams, and streams that could not be sent, continue in this process.
+    pub fn hand_off(&self, path: &Path) -> io::Result<HandingOff> {
+        self.0.borrow().stopped.store(true, Ordering::SeqCst);
+        let sock = UnixStream::connect(path)?;
+        let timeout = Some(Duration::from_secs(SEND_TIMEOUT_SECS));
+        sock.set_write_timeout(timeout)?;
+        sock.set_read_timeout(timeout)?;
+        let sender = spawn_sender(sock)?;
+        let mut inner = self.0.borrow_mut();
+        inner.sender = Some(sender);
+        inner.handing_off = true;
+        info!("handing off {} streams", inner.streams.len());
+        for task in inner.streams.values_mut() {
+            if let Some(task) = task.take() {
+                task.notify();
+            }
+        }
+        Ok(HandingOff(self.clone()))
+    }
+
+    /// Receives streams handed off over connections to `listener`, resuming each on
+    /// `reactor`.
+    ///
+    /// Connections are accepted on a dedicated thread, and each is read on a thread of
+    /// its own, since each blocks as its handoffs are read; a connection that stalls
+    /// does not delay the others. Once this process hands its own streams off, it stops
+    /// receiving them, so that it never receives its own.
+    pub fn receive(
+        &self,
+        listener: UnixListener,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> io::Result<()> {
+        let (tx, rx) = mpsc::unbounded();
+        let stopped = self.0.borrow().stopped.clone();
+        thread::Builder::new().name("handoff".into()).spawn(move || {
+            for sock in listener.incoming() {
+                let sock = match sock {
+                    Ok(sock) => sock,
+                    Err(e) => {
+                        warn!("failed to accept a handoff connection: {}", e);
+                        continue;
+                    }
+                };
+                if stopped.load(Ordering::SeqCst) {
+                    debug!("refusing handoffs while handing off");
+                    continue;
+                }
+                let tx = tx.clone();
+                let spawned = thread::Builder::new()
+                    .name("handoff-recv".into())
+                    .spawn(move || receive_from(&sock, &tx));
+                if let Err(e) = spawned {
+                    warn!("failed to receive handoffs: {}", e);
+                }
+            }
+        })?;
+
+        let handoffs = self.clone();
+        let resume_reactor = reactor.clone();
+        let timer = timer.clone();
+        let resuming = rx.for_each(move |handoff| {
+            let client_addr = handoff.state.client_addr;
+            if let Err(e) = handoffs.resume(handoff, &resume_reactor, &timer) {
+                warn!("failed to resume the stream from {}: {}", client_addr, e);
+            }
+            Ok(())
+        });
+        reactor.spawn(resuming);
+        Ok(())
+    }
+
+    /// Resumes proxying a stream that was handed off to this process on `reactor`.
+    ///
+    /// The stream's unwritten bytes are written before any more are read. The resumed
+    /// stream may itself be handed off.
+    pub fn resume(&self, handoff: Handoff, reactor: &Handle, timer: &Timer) -> io::Result<()> {
+        let Handoff {
+            state,
+            client,
+            endpoint,
+        } = handoff;
+        let client = TcpStream::from_stream(client, reactor)?;
+        let endpoint = TcpStream::from_stream(endpoint, reactor)?;
+        debug!(
+            "resuming {} to {} ({}B to the endpoint and {}B to the client unwritten)",
+            state.client_addr,
+            state.endpoint_addr,
+            state.to_endpoint.len(),
+            state.to_client.len()
+        );
+
+        // Unwritten bytes are read again from the peer that sent them.
+        let mut client = socket::plain(client);
+        client.replay(state.to_endpoint);
+        let mut endpoint = socket::plain(endpoint);
+        endpoint.replay(state.to_client);
+
+        let (bufs, resumed) = {
+            let inner = self.0.borrow();
+            inner.metrics.received.incr(1);
+            (inner.bufs.clone(), inner.metrics.resumed.clone())
+        };
+        let request = self.request_resumed(
+            &state.dst_name,
+            state.to_endpoint_bytes,
+            state.to_client_bytes,
+        );
+        let mut duplex = Connection::new(client, ctx::null()).into_duplex(
+            Connection::new(endpoint, ctx::null()),
+            bufs,
+            None,
+            None,
+            None,
+            timer,
+            None,
+            None,
+            None,
+        );
+        duplex.hand_off_with(request);
+        let close_reason = duplex.close_reason();
+        let client_addr = state.client_addr;
+        let endpoint_addr = state.endpoint_addr;
+        resumed.incr(1);
+        reactor.spawn(duplex.then(move |res| {
+            resumed.decr(1);
+            let reason = close_reason.get().unwrap_or_else(|| match res {
+                Err(ref e) => CloseReason::Error(e.kind()),
+                Ok(_) => CloseReason::Error(io::ErrorKind::Other),
+            });
+            debug!(
+                "resumed connection from {} to {} closed: {}",
+                client_addr,
+                endpoint_addr,
+                reason
+            );
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+/// Receives handoffs from `sock` until it is closed, passing each to `tx`.
+fn receive_from(sock: &UnixStream, tx: &mpsc::UnboundedSender<Handoff>) {
+    loop {
+        match recv(sock) {
+            Ok(Some(handoff)) => {
+                if tx.unbounded_send(handoff).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                warn!("failed to receive a handoff: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// A handoff waiting to be sent, and the means to report whether it was.
+type Outgoing = (Handoff, oneshot::Sender<io::Result<()>>);
+
+/// Sends handoffs to `sock`, in order, on a dedicated thread, since each blocks until it
+/// is acknowledged.
+fn spawn_sender(sock: UnixStream) -> io::Result<mpsc::UnboundedSender<Outgoing>> {
+    let (tx, rx) = mpsc::unbounded::<Outgoing>();
+    thread::Builder::new().name("handoff-sender".into()).spawn(move || {
+        let mut sock = Some(sock);
+        for outgoing in rx.wait() {
+            let (handoff, sent_tx) = match outgoing {
+                Ok(outgoing) => outgoing,
+                Err(()) => return,
+            };
+            let sent = match sock {
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "handoff socket failed")),
+                Some(ref sock) => send(sock, &handoff),
+            };
+            if sent.is_err() {
+                // A partially written handoff corrupts the rest of the stream, so no
+                // more are sent.
+                sock = None;
+            }
+            let _ = sent_tx.send(sent);
+        }
+    })?;
+    Ok(tx)
+}
+
+/// Completes once every stream has been handed off or refused.
+pub struct HandingOff(Handoffs);
+
+impl Future for HandingOff {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut inner = (self.0).0.borrow_mut();
+        if inner.streams.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        inner.task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// Registers a stream with `Handoffs`, so that it may be handed off.
+///
+/// Once the stream has been handed off or refused, its request is dropped.
+pub struct Request {
+    id: u64,
+    dst_name: String,
+    /// The bytes the stream wrote before it was last resumed.
+    to_endpoint_bytes: u64,
+    to_client_bytes: u64,
+    handoffs: Handoffs,
+}
+
+impl Request {
+    /// Indicates whether the stream should be handed off. Otherwise, the current task is
+    /// notified when it should be.
+    pub fn poll_requested(&self) -> bool {
+        let mut inner = self.handoffs.0.borrow_mut();
+        if inner.handing_off {
+            return true;
+        }
+        inner.streams.insert(self.id, Some(task::current()));
+        false
+    }
+
+    /// Sends the stream, whose `state` describes only the bytes it has written since it
+    /// was resumed and not its destination.
+    ///
+    /// The stream is sent by the handoff thread, so the returned future completes once
+    /// it has been acknowledged and committed. It fails if the stream could not be sent
+    /// or committed, in which case the receiver does not resume it and the stream should
+    /// continue in this process.
+    pub fn send(self, mut handoff: Handoff) -> Sending {
+        handoff.state.dst_name = self.dst_name.clone();
+        handoff.state.to_endpoint_bytes += self.to_endpoint_bytes;
+        handoff.state.to_client_bytes += self.to_client_bytes;
+        let client_addr = handoff.state.client_addr;
+        let endpoint_addr = handoff.state.endpoint_addr;
+        let (sent_tx, sent_rx) = oneshot::channel();
+        if let Some(ref sender) = self.handoffs.0.borrow().sender {
+            // If the thread has exited, the stream is dropped along with `sent_tx`, and
+            // is reported as not sent.
+            let _ = sender.unbounded_send((handoff, sent_tx));
+        }
+        Sending {
+            request: self,
+            sent: sent_rx,
+            client_addr,
+            endpoint_addr,
+        }
+    }
+
+    /// Records that the stream could not be prepared to be handed off, e.g. because
+    /// its sockets could not be duplicated. The stream continues in this process.
+    pub fn fail(self, e: &io::Error) {
+        warn!("failed to hand off a stream: {}", e);
+        self.handoffs.0.borrow().metrics.failures.incr(1);
+    }
+
+    /// Records that the stream may not be handed off. The stream continues in this
+    /// process.
+    pub fn refuse(self, why: Refusal) {
+        debug!("not handing off a stream: {}", why);
+        let inner = self.handoffs.0.borrow();
+        match why {
+            Refusal::Tls => inner.metrics.tls.incr(1),
+            Refusal::Closing => inner.metrics.closing.incr(1),
+        }
+    }
+}
+
+/// Completes once a stream has been handed off, or fails if it could not be.
+///
+/// The stream remains registered, so that `HandingOff` waits for it, until this is
+/// dropped.
+pub struct Sending {
+    request: Request,
+    sent: oneshot::Receiver<io::Result<()>>,
+    client_addr: net::SocketAddr,
+    endpoint_addr: net::SocketAddr,
+}
+
+impl Future for Sending {
+    type Item = ();
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let sent = match self.sent.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(sent)) => sent,
+            Err(_) => Err(io::Error::new(io::ErrorKind::NotConnected, "handoff socket failed")),
+        };
+        let inner = self.request.handoffs.0.borrow();
+        match sent {
+            Ok(()) => {
+                debug!("handed off {} to {}", self.client_addr, self.endpoint_addr);
+                inner.metrics.sent.incr(1);
+            }
+            Err(ref e) => {
+                warn!("failed to hand off {}: {}", self.client_addr, e);
+                inner.metrics.failures.incr(1);
+            }
+        }
+        sent.map(Async::Ready)
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        let mut inner = self.handoffs.0.borrow_mut();
+        inner.streams.remove(&self.id);
+        if inner.streams.is_empty() {
+            if let Some(task) = inner.task.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handoff, State, encode_len, recv, send, send_rights};
+    use serde_json;
+    use std::os::unix::io::AsRawFd;
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    /// Connects a TCP stream over loopback, returning both of its ends.
+    fn tcp_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let near = TcpStream::connect(listener.local_addr().unwrap()).expect("failed to connect");
+        let (far, _) = listener.accept().expect("failed to accept");
+        (near, far)
+    }
+
+    #[test]
+    fn passes_sockets_and_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let (mut client, client_sock) = tcp_pair(&listener);
+        let (endpoint_sock, mut endpoint) = tcp_pair(&listener);
+        let state = State {
+            dst_name: "/svc/echo".into(),
+            client_addr: client.local_addr().unwrap(),
+            endpoint_addr: endpoint.local_addr().unwrap(),
+            to_endpoint_bytes: 5,
+            to_client_bytes: 7,
+            to_endpoint: b"unwritten".to_vec(),
+            to_client: Vec::new(),
+        };
+
+        // The sender waits for the handoff to be acknowledged.
+        let (tx, rx) = UnixStream::pair().expect("failed to connect");
+        let sent = {
+            let state = state.clone();
+            thread::spawn(move || {
+                let handoff = Handoff {
+                    state,
+                    client: client_sock,
+                    endpoint: endpoint_sock,
+                };
+                send(&tx, &handoff)
+            })
+        };
+        let received = recv(&rx).expect("failed to receive").expect(
+            "no handoff received",
+        );
+        sent.join().unwrap().expect("failed to send");
+        assert_eq!(received.state, state);
+
+        // The sender's copies of the sockets are closed, and the received sockets carry the
+        // stream in both directions.
+        let Handoff {
+            client: mut client_sock,
+            endpoint: mut endpoint_sock,
+            ..
+        } = received;
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        client_sock.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        endpoint.write_all(b"pong").unwrap();
+        endpoint_sock.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn rejects_handoffs_without_sockets() {
+        let (mut tx, rx) = UnixStream::pair().expect("failed to connect");
+        tx.write_all(&[0, 0, 0, 2]).unwrap();
+        let err = recv(&rx).expect_err("received a handoff without sockets");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A connection closed between handoffs ends cleanly.
+        drop(tx);
+        assert!(recv(&rx).expect("failed to receive").is_none());
+    }
+
+    #[test]
+    fn drops_handoffs_that_are_not_committed() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let (mut client, mut client_sock) = tcp_pair(&listener);
+        let (endpoint_sock, mut endpoint) = tcp_pair(&listener);
+        let state = State {
+            dst_name: "/svc/echo".into(),
+            client_addr: client.local_addr().unwrap(),
+            endpoint_addr: endpoint.local_addr().unwrap(),
+            to_endpoint_bytes: 0,
+            to_client_bytes: 0,
+            to_endpoint: Vec::new(),
+            to_client: Vec::new(),
+        };
+        let state = serde_json::to_vec(&state).unwrap();
+        let mut frame = encode_len(state.len()).to_vec();
+        frame.extend_from_slice(&state);
+
+        // The sender passes the sockets and reads the acknowledgement, but gives up
+        // before committing the handoff.
+        let (mut tx, rx) = UnixStream::pair().expect("failed to connect");
+        let receiver = thread::spawn(move || recv(&rx));
+        let fds = [client_sock.as_raw_fd(), endpoint_sock.as_raw_fd()];
+        let sent = send_rights(tx.as_raw_fd(), &frame, fds).expect("failed to send");
+        tx.write_all(&frame[sent..]).unwrap();
+        let mut ack = [0u8; 1];
+        tx.read_exact(&mut ack).unwrap();
+        drop(tx);
+        let err = receiver.join().unwrap().expect_err("resumed an uncommitted handoff");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // The receiver's copies of the sockets were closed without shutting the stream
+        // down, so it continues in the sender.
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        client_sock.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        client_sock.write_all(b"pong").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+        drop(endpoint_sock);
+        let mut rest = Vec::new();
+        endpoint.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
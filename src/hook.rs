@@ -0,0 +1,265 @@
+//! Lets applications embedding the proxy run their own logic as connections are
+//! accepted, dispatched, and closed.
+//!
+//! Hooks are registered on an `AppBuilder` and are called, in the order in which they
+//! were registered, on the thread that serves connections. `on_accept` is called before
+//! a connection is routed, so that a hook may consult another service (e.g. to
+//! authorize the client); if any hook rejects the connection, it is closed without an
+//! upstream connection being attempted. A hook's decision is bounded by a timeout, so
+//! that a slow hook cannot hold connections indefinitely: a decision that is not made
+//! in time, or that fails, rejects the connection.
+//!
+//! `on_dispatch` and `on_close` are called synchronously and should not block. Hooks are
+//! not run for UDP servers.
+
+use super::{Result, app};
+use super::balancer::Generation;
+use super::connection::TcpInfo;
+use super::connector::Cidr;
+use super::server::TlsProperties;
+use super::timeout::timeout;
+use futures::{Future, future};
+use std::{fmt, io, net};
+use std::rc::Rc;
+use std::time::Duration;
+use tacho;
+use tokio_core::net::TcpStream;
+use tokio_timer::Timer;
+
+/// The default bound on the time each hook may take to decide on a connection.
+pub const DEFAULT_HOOK_TIMEOUT_MS: u64 = 1_000;
+
+/// Whether a connection may be dispatched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The connection may be routed, subject to other hooks.
+    Accept,
+    /// The connection is closed without being routed.
+    Reject,
+}
+
+/// A decision that may be made asynchronously. A failed decision rejects the connection.
+pub type DecisionFuture = Box<Future<Item = Decision, Error = ()>>;
+
+/// Describes a connection once it has closed.
+#[derive(Clone, Debug)]
+pub struct ConnectionSummary {
+    /// The client's address.
+    pub client_addr: net::SocketAddr,
+    /// The address on which the connection was accepted.
+    pub server_addr: net::SocketAddr,
+    /// The name to which the connection was routed, e.g. `/svc/web`.
+    pub dst_name: String,
+    /// The endpoint to which the connection was dispatched, if it was.
+    pub endpoint_addr: Option<net::SocketAddr>,
+    /// The generation of the endpoints from which the endpoint was selected, if the
+    /// connection was dispatched after a resolution was applied.
+    pub resolution: Option<Generation>,
+    /// The bytes read from the client.
+    pub rx_bytes: u64,
+    /// The bytes written to the client.
+    pub tx_bytes: u64,
+    /// The time from when the connection was accepted until it closed.
+    pub duration: Duration,
+    /// Set when the connection failed, rather than being closed by either peer.
+    pub error: Option<io::ErrorKind>,
+    /// The final sample of the client's socket, if the server samples `tcpInfo`.
+    pub client_tcp_info: Option<TcpInfo>,
+    /// The final sample of the endpoint's socket, if the server samples `tcpInfo` and
+    /// the connection was dispatched.
+    pub endpoint_tcp_info: Option<TcpInfo>,
+    /// The parameters negotiated with the client, if the server terminates TLS.
+    pub tls: Option<TlsProperties>,
+}
+
+/// Callbacks run at points in each connection's lifecycle.
+///
+/// Each callback does nothing by default, so that hooks need only implement those they
+/// use.
+pub trait ConnectionHook {
+    /// Decides whether a connection from `client_addr` may be dispatched.
+    fn on_accept(&self, client_addr: &net::SocketAddr) -> DecisionFuture {
+        let _ = client_addr;
+        Box::new(future::ok(Decision::Accept))
+    }
+
+    /// Called once a connection from `client_addr` is connected to `endpoint_addr`.
+    fn on_dispatch(&self, client_addr: &net::SocketAddr, endpoint_addr: &net::SocketAddr) {
+        let _ = (client_addr, endpoint_addr);
+    }
+
+    /// Called once an accepted connection has closed, whether or not it was dispatched.
+    /// Connections rejected by a hook are not reported.
+    fn on_close(&self, summary: &ConnectionSummary) {
+        let _ = summary;
+    }
+}
+
+/// A hook that does nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopHook;
+
+impl ConnectionHook for NoopHook {}
+
+/// A hook that rejects clients from any of a set of address ranges.
+#[derive(Clone, Debug)]
+pub struct RejectCidrs(Vec<Cidr>);
+
+impl RejectCidrs {
+    /// Rejects clients in any of `cidrs`, e.g. `10.0.0.0/8`.
+    ///
+    /// Fails with `Error::Config` if a range cannot be parsed.
+    pub fn new<S: AsRef<str>>(cidrs: &[S]) -> Result<RejectCidrs> {
+        let mut parsed = Vec::with_capacity(cidrs.len());
+        for cidr in cidrs {
+            let cidr = cidr.as_ref();
+            let c = cidr.parse::<Cidr>().map_err(
+                |_| app::Error::InvalidHookCidr(cidr.to_owned()),
+            )?;
+            parsed.push(c);
+        }
+        Ok(RejectCidrs(parsed))
+    }
+}
+
+impl ConnectionHook for RejectCidrs {
+    fn on_accept(&self, client_addr: &net::SocketAddr) -> DecisionFuture {
+        let ip = client_addr.ip();
+        let decision = if self.0.iter().any(|cidr| cidr.contains(&ip)) {
+            Decision::Reject
+        } else {
+            Decision::Accept
+        };
+        Box::new(future::ok(decision))
+    }
+}
+
+/// The hooks registered on an application.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Rc<ConnectionHook>>,
+    timeout: Option<Duration>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("hooks", &self.hooks.len())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub fn push(&mut self, hook: Rc<ConnectionHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or_else(
+            || Duration::from_millis(DEFAULT_HOOK_TIMEOUT_MS),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Counts connections closed by hooks as `hook_rejects`, labeled by the `cause` of
+    /// each: `rejected`, `timeout`, or `failure`.
+    pub fn bind(&self, timer: &Timer, metrics: &tacho::Scope) -> BoundHooks {
+        let rejects = |cause: &'static str| {
+            metrics.clone().labeled("cause", cause).counter("hook_rejects")
+        };
+        BoundHooks(Rc::new(Bound {
+            hooks: self.hooks.clone(),
+            timeout: self.timeout(),
+            timer: timer.clone(),
+            rejected: rejects("rejected"),
+            timeouts: rejects("timeout"),
+            failures: rejects("failure"),
+        }))
+    }
+}
+
+/// A server's hooks.
+#[derive(Clone)]
+pub struct BoundHooks(Rc<Bound>);
+
+struct Bound {
+    hooks: Vec<Rc<ConnectionHook>>,
+    timeout: Duration,
+    timer: Timer,
+    rejected: tacho::Counter,
+    timeouts: tacho::Counter,
+    failures: tacho::Counter,
+}
+
+impl BoundHooks {
+    /// Completes with the connection if every hook accepts it, or with nothing if it was
+    /// rejected. Never fails, so that the accept stream is not interrupted.
+    pub fn accept(
+        &self,
+        tcp: TcpStream,
+        src_addr: net::SocketAddr,
+    ) -> Box<Future<Item = Option<(TcpStream, net::SocketAddr)>, Error = io::Error>> {
+        let mut decision: Box<Future<Item = bool, Error = io::Error>> =
+            Box::new(future::ok(true));
+        for hook in &self.0.hooks {
+            let hook = hook.clone();
+            let bound = self.0.clone();
+            decision = Box::new(decision.and_then(move |accepted| {
+                if !accepted {
+                    return future::Either::A(future::ok(false));
+                }
+                let decided = hook.on_accept(&src_addr).map_err(|_| {
+                    io::Error::new(io::ErrorKind::Other, "hook failed")
+                });
+                let decided = timeout(decided, Some(bound.timeout), &bound.timer);
+                let decided = decided.then(move |res| -> io::Result<bool> {
+                    match res {
+                        Ok(Decision::Accept) => Ok(true),
+                        Ok(Decision::Reject) => {
+                            debug!("hook rejected connection from {}", src_addr);
+                            bound.rejected.incr(1);
+                            Ok(false)
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                            debug!("hook timed out for connection from {}", src_addr);
+                            bound.timeouts.incr(1);
+                            Ok(false)
+                        }
+                        Err(e) => {
+                            debug!("hook failed for connection from {}: {}", src_addr, e);
+                            bound.failures.incr(1);
+                            Ok(false)
+                        }
+                    }
+                });
+                future::Either::B(decided)
+            }));
+        }
+        // Dropping a rejected connection closes it.
+        Box::new(decision.map(move |accepted| if accepted {
+            Some((tcp, src_addr))
+        } else {
+            None
+        }))
+    }
+
+    pub fn dispatch(&self, client_addr: &net::SocketAddr, endpoint_addr: &net::SocketAddr) {
+        for hook in &self.0.hooks {
+            hook.on_dispatch(client_addr, endpoint_addr);
+        }
+    }
+
+    pub fn close(&self, summary: &ConnectionSummary) {
+        for hook in &self.0.hooks {
+            hook.on_close(summary);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Describes this process for fleet management: its build, how long it has been
+//! running, and which configuration it was started with.
+//!
+//! The configuration is identified by a hash of its canonical serialization, so that
+//! configurations that differ only in formatting or in the order of their fields share a
+//! hash. The build is also reported as a constant `build_info` gauge, labeled by version,
+//! so that other metrics may be correlated with it.
+
+use super::connector;
+use serde_json;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// This build's version.
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// The commit from which this build was made, if it was provided at build time via the
+/// `LINKERD_TCP_GIT_SHA` environment variable.
+pub const GIT_SHA: Option<&'static str> = option_env!("LINKERD_TCP_GIT_SHA");
+
+/// Describes a running process, as served by the admin server's `/admin/info` endpoint.
+#[derive(Clone, Debug)]
+pub struct Info {
+    start: Instant,
+    started_at: SystemTime,
+    host: Option<String>,
+    proxies: usize,
+    config_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InfoJson<'a> {
+    version: &'a str,
+    git_sha: Option<&'a str>,
+    start_time_secs: u64,
+    uptime_secs: u64,
+    host: Option<&'a str>,
+    proxies: usize,
+    config_hash: Option<&'a str>,
+}
+
+impl Info {
+    /// Describes a process, started now, that serves `proxies` servers with the
+    /// configuration identified by `config_hash`, if it was loaded from one.
+    pub fn new(proxies: usize, config_hash: Option<String>) -> Info {
+        Info {
+            start: Instant::now(),
+            started_at: SystemTime::now(),
+            host: connector::hostname().ok(),
+            proxies,
+            config_hash,
+        }
+    }
+
+    /// The hash of the configuration from which the process was built, if any.
+    pub fn config_hash(&self) -> Option<&str> {
+        self.config_hash.as_ref().map(|h| h.as_str())
+    }
+
+    /// Renders the process's description as JSON.
+    pub fn to_json(&self) -> String {
+        let info = InfoJson {
+            version: VERSION,
+            git_sha: GIT_SHA,
+            start_time_secs: self.started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_else(|_| Duration::from_secs(0))
+                .as_secs(),
+            uptime_secs: self.start.elapsed().as_secs(),
+            host: self.host.as_ref().map(|h| h.as_str()),
+            proxies: self.proxies,
+            config_hash: self.config_hash(),
+        };
+        serde_json::to_string_pretty(&info).expect("failed to serialize info")
+    }
+}
+
+/// Hashes a value's canonical serialization, in which object fields are sorted, as 16
+/// hex digits.
+pub fn canonical_hash<T: ::serde::Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    // JSON objects are sorted by key when they are read as `Value`s.
+    let value = serde_json::to_value(value)?;
+    let canonical = serde_json::to_string(&value)?;
+    Ok(format!("{:016x}", connector::stable_hash(canonical.as_bytes())))
+}
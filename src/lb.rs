@@ -0,0 +1,94 @@
+//! Embeds a load balancer without the rest of the proxy.
+//!
+//! Within the proxy, balancers are created by routers from namerd resolutions and report
+//! into tacho. An embedded balancer is given its endpoints directly and reports through
+//! any `Metrics` implementation.
+//!
+//! Applications that embed the whole proxy may instead observe and filter its
+//! connections by registering a `ConnectionHook` on an `AppBuilder`.
+
+use super::{Path, Result, app, balancer, state};
+use super::connector::{Connector, ConnectorConfig};
+use super::dns::Dns;
+use super::resolver::Resolve;
+use futures::Stream;
+use rand::{self, SeedableRng, StdRng};
+use tokio_core::reactor::Handle;
+use tokio_timer;
+
+pub use super::WeightedAddr;
+pub use super::balancer::{Balancer, Connect, Generation, OpenSession, SelectionStrategy, Session,
+                          time_selections};
+pub use super::connection::TcpInfo;
+pub use super::hook::{ConnectionHook, ConnectionSummary, Decision, DecisionFuture, NoopHook,
+                      RejectCidrs};
+pub use super::metrics::{Counter, Gauge, Key, Metrics, NoopMetrics, Scope, TimeUnit, Timer,
+                         Timed, timed};
+
+/// Creates a balancer for `dst` (e.g. `/svc/web`) over the endpoints produced by
+/// `addrs`, using the default client configuration.
+///
+/// Each item produced by `addrs` replaces the balancer's set of endpoints. The balancer
+/// runs on `reactor`.
+pub fn new<S>(
+    reactor: &Handle,
+    timer: &tokio_timer::Timer,
+    dst: &str,
+    addrs: S,
+    metrics: &Scope,
+) -> Balancer
+where
+    S: Stream<Item = Vec<WeightedAddr>, Error = ()> + 'static,
+{
+    let connector = ConnectorConfig::default().mk_connector().expect(
+        "default client configuration must be valid",
+    );
+    mk(reactor, timer, dst, addrs, connector, metrics)
+}
+
+/// Creates a balancer for `dst` over the endpoints produced by `addrs`, as `new` does,
+/// configured by `config`.
+///
+/// Fails with `Error::Config` if `config` is invalid.
+pub fn with_config<S>(
+    reactor: &Handle,
+    timer: &tokio_timer::Timer,
+    dst: &str,
+    addrs: S,
+    config: &ConnectorConfig,
+    metrics: &Scope,
+) -> Result<Balancer>
+where
+    S: Stream<Item = Vec<WeightedAddr>, Error = ()> + 'static,
+{
+    let connector = config.mk_connector().map_err(app::Error::Connector)?;
+    Ok(mk(reactor, timer, dst, addrs, connector, metrics))
+}
+
+fn mk<S>(
+    reactor: &Handle,
+    timer: &tokio_timer::Timer,
+    dst: &str,
+    addrs: S,
+    connector: Connector,
+    metrics: &Scope,
+) -> Balancer
+where
+    S: Stream<Item = Vec<WeightedAddr>, Error = ()> + 'static,
+{
+    let dst = Path::from(dst);
+    let state = state::Registry::default().reporter("lb", &dst);
+    let rng = StdRng::from_seed(&[rand::random::<usize>()][..]);
+    balancer::new(
+        reactor,
+        timer,
+        &dst,
+        connector,
+        Resolve::from_stream(addrs),
+        state,
+        rng,
+        metrics,
+        &Dns::system(),
+        None,
+    )
+}
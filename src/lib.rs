@@ -10,11 +10,16 @@
 extern crate bytes;
 #[macro_use]
 extern crate log;
+#[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
 extern crate hyper;
+extern crate libc;
 extern crate ordermap;
 extern crate rand;
+#[cfg(feature = "tls")]
 extern crate rustls;
+#[macro_use]
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -22,20 +27,54 @@ extern crate serde_json;
 extern crate serde_yaml;
 extern crate tacho;
 extern crate tokio_core;
-#[macro_use]
 extern crate tokio_io;
 extern crate tokio_timer;
+extern crate tokio_uds;
+#[cfg(feature = "tls")]
+extern crate untrusted;
 extern crate url;
+#[cfg(feature = "tls")]
+extern crate webpki;
 
-mod admin;
+mod accept;
+pub mod admin;
 pub mod app;
 mod balancer;
+pub mod bench;
 mod connection;
 mod connector;
+mod damping;
+pub mod dns;
+mod duration;
+mod error;
+mod fd;
+mod handoff;
+mod hook;
+pub mod info;
+pub mod lb;
+mod log_limit;
+mod metrics;
+mod metrics_log;
+mod notify;
 mod path;
 mod resolver;
 mod router;
+mod schema;
+mod security;
 mod server;
+mod startup;
+mod state;
+mod summary;
+mod supervise;
+mod timeout;
+mod tracing;
 
-use balancer::WeightedAddr;
+pub use balancer::WeightedAddr;
+pub use connection::TcpInfo;
+pub use error::{ConnectErrorKind, Error, ResolveError, Result};
+pub use server::TlsProperties;
+#[cfg(feature = "tls")]
+pub use server::{ClientHello, HandshakeFailure};
+pub use state::{Ejections, Registry, WeightOverrides};
+pub use supervise::log_panics;
 use path::Path;
@@ -0,0 +1,273 @@
+//! Limits how often repeated events, such as connection failures, are logged.
+//!
+//! Events are grouped by key (e.g. an endpoint's address). Each key has a bucket of
+//! `burst` tokens that is refilled once `window` has elapsed since the key's window
+//! began. An event that finds a token is logged; later identical events are counted,
+//! and are summarized in a single line once the window has elapsed, either by the next
+//! event or by `flush`. While events continue, each window produces one summary.
+//!
+//! An event whose message differs from its key's previous event (a change of state, e.g.
+//! from `Connection refused` to a timeout) is always logged, after any summary of the
+//! previous state's suppressed events. A window of zero disables suppression.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Limits the events logged for each key.
+pub struct LogLimit<K> {
+    burst: usize,
+    window: Duration,
+    keys: HashMap<K, Bucket>,
+}
+
+struct Bucket {
+    /// The message of the key's most recent event.
+    message: String,
+    window_start: Instant,
+    tokens: usize,
+    /// The number of events suppressed in the current window.
+    suppressed: usize,
+}
+
+/// How an event should be logged.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// The event should be logged, after the summary of the previous state's suppressed
+    /// events, if any.
+    Log(Option<Suppressed>),
+    /// The event should be logged as part of a summary of it and the events suppressed
+    /// before it.
+    Summarize(Suppressed),
+    /// The event should not be logged. It is counted, to be summarized later.
+    Suppress,
+}
+
+/// A summary of suppressed events.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Suppressed {
+    /// The number of events summarized.
+    pub count: usize,
+    /// The time over which the events occurred.
+    pub elapsed: Duration,
+    /// The message shared by the events.
+    pub message: String,
+}
+
+impl<K: Hash + Eq> LogLimit<K> {
+    /// Logs up to `burst` identical events for each key per `window`.
+    pub fn new(burst: usize, window: Duration) -> LogLimit<K> {
+        LogLimit {
+            burst,
+            window,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Records an event for `key`, described by `message`, that occurred at `now`.
+    pub fn record(&mut self, key: K, message: &str, now: Instant) -> Verdict {
+        let (burst, window) = (self.burst, self.window);
+        let b = self.keys.entry(key).or_insert_with(|| {
+            Bucket {
+                message: String::new(),
+                window_start: now,
+                tokens: burst,
+                suppressed: 0,
+            }
+        });
+
+        if b.message != message {
+            let prior = b.summarize(now);
+            b.message = message.to_owned();
+            b.window_start = now;
+            b.tokens = burst.saturating_sub(1);
+            return Verdict::Log(prior);
+        }
+
+        if now >= b.window_start + window {
+            if b.suppressed > 0 {
+                b.suppressed += 1;
+                let summary = b.summarize(now);
+                b.window_start = now;
+                b.tokens = 0;
+                return Verdict::Summarize(summary.expect("events were suppressed"));
+            }
+            b.window_start = now;
+            b.tokens = burst;
+        }
+        if b.tokens > 0 {
+            b.tokens -= 1;
+            return Verdict::Log(None);
+        }
+        b.suppressed += 1;
+        Verdict::Suppress
+    }
+
+    /// Summarizes the suppressed events of each key whose window has elapsed by `now`,
+    /// and forgets keys that have had no events for a whole window.
+    pub fn flush(&mut self, now: Instant) -> Vec<(K, Suppressed)>
+    where
+        K: Clone,
+    {
+        let window = self.window;
+        let mut summaries = Vec::new();
+        self.keys.retain(|key, b| {
+            if now < b.window_start + window {
+                return true;
+            }
+            match b.summarize(now) {
+                None => false,
+                Some(summary) => {
+                    summaries.push((key.clone(), summary));
+                    // The next window's events are summarized in turn.
+                    b.window_start = now;
+                    b.tokens = 0;
+                    true
+                }
+            }
+        });
+        summaries
+    }
+
+    /// Forgets `key` (e.g. once it has recovered), summarizing its suppressed events.
+    pub fn reset(&mut self, key: &K, now: Instant) -> Option<Suppressed> {
+        self.keys.remove(key).and_then(|mut b| b.summarize(now))
+    }
+}
+
+impl Bucket {
+    /// Takes a summary of the events suppressed in the current window, if any.
+    fn summarize(&mut self, now: Instant) -> Option<Suppressed> {
+        if self.suppressed == 0 {
+            return None;
+        }
+        let summary = Suppressed {
+            count: self.suppressed,
+            elapsed: if now > self.window_start {
+                now - self.window_start
+            } else {
+                Duration::from_secs(0)
+            },
+            message: self.message.clone(),
+        };
+        self.suppressed = 0;
+        Some(summary)
+    }
+}
+
+/// E.g. `4,312 times in the last 60s: Connection refused`.
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.count.to_string();
+        let mut count = String::with_capacity(digits.len() + digits.len() / 3);
+        for (i, d) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                count.push(',');
+            }
+            count.push(d);
+        }
+        let round = if self.elapsed.subsec_nanos() >= 500_000_000 { 1 } else { 0 };
+        write!(
+            f,
+            "{} {} in the last {}s: {}",
+            count,
+            if self.count == 1 { "time" } else { "times" },
+            self.elapsed.as_secs() + round,
+            self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogLimit, Suppressed, Verdict};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    fn dns_addr(n: u8, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)), port)
+    }
+
+    #[test]
+    fn summarizes_repeated_failures_once_per_window() {
+        let t0 = Instant::now();
+        let secs = |s: u64| t0 + Duration::from_secs(s);
+        let addr = dns_addr(1, 80);
+        let mut log = LogLimit::new(1, Duration::from_secs(60));
+
+        assert_eq!(log.record(addr, "Connection refused", t0), Verdict::Log(None));
+        for i in 0..4_311 {
+            let at = t0 + Duration::from_millis(i);
+            assert_eq!(log.record(addr, "Connection refused", at), Verdict::Suppress);
+        }
+        assert!(log.flush(secs(59)).is_empty());
+
+        // The failure that ends the window is summarized along with those suppressed.
+        let summary = match log.record(addr, "Connection refused", secs(60)) {
+            Verdict::Summarize(s) => s,
+            v => panic!("unexpected verdict: {:?}", v),
+        };
+        assert_eq!(summary.count, 4_312);
+        assert_eq!(
+            summary.to_string(),
+            "4,312 times in the last 60s: Connection refused"
+        );
+
+        // Failures suppressed in the next window are summarized by a flush once it ends.
+        assert_eq!(log.record(addr, "Connection refused", secs(70)), Verdict::Suppress);
+        assert_eq!(log.record(addr, "Connection refused", secs(80)), Verdict::Suppress);
+        assert!(log.flush(secs(90)).is_empty());
+        let flushed = log.flush(secs(120));
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].0, addr);
+        assert_eq!(flushed[0].1.count, 2);
+
+        // Once quiet for a whole window, the endpoint is forgotten and logged anew.
+        assert!(log.flush(secs(180)).is_empty());
+        assert_eq!(log.record(addr, "Connection refused", secs(181)), Verdict::Log(None));
+    }
+
+    #[test]
+    fn logs_changes_in_failures_and_recoveries_with_summaries() {
+        let t0 = Instant::now();
+        let secs = |s: u64| t0 + Duration::from_secs(s);
+        let (a, b) = (dns_addr(1, 80), dns_addr(2, 80));
+        let mut log = LogLimit::new(1, Duration::from_secs(60));
+
+        assert_eq!(log.record(a, "Connection refused", t0), Verdict::Log(None));
+        assert_eq!(log.record(a, "Connection refused", secs(1)), Verdict::Suppress);
+        assert_eq!(log.record(a, "Connection refused", secs(2)), Verdict::Suppress);
+        // Endpoints are limited independently.
+        assert_eq!(log.record(b, "Connection refused", secs(2)), Verdict::Log(None));
+
+        // A different failure is logged immediately, after a summary of the prior one.
+        assert_eq!(
+            log.record(a, "connection timed out", secs(10)),
+            Verdict::Log(Some(Suppressed {
+                count: 2,
+                elapsed: Duration::from_secs(10),
+                message: "Connection refused".into(),
+            }))
+        );
+        assert_eq!(log.record(a, "connection timed out", secs(11)), Verdict::Suppress);
+
+        // A recovery summarizes the failures that preceded it.
+        let summary = log.reset(&a, secs(20)).expect("failures were suppressed");
+        assert_eq!(summary.count, 1);
+        assert_eq!(summary.to_string(), "1 time in the last 10s: connection timed out");
+        assert_eq!(log.reset(&b, secs(20)), None);
+        assert_eq!(log.record(a, "connection timed out", secs(21)), Verdict::Log(None));
+    }
+
+    #[test]
+    fn logs_every_failure_without_a_suppression_window() {
+        let t0 = Instant::now();
+        let addr = dns_addr(1, 80);
+        let mut log = LogLimit::new(1, Duration::from_secs(0));
+        for _ in 0..3 {
+            assert_eq!(log.record(addr, "Connection refused", t0), Verdict::Log(None));
+        }
+        assert!(log.flush(t0).is_empty());
+    }
+}
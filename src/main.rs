@@ -8,47 +8,109 @@ extern crate pretty_env_logger;
 extern crate tokio_core;
 extern crate tokio_timer;
 
-use clap::{Arg, App as ClapApp};
-use linkerd_tcp::app::{self, AppConfig, App, AdminRunner, RouterSpawner};
+use clap::{AppSettings, Arg, ArgMatches, App as ClapApp, SubCommand};
+use linkerd_tcp::app::{self, AppConfig, App, AdminRunner, ConnectorConfig, Notifier, Privileges,
+                       RouterSpawner, TlsConnectorFactoryConfig};
+use linkerd_tcp::{bench, duration};
+use futures::Future;
 use std::collections::VecDeque;
-use std::fs;
 use std::io::Read;
+use std::path::PathBuf;
 use std::thread;
+use std::time::{Duration, Instant};
 use tokio_core::reactor::{Core, Handle};
 use tokio_timer::Timer;
 
 static CONFIG_PATH_ARG: &'static str = "PATH";
+static CONFIG_ARG: &'static str = "config";
+static CONFIG_DIR_ARG: &'static str = "config-dir";
+static CHAOS_ARG: &'static str = "chaos";
+
+static BENCH_CMD: &'static str = "bench";
+static TARGET_ARG: &'static str = "target";
+static CONNECTIONS_ARG: &'static str = "connections";
+static RATE_ARG: &'static str = "rate";
+static PAYLOAD_BYTES_ARG: &'static str = "payload-bytes";
+static DURATION_ARG: &'static str = "duration";
+static TLS_ARG: &'static str = "tls";
+static SNI_ARG: &'static str = "sni";
+static TRUST_CERTS_ARG: &'static str = "trust-certs";
 
 /// Runs linkerd-tcp.
 ///
-/// Accepts a configuration file
+/// Accepts one or more configuration files, or directories of configuration files, which
+/// are combined by `app::load_paths`. The `bench` subcommand generates load instead.
 fn main() {
     // Configure the logger from the RUST_LOG environment variable.
     drop(pretty_env_logger::init());
+    // Log panics, which are contained to the connection or task that caused them.
+    linkerd_tcp::log_panics();
 
     // Load command-line options.
     let opts = ClapApp::new(crate_name!())
         .version(crate_version!())
         .about(crate_description!())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name(CONFIG_PATH_ARG)
-                .required(true)
+                .required_unless_one(&[CONFIG_ARG, CONFIG_DIR_ARG])
                 .index(1)
-                .help("Config file path."),
+                .help("Config file path, or - to read a config from stdin."),
+        )
+        .arg(
+            Arg::with_name(CONFIG_ARG)
+                .long(CONFIG_ARG)
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Additional config file path. May be repeated."),
         )
+        .arg(
+            Arg::with_name(CONFIG_DIR_ARG)
+                .long(CONFIG_DIR_ARG)
+                .value_name("DIR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Directory whose *.yml, *.yaml, and *.json files are loaded in \
+                     lexical order. May be repeated.",
+                ),
+        )
+        .arg(Arg::with_name(CHAOS_ARG).long(CHAOS_ARG).help(
+            "Injects the faults configured by each router's chaos. Refused unless the \
+             config sets allowChaos: true.",
+        ))
+        .subcommand(bench_command())
         .get_matches();
 
-    // Parse configuration file.
-    let config: AppConfig = {
-        let path = opts.value_of(CONFIG_PATH_ARG).unwrap();
-        let mut txt = String::new();
-        let res = match path {
-            "-" => ::std::io::stdin().read_to_string(&mut txt),
-            path => fs::File::open(path).and_then(|mut f| f.read_to_string(&mut txt)),
-        };
-        match res {
-            Err(e) => panic!("error reading configuration from {}: {}", path, e),
-            Ok(_) => txt.parse().expect("failed to parse configuration"),
+    if let Some(opts) = opts.subcommand_matches(BENCH_CMD) {
+        run_bench(opts);
+        return;
+    }
+
+    // Parse configuration files: the positional path first, then each `--config`, then
+    // each `--config-dir`. Later files override earlier files' global settings.
+    let config: AppConfig = match opts.value_of(CONFIG_PATH_ARG) {
+        Some("-") => {
+            let mut txt = String::new();
+            if let Err(e) = ::std::io::stdin().read_to_string(&mut txt) {
+                panic!("error reading configuration from stdin: {}", e);
+            }
+            txt.parse().expect("failed to parse configuration")
+        }
+        path => {
+            let mut paths: Vec<PathBuf> = path.into_iter().map(PathBuf::from).collect();
+            for arg in &[CONFIG_ARG, CONFIG_DIR_ARG] {
+                if let Some(vs) = opts.values_of(arg) {
+                    paths.extend(vs.map(PathBuf::from));
+                }
+            }
+            match app::load_paths(&paths) {
+                Err(e) => panic!("error loading configuration: {}", e),
+                Ok(config) => config,
+            }
         }
     };
     debug!("parsed config: {:?}", config);
@@ -57,7 +119,21 @@ fn main() {
     // connected by synchronization primitives as needed, but no work is being done yet.
     // Next, we'll attach each of these to a reactor in an independent thread, driving
     // both admin and serving work.
-    let App { routers, admin } = config.into_app().expect("failed to load configuration");
+    let mut builder = config.into_builder();
+    if opts.is_present(CHAOS_ARG) {
+        builder = builder.chaos();
+    }
+    // When run by systemd with `Type=notify`, readiness is reported on `$NOTIFY_SOCKET`.
+    if let Some(notifier) = Notifier::from_env() {
+        builder = builder.notifier(notifier);
+    }
+    let App {
+        routers,
+        mut admin,
+        privileges,
+        readiness,
+        handoff,
+    } = builder.build().expect("failed to load configuration");
     debug!("loaded app");
 
     let (closer, closed) = app::closer();
@@ -66,14 +142,62 @@ fn main() {
     // granularity of 100ms.
     let timer = Timer::default();
 
+    // Bind every listener before dropping privileges, so that privileged ports may be
+    // served by an unprivileged process.
+    admin.bind().expect("failed to bind the admin server");
+    let mut core = Core::new().expect("failed to initialize server reactor");
+    spawn_routers(routers, &core.handle(), &timer);
+    // Streams are received from the process being replaced once this one is serving.
+    if let Some(ref handoff) = handoff {
+        handoff.receive(&core.handle(), &timer).expect(
+            "failed to receive handed off streams",
+        );
+    }
+    if let Some(privileges) = privileges {
+        drop_privileges(&privileges);
+    }
+
+    // Every listener is bound, so systemd may be notified once routers have resolved.
+    // The watchdog is pinged from the serving reactor.
+    if let Some(readiness) = readiness {
+        readiness.spawn(&core.handle(), &timer);
+    }
+
     // Create a background admin thread that runs an admin server and executes executes
     // namerd resolutions
     let admin_thread = spawn_admin(admin, closer, &timer);
-    run_routers(routers, closed, &timer);
+
+    // Run until the admin thread closes the application.
+    debug!("running until admin server closes");
+    let deadline = core.run(closed).expect("failed to run");
+
+    // Hand streams off to the process that replaces this one, if any, by the deadline.
+    // Streams that are not handed off continue until the process exits.
+    if let Some(handoff) = handoff {
+        match handoff.hand_off() {
+            Err(e) => info!("not handing off streams: {}", e),
+            Ok(handing_off) => {
+                let now = Instant::now();
+                let remaining = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                let done = handing_off.select2(timer.sleep(remaining)).then(|_| Ok::<(), ()>(()));
+                drop(core.run(done));
+            }
+        }
+    }
     admin_thread.join().expect("failed to join admin thread");
     debug!("stopped")
 }
 
+fn drop_privileges(privileges: &Privileges) {
+    if let Err(e) = privileges.drop_privileges() {
+        panic!("failed to drop privileges: {}", e);
+    }
+}
+
 fn spawn_admin(admin: AdminRunner, closer: app::Closer, timer: &Timer) -> thread::JoinHandle<()> {
     let timer = timer.clone();
     thread::Builder::new()
@@ -88,19 +212,86 @@ fn spawn_admin(admin: AdminRunner, closer: app::Closer, timer: &Timer) -> thread
         .expect("failed to spawn admin thread")
 }
 
-fn run_routers(routers: VecDeque<RouterSpawner>, closed: app::Closed, timer: &Timer) {
-    // Schedule all routers on the main thread.
-    let mut core = Core::new().expect("failed to initialize server reactor");
-    spawn_routers(routers, &core.handle(), timer);
-
-    // Run until the admin thread closes the application.
-    debug!("running until admin server closes");
-    core.run(closed).expect("failed to run");
-}
-
 fn spawn_routers(mut routers: VecDeque<RouterSpawner>, reactor: &Handle, timer: &Timer) {
     while let Some(r) = routers.pop_front() {
         debug!("spawning router");
         r.spawn(reactor, timer).expect("failed to spawn router");
     }
 }
+
+fn bench_command<'a, 'b>() -> ClapApp<'a, 'b> {
+    let value = |name: &'a str, help: &'b str| {
+        Arg::with_name(name).long(name).takes_value(true).help(help)
+    };
+    SubCommand::with_name(BENCH_CMD)
+        .about(
+            "Opens connections to a listener at a fixed rate, writing a payload on each \
+             and reading it back, and reports connection rates, throughput, latencies, \
+             and errors.",
+        )
+        .arg(value(TARGET_ARG, "The address to which connections are made.").required(true))
+        .arg(
+            value(CONNECTIONS_ARG, "The most connections open at once.")
+                .default_value("100"),
+        )
+        .arg(value(RATE_ARG, "Connections opened per second.").default_value("100"))
+        .arg(
+            value(PAYLOAD_BYTES_ARG, "Bytes written, and read back, on each connection.")
+                .default_value("512"),
+        )
+        .arg(
+            value(DURATION_ARG, "How long to open connections for, e.g. 60s.")
+                .default_value("10s"),
+        )
+        .arg(Arg::with_name(TLS_ARG).long(TLS_ARG).requires(SNI_ARG).help(
+            "Completes a TLS handshake on each connection.",
+        ))
+        .arg(value(SNI_ARG, "The server name used for TLS handshakes."))
+        .arg(
+            value(TRUST_CERTS_ARG, "A PEM file of trusted root certificates. May be repeated.")
+                .multiple(true)
+                .number_of_values(1),
+        )
+}
+
+/// Generates the load described by the `bench` subcommand's options and prints a report.
+fn run_bench(opts: &ArgMatches) {
+    let duration = opts.value_of(DURATION_ARG).unwrap();
+    let config = bench::Config {
+        target: value_t_or_exit!(opts, TARGET_ARG, ::std::net::SocketAddr),
+        connections: value_t_or_exit!(opts, CONNECTIONS_ARG, usize),
+        rate: value_t_or_exit!(opts, RATE_ARG, u64),
+        payload_bytes: value_t_or_exit!(opts, PAYLOAD_BYTES_ARG, usize),
+        duration: duration::parse(duration).unwrap_or_else(|e| {
+            panic!("invalid --{}: {}", DURATION_ARG, e)
+        }),
+        client: ConnectorConfig {
+            tls: if opts.is_present(TLS_ARG) {
+                Some(TlsConnectorFactoryConfig {
+                    dns_name: opts.value_of(SNI_ARG).unwrap().to_owned(),
+                    trust_certs: opts.values_of(TRUST_CERTS_ARG)
+                        .map(|vs| vs.map(|v| v.to_owned()).collect()),
+                    ..Default::default()
+                })
+            } else {
+                None
+            },
+            ..Default::default()
+        },
+    };
+    if config.connections == 0 || config.rate == 0 {
+        panic!("--{} and --{} must be positive", CONNECTIONS_ARG, RATE_ARG);
+    }
+
+    println!(
+        "opening {} connections/s to {} for {}, at most {} at once",
+        config.rate,
+        config.target,
+        duration,
+        config.connections
+    );
+    match bench::run(&config) {
+        Ok(report) => print!("{}", report),
+        Err(e) => panic!("bench failed: {}", e),
+    }
+}
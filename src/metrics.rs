@@ -0,0 +1,276 @@
+//! A minimal metrics interface for the balancer and resolver.
+//!
+//! The balancer and resolver only need to increment counters, set gauges, and record
+//! latencies. They do so through `Metrics`, so that applications embedding them may
+//! report into any metrics system. The proxy itself reports into tacho.
+
+use futures::{Async, Future, Poll};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tacho;
+
+/// Identifies a metric.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    /// Scopes applied to the metric's name, outermost first.
+    pub prefixes: Vec<&'static str>,
+
+    /// The metric's name, without prefixes.
+    pub name: &'static str,
+
+    /// Labels qualifying the metric (e.g. `dst`).
+    pub labels: BTreeMap<&'static str, String>,
+}
+
+impl Key {
+    /// The metric's name, joined with its prefixes by `_`.
+    pub fn full_name(&self) -> String {
+        let mut name = String::new();
+        for p in &self.prefixes {
+            name.push_str(p);
+            name.push('_');
+        }
+        name.push_str(self.name);
+        name
+    }
+}
+
+/// The units in which a timer records durations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimeUnit {
+    /// Microseconds.
+    Micros,
+    /// Milliseconds.
+    Millis,
+}
+
+/// Creates the metrics reported by balancers and resolvers.
+///
+/// A metric may be requested more than once for the same key; implementations should
+/// return handles that report into the same metric.
+pub trait Metrics: Send + Sync {
+    /// Creates a counter.
+    fn counter(&self, key: &Key) -> Arc<Counter>;
+
+    /// Creates a gauge.
+    fn gauge(&self, key: &Key) -> Arc<Gauge>;
+
+    /// Creates a timer that records durations in the given units.
+    fn timer(&self, key: &Key, unit: TimeUnit) -> Arc<Timer>;
+}
+
+/// A monotonically increasing count.
+pub trait Counter: Send + Sync {
+    /// Increases the count by `n`.
+    fn incr(&self, n: usize);
+}
+
+/// A value that may go up and down.
+pub trait Gauge: Send + Sync {
+    /// Sets the value.
+    fn set(&self, n: usize);
+
+    /// Increases the value by `n`.
+    fn incr(&self, n: usize);
+
+    /// Decreases the value by `n`.
+    fn decr(&self, n: usize);
+}
+
+/// A distribution of durations.
+pub trait Timer: Send + Sync {
+    /// Records the time elapsed since `t0`.
+    fn record_since(&self, t0: Instant);
+}
+
+/// Builds keys for a `Metrics` implementation.
+///
+/// Like a `tacho::Scope`, a `Scope` may be prefixed and labeled before metrics are
+/// created from it.
+#[derive(Clone)]
+pub struct Scope {
+    metrics: Arc<Metrics>,
+    prefixes: Vec<&'static str>,
+    labels: BTreeMap<&'static str, String>,
+}
+
+impl Scope {
+    /// Creates a root scope that reports into `metrics`.
+    pub fn new<M: Metrics + 'static>(metrics: M) -> Scope {
+        Scope {
+            metrics: Arc::new(metrics),
+            prefixes: Vec::new(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a scope that discards all metrics.
+    pub fn noop() -> Scope {
+        Scope::new(NoopMetrics)
+    }
+
+    /// Prefixes the names of all metrics created from this scope.
+    pub fn prefixed(mut self, prefix: &'static str) -> Scope {
+        self.prefixes.push(prefix);
+        self
+    }
+
+    /// Labels all metrics created from this scope.
+    pub fn labeled<V: ToString>(mut self, key: &'static str, value: V) -> Scope {
+        self.labels.insert(key, value.to_string());
+        self
+    }
+
+    fn key(&self, name: &'static str) -> Key {
+        Key {
+            prefixes: self.prefixes.clone(),
+            name,
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// Creates a counter.
+    pub fn counter(&self, name: &'static str) -> Arc<Counter> {
+        self.metrics.counter(&self.key(name))
+    }
+
+    /// Creates a gauge.
+    pub fn gauge(&self, name: &'static str) -> Arc<Gauge> {
+        self.metrics.gauge(&self.key(name))
+    }
+
+    /// Creates a timer that records microseconds.
+    pub fn timer_us(&self, name: &'static str) -> Arc<Timer> {
+        self.metrics.timer(&self.key(name), TimeUnit::Micros)
+    }
+
+    /// Creates a timer that records milliseconds.
+    pub fn timer_ms(&self, name: &'static str) -> Arc<Timer> {
+        self.metrics.timer(&self.key(name), TimeUnit::Millis)
+    }
+}
+
+impl From<tacho::Scope> for Scope {
+    fn from(scope: tacho::Scope) -> Scope {
+        Scope::new(scope)
+    }
+}
+
+/// Discards all metrics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn counter(&self, _key: &Key) -> Arc<Counter> {
+        Arc::new(NoopMetrics)
+    }
+
+    fn gauge(&self, _key: &Key) -> Arc<Gauge> {
+        Arc::new(NoopMetrics)
+    }
+
+    fn timer(&self, _key: &Key, _unit: TimeUnit) -> Arc<Timer> {
+        Arc::new(NoopMetrics)
+    }
+}
+
+impl Counter for NoopMetrics {
+    fn incr(&self, _n: usize) {}
+}
+
+impl Gauge for NoopMetrics {
+    fn set(&self, _n: usize) {}
+    fn incr(&self, _n: usize) {}
+    fn decr(&self, _n: usize) {}
+}
+
+impl Timer for NoopMetrics {
+    fn record_since(&self, _t0: Instant) {}
+}
+
+impl Metrics for tacho::Scope {
+    fn counter(&self, key: &Key) -> Arc<Counter> {
+        Arc::new(tacho_scope(self, key).counter(key.name))
+    }
+
+    fn gauge(&self, key: &Key) -> Arc<Gauge> {
+        Arc::new(tacho_scope(self, key).gauge(key.name))
+    }
+
+    fn timer(&self, key: &Key, unit: TimeUnit) -> Arc<Timer> {
+        let scope = tacho_scope(self, key);
+        let timer = match unit {
+            TimeUnit::Micros => scope.timer_us(key.name),
+            TimeUnit::Millis => scope.timer_ms(key.name),
+        };
+        Arc::new(timer)
+    }
+}
+
+fn tacho_scope(scope: &tacho::Scope, key: &Key) -> tacho::Scope {
+    let mut scope = scope.clone();
+    for p in &key.prefixes {
+        scope = scope.prefixed(*p);
+    }
+    for (k, v) in &key.labels {
+        scope = scope.labeled(*k, v.clone());
+    }
+    scope
+}
+
+impl Counter for tacho::Counter {
+    fn incr(&self, n: usize) {
+        tacho::Counter::incr(self, n)
+    }
+}
+
+impl Gauge for tacho::Gauge {
+    fn set(&self, n: usize) {
+        tacho::Gauge::set(self, n)
+    }
+
+    fn incr(&self, n: usize) {
+        tacho::Gauge::incr(self, n)
+    }
+
+    fn decr(&self, n: usize) {
+        tacho::Gauge::decr(self, n)
+    }
+}
+
+impl Timer for tacho::Timer {
+    fn record_since(&self, t0: Instant) {
+        tacho::Timer::record_since(self, t0)
+    }
+}
+
+/// Records the time a future takes to complete.
+pub fn timed<F: Future>(timer: &Arc<Timer>, fut: F) -> Timed<F> {
+    Timed {
+        fut,
+        timer: timer.clone(),
+        start: Instant::now(),
+    }
+}
+
+/// A future whose completion time is recorded by a `Timer`.
+pub struct Timed<F> {
+    fut: F,
+    timer: Arc<Timer>,
+    start: Instant,
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        match self.fut.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            res => {
+                self.timer.record_since(self.start);
+                res
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Periodically logs a compact snapshot of key metrics, for deployments in which metrics
+//! are not scraped from the admin server.
+//!
+//! Snapshots are built from the same prometheus export that the admin server serves, and
+//! are logged as a single line of JSON, aggregated by router.
+
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+
+/// Counters, by metric name suffix, and the field name under which they are logged.
+///
+/// Each counter is logged both as `<field>_total` and as `<field>_delta`, the change
+/// since the previous snapshot.
+const COUNTERS: &'static [(&'static str, &'static str)] = &[
+    ("srv_accepts", "accepts"),
+    ("srv_closes", "closes"),
+    ("srv_failures", "failures"),
+    ("srv_stream_rx_bytes", "rx_bytes"),
+    ("srv_stream_tx_bytes", "tx_bytes"),
+    ("balancer_connection_connects", "connects"),
+    ("balancer_connection_failure", "connect_failures"),
+    ("resolver_success_count", "namerd_successes"),
+    ("resolver_failure_count", "namerd_failures"),
+];
+
+/// Gauges, by metric name suffix, and the field name under which they are logged.
+const GAUGES: &'static [(&'static str, &'static str)] = &[
+    ("srv_active", "active_connections"),
+    ("balancer_endpoint_available", "available_endpoints"),
+];
+
+#[derive(Serialize)]
+struct Snapshot {
+    routers: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// Formats snapshots, remembering counter values so that deltas may be reported.
+#[derive(Default)]
+pub struct MetricsLog {
+    previous: HashMap<(String, &'static str), u64>,
+}
+
+impl MetricsLog {
+    /// Builds a JSON snapshot from a prometheus-formatted export.
+    pub fn snapshot(&mut self, prometheus: &str) -> String {
+        let mut counters: HashMap<(String, &'static str), u64> = HashMap::new();
+        let mut routers: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+
+        for line in prometheus.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let (name, labels, value) = match parse_line(line) {
+                None => continue,
+                Some(m) => m,
+            };
+            let router = label(labels, "rt").unwrap_or("").to_string();
+
+            if let Some(field) = field_name(COUNTERS, name) {
+                *counters.entry((router, field)).or_insert(0) += value;
+            } else if let Some(field) = field_name(GAUGES, name) {
+                *routers
+                    .entry(router)
+                    .or_insert_with(BTreeMap::new)
+                    .entry(field.to_string())
+                    .or_insert(0) += value;
+            }
+        }
+
+        for ((router, field), total) in counters {
+            let prior = self.previous.get(&(router.clone(), field)).cloned().unwrap_or(0);
+            let fields = routers.entry(router.clone()).or_insert_with(BTreeMap::new);
+            fields.insert(format!("{}_total", field), total);
+            fields.insert(format!("{}_delta", field), total.saturating_sub(prior));
+            self.previous.insert((router, field), total);
+        }
+
+        serde_json::to_string(&Snapshot { routers }).expect("failed to serialize metrics")
+    }
+}
+
+fn field_name(metrics: &[(&'static str, &'static str)], name: &str) -> Option<&'static str> {
+    metrics
+        .iter()
+        .find(|&&(sfx, _)| name.ends_with(sfx))
+        .map(|&(_, field)| field)
+}
+
+/// Splits a prometheus sample into its name, labels, and value.
+fn parse_line(line: &str) -> Option<(&str, &str, u64)> {
+    let name_end = line.find(|c: char| c == '{' || c == ' ')?;
+    let name = &line[..name_end];
+    let labels = if line[name_end..].starts_with('{') {
+        let end = line.find('}')?;
+        &line[name_end + 1..end]
+    } else {
+        ""
+    };
+    let value = line.rsplit(' ').next()?.parse::<f64>().ok()?;
+    Some((name, labels, value as u64))
+}
+
+/// Finds the value of label `key` in a prometheus label list (`k0="v0",k1="v1"`).
+fn label<'a>(labels: &'a str, key: &str) -> Option<&'a str> {
+    for pair in labels.split(',') {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next() == Some(key) {
+            return kv.next().map(|v| v.trim_matches('"'));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetricsLog;
+
+    static FIRST: &'static str = r#"# TYPE srv_accepts counter
+srv_accepts{rt="a",srv="127.0.0.1:7474"} 3
+srv_accepts{rt="a",srv="127.0.0.1:7575"} 2
+srv_accepts{rt="b",srv="127.0.0.1:7676"} 1
+srv_active{rt="a",srv="127.0.0.1:7474"} 4
+balancer_connection_connects{rt="a",dst="/svc/echo"} 5
+balancer_endpoint_available{rt="a",dst="/svc/echo"} 2.0
+resolver_success_count{rt="a"} 7
+resolver_failure_count 2
+process_open_fds 12
+"#;
+
+    static SECOND: &'static str = r#"srv_accepts{rt="a",srv="127.0.0.1:7474"} 4
+srv_accepts{rt="a",srv="127.0.0.1:7575"} 2
+srv_accepts{rt="b",srv="127.0.0.1:7676"} 4
+srv_active{rt="a",srv="127.0.0.1:7474"} 1
+balancer_connection_connects{rt="a",dst="/svc/echo"} 5
+resolver_success_count{rt="a"} 3
+"#;
+
+    #[test]
+    fn aggregates_key_metrics_by_router() {
+        let mut log = MetricsLog::default();
+        assert_eq!(
+            log.snapshot(FIRST),
+            concat!(
+                r#"{"routers":{"#,
+                r#""":{"namerd_failures_delta":2,"namerd_failures_total":2},"#,
+                r#""a":{"accepts_delta":5,"accepts_total":5,"active_connections":4,"#,
+                r#""available_endpoints":2,"connects_delta":5,"connects_total":5,"#,
+                r#""namerd_successes_delta":7,"namerd_successes_total":7},"#,
+                r#""b":{"accepts_delta":1,"accepts_total":1}"#,
+                r#"}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn reports_counter_changes_since_the_previous_snapshot() {
+        let mut log = MetricsLog::default();
+        log.snapshot(FIRST);
+
+        // Counters that were reset (e.g. by a restarted resolver) report no change, rather
+        // than underflowing. Metrics that are no longer exported are not reported.
+        assert_eq!(
+            log.snapshot(SECOND),
+            concat!(
+                r#"{"routers":{"#,
+                r#""a":{"accepts_delta":1,"accepts_total":6,"active_connections":1,"#,
+                r#""connects_delta":0,"connects_total":5,"#,
+                r#""namerd_successes_delta":0,"namerd_successes_total":3},"#,
+                r#""b":{"accepts_delta":3,"accepts_total":4}"#,
+                r#"}}"#
+            )
+        );
+
+        // Deltas are measured from the most recent snapshot.
+        let third = SECOND.replace(
+            r#"resolver_success_count{rt="a"} 3"#,
+            r#"resolver_success_count{rt="a"} 5"#,
+        );
+        let snapshot = log.snapshot(&third);
+        assert!(
+            snapshot.contains(r#""namerd_successes_delta":2,"namerd_successes_total":5"#),
+            "{}",
+            snapshot
+        );
+        assert!(snapshot.contains(r#""accepts_delta":0,"accepts_total":6"#), "{}", snapshot);
+    }
+}
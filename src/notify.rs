@@ -0,0 +1,201 @@
+//! Notifies systemd of the process's lifecycle, as services with `Type=notify` expect.
+//!
+//! Each notification is a datagram sent to the unix socket named by `$NOTIFY_SOCKET`.
+//! `READY=1` is sent once every listener has been bound and each namerd-backed router
+//! has resolved a name or been given its `bootstrapTimeoutSecs`; `STOPPING=1` is sent
+//! when the process begins to drain. When systemd sets `$WATCHDOG_USEC`, `WATCHDOG=1` is
+//! sent from the serving reactor at half of that interval, so that a wedged reactor
+//! stops pinging and is restarted.
+//!
+//! Without `$NOTIFY_SOCKET`, nothing is sent.
+
+use super::summary::Signals;
+use futures::{Async, Future, Poll, Stream};
+use libc;
+use std::{env, io};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
+use tokio_timer::{Interval, Timer};
+
+/// Names the socket to which notifications are sent.
+pub const NOTIFY_SOCKET_ENV: &'static str = "NOTIFY_SOCKET";
+
+/// The watchdog's timeout, in microseconds.
+const WATCHDOG_USEC_ENV: &'static str = "WATCHDOG_USEC";
+
+/// When set, the watchdog is only armed for the process with this ID.
+const WATCHDOG_PID_ENV: &'static str = "WATCHDOG_PID";
+
+/// How often readiness is checked while routers resolve.
+const READINESS_CHECK_MS: u64 = 100;
+
+/// Sends notifications to systemd.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    socket: Arc<UnixDatagram>,
+    path: PathBuf,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Notifies the socket named by `$NOTIFY_SOCKET`, pinging the watchdog when
+    /// `$WATCHDOG_USEC` is set for this process.
+    ///
+    /// Returns `None` when `$NOTIFY_SOCKET` is unset, or when it names a socket in the
+    /// abstract namespace, which is not supported.
+    pub fn from_env() -> Option<Notifier> {
+        let path = match env::var_os(NOTIFY_SOCKET_ENV) {
+            None => return None,
+            Some(p) => PathBuf::from(p),
+        };
+        if path.to_string_lossy().starts_with('@') {
+            warn!("not notifying abstract socket {:?}", path);
+            return None;
+        }
+
+        let pid = unsafe { libc::getpid() };
+        let pid_matches = env::var(WATCHDOG_PID_ENV).ok().map_or(true, |p| {
+            p.parse::<libc::pid_t>().ok() == Some(pid)
+        });
+        let watchdog = env::var(WATCHDOG_USEC_ENV)
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .and_then(|usec| if pid_matches && usec > 0 {
+                Some(Duration::new(usec / 1_000_000, (usec % 1_000_000) as u32 * 1_000))
+            } else {
+                None
+            });
+
+        match Notifier::new(path.clone(), watchdog) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                warn!("not notifying {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Notifies the socket at `path`, pinging the watchdog at half of `watchdog`, if it
+    /// is set.
+    pub fn new<P: Into<PathBuf>>(path: P, watchdog: Option<Duration>) -> io::Result<Notifier> {
+        let socket = UnixDatagram::unbound()?;
+        Ok(Notifier {
+            socket: Arc::new(socket),
+            path: path.into(),
+            watchdog,
+        })
+    }
+
+    /// Sends `state`, e.g. `READY=1`. Failures are logged, since systemd may have gone
+    /// away.
+    pub fn notify(&self, state: &str) {
+        debug!("notifying {:?}: {}", self.path, state);
+        if let Err(e) = self.socket.send_to(state.as_bytes(), &self.path) {
+            warn!("failed to notify {:?} of {}: {}", self.path, state, e);
+        }
+    }
+
+    /// The interval at which the watchdog is pinged, if it is armed.
+    fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|w| w / 2)
+    }
+}
+
+/// Notifies systemd once the process is ready, and pings its watchdog.
+///
+/// Should be spawned on the serving reactor once every listener has been bound.
+pub struct Readiness {
+    notifier: Notifier,
+    routers: Vec<Router>,
+}
+
+struct Router {
+    label: String,
+    signals: Arc<Signals>,
+    bootstrap_timeout: Duration,
+}
+
+impl Readiness {
+    /// Notifies `notifier` once the routers passed to `await_router` are ready.
+    pub fn new(notifier: Notifier) -> Readiness {
+        Readiness {
+            notifier,
+            routers: Vec::new(),
+        }
+    }
+
+    /// Delays readiness until the router has resolved a name, or for
+    /// `bootstrap_timeout`.
+    pub fn await_router(
+        &mut self,
+        label: &str,
+        signals: Arc<Signals>,
+        bootstrap_timeout: Duration,
+    ) {
+        self.routers.push(Router {
+            label: label.to_owned(),
+            signals,
+            bootstrap_timeout,
+        });
+    }
+
+    /// Sends `READY=1` once each router is ready, and then pings the watchdog, if it is
+    /// armed, until the reactor stops.
+    pub fn spawn(self, handle: &Handle, timer: &Timer) {
+        let Readiness { notifier, routers } = self;
+        if let Some(interval) = notifier.watchdog_interval() {
+            let n = notifier.clone();
+            let pings = timer.interval(interval).map_err(|_| {}).for_each(move |_| {
+                n.notify("WATCHDOG=1");
+                Ok(())
+            });
+            handle.spawn(pings);
+        }
+        handle.spawn(Ready {
+            notifier,
+            routers,
+            started: Instant::now(),
+            checks: timer.interval(Duration::from_millis(READINESS_CHECK_MS)),
+        });
+    }
+}
+
+/// Waits for each router to resolve a name or exhaust its bootstrap timeout.
+struct Ready {
+    notifier: Notifier,
+    routers: Vec<Router>,
+    started: Instant,
+    checks: Interval,
+}
+
+impl Future for Ready {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let elapsed = self.started.elapsed();
+            self.routers.retain(|r| if r.signals.is_resolved() {
+                false
+            } else if elapsed >= r.bootstrap_timeout {
+                info!("{}: ready without a resolution", r.label);
+                false
+            } else {
+                true
+            });
+            if self.routers.is_empty() {
+                self.notifier.notify("READY=1");
+                return Ok(Async::Ready(()));
+            }
+
+            match self.checks.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) | Err(_) => return Err(()),
+            }
+        }
+    }
+}
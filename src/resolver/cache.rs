@@ -0,0 +1,270 @@
+//! Persists namerd resolutions so that a restarted process may serve them while namerd
+//! is unavailable.
+//!
+//! Each successful resolution is saved to the cache file, which is replaced atomically
+//! by renaming a temporary file over it. The file is rewritten when a name's addresses
+//! change, or when its entry has aged by half of `maxAgeSecs`, so that saved entries
+//! remain fresh enough to be served after a restart.
+//!
+//! When a name is first resolved, its cached addresses are served if namerd has not
+//! responded successfully within `bootstrapTimeoutSecs`, provided that they were saved
+//! within `maxAgeSecs`. namerd continues to be polled, and its first successful
+//! resolution replaces the cached addresses.
+
+use super::super::WeightedAddr;
+use super::super::metrics;
+use super::super::state::CachedResolutions;
+use futures::{Async, Future};
+use serde_json;
+use std::{fs, io};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_timer::{Sleep, Timer};
+
+pub const DEFAULT_MAX_AGE_SECS: u64 = 600;
+pub const DEFAULT_BOOTSTRAP_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub path: PathBuf,
+    pub max_age: Duration,
+    pub bootstrap_timeout: Duration,
+}
+
+impl Policy {
+    /// Reads the cache file. A missing file is treated as an empty cache; a file that
+    /// cannot be read or parsed is logged and ignored, and is replaced by the next
+    /// successful resolution.
+    pub fn load(self) -> Cache {
+        let entries = match fs::File::open(&self.path) {
+            Ok(f) => {
+                match serde_json::from_reader::<_, CacheFile>(io::BufReader::new(f)) {
+                    Ok(file) => file.resolutions,
+                    Err(e) => {
+                        warn!("ignoring invalid resolution cache {:?}: {}", self.path, e);
+                        BTreeMap::new()
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("ignoring unreadable resolution cache {:?}: {}", self.path, e);
+                BTreeMap::new()
+            }
+        };
+        Cache(Rc::new(RefCell::new(Inner {
+            policy: self,
+            entries,
+        })))
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(default)]
+    resolutions: BTreeMap<String, Entry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// Seconds since the Unix epoch.
+    saved_at_secs: u64,
+    addrs: Vec<WeightedAddr>,
+}
+
+/// The resolutions saved by a namerd interpreter, shared by its names.
+#[derive(Clone)]
+pub struct Cache(Rc<RefCell<Inner>>);
+
+struct Inner {
+    policy: Policy,
+    entries: BTreeMap<String, Entry>,
+}
+
+/// The cached resolution of a name.
+enum Lookup {
+    Fresh(Vec<WeightedAddr>),
+    Expired,
+    Missing,
+}
+
+impl Cache {
+    /// Arranges for `path`'s cached addresses to be served if namerd does not resolve
+    /// it in time.
+    ///
+    /// Cached addresses served are counted as `cache_hits`, names without fresh cached
+    /// addresses as `cache_misses`, and failed resolutions while cached addresses are
+    /// served as `cache_stale_serves`.
+    pub fn bootstrap(
+        &self,
+        namespace: &str,
+        path: &str,
+        cached: &CachedResolutions,
+        timer: &Timer,
+        metrics: &metrics::Scope,
+    ) -> Bootstrap {
+        let timeout = self.0.borrow().policy.bootstrap_timeout;
+        Bootstrap {
+            cache: self.clone(),
+            namespace: namespace.to_owned(),
+            path: path.to_owned(),
+            cached: cached.clone(),
+            timeout: Some(timer.sleep(timeout)),
+            serving: false,
+            hits: metrics.counter("cache_hits"),
+            misses: metrics.counter("cache_misses"),
+            stale_serves: metrics.counter("cache_stale_serves"),
+        }
+    }
+
+    fn get(&self, path: &str) -> Lookup {
+        let inner = self.0.borrow();
+        match inner.entries.get(path) {
+            None => Lookup::Missing,
+            Some(entry) => {
+                if now_secs().saturating_sub(entry.saved_at_secs) > inner.policy.max_age.as_secs() {
+                    Lookup::Expired
+                } else {
+                    Lookup::Fresh(entry.addrs.clone())
+                }
+            }
+        }
+    }
+
+    fn put(&self, path: &str, addrs: &[WeightedAddr]) {
+        let mut inner = self.0.borrow_mut();
+        let now = now_secs();
+        let refresh = inner.policy.max_age.as_secs() / 2;
+        let unchanged = inner.entries.get(path).map_or(false, |e| {
+            e.addrs.as_slice() == addrs && now.saturating_sub(e.saved_at_secs) < refresh
+        });
+        if unchanged {
+            return;
+        }
+        inner.entries.insert(
+            path.to_owned(),
+            Entry {
+                saved_at_secs: now,
+                addrs: addrs.to_vec(),
+            },
+        );
+        if let Err(e) = inner.persist() {
+            warn!(
+                "failed to write resolution cache {:?}: {}",
+                inner.policy.path,
+                e
+            );
+        }
+    }
+}
+
+impl Inner {
+    /// Writes the cache to a temporary file that replaces the cache file, so that the
+    /// cache file is never partially written.
+    fn persist(&self) -> io::Result<()> {
+        let mut tmp = self.policy.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        {
+            let file = CacheFile { resolutions: self.entries.clone() };
+            let mut f = fs::File::create(&tmp)?;
+            serde_json::to_writer(&mut f, &file).map_err(
+                |e| io::Error::new(io::ErrorKind::Other, e),
+            )?;
+            f.flush()?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &self.policy.path)
+    }
+}
+
+/// Serves a name's cached resolution until namerd resolves it.
+pub struct Bootstrap {
+    cache: Cache,
+    namespace: String,
+    path: String,
+    cached: CachedResolutions,
+    /// Unset once namerd has resolved the name or the timeout has elapsed.
+    timeout: Option<Sleep>,
+    /// Set while cached addresses are being served.
+    serving: bool,
+    hits: Arc<metrics::Counter>,
+    misses: Arc<metrics::Counter>,
+    stale_serves: Arc<metrics::Counter>,
+}
+
+impl Bootstrap {
+    /// Saves a successful resolution, which replaces any cached addresses being served.
+    pub fn resolved(&mut self, addrs: &[WeightedAddr]) {
+        self.timeout = None;
+        if self.serving {
+            info!("{}: serving resolution from namerd", self.path);
+            self.serving = false;
+            self.cached.remove(&self.namespace, &self.path);
+        }
+        self.cache.put(&self.path, addrs);
+    }
+
+    /// Notes a failed resolution.
+    pub fn failed(&self) {
+        if self.serving {
+            self.stale_serves.incr(1);
+        }
+    }
+
+    /// Returns the cached addresses once, when the bootstrap timeout elapses before
+    /// namerd has resolved the name.
+    pub fn poll_cached(&mut self) -> Option<Vec<WeightedAddr>> {
+        match self.timeout.as_mut().map(|t| t.poll()) {
+            None | Some(Ok(Async::NotReady)) => return None,
+            Some(Ok(Async::Ready(_))) => {}
+            Some(Err(e)) => {
+                warn!("{}: resolution cache timer failed: {}", self.path, e);
+            }
+        }
+        self.timeout = None;
+        match self.cache.get(&self.path) {
+            Lookup::Fresh(addrs) => {
+                info!(
+                    "{}: serving {} cached addresses until namerd responds",
+                    self.path,
+                    addrs.len()
+                );
+                self.hits.incr(1);
+                self.serving = true;
+                self.cached.insert(&self.namespace, &self.path);
+                Some(addrs)
+            }
+            Lookup::Expired => {
+                info!("{}: cached resolution is too old to serve", self.path);
+                self.misses.incr(1);
+                None
+            }
+            Lookup::Missing => {
+                self.misses.incr(1);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Bootstrap {
+    fn drop(&mut self) {
+        if self.serving {
+            self.cached.remove(&self.namespace, &self.path);
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -1,40 +1,253 @@
+use super::cache;
+use super::local;
 use super::namerd::Namerd;
+use super::super::connector::REMOTE_POLICY_KEYS;
+use super::super::duration::{Millis, Secs};
+use super::super::metrics;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
 use std::time::Duration;
-use tacho;
 use url::{self, Url};
 
+/// Bounds the memory used to read a response. Large namespaces may resolve to many
+/// thousands of addresses, so the default is generous.
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Bounds the number of distinct addresses a name may resolve to, so that a misbehaving
+/// namer cannot inflate a balancer without bound.
+const DEFAULT_MAX_ADDRS: usize = 10_000;
+
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10_000;
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    InvalidPeriod(u64),
+    InvalidPeriod(Duration),
     InvalidBaseUrl(String, url::ParseError),
+    /// The base URL is not an `http` URL with a host.
+    UnsupportedBaseUrl(String),
+    InvalidMaxResponseBytes,
+    InvalidMaxAddrs,
+    InvalidRequestTimeout(Duration),
+    /// The namespace is empty or has leading or trailing whitespace.
+    InvalidNamespace(String),
+    InvalidResolutionCachePath,
+    InvalidResolutionCacheMaxAge(Duration),
+    InvalidBootstrapTimeout(Duration),
+    InvalidLocalResolverSocketPath,
+    /// An `acceptRemotePolicy` key that does not name an overridable setting.
+    UnknownRemotePolicyKey(String),
 }
 
+/// Configures a resolver that polls namerd's HTTP interface.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct NamerdConfig {
+    /// An `http` URL, or a `host:port` (e.g. `namerd.default.svc:4180` or
+    /// `10.0.0.1:4180`) that is treated as one.
+    ///
+    /// Hostnames are resolved each time namerd is polled, so namerd may move between
+    /// addresses. Resolution failures are counted as failed requests.
     pub base_url: String,
-    pub period_secs: u64,
+    /// How often each name is polled.
+    pub period_secs: Secs,
+    /// The namerd namespace in which names are resolved. It is percent-encoded as a
+    /// single path segment, so it may contain any characters.
     pub namespace: String,
+    /// Responses with larger bodies are abandoned and counted as failures.
+    pub max_response_bytes: Option<usize>,
+    /// Responses with more distinct addresses are rejected and counted as failures.
+    pub max_addrs: Option<usize>,
+    /// Requests that are not answered in time are abandoned and counted as failures.
+    pub request_timeout_ms: Option<Millis>,
+    /// Saves resolutions so that they may be served after a restart while namerd is
+    /// unavailable.
+    pub resolution_cache: Option<ResolutionCacheConfig>,
+    /// Shares resolutions with sibling processes on the same host, so that namerd is
+    /// polled by one of them on behalf of all.
+    pub local_resolver: Option<LocalResolverConfig>,
+    /// Applies balancer and connector overrides that the control plane publishes in the
+    /// metadata of namerd's responses.
+    pub accept_remote_policy: Option<AcceptRemotePolicyConfig>,
+}
+
+/// Names the remote policy that is honored. Other keys in namerd's response metadata
+/// are ignored and counted.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AcceptRemotePolicyConfig {
+    /// Any of `loadBalancer`, `loadBalancer.decaySecs`, `failureAccrual.maxFailures`,
+    /// `failureAccrual.penaltySecs`, `failureAccrual.successThreshold`, and
+    /// `connectTimeoutMs`.
+    pub allowed_keys: Vec<String>,
+}
+
+/// Shares resolutions among the processes on a host that bind the same unix socket.
+///
+/// The process that binds `socketPath` polls namerd and serves its siblings, which
+/// resolve names from namerd directly if it cannot be reached.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct LocalResolverConfig {
+    /// The unix socket through which resolutions are shared. Its directory must be
+    /// writable by each process.
+    pub socket_path: String,
+}
+
+/// Saves each name's most recent resolution to a file.
+///
+/// After a restart, a name whose first resolution has not succeeded within
+/// `bootstrapTimeoutSecs` is served its saved addresses, if they were saved within
+/// `maxAgeSecs`, while namerd continues to be polled.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ResolutionCacheConfig {
+    /// The file in which resolutions are saved. Its directory must be writable, since
+    /// the file is replaced by renaming a temporary file alongside it.
+    pub path: String,
+    /// Saved resolutions older than this are not served (600s by default).
+    pub max_age_secs: Option<Secs>,
+    /// How long namerd is given to resolve a name before saved addresses are served (5s
+    /// by default).
+    pub bootstrap_timeout_secs: Option<Secs>,
+}
+
+impl ResolutionCacheConfig {
+    fn mk_policy(&self) -> Result<cache::Policy> {
+        if self.path.is_empty() {
+            return Err(Error::InvalidResolutionCachePath);
+        }
+        let max_age = self.max_age_secs.map(Duration::from).unwrap_or_else(|| {
+            Duration::from_secs(cache::DEFAULT_MAX_AGE_SECS)
+        });
+        if max_age == Duration::from_secs(0) {
+            return Err(Error::InvalidResolutionCacheMaxAge(max_age));
+        }
+        let bootstrap_timeout = self.bootstrap_timeout_secs.map(Duration::from).unwrap_or_else(
+            || Duration::from_secs(cache::DEFAULT_BOOTSTRAP_TIMEOUT_SECS),
+        );
+        if bootstrap_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidBootstrapTimeout(bootstrap_timeout));
+        }
+        Ok(cache::Policy {
+            path: PathBuf::from(&self.path),
+            max_age,
+            bootstrap_timeout,
+        })
+    }
 }
 
 impl NamerdConfig {
-    pub fn into_namerd(self, metrics: &tacho::Scope) -> Result<Namerd> {
-        if self.period_secs == 0 {
-            return Err(Error::InvalidPeriod(self.period_secs));
+    /// Validates how resolutions are shared with sibling processes, if they are.
+    pub fn local_policy(&self) -> Result<Option<local::Policy>> {
+        match self.local_resolver {
+            None => Ok(None),
+            Some(ref l) if l.socket_path.is_empty() => Err(Error::InvalidLocalResolverSocketPath),
+            Some(ref l) => Ok(Some(local::Policy { socket_path: PathBuf::from(&l.socket_path) })),
+        }
+    }
+
+    /// Validates the remote policy keys that are honored, if any are.
+    pub fn remote_policy_keys(&self) -> Result<Option<BTreeSet<String>>> {
+        let accept = match self.accept_remote_policy {
+            None => return Ok(None),
+            Some(ref a) => a,
+        };
+        let mut keys = BTreeSet::new();
+        for k in &accept.allowed_keys {
+            if !REMOTE_POLICY_KEYS.contains(&k.as_str()) {
+                return Err(Error::UnknownRemotePolicyKey(k.clone()));
+            }
+            keys.insert(k.clone());
+        }
+        Ok(Some(keys))
+    }
+
+    /// How long namerd is given to resolve a name before saved addresses would be
+    /// served, whether or not resolutions are cached.
+    pub fn bootstrap_timeout(&self) -> Duration {
+        self.resolution_cache
+            .as_ref()
+            .and_then(|c| c.bootstrap_timeout_secs)
+            .map(Duration::from)
+            .unwrap_or_else(|| Duration::from_secs(cache::DEFAULT_BOOTSTRAP_TIMEOUT_SECS))
+    }
+
+    /// Validates this configuration to produce a namerd client.
+    pub fn into_namerd(self, metrics: &metrics::Scope) -> Result<Namerd> {
+        let period = Duration::from(self.period_secs);
+        if period == Duration::from_secs(0) {
+            return Err(Error::InvalidPeriod(period));
         }
-        let period = Duration::from_secs(self.period_secs);
 
-        if let Err(e) = Url::parse(&self.base_url) {
-            return Err(Error::InvalidBaseUrl(self.base_url, e));
+        let resolve_url = resolve_url(&self.base_url, &self.namespace)?;
+        let max_response_bytes = self.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+        if max_response_bytes == 0 {
+            return Err(Error::InvalidMaxResponseBytes);
+        }
+        let max_addrs = self.max_addrs.unwrap_or(DEFAULT_MAX_ADDRS);
+        if max_addrs == 0 {
+            return Err(Error::InvalidMaxAddrs);
+        }
+        let request_timeout = self.request_timeout_ms.map(Duration::from).unwrap_or_else(|| {
+            Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS)
+        });
+        if request_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidRequestTimeout(request_timeout));
         }
 
+        let cache = match self.resolution_cache {
+            None => None,
+            Some(ref c) => Some(c.mk_policy()?),
+        };
+
         let metrics = metrics.clone().prefixed("resolver").labeled(
-            "namespace".into(),
+            "namespace",
             self.namespace.clone(),
         );
-        let namerd = Namerd::new(self.base_url, period, self.namespace, metrics);
+        let namerd = Namerd::new(
+            resolve_url,
+            period,
+            self.namespace,
+            max_response_bytes,
+            max_addrs,
+            metrics,
+            request_timeout,
+            cache,
+        );
         Ok(namerd)
     }
 }
+
+/// Builds the URL at which names are resolved in `namespace`.
+fn resolve_url(base_url: &str, namespace: &str) -> Result<Url> {
+    if namespace.is_empty() || namespace.trim() != namespace {
+        return Err(Error::InvalidNamespace(namespace.to_owned()));
+    }
+    let mut url = normalize_base_url(base_url)?;
+    {
+        let mut segments = url.path_segments_mut().map_err(
+            |_| Error::UnsupportedBaseUrl(base_url.to_owned()),
+        )?;
+        segments.pop_if_empty().extend(&["api", "1", "resolve", namespace]);
+    }
+    Ok(url)
+}
+
+/// Validates a base URL, treating a bare `host:port` as an `http` URL.
+fn normalize_base_url(base_url: &str) -> Result<Url> {
+    let full = if base_url.contains("://") {
+        base_url.to_owned()
+    } else {
+        format!("http://{}", base_url)
+    };
+    let url = match Url::parse(&full) {
+        Ok(url) => url,
+        Err(e) => return Err(Error::InvalidBaseUrl(base_url.to_owned(), e)),
+    };
+    if url.scheme() != "http" || url.host_str().is_none() {
+        return Err(Error::UnsupportedBaseUrl(base_url.to_owned()));
+    }
+    Ok(url)
+}
@@ -0,0 +1,575 @@
+//! Shares namerd resolutions among sibling processes on a host (e.g. one process per
+//! core, sharing listeners with `SO_REUSEPORT`), so that namerd is polled once per host
+//! rather than once per process.
+//!
+//! Each process tries to bind the interpreter's `localResolver.socketPath`. The process
+//! that binds it leads: it polls namerd, and serves each name's resolutions to its own
+//! routers and to its siblings. The others follow: each name is requested from the
+//! leader, and is polled from namerd directly if the leader cannot be reached or goes
+//! away.
+//!
+//! A follower requests a name by sending `{"path", "namespace"}`. The leader responds
+//! with an `{"addrs"}` or `{"error", "category"}` message for each resolution, until
+//! either side closes the connection. Each message is JSON, prefixed by its length as a
+//! 4-byte big-endian integer.
+
+use super::{ERROR_CATEGORIES, Error, Result, WeightedAddr};
+use super::namerd::{Addrs, WithClient};
+use super::super::{metrics, supervise};
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::mpsc;
+use libc;
+use serde_json;
+use std::{fs, io, thread};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+/// Bounds the size of a single message. Large namespaces may resolve to many thousands of
+/// addresses, so the bound is generous.
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// A stream of a name's resolutions.
+pub type Resolutions = Box<Stream<Item = Result<Vec<WeightedAddr>>, Error = Error>>;
+
+/// Shares resolutions through the unix socket at `socket_path`.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub socket_path: PathBuf,
+}
+
+impl Policy {
+    /// Leads if the socket can be bound, and follows the process that has bound it
+    /// otherwise.
+    ///
+    /// If the socket can neither be bound nor connected to, names are resolved from
+    /// namerd directly.
+    pub fn elect(&self, namerd: &Rc<WithClient>, handle: &Handle, timer: &Timer) -> Role {
+        let metrics = namerd.metrics().clone().prefixed("local");
+        let leader = metrics.gauge("leader");
+        let listener = match bind(&self.socket_path) {
+            Ok(Some(listener)) => listener,
+            Ok(None) => {
+                info!("following the local resolver at {:?}", self.socket_path);
+                leader.set(0);
+                return Role::Follower(Follower {
+                    socket_path: self.socket_path.clone(),
+                    namespace: namerd.namespace().to_owned(),
+                    namerd: namerd.clone(),
+                    updates: metrics.counter("updates"),
+                    fallbacks: metrics.counter("fallbacks"),
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "not sharing resolutions: failed to bind {:?}: {}",
+                    self.socket_path,
+                    e
+                );
+                leader.set(0);
+                return Role::Leader(Hub::new(namerd, handle, timer, None));
+            }
+        };
+        info!("leading the local resolver at {:?}", self.socket_path);
+        leader.set(1);
+
+        let (subscriptions_tx, subscriptions_rx) = mpsc::unbounded();
+        let closed = Arc::new(AtomicBool::new(false));
+        let serve_followers = {
+            let socket_path = self.socket_path.clone();
+            let namespace = namerd.namespace().to_owned();
+            let followers = metrics.gauge("followers");
+            let closed = closed.clone();
+            move || {
+                accept(listener, &socket_path, &namespace, &subscriptions_tx, &followers, &closed)
+            }
+        };
+        let spawned = thread::Builder::new().name("local-resolver".into()).spawn(serve_followers);
+        let guard = match spawned {
+            Ok(_) => Some(Guard {
+                socket_path: self.socket_path.clone(),
+                closed,
+            }),
+            Err(e) => {
+                warn!("not sharing resolutions: failed to spawn a thread: {}", e);
+                None
+            }
+        };
+
+        let hub = Hub::new(namerd, handle, timer, guard);
+        let serve = {
+            let hub = hub.clone();
+            let handle = handle.clone();
+            subscriptions_rx.for_each(move |(path, tx): Subscription| {
+                let respond = hub.subscribe(&path).for_each(move |rsp| {
+                    tx.unbounded_send(Message::from_result(&rsp)).map_err(|_| {})
+                });
+                handle.spawn(respond);
+                Ok(())
+            })
+        };
+        handle.spawn(serve);
+        Role::Leader(hub)
+    }
+}
+
+/// Binds the socket at `path`, unless another process is serving it.
+///
+/// A socket left behind by a leader that has exited is replaced. Siblings take turns
+/// holding an exclusive lock on `<path>.lock` while they are elected, so that only the
+/// first to find a stale socket replaces it and the others follow it.
+fn bind(path: &Path) -> io::Result<Option<UnixListener>> {
+    let _lock = lock(&lock_path(path))?;
+    match UnixListener::bind(path) {
+        Ok(listener) => Ok(Some(listener)),
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+            match UnixStream::connect(path) {
+                Ok(_) => Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => replace(path),
+                // An exiting leader may have removed its socket already.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => replace(path),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Binds the socket at `path` in place of a stale one.
+fn replace(path: &Path) -> io::Result<Option<UnixListener>> {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    UnixListener::bind(path).map(Some)
+}
+
+/// The lock file beside the socket at `path`. It is never removed, so that every sibling
+/// locks the same file.
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+/// Blocks until an exclusive lock on the file at `path` is held. The lock is released as
+/// the returned file is closed.
+fn lock(path: &Path) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new().create(true).write(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// The part this process plays in sharing resolutions.
+pub enum Role {
+    /// Polls namerd, sharing each name's resolutions.
+    Leader(Hub),
+    /// Requests resolutions from the leader.
+    Follower(Follower),
+}
+
+impl Role {
+    pub fn resolve(&self, path: &str) -> Resolutions {
+        match *self {
+            Role::Leader(ref hub) => Box::new(hub.subscribe(path).map_err(|_| Error::Rejected)),
+            Role::Follower(ref follower) => Box::new(follower.resolve(path)),
+        }
+    }
+}
+
+/// A follower's request for a name's resolutions, and the channel on which they are
+/// sent.
+type Subscription = (String, mpsc::UnboundedSender<Message>);
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    path: String,
+    namespace: String,
+}
+
+/// A resolution, as it is sent to followers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Message {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    addrs: Option<Vec<WeightedAddr>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+}
+
+impl Message {
+    fn from_result(rsp: &Result<Vec<WeightedAddr>>) -> Message {
+        match *rsp {
+            Ok(ref addrs) => Message {
+                addrs: Some(addrs.clone()),
+                error: None,
+                category: None,
+            },
+            Err(ref e) => Message {
+                addrs: None,
+                error: Some(e.to_string()),
+                category: Some(e.category().to_owned()),
+            },
+        }
+    }
+
+    /// Failures are described as they were by the leader. Unknown categories are
+    /// counted as transport failures.
+    fn into_result(self) -> Result<Vec<WeightedAddr>> {
+        if let Some(addrs) = self.addrs {
+            return Ok(addrs);
+        }
+        let category = self.category
+            .and_then(|c| ERROR_CATEGORIES.iter().find(|k| **k == c).cloned())
+            .unwrap_or("transport");
+        let message = self.error.unwrap_or_else(|| "resolution failed".to_owned());
+        Err(Error::Shared(message, category))
+    }
+}
+
+/// Shares each name's namerd resolutions among its subscribers, so that each name is
+/// polled once however many routers, in this process and its siblings, resolve it.
+#[derive(Clone)]
+pub struct Hub(Rc<HubInner>);
+
+struct HubInner {
+    namerd: Rc<WithClient>,
+    handle: Handle,
+    timer: Timer,
+    names: RefCell<HashMap<String, Rc<RefCell<Shared>>>>,
+    /// Set while siblings are served.
+    _guard: Option<Guard>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// The name's most recent resolution, which is sent to new subscribers.
+    last: Option<Message>,
+    subscribers: Vec<mpsc::UnboundedSender<Result<Vec<WeightedAddr>>>>,
+}
+
+impl Hub {
+    fn new(namerd: &Rc<WithClient>, handle: &Handle, timer: &Timer, guard: Option<Guard>) -> Hub {
+        Hub(Rc::new(HubInner {
+            namerd: namerd.clone(),
+            handle: handle.clone(),
+            timer: timer.clone(),
+            names: RefCell::new(HashMap::new()),
+            _guard: guard,
+        }))
+    }
+
+    /// Streams `path`'s resolutions, beginning with its most recent, if it has been
+    /// resolved.
+    pub fn subscribe(&self, path: &str) -> mpsc::UnboundedReceiver<Result<Vec<WeightedAddr>>> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut names = self.0.names.borrow_mut();
+        if let Some(shared) = names.get(path) {
+            let mut shared = shared.borrow_mut();
+            if let Some(ref last) = shared.last {
+                let _ = tx.unbounded_send(last.clone().into_result());
+            }
+            shared.subscribers.push(tx);
+            return rx;
+        }
+        let shared = Rc::new(RefCell::new(Shared {
+            last: None,
+            subscribers: vec![tx],
+        }));
+        names.insert(path.to_owned(), shared.clone());
+        self.poll(path, shared);
+        rx
+    }
+
+    /// Polls namerd for `path` until it has no subscribers.
+    fn poll(&self, path: &str, shared: Rc<RefCell<Shared>>) {
+        let name = format!("shared resolution of {}", path);
+        let panics = self.0.namerd.panics(path);
+        let hub = self.clone();
+        let path = path.to_owned();
+        let resolve = move || -> Box<Future<Item = (), Error = ()>> {
+            let shared = shared.clone();
+            let hub = hub.clone();
+            let p = path.clone();
+            let addrs = hub.0.namerd.resolve(&path);
+            let f = addrs
+                .map_err(|e| debug!("shared resolution failed: {}", e))
+                .for_each(move |rsp| {
+                    let msg = Message::from_result(&rsp);
+                    let mut shared = shared.borrow_mut();
+                    shared.subscribers.retain(|tx| {
+                        tx.unbounded_send(msg.clone().into_result()).is_ok()
+                    });
+                    shared.last = Some(msg);
+                    if shared.subscribers.is_empty() {
+                        return Err(());
+                    }
+                    Ok(())
+                })
+                .then(move |_| {
+                    hub.0.names.borrow_mut().remove(&p);
+                    Ok(())
+                });
+            Box::new(f)
+        };
+        let task = supervise::restart(name, resolve, &self.0.timer, panics);
+        self.0.handle.spawn(task);
+    }
+}
+
+/// Stops serving siblings once the leader is dropped.
+struct Guard {
+    socket_path: PathBuf,
+    closed: Arc<AtomicBool>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+        // Wakes the listener, so that it observes that it is closed.
+        let _ = UnixStream::connect(&self.socket_path);
+    }
+}
+
+/// Serves followers' connections until the leader is dropped.
+fn accept(
+    listener: UnixListener,
+    socket_path: &Path,
+    namespace: &str,
+    subscriptions: &mpsc::UnboundedSender<Subscription>,
+    followers: &Arc<metrics::Gauge>,
+    closed: &AtomicBool,
+) {
+    for conn in listener.incoming() {
+        if closed.load(Ordering::Acquire) {
+            break;
+        }
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("failed to accept a follower: {}", e);
+                continue;
+            }
+        };
+        let namespace = namespace.to_owned();
+        let subscriptions = subscriptions.clone();
+        let followers = followers.clone();
+        let serve = move || {
+            followers.incr(1);
+            if let Err(e) = serve_follower(conn, &namespace, &subscriptions) {
+                debug!("stopped serving a follower: {}", e);
+            }
+            followers.decr(1);
+        };
+        if let Err(e) = thread::Builder::new().name("local-resolver".into()).spawn(serve) {
+            warn!("failed to serve a follower: {}", e);
+        }
+    }
+    // The socket is removed while it is still bound, so that a sibling cannot bind it
+    // before it is removed.
+    if let Err(e) = fs::remove_file(socket_path) {
+        debug!("failed to remove {:?}: {}", socket_path, e);
+    }
+}
+
+/// Sends a follower the resolutions of the name it requests.
+fn serve_follower(
+    mut conn: UnixStream,
+    namespace: &str,
+    subscriptions: &mpsc::UnboundedSender<Subscription>,
+) -> io::Result<()> {
+    let req = read_message(&mut conn)?;
+    let req: Request = serde_json::from_slice(&req).map_err(invalid)?;
+    // A follower resolving another namespace resolves its names itself.
+    if req.namespace != namespace {
+        warn!(
+            "not serving {}: the local resolver serves namespace {}, not {}",
+            req.path,
+            namespace,
+            req.namespace
+        );
+        return Ok(());
+    }
+    let (tx, rx) = mpsc::unbounded();
+    if subscriptions.unbounded_send((req.path, tx)).is_err() {
+        return Ok(());
+    }
+    for msg in rx.wait() {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(()) => break,
+        };
+        write_message(&mut conn, &serde_json::to_vec(&msg).map_err(invalid)?)?;
+    }
+    Ok(())
+}
+
+/// Requests resolutions from the leader.
+#[derive(Clone)]
+pub struct Follower {
+    socket_path: PathBuf,
+    namespace: String,
+    namerd: Rc<WithClient>,
+    updates: Arc<metrics::Counter>,
+    fallbacks: Arc<metrics::Counter>,
+}
+
+impl Follower {
+    /// Streams `path`'s resolutions from the leader, until the leader goes away, and
+    /// from namerd afterwards.
+    pub fn resolve(&self, path: &str) -> Follow {
+        let (tx, rx) = mpsc::unbounded();
+        let req = Request {
+            path: path.to_owned(),
+            namespace: self.namespace.clone(),
+        };
+        let socket_path = self.socket_path.clone();
+        let updates = self.updates.clone();
+        let relay = move || if let Err(e) = follow(&socket_path, &req, &tx, &updates) {
+            info!("{}: lost the local resolver: {}", req.path, e);
+        };
+        // If the thread cannot be spawned, the channel is closed, and namerd is polled.
+        if let Err(e) = thread::Builder::new().name("local-resolver".into()).spawn(relay) {
+            warn!("failed to follow the local resolver: {}", e);
+        }
+        Follow {
+            path: path.to_owned(),
+            namerd: self.namerd.clone(),
+            fallbacks: self.fallbacks.clone(),
+            state: FollowState::Leader(rx),
+        }
+    }
+}
+
+/// Relays a name's resolutions from the leader until either the leader or the
+/// follower's router goes away.
+fn follow(
+    socket_path: &Path,
+    req: &Request,
+    tx: &mpsc::UnboundedSender<Result<Vec<WeightedAddr>>>,
+    updates: &Arc<metrics::Counter>,
+) -> io::Result<()> {
+    let mut conn = UnixStream::connect(socket_path)?;
+    write_message(&mut conn, &serde_json::to_vec(req).map_err(invalid)?)?;
+    loop {
+        let msg = read_message(&mut conn)?;
+        let msg: Message = serde_json::from_slice(&msg).map_err(invalid)?;
+        updates.incr(1);
+        if tx.unbounded_send(msg.into_result()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Streams a name's resolutions, as a follower.
+pub struct Follow {
+    path: String,
+    namerd: Rc<WithClient>,
+    fallbacks: Arc<metrics::Counter>,
+    state: FollowState,
+}
+
+enum FollowState {
+    Leader(mpsc::UnboundedReceiver<Result<Vec<WeightedAddr>>>),
+    Namerd(Addrs),
+}
+
+impl Stream for Follow {
+    type Item = Result<Vec<WeightedAddr>>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        loop {
+            let addrs = match self.state {
+                FollowState::Namerd(ref mut addrs) => return addrs.poll(),
+                FollowState::Leader(ref mut rx) => {
+                    match rx.poll() {
+                        Ok(Async::Ready(Some(rsp))) => return Ok(Async::Ready(Some(rsp))),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(None)) | Err(()) => {
+                            info!("{}: resolving from namerd", self.path);
+                            self.fallbacks.incr(1);
+                            self.namerd.resolve(&self.path)
+                        }
+                    }
+                }
+            };
+            self.state = FollowState::Namerd(addrs);
+        }
+    }
+}
+
+fn write_message<W: Write>(w: &mut W, msg: &[u8]) -> io::Result<()> {
+    let len = msg.len() as u32;
+    w.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    w.write_all(msg)?;
+    w.flush()
+}
+
+fn read_message<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = len.iter().fold(0usize, |n, b| (n << 8) | *b as usize);
+    if len > MAX_MESSAGE_BYTES {
+        return Err(invalid(format!("message of {} bytes is too large", len)));
+    }
+    let mut msg = vec![0; len];
+    r.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bind;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn elects_one_sibling_to_replace_a_stale_socket() {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let path = ::std::env::temp_dir().join(format!("linkerd-tcp-local-{}.sock", nanos));
+        // A leader that exited without removing its socket.
+        drop(UnixListener::bind(&path).expect("failed to bind"));
+
+        let siblings = 8;
+        let barrier = Arc::new(Barrier::new(siblings));
+        let elections = (0..siblings)
+            .map(|_| {
+                let (path, barrier) = (path.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    bind(&path).expect("failed to elect")
+                })
+            })
+            .collect::<Vec<_>>();
+        // Leaders' listeners are held until every sibling has been elected.
+        let leaders = elections
+            .into_iter()
+            .filter_map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(super::lock_path(&path));
+        assert_eq!(leaders.len(), 1);
+    }
+}
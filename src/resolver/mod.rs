@@ -1,22 +1,121 @@
-use super::{WeightedAddr, Path};
-use futures::{Future, Stream, Poll};
+use super::{WeightedAddr, Path, supervise};
+use super::startup::Startup;
+use futures::{Future, Stream, Poll, future};
 use futures::sync::mpsc;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{error, fmt, io};
 use tokio_core::reactor::Handle;
-use tokio_timer::{Timer, TimerError};
+use tokio_timer::{Timer, TimeoutError, TimerError};
 
+mod cache;
 mod config;
+mod local;
 mod namerd;
-pub use self::config::{Error as ConfigError, NamerdConfig};
+pub use self::config::{AcceptRemotePolicyConfig, Error as ConfigError, LocalResolverConfig,
+                       NamerdConfig, ResolutionCacheConfig};
+pub use self::local::Policy as LocalPolicy;
 pub use self::namerd::{Namerd, Addrs};
 
+/// Describes why a destination could not be resolved.
 #[derive(Debug)]
 pub enum Error {
+    /// The request to namerd failed.
     Hyper(::hyper::Error),
+    /// namerd responded with a status other than 200.
     UnexpectedStatus(::hyper::StatusCode),
+    /// namerd's response could not be parsed.
     Serde(::serde_json::Error),
+    /// The timer used to schedule requests failed.
     Timer(TimerError),
+    /// The resolution could not be delivered to its balancer.
     Rejected,
+    /// The name is not bound to any endpoints.
     NotBound,
+    /// The response body exceeded the given number of bytes.
+    ResponseTooLarge(usize),
+    /// The response had more than the given number of distinct addresses.
+    TooManyAddrs(usize),
+    /// No response was received within the request timeout.
+    Timeout,
+    /// The name could not be encoded in a namerd request.
+    InvalidPath(String),
+    /// The local resolver's leader failed to resolve the name, as described, with a
+    /// failure in the given category.
+    Shared(String, &'static str),
+}
+
+/// The categories by which failed resolutions are counted, as `error_count{cause}`.
+pub static ERROR_CATEGORIES: &'static [&'static str] =
+    &["http_4xx", "http_5xx", "transport", "timeout", "parse", "not_bound"];
+
+impl Error {
+    /// Categorizes this error as one of `ERROR_CATEGORIES`.
+    ///
+    /// Responses with other unexpected statuses (e.g. redirects, which are not followed)
+    /// are counted with responses that could not be parsed, since neither could be used.
+    pub fn category(&self) -> &'static str {
+        match *self {
+            Error::UnexpectedStatus(s) if s.is_client_error() => "http_4xx",
+            Error::UnexpectedStatus(s) if s.is_server_error() => "http_5xx",
+            Error::UnexpectedStatus(_) |
+            Error::Serde(_) |
+            Error::ResponseTooLarge(_) |
+            Error::TooManyAddrs(_) |
+            Error::InvalidPath(_) => "parse",
+            Error::Hyper(::hyper::Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+                "timeout"
+            }
+            Error::Timeout | Error::Timer(_) => "timeout",
+            Error::Hyper(_) | Error::Rejected => "transport",
+            Error::NotBound => "not_bound",
+            Error::Shared(_, category) => category,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Hyper(ref e) => write!(f, "namerd request failed: {}", e),
+            Error::UnexpectedStatus(ref s) => write!(f, "unexpected namerd response: {}", s),
+            Error::Serde(ref e) => write!(f, "invalid namerd response: {}", e),
+            Error::Timer(ref e) => write!(f, "timer failed: {}", e),
+            Error::Rejected => f.write_str("resolution rejected"),
+            Error::NotBound => f.write_str("name not bound"),
+            Error::ResponseTooLarge(max) => write!(f, "namerd response exceeds {} bytes", max),
+            Error::TooManyAddrs(max) => write!(f, "namerd response exceeds {} addresses", max),
+            Error::Timeout => f.write_str("namerd request timed out"),
+            Error::InvalidPath(ref p) => write!(f, "invalid name: {}", p),
+            Error::Shared(ref e, _) => f.write_str(e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Hyper(_) => "namerd request failed",
+            Error::UnexpectedStatus(_) => "unexpected namerd response",
+            Error::Serde(_) => "invalid namerd response",
+            Error::Timer(_) => "timer failed",
+            Error::Rejected => "resolution rejected",
+            Error::NotBound => "name not bound",
+            Error::ResponseTooLarge(_) => "namerd response too large",
+            Error::TooManyAddrs(_) => "namerd response has too many addresses",
+            Error::Timeout => "namerd request timed out",
+            Error::InvalidPath(_) => "invalid name",
+            Error::Shared(..) => "shared resolution failed",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Hyper(ref e) => Some(e),
+            Error::Serde(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl<T> From<mpsc::SendError<T>> for Error {
@@ -25,6 +124,15 @@ impl<T> From<mpsc::SendError<T>> for Error {
     }
 }
 
+impl<T> From<TimeoutError<T>> for Error {
+    fn from(e: TimeoutError<T>) -> Error {
+        match e {
+            TimeoutError::TimedOut(_) => Error::Timeout,
+            TimeoutError::Timer(_, e) => Error::Timer(e),
+        }
+    }
+}
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 /// Creates a multithreaded resolver.
@@ -32,11 +140,29 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 /// The `Resolver` side is a client of the `Executor`. Namerd work is performed on
 /// whatever thread the executor is spawned on.
 pub fn new(namerd: Namerd) -> (Resolver, Executor) {
+    mk(Source::Namerd(namerd))
+}
+
+/// Creates a multithreaded resolver, as `new` does, that shares its resolutions with
+/// sibling processes as described by `local`.
+pub fn new_local(namerd: Namerd, local: LocalPolicy) -> (Resolver, Executor) {
+    mk(Source::Local(namerd, local))
+}
+
+/// Creates a resolver that resolves each name to a fixed set of addresses.
+///
+/// Names without addresses are not bound.
+pub fn new_static(addrs: HashMap<Path, Vec<WeightedAddr>>) -> (Resolver, Executor) {
+    mk(Source::Static(addrs))
+}
+
+fn mk(source: Source) -> (Resolver, Executor) {
     let (tx, rx) = mpsc::unbounded();
     let res = Resolver { requests: tx };
     let exe = Executor {
         requests: rx,
-        namerd: namerd,
+        source,
+        startup: None,
     };
     (res, exe)
 }
@@ -60,11 +186,29 @@ impl Resolver {
             );
             rx
         };
-        Resolve(addrs)
+        Resolve(Box::new(addrs))
     }
 }
 
-pub struct Resolve(mpsc::UnboundedReceiver<Result<Vec<WeightedAddr>>>);
+pub struct Resolve(Box<Stream<Item = Result<Vec<WeightedAddr>>, Error = ()>>);
+
+impl Resolve {
+    /// Provides the resolutions produced by `addrs` instead of a namerd lookup.
+    pub fn from_stream<S>(addrs: S) -> Resolve
+    where
+        S: Stream<Item = Vec<WeightedAddr>, Error = ()> + 'static,
+    {
+        Resolve(Box::new(addrs.map(Ok)))
+    }
+
+    /// Provides the resolutions, and resolution failures, produced by `resolutions`.
+    pub fn from_results<S>(resolutions: S) -> Resolve
+    where
+        S: Stream<Item = Result<Vec<WeightedAddr>>, Error = ()> + 'static,
+    {
+        Resolve(Box::new(resolutions))
+    }
+}
 
 impl Stream for Resolve {
     type Item = Result<Vec<WeightedAddr>>;
@@ -77,17 +221,91 @@ impl Stream for Resolve {
 /// Serves resolutions from `Resolver`s.
 pub struct Executor {
     requests: mpsc::UnboundedReceiver<(Path, mpsc::UnboundedSender<Result<Vec<WeightedAddr>>>)>,
-    namerd: Namerd,
+    source: Source,
+    /// When set, each name's initial namerd resolution is bounded by the process's
+    /// startup, under the router's label.
+    startup: Option<(Startup, String)>,
+}
+
+enum Source {
+    Namerd(Namerd),
+    /// Resolves names through the local resolver, which polls `Namerd`.
+    Local(Namerd, LocalPolicy),
+    Static(HashMap<Path, Vec<WeightedAddr>>),
 }
 
 impl Executor {
+    /// Bounds each name's initial namerd resolution by `startup`, on behalf of `router`.
+    pub fn with_startup(mut self, startup: &Startup, router: &str) -> Executor {
+        self.startup = Some((startup.clone(), router.to_owned()));
+        self
+    }
+
     pub fn execute(self, handle: &Handle, timer: &Timer) -> Execute {
+        let (namerd, local) = match self.source {
+            Source::Namerd(namerd) => (namerd, None),
+            Source::Local(namerd, local) => (namerd, Some(local)),
+            Source::Static(addrs) => {
+                // Each name is resolved once, after which its resolution is complete.
+                let f = self.requests.for_each(move |(path, rsp_tx)| {
+                    let rsp = match addrs.get(&path) {
+                        Some(a) => Ok(a.clone()),
+                        None => Err(Error::NotBound),
+                    };
+                    let _ = rsp_tx.unbounded_send(rsp);
+                    Ok(())
+                });
+                return Execute(Box::new(f));
+            }
+        };
         let handle = handle.clone();
-        let namerd = self.namerd.with_client(&handle, timer);
+        let timer = timer.clone();
+        let namerd = Rc::new(namerd.with_client(&handle, &timer));
+        let resolve: Rc<Fn(&str) -> local::Resolutions> = match local {
+            None => {
+                let namerd = namerd.clone();
+                Rc::new(move |path: &str| -> local::Resolutions {
+                    Box::new(namerd.resolve(path))
+                })
+            }
+            Some(local) => {
+                let role = local.elect(&namerd, &handle, &timer);
+                Rc::new(move |path: &str| role.resolve(path))
+            }
+        };
+        let startup = self.startup;
         let f = self.requests.for_each(move |(path, rsp_tx)| {
-            // Stream namerd resolutions to the response channel.
-            let resolve = namerd.resolve(path.as_str());
-            let respond = resolve.forward(rsp_tx).map_err(|_| {}).map(|_| {});
+            // Stream namerd resolutions to the response channel. A resolution that
+            // panics is restarted, so that the name continues to be resolved.
+            let panics = namerd.panics(path.as_str());
+            let name = format!("resolution of {}", path);
+            let resolve = resolve.clone();
+            let startup = startup.clone();
+            let respond = supervise::restart(
+                name,
+                move || -> Box<Future<Item = (), Error = ()>> {
+                    let rsp_tx = rsp_tx.clone();
+                    match startup {
+                        None => {
+                            let resolve = resolve(path.as_str());
+                            Box::new(resolve.forward(rsp_tx).map_err(|_| {}).map(|_| {}))
+                        }
+                        Some((ref startup, ref router)) => {
+                            // The request is not issued until the resolution is
+                            // permitted to start.
+                            let resolve = resolve.clone();
+                            let p = path.clone();
+                            let resolve =
+                                future::lazy(move || Ok::<_, Error>(resolve(p.as_str())))
+                                    .flatten_stream();
+                            let resolve = startup.initial(router, path.as_str(), resolve);
+                            Box::new(resolve.forward(rsp_tx).map_err(|_| {}).map(|_| {}))
+                        }
+                    }
+                },
+                &timer,
+                panics,
+            );
             // Do all of this work in another task so that we can receive
             // additional requests.
             handle.spawn(respond);
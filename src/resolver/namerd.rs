@@ -4,23 +4,45 @@
 // balancers can be shared across logical names. In the meantime, it's sufficient to have
 // a balancer per logical name.
 
-use super::{WeightedAddr, Result, Error};
-use bytes::{Buf, BufMut, IntoBuf, Bytes, BytesMut};
+use super::{ERROR_CATEGORIES, WeightedAddr, Result, Error};
+use super::cache::{self, Bootstrap, Cache};
+use super::super::connector::RemotePolicy;
+use super::super::metrics;
+use super::super::state::{CachedResolutions, RemotePolicies};
+use super::super::summary::Signals;
 use futures::{Async, Future, IntoFuture, Poll, Stream};
-use hyper::{Body, Chunk, Client, StatusCode, Uri};
+use futures_cpupool::{self, CpuPool};
+use hyper::{Body, Chunk, Client, Method, Request, StatusCode, Uri};
 use hyper::client::{Connect as HyperConnect, HttpConnector};
+use hyper::header::{ContentLength, ETag, EntityTag, IfNoneMatch};
 use serde_json as json;
-use std::{net, time};
-use std::collections::HashMap;
+use std::{io, mem, net, time, vec};
+use std::io::Read;
+use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
-use tacho;
+use std::sync::Arc;
 use tokio_core::reactor::Handle;
 use tokio_timer::{Timer, Interval};
 use url::Url;
 
 type HttpConnectorFactory = Client<HttpConnector>;
 
-type AddrsFuture = Box<Future<Item = Vec<WeightedAddr>, Error = Error>>;
+/// The address metadata entry that carries its weight.
+const WEIGHT_META_KEY: &'static str = "endpoint_addr_weight";
+
+type AddrsFuture = Box<Future<Item = Resolution, Error = Error>>;
+
+type ParseFuture = Box<Future<Item = Parsed, Error = Error>>;
+
+/// A successful namerd response.
+enum Resolution {
+    /// The name's addresses, and the entity tag identifying them if namerd provided one.
+    Bound(Parsed, Option<EntityTag>),
+    /// The addresses are unchanged since the entity tag sent with the request.
+    NotModified,
+}
 
 // pub struct Addrs(Box<Stream<Item = Result<Vec<WeightedAddr>>, Error = ()>>);
 // impl Stream for Addrs {
@@ -33,34 +55,107 @@ type AddrsFuture = Box<Future<Item = Vec<WeightedAddr>, Error = Error>>;
 
 #[derive(Clone)]
 pub struct Namerd {
-    base_url: String,
+    /// The URL at which names are resolved in the namespace.
+    resolve_url: Url,
     period: time::Duration,
     namespace: String,
-    stats: Stats,
+    /// Responses with larger bodies fail with `Error::ResponseTooLarge`.
+    max_response_bytes: usize,
+    /// Responses with more distinct addresses fail with `Error::TooManyAddrs`.
+    max_addrs: usize,
+    metrics: metrics::Scope,
+    /// Requests that take longer fail with `Error::Timeout`.
+    request_timeout: time::Duration,
+    /// Set when resolutions are saved, to be served after a restart.
+    cache: Option<cache::Policy>,
+    /// Notes names served from the cache.
+    cached: CachedResolutions,
+    /// Notes when names are resolved, so that staleness may be summarized.
+    signals: Arc<Signals>,
+    /// Set when remote policy is accepted from the metadata of namerd's responses.
+    remote_policy: Option<AcceptRemotePolicy>,
+}
+
+/// Records the remote policy that namerd publishes for each of a router's names.
+#[derive(Clone)]
+struct AcceptRemotePolicy {
+    router: String,
+    allowed: Arc<BTreeSet<String>>,
+    policies: RemotePolicies,
 }
 
 impl Namerd {
     pub fn new(
-        base_url: String,
+        resolve_url: Url,
         period: time::Duration,
         namespace: String,
-        metrics: tacho::Scope,
+        max_response_bytes: usize,
+        max_addrs: usize,
+        metrics: metrics::Scope,
+        request_timeout: time::Duration,
+        cache: Option<cache::Policy>,
     ) -> Namerd {
         Namerd {
-            base_url: format!("{}/api/1/resolve/{}", base_url, namespace),
-            stats: Stats::new(metrics),
+            resolve_url,
+            metrics,
             namespace,
+            max_response_bytes,
+            max_addrs,
             period,
+            request_timeout,
+            cache,
+            cached: CachedResolutions::default(),
+            signals: Arc::new(Signals::default()),
+            remote_policy: None,
         }
     }
+
+    /// Notes the names served from the resolution cache in `cached`.
+    pub fn with_cached_resolutions(mut self, cached: &CachedResolutions) -> Namerd {
+        self.cached = cached.clone();
+        self
+    }
+
+    /// Notes successful resolutions in `signals`.
+    pub fn with_signals(mut self, signals: &Arc<Signals>) -> Namerd {
+        self.signals = signals.clone();
+        self
+    }
+
+    /// Records the `allowed` entries of each response's metadata in `policies`, as
+    /// `router`'s remote policy for the name.
+    pub fn with_remote_policy(
+        mut self,
+        router: &str,
+        allowed: BTreeSet<String>,
+        policies: &RemotePolicies,
+    ) -> Namerd {
+        self.remote_policy = Some(AcceptRemotePolicy {
+            router: router.to_owned(),
+            allowed: Arc::new(allowed),
+            policies: policies.clone(),
+        });
+        self
+    }
 }
 
 impl Namerd {
     pub fn with_client(self, handle: &Handle, timer: &Timer) -> WithClient {
+        let parser = Parser {
+            pool: futures_cpupool::Builder::new()
+                .pool_size(1)
+                .name_prefix("namerd-parser-")
+                .create(),
+            max_response_bytes: self.max_response_bytes,
+            max_addrs: self.max_addrs,
+        };
+        let cache = self.cache.clone().map(cache::Policy::load);
         WithClient {
             namerd: self,
             client: Rc::new(Client::new(handle)),
+            parser,
             timer: timer.clone(),
+            cache,
         }
     }
 }
@@ -69,23 +164,64 @@ impl Namerd {
 pub struct WithClient {
     namerd: Namerd,
     client: Rc<HttpConnectorFactory>,
+    parser: Parser,
     timer: Timer,
+    cache: Option<Cache>,
 }
 impl WithClient {
+    /// The namespace in which names are resolved.
+    pub fn namespace(&self) -> &str {
+        &self.namerd.namespace
+    }
+
+    /// Scopes the resolver's metrics.
+    pub fn metrics(&self) -> &metrics::Scope {
+        &self.namerd.metrics
+    }
+
+    /// Counts panics while resolving `target`.
+    pub fn panics(&self, target: &str) -> Arc<metrics::Counter> {
+        self.namerd.metrics.clone().labeled("path", target).counter("resolution_panics")
+    }
+
     pub fn resolve(&self, target: &str) -> Addrs {
-        let uri = Url::parse_with_params(&self.namerd.base_url, &[("path", &target)])
-            .expect("invalid namerd url")
-            .as_str()
-            .parse::<Uri>()
-            .expect("Could not parse namerd URI");
-        let init = request(self.client.clone(), uri.clone(), self.namerd.stats.clone());
-        let interval = self.timer.interval(self.namerd.period);
-        Addrs {
+        // The name is percent-encoded in the query, so it may contain any characters.
+        let mut url = self.namerd.resolve_url.clone();
+        url.query_pairs_mut().append_pair("path", target);
+        let uri = url.as_str().parse::<Uri>().ok();
+        // Each path's requests are measured separately, so that failures to resolve a
+        // single path may be identified.
+        let metrics = self.namerd.metrics.clone().labeled("path", target);
+        let bootstrap = self.cache.as_ref().map(|c| {
+            c.bootstrap(
+                &self.namerd.namespace,
+                target,
+                &self.namerd.cached,
+                &self.timer,
+                &metrics,
+            )
+        });
+        let stats = Stats::new(metrics);
+        let mut addrs = Addrs {
             client: self.client.clone(),
-            stats: self.namerd.stats.clone(),
-            state: Some(State::Pending(init, interval)),
+            parser: self.parser.clone(),
+            stats,
+            timer: self.timer.clone(),
+            request_timeout: self.namerd.request_timeout,
+            state: None,
+            target: target.to_owned(),
             uri,
-        }
+            bootstrap,
+            signals: self.namerd.signals.clone(),
+            remote_policy: self.namerd.remote_policy.clone(),
+            policy_meta: None,
+            etag: None,
+            digest: None,
+        };
+        let init = addrs.request();
+        let interval = self.timer.interval(self.namerd.period);
+        addrs.state = Some(State::Pending(init, interval));
+        addrs
     }
 }
 
@@ -93,8 +229,50 @@ impl WithClient {
 pub struct Addrs {
     state: Option<State>,
     client: Rc<HttpConnectorFactory>,
-    uri: Uri,
+    parser: Parser,
+    target: String,
+    /// Unset if the target could not be encoded in a request, in which case each
+    /// request fails.
+    uri: Option<Uri>,
     stats: Stats,
+    timer: Timer,
+    request_timeout: time::Duration,
+    /// Set when resolutions are cached.
+    bootstrap: Option<Bootstrap>,
+    signals: Arc<Signals>,
+    /// Set when remote policy is accepted.
+    remote_policy: Option<AcceptRemotePolicy>,
+    /// The metadata from which remote policy was last recorded, so that it is parsed
+    /// only when it changes.
+    policy_meta: Option<HashMap<String, String>>,
+    /// Identifies the last addresses namerd returned, so that unchanged addresses need
+    /// not be sent again.
+    etag: Option<EntityTag>,
+    /// Identifies the last addresses emitted, so that identical updates are not.
+    digest: Option<u64>,
+}
+
+impl Addrs {
+    fn request(&self) -> AddrsFuture {
+        match self.uri {
+            Some(ref uri) => {
+                request(
+                    self.client.clone(),
+                    uri.clone(),
+                    self.etag.clone(),
+                    self.parser.clone(),
+                    self.stats.clone(),
+                    &self.timer,
+                    self.request_timeout,
+                )
+            }
+            None => {
+                let e = Error::InvalidPath(self.target.clone());
+                self.stats.record_failure(&e);
+                Box::new(Err(e).into_future())
+            }
+        }
+    }
 }
 
 enum State {
@@ -107,6 +285,31 @@ impl Stream for Addrs {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let rsp = self.poll_namerd()?;
+        if let Async::Ready(Some(Ok(_))) = rsp {
+            self.signals.resolved();
+        }
+        if let Some(ref mut bootstrap) = self.bootstrap {
+            match rsp {
+                Async::Ready(Some(Ok(ref addrs))) => bootstrap.resolved(addrs),
+                Async::Ready(Some(Err(_))) => bootstrap.failed(),
+                Async::Ready(None) => {}
+                Async::NotReady => {
+                    if let Some(addrs) = bootstrap.poll_cached() {
+                        // Namerd's next response replaces the cached addresses, even
+                        // if namerd's addresses are unchanged.
+                        self.digest = None;
+                        return Ok(Async::Ready(Some(Ok(addrs))));
+                    }
+                }
+            }
+        }
+        Ok(rsp)
+    }
+}
+
+impl Addrs {
+    fn poll_namerd(&mut self) -> Poll<Option<Result<Vec<WeightedAddr>>>, Error> {
         loop {
             match self.state.take().expect("polled after completion") {
                 State::Waiting(mut int) => {
@@ -120,12 +323,7 @@ impl Stream for Addrs {
                             return Ok(Async::NotReady);
                         }
                         Ok(Async::Ready(_)) => {
-                            let fut = {
-                                let c = self.client.clone();
-                                let u = self.uri.clone();
-                                let s = self.stats.clone();
-                                request(c, u, s)
-                            };
+                            let fut = self.request();
                             self.state = Some(State::Pending(fut, int));
                         }
                     }
@@ -137,9 +335,23 @@ impl Stream for Addrs {
                             self.state = Some(State::Waiting(int));
                             return Ok(Async::Ready(Some(Err(e))));
                         }
-                        Ok(Async::Ready(addrs)) => {
+                        Ok(Async::Ready(Resolution::Bound(mut parsed, etag))) => {
                             self.state = Some(State::Waiting(int));
-                            return Ok(Async::Ready(Some(Ok(addrs))));
+                            self.etag = etag;
+                            // Policy may change while addresses do not.
+                            let meta = mem::replace(&mut parsed.meta, HashMap::new());
+                            self.record_policy(meta);
+                            let d = digest(&parsed.addrs);
+                            if self.digest != Some(d) {
+                                self.digest = Some(d);
+                                let addrs = self.cleaned(parsed);
+                                return Ok(Async::Ready(Some(Ok(addrs))));
+                            }
+                            self.unchanged();
+                        }
+                        Ok(Async::Ready(Resolution::NotModified)) => {
+                            self.state = Some(State::Waiting(int));
+                            self.unchanged();
                         }
                         Ok(Async::NotReady) => {
                             self.state = Some(State::Pending(fut, int));
@@ -152,27 +364,133 @@ impl Stream for Addrs {
     }
 }
 
-fn request<C: HyperConnect>(client: Rc<Client<C>>, uri: Uri, stats: Stats) -> AddrsFuture {
+impl Addrs {
+    /// Notes that namerd resolved the name to the addresses that were last emitted.
+    ///
+    /// Nothing is emitted, so that balancers are not updated needlessly, but the name
+    /// is still considered freshly resolved.
+    fn unchanged(&mut self) {
+        trace!("{}: resolution unchanged", self.target);
+        self.stats.unchanged_count.incr(1);
+        self.signals.resolved();
+    }
+
+    /// Records the remote policy in a response's metadata, if it is accepted and the
+    /// metadata has changed. Keys that are not allowed, not known, or not valid are
+    /// counted and logged.
+    fn record_policy(&mut self, meta: HashMap<String, String>) {
+        let accept = match self.remote_policy {
+            None => return,
+            Some(ref accept) => accept,
+        };
+        if self.policy_meta.as_ref() == Some(&meta) {
+            return;
+        }
+        let (policy, ignored) = RemotePolicy::parse(&meta, &accept.allowed);
+        if !ignored.is_empty() {
+            warn!(
+                "{}: ignored {} remote policy keys: {}",
+                self.target,
+                ignored.len(),
+                ignored.join(", ")
+            );
+            self.stats.ignored_policy_keys.incr(ignored.len());
+        }
+        if accept.policies.set(&accept.router, &self.target, policy) {
+            debug!("{}: remote policy changed", self.target);
+        }
+        self.policy_meta = Some(meta);
+    }
+
+    /// Counts and warns about the entries that were dropped from an update, once per
+    /// update rather than once per response.
+    fn cleaned(&self, parsed: Parsed) -> Vec<WeightedAddr> {
+        if parsed.duplicates > 0 {
+            debug!(
+                "{}: merged {} duplicate addresses",
+                self.target,
+                parsed.duplicates
+            );
+            self.stats.duplicate_addrs.incr(parsed.duplicates);
+        }
+        if !parsed.invalid.is_empty() {
+            warn!(
+                "{}: skipped {} invalid addresses: {}",
+                self.target,
+                parsed.invalid.len(),
+                parsed.invalid.join(", ")
+            );
+            self.stats.invalid_addrs.incr(parsed.invalid.len());
+        }
+        parsed.addrs
+    }
+}
+
+/// Identifies a set of addresses, regardless of their order.
+fn digest(addrs: &[WeightedAddr]) -> u64 {
+    let mut addrs: Vec<&WeightedAddr> = addrs.iter().collect();
+    addrs.sort_by(|a, b| {
+        a.addr.cmp(&b.addr).then_with(|| {
+            a.weight.partial_cmp(&b.weight).unwrap_or(::std::cmp::Ordering::Equal)
+        })
+    });
+    let mut hasher = DefaultHasher::new();
+    for a in addrs {
+        a.addr.hash(&mut hasher);
+        a.weight.to_bits().hash(&mut hasher);
+        a.meta.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn request<C: HyperConnect>(
+    client: Rc<Client<C>>,
+    uri: Uri,
+    etag: Option<EntityTag>,
+    parser: Parser,
+    stats: Stats,
+    timer: &Timer,
+    timeout: time::Duration,
+) -> AddrsFuture {
     debug!("Polling namerd at {}", uri.to_string());
-    let rsp = stats
-        .request_latency
-        .time(client.get(uri).then(handle_response))
-        .then(move |rsp| {
-            if rsp.is_ok() {
-                stats.success_count.incr(1);
-            } else {
-                stats.failure_count.incr(1);
-            }
-            rsp
-        });
+    let mut req = Request::new(Method::Get, uri);
+    if let Some(etag) = etag {
+        req.headers_mut().set(IfNoneMatch::Items(vec![etag]));
+    }
+    let rsp = client.request(req).then(move |rsp| handle_response(rsp, &parser));
+    let rsp = timer.timeout(rsp, timeout);
+    let rsp = metrics::timed(&stats.request_latency, rsp).then(move |rsp| {
+        match rsp {
+            Ok(_) => stats.success_count.incr(1),
+            Err(ref e) => stats.record_failure(e),
+        }
+        rsp
+    });
     Box::new(rsp)
 }
 
-fn handle_response(result: ::hyper::Result<::hyper::client::Response>) -> AddrsFuture {
+fn handle_response(
+    result: ::hyper::Result<::hyper::client::Response>,
+    parser: &Parser,
+) -> AddrsFuture {
     match result {
         Ok(rsp) => {
             match rsp.status() {
-                StatusCode::Ok => parse_body(rsp.body()),
+                StatusCode::Ok => {
+                    if let Some(&ContentLength(len)) = rsp.headers().get::<ContentLength>() {
+                        if len > parser.max_response_bytes as u64 {
+                            info!("error: response of {} bytes is too large", len);
+                            let e = Error::ResponseTooLarge(parser.max_response_bytes);
+                            return Box::new(Err(e).into_future());
+                        }
+                    }
+                    let etag = rsp.headers().get::<ETag>().map(|&ETag(ref t)| t.clone());
+                    Box::new(parser.parse(rsp.body()).map(
+                        move |addrs| Resolution::Bound(addrs, etag),
+                    ))
+                }
+                // Sent only when the request's entity tag matches, without a body.
+                StatusCode::NotModified => Box::new(Ok(Resolution::NotModified).into_future()),
                 status => {
                     info!("error: bad response: {}", status);
                     Box::new(Err(Error::UnexpectedStatus(status)).into_future())
@@ -186,41 +504,84 @@ fn handle_response(result: ::hyper::Result<::hyper::client::Response>) -> AddrsF
     }
 }
 
-fn parse_body(body: Body) -> AddrsFuture {
-    trace!("parsing namerd response");
-    let f = body.collect()
-        .then(|res| match res {
-            Ok(ref chunks) => parse_chunks(chunks),
-            Err(e) => {
-                info!("error: {}", e);
-                Err(Error::Hyper(e))
+/// Parses namerd responses on a worker thread, so that large responses don't stall the
+/// reactor.
+#[derive(Clone)]
+struct Parser {
+    pool: CpuPool,
+    max_response_bytes: usize,
+    max_addrs: usize,
+}
+
+impl Parser {
+    fn parse(&self, body: Body) -> ParseFuture {
+        trace!("parsing namerd response");
+        let max = self.max_response_bytes;
+        let max_addrs = self.max_addrs;
+        let pool = self.pool.clone();
+        let f = body.map_err(|e| {
+            info!("error: {}", e);
+            Error::Hyper(e)
+        }).fold((Vec::new(), 0), move |(mut chunks, sz), chunk| {
+            // The response is abandoned as soon as it is known to be too large.
+            let sz = sz + chunk.len();
+            if sz > max {
+                info!("error: response exceeds {} bytes", max);
+                return Err(Error::ResponseTooLarge(max));
             }
-        });
-    Box::new(f)
+            chunks.push(chunk);
+            Ok((chunks, sz))
+        })
+            .and_then(move |(chunks, _)| {
+                pool.spawn_fn(move || parse_chunks(chunks, max_addrs))
+            });
+        Box::new(f)
+    }
+}
+
+/// Reads a response body's chunks in order, without copying them into one buffer.
+struct ChunksReader {
+    chunks: vec::IntoIter<Chunk>,
+    current: Option<io::Cursor<Chunk>>,
 }
 
-fn bytes_in(chunks: &[Chunk]) -> usize {
-    let mut sz = 0;
-    for c in chunks {
-        sz += (*c).len();
+impl ChunksReader {
+    fn new(chunks: Vec<Chunk>) -> ChunksReader {
+        ChunksReader {
+            chunks: chunks.into_iter(),
+            current: None,
+        }
     }
-    sz
 }
 
-fn to_buf(chunks: &[Chunk]) -> Bytes {
-    let mut buf = BytesMut::with_capacity(bytes_in(chunks));
-    for c in chunks {
-        buf.put_slice(&*c)
+impl io::Read for ChunksReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref mut chunk) = self.current {
+                let sz = chunk.read(buf)?;
+                if sz > 0 || buf.is_empty() {
+                    return Ok(sz);
+                }
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = Some(io::Cursor::new(chunk)),
+                None => return Ok(0),
+            }
+        }
     }
-    buf.freeze()
 }
 
-fn parse_chunks(chunks: &[Chunk]) -> Result<Vec<WeightedAddr>> {
-    let r = to_buf(chunks).into_buf().reader();
-    let result: json::Result<NamerdResponse> = json::from_reader(r);
+fn parse_chunks(chunks: Vec<Chunk>, max_addrs: usize) -> Result<Parsed> {
+    let result: json::Result<NamerdResponse> = json::from_reader(ChunksReader::new(chunks));
     match result {
-        Ok(ref nrsp) if nrsp.kind == "bound" => Ok(to_weighted_addrs(&nrsp.addrs)),
-        Ok(_) => Err(Error::NotBound),
+        Ok(nrsp) => {
+            if nrsp.kind != "bound" {
+                return Err(Error::NotBound);
+            }
+            let mut parsed = to_weighted_addrs(&nrsp.addrs, max_addrs)?;
+            parsed.meta = nrsp.meta;
+            Ok(parsed)
+        }
         Err(e) => {
             info!("error parsing response: {}", e);
             Err(Error::Serde(e))
@@ -228,21 +589,70 @@ fn parse_chunks(chunks: &[Chunk]) -> Result<Vec<WeightedAddr>> {
     }
 }
 
-fn to_weighted_addrs(namerd_addrs: &[NamerdAddr]) -> Vec<WeightedAddr> {
+/// The addresses in a namerd response, and the entries that were dropped from it.
+struct Parsed {
+    addrs: Vec<WeightedAddr>,
+    /// The number of entries whose addresses repeated an earlier entry's.
+    duplicates: usize,
+    /// Entries that cannot be connected to, e.g. `0.0.0.0:8080` or `10.0.0.1:0`.
+    invalid: Vec<String>,
+    /// The response's metadata, which may carry remote policy.
+    meta: HashMap<String, String>,
+}
+
+/// Converts namerd's addresses into weighted addresses, carrying their weights as they
+/// were resolved. Balancers normalize them unless configured with `weightMode: absolute`.
+///
+/// Entries that repeat an address are merged into its first entry, adding their weights
+/// to it. Entries with an unparseable, unspecified, or multicast IP, with port 0, or
+/// with a negative or non-finite weight are skipped. Responses with more than
+/// `max_addrs` distinct addresses are rejected.
+fn to_weighted_addrs(namerd_addrs: &[NamerdAddr], max_addrs: usize) -> Result<Parsed> {
     // We never intentionally clear the EndpointMap.
     let mut dsts: Vec<WeightedAddr> = Vec::new();
-    let mut sum = 0.0;
+    let mut indices: HashMap<net::SocketAddr, usize> = HashMap::new();
+    let mut duplicates = 0;
+    let mut invalid = Vec::new();
     for na in namerd_addrs {
-        let addr = net::SocketAddr::new(na.ip.parse().unwrap(), na.port);
-        let w = na.meta.endpoint_addr_weight.unwrap_or(1.0);
-        sum += w;
-        dsts.push(WeightedAddr::new(addr, w));
-    }
-    // Normalize weights on [0.0, 0.1].
-    for dst in &mut dsts {
-        dst.weight /= sum;
+        let ip = match na.ip.parse::<net::IpAddr>() {
+            Ok(ip) if na.port != 0 && !ip.is_unspecified() && !ip.is_multicast() => ip,
+            _ => {
+                invalid.push(format!("{}:{}", na.ip, na.port));
+                continue;
+            }
+        };
+        let addr = net::SocketAddr::new(ip, na.port);
+        let w = na.meta.get(WEIGHT_META_KEY).and_then(|w| w.as_f64()).unwrap_or(1.0);
+        if !w.is_finite() || w < 0.0 {
+            invalid.push(format!("{}:{} (weight {})", na.ip, na.port, w));
+            continue;
+        }
+        if let Some(&i) = indices.get(&addr) {
+            duplicates += 1;
+            dsts[i].weight += w;
+            continue;
+        }
+        if dsts.len() == max_addrs {
+            info!("error: response exceeds {} addresses", max_addrs);
+            return Err(Error::TooManyAddrs(max_addrs));
+        }
+        indices.insert(addr, dsts.len());
+        // Every string-valued entry (e.g. `authority`, `nodeName`, or `zone`) is carried
+        // with the address.
+        let mut dst = WeightedAddr::new(addr, w);
+        for (k, v) in &na.meta {
+            if let Some(v) = v.as_str() {
+                dst = dst.with_meta(k.as_str(), v);
+            }
+        }
+        dsts.push(dst);
     }
-    dsts
+    Ok(Parsed {
+        addrs: dsts,
+        duplicates,
+        invalid,
+        meta: HashMap::new(),
+    })
 }
 
 #[derive(Debug, Deserialize)]
@@ -257,32 +667,57 @@ struct NamerdResponse {
 struct NamerdAddr {
     ip: String,
     port: u16,
-    meta: Meta,
-}
-
-#[derive(Debug, Deserialize)]
-struct Meta {
-    authority: Option<String>,
-
-    #[serde(rename = "nodeName")]
-    node_name: Option<String>,
-
-    endpoint_addr_weight: Option<f64>,
+    meta: HashMap<String, json::Value>,
 }
 
 
 #[derive(Clone)]
 pub struct Stats {
-    request_latency: tacho::Timer,
-    success_count: tacho::Counter,
-    failure_count: tacho::Counter,
+    request_latency: Arc<metrics::Timer>,
+    success_count: Arc<metrics::Counter>,
+    /// Counts successful responses whose addresses were unchanged.
+    unchanged_count: Arc<metrics::Counter>,
+    /// Counts entries merged into an earlier entry with the same address.
+    duplicate_addrs: Arc<metrics::Counter>,
+    /// Counts entries skipped because they could not be connected to.
+    invalid_addrs: Arc<metrics::Counter>,
+    /// Counts responses rejected for having too many addresses.
+    too_many_addrs: Arc<metrics::Counter>,
+    /// Counts metadata keys that were not accepted as remote policy.
+    ignored_policy_keys: Arc<metrics::Counter>,
+    /// Counts all failures, regardless of their category.
+    failure_count: Arc<metrics::Counter>,
+    /// Counts failures by category.
+    error_counts: Arc<HashMap<&'static str, Arc<metrics::Counter>>>,
 }
 impl Stats {
-    fn new(metrics: tacho::Scope) -> Stats {
+    fn new(metrics: metrics::Scope) -> Stats {
+        let error_counts = ERROR_CATEGORIES
+            .iter()
+            .map(|&c| {
+                (c, metrics.clone().labeled("cause", c).counter("error_count"))
+            })
+            .collect();
         Stats {
-            request_latency: metrics.timer_ms("request_latency_ms".into()),
-            success_count: metrics.counter("success_count".into()),
-            failure_count: metrics.counter("failure_count".into()),
+            request_latency: metrics.timer_ms("request_latency_ms"),
+            success_count: metrics.counter("success_count"),
+            unchanged_count: metrics.counter("resolutions_unchanged"),
+            duplicate_addrs: metrics.counter("duplicate_addrs"),
+            invalid_addrs: metrics.counter("invalid_addrs"),
+            too_many_addrs: metrics.counter("too_many_addrs"),
+            ignored_policy_keys: metrics.counter("remote_policy_ignored_keys"),
+            failure_count: metrics.counter("failure_count"),
+            error_counts: Arc::new(error_counts),
+        }
+    }
+
+    fn record_failure(&self, e: &Error) {
+        self.failure_count.incr(1);
+        if let Some(c) = self.error_counts.get(e.category()) {
+            c.incr(1);
+        }
+        if let Error::TooManyAddrs(_) = *e {
+            self.too_many_addrs.incr(1);
         }
     }
 }
@@ -0,0 +1,170 @@
+//! Describes the fields known to each section of a configuration.
+//!
+//! Configurations are checked against their schema before they are deserialized, so
+//! that unknown fields may be reported by their path through the configuration, and
+//! so that they may be ignored rather than rejected when a configuration is not strict.
+//!
+//! Each struct's field names are taken from its `Deserialize` implementation. Schemas
+//! only name the fields that contain other sections.
+
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde_json::Value;
+use std::{fmt, mem};
+
+pub enum Schema {
+    /// An object with the given fields, some of which are described by schemas of their
+    /// own. Other fields' values are not inspected.
+    Struct(&'static [&'static str], Vec<(&'static str, Schema)>),
+
+    /// A list of values described by a schema.
+    List(Box<Schema>),
+
+    /// An object with arbitrary keys, each of whose values is described by a schema.
+    Map(Box<Schema>),
+
+    /// An object whose `kind`-like field selects the schema describing the object.
+    Tagged(&'static str, Vec<(&'static str, Schema)>),
+}
+
+impl Schema {
+    /// Describes a struct deserialized as `T`.
+    pub fn of<T: DeserializeOwned>(nested: Vec<(&'static str, Schema)>) -> Schema {
+        let fields = fields::<T>();
+        for &(f, _) in &nested {
+            debug_assert!(fields.contains(&f), "no field named {}", f);
+        }
+        Schema::Struct(fields, nested)
+    }
+
+    pub fn list(item: Schema) -> Schema {
+        Schema::List(Box::new(item))
+    }
+
+    pub fn map(value: Schema) -> Schema {
+        Schema::Map(Box::new(value))
+    }
+
+    /// Removes fields not described by this schema from `value`, returning the JSON
+    /// pointer to each removed field.
+    pub fn strip_unknown(&self, value: &mut Value) -> Vec<String> {
+        let mut unknown = Vec::new();
+        self.strip(value, "", &mut unknown);
+        unknown
+    }
+
+    fn strip(&self, value: &mut Value, path: &str, unknown: &mut Vec<String>) {
+        match (self, value) {
+            (&Schema::Struct(fields, ref nested), &mut Value::Object(ref mut obj)) => {
+                let removed: Vec<String> = obj.keys()
+                    .filter(|k| !fields.iter().any(|&f| f == k.as_str()))
+                    .cloned()
+                    .collect();
+                for k in removed {
+                    obj.remove(&k);
+                    unknown.push(pointer(path, &k));
+                }
+                for &(k, ref schema) in nested {
+                    if let Some(v) = obj.get_mut(k) {
+                        schema.strip(v, &pointer(path, k), unknown);
+                    }
+                }
+            }
+
+            (&Schema::List(ref item), &mut Value::Array(ref mut values)) => {
+                for (i, v) in values.iter_mut().enumerate() {
+                    item.strip(v, &pointer(path, &i.to_string()), unknown);
+                }
+            }
+
+            (&Schema::Map(ref schema), &mut Value::Object(ref mut obj)) => {
+                for (k, v) in obj.iter_mut() {
+                    schema.strip(v, &pointer(path, k), unknown);
+                }
+            }
+
+            (&Schema::Tagged(tag, ref variants), &mut Value::Object(ref mut obj)) => {
+                // The tag is not a field of the variant, so it is set aside while the
+                // variant is checked. Unknown variants are left to fail deserialization.
+                let kind = match obj.remove(tag) {
+                    None => return,
+                    Some(kind) => kind,
+                };
+                if let Some(&(_, ref schema)) = variants.iter().find(|&&(v, _)| {
+                    kind.as_str() == Some(v)
+                })
+                {
+                    let mut variant = Value::Object(mem::replace(obj, Default::default()));
+                    schema.strip(&mut variant, path, unknown);
+                    if let Value::Object(v) = variant {
+                        *obj = v;
+                    }
+                }
+                obj.insert(tag.to_owned(), kind);
+            }
+
+            _ => {}
+        }
+    }
+}
+
+/// Appends `key` to a JSON pointer, escaping it as described by RFC 6901.
+fn pointer(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Returns the field names of a struct deserialized as `T`.
+///
+/// `T` is asked to deserialize itself from a deserializer that fails as soon as it is
+/// told which fields `T` expects.
+fn fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    match T::deserialize(FieldsProbe) {
+        Err(Probed(Some(fields))) => fields,
+        _ => panic!("not a struct"),
+    }
+}
+
+struct FieldsProbe;
+
+#[derive(Debug)]
+struct Probed(Option<&'static [&'static str]>);
+
+impl fmt::Display for Probed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("probed struct fields")
+    }
+}
+
+impl ::std::error::Error for Probed {
+    fn description(&self) -> &str {
+        "probed struct fields"
+    }
+}
+
+impl de::Error for Probed {
+    fn custom<T: fmt::Display>(_msg: T) -> Probed {
+        Probed(None)
+    }
+}
+
+impl<'de> Deserializer<'de> for FieldsProbe {
+    type Error = Probed;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _v: V) -> Result<V::Value, Probed> {
+        Err(Probed(None))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _v: V,
+    ) -> Result<V::Value, Probed> {
+        Err(Probed(Some(fields)))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
@@ -0,0 +1,227 @@
+//! Drops the process's privileges once its listeners are bound.
+//!
+//! A proxy may be started as root (or with `CAP_NET_BIND_SERVICE`) so that it can bind
+//! low ports, and then confine itself to a directory and switch to an unprivileged user
+//! before serving any traffic. Users and groups are resolved when the configuration is
+//! loaded, since the user and group databases may not be readable from within a chroot.
+//!
+//! Everything that the proxy reads from files at startup (e.g. certificates, keys, and
+//! trust anchors) is read before privileges are dropped. Files that are opened while
+//! serving, like resolution caches, are resolved relative to the chroot. Agents'
+//! sockets and reloaded TLS files are re-opened by their configured paths, so agent
+//! identities and `reloadIntervalSecs` may not be combined with a chroot.
+
+use std::{fmt, io};
+use std::path::PathBuf;
+
+/// Configures how the process reduces its privileges after binding its listeners.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SecurityConfig {
+    /// The user, by name or id, as which the process runs. Unless `runAsGroup` is set,
+    /// the user's primary group is also assumed.
+    pub run_as_user: Option<String>,
+
+    /// The group, by name or id, as which the process runs.
+    pub run_as_group: Option<String>,
+
+    /// The directory to which the process's filesystem root is changed.
+    pub chroot_dir: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Unsupported,
+    UnknownUser(String),
+    UnknownGroup(String),
+    InvalidChrootDir(String),
+    UnsupportedInChroot(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Unsupported => f.write_str("security settings are only supported on unix"),
+            Error::UnknownUser(ref u) => write!(f, "unknown runAsUser: {}", u),
+            Error::UnknownGroup(ref g) => write!(f, "unknown runAsGroup: {}", g),
+            Error::InvalidChrootDir(ref d) => write!(f, "chrootDir is not a directory: {}", d),
+            Error::UnsupportedInChroot(s) => write!(f, "{} cannot be combined with chrootDir", s),
+        }
+    }
+}
+
+impl SecurityConfig {
+    /// Resolves the configured user and group, returning `None` if no privileges are to
+    /// be dropped.
+    pub fn mk_privileges(&self) -> Result<Option<Privileges>, Error> {
+        if self.run_as_user.is_none() && self.run_as_group.is_none() &&
+            self.chroot_dir.is_none()
+        {
+            return Ok(None);
+        }
+        if !cfg!(unix) {
+            return Err(Error::Unsupported);
+        }
+
+        let (uid, user_gid) = match self.run_as_user {
+            None => (None, None),
+            Some(ref u) => {
+                let (uid, gid) = sys::user(u).ok_or_else(|| Error::UnknownUser(u.clone()))?;
+                (Some(uid), gid)
+            }
+        };
+        let gid = match self.run_as_group {
+            None => user_gid,
+            Some(ref g) => Some(sys::group(g).ok_or_else(|| Error::UnknownGroup(g.clone()))?),
+        };
+        let chroot_dir = match self.chroot_dir {
+            None => None,
+            Some(ref d) => {
+                let dir = PathBuf::from(d);
+                if !dir.is_dir() {
+                    return Err(Error::InvalidChrootDir(d.clone()));
+                }
+                Some(dir)
+            }
+        };
+        Ok(Some(Privileges {
+            uid,
+            gid,
+            chroot_dir,
+        }))
+    }
+}
+
+/// Privileges that are dropped once the process has bound its listeners.
+#[derive(Clone, Debug)]
+pub struct Privileges {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    chroot_dir: Option<PathBuf>,
+}
+
+impl Privileges {
+    /// Changes the process's root directory, group, and user, in that order.
+    ///
+    /// This affects every thread in the process, and cannot be undone. It should be
+    /// called after all listeners are bound and before any traffic is served; if it
+    /// fails, the process should not continue.
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        if let Some(ref dir) = self.chroot_dir {
+            sys::chroot(dir)?;
+            info!("changed root directory to {}", dir.display());
+        }
+        if let Some(gid) = self.gid {
+            sys::set_gid(gid)?;
+            info!("running as group {}", gid);
+        }
+        if let Some(uid) = self.uid {
+            sys::set_uid(uid)?;
+            info!("running as user {}", uid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use libc;
+    use std::{env, io};
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// Resolves a user name or id to its id and, if known, its primary group.
+    pub fn user(name: &str) -> Option<(u32, Option<u32>)> {
+        let pw = match name.parse::<libc::uid_t>() {
+            Ok(uid) => unsafe { libc::getpwuid(uid) },
+            Err(_) => {
+                let name = CString::new(name).ok()?;
+                unsafe { libc::getpwnam(name.as_ptr()) }
+            }
+        };
+        if pw.is_null() {
+            // Numeric ids need not have an entry in the user database.
+            return name.parse().ok().map(|uid| (uid, None));
+        }
+        unsafe { Some(((*pw).pw_uid, Some((*pw).pw_gid))) }
+    }
+
+    /// Resolves a group name or id to its id.
+    pub fn group(name: &str) -> Option<u32> {
+        if let Ok(gid) = name.parse::<libc::gid_t>() {
+            return Some(gid);
+        }
+        let name = CString::new(name).ok()?;
+        let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+        if gr.is_null() {
+            None
+        } else {
+            unsafe { Some((*gr).gr_gid) }
+        }
+    }
+
+    pub fn chroot(dir: &Path) -> io::Result<()> {
+        let dir = CString::new(dir.as_os_str().as_bytes()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "chrootDir contains a nul byte")
+        })?;
+        check(unsafe { libc::chroot(dir.as_ptr()) })?;
+        env::set_current_dir("/")
+    }
+
+    pub fn set_gid(gid: u32) -> io::Result<()> {
+        // Supplementary groups (e.g. root's) are dropped along with the primary group.
+        check(unsafe { libc::setgroups(1, &gid) })?;
+        check(unsafe { libc::setgid(gid) })
+    }
+
+    pub fn set_uid(uid: u32) -> io::Result<()> {
+        check(unsafe { libc::setuid(uid) })?;
+        // Ensure that root cannot be regained.
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "privileges were regained after changing users",
+            ));
+        }
+        Ok(())
+    }
+
+    fn check(rc: libc::c_int) -> io::Result<()> {
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn user(_name: &str) -> Option<(u32, Option<u32>)> {
+        None
+    }
+
+    pub fn group(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn chroot(_dir: &Path) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_gid(_gid: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_uid(_uid: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "unsupported on this platform")
+    }
+}
@@ -0,0 +1,363 @@
+//! Validates, and orders, the certificate chains that servers present.
+//!
+//! A chain is read from one or more files that are easily listed in the wrong order or
+//! without an intermediate, which clients only report as failed handshakes. So, when an
+//! identity is loaded, its certificates are ordered from the certificate whose public
+//! key matches the private key, following each certificate's issuer. Each certificate
+//! must be on that path. The path may end without its root, since clients hold roots.
+//!
+//! Only the names and RSA public keys of certificates are read; clients verify their
+//! signatures. The names of clients' certificates are also read to describe connections.
+
+use rustls::{Certificate, PrivateKey};
+use std::{fmt, net};
+
+#[derive(Debug)]
+pub enum Error {
+    /// No certificate matches the private key. Holds the subject of each certificate.
+    NoCertificateForKey(Vec<String>),
+    /// Holds the subject of the last certificate on the chain and its issuer, which
+    /// was not provided, though other certificates were.
+    MissingIssuer {
+        subject: String,
+        issuer: String,
+    },
+    /// Holds the subject of a certificate that is not on the chain.
+    UnchainedCertificate(String),
+    /// Holds the index of a certificate that could not be parsed.
+    InvalidCertificate(usize),
+    InvalidPrivateKey,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoCertificateForKey(ref subjects) => {
+                write!(f, "no certificate matches the private key: {}", subjects.join(", "))
+            }
+            Error::MissingIssuer {
+                ref subject,
+                ref issuer,
+            } => {
+                write!(
+                    f,
+                    "the chain is missing the issuer of {:?}: {:?} (set allowIncompleteChain \
+                     to serve it anyway)",
+                    subject,
+                    issuer
+                )
+            }
+            Error::UnchainedCertificate(ref s) => {
+                write!(f, "certificate {:?} is not part of the chain", s)
+            }
+            Error::InvalidCertificate(i) => write!(f, "certificate {} could not be parsed", i),
+            Error::InvalidPrivateKey => f.write_str("the private key could not be parsed"),
+        }
+    }
+}
+
+/// Orders `certs` from the certificate matching `key` through each issuer.
+///
+/// When `allow_incomplete` is set, certificates that are not on the chain are logged and
+/// presented after it, in the order given, rather than failing.
+pub fn order(
+    certs: Vec<Certificate>,
+    key: &PrivateKey,
+    allow_incomplete: bool,
+) -> Result<Vec<Certificate>, Error> {
+    let chain = chain(&certs, key, allow_incomplete)?;
+    let mut certs: Vec<Option<Certificate>> = certs.into_iter().map(Some).collect();
+    Ok(
+        chain
+            .into_iter()
+            .map(|i| certs[i].take().expect("certificate used twice"))
+            .collect(),
+    )
+}
+
+/// The indices of `certs` in the order in which they should be presented.
+fn chain(
+    certs: &[Certificate],
+    key: &PrivateKey,
+    allow_incomplete: bool,
+) -> Result<Vec<usize>, Error> {
+    let key = rsa_private_key(&key.0).ok_or(Error::InvalidPrivateKey)?;
+    let mut parsed = Vec::with_capacity(certs.len());
+    for (i, c) in certs.iter().enumerate() {
+        parsed.push(Parsed::new(&c.0).ok_or(Error::InvalidCertificate(i))?);
+    }
+
+    let leaf = parsed.iter().position(|p| p.public_key.as_ref() == Some(&key));
+    let mut chain = match leaf {
+        Some(i) => vec![i],
+        None => {
+            let subjects = parsed.iter().map(|p| common_name(p.subject)).collect();
+            return Err(Error::NoCertificateForKey(subjects));
+        }
+    };
+    loop {
+        let last = &parsed[*chain.last().expect("chain must not be empty")];
+        if last.issuer == last.subject {
+            break;
+        }
+        let next = (0..parsed.len()).find(|i| {
+            !chain.contains(i) && parsed[*i].subject == last.issuer
+        });
+        match next {
+            Some(i) => chain.push(i),
+            None => break,
+        }
+    }
+
+    if chain.len() < parsed.len() {
+        let last = &parsed[*chain.last().expect("chain must not be empty")];
+        let unchained = (0..parsed.len()).find(|i| !chain.contains(i)).expect(
+            "chain must omit a certificate",
+        );
+        let e = if last.issuer == last.subject {
+            Error::UnchainedCertificate(common_name(parsed[unchained].subject))
+        } else {
+            Error::MissingIssuer {
+                subject: common_name(last.subject),
+                issuer: common_name(last.issuer),
+            }
+        };
+        if !allow_incomplete {
+            return Err(e);
+        }
+        warn!("serving an incomplete certificate chain: {}", e);
+        for i in 0..parsed.len() {
+            if !chain.contains(&i) {
+                chain.push(i);
+            }
+        }
+    }
+
+    if chain.iter().enumerate().any(|(pos, &i)| pos != i) {
+        info!(
+            "reordered the certificate chain for {:?}",
+            common_name(parsed[chain[0]].subject)
+        );
+    }
+    Ok(chain)
+}
+
+/// The parts of a certificate by which it is chained.
+struct Parsed<'a> {
+    subject: &'a [u8],
+    issuer: &'a [u8],
+    /// Set for RSA keys.
+    public_key: Option<RsaKey<'a>>,
+    /// The fields that follow the public key, including any extensions.
+    rest: &'a [u8],
+}
+
+impl<'a> Parsed<'a> {
+    fn new(der: &'a [u8]) -> Option<Parsed<'a>> {
+        let cert = Der(der).expect(SEQUENCE)?;
+        let mut tbs = Der(Der(cert).expect(SEQUENCE)?);
+        // The version is optional and explicitly tagged.
+        if tbs.0.first() == Some(&0xa0) {
+            tbs.read()?;
+        }
+        tbs.expect(INTEGER)?; // serialNumber
+        tbs.expect(SEQUENCE)?; // signature
+        let issuer = tbs.expect(SEQUENCE)?;
+        tbs.expect(SEQUENCE)?; // validity
+        let subject = tbs.expect(SEQUENCE)?;
+        let spki = tbs.expect(SEQUENCE)?;
+        Some(Parsed {
+            subject,
+            issuer,
+            public_key: rsa_public_key(spki),
+            rest: tbs.0,
+        })
+    }
+}
+
+/// An RSA modulus and public exponent, without leading zeros.
+#[derive(PartialEq)]
+struct RsaKey<'a> {
+    modulus: &'a [u8],
+    exponent: &'a [u8],
+}
+
+/// 1.2.840.113549.1.1.1
+const RSA_ENCRYPTION: &'static [u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// 2.5.4.3
+const COMMON_NAME: &'static [u8] = &[0x55, 0x04, 0x03];
+
+/// 2.5.29.17
+const SUBJECT_ALT_NAME: &'static [u8] = &[0x55, 0x1d, 0x11];
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+/// The explicitly-tagged extensions of a certificate.
+const EXTENSIONS: u8 = 0xa3;
+/// The implicitly-tagged forms of a subject alternative name.
+const DNS_NAME: u8 = 0x82;
+const URI: u8 = 0x86;
+const IP_ADDRESS: u8 = 0x87;
+
+/// The common name of a certificate's subject, if it has one, and its DNS, URI, and IP
+/// subject alternative names.
+pub fn subject_names(cert: &Certificate) -> (Option<String>, Vec<String>) {
+    match Parsed::new(&cert.0) {
+        None => (None, vec![]),
+        Some(p) => {
+            let sans = subject_alt_names(p.rest).unwrap_or_else(Vec::new);
+            (find_common_name(p.subject), sans)
+        }
+    }
+}
+
+/// Reads the subject alternative names from the fields that follow a certificate's
+/// public key.
+fn subject_alt_names(rest: &[u8]) -> Option<Vec<String>> {
+    // Unique identifiers may precede the extensions.
+    let mut fields = Der(rest);
+    let mut exts = loop {
+        match fields.read()? {
+            (EXTENSIONS, exts) => break Der(Der(exts).expect(SEQUENCE)?),
+            _ => continue,
+        }
+    };
+    while let Some(ext) = exts.expect(SEQUENCE) {
+        let mut ext = Der(ext);
+        if ext.expect(OID)? != SUBJECT_ALT_NAME {
+            continue;
+        }
+        // The criticality of an extension is optional.
+        let mut value = ext.read()?;
+        if value.0 == BOOLEAN {
+            value = ext.read()?;
+        }
+        if value.0 != OCTET_STRING {
+            return None;
+        }
+        let mut names = Der(Der(value.1).expect(SEQUENCE)?);
+        let mut sans = Vec::new();
+        while let Some((tag, name)) = names.read() {
+            match tag {
+                DNS_NAME | URI => sans.push(String::from_utf8_lossy(name).into_owned()),
+                IP_ADDRESS => {
+                    if let Some(ip) = ip_address(name) {
+                        sans.push(ip.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        return Some(sans);
+    }
+    None
+}
+
+fn ip_address(octets: &[u8]) -> Option<net::IpAddr> {
+    match octets.len() {
+        4 => Some(net::Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into()),
+        16 => {
+            let mut v6 = [0u8; 16];
+            v6.copy_from_slice(octets);
+            Some(net::Ipv6Addr::from(v6).into())
+        }
+        _ => None,
+    }
+}
+
+fn rsa_public_key(spki: &[u8]) -> Option<RsaKey> {
+    let mut spki = Der(spki);
+    let mut alg = Der(spki.expect(SEQUENCE)?);
+    if alg.expect(OID)? != RSA_ENCRYPTION {
+        return None;
+    }
+    let bits = spki.expect(BIT_STRING)?;
+    // The key is a whole number of bytes.
+    let (&unused, key) = bits.split_first()?;
+    if unused != 0 {
+        return None;
+    }
+    let mut key = Der(Der(key).expect(SEQUENCE)?);
+    Some(RsaKey {
+        modulus: unsigned(key.expect(INTEGER)?),
+        exponent: unsigned(key.expect(INTEGER)?),
+    })
+}
+
+/// Reads the public part of a PKCS#1 private key.
+fn rsa_private_key(der: &[u8]) -> Option<RsaKey> {
+    let mut key = Der(Der(der).expect(SEQUENCE)?);
+    key.expect(INTEGER)?; // version
+    Some(RsaKey {
+        modulus: unsigned(key.expect(INTEGER)?),
+        exponent: unsigned(key.expect(INTEGER)?),
+    })
+}
+
+fn unsigned(mut int: &[u8]) -> &[u8] {
+    while int.len() > 1 && int[0] == 0 {
+        int = &int[1..];
+    }
+    int
+}
+
+/// Describes a name by its common name, if it has one.
+fn common_name(name: &[u8]) -> String {
+    find_common_name(name).unwrap_or_else(|| "<no common name>".to_owned())
+}
+
+fn find_common_name(name: &[u8]) -> Option<String> {
+    let mut rdns = Der(name);
+    while let Some((SET, rdn)) = rdns.read() {
+        let mut attrs = Der(rdn);
+        while let Some(attr) = attrs.expect(SEQUENCE) {
+            let mut attr = Der(attr);
+            if attr.expect(OID) == Some(COMMON_NAME) {
+                if let Some((_, value)) = attr.read() {
+                    return Some(String::from_utf8_lossy(value).into_owned());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Reads DER-encoded values, each as its tag and contents.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&len, mut rest) = rest.split_first()?;
+        let len = if len & 0x80 == 0 {
+            len as usize
+        } else {
+            let n = (len & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0, |l, &b| (l << 8) | b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        self.0 = &rest[len..];
+        Some((tag, &rest[..len]))
+    }
+
+    /// Reads the contents of a value with the given tag.
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read() {
+            Some((t, contents)) if t == tag => Some(contents),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Describes the ClientHello that began a failed server handshake.
+//!
+//! When a handshake fails, knowing what the client offered (its protocol versions, the
+//! server name it requested, its ALPN protocols, and its cipher suites) is usually
+//! enough to explain why. The ClientHello is parsed leniently from the first bytes the
+//! client sent, so that even a truncated hello is described as far as it was received,
+//! and only a bounded summary of it is retained.
+
+use std::{cmp, fmt};
+
+/// The most ALPN protocols and cipher suites that are described.
+const MAX_ALPN: usize = 4;
+const MAX_CIPHERS: usize = 5;
+
+/// Longer server names and ALPN protocols are truncated.
+const MAX_NAME_LEN: usize = 64;
+
+const HANDSHAKE_CONTENT_TYPE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// What a client offered in its ClientHello.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// The protocol versions offered, from the `supported_versions` extension if the
+    /// client sent one and otherwise from the hello's version, e.g. `TLSv1.2`.
+    pub versions: Vec<String>,
+    /// The server name requested with SNI.
+    pub sni: Option<String>,
+    /// The first few ALPN protocols offered.
+    pub alpn: Vec<String>,
+    /// The first few cipher suites offered, most preferred first.
+    pub ciphers: Vec<String>,
+    /// The number of cipher suites offered.
+    pub cipher_count: usize,
+}
+
+impl ClientHello {
+    /// Parses the ClientHello at the start of what a client sent, or returns `None` if
+    /// what was sent does not begin with one.
+    pub fn parse(bytes: &[u8]) -> Option<ClientHello> {
+        let msg = handshake_message(bytes);
+        let mut r = Reader(&msg);
+        if r.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        let _len = r.u24()?;
+        let legacy_version = r.u16()?;
+        r.bytes(32)?;
+        let sid_len = r.u8()? as usize;
+        r.bytes(sid_len)?;
+
+        let mut hello = ClientHello::default();
+        let mut offered_versions = None;
+        let cs_len = r.u16()? as usize;
+        let mut cs = Reader(r.bytes(cs_len).unwrap_or_else(|| r.rest()));
+        while let Some(c) = cs.u16() {
+            if is_grease(c) {
+                continue;
+            }
+            hello.cipher_count += 1;
+            if hello.ciphers.len() < MAX_CIPHERS {
+                hello.ciphers.push(cipher_name(c));
+            }
+        }
+
+        // The extensions are optional, and are described as far as they were received.
+        let comp_len = r.u8().unwrap_or(0) as usize;
+        r.bytes(comp_len);
+        let exts_len = r.u16().unwrap_or(0) as usize;
+        let mut exts = Reader(r.bytes(exts_len).unwrap_or_else(|| r.rest()));
+        while let (Some(typ), Some(len)) = (exts.u16(), exts.u16()) {
+            let mut ext = match exts.bytes(len as usize) {
+                Some(ext) => Reader(ext),
+                None => break,
+            };
+            match typ {
+                EXT_SERVER_NAME => hello.sni = server_name(&mut ext),
+                EXT_ALPN => hello.alpn = alpn(&mut ext),
+                EXT_SUPPORTED_VERSIONS => offered_versions = supported_versions(&mut ext),
+                _ => {}
+            }
+        }
+
+        hello.versions = match offered_versions {
+            Some(vs) => vs.into_iter().map(version_name).collect(),
+            None => vec![version_name(legacy_version)],
+        };
+        Some(hello)
+    }
+}
+
+impl fmt::Display for ClientHello {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "versions=[{}] sni=", self.versions.join(","))?;
+        match self.sni {
+            Some(ref sni) => f.write_str(sni)?,
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            " alpn=[{}] ciphers={}:[{}",
+            self.alpn.join(","),
+            self.cipher_count,
+            self.ciphers.join(",")
+        )?;
+        if self.cipher_count > self.ciphers.len() {
+            f.write_str(",...")?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Reassembles the first handshake message from the handshake records at the start of
+/// `bytes`, as far as it was received.
+fn handshake_message(mut bytes: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::new();
+    while bytes.len() > 5 && bytes[0] == HANDSHAKE_CONTENT_TYPE {
+        let len = ((bytes[3] as usize) << 8) | bytes[4] as usize;
+        let end = cmp::min(5 + len, bytes.len());
+        msg.extend_from_slice(&bytes[5..end]);
+        bytes = &bytes[end..];
+    }
+    msg
+}
+
+fn server_name(ext: &mut Reader) -> Option<String> {
+    let _list_len = ext.u16()?;
+    while let Some(typ) = ext.u8() {
+        let len = ext.u16()? as usize;
+        let name = ext.bytes(len)?;
+        // Only host names (type 0) are defined.
+        if typ == 0 {
+            return Some(printable(name));
+        }
+    }
+    None
+}
+
+fn alpn(ext: &mut Reader) -> Vec<String> {
+    let mut protocols = Vec::new();
+    if ext.u16().is_none() {
+        return protocols;
+    }
+    while let Some(len) = ext.u8() {
+        match ext.bytes(len as usize) {
+            Some(p) if protocols.len() < MAX_ALPN => protocols.push(printable(p)),
+            Some(_) => {}
+            None => break,
+        }
+    }
+    protocols
+}
+
+fn supported_versions(ext: &mut Reader) -> Option<Vec<u16>> {
+    let len = ext.u8()? as usize;
+    let mut vs = Reader(ext.bytes(len).unwrap_or_else(|| ext.rest()));
+    let mut versions = Vec::new();
+    while let Some(v) = vs.u16() {
+        if !is_grease(v) {
+            versions.push(v);
+        }
+    }
+    Some(versions)
+}
+
+/// Whether `v` is one of the reserved values that clients offer to ensure that peers
+/// tolerate unknown values (RFC 8701).
+fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+fn version_name(v: u16) -> String {
+    match v {
+        0x0300 => "SSLv3".into(),
+        0x0301 => "TLSv1.0".into(),
+        0x0302 => "TLSv1.1".into(),
+        0x0303 => "TLSv1.2".into(),
+        0x0304 => "TLSv1.3".into(),
+        v => format!("0x{:04x}", v),
+    }
+}
+
+fn cipher_name(c: u16) -> String {
+    let name = match c {
+        0x1301 => "TLS13_AES_128_GCM_SHA256",
+        0x1302 => "TLS13_AES_256_GCM_SHA384",
+        0x1303 => "TLS13_CHACHA20_POLY1305_SHA256",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA",
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA",
+        0x000a => "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+        0x0005 => "TLS_RSA_WITH_RC4_128_SHA",
+        0x0004 => "TLS_RSA_WITH_RC4_128_MD5",
+        0x00ff => "TLS_EMPTY_RENEGOTIATION_INFO_SCSV",
+        c => return format!("0x{:04x}", c),
+    };
+    name.into()
+}
+
+/// Renders a client-provided name for logging, replacing anything but printable ASCII
+/// and truncating it to `MAX_NAME_LEN`.
+fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take(MAX_NAME_LEN)
+        .map(|&b| if b > b' ' && b <= b'~' { b as char } else { '?' })
+        .collect()
+}
+
+/// Reads big-endian integers and length-delimited fields from a message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (b, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(b)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.0;
+        self.0 = &[];
+        rest
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| (u16::from(b[0]) << 8) | u16::from(b[1]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3).map(|b| {
+            ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize
+        })
+    }
+}
@@ -1,51 +1,250 @@
-use super::{Unbound, sni};
+use super::{Unbound, UnboundTls};
+use super::dispatch_queue;
+use super::mirror;
+use super::probe;
+use super::reuse;
+use super::retry;
+use super::sniff::MisdirectedTls;
+use super::transfer_size::TransferSizes;
+use super::udp;
+#[cfg(feature = "tls")]
+use super::resumption::{self, Resumption};
+#[cfg(feature = "tls")]
+use super::sni;
+#[cfg(feature = "tls")]
+use super::tls_policy::{self, TlsPolicy};
+use super::super::connection::{Buffers, WriteCoalescing};
+use super::super::connection::{integrity, tcp_info};
+use super::super::connector::Cidr;
+use super::super::duration::{Millis, Secs};
+use super::super::fd::FdLimit;
+use super::super::hook::Hooks;
 use super::super::router::Router;
-use rustls;
-use std::cell::RefCell;
+use super::super::schema::Schema;
+use super::super::summary::Signals;
+use super::super::tracing::Tracer;
 use std::collections::HashMap;
 use std::net;
-use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 use tacho;
 
+const DEFAULT_COALESCING_MAX_DELAY_MICROS: u64 = 200;
+const DEFAULT_COALESCING_MAX_BYTES: usize = 16 * 1024;
+const DEFAULT_TCP_INFO_SAMPLE_INTERVAL_SECS: u64 = 30;
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     NoDstName,
+    #[cfg(feature = "tls")]
     Sni(sni::Error),
+    BuiltWithoutTlsSupport,
+    RequireAlpnWithoutProtocols,
+    /// Holds the listener whose `alpnProtocols` is empty.
+    EmptyAlpnProtocols(net::SocketAddr),
+    /// Holds the listener and an ALPN protocol that is empty, longer than 255 bytes, or
+    /// contains whitespace.
+    InvalidAlpnProtocol(net::SocketAddr, String),
+    /// Holds the listener and an ALPN protocol that it lists more than once.
+    DuplicateAlpnProtocol(net::SocketAddr, String),
+    InvalidTicketRotation(Duration),
+    UdpWithTls,
+    InvalidSessionTimeout(Duration),
+    InvalidMaxDatagramBytes(usize),
+    InvalidProbeCidr(String),
+    InvalidProbeIdle(Duration),
+    UdpWithProbeFilter,
+    InvalidDispatchQueueDepth(usize),
+    InvalidMaxQueueDelay(Duration),
+    ByAgeWithoutMaxQueueDelay,
+    MaxQueueDelayWithoutByAge,
+    UdpWithDispatchQueue,
+    InvalidReuseWindow(Duration),
+    InvalidMaxTracked(usize),
+    UdpWithSourcePortReuse,
+    InvalidHandshakeTimeout(Duration),
+    InvalidMaxConcurrentHandshakes(usize),
+    InvalidCoalescingDelay(Duration),
+    InvalidCoalescingMaxBytes(usize),
+    UdpWithWriteCoalescing,
+    InvalidMirrorPath(String),
+    InvalidMirrorSampleRate(f64),
+    InvalidMirrorMaxBufferedBytes(usize),
+    UdpWithMirror,
+    InvalidTcpInfoSampleInterval(Duration),
+    UdpWithTcpInfo,
+    InvalidRetryBufferBytes(usize),
+    InvalidMaxRetries(usize),
+    UdpWithTransparentRetry,
+    /// Holds a `minVersion` that is not a known TLS version.
+    InvalidTlsMinVersion(String),
+    /// Holds a denied cipher suite that is not supported.
+    UnknownTlsCipher(String),
+    TlsPolicyDeniesAllCiphers,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Configures a server that accepts connections and routes them to `dstName`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct ServerConfig {
-    port: u16,
-    ip: Option<net::IpAddr>,
-    dst_name: Option<String>,
-    tls: Option<TlsServerConfig>,
-    connect_timeout_ms: Option<u64>,
-    connection_lifetime_secs: Option<u64>,
-    max_concurrency: Option<usize>,
+    /// The transport on which the server accepts clients. Defaults to `io.l5d.tcp`.
+    pub kind: Option<ServerKind>,
+    /// The port on which the server listens. Port 0 binds an ephemeral port.
+    pub port: u16,
+    /// The IP address on which the server listens. Defaults to localhost.
+    pub ip: Option<net::IpAddr>,
+    /// The name to which accepted connections are routed, e.g. `/svc/web`. Required.
+    pub dst_name: Option<String>,
+    /// When set, clients must complete a TLS handshake.
+    pub tls: Option<TlsServerConfig>,
+    /// Bounds the time spent obtaining an upstream connection (and completing any TLS
+    /// handshake).
+    pub connect_timeout_ms: Option<Millis>,
+    /// Closes connections that have been open this long.
+    pub connection_lifetime_secs: Option<Secs>,
+    /// Closes connections when either peer accepts no written bytes for this long.
+    pub write_timeout_secs: Option<Secs>,
+    /// Limits the number of connections (or UDP sessions) that are served at once.
+    pub max_concurrency: Option<usize>,
+    /// Determines how TLS clients of a plaintext server are handled.
+    pub detect_misdirected_tls: Option<MisdirectedTls>,
+    /// Checks that each stream is proxied without modification.
+    pub integrity_check: Option<IntegrityCheckConfig>,
+    /// Closes UDP sessions that have sent and received no datagrams for this long.
+    pub session_timeout_secs: Option<Secs>,
+    /// UDP datagrams larger than this are dropped.
+    pub max_datagram_bytes: Option<usize>,
+    /// Closes connections that appear to be health checks without dispatching them.
+    pub probe_filter: Option<ProbeFilterConfig>,
+    /// Bounds the connections waiting for upstream connections.
+    pub dispatch_queue: Option<DispatchQueueConfig>,
+    /// Counts clients that reconnect from a source port soon after closing it.
+    pub source_port_reuse: Option<SourcePortReuseConfig>,
+    /// Holds small writes briefly so that they are written together. Disabled by
+    /// default, since it adds latency.
+    pub write_coalescing: Option<WriteCoalescingConfig>,
+    /// Copies a sample of the bytes clients send to a shadow destination.
+    pub mirror: Option<MirrorConfig>,
+    /// Samples the kernel's statistics (e.g. round-trip times) for each stream's
+    /// sockets. Only supported on Linux.
+    pub tcp_info: Option<TcpInfoConfig>,
+    /// Retries streams whose endpoints reset them before any bytes are forwarded.
+    pub transparent_retry: Option<TransparentRetryConfig>,
     // TODO idle time
 }
 
+/// The transport on which a server accepts clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerKind {
+    /// Proxies TCP connections.
+    #[serde(rename = "io.l5d.tcp")]
+    Tcp,
+    /// Load balances UDP datagrams. Each client address is a session, assigned to an
+    /// endpoint when its first datagram is received.
+    #[serde(rename = "io.l5d.udp")]
+    Udp,
+}
+
+/// Checks that each stream is proxied without modification, e.g. while canarying a new
+/// release. Checks add per-byte work, so they are disabled by default.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct IntegrityCheckConfig {
+    /// Defaults to true, so that checks may be disabled without removing their
+    /// configuration.
+    pub enabled: Option<bool>,
+    /// Only `crc32` is supported.
+    pub algorithm: Option<IntegrityAlgorithm>,
+    /// Corrupts each client's first byte so that mismatches may be tested.
+    #[doc(hidden)]
+    pub corrupt_for_testing: Option<bool>,
+}
+
+/// The checksum used by integrity checks.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegrityAlgorithm {
+    /// CRC-32, as used by IEEE 802.3.
+    Crc32,
+}
+
+impl IntegrityCheckConfig {
+    fn mk_policy(&self) -> Option<integrity::Policy> {
+        if !self.enabled.unwrap_or(true) {
+            return None;
+        }
+        Some(integrity::Policy {
+            corrupt_for_testing: self.corrupt_for_testing.unwrap_or(false),
+        })
+    }
+}
+
 impl ServerConfig {
+    /// Describes the fields of a server's configuration.
+    pub fn schema() -> Schema {
+        let identity = || {
+            let source = Schema::Tagged(
+                "kind",
+                vec![("agent", Schema::of::<AgentIdentityConfig>(vec![]))],
+            );
+            Schema::of::<TlsServerIdentityConfig>(vec![("identitySource", source)])
+        };
+        let tls = Schema::of::<TlsServerConfig>(vec![
+            ("defaultIdentity", identity()),
+            ("identities", Schema::map(identity())),
+            ("sessionResumption", Schema::of::<TlsSessionResumptionConfig>(vec![])),
+            ("tlsPolicy", Schema::of::<TlsPolicyConfig>(vec![])),
+        ]);
+        Schema::of::<ServerConfig>(vec![
+            ("tls", tls),
+            ("integrityCheck", Schema::of::<IntegrityCheckConfig>(vec![])),
+            ("probeFilter", Schema::of::<ProbeFilterConfig>(vec![])),
+            ("dispatchQueue", Schema::of::<DispatchQueueConfig>(vec![])),
+            ("sourcePortReuse", Schema::of::<SourcePortReuseConfig>(vec![])),
+            ("writeCoalescing", Schema::of::<WriteCoalescingConfig>(vec![])),
+            ("mirror", Schema::of::<MirrorConfig>(vec![])),
+            ("tcpInfo", Schema::of::<TcpInfoConfig>(vec![])),
+            ("transparentRetry", Schema::of::<TransparentRetryConfig>(vec![])),
+        ])
+    }
+
+    /// Validates this configuration to produce a server that has not yet been bound.
     pub fn mk_server(
         &self,
         router: Router,
-        buf: Rc<RefCell<Vec<u8>>>,
+        bufs: Buffers,
+        fd_limit: &FdLimit,
+        tracer: Option<Tracer>,
+        hooks: Option<Hooks>,
         metrics: &tacho::Scope,
+        transfer_sizes: Option<TransferSizes>,
+        handoffs: Option<Handoffs>,
+        signals: Arc<Signals>,
     ) -> Result<Unbound> {
         match *self {
             ServerConfig {
+                ref kind,
                 port,
                 ref ip,
                 ref dst_name,
                 ref tls,
                 ref connect_timeout_ms,
                 ref connection_lifetime_secs,
+                ref write_timeout_secs,
                 ref max_concurrency,
+                ref detect_misdirected_tls,
+                ref integrity_check,
+                ref session_timeout_secs,
+                ref max_datagram_bytes,
+                ref probe_filter,
+                ref dispatch_queue,
+                ref source_port_reuse,
+                ref write_coalescing,
+                ref mirror,
+                tcp_info: ref tcp_info_config,
+                ref transparent_retry,
             } => {
                 if dst_name.is_none() {
                     return Err(Error::NoDstName);
@@ -55,53 +254,703 @@ impl ServerConfig {
                 let addr = net::SocketAddr::new(ip, port);
                 let tls = match tls.as_ref() {
                     None => None,
-                    Some(&TlsServerConfig {
-                             ref alpn_protocols,
-                             ref default_identity,
-                             ref identities,
-                         }) => {
-                        let mut tls = rustls::ServerConfig::new();
-                        if let Some(protos) = alpn_protocols.as_ref() {
-                            tls.set_protocols(protos);
+                    Some(tls) => Some(tls.mk_tls(&addr)?),
+                };
+                let timeout = connect_timeout_ms.map(Duration::from);
+                let lifetime = connection_lifetime_secs.map(Duration::from);
+                let write_timeout = write_timeout_secs.map(Duration::from);
+                let max_concurrency = max_concurrency.unwrap_or(super::DEFAULT_MAX_CONCURRENCY);
+                let integrity = integrity_check.as_ref().and_then(|i| i.mk_policy());
+                let probe_filter = match probe_filter.as_ref() {
+                    None => None,
+                    Some(p) => Some(p.mk_policy()?),
+                };
+                let dispatch_queue = match dispatch_queue.as_ref() {
+                    None => None,
+                    Some(q) => Some(q.mk_policy()?),
+                };
+                let source_port_reuse = match source_port_reuse.as_ref() {
+                    None => None,
+                    Some(r) => Some(r.mk_policy()?),
+                };
+                let write_coalescing = match write_coalescing.as_ref() {
+                    None => None,
+                    Some(c) => Some(c.mk_policy()?),
+                };
+                let mirror = match mirror.as_ref() {
+                    None => None,
+                    Some(m) => Some(m.mk_policy()?),
+                };
+                let tcp_info = match tcp_info_config.as_ref() {
+                    None => None,
+                    Some(t) => t.mk_policy()?,
+                };
+                let transparent_retry = match transparent_retry.as_ref() {
+                    None => None,
+                    Some(r) => Some(r.mk_policy()?),
+                };
+                let udp = match kind.unwrap_or(ServerKind::Tcp) {
+                    ServerKind::Tcp => None,
+                    ServerKind::Udp => {
+                        if tls.is_some() {
+                            return Err(Error::UdpWithTls);
+                        }
+                        if probe_filter.is_some() {
+                            return Err(Error::UdpWithProbeFilter);
+                        }
+                        if dispatch_queue.is_some() {
+                            return Err(Error::UdpWithDispatchQueue);
+                        }
+                        if source_port_reuse.is_some() {
+                            return Err(Error::UdpWithSourcePortReuse);
+                        }
+                        if write_coalescing.is_some() {
+                            return Err(Error::UdpWithWriteCoalescing);
                         }
-                        let sni = sni::new(identities, default_identity).map_err(Error::Sni)?;
-                        tls.cert_resolver = Arc::new(sni);
-                        Some(super::UnboundTls { config: Arc::new(tls) })
+                        if mirror.is_some() {
+                            return Err(Error::UdpWithMirror);
+                        }
+                        // Rejected even where sampling is unsupported and ignored.
+                        if tcp_info_config.is_some() {
+                            return Err(Error::UdpWithTcpInfo);
+                        }
+                        if transparent_retry.is_some() {
+                            return Err(Error::UdpWithTransparentRetry);
+                        }
+                        Some(mk_udp_policy(session_timeout_secs, max_datagram_bytes)?)
                     }
                 };
-                let timeout = connect_timeout_ms.map(Duration::from_millis);
-                let lifetime = connection_lifetime_secs.map(Duration::from_secs);
-                let max_concurrency = max_concurrency.unwrap_or(super::DEFAULT_MAX_CONCURRENCY);
+                let policy = ServerPolicy {
+                    connect_timeout: timeout,
+                    connection_lifetime: lifetime,
+                    write_timeout,
+                    max_concurrency,
+                    detect_misdirected_tls: *detect_misdirected_tls,
+                    integrity,
+                    udp,
+                    probe_filter,
+                    dispatch_queue,
+                    source_port_reuse,
+                    write_coalescing,
+                    mirror,
+                    tcp_info,
+                    transparent_retry,
+                };
                 Ok(super::unbound(
                     addr,
                     dst_name.into(),
                     router,
-                    buf,
+                    bufs,
                     tls,
-                    timeout,
-                    lifetime,
-                    max_concurrency,
+                    policy,
+                    fd_limit.clone(),
+                    tracer,
+                    hooks,
                     metrics,
+                    transfer_sizes,
+                    handoffs,
+                    signals,
                 ))
             }
         }
     }
 }
 
+/// A server's options, as validated from its configuration.
+#[derive(Clone)]
+pub struct ServerPolicy {
+    pub connect_timeout: Option<Duration>,
+    pub connection_lifetime: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub max_concurrency: usize,
+    pub detect_misdirected_tls: Option<MisdirectedTls>,
+    pub integrity: Option<integrity::Policy>,
+    /// Set when the server load balances UDP datagrams rather than TCP connections.
+    pub udp: Option<udp::Policy>,
+    /// Set when connections from some sources may be health checks.
+    pub probe_filter: Option<probe::Policy>,
+    /// Set when the connections waiting to be dispatched are bounded.
+    pub dispatch_queue: Option<dispatch_queue::Policy>,
+    /// Set when clients that quickly reuse source ports are counted.
+    pub source_port_reuse: Option<reuse::Policy>,
+    /// Set when small writes are held briefly so that they are written together.
+    pub write_coalescing: Option<WriteCoalescing>,
+    /// Set when a sample of streams is copied to a shadow destination.
+    pub mirror: Option<mirror::Policy>,
+    /// Set when the kernel's statistics for each stream's sockets are sampled.
+    pub tcp_info: Option<tcp_info::Policy>,
+    /// Set when streams whose endpoints fail before any bytes are forwarded are retried.
+    pub transparent_retry: Option<retry::Policy>,
+}
+
+/// Identifies connections that may be health checks, e.g. from a cloud load balancer.
+///
+/// Connections from `fromCidrs` that send no bytes within `idleMs` are closed without
+/// being dispatched, and are counted as `probe_connections`. Connections from these
+/// ranges that do send data are proxied normally, though they may not use protocols in
+/// which the server speaks first.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ProbeFilterConfig {
+    /// Source address ranges, e.g. `169.254.0.0/16`.
+    pub from_cidrs: Vec<String>,
+    /// How long a connection may be idle before it is considered a probe (100ms by
+    /// default).
+    pub idle_ms: Option<Millis>,
+}
+
+impl ProbeFilterConfig {
+    fn mk_policy(&self) -> Result<probe::Policy> {
+        let mut from = Vec::with_capacity(self.from_cidrs.len());
+        for cidr in &self.from_cidrs {
+            from.push(cidr.parse::<Cidr>().map_err(|_| Error::InvalidProbeCidr(cidr.clone()))?);
+        }
+        let idle = self.idle_ms.map(Duration::from).unwrap_or_else(|| {
+            Duration::from_millis(probe::DEFAULT_IDLE_MS)
+        });
+        if idle == Duration::from_secs(0) {
+            return Err(Error::InvalidProbeIdle(idle));
+        }
+        Ok(probe::Policy { from, idle })
+    }
+}
+
+/// Bounds the connections that have been accepted but are still waiting for upstream
+/// connections.
+///
+/// When `maxDepth` connections are waiting, a connection is closed according to
+/// `shedPolicy`. Queued connections are reported as `dispatch_queue_depth`, the age of
+/// the oldest as `dispatch_queue_max_age_ms`, and closed connections are counted as
+/// `dispatch_sheds`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct DispatchQueueConfig {
+    /// The number of connections that may wait at once.
+    pub max_depth: usize,
+    /// Determines which connection is closed when the queue is full (`newest` by
+    /// default).
+    pub shed_policy: Option<ShedPolicy>,
+    /// With the `byAge` policy, how long a connection may wait before it is closed in
+    /// favor of new arrivals.
+    pub max_queue_delay_ms: Option<Millis>,
+}
+
+/// Determines which connection is closed when a dispatch queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ShedPolicy {
+    /// Closes the arriving connection.
+    Newest,
+    /// Closes the connection that has waited longest.
+    Oldest,
+    /// Closes the connection that has waited longest if it has waited longer than
+    /// `maxQueueDelayMs`, and otherwise the arriving connection.
+    ByAge,
+}
+
+impl DispatchQueueConfig {
+    fn mk_policy(&self) -> Result<dispatch_queue::Policy> {
+        if self.max_depth == 0 {
+            return Err(Error::InvalidDispatchQueueDepth(self.max_depth));
+        }
+        let max_delay = self.max_queue_delay_ms.map(Duration::from);
+        if max_delay == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidMaxQueueDelay(Duration::from_secs(0)));
+        }
+        let shed = match (self.shed_policy.unwrap_or(ShedPolicy::Newest), max_delay) {
+            (ShedPolicy::ByAge, Some(d)) => dispatch_queue::ShedPolicy::ByAge(d),
+            (ShedPolicy::ByAge, None) => return Err(Error::ByAgeWithoutMaxQueueDelay),
+            (_, Some(_)) => return Err(Error::MaxQueueDelayWithoutByAge),
+            (ShedPolicy::Newest, None) => dispatch_queue::ShedPolicy::Newest,
+            (ShedPolicy::Oldest, None) => dispatch_queue::ShedPolicy::Oldest,
+        };
+        Ok(dispatch_queue::Policy {
+            max_depth: self.max_depth,
+            shed,
+        })
+    }
+}
+
+/// Counts clients that reconnect from the same address and port within
+/// `reuseWindowMs` of closing a connection from it, as `source_port_reuses`.
+///
+/// At most `maxTracked` recently-closed sources are remembered; those that closed least
+/// recently are forgotten first, and are counted as `source_port_evictions`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SourcePortReuseConfig {
+    /// How soon after a close a reconnection is counted (1000ms by default).
+    pub reuse_window_ms: Option<Millis>,
+    /// The number of recently-closed sources remembered (10000 by default).
+    pub max_tracked: Option<usize>,
+}
+
+impl SourcePortReuseConfig {
+    fn mk_policy(&self) -> Result<reuse::Policy> {
+        let window = self.reuse_window_ms.map(Duration::from).unwrap_or_else(|| {
+            Duration::from_millis(reuse::DEFAULT_REUSE_WINDOW_MS)
+        });
+        if window == Duration::from_secs(0) {
+            return Err(Error::InvalidReuseWindow(window));
+        }
+        let max_tracked = self.max_tracked.unwrap_or(reuse::DEFAULT_MAX_TRACKED);
+        if max_tracked == 0 {
+            return Err(Error::InvalidMaxTracked(max_tracked));
+        }
+        Ok(reuse::Policy {
+            window,
+            max_tracked,
+        })
+    }
+}
+
+/// Holds the data read from each peer, rather than writing it immediately, for up to
+/// `maxDelayMicros` or until `maxBytes` have been read, so that small writes reach the
+/// other peer together. This trades latency for fewer writes (and, with `TCP_NODELAY`,
+/// fewer packets), so it suits chatty protocols that send many small frames.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct WriteCoalescingConfig {
+    /// How long data may be held (200us by default). Rounded up to the reactor's timer
+    /// resolution.
+    pub max_delay_micros: Option<u64>,
+    /// Held data is written as soon as this much has been read (16KB by default).
+    pub max_bytes: Option<usize>,
+}
+
+impl WriteCoalescingConfig {
+    fn mk_policy(&self) -> Result<WriteCoalescing> {
+        let micros = self.max_delay_micros.unwrap_or(DEFAULT_COALESCING_MAX_DELAY_MICROS);
+        let max_delay = Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000);
+        if max_delay == Duration::from_secs(0) {
+            return Err(Error::InvalidCoalescingDelay(max_delay));
+        }
+        let max_bytes = self.max_bytes.unwrap_or(DEFAULT_COALESCING_MAX_BYTES);
+        if max_bytes == 0 {
+            return Err(Error::InvalidCoalescingMaxBytes(max_bytes));
+        }
+        Ok(WriteCoalescing {
+            max_delay,
+            max_bytes,
+        })
+    }
+}
+
+/// Copies the bytes that a sample of clients send to an endpoint of `path`, e.g. to
+/// validate a migration with production traffic.
+///
+/// Mirrors are best-effort: the bytes the shadow endpoint sends are discarded, and a
+/// mirror that fails or falls more than `maxBufferedBytes` behind is closed without
+/// affecting its stream. Mirrors are counted as `mirror_sampled`, `mirror_completed`,
+/// and `mirror_dropped`, rather than in the server's stream metrics.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct MirrorConfig {
+    /// The name to which mirrors are routed, e.g. `/svc/shadow`.
+    pub path: String,
+    /// The fraction of streams that are mirrored (all, by default).
+    pub sample_rate: Option<f64>,
+    /// How far a mirror may fall behind its stream before it is closed (64KB by
+    /// default).
+    pub max_buffered_bytes: Option<usize>,
+}
+
+impl MirrorConfig {
+    fn mk_policy(&self) -> Result<mirror::Policy> {
+        if !self.path.starts_with('/') {
+            return Err(Error::InvalidMirrorPath(self.path.clone()));
+        }
+        let sample_rate = self.sample_rate.unwrap_or(mirror::DEFAULT_SAMPLE_RATE);
+        if !(0.0 <= sample_rate && sample_rate <= 1.0) {
+            return Err(Error::InvalidMirrorSampleRate(sample_rate));
+        }
+        let max_buffered_bytes = self.max_buffered_bytes.unwrap_or(
+            mirror::DEFAULT_MAX_BUFFERED_BYTES,
+        );
+        if max_buffered_bytes == 0 {
+            return Err(Error::InvalidMirrorMaxBufferedBytes(max_buffered_bytes));
+        }
+        Ok(mirror::Policy {
+            dst_name: self.path.clone().into(),
+            sample_rate,
+            max_buffered_bytes,
+        })
+    }
+}
+
+/// Samples the kernel's `TCP_INFO` for each stream's client and endpoint sockets every
+/// `sampleIntervalSecs`, and once more as the stream closes. Round-trip times and
+/// delivery rates are recorded as `tcp_rtt_us`, `tcp_rtt_var_us`, and
+/// `tcp_delivery_rate_bps` at each sample, and retransmitted segments as
+/// `tcp_retransmits` as each stream closes, labeled by `peer` (`client` or
+/// `endpoint`). The final samples are also included in the summaries of closed
+/// connections.
+///
+/// Sampling is only supported on Linux; elsewhere, this configuration is ignored with a
+/// warning.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TcpInfoConfig {
+    /// How often each stream is sampled (30s by default).
+    pub sample_interval_secs: Option<Secs>,
+}
+
+impl TcpInfoConfig {
+    fn mk_policy(&self) -> Result<Option<tcp_info::Policy>> {
+        let interval = self.sample_interval_secs.map(Duration::from).unwrap_or_else(|| {
+            Duration::from_secs(DEFAULT_TCP_INFO_SAMPLE_INTERVAL_SECS)
+        });
+        if interval == Duration::from_secs(0) {
+            return Err(Error::InvalidTcpInfoSampleInterval(interval));
+        }
+        if !tcp_info::is_supported() {
+            tcp_info::warn_unsupported();
+            return Ok(None);
+        }
+        Ok(Some(tcp_info::Policy { interval }))
+    }
+}
+
+/// Retries a stream against another endpoint when its endpoint resets it before any
+/// bytes have been written to either peer, e.g. because the endpoint accepted the
+/// connection while shutting down.
+///
+/// The bytes the client sends are retained, up to `retryBufferBytes`, until the first
+/// bytes are written, and are replayed to the next endpoint. Streams whose clients send
+/// more than `retryBufferBytes` before then are not retried. Each retry is re-dispatched
+/// within `connectTimeoutMs`, and is counted as `transparent_retries`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TransparentRetryConfig {
+    /// How many of the bytes a client sends are retained for a retry (16KB by default).
+    pub retry_buffer_bytes: Option<usize>,
+    /// How many times each stream may be retried (2 by default).
+    pub max_retries: Option<usize>,
+}
+
+impl TransparentRetryConfig {
+    fn mk_policy(&self) -> Result<retry::Policy> {
+        let retry_buffer_bytes = self.retry_buffer_bytes.unwrap_or(
+            retry::DEFAULT_RETRY_BUFFER_BYTES,
+        );
+        if retry_buffer_bytes == 0 {
+            return Err(Error::InvalidRetryBufferBytes(retry_buffer_bytes));
+        }
+        let max_retries = self.max_retries.unwrap_or(retry::DEFAULT_MAX_RETRIES);
+        if max_retries == 0 {
+            return Err(Error::InvalidMaxRetries(max_retries));
+        }
+        Ok(retry::Policy {
+            retry_buffer_bytes,
+            max_retries,
+        })
+    }
+}
+
+fn mk_udp_policy(
+    session_timeout_secs: &Option<Secs>,
+    max_datagram_bytes: &Option<usize>,
+) -> Result<udp::Policy> {
+    let session_timeout = session_timeout_secs.map(Duration::from).unwrap_or_else(|| {
+        Duration::from_secs(udp::DEFAULT_SESSION_TIMEOUT_SECS)
+    });
+    if session_timeout == Duration::from_secs(0) {
+        return Err(Error::InvalidSessionTimeout(session_timeout));
+    }
+    let max_datagram_bytes = max_datagram_bytes.unwrap_or(udp::MAX_DATAGRAM_BYTES);
+    if max_datagram_bytes == 0 || max_datagram_bytes > udp::MAX_DATAGRAM_BYTES {
+        return Err(Error::InvalidMaxDatagramBytes(max_datagram_bytes));
+    }
+    Ok(udp::Policy {
+        session_timeout,
+        max_datagram_bytes,
+    })
+}
+
 // TODO support cypher suites
 // TODO support client validation
-// TODO supoprt persistence?
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Configures the TLS handshakes of a server.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TlsServerConfig {
+    /// The protocols that may be negotiated via ALPN, in order of preference. The order
+    /// is preserved, so the first protocol that the client also offers is negotiated.
     pub alpn_protocols: Option<Vec<String>>,
+    /// When set, protocols that are not registered with IANA are used without warning.
+    pub allow_unknown_alpn: Option<bool>,
+    /// When set, handshakes in which the client offers none of `alpn_protocols` are
+    /// refused.
+    pub require_alpn: Option<bool>,
+    /// The identity presented to clients that request no server name, or a name with
+    /// no identity of its own.
     pub default_identity: Option<TlsServerIdentityConfig>,
+    /// The identity presented for each server name requested via SNI.
     pub identities: Option<HashMap<String, TlsServerIdentityConfig>>,
+    /// Controls how clients may resume TLS sessions.
+    pub session_resumption: Option<TlsSessionResumptionConfig>,
+    /// Bounds each handshake, from when its connection is accepted. Defaults to the
+    /// server's `connectTimeoutMs`.
+    pub handshake_timeout_ms: Option<Millis>,
+    /// When set, connections are not accepted while this many handshakes are in flight.
+    pub max_concurrent_handshakes: Option<usize>,
+    /// Refuses clients whose negotiated parameters are not allowed.
+    pub tls_policy: Option<TlsPolicyConfig>,
+}
+
+impl TlsServerConfig {
+    /// Determines whether any of the server's identities is provided by an agent.
+    pub fn has_agent_identities(&self) -> bool {
+        let identities = self.identities.iter().flat_map(|ids| ids.values());
+        self.default_identity.iter().chain(identities).any(|id| match id.identity_source {
+            Some(IdentitySourceConfig::Agent(_)) => true,
+            _ => false,
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    fn mk_tls(&self, addr: &net::SocketAddr) -> Result<UnboundTls> {
+        use rustls;
+        use std::sync::Arc;
+
+        let alpn_protocols = match self.alpn_protocols {
+            None => vec![],
+            Some(ref protocols) => {
+                let allow_unknown = self.allow_unknown_alpn.unwrap_or(false);
+                mk_alpn_protocols(addr, protocols, allow_unknown)?
+            }
+        };
+        let require_alpn = self.require_alpn.unwrap_or(false);
+        if require_alpn && alpn_protocols.is_empty() {
+            return Err(Error::RequireAlpnWithoutProtocols);
+        }
+
+        let resumption = match self.session_resumption {
+            None => Resumption::default(),
+            Some(ref r) => r.mk_resumption()?,
+        };
+
+        let handshake_timeout = self.handshake_timeout_ms.map(Duration::from);
+        if let Some(t) = handshake_timeout {
+            if t == Duration::from_secs(0) {
+                return Err(Error::InvalidHandshakeTimeout(t));
+            }
+        }
+        if let Some(0) = self.max_concurrent_handshakes {
+            return Err(Error::InvalidMaxConcurrentHandshakes(0));
+        }
+
+        let policy = match self.tls_policy {
+            None => TlsPolicy::default(),
+            Some(ref p) => p.mk_policy()?,
+        };
+
+        let sni = sni::new(&self.identities, &self.default_identity)
+            .map_err(Error::Sni)?;
+        Ok(UnboundTls {
+            agents: sni.agents().to_vec(),
+            cert_resolver: Arc::new(sni),
+            alpn_protocols,
+            require_alpn,
+            resumption,
+            handshake_timeout,
+            max_concurrent_handshakes: self.max_concurrent_handshakes,
+            policy,
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn mk_tls(&self, _addr: &net::SocketAddr) -> Result<UnboundTls> {
+        Err(Error::BuiltWithoutTlsSupport)
+    }
+}
+
+/// Protocol IDs registered with IANA, against which configured protocols are checked for
+/// typos.
+#[cfg(feature = "tls")]
+const KNOWN_ALPN_PROTOCOLS: &'static [&'static str] = &[
+    "http/0.9",
+    "http/1.0",
+    "http/1.1",
+    "spdy/1",
+    "spdy/2",
+    "spdy/3",
+    "stun.turn",
+    "stun.nat-discovery",
+    "h2",
+    "h2c",
+    "h3",
+    "webrtc",
+    "c-webrtc",
+    "ftp",
+    "imap",
+    "pop3",
+    "managesieve",
+    "coap",
+    "xmpp-client",
+    "xmpp-server",
+    "acme-tls/1",
+    "mqtt",
+    "dot",
+    "doq",
+    "ntske/1",
+    "sunrpc",
+    "smb",
+    "irc",
+    "nntp",
+    "nnsp",
+];
+
+/// The longest protocol ID that may be encoded in a ClientHello.
+#[cfg(feature = "tls")]
+const MAX_ALPN_PROTOCOL_BYTES: usize = 255;
+
+/// Validates the ALPN protocols of the listener on `addr`, preserving their order.
+///
+/// Protocols that are not registered with IANA are logged, since a misspelled protocol
+/// is never negotiated, unless `allow_unknown` is set.
+#[cfg(feature = "tls")]
+fn mk_alpn_protocols(
+    addr: &net::SocketAddr,
+    protocols: &[String],
+    allow_unknown: bool,
+) -> Result<Vec<String>> {
+    if protocols.is_empty() {
+        return Err(Error::EmptyAlpnProtocols(*addr));
+    }
+    for (i, p) in protocols.iter().enumerate() {
+        if p.is_empty() || p.len() > MAX_ALPN_PROTOCOL_BYTES ||
+            p.chars().any(char::is_whitespace)
+        {
+            return Err(Error::InvalidAlpnProtocol(*addr, p.clone()));
+        }
+        if protocols[..i].contains(p) {
+            return Err(Error::DuplicateAlpnProtocol(*addr, p.clone()));
+        }
+        if !allow_unknown && !KNOWN_ALPN_PROTOCOLS.contains(&p.as_str()) {
+            warn!(
+                "{}: ALPN protocol {:?} is not registered with IANA (set allowUnknownAlpn \
+                 to silence this warning)",
+                addr,
+                p
+            );
+        }
+    }
+    Ok(protocols.to_vec())
+}
+
+/// Controls how clients may resume TLS sessions.
+///
+/// Tickets are disabled unless `tickets` is set. Setting `sessionCacheSize` to 0 stops
+/// session IDs from being cached, so that resumption may be disabled entirely.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TlsSessionResumptionConfig {
+    /// Enables session tickets.
+    pub tickets: Option<bool>,
+    /// How often the key that encrypts tickets is replaced. Tickets issued with the
+    /// previous key remain valid until the next rotation.
+    pub ticket_rotation_secs: Option<Secs>,
+    /// The number of session IDs that are cached.
+    pub session_cache_size: Option<usize>,
+}
+
+impl TlsSessionResumptionConfig {
+    #[cfg(feature = "tls")]
+    fn mk_resumption(&self) -> Result<Resumption> {
+        let ticket_rotation = self.ticket_rotation_secs.map(Duration::from).unwrap_or_else(
+            || Duration::from_secs(resumption::DEFAULT_TICKET_ROTATION_SECS),
+        );
+        if ticket_rotation == Duration::from_secs(0) {
+            return Err(Error::InvalidTicketRotation(ticket_rotation));
+        }
+        Ok(Resumption {
+            tickets: self.tickets.unwrap_or(false),
+            ticket_rotation,
+            session_cache_size: self.session_cache_size.unwrap_or(
+                resumption::DEFAULT_SESSION_CACHE_SIZE,
+            ),
+        })
+    }
+}
+
+/// Limits the parameters that a server's clients may negotiate. Connections that
+/// violate the policy are closed once their handshakes complete, and counted as
+/// `refused` with the cause `tls_policy`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TlsPolicyConfig {
+    /// The oldest protocol version that may be negotiated: `1.0`, `1.1`, `1.2`, or
+    /// `1.3`.
+    pub min_version: Option<String>,
+    /// Cipher suites that may not be negotiated, named as by IANA, e.g.
+    /// `TLS_RSA_WITH_AES_128_GCM_SHA256`.
+    pub deny_ciphers: Option<Vec<String>>,
+}
+
+impl TlsPolicyConfig {
+    /// Fails if `minVersion` is not known, or if `denyCiphers` names a cipher suite
+    /// that is not supported (which is likely a typo) or denies every supported suite.
+    #[cfg(feature = "tls")]
+    fn mk_policy(&self) -> Result<TlsPolicy> {
+        if let Some(ref v) = self.min_version {
+            if !tls_policy::VERSIONS.contains(&v.as_str()) {
+                return Err(Error::InvalidTlsMinVersion(v.clone()));
+            }
+        }
+        let deny_ciphers = self.deny_ciphers.clone().unwrap_or_else(Vec::new);
+        let supported = tls_policy::cipher_suites();
+        for c in &deny_ciphers {
+            if !supported.contains(c) {
+                return Err(Error::UnknownTlsCipher(c.clone()));
+            }
+        }
+        if supported.iter().all(|s| deny_ciphers.contains(s)) {
+            return Err(Error::TlsPolicyDeniesAllCiphers);
+        }
+        Ok(TlsPolicy::new(
+            self.min_version.as_ref().map(|v| v.as_str()),
+            deny_ciphers,
+        ))
+    }
 }
 
+/// A certificate chain and its private key, read from PEM files or provided by an
+/// `identitySource`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TlsServerIdentityConfig {
+    /// Paths to the certificates of the chain, beginning with the server's.
+    #[serde(default)]
     pub certs: Vec<String>,
-    pub private_key: String,
+    /// The path to the private key.
+    pub private_key: Option<String>,
+    /// Provides the identity instead of `certs` and `privateKey`.
+    pub identity_source: Option<IdentitySourceConfig>,
+    /// When set, certificates that do not chain from the certificate matching the
+    /// private key are presented after the chain instead of failing to load. The chain
+    /// is otherwise validated and, if necessary, reordered when it is loaded.
+    pub allow_incomplete_chain: Option<bool>,
+}
+
+/// Provides a server identity other than from files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "kind")]
+pub enum IdentitySourceConfig {
+    /// Fetches the identity, and watches for its replacements, from a local agent.
+    #[serde(rename = "agent")]
+    Agent(AgentIdentityConfig),
+}
+
+/// Fetches a server identity from a local agent over a unix socket.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AgentIdentityConfig {
+    /// The path of the agent's unix socket.
+    pub socket_path: String,
+    /// Identifies, to the agent, the identity being requested.
+    pub audience: Option<String>,
+    /// When set, the server starts before the agent has provided its identity, refusing
+    /// handshakes until it has. Otherwise, the server fails to start if the agent cannot
+    /// provide its identity.
+    pub start_without_identity: Option<bool>,
 }
@@ -0,0 +1,237 @@
+//! Bounds the connections waiting to be dispatched to an upstream endpoint.
+//!
+//! A connection is queued from the time it is accepted until its upstream connection is
+//! established (or fails). When the queue is full, a connection is shed according to
+//! the server's `shedPolicy`:
+//!
+//! - `newest` closes the arriving connection;
+//! - `oldest` closes the connection that has been queued longest;
+//! - `byAge` closes the connection that has been queued longest if it has been queued
+//!   for more than `maxQueueDelayMs`, since its client has most likely given up on it,
+//!   and otherwise closes the arriving connection.
+
+use futures::{Future, Poll};
+use futures::task::{self, Task};
+use std::{error, fmt, io};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tacho;
+
+/// Determines which connection is shed when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShedPolicy {
+    Newest,
+    Oldest,
+    /// Carries the delay beyond which a queued connection is shed before new arrivals.
+    ByAge(Duration),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub max_depth: usize,
+    pub shed: ShedPolicy,
+}
+
+impl Policy {
+    /// Reports the queue's depth as `dispatch_queue_depth`, the age of its oldest
+    /// connection as `dispatch_queue_max_age_ms`, and counts shed connections as
+    /// `dispatch_sheds`, labeled by the `cause` that selected them (`newest`, `oldest`,
+    /// or `expired`).
+    pub fn bind(self, metrics: &tacho::Scope) -> DispatchQueue {
+        let sheds = |cause: &'static str| {
+            metrics.clone().labeled("cause", cause).counter("dispatch_sheds")
+        };
+        DispatchQueue(Rc::new(RefCell::new(Inner {
+            policy: self,
+            next_id: 0,
+            entries: VecDeque::with_capacity(self.max_depth),
+            depth: metrics.gauge("dispatch_queue_depth"),
+            max_age_ms: metrics.gauge("dispatch_queue_max_age_ms"),
+            shed_newest: sheds("newest"),
+            shed_oldest: sheds("oldest"),
+            shed_expired: sheds("expired"),
+        })))
+    }
+}
+
+/// The connections accepted by a server that are waiting for upstream connections.
+#[derive(Clone)]
+pub struct DispatchQueue(Rc<RefCell<Inner>>);
+
+struct Inner {
+    policy: Policy,
+    next_id: u64,
+    /// Queued connections, oldest first.
+    entries: VecDeque<Entry>,
+    depth: tacho::Gauge,
+    max_age_ms: tacho::Gauge,
+    shed_newest: tacho::Counter,
+    shed_oldest: tacho::Counter,
+    shed_expired: tacho::Counter,
+}
+
+struct Entry {
+    id: u64,
+    enqueued: Instant,
+    slot: Slot,
+}
+
+/// Marks a queued connection as shed, notifying the task dispatching it.
+#[derive(Clone, Default)]
+struct Slot(Rc<RefCell<SlotState>>);
+
+#[derive(Default)]
+struct SlotState {
+    shed: bool,
+    task: Option<Task>,
+}
+
+impl Slot {
+    fn shed(&self) {
+        let mut state = self.0.borrow_mut();
+        state.shed = true;
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Determines whether the connection has been shed, and if not, arranges for the
+    /// current task to be notified when it is.
+    fn poll_shed(&self) -> bool {
+        let mut state = self.0.borrow_mut();
+        if !state.shed {
+            state.task = Some(task::current());
+        }
+        state.shed
+    }
+}
+
+impl DispatchQueue {
+    /// Queues a connection until `dispatch` completes.
+    ///
+    /// If the queue is full, either this connection or one that has been queued longer
+    /// is shed. A shed connection's dispatch fails with a `Shed` error.
+    pub fn dispatch<F>(&self, dispatch: F) -> Dispatch<F>
+    where
+        F: Future<Error = io::Error>,
+    {
+        let slot = Slot::default();
+        let mut inner = self.0.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let now = Instant::now();
+
+        if inner.entries.len() >= inner.policy.max_depth {
+            let oldest_age = inner.entries.front().map(|e| now - e.enqueued);
+            let victim = match (inner.policy.shed, oldest_age) {
+                (ShedPolicy::Oldest, Some(_)) => {
+                    inner.shed_oldest.incr(1);
+                    inner.entries.pop_front()
+                }
+                (ShedPolicy::ByAge(max), Some(age)) if age > max => {
+                    inner.shed_expired.incr(1);
+                    inner.entries.pop_front()
+                }
+                _ => {
+                    inner.shed_newest.incr(1);
+                    None
+                }
+            };
+            match victim {
+                Some(victim) => victim.slot.shed(),
+                None => {
+                    // The arriving connection is not queued.
+                    slot.shed();
+                    return Dispatch {
+                        id,
+                        slot,
+                        queue: self.clone(),
+                        inner: dispatch,
+                    };
+                }
+            }
+        }
+
+        inner.entries.push_back(Entry {
+            id,
+            enqueued: now,
+            slot: slot.clone(),
+        });
+        inner.report(now);
+        Dispatch {
+            id,
+            slot,
+            queue: self.clone(),
+            inner: dispatch,
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        let mut inner = self.0.borrow_mut();
+        if let Some(idx) = inner.entries.iter().position(|e| e.id == id) {
+            inner.entries.remove(idx);
+            inner.report(Instant::now());
+        }
+    }
+}
+
+impl Inner {
+    /// Updates the queue's gauges. Its oldest connection's age is as of the most recent
+    /// change to the queue.
+    fn report(&self, now: Instant) {
+        self.depth.set(self.entries.len());
+        let age = self.entries.front().map(|e| now - e.enqueued).unwrap_or_default();
+        let age_ms = age.as_secs() * 1_000 + u64::from(age.subsec_nanos()) / 1_000_000;
+        self.max_age_ms.set(age_ms as usize);
+    }
+}
+
+/// A queued dispatch, which leaves the queue when it completes or is dropped.
+pub struct Dispatch<F> {
+    id: u64,
+    slot: Slot,
+    queue: DispatchQueue,
+    inner: F,
+}
+
+impl<F: Future<Error = io::Error>> Future for Dispatch<F> {
+    type Item = F::Item;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<F::Item, io::Error> {
+        if self.slot.poll_shed() {
+            return Err(io::Error::new(io::ErrorKind::Other, Shed));
+        }
+        self.inner.poll()
+    }
+}
+
+impl<F> Drop for Dispatch<F> {
+    fn drop(&mut self) {
+        self.queue.remove(self.id);
+    }
+}
+
+/// Indicates that a connection was shed from a full dispatch queue.
+#[derive(Debug)]
+pub struct Shed;
+
+impl Shed {
+    /// Determines whether an error was caused by shedding.
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().map(|e| e.is::<Shed>()).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Shed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("shed from a full dispatch queue")
+    }
+}
+
+impl error::Error for Shed {
+    fn description(&self) -> &str {
+        "shed"
+    }
+}
@@ -0,0 +1,304 @@
+//! Classifies failed server-side TLS handshakes.
+//!
+//! Each failure is counted as `tls_handshake_failures`, labeled by its `cause`. The
+//! first few failures of each cause are logged every minute as warnings, with the
+//! client's address and what its ClientHello offered; the rest are summarized.
+
+use super::super::connection::secure::{self, SecureStream, ServerHandshake};
+use super::super::log_limit::{LogLimit, Verdict};
+use super::client_hello::ClientHello;
+use futures::{Async, Future, Poll};
+use rustls::{ServerConfig, ServerSession, TLSError};
+use rustls::internal::msgs::enums::AlertDescription;
+use std::{fmt, io, net};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tacho;
+use tokio_core::net::TcpStream;
+use tokio_timer::{Sleep, Timer};
+use webpki;
+
+/// The content type of a TLS handshake record, which begins every TLS connection.
+const HANDSHAKE_CONTENT_TYPE: u8 = 22;
+
+/// Limits how many failures of each cause are logged per `LOG_WINDOW_SECS`.
+const LOGS_PER_WINDOW: usize = 5;
+const LOG_WINDOW_SECS: u64 = 60;
+
+/// The cause of a failed server-side TLS handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+    /// The client offered no cipher suite (or other parameter) that the server supports.
+    NoSharedCipher,
+    /// The client does not support a protocol version that the server supports.
+    UnsupportedVersion,
+    /// A certificate was missing, malformed, expired, or otherwise rejected.
+    BadCertificate,
+    /// A certificate was not issued by a trusted authority.
+    UnknownCa,
+    /// A message could not be decrypted or verified.
+    DecryptError,
+    /// The client does not speak TLS (e.g. it sent plaintext or closed without sending
+    /// anything).
+    NotTls,
+    /// The handshake did not complete in time.
+    Timeout,
+    /// Any other failure.
+    Other,
+}
+
+static ALL: &'static [HandshakeFailure] = &[
+    HandshakeFailure::NoSharedCipher,
+    HandshakeFailure::UnsupportedVersion,
+    HandshakeFailure::BadCertificate,
+    HandshakeFailure::UnknownCa,
+    HandshakeFailure::DecryptError,
+    HandshakeFailure::NotTls,
+    HandshakeFailure::Timeout,
+    HandshakeFailure::Other,
+];
+
+impl HandshakeFailure {
+    /// Classifies an error that failed a handshake, given the first byte the client
+    /// sent, if any.
+    pub fn classify(err: &io::Error, first_byte: Option<u8>) -> HandshakeFailure {
+        match first_byte {
+            Some(b) if b != HANDSHAKE_CONTENT_TYPE => return HandshakeFailure::NotTls,
+            None if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return HandshakeFailure::NotTls;
+            }
+            _ => {}
+        }
+        if err.kind() == io::ErrorKind::TimedOut {
+            return HandshakeFailure::Timeout;
+        }
+        match err.get_ref().and_then(|e| e.downcast_ref::<TLSError>()) {
+            Some(e) => HandshakeFailure::from_tls_error(e),
+            None => HandshakeFailure::Other,
+        }
+    }
+
+    /// Classifies an error reported by rustls.
+    pub fn from_tls_error(err: &TLSError) -> HandshakeFailure {
+        match *err {
+            TLSError::PeerIncompatibleError(ref msg) => {
+                let msg = msg.to_lowercase();
+                if msg.contains("version") || msg.contains("tlsv1") {
+                    HandshakeFailure::UnsupportedVersion
+                } else if msg.contains("cipher") || msg.contains("sig") ||
+                           msg.contains("group") || msg.contains("curve")
+                {
+                    HandshakeFailure::NoSharedCipher
+                } else {
+                    HandshakeFailure::Other
+                }
+            }
+            TLSError::AlertReceived(ref alert) => from_alert(alert),
+            TLSError::WebPKIError(webpki::Error::UnknownIssuer) => HandshakeFailure::UnknownCa,
+            TLSError::WebPKIError(_) |
+            TLSError::NoCertificatesPresented => HandshakeFailure::BadCertificate,
+            TLSError::DecryptError => HandshakeFailure::DecryptError,
+            TLSError::CorruptMessage |
+            TLSError::CorruptMessagePayload(_) => HandshakeFailure::NotTls,
+            _ => HandshakeFailure::Other,
+        }
+    }
+
+    /// Every cause, in the order in which they are declared.
+    pub fn all() -> &'static [HandshakeFailure] {
+        ALL
+    }
+
+    /// The `cause` label of this failure's metrics.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HandshakeFailure::NoSharedCipher => "no_shared_cipher",
+            HandshakeFailure::UnsupportedVersion => "unsupported_version",
+            HandshakeFailure::BadCertificate => "bad_certificate",
+            HandshakeFailure::UnknownCa => "unknown_ca",
+            HandshakeFailure::DecryptError => "decrypt_error",
+            HandshakeFailure::NotTls => "not_tls",
+            HandshakeFailure::Timeout => "timeout",
+            HandshakeFailure::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Classifies an alert sent by the client, which describes why the client failed the
+/// handshake.
+fn from_alert(alert: &AlertDescription) -> HandshakeFailure {
+    match *alert {
+        AlertDescription::ProtocolVersion => HandshakeFailure::UnsupportedVersion,
+        AlertDescription::HandshakeFailure |
+        AlertDescription::InsufficientSecurity => HandshakeFailure::NoSharedCipher,
+        AlertDescription::UnknownCA => HandshakeFailure::UnknownCa,
+        AlertDescription::BadCertificate |
+        AlertDescription::UnsupportedCertificate |
+        AlertDescription::CertificateRevoked |
+        AlertDescription::CertificateExpired |
+        AlertDescription::CertificateUnknown => HandshakeFailure::BadCertificate,
+        AlertDescription::DecryptError |
+        AlertDescription::BadRecordMac => HandshakeFailure::DecryptError,
+        _ => HandshakeFailure::Other,
+    }
+}
+
+/// Counts, and occasionally logs, a server's failed handshakes.
+#[derive(Clone)]
+pub struct HandshakeFailures {
+    counters: Rc<HashMap<HandshakeFailure, tacho::Counter>>,
+    /// Limits the failures of each cause that are logged.
+    logged: Rc<RefCell<LogLimit<HandshakeFailure>>>,
+}
+
+impl HandshakeFailures {
+    pub fn new(tls_metrics: &tacho::Scope) -> HandshakeFailures {
+        let counters = HandshakeFailure::all()
+            .iter()
+            .map(|&f| {
+                let c = tls_metrics.clone().labeled("cause", f.as_str()).counter(
+                    "handshake_failures",
+                );
+                (f, c)
+            })
+            .collect();
+        HandshakeFailures {
+            counters: Rc::new(counters),
+            logged: Rc::new(RefCell::new(LogLimit::new(
+                LOGS_PER_WINDOW,
+                Duration::from_secs(LOG_WINDOW_SECS),
+            ))),
+        }
+    }
+
+    fn record(
+        &self,
+        cause: HandshakeFailure,
+        peer: &net::SocketAddr,
+        err: &io::Error,
+        hello: &[u8],
+    ) {
+        if let Some(c) = self.counters.get(&cause) {
+            c.incr(1);
+        }
+
+        let verdict = self.logged.borrow_mut().record(
+            cause,
+            cause.as_str(),
+            Instant::now(),
+        );
+        match verdict {
+            Verdict::Suppress => {}
+            Verdict::Summarize(s) => warn!("TLS handshakes failed {}", s),
+            // The hello is only parsed for the failures that are logged.
+            Verdict::Log(_) => {
+                match ClientHello::parse(hello) {
+                    Some(hello) => {
+                        warn!(
+                            "TLS handshake from {} failed: cause={} {} error={}",
+                            peer.ip(),
+                            cause,
+                            hello,
+                            err
+                        )
+                    }
+                    None => {
+                        warn!(
+                            "TLS handshake from {} failed: cause={} error={}",
+                            peer.ip(),
+                            cause,
+                            err
+                        )
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Performs a server handshake on `tcp`, failing it if it does not complete within
+/// `handshake_timeout` of now.
+pub fn handshake(
+    tcp: TcpStream,
+    config: &Arc<ServerConfig>,
+    handshake_timeout: Option<Duration>,
+    timer: &Timer,
+    failures: &HandshakeFailures,
+) -> Handshake {
+    let peer = tcp.peer_addr().unwrap();
+    Handshake {
+        hs: secure::server_handshake(tcp, config),
+        peer,
+        // The deadline is fixed as the connection is accepted, so that the handshake
+        // times out no later than the connect timeout that also bounds it, and its
+        // timeout may be classified.
+        deadline: handshake_timeout.map(|t| Instant::now() + t),
+        timer: timer.clone(),
+        sleep: None,
+        failures: failures.clone(),
+    }
+}
+
+/// A server handshake whose failures are classified as they occur.
+pub struct Handshake {
+    hs: ServerHandshake,
+    peer: net::SocketAddr,
+    deadline: Option<Instant>,
+    timer: Timer,
+    sleep: Option<Sleep>,
+    failures: HandshakeFailures,
+}
+
+impl Handshake {
+    fn timed_out(&mut self) -> io::Result<bool> {
+        let deadline = match self.deadline {
+            None => return Ok(false),
+            Some(d) => d,
+        };
+        if self.sleep.is_none() {
+            let now = Instant::now();
+            let remaining = if now < deadline {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            self.sleep = Some(self.timer.sleep(remaining));
+        }
+        match self.sleep.as_mut().map(|s| s.poll()) {
+            Some(Ok(Async::Ready(_))) => Ok(true),
+            Some(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Future for Handshake {
+    type Item = SecureStream<ServerSession>;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let err = match self.hs.poll() {
+            Ok(Async::Ready(tls)) => return Ok(Async::Ready(tls)),
+            Ok(Async::NotReady) => {
+                match self.timed_out() {
+                    Ok(false) => return Ok(Async::NotReady),
+                    Ok(true) => io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        let cause = HandshakeFailure::classify(&err, self.hs.first_byte());
+        debug!("TLS handshake from {} failed: {}: {}", self.peer, cause, err);
+        self.failures.record(cause, &self.peer, &err, self.hs.client_hello());
+        Err(err)
+    }
+}
@@ -0,0 +1,115 @@
+//! Tracks, and optionally bounds, the TLS handshakes that a server performs concurrently.
+//!
+//! A handshake is in flight from when its connection is accepted until the handshake
+//! completes or fails, including while the connection is held by a `probeFilter` or by
+//! hooks. Handshakes in flight are reported as `tls_handshakes_in_flight`.
+//!
+//! With `maxConcurrentHandshakes`, the server stops accepting connections while that
+//! many handshakes are in flight, so that clients that are slow to complete handshakes
+//! (or that never begin them) cannot tie up an unbounded number of connections. Deferred
+//! connections wait in the listener's backlog until a handshake completes, fails, or
+//! times out. Each time accepting is paused, `tls_handshake_deferrals` is incremented.
+
+use futures::{Async, Poll, Stream};
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tacho;
+
+/// The handshakes in flight on a server.
+#[derive(Clone)]
+pub struct HandshakeLimit(Rc<RefCell<Inner>>);
+
+struct Inner {
+    max: Option<usize>,
+    in_flight: usize,
+    /// The task accepting connections, while it is deferred.
+    acceptor: Option<Task>,
+    in_flight_gauge: tacho::Gauge,
+    deferrals: tacho::Counter,
+}
+
+impl HandshakeLimit {
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    pub fn new(max: Option<usize>, tls_metrics: &tacho::Scope) -> HandshakeLimit {
+        HandshakeLimit(Rc::new(RefCell::new(Inner {
+            max,
+            in_flight: 0,
+            acceptor: None,
+            in_flight_gauge: tls_metrics.gauge("handshakes_in_flight"),
+            deferrals: tls_metrics.counter("handshake_deferrals"),
+        })))
+    }
+
+    /// Determines whether another connection may be accepted, and if not, arranges for
+    /// the current task to be notified once a handshake finishes.
+    fn poll_ready(&self) -> bool {
+        let mut inner = self.0.borrow_mut();
+        match inner.max {
+            Some(max) if inner.in_flight >= max => {
+                if inner.acceptor.is_none() {
+                    inner.deferrals.incr(1);
+                }
+                inner.acceptor = Some(task::current());
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Counts a handshake as in flight until the returned guard is dropped.
+    fn start(&self) -> InFlight {
+        {
+            let mut inner = self.0.borrow_mut();
+            inner.in_flight += 1;
+            inner.in_flight_gauge.set(inner.in_flight);
+        }
+        InFlight(self.clone())
+    }
+}
+
+/// A handshake in flight, which finishes when this is dropped.
+pub struct InFlight(HandshakeLimit);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut inner = (self.0).0.borrow_mut();
+        inner.in_flight -= 1;
+        inner.in_flight_gauge.set(inner.in_flight);
+        if let Some(task) = inner.acceptor.take() {
+            task.notify();
+        }
+    }
+}
+
+/// Stops polling `incoming` for connections while `limit` is reached. Each accepted
+/// connection is paired with its handshake's guard, if the server performs handshakes.
+pub fn gate<S: Stream>(incoming: S, limit: Option<HandshakeLimit>) -> Gated<S> {
+    Gated { incoming, limit }
+}
+
+pub struct Gated<S> {
+    incoming: S,
+    limit: Option<HandshakeLimit>,
+}
+
+impl<S, T, A> Stream for Gated<S>
+where
+    S: Stream<Item = (T, A)>,
+{
+    type Item = (T, A, Option<InFlight>);
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, S::Error> {
+        if let Some(ref limit) = self.limit {
+            if !limit.poll_ready() {
+                return Ok(Async::NotReady);
+            }
+        }
+        let (tcp, addr) = match try_ready!(self.incoming.poll()) {
+            None => return Ok(Async::Ready(None)),
+            Some(accepted) => accepted,
+        };
+        let in_flight = self.limit.as_ref().map(|l| l.start());
+        Ok(Async::Ready(Some((tcp, addr, in_flight))))
+    }
+}
@@ -0,0 +1,283 @@
+//! Obtains server identities from a local agent rather than from files.
+//!
+//! Meshes may provision workload certificates through an agent on each host (e.g.
+//! SPIFFE's) that serves them over a unix socket. Each agent-provided identity is watched
+//! by a thread of its own: the agent provides the identity and then pushes a replacement
+//! whenever the certificate is rotated, and each replacement is used for subsequent
+//! handshakes. While the agent is unavailable, the last identity it provided continues to
+//! be presented and the agent is retried with exponential backoff.
+//!
+//! By default, a server does not start until its agent has provided its identity. With
+//! `startWithoutIdentity`, the server starts immediately and refuses handshakes until the
+//! agent provides the identity.
+//!
+//! The agent's protocol is abstracted by `Agent`. `JsonAgent` speaks a simple protocol
+//! in which each message is a JSON object, prefixed by its length as a 32-bit big-endian
+//! integer. The client sends `{"audience": ...}`, and the agent replies with
+//! `{"certs": <PEM chain>, "privateKey": <PEM key>}` whenever the identity changes, or
+//! with `{"error": ...}` if it cannot provide one.
+
+use rustls::sign;
+use rustls::internal::pemfile;
+use serde_json;
+use std::{cmp, io, thread};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+const BASE_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 10_000;
+
+/// The number of times the agent is tried before a server that requires its identity
+/// fails to start.
+const STARTUP_ATTEMPTS: u32 = 4;
+
+/// Larger messages are treated as protocol errors.
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct AgentPolicy {
+    pub socket_path: PathBuf,
+    pub audience: Option<String>,
+    pub start_without_identity: bool,
+}
+
+/// Speaks an identity agent's protocol.
+pub trait Agent: Send {
+    /// Requests the identity, returning a watch on which the agent provides it and its
+    /// replacements.
+    fn watch(&self) -> io::Result<Box<Watch>>;
+}
+
+/// Receives the identities provided by an agent.
+pub trait Watch: Send {
+    /// Blocks until the agent provides an identity.
+    fn next(&mut self) -> io::Result<sign::CertifiedKey>;
+}
+
+/// The identity most recently provided by an agent, if any.
+#[derive(Clone, Default)]
+pub struct Slot(Arc<RwLock<Option<sign::CertifiedKey>>>);
+
+impl Slot {
+    pub fn get(&self) -> Option<sign::CertifiedKey> {
+        self.0.read().expect("identity lock poisoned").clone()
+    }
+}
+
+/// An identity that is provided by an agent once the server is bound.
+#[derive(Clone)]
+pub struct AgentIdentity {
+    policy: AgentPolicy,
+    slot: Slot,
+}
+
+impl AgentIdentity {
+    pub fn new(policy: AgentPolicy) -> AgentIdentity {
+        AgentIdentity {
+            policy,
+            slot: Slot::default(),
+        }
+    }
+
+    /// The identity, as it is updated by the agent.
+    pub fn slot(&self) -> Slot {
+        self.slot.clone()
+    }
+
+    /// Starts watching the agent.
+    ///
+    /// Unless the server may start without its identity, blocks until the agent has
+    /// provided it, failing if the agent cannot provide it after a few attempts.
+    pub fn start(&self) -> io::Result<()> {
+        let agent = JsonAgent {
+            socket_path: self.policy.socket_path.clone(),
+            audience: self.policy.audience.clone(),
+        };
+        self.start_with(Box::new(agent))
+    }
+
+    fn start_with(&self, agent: Box<Agent>) -> io::Result<()> {
+        let name = format!("{}", self.policy.socket_path.display());
+        let mut watch = None;
+        if !self.policy.start_without_identity {
+            let mut attempts = 0;
+            loop {
+                match fetch(&*agent) {
+                    Ok((w, key)) => {
+                        info!("{}: identity provided", name);
+                        *self.slot.0.write().expect("identity lock poisoned") = Some(key);
+                        watch = Some(w);
+                        break;
+                    }
+                    Err(e) => {
+                        attempts += 1;
+                        if attempts == STARTUP_ATTEMPTS {
+                            return Err(io::Error::new(
+                                e.kind(),
+                                format!("identity agent {} failed: {}", name, e),
+                            ));
+                        }
+                        warn!("{}: identity agent failed: {}", name, e);
+                        thread::sleep(backoff(attempts));
+                    }
+                }
+            }
+        }
+
+        let slot = Arc::downgrade(&self.slot.0);
+        thread::Builder::new()
+            .name("identity-agent".into())
+            .spawn(move || watch_agent(agent, watch, slot, name))?;
+        Ok(())
+    }
+}
+
+fn fetch(agent: &Agent) -> io::Result<(Box<Watch>, sign::CertifiedKey)> {
+    let mut watch = agent.watch()?;
+    let key = watch.next()?;
+    Ok((watch, key))
+}
+
+/// Updates `slot` with each identity provided by `agent`, until the server that presents
+/// it is dropped.
+fn watch_agent(
+    agent: Box<Agent>,
+    mut watch: Option<Box<Watch>>,
+    slot: Weak<RwLock<Option<sign::CertifiedKey>>>,
+    name: String,
+) {
+    let mut failures = 0;
+    loop {
+        let provided = match watch.take() {
+            Some(mut w) => w.next().map(|key| (w, key)),
+            None => fetch(&*agent),
+        };
+        let slot = match slot.upgrade() {
+            Some(slot) => slot,
+            None => return,
+        };
+        match provided {
+            Ok((w, key)) => {
+                info!("{}: identity provided", name);
+                failures = 0;
+                *slot.write().expect("identity lock poisoned") = Some(key);
+                watch = Some(w);
+            }
+            Err(e) => {
+                failures += 1;
+                warn!("{}: identity agent failed: {}", name, e);
+                thread::sleep(backoff(failures));
+            }
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let exp = cmp::min(failures.saturating_sub(1), 16);
+    Duration::from_millis(cmp::min(BASE_BACKOFF_MS << exp, MAX_BACKOFF_MS))
+}
+
+/// Speaks the length-prefixed JSON protocol over a unix socket.
+pub struct JsonAgent {
+    socket_path: PathBuf,
+    audience: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audience: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    certs: Option<String>,
+    private_key: Option<String>,
+    error: Option<String>,
+}
+
+#[cfg(unix)]
+impl Agent for JsonAgent {
+    fn watch(&self) -> io::Result<Box<Watch>> {
+        use std::os::unix::net::UnixStream;
+
+        let mut sock = UnixStream::connect(&self.socket_path)?;
+        let req = Request { audience: self.audience.as_ref().map(|a| a.as_str()) };
+        let req = serde_json::to_vec(&req).map_err(invalid)?;
+        write_message(&mut sock, &req)?;
+        Ok(Box::new(JsonWatch(sock)))
+    }
+}
+
+#[cfg(not(unix))]
+impl Agent for JsonAgent {
+    fn watch(&self) -> io::Result<Box<Watch>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "identity agents require unix sockets",
+        ))
+    }
+}
+
+struct JsonWatch<S>(S);
+
+impl<S: Read + Send> Watch for JsonWatch<S> {
+    fn next(&mut self) -> io::Result<sign::CertifiedKey> {
+        let msg = read_message(&mut self.0)?;
+        let rsp: Response = serde_json::from_slice(&msg).map_err(invalid)?;
+        if let Some(e) = rsp.error {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("agent error: {}", e)));
+        }
+        match (rsp.certs, rsp.private_key) {
+            (Some(certs), Some(key)) => certified_key(certs.as_bytes(), key.as_bytes()),
+            _ => Err(invalid("identity without certs or privateKey")),
+        }
+    }
+}
+
+fn write_message<W: Write>(w: &mut W, msg: &[u8]) -> io::Result<()> {
+    let len = msg.len() as u32;
+    w.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    w.write_all(msg)?;
+    w.flush()
+}
+
+fn read_message<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = len.iter().fold(0usize, |n, b| (n << 8) | *b as usize);
+    if len > MAX_MESSAGE_BYTES {
+        return Err(invalid(format!("message of {} bytes is too large", len)));
+    }
+    let mut msg = vec![0; len];
+    r.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+fn certified_key(mut certs: &[u8], mut key: &[u8]) -> io::Result<sign::CertifiedKey> {
+    let certs = pemfile::certs(&mut certs).map_err(|()| invalid("invalid certs"))?;
+    if certs.is_empty() {
+        return Err(invalid("no certs"));
+    }
+    let keys = pemfile::rsa_private_keys(&mut key).map_err(
+        |()| invalid("invalid privateKey"),
+    )?;
+    if keys.len() != 1 {
+        return Err(invalid(format!("{} private keys", keys.len())));
+    }
+    let key: Box<sign::SigningKey> = match sign::RSASigningKey::new(&keys[0]) {
+        Ok(key) => Box::new(key),
+        Err(()) => return Err(invalid("invalid privateKey")),
+    };
+    Ok(sign::CertifiedKey::new(certs, Arc::new(key)))
+}
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,141 @@
+//! Mirrors a sample of a server's streams to a shadow destination, e.g. to validate a
+//! migration with production traffic.
+//!
+//! Each sampled stream opens a second, best-effort connection to an endpoint of the
+//! mirror's destination, which is balanced independently of the server's own
+//! destination. The bytes the client sends are copied to it; the bytes the shadow
+//! endpoint sends are discarded. The primary stream never waits for its mirror: a
+//! mirror that cannot connect, fails, or falls more than `maxBufferedBytes` behind is
+//! closed, and the primary stream continues.
+//!
+//! Mirrors are not counted in the server's connection or stream metrics. Sampled
+//! streams are counted as `mirror_sampled`, mirrors that finish as `mirror_completed`,
+//! and mirrors that are closed early as `mirror_dropped`, labeled by `cause`.
+
+use super::super::Path;
+use super::super::connection::tee::{self, Overflow, Tee};
+use super::super::router::Router;
+use super::super::timeout::timeout;
+use futures::{Future, future};
+use rand;
+use std::{io, net};
+use std::rc::Rc;
+use std::time::Duration;
+use tacho;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+pub const DEFAULT_SAMPLE_RATE: f64 = 1.0;
+pub const DEFAULT_MAX_BUFFERED_BYTES: usize = 64 * 1024;
+
+/// Once a primary stream has finished, its mirror has this long to write what remains.
+const DRAIN_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub dst_name: Path,
+    pub sample_rate: f64,
+    pub max_buffered_bytes: usize,
+}
+
+impl Policy {
+    pub fn bind(self, metrics: &tacho::Scope) -> Mirror {
+        let metrics = metrics.clone().prefixed("mirror");
+        let dropped = |cause: &'static str| {
+            metrics.clone().labeled("cause", cause).counter("dropped")
+        };
+        Mirror(Rc::new(Inner {
+            metrics: Metrics {
+                sampled: metrics.counter("sampled"),
+                completed: metrics.counter("completed"),
+                connect_failures: dropped("connect"),
+                overflows: dropped("overflow"),
+                timeouts: dropped("timeout"),
+                errors: dropped("error"),
+            },
+            policy: self,
+        }))
+    }
+}
+
+/// Opens mirrors for sampled streams.
+#[derive(Clone)]
+pub struct Mirror(Rc<Inner>);
+
+struct Inner {
+    policy: Policy,
+    metrics: Metrics,
+}
+
+struct Metrics {
+    sampled: tacho::Counter,
+    completed: tacho::Counter,
+    connect_failures: tacho::Counter,
+    overflows: tacho::Counter,
+    timeouts: tacho::Counter,
+    errors: tacho::Counter,
+}
+
+impl Mirror {
+    /// Decides whether the stream from `src_addr` is mirrored. If it is, a connection to
+    /// the mirror's destination is spawned, bounded by `connect_timeout`, and the
+    /// returned tee copies the client's bytes to it.
+    pub fn sample(
+        &self,
+        src_addr: net::SocketAddr,
+        router: &Router,
+        connect_timeout: Option<Duration>,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Option<Tee> {
+        let rate = self.0.policy.sample_rate;
+        if rate < 1.0 && rand::random::<f64>() >= rate {
+            return None;
+        }
+        self.0.metrics.sampled.incr(1);
+
+        let (tee, copied) = tee::new(self.0.policy.max_buffered_bytes);
+        let connect = router.route(&self.0.policy.dst_name, reactor, timer).and_then(
+            move |b| {
+                b.connect_from(&src_addr, None).map_err(io::Error::from)
+            },
+        );
+        let connect = timeout(connect, connect_timeout, timer);
+
+        let inner = self.0.clone();
+        let timer = timer.clone();
+        let mirror = connect.then(move |res| match res {
+            Err(e) => {
+                debug!("failed to connect mirror for {}: {}", src_addr, e);
+                inner.metrics.connect_failures.incr(1);
+                future::Either::A(future::ok::<(), ()>(()))
+            }
+            Ok(conn) => {
+                trace!("mirroring {} to {}", src_addr, conn.peer_addr());
+                let drain_timeout = Duration::from_secs(DRAIN_TIMEOUT_SECS);
+                let shadow = copied.write_to(conn, drain_timeout, &timer);
+                future::Either::B(shadow.then(move |res| {
+                    match res {
+                        Ok(sz) => {
+                            trace!("mirrored {} bytes from {}", sz, src_addr);
+                            inner.metrics.completed.incr(1);
+                        }
+                        Err(e) => {
+                            debug!("dropped mirror for {}: {}", src_addr, e);
+                            if Overflow::is(&e) {
+                                inner.metrics.overflows.incr(1);
+                            } else if e.kind() == io::ErrorKind::TimedOut {
+                                inner.metrics.timeouts.incr(1);
+                            } else {
+                                inner.metrics.errors.incr(1);
+                            }
+                        }
+                    }
+                    Ok(())
+                }))
+            }
+        });
+        reactor.spawn(mirror);
+        Some(tee)
+    }
+}
@@ -1,23 +1,69 @@
 //! TODO `dst_name` should be chosen dynamically.
 
-use super::Path;
-use super::connection::{Connection, Socket, ctx, secure, socket};
+use super::{Path, accept, metrics, supervise};
+use super::balancer::Generation;
+use super::connection::{Buffers, CloseReason, CloseReasonCell, Connection, Peer, Socket,
+                        WriteTimeout, ctx, duplex, socket};
+use super::fd::FdLimit;
+use super::handoff::Handoffs;
+use super::hook::{BoundHooks, ConnectionSummary, Hooks};
 use super::router::Router;
-use futures::{Async, Future, Poll, Stream, future};
-use rustls;
+use super::summary::Signals;
+use super::timeout::timeout;
+use super::tracing::{Span, Tracer};
+use self::config::ServerPolicy;
+use self::dispatch_queue::Shed;
+use self::handshake_limit::InFlight;
+use self::sniff::Sniffer;
+use futures::{Future, Poll, Stream, future};
 use std::{io, net};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tacho;
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 
 mod config;
+mod dispatch_queue;
+mod handshake_limit;
+mod mirror;
+mod probe;
+mod retry;
+mod reuse;
+mod sniff;
+mod tls_policy;
+mod transfer_size;
+mod udp;
+#[cfg(feature = "tls")]
+mod chain;
+#[cfg(feature = "tls")]
+mod client_hello;
+#[cfg(feature = "tls")]
+mod handshake;
+#[cfg(feature = "tls")]
+mod identity;
+#[cfg(feature = "tls")]
+mod resumption;
+#[cfg(feature = "tls")]
 mod sni;
-pub use self::config::{Error as ConfigError, ServerConfig};
+pub use self::config::{AgentIdentityConfig, DispatchQueueConfig, Error as ConfigError,
+                       IdentitySourceConfig, IntegrityAlgorithm, IntegrityCheckConfig,
+                       MirrorConfig, ProbeFilterConfig, ServerConfig, ServerKind, ShedPolicy,
+                       SourcePortReuseConfig, TcpInfoConfig, TlsServerConfig,
+                       TlsPolicyConfig, TlsServerIdentityConfig, TlsSessionResumptionConfig,
+                       TransparentRetryConfig, WriteCoalescingConfig};
+pub use self::sniff::MisdirectedTls;
+pub use self::tls_policy::TlsProperties;
+pub use self::transfer_size::{DEFAULT_SIZE_BUCKETS, TransferSizes};
+#[cfg(feature = "tls")]
+pub use self::client_hello::ClientHello;
+#[cfg(feature = "tls")]
+pub use self::handshake::HandshakeFailure;
 
 const DEFAULT_MAX_CONCURRENCY: usize = 100000;
 
@@ -26,37 +72,54 @@ fn unbound(
     listen_addr: net::SocketAddr,
     dst_name: Path,
     router: Router,
-    buf: Rc<RefCell<Vec<u8>>>,
+    bufs: Buffers,
     tls: Option<UnboundTls>,
-    connect_timeout: Option<Duration>,
-    connection_lifetime: Option<Duration>,
-    max_concurrency: usize,
+    policy: ServerPolicy,
+    fd_limit: FdLimit,
+    tracer: Option<Tracer>,
+    hooks: Option<Hooks>,
     metrics: &tacho::Scope,
+    transfer_sizes: Option<TransferSizes>,
+    handoffs: Option<Handoffs>,
+    signals: Arc<Signals>,
 ) -> Unbound {
     let metrics = metrics.clone().prefixed("srv");
     Unbound {
         listen_addr,
         dst_name,
         router,
-        buf,
+        bufs,
         tls,
-        connect_timeout,
-        connection_lifetime,
-        max_concurrency,
+        policy,
+        fd_limit,
+        tracer,
+        hooks,
         metrics,
+        transfer_sizes,
+        handoffs,
+        signals,
     }
 }
 
+#[derive(Clone)]
 pub struct Unbound {
     listen_addr: net::SocketAddr,
     dst_name: Path,
     router: Router,
-    buf: Rc<RefCell<Vec<u8>>>,
+    bufs: Buffers,
     tls: Option<UnboundTls>,
     metrics: tacho::Scope,
-    connect_timeout: Option<Duration>,
-    connection_lifetime: Option<Duration>,
-    max_concurrency: usize,
+    policy: ServerPolicy,
+    fd_limit: FdLimit,
+    tracer: Option<Tracer>,
+    /// Set when an embedding application observes and filters connections.
+    hooks: Option<Hooks>,
+    /// Set when the router counts connections by the sizes of their transfers.
+    transfer_sizes: Option<TransferSizes>,
+    /// Set when streams may be handed off to another process as this one shuts down.
+    handoffs: Option<Handoffs>,
+    /// The router's golden signals, as summarized by the admin server.
+    signals: Arc<Signals>,
 }
 impl Unbound {
     pub fn listen_addr(&self) -> net::SocketAddr {
@@ -67,91 +130,254 @@ impl Unbound {
         &self.dst_name
     }
 
+    /// Begins resolving the server's destination before its first connection is
+    /// accepted.
+    pub fn resolve(&self, reactor: &Handle, timer: &Timer) {
+        drop(self.router.route(&self.dst_name, reactor, timer));
+        // Mirrors are balanced independently of the server's destination.
+        if let Some(ref mirror) = self.policy.mirror {
+            drop(self.router.route(&mirror.dst_name, reactor, timer));
+        }
+    }
+
     fn init_src_connection(
         src_tcp: TcpStream,
+        in_flight: Option<InFlight>,
         metrics: &Metrics,
         tls: &Option<BoundTls>,
+        span: Option<Span>,
+        summary: Option<Rc<RefCell<ConnectionSummary>>>,
+        signals: Arc<Signals>,
     ) -> Box<Future<Item = Connection<SrcCtx>, Error = io::Error>> {
 
-        let sock: Box<Future<Item = Socket, Error = io::Error>> = match tls.as_ref() {
-            None => Box::new(future::ok(socket::plain(src_tcp))),
-            Some(tls) => {
-                // TODO we should be able to get metadata from a TLS handshake but we can't!
-                let sock = tls.handshake_latency
-                    .time(secure::server_handshake(src_tcp, &tls.config))
-                    .map(socket::secure_server);
-                Box::new(sock)
-            }
-        };
+        let sock: Box<Future<Item = (Socket, Option<TlsProperties>), Error = io::Error>> =
+            match tls.as_ref() {
+                None => Box::new(future::ok((socket::plain(src_tcp), None))),
+                Some(tls) => Box::new(tls.handshake(src_tcp, in_flight).map(
+                    |(sock, props)| (sock, Some(props)),
+                )),
+            };
 
+        let transfer_sizes = metrics.transfer_sizes.clone();
         let metrics = metrics.per_conn.clone();
-        let conn = sock.map(move |sock| {
+        let conn = sock.map(move |(sock, props)| {
+            let alpn = sock.alpn_protocol();
+            if let Some(ref span) = span {
+                if let Some(ref props) = props {
+                    span.negotiated(props);
+                }
+                span.ready();
+            }
+            if let Some(ref summary) = summary {
+                summary.borrow_mut().tls = props;
+            }
             let ctx = SrcCtx {
                 rx_bytes_total: 0,
                 tx_bytes_total: 0,
-                metrics,
+                metrics: metrics.get(alpn.as_ref().map(|p| p.as_str())).clone(),
+                transfer_sizes,
+                alpn,
+                span,
+                summary,
+                signals,
             };
             Connection::new(sock, ctx)
         });
         Box::new(conn)
     }
 
-    pub fn bind(self, reactor: &Handle, timer: &Timer) -> io::Result<Bound> {
+    pub fn bind(mut self, reactor: &Handle, timer: &Timer) -> io::Result<Bound> {
         debug!("routing on {} to {}", self.listen_addr, self.dst_name);
+        if let Some(udp) = self.policy.udp {
+            return udp.bind(
+                self.listen_addr,
+                self.dst_name,
+                self.router,
+                self.policy.max_concurrency,
+                self.fd_limit,
+                &self.metrics,
+                reactor,
+                timer,
+            );
+        }
         let listen = TcpListener::bind(&self.listen_addr, reactor)?;
         let bound_addr = listen.local_addr().unwrap();
 
+        // The socket and TLS configuration are shared by each instance of the listener,
+        // so that identities are only provided once.
+        let metrics = self.metrics.clone().labeled("srv_addr", format!("{}", bound_addr));
+        let tls = match self.tls.take() {
+            None => None,
+            Some(tls) => Some(tls.bind(self.policy.connect_timeout, timer, &metrics)?),
+        };
+        let listen = Rc::new(RefCell::new(listen));
+        let (serve_reactor, serve_timer) = (reactor.clone(), timer.clone());
+        let serve = move || {
+            self.clone().serve(
+                listen.clone(),
+                bound_addr,
+                tls.clone(),
+                &serve_reactor,
+                &serve_timer,
+            )
+        };
+        Ok(Bound::new(bound_addr, serve, &metrics, timer))
+    }
+
+    /// Serves the connections accepted by `listen`, which is bound to `bound_addr`.
+    fn serve(
+        self,
+        listen: Rc<RefCell<TcpListener>>,
+        bound_addr: net::SocketAddr,
+        tls: Option<BoundTls>,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Box<Stream<Item = (), Error = io::Error>> {
         let metrics = self.metrics.labeled("srv_addr", format!("{}", bound_addr));
-        let tls = self.tls.map(|tls| {
-            BoundTls {
-                config: tls.config,
-                handshake_latency: metrics.clone().prefixed("tls").timer_us("handshake_us"),
-            }
-        });
+        let incoming = accept::accepting(listen, accept::Policy::default(), timer, &metrics);
+        let connect_timeout = self.policy.connect_timeout;
+        let integrity = self.policy.integrity.map(|i| i.bind(&metrics));
+        let probe_filter = self.policy.probe_filter.map(|p| p.bind(timer, &metrics));
+        let dispatch_queue = self.policy.dispatch_queue.map(|q| q.bind(&metrics));
+        let source_port_reuse = self.policy.source_port_reuse.map(|r| r.bind(&metrics));
+        let hooks = self.hooks.map(|h| h.bind(timer, &metrics));
+        let mirror = self.policy.mirror.map(|m| m.bind(&metrics));
+        let tcp_info = self.policy.tcp_info.map(|t| t.bind(&metrics));
+        let transparent_retry = self.policy.transparent_retry.map(|r| r.bind(&metrics));
+        let accept_hooks = hooks.clone();
+        let in_flight_limit = tls.as_ref().map(|tls| tls.handshake_limit());
+
+        // Plaintext streams are classified to detect misdirected clients.
+        let sniffer = if tls.is_none() {
+            Some(Sniffer::new(self.policy.detect_misdirected_tls, &metrics))
+        } else {
+            None
+        };
 
         let connect_metrics = metrics.clone().prefixed("connect");
         let stream_metrics = metrics.clone().prefixed("stream");
-        let per_conn = ConnMetrics {
-            rx_bytes: stream_metrics.counter("rx_bytes"),
-            tx_bytes: stream_metrics.counter("tx_bytes"),
-            rx_bytes_per_conn: stream_metrics.stat("connection_rx_bytes"),
-            tx_bytes_per_conn: stream_metrics.stat("connection_tx_bytes"),
-            latency: connect_metrics.timer_us("latency_us"),
-            duration: stream_metrics.timer_ms("duration_ms"),
+        let per_conn = {
+            let protocols = tls.as_ref().map(|tls| tls.alpn_protocols()).unwrap_or(&[]);
+            StreamMetrics::new(&stream_metrics, protocols)
         };
         let metrics = Metrics {
             accepts: metrics.counter("accepts"),
             closes: metrics.counter("closes"),
             failures: metrics.counter("failures"),
+            panics: metrics.counter("connection_panics"),
             active: metrics.gauge("active"),
             waiters: metrics.gauge("waiters"),
+            connect_latency: connect_metrics.timer_us("latency_us"),
+            close_reasons: CloseReasonMetrics::new(&metrics),
             connect_failures: FailureMetrics::new(&connect_metrics, "failure"),
             stream_failures: FailureMetrics::new(&stream_metrics, "failure"),
             per_conn,
+            transfer_sizes: self.transfer_sizes,
         };
 
         // TODO determine dst_addr dynamically.
         let dst_name = self.dst_name;
         let router = self.router;
-        let connect_timeout = self.connect_timeout;
-        let connection_lifetime = self.connection_lifetime;
-        let buf = self.buf;
+        let connection_lifetime = self.policy.connection_lifetime;
+        let write_timeout = self.policy.write_timeout;
+        let write_coalescing = self.policy.write_coalescing;
+        let bufs = self.bufs;
+        let tracer = self.tracer;
+        let signals = self.signals;
+        let handoffs = self.handoffs;
 
         let reactor = reactor.clone();
         let timer = timer.clone();
-        let serving = listen
-            .incoming()
-            .map(move |(src_tcp, src_addr)| {
+        // Accepts are deferred while the process is near its file descriptor limit, so
+        // that new connections wait in the listener's backlog, and while too many TLS
+        // handshakes are in flight.
+        let incoming = self.fd_limit.gate(incoming);
+        let serving = handshake_limit::gate(incoming, in_flight_limit)
+            // Connections that may be health checks are held until they send data, so
+            // that probes may be closed without being dispatched. Others pass
+            // immediately.
+            .map(move |(src_tcp, src_addr, in_flight)| {
+                let accepted = match probe_filter {
+                    None => future::Either::A(future::ok(Some((src_tcp, src_addr)))),
+                    Some(ref probes) => future::Either::B(probes.accept(src_tcp, src_addr)),
+                };
+                (accepted, in_flight)
+            })
+            // Hooks may reject connections before they are routed.
+            .map(move |(accepted, in_flight)| {
+                let hooks = accept_hooks.clone();
+                accepted
+                    .and_then(move |accepted| match (accepted, hooks) {
+                        (Some((src_tcp, src_addr)), Some(hooks)) => {
+                            future::Either::A(hooks.accept(src_tcp, src_addr))
+                        }
+                        (accepted, _) => future::Either::B(future::ok(accepted)),
+                    })
+                    .map(move |accepted| {
+                        accepted.map(|(src_tcp, src_addr)| (src_tcp, src_addr, in_flight))
+                    })
+            })
+            .buffer_unordered(self.policy.max_concurrency)
+            .filter_map(|accepted| accepted)
+            .map(move |(src_tcp, src_addr, in_flight)| {
                 trace!("received incoming connection from {}", src_addr);
                 metrics.accepts.incr(1);
+                signals.accepted();
+                let signals = signals.clone();
                 let active = metrics.active.clone();
                 active.incr(1);
                 let waiters = metrics.waiters.clone();
                 waiters.incr(1);
+                if let Some(ref reuse) = source_port_reuse {
+                    reuse.accept(&src_addr);
+                }
+                let reuse = source_port_reuse.clone();
+
+                // Connections are summarized for hooks as they are closed.
+                let accepted_at = Instant::now();
+                let summary = hooks.as_ref().map(|_| {
+                    Rc::new(RefCell::new(ConnectionSummary {
+                        client_addr: src_addr,
+                        server_addr: bound_addr,
+                        dst_name: format!("{}", dst_name),
+                        endpoint_addr: None,
+                        resolution: None,
+                        rx_bytes: 0,
+                        tx_bytes: 0,
+                        duration: Duration::from_secs(0),
+                        error: None,
+                        client_tcp_info: None,
+                        endpoint_tcp_info: None,
+                        tls: None,
+                    }))
+                });
+
+                // Whether the connection is traced is decided as it is accepted.
+                let span = tracer.as_ref().and_then(|t| {
+                    t.sample(src_addr, bound_addr, &dst_name)
+                });
 
                 // Finish accepting the connection from the server.
                 // TODO determine dst_addr dynamically.
-                let src = Unbound::init_src_connection(src_tcp, &metrics, &tls);
+                let src = Unbound::init_src_connection(
+                    src_tcp,
+                    in_flight,
+                    &metrics,
+                    &tls,
+                    span.clone(),
+                    summary.clone(),
+                    signals.clone(),
+                );
+
+                // Plaintext streams are classified, and misdirected TLS clients may be
+                // refused, by the first bytes their clients send.
+                let first_read = sniffer.as_ref().map(|s| s.first_read(src_addr));
+                let src = src.map(move |mut src| {
+                    if let Some(first_read) = first_read {
+                        src.socket.on_first_read(first_read);
+                    }
+                    src
+                });
 
                 // Obtain a balancing endpoint selector for the given destination.
                 let balancer = router.route(&dst_name, &reactor, &timer);
@@ -161,92 +387,394 @@ impl Unbound {
                 // the incoming handshake is complete so that we don't waste outbound connections
                 // on failed inbound connections.
                 let connect = src.join(balancer).and_then(move |(src, b)| {
-                    b.connect().map(move |dst| (src, dst))
+                    // The downstream client's requested server name may be used to name
+                    // the upstream connection.
+                    let sni = src.socket.sni_hostname().map(|s| s.to_owned());
+                    b.connect_from(&src_addr, sni).map_err(io::Error::from).map(
+                        move |dst| (src, dst),
+                    )
                 });
 
+                // The connection waits in the dispatch queue, if any, until it is
+                // connected, and may be shed to make room for others.
+                let connect = match dispatch_queue {
+                    None => future::Either::A(connect),
+                    Some(ref queue) => future::Either::B(queue.dispatch(connect)),
+                };
+
                 // Enforce a connection timeout, measure successful connection
                 // latencies and failure counts.
                 let connect = {
                     // Measure the time until the connection is established, if it completes.
                     let c = timeout(
-                        metrics.per_conn.latency.time(connect),
+                        metrics.connect_latency.time(connect),
                         connect_timeout,
                         &timer,
                     );
                     let fails = metrics.connect_failures.clone();
+                    let close_reasons = metrics.close_reasons.clone();
+                    let span = span.clone();
+                    let hooks = hooks.clone();
+                    let summary = summary.clone();
+                    let signals = signals.clone();
                     c.then(move |res| match res {
                         Ok((src, dst)) => {
                             trace!("connection ready for {} to {}", src_addr, dst.peer_addr());
                             waiters.decr(1);
+                            signals.dispatched(accepted_at.elapsed());
+                            let generation = dst.ctx.generation();
+                            record_dispatch(
+                                &src_addr,
+                                dst.peer_addr(),
+                                generation,
+                                &span,
+                                &hooks,
+                                &summary,
+                            );
                             Ok((src, dst))
                         }
                         Err(e) => {
                             trace!("connection failed for {}: {}", src_addr, e);
                             waiters.decr(1);
                             fails.record(&e);
+                            let reason = if e.kind() == io::ErrorKind::TimedOut {
+                                CloseReason::DispatchTimeout
+                            } else if Shed::is(&e) {
+                                CloseReason::Shed
+                            } else {
+                                CloseReason::Error(e.kind())
+                            };
+                            debug!("connection from {} closed: {}", src_addr, reason);
+                            close_reasons.record(reason);
+                            if let Some(ref span) = span {
+                                span.close(reason);
+                            }
                             Err(e)
                         }
                     })
                 };
 
+                // The stream's sockets are sampled until they are closed. The final
+                // samples remain available to summarize the connection.
+                let sampler = tcp_info.as_ref().map(|t| t.sampler(&timer));
+                let samples = sampler.as_ref().map(|s| s.samples());
+
                 // Copy data between the endpoints.
                 let stream = {
-                    let buf = buf.clone();
+                    let bufs = bufs.clone();
+                    let integrity = integrity.clone();
                     let stream_fails = metrics.stream_failures.clone();
-                    let duration = metrics.per_conn.duration.clone();
+                    let close_reasons = metrics.close_reasons.clone();
                     let lifetime = connection_lifetime;
                     let timer = timer.clone();
+                    let reactor = reactor.clone();
+                    let span = span.clone();
+                    let mirror = mirror.clone();
+                    let router = router.clone();
+                    let dst_name = dst_name.clone();
+                    let transparent_retry = transparent_retry.clone();
+                    let connect_latency = metrics.connect_latency.clone();
+                    let connect_fails = metrics.connect_failures.clone();
+                    let hooks = hooks.clone();
+                    let summary = summary.clone();
+                    let handoffs = handoffs.clone();
                     connect.and_then(move |(src, dst)| {
-                        // Enforce a timeout on total connection lifetime.
                         let dst_addr = dst.peer_addr();
-                        let duplex = src.into_duplex(dst, buf);
-                        duration.time(timeout(duplex, lifetime, &timer)).then(
-                            move |res| match res {
-                                Ok(_) => {
-                                    trace!("stream succeeded for {} to {}", src_addr, dst_addr);
-                                    Ok(())
+                        debug!(
+                            "streaming from {} to {} (ALPN: {:?})",
+                            src_addr,
+                            dst_addr,
+                            src.ctx.alpn
+                        );
+
+                        // Enforce a timeout on total connection lifetime. The balancer
+                        // may also close the connection to rebalance its endpoints.
+                        let duration = src.ctx.metrics.duration.clone();
+                        let tee = mirror.as_ref().and_then(|m| {
+                            m.sample(src_addr, &router, connect_timeout, &reactor, &timer)
+                        });
+                        let (duplex, close_reason) = match transparent_retry {
+                            None => {
+                                let eviction = dst.ctx.eviction();
+                                let mut duplex = src.into_duplex(
+                                    dst,
+                                    bufs,
+                                    write_timeout,
+                                    integrity.as_ref(),
+                                    Some(eviction),
+                                    &timer,
+                                    write_coalescing.map(|c| (c, reactor.clone())),
+                                    tee,
+                                    sampler,
+                                );
+                                if let Some(ref h) = handoffs {
+                                    duplex.hand_off_with(h.request(dst_name.as_str()));
                                 }
-                                Err(e) => {
-                                    trace!("stream failed for {} to {}: {}", src_addr, dst_addr, e);
-                                    stream_fails.record(&e);
-                                    Err(e)
+                                let close_reason = duplex.close_reason();
+                                (future::Either::A(duplex), close_reason)
+                            }
+                            Some(ref retries) => {
+                                // Retried streams are re-dispatched to another endpoint of
+                                // the same destination, bypassing the dispatch queue, before
+                                // the deadline set for the first dispatch. Only the first
+                                // attempt is mirrored.
+                                let sni = src.socket.sni_hostname().map(|s| s.to_owned());
+                                let deadline = connect_timeout.map(|t| accepted_at + t);
+                                let handoff_dst = dst_name.as_str().to_owned();
+                                let dispatch = {
+                                    let router = router.clone();
+                                    let reactor = reactor.clone();
+                                    let timer = timer.clone();
+                                    let span = span.clone();
+                                    move |failed| {
+                                        let sni = sni.clone();
+                                        let connect = router
+                                            .route(&dst_name, &reactor, &timer)
+                                            .and_then(move |b| {
+                                                b.retry_from(&src_addr, sni, &failed)
+                                                    .map_err(io::Error::from)
+                                            });
+                                        let remaining = deadline.map(|d| {
+                                            let now = Instant::now();
+                                            if d > now {
+                                                d - now
+                                            } else {
+                                                Duration::from_secs(0)
+                                            }
+                                        });
+                                        let connect = timeout(
+                                            connect_latency.time(connect),
+                                            remaining,
+                                            &timer,
+                                        );
+                                        let fails = connect_fails.clone();
+                                        let span = span.clone();
+                                        let hooks = hooks.clone();
+                                        let summary = summary.clone();
+                                        connect.then(move |res| match res {
+                                            Ok(dst) => {
+                                                record_dispatch(
+                                                    &src_addr,
+                                                    dst.peer_addr(),
+                                                    dst.ctx.generation(),
+                                                    &span,
+                                                    &hooks,
+                                                    &summary,
+                                                );
+                                                Ok(dst)
+                                            }
+                                            Err(e) => {
+                                                debug!("retry failed for {}: {}", src_addr, e);
+                                                fails.record(&e);
+                                                Err(e)
+                                            }
+                                        })
+                                    }
+                                };
+                                let tee = RefCell::new(tee);
+                                let timer = timer.clone();
+                                let reactor = reactor.clone();
+                                let retrying = retries.stream(
+                                    src,
+                                    dst,
+                                    sampler,
+                                    move |src, dst, sampler, close, retryable| {
+                                        let eviction = dst.ctx.eviction();
+                                        let mut duplex = duplex::resume(
+                                            src,
+                                            dst,
+                                            bufs.clone(),
+                                            write_timeout,
+                                            integrity.as_ref(),
+                                            Some(eviction),
+                                            &timer,
+                                            write_coalescing.map(|c| (c, reactor.clone())),
+                                            tee.borrow_mut().take(),
+                                            sampler,
+                                            close,
+                                            retryable,
+                                        );
+                                        if let Some(ref h) = handoffs {
+                                            duplex.hand_off_with(h.request(&handoff_dst));
+                                        }
+                                        duplex
+                                    },
+                                    dispatch,
+                                );
+                                let close_reason = retrying.close_reason();
+                                (future::Either::B(retrying), close_reason)
+                            }
+                        };
+                        let stream = duration.time(timeout(duplex, lifetime, &timer)).then(
+                            move |res| {
+                                // If the stream ended without either half observing a
+                                // close, it was timed out by its lifetime.
+                                let reason = close_reason.get().unwrap_or_else(|| match res {
+                                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                                        CloseReason::MaxAge
+                                    }
+                                    Err(ref e) => CloseReason::Error(e.kind()),
+                                    Ok(_) => CloseReason::Error(io::ErrorKind::Other),
+                                });
+                                debug!(
+                                    "connection from {} to {} closed: {}",
+                                    src_addr,
+                                    dst_addr,
+                                    reason
+                                );
+                                close_reasons.record(reason);
+                                close_reasons.record_directions(&close_reason);
+                                if let Some(ref span) = span {
+                                    span.close(reason);
+                                }
+                                match res {
+                                    Ok(_) => {
+                                        trace!("stream succeeded for {} to {}", src_addr, dst_addr);
+                                        Ok(())
+                                    }
+                                    Err(e) => {
+                                        trace!(
+                                            "stream failed for {} to {}: {}",
+                                            src_addr,
+                                            dst_addr,
+                                            e
+                                        );
+                                        stream_fails.record(&e);
+                                        Err(e)
+                                    }
                                 }
                             },
-                        )
+                        );
+                        stream
                     })
                 };
 
+                // A connection that panics is torn down as though it had failed, so that
+                // the listener and its other connections are unaffected.
+                let panics = metrics.panics.clone();
+                let stream = AssertUnwindSafe(stream).catch_unwind().then(move |res| {
+                    match res {
+                        Ok(ret) => ret,
+                        Err(payload) => {
+                            error!(
+                                "connection from {} panicked: {}",
+                                src_addr,
+                                supervise::message(&*payload)
+                            );
+                            panics.incr(1);
+                            Err(io::Error::new(io::ErrorKind::Other, "connection panicked"))
+                        }
+                    }
+                });
+
                 let closes = metrics.closes.clone();
                 let failures = metrics.failures.clone();
+                let hooks = hooks.clone();
                 stream.then(move |ret| {
                     active.decr(1);
+                    signals.closed(ret.is_err());
+                    if let Some(reuse) = reuse {
+                        reuse.close(src_addr);
+                    }
+                    if let (Some(hooks), Some(summary)) = (hooks, summary) {
+                        let mut summary = summary.borrow_mut();
+                        summary.duration = accepted_at.elapsed();
+                        summary.error = ret.as_ref().err().map(|e| e.kind());
+                        if let Some(samples) = samples {
+                            let samples = samples.get();
+                            summary.client_tcp_info = samples.client;
+                            summary.endpoint_tcp_info = samples.endpoint;
+                        }
+                        hooks.close(&summary);
+                    }
                     if ret.is_ok() {
                         closes.incr(1);
                     } else {
                         failures.incr(1);
                     }
+                    // Spans of streams refused before they were proxied are completed
+                    // here; other spans have already been completed.
+                    if let (Some(span), Err(e)) = (span, ret) {
+                        span.close(CloseReason::Error(e.kind()));
+                    }
                     Ok(())
                 })
             })
-            .buffer_unordered(self.max_concurrency);
-
-        Ok(Bound(Box::new(serving)))
+            .buffer_unordered(self.policy.max_concurrency);
+        Box::new(serving)
     }
 }
 
-pub struct Bound(Box<Stream<Item = (), Error = io::Error> + 'static>);
+pub struct Bound {
+    local_addr: net::SocketAddr,
+    serving: Box<Future<Item = (), Error = ()>>,
+}
+impl Bound {
+    /// Serves each item of the stream built by `serve` until it completes. Errors are
+    /// ignored.
+    ///
+    /// A listener that panics is counted as `listener_panics` and is rebuilt by `serve`
+    /// after a backoff. Its open connections are dropped with it, since they may have
+    /// been left inconsistent.
+    fn new<M, S>(
+        local_addr: net::SocketAddr,
+        mut serve: M,
+        metrics: &tacho::Scope,
+        timer: &Timer,
+    ) -> Bound
+    where
+        M: FnMut() -> S + 'static,
+        S: Stream<Item = (), Error = io::Error> + 'static,
+    {
+        let serving = move || {
+            serve().then(|_| Ok::<(), ()>(())).for_each(|_| Ok::<(), ()>(()))
+        };
+        let panics: Arc<metrics::Counter> = Arc::new(metrics.counter("listener_panics"));
+        Bound {
+            local_addr,
+            serving: Box::new(supervise::restart(
+                format!("listener on {}", local_addr),
+                serving,
+                timer,
+                panics,
+            )),
+        }
+    }
+
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+}
 impl Future for Bound {
     type Item = ();
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        loop {
-            match self.0.poll() {
-                Ok(Async::NotReady) => return Ok(Async::NotReady),
-                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
-                Ok(Async::Ready(Some(_))) |
-                Err(_) => {}
-            }
-        }
+        self.serving.poll().map_err(|()| {
+            io::Error::new(io::ErrorKind::Other, "listener failed")
+        })
+    }
+}
+
+/// Records that the connection from `src_addr` was dispatched to `dst_addr`, which was
+/// selected from the endpoints' `generation`.
+fn record_dispatch(
+    src_addr: &net::SocketAddr,
+    dst_addr: net::SocketAddr,
+    generation: Option<Generation>,
+    span: &Option<Span>,
+    hooks: &Option<BoundHooks>,
+    summary: &Option<Rc<RefCell<ConnectionSummary>>>,
+) {
+    if let Some(ref span) = *span {
+        span.connected(dst_addr, generation);
+    }
+    if let Some(ref hooks) = *hooks {
+        hooks.dispatch(src_addr, &dst_addr);
+    }
+    if let Some(ref summary) = *summary {
+        let mut summary = summary.borrow_mut();
+        summary.endpoint_addr = Some(dst_addr);
+        summary.resolution = generation;
     }
 }
 
@@ -254,28 +782,87 @@ struct Metrics {
     accepts: tacho::Counter,
     closes: tacho::Counter,
     failures: tacho::Counter,
+    panics: tacho::Counter,
     active: tacho::Gauge,
     waiters: tacho::Gauge,
-    per_conn: ConnMetrics,
+    connect_latency: tacho::Timer,
+    close_reasons: CloseReasonMetrics,
+    per_conn: StreamMetrics,
     connect_failures: FailureMetrics,
     stream_failures: FailureMetrics,
+    transfer_sizes: Option<TransferSizes>,
+}
+
+/// Counts torn-down connections by reason, and the directions of connections by the
+/// reason each finished.
+#[derive(Clone)]
+struct CloseReasonMetrics {
+    reasons: Rc<HashMap<&'static str, tacho::Counter>>,
+    directions: Rc<HashMap<(&'static str, &'static str), tacho::Counter>>,
+}
+impl CloseReasonMetrics {
+    fn new(metrics: &tacho::Scope) -> CloseReasonMetrics {
+        let reasons = CloseReason::distinct()
+            .iter()
+            .map(|r| {
+                let c = metrics.clone().labeled("reason", r.as_str()).counter("close_reasons");
+                (r.as_str(), c)
+            })
+            .collect();
+        let mut directions = HashMap::new();
+        for peer in &[Peer::Client, Peer::Server] {
+            for r in &CloseReason::distinct() {
+                let c = metrics
+                    .clone()
+                    .labeled("direction", peer.direction())
+                    .labeled("reason", r.as_str())
+                    .counter("close_reasons_by_direction");
+                directions.insert((peer.direction(), r.as_str()), c);
+            }
+        }
+        CloseReasonMetrics {
+            reasons: Rc::new(reasons),
+            directions: Rc::new(directions),
+        }
+    }
+
+    fn record(&self, reason: CloseReason) {
+        if let Some(c) = self.reasons.get(reason.as_str()) {
+            c.incr(1);
+        }
+    }
+
+    /// Records how each direction of a connection finished, if it did.
+    fn record_directions(&self, close: &CloseReasonCell) {
+        for peer in &[Peer::Client, Peer::Server] {
+            if let Some(reason) = close.direction(*peer) {
+                if let Some(c) = self.directions.get(&(peer.direction(), reason.as_str())) {
+                    c.incr(1);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 struct FailureMetrics {
     timeouts: tacho::Counter,
+    write_timeouts: tacho::Counter,
     other: tacho::Counter,
 }
 impl FailureMetrics {
     fn new(metrics: &tacho::Scope, key: &'static str) -> FailureMetrics {
         FailureMetrics {
             timeouts: metrics.clone().labeled("cause", "timeout").counter(key),
+            write_timeouts: metrics.clone().labeled("cause", "write_timeout").counter(key),
             other: metrics.clone().labeled("cause", "other").counter(key),
         }
     }
 
     fn record(&self, e: &io::Error) {
-        if e.kind() == io::ErrorKind::TimedOut {
+        if WriteTimeout::is(e) {
+            self.write_timeouts.incr(1);
+        } else if e.kind() == io::ErrorKind::TimedOut {
             self.timeouts.incr(1);
         } else {
             self.other.incr(1);
@@ -283,6 +870,41 @@ impl FailureMetrics {
     }
 }
 
+/// Per-connection stream metrics. Servers that negotiate ALPN protocols label these
+/// metrics by the negotiated protocol, or `none`.
+#[derive(Clone)]
+struct StreamMetrics {
+    none: ConnMetrics,
+    protocols: Rc<Vec<(String, ConnMetrics)>>,
+}
+impl StreamMetrics {
+    fn new(metrics: &tacho::Scope, protocols: &[String]) -> StreamMetrics {
+        if protocols.is_empty() {
+            return StreamMetrics {
+                none: ConnMetrics::new(metrics),
+                protocols: Rc::new(Vec::new()),
+            };
+        }
+
+        let labeled = |p: &str| ConnMetrics::new(&metrics.clone().labeled("alpn", p.to_owned()));
+        StreamMetrics {
+            none: labeled("none"),
+            protocols: Rc::new(
+                protocols
+                    .iter()
+                    .map(|p| (p.clone(), labeled(p.as_str())))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn get(&self, alpn: Option<&str>) -> &ConnMetrics {
+        alpn.and_then(|alpn| self.protocols.iter().find(|&&(ref p, _)| p == alpn))
+            .map(|&(_, ref m)| m)
+            .unwrap_or(&self.none)
+    }
+}
+
 #[derive(Clone)]
 struct ConnMetrics {
     rx_bytes: tacho::Counter,
@@ -290,52 +912,266 @@ struct ConnMetrics {
     rx_bytes_per_conn: tacho::Stat,
     tx_bytes_per_conn: tacho::Stat,
     duration: tacho::Timer,
-    latency: tacho::Timer,
 }
+impl ConnMetrics {
+    fn new(metrics: &tacho::Scope) -> ConnMetrics {
+        ConnMetrics {
+            rx_bytes: metrics.counter("rx_bytes"),
+            tx_bytes: metrics.counter("tx_bytes"),
+            rx_bytes_per_conn: metrics.stat("connection_rx_bytes"),
+            tx_bytes_per_conn: metrics.stat("connection_tx_bytes"),
+            duration: metrics.timer_ms("duration_ms"),
+        }
+    }
+}
+
+
+#[cfg(feature = "tls")]
+pub use self::tls::{BoundTls, UnboundTls};
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::super::connection::{Socket, socket};
+    use super::handshake::{self, HandshakeFailures};
+    use super::handshake_limit::{HandshakeLimit, InFlight};
+    use super::identity::AgentIdentity;
+    use super::resumption::Resumption;
+    use super::tls_policy::{self, Negotiations, TlsPolicy, TlsProperties};
+    use futures::Future;
+    use rustls;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tacho;
+    use tokio_core::net::TcpStream;
+    use tokio_timer::Timer;
+
+    #[derive(Clone)]
+    pub struct UnboundTls {
+        pub cert_resolver: Arc<rustls::ResolvesServerCert>,
+        /// Identities that are provided by agents once the server is bound.
+        pub agents: Vec<AgentIdentity>,
+        pub alpn_protocols: Vec<String>,
+        pub require_alpn: bool,
+        pub resumption: Resumption,
+        /// Overrides the server's connect timeout as the bound on each handshake.
+        pub handshake_timeout: Option<Duration>,
+        pub max_concurrent_handshakes: Option<usize>,
+        pub policy: TlsPolicy,
+    }
 
-fn timeout<F>(
-    fut: F,
-    timeout: Option<Duration>,
-    timer: &Timer,
-) -> Box<Future<Item = F::Item, Error = io::Error>>
-where
-    F: Future<Error = io::Error> + 'static,
-{
-    match timeout {
-        None => Box::new(fut),
-        Some(duration) => {
-            let timer = timer.clone();
-            let fut = future::lazy(move || timer.timeout(fut, duration));
-            Box::new(fut)
+    impl UnboundTls {
+        /// Builds the rustls configuration, so that session resumption may be
+        /// reported with the bound server's metrics.
+        ///
+        /// Handshakes are failed if they do not complete within the configured handshake
+        /// timeout or, by default, the server's `connect_timeout`.
+        ///
+        /// Fails if an agent cannot provide an identity that the server requires to start.
+        pub fn bind(
+            self,
+            connect_timeout: Option<Duration>,
+            timer: &Timer,
+            metrics: &tacho::Scope,
+        ) -> io::Result<BoundTls> {
+            for agent in &self.agents {
+                agent.start()?;
+            }
+
+            let mut config = rustls::ServerConfig::new();
+            if !self.alpn_protocols.is_empty() {
+                config.set_protocols(&self.alpn_protocols);
+            }
+            config.cert_resolver = self.cert_resolver;
+            self.resumption.configure(&mut config, metrics);
+
+            let tls_metrics = metrics.clone().prefixed("tls");
+            Ok(BoundTls {
+                config: Arc::new(config),
+                alpn_protocols: self.alpn_protocols,
+                require_alpn: self.require_alpn,
+                handshakes: tls_metrics.counter("handshakes"),
+                handshake_latency: tls_metrics.timer_us("handshake_us"),
+                handshake_failures: HandshakeFailures::new(&tls_metrics),
+                handshake_timeout: self.handshake_timeout.or(connect_timeout),
+                handshake_limit: HandshakeLimit::new(
+                    self.max_concurrent_handshakes,
+                    &tls_metrics,
+                ),
+                timer: timer.clone(),
+                alpn_refused: metrics.clone().labeled("cause", "alpn").counter("refused"),
+                policy: self.policy,
+                negotiations: Negotiations::new(&tls_metrics),
+                policy_refused: metrics.clone().labeled("cause", "tls_policy").counter(
+                    "refused",
+                ),
+            })
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct BoundTls {
+        config: Arc<rustls::ServerConfig>,
+        alpn_protocols: Vec<String>,
+        require_alpn: bool,
+        /// Completed handshakes. Those that were not counted by `tls_resumptions` were
+        /// full handshakes.
+        handshakes: tacho::Counter,
+        handshake_latency: tacho::Timer,
+        handshake_failures: HandshakeFailures,
+        handshake_timeout: Option<Duration>,
+        handshake_limit: HandshakeLimit,
+        timer: Timer,
+        alpn_refused: tacho::Counter,
+        policy: TlsPolicy,
+        /// Counts the versions and cipher suites of completed handshakes, including those
+        /// that are then refused.
+        negotiations: Negotiations,
+        policy_refused: tacho::Counter,
+    }
+
+    impl BoundTls {
+        pub fn alpn_protocols(&self) -> &[String] {
+            &self.alpn_protocols
+        }
+
+        pub fn handshake_limit(&self) -> HandshakeLimit {
+            self.handshake_limit.clone()
+        }
+
+        /// Performs a handshake, which is in flight until `in_flight` is dropped as it
+        /// finishes, and describes its session.
+        ///
+        /// Fails if the session violates the server's policy.
+        pub fn handshake(
+            &self,
+            tcp: TcpStream,
+            in_flight: Option<InFlight>,
+        ) -> Box<Future<Item = (Socket, TlsProperties), Error = io::Error>> {
+            let require_alpn = self.require_alpn;
+            let handshakes = self.handshakes.clone();
+            let alpn_refused = self.alpn_refused.clone();
+            let policy = self.policy.clone();
+            let negotiations = self.negotiations.clone();
+            let policy_refused = self.policy_refused.clone();
+            let hs = handshake::handshake(
+                tcp,
+                &self.config,
+                self.handshake_timeout,
+                &self.timer,
+                &self.handshake_failures,
+            );
+            let hs = hs.then(move |res| {
+                drop(in_flight);
+                res
+            });
+            let sock = self.handshake_latency
+                .time(hs)
+                .and_then(move |tls| {
+                    handshakes.incr(1);
+                    // rustls completes handshakes in which no protocol is agreed upon,
+                    // so such connections are refused once the handshake completes.
+                    if require_alpn && tls.alpn_protocol().is_none() {
+                        debug!("refusing connection from {}: no ALPN protocol", tls.peer_addr());
+                        alpn_refused.incr(1);
+                        let e = io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "client offered no supported ALPN protocol",
+                        );
+                        return Err(e);
+                    }
+
+                    let props = tls_policy::properties(&tls);
+                    negotiations.record(&props);
+                    if let Err(v) = policy.check(&props) {
+                        debug!("refusing connection from {}: {}", tls.peer_addr(), v);
+                        policy_refused.incr(1);
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied, v.to_string()));
+                    }
+                    Ok((socket::secure_server(tls), props))
+                });
+            Box::new(sock)
         }
     }
 }
 
+/// Stands in for server TLS configuration when built without TLS support.
+///
+/// Configurations including TLS fail to load, so this can never be constructed.
+#[cfg(not(feature = "tls"))]
 #[derive(Clone)]
-pub struct UnboundTls {
-    config: Arc<rustls::ServerConfig>,
+pub enum UnboundTls {}
+
+#[cfg(not(feature = "tls"))]
+impl UnboundTls {
+    fn bind(
+        self,
+        _timeout: Option<Duration>,
+        _timer: &Timer,
+        _metrics: &tacho::Scope,
+    ) -> io::Result<BoundTls> {
+        match self {}
+    }
 }
 
+#[cfg(not(feature = "tls"))]
 #[derive(Clone)]
-pub struct BoundTls {
-    config: Arc<rustls::ServerConfig>,
-    handshake_latency: tacho::Timer,
+pub enum BoundTls {}
+
+#[cfg(not(feature = "tls"))]
+impl BoundTls {
+    fn alpn_protocols(&self) -> &[String] {
+        match *self {}
+    }
+
+    fn handshake_limit(&self) -> handshake_limit::HandshakeLimit {
+        match *self {}
+    }
+
+    fn handshake(
+        &self,
+        _tcp: TcpStream,
+        _in_flight: Option<InFlight>,
+    ) -> Box<Future<Item = (Socket, TlsProperties), Error = io::Error>> {
+        match *self {}
+    }
 }
 
 pub struct SrcCtx {
     rx_bytes_total: usize,
     tx_bytes_total: usize,
     metrics: ConnMetrics,
+    transfer_sizes: Option<TransferSizes>,
+    /// The protocol negotiated via ALPN, if any.
+    alpn: Option<String>,
+    span: Option<Span>,
+    /// Set when the connection is summarized for hooks.
+    summary: Option<Rc<RefCell<ConnectionSummary>>>,
+    signals: Arc<Signals>,
 }
 impl ctx::Ctx for SrcCtx {
     fn read(&mut self, sz: usize) {
         self.rx_bytes_total += sz;
         self.metrics.rx_bytes.incr(sz);
+        self.signals.read(sz);
+        if let Some(ref span) = self.span {
+            span.client_byte();
+        }
+        if let Some(ref summary) = self.summary {
+            summary.borrow_mut().rx_bytes += sz as u64;
+        }
     }
 
     fn wrote(&mut self, sz: usize) {
         self.tx_bytes_total += sz;
         self.metrics.tx_bytes.incr(sz);
+        self.signals.wrote(sz);
+        if let Some(ref span) = self.span {
+            span.server_byte();
+        }
+        if let Some(ref summary) = self.summary {
+            summary.borrow_mut().tx_bytes += sz as u64;
+        }
     }
 }
 impl Drop for SrcCtx {
@@ -346,5 +1182,8 @@ impl Drop for SrcCtx {
         self.metrics.tx_bytes_per_conn.add(
             self.tx_bytes_total as u64,
         );
+        if let Some(ref sizes) = self.transfer_sizes {
+            sizes.record(self.rx_bytes_total as u64, self.tx_bytes_total as u64);
+        }
     }
 }
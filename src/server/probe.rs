@@ -0,0 +1,121 @@
+//! Closes health checkers' connections without dispatching them upstream.
+//!
+//! Load balancers and orchestrators often check that a port is open by connecting and
+//! then closing without sending anything. Connections from the configured source ranges
+//! are held until they send their first bytes; those that send nothing within the idle
+//! window (or that close first) are closed locally, without being routed or consuming
+//! an upstream connection. Connections from other sources are dispatched immediately.
+//!
+//! Protocols in which the server speaks first cannot be served to probed ranges, since
+//! such clients never send bytes before the idle window expires.
+
+use super::super::connector::Cidr;
+use futures::{Async, Future, Poll};
+use std::{io, net};
+use std::rc::Rc;
+use std::time::Duration;
+use tacho;
+use tokio_core::net::TcpStream;
+use tokio_timer::{Sleep, Timer};
+
+pub const DEFAULT_IDLE_MS: u64 = 100;
+
+/// Describes which connections may be health checks.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub from: Vec<Cidr>,
+    pub idle: Duration,
+}
+
+impl Policy {
+    /// Counts connections closed locally as `probe_connections`.
+    pub fn bind(self, timer: &Timer, metrics: &tacho::Scope) -> ProbeFilter {
+        ProbeFilter {
+            from: Rc::new(self.from),
+            idle: self.idle,
+            timer: timer.clone(),
+            probes: metrics.counter("probe_connections"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProbeFilter {
+    from: Rc<Vec<Cidr>>,
+    idle: Duration,
+    timer: Timer,
+    probes: tacho::Counter,
+}
+
+impl ProbeFilter {
+    /// Holds a connection from a probed range until it has sent data.
+    pub fn accept(&self, tcp: TcpStream, src_addr: net::SocketAddr) -> Accept {
+        let ip = src_addr.ip();
+        let sleep = if self.from.iter().any(|cidr| cidr.contains(&ip)) {
+            Some(self.timer.sleep(self.idle))
+        } else {
+            None
+        };
+        Accept {
+            conn: Some((tcp, src_addr)),
+            sleep,
+            probes: self.probes.clone(),
+        }
+    }
+}
+
+/// Completes with the connection once it may be dispatched, or with nothing if it was
+/// closed as a probe.
+pub struct Accept {
+    conn: Option<(TcpStream, net::SocketAddr)>,
+    /// Unset for connections that are dispatched immediately.
+    sleep: Option<Sleep>,
+    probes: tacho::Counter,
+}
+
+impl Accept {
+    fn close(&mut self) -> Poll<Option<(TcpStream, net::SocketAddr)>, io::Error> {
+        if let Some((_, src_addr)) = self.conn.take() {
+            debug!("closing probe connection from {}", src_addr);
+            self.probes.incr(1);
+        }
+        Ok(Async::Ready(None))
+    }
+}
+
+impl Future for Accept {
+    type Item = Option<(TcpStream, net::SocketAddr)>;
+    /// Never fails, so that the accept stream is not interrupted.
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        if self.sleep.is_none() {
+            return Ok(Async::Ready(self.conn.take()));
+        }
+
+        let peeked = {
+            let &(ref tcp, _) = self.conn.as_ref().expect("polled after completion");
+            let mut buf = [0u8; 1];
+            tcp.peek(&mut buf)
+        };
+        match peeked {
+            Ok(0) => return self.close(),
+            Ok(_) => return Ok(Async::Ready(self.conn.take())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                debug!("probe connection failed: {}", e);
+                return self.close();
+            }
+        }
+
+        match self.sleep.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => self.close(),
+            Err(e) => {
+                // Rather than closing clients that may not be probes, dispatch them.
+                warn!("probe timer failed: {}", e);
+                Ok(Async::Ready(self.conn.take()))
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! TLS session resumption for servers.
+//!
+//! Clients may resume sessions either by presenting a ticket, encrypted with a key that
+//! is rotated every `ticket_rotation`, or by presenting a session ID held in a bounded
+//! in-memory cache.
+
+use rustls::{self, ProducesTickets, StoresServerSessions};
+use rustls::internal::msgs::handshake::SessionID;
+use std::cmp;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tacho;
+
+pub const DEFAULT_TICKET_ROTATION_SECS: u64 = 60 * 60;
+
+/// Matches rustls's default.
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct Resumption {
+    pub tickets: bool,
+    pub ticket_rotation: Duration,
+    /// When 0, session IDs are not cached.
+    pub session_cache_size: usize,
+}
+
+impl Default for Resumption {
+    fn default() -> Resumption {
+        Resumption {
+            tickets: false,
+            ticket_rotation: Duration::from_secs(DEFAULT_TICKET_ROTATION_SECS),
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+        }
+    }
+}
+
+impl Resumption {
+    /// Configures `tls` to resume sessions, counting resumptions as
+    /// `tls_resumptions{via}`.
+    pub fn configure(&self, tls: &mut rustls::ServerConfig, metrics: &tacho::Scope) {
+        let metrics = metrics.clone().prefixed("tls");
+        if self.session_cache_size == 0 {
+            tls.set_persistence(Arc::new(rustls::NoServerSessionStorage {}));
+        } else {
+            tls.set_persistence(Arc::new(CountingCache {
+                cache: rustls::ServerSessionMemoryCache::new(self.session_cache_size),
+                resumptions: metrics.clone().labeled("via", "session_id").counter(
+                    "resumptions",
+                ),
+            }));
+        }
+        if self.tickets {
+            let resumptions = metrics.labeled("via", "ticket").counter("resumptions");
+            tls.ticketer = Arc::new(RotatingTicketer::new(self.ticket_rotation, resumptions));
+        }
+    }
+}
+
+/// Counts the sessions found in a session cache.
+struct CountingCache {
+    cache: Arc<StoresServerSessions>,
+    resumptions: tacho::Counter,
+}
+
+impl StoresServerSessions for CountingCache {
+    fn generate(&self) -> SessionID {
+        self.cache.generate()
+    }
+
+    fn put(&self, id: Vec<u8>, sec: Vec<u8>) -> bool {
+        self.cache.put(id, sec)
+    }
+
+    fn get(&self, id: &[u8]) -> Option<Vec<u8>> {
+        let sec = self.cache.get(id);
+        if sec.is_some() {
+            self.resumptions.incr(1);
+        }
+        sec
+    }
+}
+
+/// Issues tickets with a key that is replaced every `period`.
+///
+/// Tickets issued with the previous key are still accepted, so that each ticket is
+/// valid for at least one period.
+struct RotatingTicketer {
+    period: Duration,
+    keys: Mutex<Keys>,
+    resumptions: tacho::Counter,
+}
+
+struct Keys {
+    current: Arc<ProducesTickets>,
+    previous: Option<Arc<ProducesTickets>>,
+    rotate_at: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(period: Duration, resumptions: tacho::Counter) -> RotatingTicketer {
+        let keys = Keys {
+            current: rustls::Ticketer::new(),
+            previous: None,
+            rotate_at: Instant::now() + period,
+        };
+        RotatingTicketer {
+            period,
+            keys: Mutex::new(keys),
+            resumptions,
+        }
+    }
+
+    /// Returns the ticket keys, rotating them first if they are due.
+    fn keys(&self) -> MutexGuard<Keys> {
+        let mut keys = self.keys.lock().expect("ticket keys lock poisoned");
+        let now = Instant::now();
+        if now >= keys.rotate_at {
+            debug!("rotating tls ticket keys");
+            // A key that should have been rotated out a full period ago is dropped.
+            let previous = if now < keys.rotate_at + self.period {
+                Some(keys.current.clone())
+            } else {
+                None
+            };
+            keys.previous = previous;
+            keys.current = rustls::Ticketer::new();
+            keys.rotate_at = now + self.period;
+        }
+        keys
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        cmp::min(self.period.as_secs(), ::std::u32::MAX as u64) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys().current.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys();
+        let plain = keys.current.decrypt(cipher).or_else(|| {
+            keys.previous.as_ref().and_then(|p| p.decrypt(cipher))
+        });
+        if plain.is_some() {
+            self.resumptions.incr(1);
+        }
+        plain
+    }
+}
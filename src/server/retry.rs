@@ -0,0 +1,197 @@
+//! Transparently retries streams whose endpoints fail before any bytes are forwarded,
+//! e.g. because an endpoint accepted a connection while shutting down.
+//!
+//! Until the first bytes are written to either peer, the bytes read from the client are
+//! retained, up to `retryBufferBytes`. If the endpoint resets the stream within that
+//! window, the stream is re-dispatched to another endpoint of the same destination, before
+//! the deadline the server's `connectTimeoutMs` set for its first dispatch, and the
+//! retained bytes are replayed to it. Each stream is retried at most `maxRetries` times; a
+//! stream that has written any bytes, or whose client sent more than `retryBufferBytes`
+//! before it was written to, is never retried.
+//!
+//! Retries are counted as `transparent_retries`.
+
+use super::super::connection::{CloseReasonCell, Connection, Ctx, Sampler};
+use super::super::connection::duplex::{Duplex, Retryable, Summary};
+use futures::{Async, Future, Poll};
+use std::cell::RefCell;
+use std::{io, net};
+use std::rc::Rc;
+use tacho;
+
+pub const DEFAULT_RETRY_BUFFER_BYTES: usize = 16 * 1024;
+pub const DEFAULT_MAX_RETRIES: usize = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub retry_buffer_bytes: usize,
+    pub max_retries: usize,
+}
+
+impl Policy {
+    pub fn bind(self, metrics: &tacho::Scope) -> Retries {
+        Retries {
+            policy: self,
+            retries: metrics.counter("transparent_retries"),
+        }
+    }
+}
+
+/// Streams that may be retried.
+#[derive(Clone)]
+pub struct Retries {
+    policy: Policy,
+    retries: tacho::Counter,
+}
+
+impl Retries {
+    /// Streams between `src` and `dst`.
+    ///
+    /// Each attempt's duplex is built by `mk_duplex` from the client's connection, an
+    /// endpoint's connection, the sampler, the stream's close reasons, and whether the
+    /// attempt may be retried. A stream is re-dispatched by `dispatch`, given the address of
+    /// the endpoint that failed it.
+    pub fn stream<S, D, M, R, F>(
+        &self,
+        src: Connection<S>,
+        dst: Connection<D>,
+        sampler: Option<Sampler>,
+        mk_duplex: M,
+        dispatch: R,
+    ) -> Retrying<S, D, M, R, F>
+    where
+        S: Ctx,
+        D: Ctx,
+        M: Fn(Rc<RefCell<Connection<S>>>,
+           Connection<D>,
+           Option<Sampler>,
+           CloseReasonCell,
+           bool)
+           -> Duplex<S, D>,
+        R: Fn(net::SocketAddr) -> F,
+        F: Future<Item = Connection<D>, Error = io::Error>,
+    {
+        let remaining = self.policy.max_retries;
+        let endpoint = dst.peer_addr();
+        let mut src = src;
+        if remaining > 0 {
+            src.socket.retain(self.policy.retry_buffer_bytes);
+        }
+        let src = Rc::new(RefCell::new(src));
+        let close = CloseReasonCell::default();
+        let duplex = mk_duplex(src.clone(), dst, sampler, close.clone(), remaining > 0);
+        Retrying {
+            src,
+            state: State::Streaming(duplex),
+            endpoint,
+            sampler: None,
+            close,
+            remaining,
+            retries: self.retries.clone(),
+            mk_duplex,
+            dispatch,
+        }
+    }
+}
+
+/// A stream that is retried against another endpoint when its endpoint resets it before
+/// any bytes are forwarded.
+pub struct Retrying<S, D, M, R, F> {
+    src: Rc<RefCell<Connection<S>>>,
+    state: State<S, D, F>,
+    /// The endpoint of the current attempt.
+    endpoint: net::SocketAddr,
+    /// Holds the sampler while the stream is re-dispatched.
+    sampler: Option<Sampler>,
+    close: CloseReasonCell,
+    remaining: usize,
+    retries: tacho::Counter,
+    mk_duplex: M,
+    dispatch: R,
+}
+
+enum State<S, D, F> {
+    Streaming(Duplex<S, D>),
+    Dispatching(F),
+}
+
+impl<S, D, M, R, F> Retrying<S, D, M, R, F> {
+    /// Holds the reason the stream is torn down, once it is known.
+    ///
+    /// The reasons of attempts that are retried are forgotten once the stream is
+    /// re-dispatched.
+    pub fn close_reason(&self) -> CloseReasonCell {
+        self.close.clone()
+    }
+}
+
+impl<S, D, M, R, F> Future for Retrying<S, D, M, R, F>
+where
+    S: Ctx,
+    D: Ctx,
+    M: Fn(Rc<RefCell<Connection<S>>>,
+       Connection<D>,
+       Option<Sampler>,
+       CloseReasonCell,
+       bool)
+       -> Duplex<S, D>,
+    R: Fn(net::SocketAddr) -> F,
+    F: Future<Item = Connection<D>, Error = io::Error>,
+{
+    type Item = Summary;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Summary, io::Error> {
+        loop {
+            let dst = match self.state {
+                State::Streaming(ref mut duplex) => {
+                    match duplex.poll() {
+                        Err(ref e) if Retryable::is(e) => {
+                            debug!("retrying stream: {}", e);
+                            self.sampler = duplex.take_sampler();
+                            None
+                        }
+                        ret => return ret,
+                    }
+                }
+                State::Dispatching(ref mut connect) => {
+                    // A stream that cannot be re-dispatched fails, keeping the close
+                    // reasons of the attempt that was reset.
+                    match connect.poll()? {
+                        Async::NotReady => return Ok(Async::NotReady),
+                        Async::Ready(dst) => Some(dst),
+                    }
+                }
+            };
+
+            match dst {
+                None => {
+                    // The failed attempt's duplex releases its endpoint as it is dropped.
+                    if !self.src.borrow_mut().socket.rewind() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionReset,
+                            "endpoint reset the stream",
+                        ));
+                    }
+                    self.remaining -= 1;
+                    self.retries.incr(1);
+                    self.state = State::Dispatching((self.dispatch)(self.endpoint));
+                }
+                Some(dst) => {
+                    if self.remaining == 0 {
+                        self.src.borrow_mut().socket.release();
+                    }
+                    self.close.clear();
+                    self.endpoint = dst.peer_addr();
+                    let duplex = (self.mk_duplex)(
+                        self.src.clone(),
+                        dst,
+                        self.sampler.take(),
+                        self.close.clone(),
+                        self.remaining > 0,
+                    );
+                    self.state = State::Streaming(duplex);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Detects clients that reuse source ports soon after closing connections.
+//!
+//! A client that reconnects from the same address and port shortly after its previous
+//! connection closed may be violating TCP's TIME_WAIT, e.g. behind a NAT that
+//! allocates ports too aggressively. Each server remembers when connections from each
+//! source closed, for `reuseWindowMs`, and counts connections that arrive from a source
+//! within that window of its last close.
+//!
+//! Memory is bounded by `maxTracked`: when more sources have closed within the window,
+//! those that closed least recently are forgotten.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::net;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tacho;
+
+pub const DEFAULT_REUSE_WINDOW_MS: u64 = 1_000;
+pub const DEFAULT_MAX_TRACKED: usize = 10_000;
+
+/// Only one in this many reuses is logged, so that a misbehaving client does not flood
+/// the log.
+const LOG_SAMPLE_INTERVAL: u64 = 100;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub window: Duration,
+    pub max_tracked: usize,
+}
+
+impl Policy {
+    /// Counts reused sources as `source_port_reuses`, and sources forgotten to bound
+    /// memory as `source_port_evictions`. The number of sources remembered is reported
+    /// as `source_ports_tracked`.
+    pub fn bind(self, metrics: &tacho::Scope) -> ReuseTracker {
+        ReuseTracker(Rc::new(RefCell::new(Inner {
+            policy: self,
+            closed: HashMap::new(),
+            by_close: BTreeMap::new(),
+            next_seq: 0,
+            reuse_count: 0,
+            reuses: metrics.counter("source_port_reuses"),
+            evictions: metrics.counter("source_port_evictions"),
+            tracked: metrics.gauge("source_ports_tracked"),
+        })))
+    }
+}
+
+/// Remembers when connections from each source closed.
+#[derive(Clone)]
+pub struct ReuseTracker(Rc<RefCell<Inner>>);
+
+struct Inner {
+    policy: Policy,
+    /// The time and sequence number of each source's most recent close.
+    closed: HashMap<net::SocketAddr, (Instant, u64)>,
+    /// Sources ordered by their most recent close, least recent first.
+    by_close: BTreeMap<(Instant, u64), net::SocketAddr>,
+    next_seq: u64,
+    reuse_count: u64,
+    reuses: tacho::Counter,
+    evictions: tacho::Counter,
+    tracked: tacho::Gauge,
+}
+
+impl ReuseTracker {
+    /// Records a connection accepted from `src`, counting it if `src`'s previous
+    /// connection closed within the window.
+    pub fn accept(&self, src: &net::SocketAddr) {
+        let mut inner = self.0.borrow_mut();
+        let now = Instant::now();
+        inner.expire(now);
+        if let Some(key) = inner.closed.remove(src) {
+            inner.by_close.remove(&key);
+            inner.reuse_count += 1;
+            inner.reuses.incr(1);
+            if inner.reuse_count % LOG_SAMPLE_INTERVAL == 1 {
+                let since = now - key.0;
+                info!(
+                    "source {} reconnected {}ms after its previous connection closed \
+                     ({} reuses)",
+                    src,
+                    since.as_secs() * 1_000 + u64::from(since.subsec_nanos()) / 1_000_000,
+                    inner.reuse_count
+                );
+            }
+        }
+        inner.report();
+    }
+
+    /// Records that a connection from `src` closed.
+    pub fn close(&self, src: net::SocketAddr) {
+        let mut inner = self.0.borrow_mut();
+        let now = Instant::now();
+        inner.expire(now);
+        if let Some(key) = inner.closed.remove(&src) {
+            inner.by_close.remove(&key);
+        }
+        let key = (now, inner.next_seq);
+        inner.next_seq += 1;
+        inner.closed.insert(src, key);
+        inner.by_close.insert(key, src);
+        while inner.closed.len() > inner.policy.max_tracked {
+            inner.evictions.incr(1);
+            inner.pop_least_recent();
+        }
+        inner.report();
+    }
+}
+
+impl Inner {
+    /// Forgets sources that closed before the window.
+    fn expire(&mut self, now: Instant) {
+        loop {
+            let expired = match self.by_close.keys().next() {
+                Some(&(closed_at, _)) => now - closed_at > self.policy.window,
+                None => false,
+            };
+            if !expired {
+                return;
+            }
+            self.pop_least_recent();
+        }
+    }
+
+    fn pop_least_recent(&mut self) {
+        let key = match self.by_close.keys().next() {
+            Some(key) => *key,
+            None => return,
+        };
+        if let Some(src) = self.by_close.remove(&key) {
+            self.closed.remove(&src);
+        }
+    }
+
+    fn report(&self) {
+        self.tracked.set(self.closed.len());
+    }
+}
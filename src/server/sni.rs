@@ -1,9 +1,12 @@
-use super::config::TlsServerIdentityConfig;
-use rustls::{Certificate, ResolvesServerCert, SignatureScheme, sign};
+use super::chain;
+use super::config::{IdentitySourceConfig, TlsServerIdentityConfig};
+use super::identity::{AgentIdentity, AgentPolicy, Slot};
+use rustls::{Certificate, PrivateKey, ResolvesServerCert, SignatureScheme, sign};
 use rustls::internal::pemfile;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 pub fn new(
@@ -11,8 +14,9 @@ pub fn new(
     default: &Option<TlsServerIdentityConfig>,
 ) -> Result<Sni, Error> {
     let n_identities = identities.as_ref().map(|ids| ids.len()).unwrap_or(0);
+    let mut agents = Vec::new();
     let default = match default {
-        &Some(ref c) => Some(ServerIdentity::load(c)?),
+        &Some(ref c) => Some(ServerIdentity::load(c, &mut agents)?),
         &None if n_identities > 0 => {
             return Err(Error::NoIdentities)
         },
@@ -25,12 +29,13 @@ pub fn new(
             if let Some(identities) = identities.as_ref() {
                 for (k, c) in identities {
                     let k: String = (*k).clone();
-                    let v = ServerIdentity::load(c)?;
+                    let v = ServerIdentity::load(c, &mut agents)?;
                     ids.insert(k, v);
                 }
             }
             Arc::new(ids)
         },
+        agents,
     };
     Ok(sni)
 }
@@ -44,11 +49,26 @@ pub enum Error {
     FailedToReadPrivateKeyFile(String),
     WrongNumberOfKeysInPrivateKeyFile(String, usize),
     FailedToConstructPrivateKey(String),
+    /// An identity must be read either from files or from an agent.
+    ConflictingIdentitySources,
+    MissingIdentity,
+    InvalidAgentSocketPath,
+    /// The certificates do not form a chain for the private key.
+    InvalidCertificateChain(chain::Error),
 }
 
 pub struct Sni {
     default: Option<ServerIdentity>,
     identities: Arc<HashMap<String, ServerIdentity>>,
+    agents: Vec<AgentIdentity>,
+}
+
+impl Sni {
+    /// The identities provided by agents, which must be started once the server is
+    /// bound.
+    pub fn agents(&self) -> &[AgentIdentity] {
+        &self.agents
+    }
 }
 
 impl ResolvesServerCert for Sni {
@@ -67,24 +87,65 @@ impl ResolvesServerCert for Sni {
                 debug!("reverting to default");
                 self.default.as_ref()
             })
-            .map(|id| id.key.clone())
+            .and_then(|id| match *id {
+                ServerIdentity::Static(ref key) => Some(key.clone()),
+                ServerIdentity::Agent(ref slot) => {
+                    let key = slot.get();
+                    if key.is_none() {
+                        debug!("identity not yet provided by agent");
+                    }
+                    key
+                }
+            })
     }
 }
 
-struct ServerIdentity {
-    key: sign::CertifiedKey,
+enum ServerIdentity {
+    /// Read from files when the configuration is loaded.
+    Static(sign::CertifiedKey),
+    /// Provided, and replaced, by an agent.
+    Agent(Slot),
 }
 
 impl ServerIdentity {
-    fn load(c: &TlsServerIdentityConfig) -> Result<ServerIdentity, Error> {
-        let mut certs = vec![];
-        for p in &c.certs {
-            certs.append(&mut load_certs(p)?);
+    fn load(
+        c: &TlsServerIdentityConfig,
+        agents: &mut Vec<AgentIdentity>,
+    ) -> Result<ServerIdentity, Error> {
+        match (&c.identity_source, c.certs.is_empty(), &c.private_key) {
+            (&Some(IdentitySourceConfig::Agent(ref a)), true, &None) => {
+                if a.socket_path.is_empty() {
+                    return Err(Error::InvalidAgentSocketPath);
+                }
+                let agent = AgentIdentity::new(AgentPolicy {
+                    socket_path: PathBuf::from(&a.socket_path),
+                    audience: a.audience.clone(),
+                    start_without_identity: a.start_without_identity.unwrap_or(false),
+                });
+                let slot = agent.slot();
+                agents.push(agent);
+                Ok(ServerIdentity::Agent(slot))
+            }
+            (&Some(_), _, _) => Err(Error::ConflictingIdentitySources),
+            (&None, false, &Some(ref private_key)) => {
+                let mut certs = vec![];
+                for p in &c.certs {
+                    certs.append(&mut load_certs(p)?);
+                }
+                let key = load_private_key(private_key)?;
+                let allow_incomplete = c.allow_incomplete_chain.unwrap_or(false);
+                let certs = chain::order(certs, &key, allow_incomplete)
+                    .map_err(Error::InvalidCertificateChain)?;
+                let key: Box<sign::SigningKey> = match sign::RSASigningKey::new(&key) {
+                    Ok(key) => Box::new(key),
+                    Err(()) => return Err(Error::FailedToConstructPrivateKey(private_key.clone())),
+                };
+                Ok(ServerIdentity::Static(
+                    sign::CertifiedKey::new(certs, Arc::new(key)),
+                ))
+            }
+            (&None, _, _) => Err(Error::MissingIdentity),
         }
-        let key = load_private_key(&c.private_key)?;
-        Ok(ServerIdentity {
-            key: sign::CertifiedKey::new(certs, Arc::new(key)),
-        })
     }
 }
 
@@ -98,17 +159,14 @@ fn load_certs(cert_file_path: &String) -> Result<Vec<Certificate>, Error> {
 }
 
 // from rustls example
-fn load_private_key(key_file_path: &String)
-                    -> Result<Box<sign::SigningKey>, Error> {
+fn load_private_key(key_file_path: &String) -> Result<PrivateKey, Error> {
     let file = File::open(&key_file_path)
         .map_err(|e| Error::FailedToOpenPrivateKeyFile(key_file_path.clone(), e))?;
     let mut r = io::BufReader::new(file);
-    let keys = pemfile::rsa_private_keys(&mut r)
+    let mut keys = pemfile::rsa_private_keys(&mut r)
         .map_err(|()| Error::FailedToReadPrivateKeyFile(key_file_path.clone()))?;
     if keys.len() != 1 {
         return Err(Error::WrongNumberOfKeysInPrivateKeyFile(key_file_path.clone(), keys.len()));
     }
-    let key = sign::RSASigningKey::new(&keys[0])
-        .map_err(|()| Error::FailedToConstructPrivateKey(key_file_path.clone()))?;
-    Ok(Box::new(key))
+    Ok(keys.remove(0))
 }
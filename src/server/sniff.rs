@@ -0,0 +1,142 @@
+//! Classifies the first bytes of plaintext streams so that misdirected clients (e.g. TLS
+//! clients connecting to a plaintext server) may be diagnosed without packet captures.
+//!
+//! Streams are classified by the bytes returned by the first read from their client, as
+//! they are proxied, so classification never delays a stream: a stream in which the
+//! server speaks first is classified once its client sends data, if ever. Streams whose
+//! clients close without sending anything are not classified. Refused streams are
+//! closed, with the `misdirected_tls` close reason, before any of their bytes are
+//! written upstream.
+
+use super::super::connection::Misdirected;
+use super::super::connection::socket::FirstRead;
+use std::{io, net};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use tacho;
+
+/// Bounds the memory used to remember which clients have already been logged.
+const MAX_LOGGED_CLIENTS: usize = 10_000;
+
+const HTTP_PREFIXES: &'static [&'static [u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    b"PRI * HTTP/2",
+];
+
+/// Determines how TLS clients connecting to a plaintext server are handled.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MisdirectedTls {
+    /// Log the first misdirected stream from each client.
+    Warn,
+    /// Log the first misdirected stream from each client and refuse all of them.
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    TlsClientHello,
+    Http,
+    Other,
+}
+
+/// Classifies the first bytes of a stream.
+pub fn classify(bytes: &[u8]) -> Protocol {
+    if looks_like_tls_client_hello(bytes) {
+        return Protocol::TlsClientHello;
+    }
+    if HTTP_PREFIXES.iter().any(|p| bytes.starts_with(p)) {
+        return Protocol::Http;
+    }
+    Protocol::Other
+}
+
+/// A TLS record header: a handshake content type (22), a 3.x record version, a 2-byte
+/// length, and then a ClientHello handshake type (1).
+fn looks_like_tls_client_hello(bytes: &[u8]) -> bool {
+    bytes.len() >= 6 && bytes[0] == 22 && bytes[1] == 3 && bytes[2] <= 4 && bytes[5] == 1
+}
+
+#[derive(Clone)]
+pub struct Sniffer {
+    misdirected_tls: Option<MisdirectedTls>,
+    logged_clients: Rc<RefCell<HashSet<net::IpAddr>>>,
+    tls: tacho::Counter,
+    http: tacho::Counter,
+    other: tacho::Counter,
+    refused: tacho::Counter,
+}
+
+impl Sniffer {
+    pub fn new(misdirected_tls: Option<MisdirectedTls>, metrics: &tacho::Scope) -> Sniffer {
+        Sniffer {
+            misdirected_tls,
+            logged_clients: Rc::new(RefCell::new(HashSet::new())),
+            tls: metrics
+                .clone()
+                .labeled("protocol", "looks_like_tls_clienthello")
+                .counter("sniffed"),
+            http: metrics
+                .clone()
+                .labeled("protocol", "looks_like_http")
+                .counter("sniffed"),
+            other: metrics.clone().labeled("protocol", "other").counter("sniffed"),
+            refused: metrics
+                .clone()
+                .labeled("cause", "misdirected_tls")
+                .counter("refused"),
+        }
+    }
+
+    /// Classifies the stream from `src_addr` by the first bytes read from its client.
+    pub fn first_read(&self, src_addr: net::SocketAddr) -> FirstRead {
+        let sniffer = self.clone();
+        Box::new(move |bytes: &[u8]| sniffer.inspect(&src_addr, bytes))
+    }
+
+    /// Classifies the first bytes read from a stream.
+    ///
+    /// Fails if the stream looks like TLS and misdirected TLS streams are rejected.
+    fn inspect(&self, src_addr: &net::SocketAddr, bytes: &[u8]) -> io::Result<()> {
+        match classify(bytes) {
+            Protocol::Http => self.http.incr(1),
+            Protocol::Other => self.other.incr(1),
+            Protocol::TlsClientHello => {
+                self.tls.incr(1);
+                if let Some(policy) = self.misdirected_tls {
+                    self.log_first(src_addr);
+                    if let MisdirectedTls::Reject = policy {
+                        self.refused.incr(1);
+                        debug!("refusing stream from {}: {}", src_addr, Misdirected);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, Misdirected));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn log_first(&self, src_addr: &net::SocketAddr) {
+        let mut logged = self.logged_clients.borrow_mut();
+        if logged.contains(&src_addr.ip()) {
+            return;
+        }
+        if logged.len() == MAX_LOGGED_CLIENTS {
+            logged.clear();
+        }
+        logged.insert(src_addr.ip());
+        warn!(
+            "{}: TLS ClientHello received on a plaintext server",
+            src_addr
+        );
+    }
+}
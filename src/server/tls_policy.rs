@@ -0,0 +1,188 @@
+//! Describes the parameters negotiated with each downstream TLS client, and refuses
+//! clients whose parameters a server's `tlsPolicy` does not allow.
+//!
+//! rustls offers clients every protocol version and cipher suite it supports, so a
+//! policy is checked once the handshake completes: a connection that violates it is
+//! closed before it is routed.
+//!
+//! Servers are only built without TLS support when no server terminates TLS, so only
+//! the description of a session is compiled without it.
+
+#[cfg(feature = "tls")]
+use std::fmt;
+
+/// The protocol versions that may be negotiated, oldest first, as they are configured
+/// and reported.
+#[cfg(feature = "tls")]
+pub const VERSIONS: &'static [&'static str] = &["1.0", "1.1", "1.2", "1.3"];
+
+/// Reported for a protocol version that is not one of `VERSIONS`.
+#[cfg(feature = "tls")]
+pub const UNKNOWN_VERSION: &'static str = "unknown";
+
+/// The TLS parameters negotiated with a downstream client.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsProperties {
+    /// The protocol version, e.g. `1.2`.
+    pub version: &'static str,
+    /// The cipher suite, named as by IANA, e.g. `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    pub cipher_suite: String,
+    /// The server name requested via SNI, if any.
+    pub sni: Option<String>,
+    /// The protocol negotiated via ALPN, if any.
+    pub alpn: Option<String>,
+    /// The common name of the client certificate's subject, if the client presented a
+    /// certificate with one.
+    pub client_subject: Option<String>,
+    /// The DNS, URI, and IP subject alternative names of the client's certificate.
+    pub client_sans: Vec<String>,
+}
+
+/// Why a connection was refused by a policy.
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// Holds the version that was negotiated.
+    VersionTooOld(&'static str),
+    /// Holds the cipher suite that was negotiated.
+    DeniedCipher(String),
+}
+
+#[cfg(feature = "tls")]
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::VersionTooOld(v) => write!(f, "TLS version {} is not allowed", v),
+            Violation::DeniedCipher(ref c) => write!(f, "cipher suite {} is denied", c),
+        }
+    }
+}
+
+/// The parameters that a server's clients may negotiate.
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug, Default)]
+pub struct TlsPolicy {
+    /// Indexes `VERSIONS`.
+    min_version: Option<usize>,
+    deny_ciphers: Vec<String>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsPolicy {
+    /// `min_version` must be one of `VERSIONS`.
+    pub fn new(min_version: Option<&str>, deny_ciphers: Vec<String>) -> TlsPolicy {
+        let min_version = min_version.map(|v| {
+            VERSIONS.iter().position(|&known| known == v).expect(
+                "unknown minimum TLS version",
+            )
+        });
+        TlsPolicy {
+            min_version,
+            deny_ciphers,
+        }
+    }
+
+    /// Fails if `props` were negotiated with a version older than the minimum (or one
+    /// that is not known), or with a denied cipher suite.
+    pub fn check(&self, props: &TlsProperties) -> Result<(), Violation> {
+        if let Some(min) = self.min_version {
+            match VERSIONS.iter().position(|&v| v == props.version) {
+                Some(v) if v >= min => {}
+                _ => return Err(Violation::VersionTooOld(props.version)),
+            }
+        }
+        if self.deny_ciphers.contains(&props.cipher_suite) {
+            return Err(Violation::DeniedCipher(props.cipher_suite.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls")]
+pub use self::tls::{Negotiations, cipher_suites, properties};
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::{TlsProperties, UNKNOWN_VERSION, VERSIONS};
+    use super::super::chain;
+    use super::super::super::connection::secure::SecureStream;
+    use rustls::{self, ProtocolVersion, ServerSession};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use tacho;
+
+    /// The names of the cipher suites that rustls supports.
+    pub fn cipher_suites() -> Vec<String> {
+        rustls::ALL_CIPHERSUITES.iter().map(|s| format!("{:?}", s.suite)).collect()
+    }
+
+    /// Describes the session of a completed handshake.
+    pub fn properties(tls: &SecureStream<ServerSession>) -> TlsProperties {
+        let version = match tls.protocol_version() {
+            Some(ProtocolVersion::TLSv1_0) => VERSIONS[0],
+            Some(ProtocolVersion::TLSv1_1) => VERSIONS[1],
+            Some(ProtocolVersion::TLSv1_2) => VERSIONS[2],
+            Some(ProtocolVersion::TLSv1_3) => VERSIONS[3],
+            _ => UNKNOWN_VERSION,
+        };
+        let (client_subject, client_sans) = match tls.client_certificate() {
+            None => (None, vec![]),
+            Some(cert) => chain::subject_names(&cert),
+        };
+        TlsProperties {
+            version,
+            cipher_suite: tls.cipher_suite().map(|s| format!("{:?}", s)).unwrap_or_else(
+                || "unknown".to_owned(),
+            ),
+            sni: tls.sni_hostname().map(|s| s.to_owned()),
+            alpn: tls.alpn_protocol(),
+            client_subject,
+            client_sans,
+        }
+    }
+
+    /// Counts completed handshakes as `tls_versions`, labeled by `version`, and as
+    /// `tls_ciphers`, labeled by `cipher`. Since labels are drawn from the versions and
+    /// cipher suites that rustls supports, each has a bounded number of values.
+    #[derive(Clone)]
+    pub struct Negotiations {
+        versions: Rc<HashMap<&'static str, tacho::Counter>>,
+        ciphers: Rc<HashMap<String, tacho::Counter>>,
+    }
+
+    impl Negotiations {
+        pub fn new(tls_metrics: &tacho::Scope) -> Negotiations {
+            let versions = VERSIONS
+                .iter()
+                .chain(Some(&UNKNOWN_VERSION))
+                .map(|&v| {
+                    let c = tls_metrics.clone().labeled("version", v).counter("versions");
+                    (v, c)
+                })
+                .collect();
+            let ciphers = cipher_suites()
+                .into_iter()
+                .map(|s| {
+                    let c = tls_metrics.clone().labeled("cipher", s.clone()).counter(
+                        "ciphers",
+                    );
+                    (s, c)
+                })
+                .collect();
+            Negotiations {
+                versions: Rc::new(versions),
+                ciphers: Rc::new(ciphers),
+            }
+        }
+
+        pub fn record(&self, props: &TlsProperties) {
+            if let Some(c) = self.versions.get(props.version) {
+                c.incr(1);
+            }
+            if let Some(c) = self.ciphers.get(&props.cipher_suite) {
+                c.incr(1);
+            }
+        }
+    }
+}
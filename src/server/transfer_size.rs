@@ -0,0 +1,80 @@
+//! Characterizes a router's traffic by the number of bytes each connection transfers,
+//! without logging each connection.
+//!
+//! As each connection closes, the bytes it transferred in each direction are counted in
+//! the smallest bucket whose bound exceeds them (or in `inf`, if none does):
+//! `transfer_size_bucket` counts the connections, and `transfer_size_bucket_bytes` their
+//! bytes, labeled by `direction` and `bucket` (the bucket's bound).
+
+use super::super::connection::Peer;
+use std::rc::Rc;
+use tacho;
+
+/// Buckets for transfers of less than 1KB, 64KB, 1MB, 100MB, and more.
+pub const DEFAULT_SIZE_BUCKETS: &'static [u64] = &[1 << 10, 1 << 16, 1 << 20, 100 << 20];
+
+const OVERFLOW_BUCKET: &'static str = "inf";
+
+/// Counts the bytes each connection transfers, by direction and size.
+#[derive(Clone)]
+pub struct TransferSizes(Rc<Inner>);
+
+struct Inner {
+    bounds: Vec<u64>,
+    /// Indexed like `bounds`, followed by the overflow bucket.
+    client_to_server: Vec<Bucket>,
+    server_to_client: Vec<Bucket>,
+}
+
+struct Bucket {
+    connections: tacho::Counter,
+    bytes: tacho::Counter,
+}
+
+impl TransferSizes {
+    /// Buckets transfers by `bounds`, which must be positive and strictly increasing.
+    /// Returns `None` if they are not.
+    pub fn new(bounds: &[u64], metrics: &tacho::Scope) -> Option<TransferSizes> {
+        if bounds.first() == Some(&0) || bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return None;
+        }
+        let buckets = |peer: Peer| {
+            let labels = bounds.iter().map(|b| b.to_string()).chain(
+                Some(OVERFLOW_BUCKET.to_owned()),
+            );
+            labels
+                .map(|bucket| {
+                    let metrics = metrics
+                        .clone()
+                        .labeled("direction", peer.direction())
+                        .labeled("bucket", bucket);
+                    Bucket {
+                        connections: metrics.counter("transfer_size_bucket"),
+                        bytes: metrics.counter("transfer_size_bucket_bytes"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        Some(TransferSizes(Rc::new(Inner {
+            bounds: bounds.to_vec(),
+            client_to_server: buckets(Peer::Client),
+            server_to_client: buckets(Peer::Server),
+        })))
+    }
+
+    /// Records a closed connection's transfers.
+    pub fn record(&self, client_to_server: u64, server_to_client: u64) {
+        let inner = &self.0;
+        for &(sz, ref buckets) in &[
+            (client_to_server, &inner.client_to_server),
+            (server_to_client, &inner.server_to_client),
+        ]
+        {
+            let i = inner.bounds.iter().position(|&b| sz < b).unwrap_or(
+                inner.bounds.len(),
+            );
+            buckets[i].connections.incr(1);
+            buckets[i].bytes.incr(sz as usize);
+        }
+    }
+}
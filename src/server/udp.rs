@@ -0,0 +1,502 @@
+//! Load balances UDP datagrams.
+//!
+//! Each client address has a session, which the destination's balancer assigns to an
+//! endpoint when the client's first datagram is received. A session sends its client's
+//! datagrams to the endpoint from a socket of its own, so that the endpoint's responses
+//! may be relayed to the client from the server's socket.
+//!
+//! Sessions are closed once they have sent and received no datagrams for the session
+//! timeout. When the server already has `maxConcurrency` sessions, the least recently
+//! active session is closed to make room for a new one.
+
+use super::Bound;
+use super::super::Path;
+use super::super::balancer::Session;
+use super::super::fd::FdLimit;
+use super::super::router::Router;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, unsync};
+use std::{cmp, io, net};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tacho;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+use tokio_timer::{Sleep, Timer};
+
+pub const DEFAULT_SESSION_TIMEOUT_SECS: u64 = 60;
+
+/// The largest payload that may be carried by a UDP datagram over IPv4.
+pub const MAX_DATAGRAM_BYTES: usize = 65_507;
+
+/// Limits how many of a client's datagrams are queued while its session is assigned an
+/// endpoint. Datagrams beyond these are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+/// Limits how long sessions sleep between checks for idleness, since the timer does
+/// not support long timeouts.
+const MAX_IDLE_CHECK_SECS: u64 = 60;
+
+/// Configures a UDP server.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub session_timeout: Duration,
+    pub max_datagram_bytes: usize,
+}
+
+impl Policy {
+    /// Binds the server's socket. Up to `max_sessions` clients are served at once.
+    pub fn bind(
+        self,
+        listen_addr: net::SocketAddr,
+        dst_name: Path,
+        router: Router,
+        max_sessions: usize,
+        fd_limit: FdLimit,
+        metrics: &tacho::Scope,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> io::Result<Bound> {
+        let socket = Rc::new(UdpSocket::bind(&listen_addr, reactor)?);
+        let local_addr = socket.local_addr()?;
+        let metrics = metrics.clone().labeled("srv_addr", format!("{}", local_addr));
+        let server_metrics = Metrics::new(&metrics);
+        let (server_reactor, server_timer) = (reactor.clone(), timer.clone());
+        // A server that is rebuilt shares the socket, but none of the sessions, of the
+        // server it replaces.
+        let serve = move || {
+            let (closed_tx, closed_rx) = unsync::mpsc::unbounded();
+            let server = Server {
+                socket: socket.clone(),
+                dst_name: dst_name.clone(),
+                router: router.clone(),
+                policy: self,
+                max_sessions,
+                fd_limit: fd_limit.clone(),
+                sessions: HashMap::new(),
+                next_id: 0,
+                closed_tx,
+                closed_rx,
+                // One byte more than the largest permitted datagram is read, so that
+                // larger datagrams are detected rather than truncated.
+                buf: vec![0; self.max_datagram_bytes + 1],
+                reactor: server_reactor.clone(),
+                timer: server_timer.clone(),
+                metrics: server_metrics.clone(),
+            };
+            server.into_stream()
+        };
+        Ok(Bound::new(local_addr, serve, &metrics, timer))
+    }
+}
+
+/// Receives clients' datagrams and dispatches them to their sessions.
+struct Server {
+    socket: Rc<UdpSocket>,
+    dst_name: Path,
+    router: Router,
+    policy: Policy,
+    max_sessions: usize,
+    fd_limit: FdLimit,
+    sessions: HashMap<net::SocketAddr, Client>,
+
+    /// Identifies sessions, so that a closed session is not mistaken for a newer
+    /// session of the same client.
+    next_id: usize,
+
+    /// Receives the clients and identifiers of sessions as they close.
+    closed_tx: unsync::mpsc::UnboundedSender<(net::SocketAddr, usize)>,
+    closed_rx: unsync::mpsc::UnboundedReceiver<(net::SocketAddr, usize)>,
+
+    buf: Vec<u8>,
+    reactor: Handle,
+    timer: Timer,
+    metrics: Metrics,
+}
+
+/// The server's handle on a client's session.
+struct Client {
+    id: usize,
+    tx: unsync::mpsc::Sender<Vec<u8>>,
+    last_active: Rc<Cell<Instant>>,
+}
+
+impl Server {
+    fn forget_closed(&mut self) {
+        while let Ok(Async::Ready(Some((addr, id)))) = self.closed_rx.poll() {
+            if self.sessions.get(&addr).map(|c| c.id) == Some(id) {
+                self.sessions.remove(&addr);
+            }
+        }
+    }
+
+    /// Queues a datagram for the client's session, opening one if necessary.
+    fn dispatch(&mut self, src_addr: net::SocketAddr, dgram: Vec<u8>) {
+        let dgram = match self.sessions.get_mut(&src_addr) {
+            None => dgram,
+            Some(client) => {
+                match client.tx.start_send(dgram) {
+                    Ok(AsyncSink::Ready) => {
+                        client.last_active.set(Instant::now());
+                        return;
+                    }
+                    Ok(AsyncSink::NotReady(_)) => {
+                        trace!("dropping datagram from {}: session busy", src_addr);
+                        self.metrics.dropped_queue_full.incr(1);
+                        return;
+                    }
+                    // The session has closed, so a new one is opened.
+                    Err(e) => e.into_inner(),
+                }
+            }
+        };
+        self.sessions.remove(&src_addr);
+
+        // Each session holds a socket, so new sessions are refused while the process is
+        // near its file descriptor limit.
+        if self.fd_limit.is_exhausted() {
+            debug!("refusing session from {}: fd limit", src_addr);
+            self.metrics.refused.incr(1);
+            return;
+        }
+        if self.sessions.len() >= self.max_sessions {
+            self.evict_least_recently_active();
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let (mut tx, rx) = unsync::mpsc::channel(MAX_QUEUED_DATAGRAMS);
+        let _ = tx.start_send(dgram);
+        let last_active = Rc::new(Cell::new(Instant::now()));
+        self.sessions.insert(
+            src_addr,
+            Client {
+                id,
+                tx,
+                last_active: last_active.clone(),
+            },
+        );
+
+        debug!("opening session from {} to {}", src_addr, self.dst_name);
+        self.metrics.sessions.incr(1);
+        self.metrics.active.incr(1);
+        let opening = self.router
+            .route(&self.dst_name, &self.reactor, &self.timer)
+            .and_then(|balancer| balancer.open_session().map_err(io::Error::from));
+        let relay = Relay {
+            client_addr: src_addr,
+            id,
+            state: State::Opening(Box::new(opening)),
+            rx,
+            pending: None,
+            server: self.socket.clone(),
+            buf: vec![0; self.buf.len()],
+            max_datagram_bytes: self.policy.max_datagram_bytes,
+            last_active,
+            timeout: self.policy.session_timeout,
+            idle: None,
+            reactor: self.reactor.clone(),
+            timer: self.timer.clone(),
+            closed_tx: self.closed_tx.clone(),
+            metrics: self.metrics.clone(),
+        };
+        self.reactor.spawn(relay);
+    }
+
+    /// Closes the session that has been idle the longest. Dropping its sender ends the
+    /// session once it has sent the datagrams already queued.
+    fn evict_least_recently_active(&mut self) {
+        let lra = self.sessions
+            .iter()
+            .min_by_key(|&(_, c)| c.last_active.get())
+            .map(|(addr, _)| *addr);
+        if let Some(addr) = lra {
+            debug!("evicting session from {}", addr);
+            self.sessions.remove(&addr);
+        }
+    }
+}
+
+impl Future for Server {
+    type Item = ();
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.forget_closed();
+        loop {
+            let (sz, src_addr) = match self.socket.recv_from(&mut self.buf) {
+                Ok(recvd) => recvd,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    debug!("failed to receive datagram: {}", e);
+                    continue;
+                }
+            };
+            self.metrics.rx_datagrams.incr(1);
+            if sz > self.policy.max_datagram_bytes {
+                trace!("dropping datagram from {}: too large", src_addr);
+                self.metrics.dropped_oversize.incr(1);
+                continue;
+            }
+            let dgram = self.buf[..sz].to_vec();
+            self.dispatch(src_addr, dgram);
+        }
+    }
+}
+
+enum State {
+    /// Waiting for the balancer to assign an endpoint.
+    Opening(Box<Future<Item = Session, Error = io::Error>>),
+    Open { session: Session, upstream: UdpSocket },
+}
+
+/// Relays a client's datagrams to its session's endpoint, and the endpoint's responses
+/// to the client.
+struct Relay {
+    client_addr: net::SocketAddr,
+    id: usize,
+    state: State,
+    rx: unsync::mpsc::Receiver<Vec<u8>>,
+
+    /// A datagram that the endpoint's socket was not yet ready to send.
+    pending: Option<Vec<u8>>,
+
+    server: Rc<UdpSocket>,
+    buf: Vec<u8>,
+    max_datagram_bytes: usize,
+    last_active: Rc<Cell<Instant>>,
+    timeout: Duration,
+    idle: Option<Sleep>,
+    reactor: Handle,
+    timer: Timer,
+    closed_tx: unsync::mpsc::UnboundedSender<(net::SocketAddr, usize)>,
+    metrics: Metrics,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CloseReason {
+    /// No datagrams were sent or received for the session timeout.
+    Idle,
+    /// The server closed the session, to make room for another.
+    Evicted,
+    /// The session could not be assigned an endpoint, or the endpoint failed.
+    Failed,
+}
+
+impl Relay {
+    /// Determines whether the session has been idle for its timeout, scheduling the
+    /// next check if it has not.
+    fn is_idle(&mut self) -> bool {
+        loop {
+            if let Some(mut idle) = self.idle.take() {
+                match idle.poll() {
+                    Ok(Async::NotReady) => {
+                        self.idle = Some(idle);
+                        return false;
+                    }
+                    Ok(Async::Ready(_)) => {}
+                    Err(e) => {
+                        // The session is still closed if it is evicted.
+                        error!("{}: session timer failed: {}", self.client_addr, e);
+                        return false;
+                    }
+                }
+            }
+            let elapsed = self.last_active.get().elapsed();
+            if elapsed >= self.timeout {
+                return true;
+            }
+            let remaining = cmp::min(
+                self.timeout - elapsed,
+                Duration::from_secs(MAX_IDLE_CHECK_SECS),
+            );
+            self.idle = Some(self.timer.sleep(remaining));
+        }
+    }
+
+    /// Opens a socket connected to the session's endpoint, so that errors reported by
+    /// the endpoint's host (e.g. closed ports) are surfaced.
+    fn connect(&self, peer_addr: &net::SocketAddr) -> io::Result<UdpSocket> {
+        let local_addr: net::SocketAddr = match *peer_addr {
+            net::SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            net::SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let sock = net::UdpSocket::bind(local_addr)?;
+        sock.connect(peer_addr)?;
+        UdpSocket::from_socket(sock, &self.reactor)
+    }
+
+    /// Sends the client's queued datagrams to the endpoint.
+    fn send_upstream(&mut self) -> Result<(), CloseReason> {
+        let (session, upstream) = match self.state {
+            State::Open {
+                ref mut session,
+                ref upstream,
+            } => (session, upstream),
+            State::Opening(_) => return Ok(()),
+        };
+        loop {
+            let dgram = match self.pending.take() {
+                Some(dgram) => dgram,
+                None => {
+                    match self.rx.poll() {
+                        Ok(Async::NotReady) => return Ok(()),
+                        Ok(Async::Ready(Some(dgram))) => dgram,
+                        Ok(Async::Ready(None)) | Err(_) => return Err(CloseReason::Evicted),
+                    }
+                }
+            };
+            match upstream.send_to(&dgram, &session.peer_addr()) {
+                Ok(sz) => {
+                    session.sent(sz);
+                    self.last_active.set(Instant::now());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.pending = Some(dgram);
+                    return Ok(());
+                }
+                Err(e) => {
+                    session.failed(&e);
+                    return Err(CloseReason::Failed);
+                }
+            }
+        }
+    }
+
+    /// Relays the endpoint's responses to the client.
+    fn send_downstream(&mut self) -> Result<(), CloseReason> {
+        let (session, upstream) = match self.state {
+            State::Open {
+                ref mut session,
+                ref upstream,
+            } => (session, upstream),
+            State::Opening(_) => return Ok(()),
+        };
+        loop {
+            let sz = match upstream.recv_from(&mut self.buf) {
+                Ok((sz, _)) => sz,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => {
+                    session.failed(&e);
+                    return Err(CloseReason::Failed);
+                }
+            };
+            session.received(sz);
+            self.last_active.set(Instant::now());
+            if sz > self.max_datagram_bytes {
+                trace!("dropping datagram from {}: too large", session.peer_addr());
+                self.metrics.dropped_oversize.incr(1);
+                continue;
+            }
+            // Like any other datagram, a response may be lost if the server's socket
+            // can't accept it.
+            match self.server.send_to(&self.buf[..sz], &self.client_addr) {
+                Ok(_) => self.metrics.tx_datagrams.incr(1),
+                Err(e) => {
+                    debug!("failed to relay datagram to {}: {}", self.client_addr, e);
+                    self.metrics.dropped_send_failed.incr(1);
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, reason: CloseReason) {
+        debug!("session from {} closed: {:?}", self.client_addr, reason);
+        match reason {
+            CloseReason::Idle => self.metrics.closed_idle.incr(1),
+            CloseReason::Evicted => self.metrics.closed_evicted.incr(1),
+            CloseReason::Failed => self.metrics.closed_failed.incr(1),
+        }
+        self.metrics.active.decr(1);
+        let _ = self.closed_tx.unbounded_send((self.client_addr, self.id));
+    }
+
+    fn poll_relay(&mut self) -> Result<(), CloseReason> {
+        if self.is_idle() {
+            return Err(CloseReason::Idle);
+        }
+
+        let session = match self.state {
+            State::Open { .. } => None,
+            State::Opening(ref mut opening) => {
+                match opening.poll() {
+                    Ok(Async::NotReady) => return Ok(()),
+                    Ok(Async::Ready(session)) => Some(session),
+                    Err(e) => {
+                        debug!("no session for {}: {}", self.client_addr, e);
+                        return Err(CloseReason::Failed);
+                    }
+                }
+            }
+        };
+        if let Some(session) = session {
+            let upstream = match self.connect(&session.peer_addr()) {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    error!("failed to open socket for {}: {}", self.client_addr, e);
+                    return Err(CloseReason::Failed);
+                }
+            };
+            debug!("session from {} to {}", self.client_addr, session.peer_addr());
+            self.state = State::Open { session, upstream };
+        }
+
+        self.send_upstream()?;
+        self.send_downstream()
+    }
+}
+
+impl Future for Relay {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.poll_relay() {
+            Ok(()) => Ok(Async::NotReady),
+            Err(reason) => {
+                self.close(reason);
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    rx_datagrams: tacho::Counter,
+    tx_datagrams: tacho::Counter,
+    dropped_oversize: tacho::Counter,
+    dropped_queue_full: tacho::Counter,
+    dropped_send_failed: tacho::Counter,
+    refused: tacho::Counter,
+    sessions: tacho::Counter,
+    active: tacho::Gauge,
+    closed_idle: tacho::Counter,
+    closed_evicted: tacho::Counter,
+    closed_failed: tacho::Counter,
+}
+
+impl Metrics {
+    fn new(metrics: &tacho::Scope) -> Metrics {
+        let dropped = |cause: &'static str| {
+            metrics.clone().labeled("cause", cause).counter("dropped_datagrams")
+        };
+        let closed = |reason: &'static str| {
+            metrics.clone().labeled("reason", reason).counter("session_closes")
+        };
+        Metrics {
+            rx_datagrams: metrics.counter("rx_datagrams"),
+            tx_datagrams: metrics.counter("tx_datagrams"),
+            dropped_oversize: dropped("oversize"),
+            dropped_queue_full: dropped("queue_full"),
+            dropped_send_failed: dropped("send_failed"),
+            refused: metrics.clone().labeled("cause", "fd_limit").counter("refused"),
+            sessions: metrics.counter("session_opens"),
+            active: metrics.gauge("active_sessions"),
+            closed_idle: closed("idle"),
+            closed_evicted: closed("evicted"),
+            closed_failed: closed("failed"),
+        }
+    }
+}
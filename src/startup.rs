@@ -0,0 +1,229 @@
+//! Coordinates the initial resolution of each proxy's destination, so that a process
+//! with many proxies does not request all of their resolutions from namerd at once.
+//!
+//! Each proxy's first resolution must acquire one of `startupConcurrency` permits
+//! before it is requested, and releases it once it has been answered (successfully or
+//! not). Later resolutions, including retries, are not bounded. A proxy is ready once
+//! its destination has been resolved; the process is ready once the configured quorum
+//! of its proxies are.
+
+use super::metrics;
+use futures::{Async, Poll, Stream};
+use futures::task::{self, Task};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// The number of initial resolutions that may be requested at once, by default.
+pub const DEFAULT_STARTUP_CONCURRENCY: usize = 5;
+
+/// The percentage of proxies that must be ready before the process is, by default.
+pub const DEFAULT_STARTUP_QUORUM_PERCENT: usize = 100;
+
+/// Tracks the startup of a process's proxies and bounds their concurrent initial
+/// resolutions.
+///
+/// A `Startup` may be cloned and shared across threads.
+#[derive(Clone)]
+pub struct Startup(Arc<Mutex<Inner>>);
+
+struct Inner {
+    concurrency: usize,
+    quorum_percent: usize,
+    in_flight: usize,
+    /// Each proxy, by router and destination, and whether it is ready.
+    proxies: HashMap<(String, String), bool>,
+    ready: usize,
+    /// Tasks waiting for a permit.
+    waiters: Vec<Task>,
+    metrics: Metrics,
+}
+
+struct Metrics {
+    proxies: Arc<metrics::Gauge>,
+    ready_proxies: Arc<metrics::Gauge>,
+    in_flight: Arc<metrics::Gauge>,
+}
+
+impl Startup {
+    /// Bounds concurrent initial resolutions by `concurrency`. The process is ready
+    /// once `quorum_percent` of its proxies are.
+    pub fn new(concurrency: usize, quorum_percent: usize, metrics: &metrics::Scope) -> Startup {
+        let metrics = metrics.clone().prefixed("startup");
+        let inner = Inner {
+            concurrency,
+            quorum_percent,
+            in_flight: 0,
+            proxies: HashMap::new(),
+            ready: 0,
+            waiters: Vec::new(),
+            metrics: Metrics {
+                proxies: metrics.gauge("proxies"),
+                ready_proxies: metrics.gauge("ready_proxies"),
+                in_flight: metrics.gauge("resolutions_in_flight"),
+            },
+        };
+        Startup(Arc::new(Mutex::new(inner)))
+    }
+
+    /// Registers a proxy that is not ready until `dst` has been resolved by `router`.
+    pub fn expect_proxy(&self, router: &str, dst: &str) {
+        let mut inner = self.0.lock().expect("startup lock poisoned");
+        inner.proxies.entry((router.to_owned(), dst.to_owned())).or_insert(false);
+        let n = inner.proxies.len();
+        inner.metrics.proxies.set(n);
+    }
+
+    /// Bounds the initial resolution of `dst` by `router`, as produced by `resolutions`.
+    ///
+    /// `resolutions` is not polled until a permit is acquired. The permit is released
+    /// once it produces its first resolution, ends, fails, or is dropped. The proxy is
+    /// ready once a resolution succeeds.
+    pub fn initial<S, T, E>(&self, router: &str, dst: &str, resolutions: S) -> Initial<S>
+    where
+        S: Stream<Item = Result<T, E>>,
+    {
+        Initial {
+            startup: self.clone(),
+            key: (router.to_owned(), dst.to_owned()),
+            resolutions,
+            permit: Permit::Waiting,
+        }
+    }
+
+    /// The number of registered proxies that are ready, and the number registered.
+    pub fn progress(&self) -> (usize, usize) {
+        let inner = self.0.lock().expect("startup lock poisoned");
+        (inner.ready, inner.proxies.len())
+    }
+
+    /// Indicates whether the quorum of registered proxies is ready.
+    pub fn is_ready(&self) -> bool {
+        let inner = self.0.lock().expect("startup lock poisoned");
+        inner.ready * 100 >= inner.proxies.len() * inner.quorum_percent
+    }
+
+    /// The number of initial resolutions that have been requested and not yet answered.
+    pub fn in_flight(&self) -> usize {
+        self.0.lock().expect("startup lock poisoned").in_flight
+    }
+
+    /// Acquires a permit for `key`'s initial resolution. Returns `None` if `key` is
+    /// already ready, in which case no permit is needed.
+    fn poll_acquire(&self, key: &(String, String)) -> Async<Option<()>> {
+        let mut inner = self.0.lock().expect("startup lock poisoned");
+        if inner.proxies.get(key) == Some(&true) {
+            return Async::Ready(None);
+        }
+        if inner.in_flight < inner.concurrency {
+            inner.in_flight += 1;
+            let n = inner.in_flight;
+            inner.metrics.in_flight.set(n);
+            return Async::Ready(Some(()));
+        }
+        inner.waiters.push(task::current());
+        Async::NotReady
+    }
+
+    fn release(&self) {
+        let waiters = {
+            let mut inner = self.0.lock().expect("startup lock poisoned");
+            inner.in_flight -= 1;
+            let n = inner.in_flight;
+            inner.metrics.in_flight.set(n);
+            // Waiters that have since been dropped are notified harmlessly; those that
+            // are not granted a permit wait again.
+            mem::replace(&mut inner.waiters, Vec::new())
+        };
+        for w in waiters {
+            w.notify();
+        }
+    }
+
+    fn set_ready(&self, key: &(String, String)) {
+        let mut inner = self.0.lock().expect("startup lock poisoned");
+        let newly_ready = match inner.proxies.get_mut(key) {
+            Some(ready) => !mem::replace(ready, true),
+            None => false,
+        };
+        if newly_ready {
+            inner.ready += 1;
+            let n = inner.ready;
+            inner.metrics.ready_proxies.set(n);
+            let total = inner.proxies.len();
+            if n == total {
+                info!("startup complete: {} of {} proxies ready", n, total);
+            }
+        }
+    }
+}
+
+enum Permit {
+    /// The permit has not been acquired.
+    Waiting,
+    /// The permit is held until the initial resolution is answered.
+    Held,
+    /// The permit has been released, or was not needed.
+    Done,
+}
+
+/// A stream of resolutions whose first request is bounded by a `Startup`.
+pub struct Initial<S> {
+    startup: Startup,
+    key: (String, String),
+    resolutions: S,
+    permit: Permit,
+}
+
+impl<S> Initial<S> {
+    fn release(&mut self) {
+        if let Permit::Held = self.permit {
+            self.startup.release();
+        }
+        self.permit = Permit::Done;
+    }
+}
+
+impl<S, T, E> Stream for Initial<S>
+where
+    S: Stream<Item = Result<T, E>>,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if let Permit::Waiting = self.permit {
+            match self.startup.poll_acquire(&self.key) {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(())) => {
+                    debug!("{}: requesting initial resolution of {}", self.key.0, self.key.1);
+                    self.permit = Permit::Held;
+                }
+                Async::Ready(None) => self.permit = Permit::Done,
+            }
+        }
+        match self.resolutions.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Some(rsp))) => {
+                self.release();
+                if rsp.is_ok() {
+                    self.startup.set_ready(&self.key);
+                }
+                Ok(Async::Ready(Some(rsp)))
+            }
+            Ok(Async::Ready(None)) => {
+                self.release();
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.release();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S> Drop for Initial<S> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
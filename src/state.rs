@@ -0,0 +1,711 @@
+//! Shares state between the serving thread and the admin server.
+//!
+//! Balancers run on the serving thread and periodically publish snapshots of their
+//! endpoints into a `Registry`, which the admin server renders as JSON. In the other
+//! direction, operators may eject endpoints via the admin server, and balancers apply
+//! these `Ejections` in preference to their own view of endpoint health. Operators may
+//! similarly scale endpoints' resolved weights with `WeightOverrides`.
+//!
+//! Resolvers, which run on the admin thread, note which names are being served from
+//! their resolution caches as `CachedResolutions`, so that readiness may reflect them.
+//! Namerd resolvers that accept remote policy record it as `RemotePolicies`, which
+//! balancers apply over their configured settings.
+//!
+//! Servers and resolvers also record each router's golden signals into a `Summary`.
+//!
+//! When proxies that fail to start are skipped, each is recorded as a `ProxyErrors`
+//! entry, so that the admin server may report a partial startup.
+//!
+//! Balancers that trace endpoint selection record each sampled selection, with its
+//! inputs, into a bounded ring of `SelectionTraces`.
+
+use super::Path;
+use super::admin::api::{self, EndpointHealth};
+use super::connector::RemotePolicy;
+use super::summary::Summary;
+use futures::task::{self, Task};
+use serde_json;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of sampled selections that are retained, across all balancers.
+pub const SELECTION_TRACE_CAPACITY: usize = 100;
+
+type Routers = BTreeMap<String, BTreeMap<String, BalancerState>>;
+
+/// Holds the most recent state published by each balancer, by router and destination.
+#[derive(Clone, Default)]
+pub struct Registry {
+    routers: Arc<Mutex<Routers>>,
+    ejections: Ejections,
+    weight_overrides: WeightOverrides,
+    remote_policies: RemotePolicies,
+    cached: CachedResolutions,
+    summary: Summary,
+    proxy_errors: ProxyErrors,
+    selection_traces: SelectionTraces,
+}
+
+impl Registry {
+    /// Returns a handle through which `router`'s balancer for `dst` publishes its state.
+    pub fn reporter(&self, router: &str, dst: &Path) -> Reporter {
+        Reporter {
+            registry: self.clone(),
+            router: router.into(),
+            dst: dst.as_str().into(),
+            ejections_version: 0,
+            weight_overrides_version: 0,
+            remote_policies_version: 0,
+        }
+    }
+
+    /// Returns the endpoints ejected by operators.
+    pub fn ejections(&self) -> &Ejections {
+        &self.ejections
+    }
+
+    /// Returns the endpoint weight overrides set by operators.
+    pub fn weight_overrides(&self) -> &WeightOverrides {
+        &self.weight_overrides
+    }
+
+    /// Returns the remote policy that namerd publishes for each destination.
+    pub fn remote_policies(&self) -> &RemotePolicies {
+        &self.remote_policies
+    }
+
+    /// Returns the names being served from resolution caches.
+    pub fn cached_resolutions(&self) -> &CachedResolutions {
+        &self.cached
+    }
+
+    /// Returns each router's golden signals.
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Returns the proxies that were skipped because they failed to start.
+    pub fn proxy_errors(&self) -> &ProxyErrors {
+        &self.proxy_errors
+    }
+
+    /// Returns the most recently sampled endpoint selections.
+    pub fn selection_traces(&self) -> &SelectionTraces {
+        &self.selection_traces
+    }
+
+    /// Counts each router's endpoints by failure accrual state, as most recently
+    /// published by its balancers.
+    pub fn endpoint_health(&self) -> BTreeMap<String, EndpointHealth> {
+        let routers = self.routers.lock().expect("state lock poisoned");
+        let mut health = BTreeMap::new();
+        for (router, balancers) in routers.iter() {
+            let mut counts = EndpointHealth::default();
+            for ep in balancers.values().flat_map(|b| b.endpoints.iter()) {
+                match ep.failure_accrual {
+                    "healthy" => counts.healthy += 1,
+                    "failing" => counts.failing += 1,
+                    "probation" => counts.probation += 1,
+                    _ => counts.failed += 1,
+                }
+            }
+            health.insert(router.clone(), counts);
+        }
+        health
+    }
+
+    /// Describes the most recent state of each balancer, as served by the admin server's
+    /// `/state` endpoint.
+    pub fn to_api(&self) -> api::State {
+        let routers = self.routers.lock().expect("state lock poisoned");
+        let routers = routers
+            .iter()
+            .map(|(router, balancers)| {
+                let balancers = balancers.iter().map(|(dst, b)| (dst.clone(), b.to_api()));
+                (router.clone(), balancers.collect())
+            })
+            .collect();
+        api::State {
+            api_version: api::VERSION,
+            routers,
+        }
+    }
+
+    /// Renders the most recent state of each balancer at the latest API version.
+    pub fn to_json(&self) -> String {
+        api::to_json(&self.to_api())
+    }
+}
+
+/// Publishes a single balancer's state.
+pub struct Reporter {
+    registry: Registry,
+    router: String,
+    dst: String,
+    ejections_version: usize,
+    weight_overrides_version: usize,
+    remote_policies_version: usize,
+}
+
+impl Reporter {
+    /// Returns a reporter for the same balancer that has observed no changes, so that a
+    /// balancer that is rebuilt applies the ejections, weight overrides, and remote
+    /// policy that are already published.
+    pub fn renew(&self) -> Reporter {
+        Reporter {
+            registry: self.registry.clone(),
+            router: self.router.clone(),
+            dst: self.dst.clone(),
+            ejections_version: 0,
+            weight_overrides_version: 0,
+            remote_policies_version: 0,
+        }
+    }
+
+    pub fn report(&self, state: BalancerState) {
+        let mut routers = self.registry.routers.lock().expect("state lock poisoned");
+        routers
+            .entry(self.router.clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(self.dst.clone(), state);
+    }
+
+    /// Records why an endpoint was chosen for a sampled dispatch.
+    pub fn trace_selection(&self, selection: SelectionExplain) {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.registry.selection_traces.record(SelectionTrace {
+            router: self.router.clone(),
+            dst: self.dst.clone(),
+            at_ms: at.as_secs() * 1_000 + u64::from(at.subsec_nanos() / 1_000_000),
+            selection,
+        });
+    }
+
+    /// Returns the addresses ejected from this balancer's router, if they have changed
+    /// since this method was last called.
+    pub fn poll_ejected(&mut self) -> Option<HashSet<net::SocketAddr>> {
+        let ejections = &self.registry.ejections;
+        let version = ejections.version.load(Ordering::Acquire);
+        if version == self.ejections_version {
+            return None;
+        }
+        self.ejections_version = version;
+        let ejected = ejections.ejected.lock().expect("ejections lock poisoned");
+        let addrs = ejected
+            .iter()
+            .filter(|&&(ref router, _)| match *router {
+                None => true,
+                Some(ref r) => *r == self.router,
+            })
+            .map(|&(_, addr)| addr)
+            .collect();
+        Some(addrs)
+    }
+
+    /// Returns the weight multipliers that apply to this balancer's router, if they have
+    /// changed since this method was last called.
+    ///
+    /// Overrides specific to the router take precedence over those that apply to all
+    /// routers.
+    pub fn poll_weight_multipliers(&mut self) -> Option<HashMap<net::SocketAddr, f64>> {
+        let overrides = &self.registry.weight_overrides;
+        let version = overrides.version.load(Ordering::Acquire);
+        if version == self.weight_overrides_version {
+            return None;
+        }
+        self.weight_overrides_version = version;
+        let multipliers = overrides.multipliers.lock().expect(
+            "weight overrides lock poisoned",
+        );
+        let mut by_addr = HashMap::new();
+        for (&(ref router, addr), &m) in multipliers.iter() {
+            match *router {
+                None => {
+                    by_addr.entry(addr).or_insert(m);
+                }
+                Some(ref r) if *r == self.router => {
+                    by_addr.insert(addr, m);
+                }
+                Some(_) => {}
+            }
+        }
+        Some(by_addr)
+    }
+
+    /// Returns the remote policy for this balancer's destination, which is empty if none
+    /// is published, if any destination's policy has changed since this method was last
+    /// called.
+    ///
+    /// The current task is notified when the destination's policy changes.
+    pub fn poll_remote_policy(&mut self) -> Option<RemotePolicy> {
+        let policies = &self.registry.remote_policies;
+        {
+            let mut watchers = policies.watchers.lock().expect("remote policies lock poisoned");
+            let key = (self.router.clone(), self.dst.clone());
+            let watching = watchers.get(&key).map(|t| t.will_notify_current());
+            if watching != Some(true) {
+                watchers.insert(key, task::current());
+            }
+        }
+        let version = policies.version.load(Ordering::Acquire);
+        if version == self.remote_policies_version {
+            return None;
+        }
+        self.remote_policies_version = version;
+        let by_dst = policies.policies.lock().expect("remote policies lock poisoned");
+        let key = (self.router.clone(), self.dst.clone());
+        Some(by_dst.get(&key).cloned().unwrap_or_default())
+    }
+}
+
+/// Endpoints that have been taken out of service by an operator.
+///
+/// Ejected endpoints receive no new connections, regardless of their health, until they
+/// are reinstated. Ejections are not affected by service discovery updates.
+#[derive(Clone, Default)]
+pub struct Ejections {
+    /// Incremented on every change so that balancers need only read `ejected` when it
+    /// has changed.
+    version: Arc<AtomicUsize>,
+    ejected: Arc<Mutex<BTreeSet<(Option<String>, net::SocketAddr)>>>,
+}
+
+impl Ejections {
+    /// Ejects `addr` from all of `router`'s balancers or, if no router is specified, from
+    /// all balancers.
+    ///
+    /// Returns false if the endpoint was already ejected.
+    pub fn eject(&self, router: Option<&str>, addr: net::SocketAddr) -> bool {
+        let mut ejected = self.ejected.lock().expect("ejections lock poisoned");
+        let added = ejected.insert((router.map(String::from), addr));
+        if added {
+            self.version.fetch_add(1, Ordering::AcqRel);
+        }
+        added
+    }
+
+    /// Reinstates `addr` in `router`'s balancers or, if no router is specified, removes
+    /// all ejections of `addr`.
+    ///
+    /// Returns false if the endpoint was not ejected.
+    pub fn reinstate(&self, router: Option<&str>, addr: net::SocketAddr) -> bool {
+        let mut ejected = self.ejected.lock().expect("ejections lock poisoned");
+        let removed = match router {
+            Some(r) => ejected.remove(&(Some(r.into()), addr)),
+            None => {
+                let matches: Vec<_> = ejected
+                    .iter()
+                    .filter(|&&(_, a)| a == addr)
+                    .cloned()
+                    .collect();
+                for m in &matches {
+                    ejected.remove(m);
+                }
+                !matches.is_empty()
+            }
+        };
+        if removed {
+            self.version.fetch_add(1, Ordering::AcqRel);
+        }
+        removed
+    }
+}
+
+/// Factors by which operators scale the weights that endpoints are given by service
+/// discovery, e.g. to shift load away from an endpoint during an incident.
+///
+/// Overrides are not affected by service discovery updates: an endpoint's effective
+/// weight is its resolved weight times its multiplier (at most 1.0) until the override
+/// is cleared.
+#[derive(Clone, Default)]
+pub struct WeightOverrides {
+    /// Incremented on every change so that balancers need only read `multipliers` when
+    /// it has changed.
+    version: Arc<AtomicUsize>,
+    multipliers: Arc<Mutex<BTreeMap<(Option<String>, net::SocketAddr), f64>>>,
+}
+
+impl WeightOverrides {
+    /// Scales the weight of `addr` by `multiplier` in all of `router`'s balancers or, if
+    /// no router is specified, in all balancers.
+    ///
+    /// Returns false, leaving overrides unchanged, if `multiplier` is negative or not
+    /// finite.
+    pub fn set(&self, router: Option<&str>, addr: net::SocketAddr, multiplier: f64) -> bool {
+        if !multiplier.is_finite() || multiplier < 0.0 {
+            return false;
+        }
+        let mut multipliers = self.multipliers.lock().expect(
+            "weight overrides lock poisoned",
+        );
+        multipliers.insert((router.map(String::from), addr), multiplier);
+        self.version.fetch_add(1, Ordering::AcqRel);
+        true
+    }
+
+    /// Clears the override of `addr` in `router`'s balancers or, if no router is
+    /// specified, clears all overrides of `addr`.
+    ///
+    /// Returns false if the endpoint's weight was not overridden.
+    pub fn clear(&self, router: Option<&str>, addr: net::SocketAddr) -> bool {
+        let mut multipliers = self.multipliers.lock().expect(
+            "weight overrides lock poisoned",
+        );
+        let removed = match router {
+            Some(r) => multipliers.remove(&(Some(r.into()), addr)).is_some(),
+            None => {
+                let matches: Vec<_> = multipliers
+                    .keys()
+                    .filter(|&&(_, a)| a == addr)
+                    .cloned()
+                    .collect();
+                for m in &matches {
+                    multipliers.remove(m);
+                }
+                !matches.is_empty()
+            }
+        };
+        if removed {
+            self.version.fetch_add(1, Ordering::AcqRel);
+        }
+        removed
+    }
+}
+
+/// The balancer and connector overrides that namerd publishes, by router and
+/// destination, for routers that accept them.
+#[derive(Clone, Default)]
+pub struct RemotePolicies {
+    /// Incremented on every change so that balancers need only read `policies` when it
+    /// has changed.
+    version: Arc<AtomicUsize>,
+    policies: Arc<Mutex<BTreeMap<(String, String), RemotePolicy>>>,
+    /// The balancer most recently polled for each destination's policy, which is woken
+    /// when the policy changes, since namerd may change policy without changing the
+    /// destination's addresses.
+    watchers: Arc<Mutex<HashMap<(String, String), Task>>>,
+}
+
+impl RemotePolicies {
+    /// Records `router`'s policy for `dst`, clearing it if it is empty, and wakes the
+    /// destination's balancer if the policy changed.
+    ///
+    /// Returns false if the policy is unchanged.
+    pub fn set(&self, router: &str, dst: &str, policy: RemotePolicy) -> bool {
+        let mut policies = self.policies.lock().expect("remote policies lock poisoned");
+        let key = (router.to_owned(), dst.to_owned());
+        let changed = if policy.is_empty() {
+            policies.remove(&key).is_some()
+        } else if policies.get(&key) == Some(&policy) {
+            false
+        } else {
+            policies.insert(key.clone(), policy);
+            true
+        };
+        if changed {
+            self.version.fetch_add(1, Ordering::AcqRel);
+            let watchers = self.watchers.lock().expect("remote policies lock poisoned");
+            if let Some(t) = watchers.get(&key) {
+                t.notify();
+            }
+        }
+        changed
+    }
+}
+
+/// The names, by namespace, that are being served from a resolution cache because they
+/// have not yet been resolved by namerd since the process started.
+#[derive(Clone, Default)]
+pub struct CachedResolutions(Arc<Mutex<BTreeSet<(String, String)>>>);
+
+impl CachedResolutions {
+    pub fn insert(&self, namespace: &str, path: &str) {
+        let mut names = self.0.lock().expect("cached resolutions lock poisoned");
+        names.insert((namespace.to_owned(), path.to_owned()));
+    }
+
+    pub fn remove(&self, namespace: &str, path: &str) {
+        let mut names = self.0.lock().expect("cached resolutions lock poisoned");
+        names.remove(&(namespace.to_owned(), path.to_owned()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().expect("cached resolutions lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The proxies that were skipped, with `onProxyError: skip`, because they could not be
+/// built or bound.
+#[derive(Clone, Default)]
+pub struct ProxyErrors(Arc<Mutex<Vec<ProxyErrorState>>>);
+
+impl ProxyErrors {
+    pub fn record(&self, router: &str, server: usize, error: String) {
+        let mut errors = self.0.lock().expect("proxy errors lock poisoned");
+        errors.push(ProxyErrorState {
+            router: router.to_owned(),
+            server,
+            error,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().expect("proxy errors lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Renders each skipped proxy, as served by the admin server's
+    /// `/admin/config/errors` endpoint.
+    pub fn to_json(&self) -> String {
+        let errors = self.0.lock().expect("proxy errors lock poisoned");
+        serde_json::to_string_pretty(&*errors).expect("failed to serialize proxy errors")
+    }
+}
+
+/// The most recently sampled endpoint selections, oldest first.
+#[derive(Clone, Default)]
+pub struct SelectionTraces(Arc<Mutex<VecDeque<SelectionTrace>>>);
+
+impl SelectionTraces {
+    fn record(&self, trace: SelectionTrace) {
+        let mut traces = self.0.lock().expect("selection traces lock poisoned");
+        if traces.len() == SELECTION_TRACE_CAPACITY {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().expect("selection traces lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Renders each sampled selection, as served by the admin server's
+    /// `/admin/selection-trace` endpoint.
+    pub fn to_json(&self) -> String {
+        let traces = self.0.lock().expect("selection traces lock poisoned");
+        serde_json::to_string_pretty(&*traces).expect("failed to serialize selection traces")
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionTrace {
+    pub router: String,
+    pub dst: String,
+    /// When the selection was made, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    pub selection: SelectionExplain,
+}
+
+/// The inputs to an endpoint selection, as reported by the strategy that made it.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionExplain {
+    /// `leastLoaded` or `ewma`, or `targeted` when connecting to an endpoint already
+    /// chosen for a waiter: a sticky client's previous endpoint, or a retry's endpoint.
+    pub strategy: &'static str,
+    /// Set when endpoints in the local zone were preferred.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locality: Option<LocalityExplain>,
+    /// The available endpoints, including those that are backing off, up to a bound.
+    pub candidates: Vec<CandidateExplain>,
+    /// The number of available endpoints beyond the bound, which are not described.
+    pub omitted_candidates: usize,
+    /// Unset when no endpoint could be chosen.
+    pub chosen: Option<net::SocketAddr>,
+    /// The chosen endpoint's score, when it was compared with another endpoint.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chosen_score: Option<f64>,
+}
+
+impl SelectionExplain {
+    /// Records the score with which a strategy compared the endpoint at `addr`.
+    pub fn scored(&mut self, addr: net::SocketAddr, score: f64) {
+        if let Some(c) = self.candidates.iter_mut().find(|c| c.addr == addr) {
+            c.score = Some(score);
+        }
+    }
+
+    /// Records the endpoint that was chosen, and its score if it was compared.
+    pub fn chose(&mut self, addr: net::SocketAddr, score: Option<f64>) {
+        self.chosen = Some(addr);
+        self.chosen_score = score;
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidateExplain {
+    pub addr: net::SocketAddr,
+    pub load: usize,
+    /// The weight used to balance connections.
+    pub weight: f64,
+    /// The sum of the endpoint's connect latency and time to first byte estimates, once
+    /// either has been measured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ewma_ms: Option<f64>,
+    pub backing_off: bool,
+    /// Set for the endpoints the strategy compared; lower scores are better.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalityExplain {
+    pub zone: String,
+    pub local_candidates: usize,
+    pub local_avg_load: f64,
+    pub global_avg_load: f64,
+    /// Set when all candidates were considered because local endpoints were too loaded
+    /// (or there were none).
+    pub spilled_over: bool,
+}
+
+/// Identifies a skipped proxy by its router's label and its index among the router's
+/// servers.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyErrorState {
+    pub router: String,
+    pub server: usize,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalancerState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<&'static str>,
+    pub waiters: usize,
+    /// The most recent failure to resolve the destination, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution_error: Option<ResolutionErrorState>,
+    /// The overrides applied from the destination's remote policy, by key.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub remote_policy: BTreeMap<String, String>,
+    pub endpoints: Vec<EndpointState>,
+}
+
+impl BalancerState {
+    fn to_api(&self) -> api::Balancer {
+        api::Balancer {
+            circuit: self.circuit.map(String::from),
+            fallback: self.fallback.map(String::from),
+            waiters: self.waiters as u64,
+            resolution_error: self.resolution_error.as_ref().map(|e| {
+                api::ResolutionError {
+                    category: e.category.to_owned(),
+                    error: e.error.clone(),
+                    at_ms: e.at_ms,
+                }
+            }),
+            remote_policy: if self.remote_policy.is_empty() {
+                None
+            } else {
+                Some(self.remote_policy.clone())
+            },
+            endpoints: self.endpoints.iter().map(|ep| ep.to_api()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolutionErrorState {
+    /// The category by which the failure was counted (e.g. `http_5xx`).
+    pub category: &'static str,
+    pub error: String,
+    /// When the failure occurred, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointState {
+    pub addr: net::SocketAddr,
+    pub status: &'static str,
+    /// The weight given to the endpoint by service discovery.
+    pub weight: f64,
+    /// The factor applied to `weight` by an operator, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight_multiplier: Option<f64>,
+    /// The weight used to balance connections, after any override and while the
+    /// endpoint is warming up or on probation.
+    pub effective_weight: f64,
+    pub pending_conns: usize,
+    pub open_conns: usize,
+    pub consecutive_failures: usize,
+    pub rx_bytes: usize,
+    pub tx_bytes: usize,
+    /// The current reconnect delay, while the endpoint is backing off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff_ms: Option<u64>,
+    /// Connection attempts within the connector's stats window.
+    pub connect_attempts: usize,
+    /// The fraction of those attempts that succeeded, if any were made.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_success_rate: Option<f64>,
+    /// The most recent failed connection attempt, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<EndpointFailureState>,
+    /// The 95th percentile of the time from each connection's establishment to the first
+    /// byte sent by the endpoint, as the upper bound of a histogram bucket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_byte_p95_ms: Option<u64>,
+    /// Whether the endpoint is `healthy`, `failing` (i.e. has failed since its last
+    /// success), `failed`, or on `probation`.
+    pub failure_accrual: &'static str,
+}
+
+impl EndpointState {
+    fn to_api(&self) -> api::Endpoint {
+        api::Endpoint {
+            addr: self.addr,
+            status: self.status.to_owned(),
+            weight: self.weight,
+            weight_multiplier: self.weight_multiplier,
+            effective_weight: self.effective_weight,
+            pending_conns: self.pending_conns as u64,
+            open_conns: self.open_conns as u64,
+            consecutive_failures: self.consecutive_failures as u64,
+            rx_bytes: self.rx_bytes as u64,
+            tx_bytes: self.tx_bytes as u64,
+            backoff_ms: self.backoff_ms,
+            connect_attempts: self.connect_attempts as u64,
+            connect_success_rate: self.connect_success_rate,
+            last_failure: self.last_failure.as_ref().map(|f| {
+                api::EndpointFailure {
+                    error: f.error.clone(),
+                    at_ms: f.at_ms,
+                }
+            }),
+            first_byte_p95_ms: self.first_byte_p95_ms,
+            failure_accrual: self.failure_accrual.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointFailureState {
+    pub error: String,
+    /// When the attempt failed, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+}
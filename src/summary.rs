@@ -0,0 +1,321 @@
+//! Summarizes each router's "golden signals" for quick health triage, as served by the
+//! admin server's `/admin/summary` endpoint.
+//!
+//! Servers and resolvers record into each router's `Signals`, which are plain atomic
+//! counters, as they run. Once a second, a `Reporter` on the admin thread takes the
+//! change in each counter into a ring buffer of per-second buckets and renders the
+//! summary, so that rates are computed over a fixed number of buckets and serving the
+//! summary only copies its most recent rendering.
+
+use super::admin::api::{self, EndpointHealth, RouterSummary};
+use super::state;
+use futures::{Future, Stream};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_timer::Timer;
+
+/// The number of per-second buckets over which rates are computed.
+pub const WINDOW_SECS: usize = 60;
+
+/// The inclusive upper bound of each dispatch latency bucket, in milliseconds. Longer
+/// latencies are counted in a final, unbounded bucket.
+const DISPATCH_BOUNDS_MS: [u64; 14] = [
+    1,
+    2,
+    5,
+    10,
+    20,
+    50,
+    100,
+    200,
+    500,
+    1_000,
+    2_000,
+    5_000,
+    10_000,
+    30_000,
+];
+
+const DISPATCH_BUCKETS: usize = 15;
+
+/// The signals recorded by a single router's servers and resolver.
+///
+/// Counters only increase; the `Reporter` computes their changes.
+#[derive(Default)]
+pub struct Signals {
+    open: AtomicUsize,
+    accepts: AtomicUsize,
+    closes: AtomicUsize,
+    failures: AtomicUsize,
+    rx_bytes: AtomicUsize,
+    tx_bytes: AtomicUsize,
+    dispatch_ms: [AtomicUsize; DISPATCH_BUCKETS],
+    /// Set once the router has resolved a name via namerd.
+    resolved: AtomicBool,
+    /// When namerd last resolved one of the router's names, in milliseconds since the
+    /// Unix epoch.
+    resolved_at_ms: AtomicUsize,
+}
+
+impl Signals {
+    pub fn accepted(&self) {
+        self.accepts.fetch_add(1, Ordering::Relaxed);
+        self.open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Notes that a connection closed, and whether it failed.
+    pub fn closed(&self, failed: bool) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+        if failed {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.closes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the time from a connection's acceptance until it was connected to an
+    /// endpoint.
+    pub fn dispatched(&self, latency: Duration) {
+        let ms = latency.as_secs() * 1_000 + u64::from(latency.subsec_nanos() / 1_000_000);
+        let i = DISPATCH_BOUNDS_MS
+            .iter()
+            .position(|&b| ms <= b)
+            .unwrap_or(DISPATCH_BOUNDS_MS.len());
+        self.dispatch_ms[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records bytes read from a client.
+    pub fn read(&self, sz: usize) {
+        self.rx_bytes.fetch_add(sz, Ordering::Relaxed);
+    }
+
+    /// Records bytes written to a client.
+    pub fn wrote(&self, sz: usize) {
+        self.tx_bytes.fetch_add(sz, Ordering::Relaxed);
+    }
+
+    /// Notes that namerd resolved one of the router's names.
+    pub fn resolved(&self) {
+        self.resolved_at_ms.store(now_ms() as usize, Ordering::Relaxed);
+        self.resolved.store(true, Ordering::Release);
+    }
+
+    /// Indicates whether namerd has resolved any of the router's names.
+    pub fn is_resolved(&self) -> bool {
+        self.resolved.load(Ordering::Acquire)
+    }
+
+    fn sample(&self) -> Sample {
+        let mut dispatch_ms = [0; DISPATCH_BUCKETS];
+        for (i, n) in self.dispatch_ms.iter().enumerate() {
+            dispatch_ms[i] = n.load(Ordering::Relaxed);
+        }
+        Sample {
+            accepts: self.accepts.load(Ordering::Relaxed),
+            closes: self.closes.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            dispatch_ms,
+        }
+    }
+
+    /// The time since namerd last resolved one of the router's names, if it has.
+    fn staleness_secs(&self) -> Option<u64> {
+        if !self.resolved.load(Ordering::Acquire) {
+            return None;
+        }
+        let at = self.resolved_at_ms.load(Ordering::Relaxed) as u64;
+        Some(now_ms().saturating_sub(at) / 1_000)
+    }
+}
+
+/// Holds each router's signals and the most recently rendered summary.
+#[derive(Clone, Default)]
+pub struct Summary {
+    routers: Arc<Mutex<BTreeMap<String, Arc<Signals>>>>,
+    rendered: Arc<Mutex<String>>,
+}
+
+impl Summary {
+    /// Returns the signals recorded for `router`.
+    pub fn router(&self, router: &str) -> Arc<Signals> {
+        let mut routers = self.routers.lock().expect("summary lock poisoned");
+        routers
+            .entry(router.to_owned())
+            .or_insert_with(|| Arc::new(Signals::default()))
+            .clone()
+    }
+
+    /// Returns the most recently rendered summary, as served by the admin server.
+    pub fn to_json(&self) -> String {
+        self.rendered.lock().expect("summary lock poisoned").clone()
+    }
+
+    /// Returns a reporter that renders this summary, including the endpoint health
+    /// published to `state`.
+    pub fn reporter(&self, state: &state::Registry) -> Reporter {
+        let mut reporter = Reporter {
+            summary: self.clone(),
+            state: state.clone(),
+            windows: BTreeMap::new(),
+        };
+        reporter.tick();
+        reporter
+    }
+}
+
+/// Computes each router's windowed signals, once a second, on the admin thread.
+pub struct Reporter {
+    summary: Summary,
+    state: state::Registry,
+    windows: BTreeMap<String, Window>,
+}
+
+impl Reporter {
+    /// Renders the summary every second.
+    pub fn run(mut self, timer: &Timer) -> Box<Future<Item = (), Error = ()>> {
+        let ticks = timer.interval(Duration::from_secs(1)).map_err(|_| {});
+        Box::new(ticks.for_each(move |_| {
+            self.tick();
+            Ok(())
+        }))
+    }
+
+    /// Takes each router's signals into its window and renders the summary.
+    fn tick(&mut self) {
+        let routers: Vec<(String, Arc<Signals>)> = {
+            let routers = self.summary.routers.lock().expect("summary lock poisoned");
+            routers.iter().map(|(r, s)| (r.clone(), s.clone())).collect()
+        };
+        let mut endpoints = self.state.endpoint_health();
+
+        let mut summaries = BTreeMap::new();
+        for (router, signals) in routers {
+            let window = self.windows.entry(router.clone()).or_insert_with(Window::default);
+            window.push(signals.sample());
+            let health = endpoints.remove(&router).unwrap_or_default();
+            summaries.insert(router, window.summarize(&signals, health));
+        }
+
+        let rendered = api::Summary {
+            api_version: api::VERSION,
+            window_secs: WINDOW_SECS as u64,
+            routers: summaries,
+        };
+        let json = api::to_json(&rendered);
+        *self.summary.rendered.lock().expect("summary lock poisoned") = json;
+    }
+}
+
+/// Cumulative counter values, or their change over a second.
+#[derive(Clone, Copy, Default)]
+struct Sample {
+    accepts: usize,
+    closes: usize,
+    failures: usize,
+    rx_bytes: usize,
+    tx_bytes: usize,
+    dispatch_ms: [usize; DISPATCH_BUCKETS],
+}
+
+impl Sample {
+    fn delta(&self, prior: &Sample) -> Sample {
+        let mut dispatch_ms = [0; DISPATCH_BUCKETS];
+        for i in 0..DISPATCH_BUCKETS {
+            dispatch_ms[i] = self.dispatch_ms[i].wrapping_sub(prior.dispatch_ms[i]);
+        }
+        Sample {
+            accepts: self.accepts.wrapping_sub(prior.accepts),
+            closes: self.closes.wrapping_sub(prior.closes),
+            failures: self.failures.wrapping_sub(prior.failures),
+            rx_bytes: self.rx_bytes.wrapping_sub(prior.rx_bytes),
+            tx_bytes: self.tx_bytes.wrapping_sub(prior.tx_bytes),
+            dispatch_ms,
+        }
+    }
+
+    fn add(&mut self, other: &Sample) {
+        self.accepts += other.accepts;
+        self.closes += other.closes;
+        self.failures += other.failures;
+        self.rx_bytes += other.rx_bytes;
+        self.tx_bytes += other.tx_bytes;
+        for i in 0..DISPATCH_BUCKETS {
+            self.dispatch_ms[i] += other.dispatch_ms[i];
+        }
+    }
+}
+
+/// A ring buffer holding the change in a router's signals over each of the last
+/// `WINDOW_SECS` seconds.
+#[derive(Default)]
+struct Window {
+    prior: Sample,
+    buckets: Vec<Sample>,
+    /// The index of the oldest bucket, once the buffer is full.
+    next: usize,
+}
+
+impl Window {
+    fn push(&mut self, sample: Sample) {
+        let delta = sample.delta(&self.prior);
+        self.prior = sample;
+        if self.buckets.len() < WINDOW_SECS {
+            self.buckets.push(delta);
+        } else {
+            self.buckets[self.next] = delta;
+            self.next = (self.next + 1) % WINDOW_SECS;
+        }
+    }
+
+    fn summarize(&self, signals: &Signals, endpoints: EndpointHealth) -> RouterSummary {
+        let mut total = Sample::default();
+        for b in &self.buckets {
+            total.add(b);
+        }
+        // Until the window has filled, rates are computed over the time observed.
+        let secs = self.buckets.len().max(1) as f64;
+        let ended = total.closes + total.failures;
+        RouterSummary {
+            open_connections: signals.open.load(Ordering::Relaxed) as u64,
+            connects_per_sec: total.accepts as f64 / secs,
+            failure_rate: if ended == 0 {
+                None
+            } else {
+                Some(total.failures as f64 / ended as f64)
+            },
+            dispatch_p95_ms: percentile_ms(&total.dispatch_ms, 95.0),
+            namerd_staleness_secs: signals.staleness_secs(),
+            endpoints,
+            rx_bytes_per_sec: total.rx_bytes as f64 / secs,
+            tx_bytes_per_sec: total.tx_bytes as f64 / secs,
+        }
+    }
+}
+
+/// The upper bound, in milliseconds, of the bucket holding the `p`th percentile, if any
+/// latencies have been recorded. Latencies beyond the largest bound are reported as
+/// `u64::MAX`.
+fn percentile_ms(counts: &[usize; DISPATCH_BUCKETS], p: f64) -> Option<u64> {
+    let total: usize = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = ((p / 100.0) * total as f64).ceil().max(1.0) as usize;
+    let mut seen = 0;
+    for (i, &n) in counts.iter().enumerate() {
+        seen += n;
+        if seen >= rank {
+            return Some(DISPATCH_BOUNDS_MS.get(i).cloned().unwrap_or(u64::max_value()));
+        }
+    }
+    Some(u64::max_value())
+}
+
+fn now_ms() -> u64 {
+    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    t.as_secs() * 1_000 + u64::from(t.subsec_nanos() / 1_000_000)
+}
@@ -0,0 +1,188 @@
+//! Contains panics, so that a bug triggered by one connection or one name does not
+//! unwind through the reactor and take down every other task on it.
+//!
+//! Each connection's future is polled with its panics caught, and a connection that
+//! panics is torn down as though it had failed. Long-lived tasks (e.g. a name's
+//! resolution, a listener, or a balancer) are supervised by `Restart`, which drops a task
+//! that panics, since its state may be inconsistent, and rebuilds it after a backoff.
+//! Whatever a task owns, such as a listener's open connections or a balancer's
+//! endpoints, is dropped with it.
+//!
+//! The backoff doubles with each consecutive panic, from 100ms to 30s, and is reset once
+//! a task has run for a minute without panicking.
+//!
+//! Panics are logged with their location by the hook installed by `log_panics`. Rust's
+//! default hook, which is still run, prints a backtrace when `RUST_BACKTRACE=1` is set.
+
+use super::metrics;
+use futures::{Async, Future, Poll};
+use std::{cmp, panic};
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::{Sleep, Timer};
+
+const BASE_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_SECS: u64 = 30;
+const RESET_AFTER_SECS: u64 = 60;
+
+/// Logs each panic, with its location, before it is handled by the previous panic hook.
+pub fn log_panics() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_else(|| "an unknown location".to_owned());
+        error!("panicked at {}: {}", location, message(info.payload()));
+        previous(info);
+    }));
+}
+
+/// Describes a panic by its payload, which is usually a message.
+pub fn message(payload: &(Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        return (*s).to_owned();
+    }
+    if let Some(s) = payload.downcast_ref::<String>() {
+        return s.clone();
+    }
+    "(no message)".to_owned()
+}
+
+/// Counts a supervised task's panics and delays its recovery from each.
+struct Supervisor {
+    name: String,
+    timer: Timer,
+    panics: Arc<metrics::Counter>,
+    /// Panics since the task last ran for `RESET_AFTER_SECS` without panicking.
+    consecutive: u32,
+    /// When the task began running since it last recovered.
+    since: Instant,
+    /// Set while the task's recovery is delayed.
+    backoff: Option<Sleep>,
+}
+
+impl Supervisor {
+    fn new(name: String, timer: &Timer, panics: Arc<metrics::Counter>) -> Supervisor {
+        Supervisor {
+            name,
+            timer: timer.clone(),
+            panics,
+            consecutive: 0,
+            since: Instant::now(),
+            backoff: None,
+        }
+    }
+
+    /// Polls the task with `poll`, catching its panics. Returns `None` if the task has
+    /// panicked or its recovery is still delayed, and `Some` once it may be polled.
+    fn poll<T, E, F>(&mut self, poll: F) -> Option<Poll<T, E>>
+    where
+        F: FnOnce() -> Poll<T, E>,
+    {
+        match panic::catch_unwind(AssertUnwindSafe(poll)) {
+            Ok(poll) => Some(poll),
+            Err(payload) => {
+                self.panics.incr(1);
+                if self.since.elapsed() >= Duration::from_secs(RESET_AFTER_SECS) {
+                    self.consecutive = 0;
+                }
+                self.consecutive += 1;
+                let delay = backoff(self.consecutive);
+                error!(
+                    "{} panicked: {}; recovering in {:?}",
+                    self.name,
+                    message(&*payload),
+                    delay
+                );
+                self.backoff = Some(self.timer.sleep(delay));
+                None
+            }
+        }
+    }
+
+    /// Indicates whether a task that panicked may recover.
+    fn poll_recovered(&mut self) -> bool {
+        let ready = match self.backoff.as_mut() {
+            None => return true,
+            Some(sleep) => {
+                match sleep.poll() {
+                    Ok(Async::NotReady) => false,
+                    // If the timer fails, the task recovers immediately.
+                    Ok(Async::Ready(())) | Err(_) => true,
+                }
+            }
+        };
+        if ready {
+            info!("{} recovered", self.name);
+            self.backoff = None;
+            self.since = Instant::now();
+        }
+        ready
+    }
+}
+
+/// The delay before a task recovers from its `consecutive`th panic.
+fn backoff(consecutive: u32) -> Duration {
+    let exp = cmp::min(consecutive.saturating_sub(1), 16);
+    cmp::min(
+        Duration::from_millis(BASE_BACKOFF_MS) * (1u32 << exp),
+        Duration::from_secs(MAX_BACKOFF_SECS),
+    )
+}
+
+/// Runs the task built by `mk`, rebuilding it whenever it panics.
+pub fn restart<M, T>(
+    name: String,
+    mut mk: M,
+    timer: &Timer,
+    panics: Arc<metrics::Counter>,
+) -> Restart<M, T>
+where
+    M: FnMut() -> T,
+    T: Future<Item = (), Error = ()>,
+{
+    let task = Some(mk());
+    Restart {
+        mk,
+        task,
+        supervisor: Supervisor::new(name, timer, panics),
+    }
+}
+
+/// A task that is rebuilt whenever it panics.
+pub struct Restart<M, T> {
+    mk: M,
+    /// Unset from when the task panics until it is rebuilt.
+    task: Option<T>,
+    supervisor: Supervisor,
+}
+
+impl<M, T> Future for Restart<M, T>
+where
+    M: FnMut() -> T,
+    T: Future<Item = (), Error = ()>,
+{
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if self.task.is_none() {
+                if !self.supervisor.poll_recovered() {
+                    return Ok(Async::NotReady);
+                }
+                self.task = Some((self.mk)());
+            }
+            let polled = {
+                let task = self.task.as_mut().expect("task must be built");
+                self.supervisor.poll(|| task.poll())
+            };
+            match polled {
+                Some(poll) => return poll,
+                // The task is dropped, since its state may be inconsistent.
+                None => self.task = None,
+            }
+        }
+    }
+}
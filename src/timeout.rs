@@ -0,0 +1,50 @@
+//! Bounds the time that futures may take to complete, without boxing them.
+
+use futures::{Async, Future, Poll};
+use std::io;
+use std::time::Duration;
+use tokio_timer::{Sleep, Timer};
+
+/// Fails `fut` with `TimedOut` if it does not complete within `timeout` of first being
+/// polled. If `timeout` is `None`, `fut` is never timed out.
+pub fn timeout<F>(fut: F, timeout: Option<Duration>, timer: &Timer) -> Timeout<F>
+where
+    F: Future<Error = io::Error>,
+{
+    Timeout {
+        fut,
+        timer: timeout.map(|t| (timer.clone(), t)),
+        sleep: None,
+    }
+}
+
+pub struct Timeout<F> {
+    fut: F,
+    /// Set until the future is first polled.
+    timer: Option<(Timer, Duration)>,
+    sleep: Option<Sleep>,
+}
+
+impl<F> Future for Timeout<F>
+where
+    F: Future<Error = io::Error>,
+{
+    type Item = F::Item;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<F::Item, io::Error> {
+        if let Some((timer, duration)) = self.timer.take() {
+            self.sleep = Some(timer.sleep(duration));
+        }
+
+        if let Async::Ready(item) = self.fut.poll()? {
+            return Ok(Async::Ready(item));
+        }
+
+        match self.sleep.as_mut().map(|s| s.poll()) {
+            None |
+            Some(Ok(Async::NotReady)) => Ok(Async::NotReady),
+            Some(Ok(Async::Ready(_))) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            Some(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+        }
+    }
+}
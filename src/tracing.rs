@@ -0,0 +1,275 @@
+//! Connection-level tracing.
+//!
+//! A sampled connection carries a `Span` that records when each stage of the connection
+//! was reached, relative to when it was accepted. Once the connection closes, the span
+//! is exported as a line of JSON. Sampling is decided when a connection is accepted, so
+//! unsampled connections carry no span.
+
+use super::Path;
+use super::balancer::Generation;
+use super::connection::CloseReason;
+use super::schema::Schema;
+use super::server::TlsProperties;
+use rand;
+use serde_json;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_SAMPLE_RATE: f64 = 1.0;
+
+#[derive(Debug)]
+pub enum Error {
+    /// `export` must configure exactly one of `udp` or `file`.
+    InvalidExport,
+    /// The sample rate must be within [0.0, 1.0].
+    InvalidSampleRate(f64),
+    Io(io::Error),
+}
+
+/// Configures connection tracing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TracingConfig {
+    /// Where completed spans are written.
+    pub export: TraceExportConfig,
+    /// The fraction of connections that are traced.
+    pub sample_rate: Option<f64>,
+}
+
+/// Determines where completed spans are written.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TraceExportConfig {
+    /// Sends each span as a datagram to this address.
+    pub udp: Option<net::SocketAddr>,
+    /// Appends each span as a line to this file.
+    pub file: Option<String>,
+}
+
+impl TracingConfig {
+    /// Describes the fields of a tracing configuration.
+    pub fn schema() -> Schema {
+        Schema::of::<TracingConfig>(vec![("export", Schema::of::<TraceExportConfig>(vec![]))])
+    }
+
+    /// Validates this configuration and opens its exporter.
+    pub fn mk_tracer(&self) -> Result<Tracer, Error> {
+        let sample_rate = self.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        if !(0.0 <= sample_rate && sample_rate <= 1.0) {
+            return Err(Error::InvalidSampleRate(sample_rate));
+        }
+        let exporter = match (self.export.udp, self.export.file.as_ref()) {
+            (Some(addr), None) => {
+                let any = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let sock = net::UdpSocket::bind(any).map_err(Error::Io)?;
+                sock.set_nonblocking(true).map_err(Error::Io)?;
+                Exporter::Udp(sock, addr)
+            }
+            (None, Some(path)) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(Error::Io)?;
+                Exporter::File(RefCell::new(file))
+            }
+            _ => return Err(Error::InvalidExport),
+        };
+        Ok(Tracer {
+            sample_rate,
+            exporter: Rc::new(exporter),
+        })
+    }
+}
+
+/// Decides which connections are traced.
+#[derive(Clone)]
+pub struct Tracer {
+    sample_rate: f64,
+    exporter: Rc<Exporter>,
+}
+
+impl Tracer {
+    /// Starts a span for a newly-accepted connection, if it is sampled.
+    pub fn sample(
+        &self,
+        src_addr: net::SocketAddr,
+        srv_addr: net::SocketAddr,
+        dst_name: &Path,
+    ) -> Option<Span> {
+        if self.sample_rate < 1.0 && rand::random::<f64>() >= self.sample_rate {
+            return None;
+        }
+        // Each connection's span is the root of its own trace.
+        let span_id = rand::random::<u64>();
+        let record = SpanRecord {
+            trace_id: format!("{:016x}", span_id),
+            span_id: format!("{:016x}", span_id),
+            // TODO A parent may be propagated by clients once the PROXY protocol is
+            // supported.
+            parent_id: None,
+            src_addr,
+            srv_addr,
+            dst_name: dst_name.as_str().to_owned(),
+            dst_addr: None,
+            resolution_generation: None,
+            resolution_updated_at_ms: None,
+            resolution_age_ms: None,
+            start_us: micros(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(
+                Duration::from_secs(0),
+            )),
+            ready_us: None,
+            connected_us: None,
+            first_client_byte_us: None,
+            first_server_byte_us: None,
+            closed_us: None,
+            close_reason: None,
+            tls: None,
+        };
+        Some(Span(Rc::new(RefCell::new(Inner {
+            accepted: Instant::now(),
+            record,
+            exporter: self.exporter.clone(),
+        }))))
+    }
+}
+
+enum Exporter {
+    Udp(net::UdpSocket, net::SocketAddr),
+    File(RefCell<File>),
+}
+
+impl Exporter {
+    fn export(&self, record: &SpanRecord) {
+        let json = match serde_json::to_vec(record) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("failed to serialize span: {}", e);
+                return;
+            }
+        };
+        let res = match *self {
+            // Spans are dropped rather than blocking the reactor.
+            Exporter::Udp(ref sock, ref addr) => sock.send_to(&json, addr).map(|_| ()),
+            Exporter::File(ref file) => {
+                let mut file = file.borrow_mut();
+                file.write_all(&json).and_then(|_| file.write_all(b"\n"))
+            }
+        };
+        if let Err(e) = res {
+            debug!("failed to export span: {}", e);
+        }
+    }
+}
+
+/// Records the progress of a traced connection.
+#[derive(Clone)]
+pub struct Span(Rc<RefCell<Inner>>);
+
+struct Inner {
+    accepted: Instant,
+    record: SpanRecord,
+    exporter: Rc<Exporter>,
+}
+
+impl Span {
+    /// The downstream connection, including any TLS handshake, is established.
+    pub fn ready(&self) {
+        self.mark(|r| &mut r.ready_us);
+    }
+
+    /// The client's TLS handshake has completed with the given parameters.
+    pub fn negotiated(&self, tls: &TlsProperties) {
+        self.0.borrow_mut().record.tls = Some(tls.clone());
+    }
+
+    /// An upstream connection to `dst_addr` has been obtained from the balancer,
+    /// selected from the endpoints' `generation`.
+    pub fn connected(&self, dst_addr: net::SocketAddr, generation: Option<Generation>) {
+        {
+            let record = &mut self.0.borrow_mut().record;
+            record.dst_addr = Some(dst_addr);
+            if let Some(g) = generation {
+                record.resolution_generation = Some(g.number);
+                record.resolution_updated_at_ms = Some(g.updated_at_ms());
+                record.resolution_age_ms = Some(g.age_ms());
+            }
+        }
+        self.mark(|r| &mut r.connected_us);
+    }
+
+    /// Bytes have been read from the client.
+    pub fn client_byte(&self) {
+        self.mark(|r| &mut r.first_client_byte_us);
+    }
+
+    /// Bytes from the server have been written to the client.
+    pub fn server_byte(&self) {
+        self.mark(|r| &mut r.first_server_byte_us);
+    }
+
+    /// Completes the span and exports it. Spans are only exported once.
+    pub fn close(&self, reason: CloseReason) {
+        if !self.mark(|r| &mut r.closed_us) {
+            return;
+        }
+        let mut inner = self.0.borrow_mut();
+        inner.record.close_reason = Some(reason.as_str());
+        let inner = &*inner;
+        inner.exporter.export(&inner.record);
+    }
+
+    /// Records the time elapsed since the connection was accepted in the field chosen
+    /// by `field`, unless it has already been recorded. Returns true if the time was
+    /// recorded.
+    fn mark<F>(&self, field: F) -> bool
+    where
+        F: FnOnce(&mut SpanRecord) -> &mut Option<u64>,
+    {
+        let mut inner = self.0.borrow_mut();
+        let elapsed = micros(inner.accepted.elapsed());
+        let t = field(&mut inner.record);
+        if t.is_some() {
+            return false;
+        }
+        *t = Some(elapsed);
+        true
+    }
+}
+
+/// The exported form of a span. Times are in microseconds since the connection was
+/// accepted, except for `start_us`, which is since the Unix epoch.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanRecord {
+    trace_id: String,
+    span_id: String,
+    parent_id: Option<String>,
+    src_addr: net::SocketAddr,
+    srv_addr: net::SocketAddr,
+    dst_name: String,
+    dst_addr: Option<net::SocketAddr>,
+    /// The generation of the endpoints from which `dst_addr` was selected, when they
+    /// were last changed (since the Unix epoch), and how long before the connection was
+    /// dispatched.
+    resolution_generation: Option<u64>,
+    resolution_updated_at_ms: Option<u64>,
+    resolution_age_ms: Option<u64>,
+    start_us: u64,
+    ready_us: Option<u64>,
+    connected_us: Option<u64>,
+    first_client_byte_us: Option<u64>,
+    first_server_byte_us: Option<u64>,
+    closed_us: Option<u64>,
+    close_reason: Option<&'static str>,
+    /// The parameters negotiated with the client, if the server terminates TLS.
+    tls: Option<TlsProperties>,
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1_000)
+}
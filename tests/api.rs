@@ -0,0 +1,191 @@
+extern crate linkerd_tcp;
+extern crate serde_json;
+
+use linkerd_tcp::admin::api::{self, Balancer, Endpoint, EndpointFailure, EndpointHealth,
+                              ResolutionError, RouterSummary, State, Summary, Unsupported};
+use std::collections::BTreeMap;
+
+/// The v1 responses that tools depend on. Changes to these files must be additive.
+static STATE_V1: &'static str = include_str!("golden/state.v1.json");
+static SUMMARY_V1: &'static str = include_str!("golden/summary.v1.json");
+
+fn state_v1() -> State {
+    let healthy = Endpoint {
+        addr: "10.0.0.1:7777".parse().unwrap(),
+        status: "available".into(),
+        weight: 1.0,
+        weight_multiplier: None,
+        effective_weight: 1.0,
+        pending_conns: 0,
+        open_conns: 3,
+        consecutive_failures: 0,
+        rx_bytes: 4096,
+        tx_bytes: 1024,
+        backoff_ms: None,
+        connect_attempts: 10,
+        connect_success_rate: Some(1.0),
+        last_failure: None,
+        first_byte_p95_ms: Some(5),
+        failure_accrual: "healthy".into(),
+    };
+    let failed = Endpoint {
+        addr: "10.0.0.2:7777".parse().unwrap(),
+        status: "failed".into(),
+        weight: 0.5,
+        weight_multiplier: Some(0.1),
+        effective_weight: 0.05,
+        pending_conns: 1,
+        open_conns: 0,
+        consecutive_failures: 4,
+        rx_bytes: 0,
+        tx_bytes: 0,
+        backoff_ms: Some(800),
+        connect_attempts: 4,
+        connect_success_rate: Some(0.0),
+        last_failure: Some(EndpointFailure {
+            error: "connection refused".into(),
+            at_ms: 1_500_000_001_000,
+        }),
+        first_byte_p95_ms: None,
+        failure_accrual: "failed".into(),
+    };
+    let mut remote_policy = BTreeMap::new();
+    remote_policy.insert("loadBalancer".to_owned(), "io.l5d.ewma".to_owned());
+    let balancer = Balancer {
+        circuit: Some("closed".into()),
+        fallback: None,
+        waiters: 2,
+        resolution_error: Some(ResolutionError {
+            category: "http_5xx".into(),
+            error: "namerd responded 503".into(),
+            at_ms: 1_500_000_000_000,
+        }),
+        remote_policy: Some(remote_policy),
+        endpoints: vec![healthy, failed],
+    };
+
+    let mut balancers = BTreeMap::new();
+    balancers.insert("/svc/echo".to_owned(), balancer);
+    let mut routers = BTreeMap::new();
+    routers.insert("default".to_owned(), balancers);
+    State {
+        api_version: 1,
+        routers,
+    }
+}
+
+fn summary_v1() -> Summary {
+    let mut routers = BTreeMap::new();
+    routers.insert(
+        "default".to_owned(),
+        RouterSummary {
+            open_connections: 3,
+            connects_per_sec: 1.5,
+            failure_rate: Some(0.25),
+            dispatch_p95_ms: Some(10),
+            namerd_staleness_secs: Some(2),
+            endpoints: EndpointHealth {
+                healthy: 1,
+                failing: 0,
+                failed: 1,
+                probation: 0,
+            },
+            rx_bytes_per_sec: 2048.0,
+            tx_bytes_per_sec: 512.0,
+        },
+    );
+    routers.insert(
+        "idle".to_owned(),
+        RouterSummary {
+            open_connections: 0,
+            connects_per_sec: 0.0,
+            failure_rate: None,
+            dispatch_p95_ms: None,
+            namerd_staleness_secs: None,
+            endpoints: EndpointHealth::default(),
+            rx_bytes_per_sec: 0.0,
+            tx_bytes_per_sec: 0.0,
+        },
+    );
+    Summary {
+        api_version: 1,
+        window_secs: 60,
+        routers,
+    }
+}
+
+fn golden(json: &str) -> serde_json::Value {
+    serde_json::from_str(json).expect("invalid golden file")
+}
+
+#[test]
+fn serializes_state_v1() {
+    let state = serde_json::to_value(&state_v1()).unwrap();
+    assert_eq!(state, golden(STATE_V1));
+}
+
+#[test]
+fn serializes_summary_v1() {
+    let summary = serde_json::to_value(&summary_v1()).unwrap();
+    assert_eq!(summary, golden(SUMMARY_V1));
+}
+
+#[test]
+fn deserializes_golden_responses() {
+    let state: State = serde_json::from_str(STATE_V1).expect("invalid state");
+    assert_eq!(state, state_v1());
+    let summary: Summary = serde_json::from_str(SUMMARY_V1).expect("invalid summary");
+    assert_eq!(summary, summary_v1());
+}
+
+#[test]
+fn ignores_fields_added_to_a_version() {
+    let mut state = golden(STATE_V1);
+    state["routers"]["default"]["/svc/echo"]["addedLater"] = serde_json::Value::Bool(true);
+    let state: State = serde_json::from_value(state).expect("added field rejected");
+    assert_eq!(state, state_v1());
+
+    let mut summary = golden(SUMMARY_V1);
+    summary["routers"]["idle"]["addedLater"] = serde_json::Value::from(7);
+    let summary: Summary = serde_json::from_value(summary).expect("added field rejected");
+    assert_eq!(summary, summary_v1());
+}
+
+#[test]
+fn negotiates_versions() {
+    let v1 = "application/vnd.linkerd-tcp.v1+json";
+    assert_eq!(api::media_type(1), v1);
+
+    // Unversioned requests are served the latest version.
+    assert_eq!(api::negotiate(None, None), Ok(api::VERSION));
+    assert_eq!(api::negotiate(None, Some("application/json")), Ok(api::VERSION));
+    assert_eq!(api::negotiate(Some("pretty=true"), Some("*/*")), Ok(api::VERSION));
+
+    assert_eq!(api::negotiate(Some("v=1"), None), Ok(1));
+    assert_eq!(api::negotiate(None, Some(v1)), Ok(1));
+    assert_eq!(
+        api::negotiate(None, Some("application/vnd.linkerd-tcp.v9+json;q=0.9, \
+                                   application/vnd.linkerd-tcp.v1+json")),
+        Ok(1)
+    );
+    // The query takes precedence over the Accept header.
+    assert_eq!(
+        api::negotiate(Some("v=1"), Some("application/vnd.linkerd-tcp.v9+json")),
+        Ok(1)
+    );
+
+    assert_eq!(
+        api::negotiate(Some("v=9"), Some(v1)),
+        Err(Unsupported("9".into()))
+    );
+    assert_eq!(
+        api::negotiate(Some("v=latest"), None),
+        Err(Unsupported("latest".into()))
+    );
+    let refused = api::negotiate(None, Some("application/vnd.linkerd-tcp.v9+json"));
+    assert_eq!(refused, Err(Unsupported("9".into())));
+    assert_eq!(
+        refused.unwrap_err().to_string(),
+        "unsupported API version 9; supported versions: 1"
+    );
+}
@@ -0,0 +1,170 @@
+extern crate linkerd_tcp;
+
+use linkerd_tcp::app::ConnectorConfig;
+use linkerd_tcp::bench::{self, Phase, Stats};
+use linkerd_tcp::lb::{SelectionStrategy, time_selections};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+#[test]
+fn reports_latency_percentiles() {
+    let mut stats = Stats::new();
+    // Recorded out of order, since connections finish out of order.
+    for i in (1..1001).rev() {
+        stats.record_connect(ms(i));
+    }
+    for i in 1..11 {
+        stats.record_roundtrip(ms(i * 10), 1024);
+    }
+
+    let report = stats.report(Duration::from_secs(2));
+    let connect = report.connect_latency.expect("no connect latencies");
+    assert_eq!(connect.p50, ms(500));
+    assert_eq!(connect.p90, ms(900));
+    assert_eq!(connect.p99, ms(990));
+    assert_eq!(connect.p999, ms(999));
+    assert_eq!(connect.max, ms(1000));
+
+    let roundtrip = report.roundtrip_latency.expect("no roundtrip latencies");
+    assert_eq!(roundtrip.p50, ms(50));
+    assert_eq!(roundtrip.p90, ms(90));
+    assert_eq!(roundtrip.p99, ms(100));
+    assert_eq!(roundtrip.max, ms(100));
+}
+
+#[test]
+fn reports_rates() {
+    let mut stats = Stats::new();
+    for _ in 0..50 {
+        stats.record_connect(ms(1));
+        stats.record_roundtrip(ms(2), 1000);
+    }
+    stats.record_skip();
+
+    let report = stats.report(Duration::from_secs(5));
+    assert_eq!(report.connects, 50);
+    assert_eq!(report.roundtrips, 50);
+    assert_eq!(report.bytes, 50_000);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(report.connects_per_sec(), 10.0);
+    assert_eq!(report.bytes_per_sec(), 10_000.0);
+
+    let empty = Stats::new().report(Duration::from_secs(0));
+    assert_eq!(empty.connect_latency, None);
+    assert_eq!(empty.roundtrip_latency, None);
+    assert_eq!(empty.connects_per_sec(), 0.0);
+}
+
+#[test]
+fn counts_errors_by_phase_and_kind() {
+    let mut stats = Stats::new();
+    stats.record_error(Phase::Connect, io::ErrorKind::ConnectionRefused);
+    stats.record_error(Phase::Connect, io::ErrorKind::ConnectionRefused);
+    stats.record_error(Phase::Connect, io::ErrorKind::TimedOut);
+    stats.record_error(Phase::Read, io::ErrorKind::UnexpectedEof);
+
+    let report = stats.report(Duration::from_secs(1));
+    let errors: Vec<(&str, u64)> = report.errors.iter().map(|(e, &n)| (e.as_str(), n)).collect();
+    assert_eq!(
+        errors,
+        vec![
+            ("connect ConnectionRefused", 2),
+            ("connect TimedOut", 1),
+            ("read UnexpectedEof", 1),
+        ]
+    );
+    assert!(report.to_string().contains("errors: 4\n"));
+}
+
+/// Echoes each connection's bytes until it is closed.
+fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || for conn in listener.incoming() {
+        let mut conn = match conn {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                match conn.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(sz) => {
+                        if conn.write_all(&buf[..sz]).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    });
+    addr
+}
+
+#[test]
+fn generates_load_against_a_listener() {
+    let config = bench::Config {
+        target: echo_server(),
+        connections: 10,
+        rate: 50,
+        payload_bytes: 2048,
+        duration: Duration::from_secs(1),
+        client: ConnectorConfig::default(),
+    };
+    let report = bench::run(&config).expect("bench failed");
+    assert!(report.errors.is_empty(), "errors: {:?}", report.errors);
+    assert!(
+        40 <= report.connects && report.connects <= 50,
+        "connects: {}",
+        report.connects
+    );
+    assert_eq!(report.roundtrips, report.connects);
+    assert_eq!(report.bytes, report.roundtrips * 2 * 2048);
+    assert!(report.roundtrip_latency.is_some());
+}
+
+#[test]
+fn counts_refused_connections() {
+    // Nothing listens on the port once the listener is dropped.
+    let target = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = bench::Config {
+        target,
+        connections: 10,
+        rate: 20,
+        payload_bytes: 16,
+        duration: Duration::from_millis(500),
+        client: ConnectorConfig::default(),
+    };
+    let report = bench::run(&config).expect("bench failed");
+    assert_eq!(report.connects, 0);
+    assert_eq!(report.connect_latency, None);
+    let refused = report.errors.get("connect ConnectionRefused").cloned().unwrap_or(0);
+    assert!(refused > 0, "errors: {:?}", report.errors);
+}
+
+#[test]
+fn selects_among_many_endpoints_quickly() {
+    // Bounds are generous enough for unoptimized builds on a loaded machine; they guard
+    // against selection becoming more than linear in the number of endpoints.
+    let bounds = [
+        (SelectionStrategy::LeastLoaded, Duration::from_millis(1)),
+        (SelectionStrategy::Ewma, Duration::from_millis(50)),
+        (SelectionStrategy::Locality, Duration::from_millis(50)),
+    ];
+    for &(strategy, per_selection) in &bounds {
+        let elapsed = time_selections(strategy, 10_000, 100);
+        assert!(
+            elapsed < per_selection * 100,
+            "{:?} took {:?} for 100 selections among 10000 endpoints",
+            strategy,
+            elapsed
+        );
+    }
+}
@@ -0,0 +1,1250 @@
+extern crate linkerd_tcp;
+
+use linkerd_tcp::app::{self, AppBuilder, AppConfig, ChaosConfig, ConnectorConfig, Interpreter,
+                       RouterBuilder};
+use linkerd_tcp::{Error, duration};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+static DURATIONS_CONFIG: &'static str = "
+admin:
+  port: 0
+  metricsIntervalSecs: 1m
+  graceSecs: 30
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: http://127.0.0.1:4180
+      namespace: default
+      periodSecs: 500ms
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 1s
+        connectionLifetimeSecs: 1h30m
+    client:
+      kind: io.l5d.global
+      connectTimeoutMs: 250
+";
+
+/// `DURATIONS_CONFIG`, with its fields in a different order.
+static REORDERED_DURATIONS_CONFIG: &'static str = "
+routers:
+  - servers:
+      - connectionLifetimeSecs: 1h30m
+        dstName: /svc/echo
+        connectTimeoutMs: 1s
+        port: 0
+    client:
+      connectTimeoutMs: 250
+      kind: io.l5d.global
+    interpreter:
+      periodSecs: 500ms
+      namespace: default
+      baseUrl: http://127.0.0.1:4180
+      kind: io.l5d.namerd.http
+    label: test
+admin:
+  graceSecs: 30
+  metricsIntervalSecs: 1m
+  port: 0
+";
+
+/// Includes fields unknown to this release, nested within lists and tagged sections.
+static UNKNOWN_FIELDS_CONFIG: &'static str = "
+configVersion: 1
+admin:
+  port: 0
+  bogus: true
+routers:
+  - label: a
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: http://127.0.0.1:4180
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/a
+  - label: b
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: http://127.0.0.1:4180
+      namespace: default
+      periodSecs: 1
+      bogus: true
+    servers:
+      - port: 0
+        dstName: /svc/b
+  - label: c
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: http://127.0.0.1:4180
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/c
+      - port: 0
+        dstName: /svc/c
+        bogus: true
+    client:
+      kind: io.l5d.static
+      configs:
+        - prefix: /svc/c
+          connectBackoff:
+            baseBackoffMs: 10
+            bogus: true
+";
+
+#[test]
+fn parses_durations_with_units() {
+    assert_eq!(duration::parse("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(duration::parse("10s"), Ok(Duration::from_secs(10)));
+    assert_eq!(duration::parse("2m"), Ok(Duration::from_secs(120)));
+    assert_eq!(duration::parse("1h30m"), Ok(Duration::from_secs(5400)));
+    assert_eq!(duration::parse("1s500ms"), Ok(Duration::from_millis(1500)));
+
+    assert!(duration::parse("").is_err());
+    assert!(duration::parse("10").is_err());
+    assert!(duration::parse("10x").is_err());
+    assert!(duration::parse("ms").is_err());
+}
+
+#[test]
+fn accepts_durations_in_config() {
+    let config: AppConfig = DURATIONS_CONFIG.parse().expect("failed to parse config");
+    config.into_app().expect("failed to load config");
+}
+
+#[test]
+fn rejects_malformed_durations() {
+    let config = DURATIONS_CONFIG.replace("periodSecs: 500ms", "periodSecs: 10x");
+    let err = config.parse::<AppConfig>().err().expect("parsed malformed duration");
+    let msg = format!("{:?}", err);
+    assert!(msg.contains("10x"), "{}", msg);
+    assert!(msg.contains("/routers/0/interpreter/periodSecs"), "{}", msg);
+}
+
+#[test]
+fn rejects_overflowing_durations() {
+    assert!(duration::parse("18446744073709551615h").is_err());
+    assert!(duration::parse("18446744073709551615s1s").is_err());
+
+    let config = DURATIONS_CONFIG.replace(
+        "connectionLifetimeSecs: 1h30m",
+        "connectionLifetimeSecs: 18446744073709551615s1h",
+    );
+    let err = config.parse::<AppConfig>().err().expect("parsed overflowing duration");
+    let msg = format!("{:?}", err);
+    assert!(msg.contains("too large"), "{}", msg);
+    assert!(msg.contains("/routers/0/servers/0/connectionLifetimeSecs"), "{}", msg);
+}
+
+#[test]
+fn accepts_namerd_base_url_as_host_and_port() {
+    for base_url in &["127.0.0.1:4180", "localhost:4180", "http://localhost:4180/"] {
+        let config = DURATIONS_CONFIG.replace("http://127.0.0.1:4180", base_url);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        if let Err(e) = config.into_app() {
+            panic!("rejected baseUrl {}: {:?}", base_url, e);
+        }
+    }
+}
+
+#[test]
+fn rejects_invalid_namerd_base_urls() {
+    for base_url in &["namerd example:4180", "ftp://localhost:4180", "http://"] {
+        let config = DURATIONS_CONFIG.replace("http://127.0.0.1:4180", base_url);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted baseUrl {}", base_url);
+    }
+}
+
+fn unknown_fields() -> Vec<String> {
+    vec![
+        "/admin/bogus".to_owned(),
+        "/routers/1/interpreter/bogus".to_owned(),
+        "/routers/2/servers/1/bogus".to_owned(),
+        "/routers/2/client/configs/0/connectBackoff/bogus".to_owned(),
+    ]
+}
+
+#[test]
+fn rejects_unknown_fields_when_strict() {
+    match AppConfig::parse(UNKNOWN_FIELDS_CONFIG) {
+        Err(Error::Config(app::Error::UnknownFields(paths))) => {
+            assert_eq!(paths, unknown_fields())
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let config = UNKNOWN_FIELDS_CONFIG.replace("configVersion: 1\n", "strict: true\n");
+    match AppConfig::parse(&config) {
+        Err(Error::Config(app::Error::UnknownFields(paths))) => {
+            assert_eq!(paths, unknown_fields())
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn ignores_unknown_fields_when_not_strict() {
+    let config = UNKNOWN_FIELDS_CONFIG.replace("configVersion: 1\n", "strict: false\n");
+    let (config, ignored) = AppConfig::parse(&config).expect("failed to parse config");
+    assert_eq!(ignored, unknown_fields());
+    config.into_app().expect("failed to load config");
+
+    // Fields that are known are still validated.
+    let config = DURATIONS_CONFIG.replace("periodSecs: 500ms", "periodSecs: 10x");
+    assert!(format!("strict: false\n{}", config).parse::<AppConfig>().is_err());
+}
+
+#[test]
+fn rejects_unsupported_config_versions() {
+    for version in &["0", "2", "one"] {
+        let version = format!("configVersion: {}\nadmin:", version);
+        match AppConfig::parse(&DURATIONS_CONFIG.replace("admin:", &version)) {
+            Err(Error::Config(app::Error::UnsupportedConfigVersion(_))) => {}
+            res => panic!("unexpected result for {}: {:?}", version, res),
+        }
+    }
+    let config = DURATIONS_CONFIG.replace("admin:", "configVersion: 1\nadmin:");
+    AppConfig::parse(&config).expect("rejected configVersion 1");
+}
+
+#[test]
+fn rejects_empty_transfer_buffers() {
+    for field in &["bufferSizeBytes", "clientToServerBufferBytes", "serverToClientBufferBytes"] {
+        let config = format!("{}: 0\n{}", field, DURATIONS_CONFIG.trim_left());
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(Error::Config(app::Error::InvalidBufferSize)) => {}
+            _ => panic!("accepted empty {}", field),
+        }
+    }
+}
+
+#[test]
+fn rejects_zero_max_upstream_connections() {
+    let config = format!("maxUpstreamConnections: 0\n{}", DURATIONS_CONFIG.trim_left());
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::InvalidMaxUpstreamConnections)) => {}
+        _ => panic!("accepted maxUpstreamConnections: 0"),
+    }
+
+    let config = format!("maxUpstreamConnections: 1\n{}", DURATIONS_CONFIG.trim_left());
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert_eq!(config.max_upstream_connections, Some(1));
+    assert!(config.into_app().is_ok());
+}
+
+#[test]
+fn validates_startup() {
+    let config = format!("startupConcurrency: 0\n{}", DURATIONS_CONFIG.trim_left());
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::InvalidStartupConcurrency)) => {}
+        _ => panic!("accepted startupConcurrency: 0"),
+    }
+
+    for pct in &[0, 101] {
+        let config = format!("startupQuorumPercent: {}\n{}", pct, DURATIONS_CONFIG.trim_left());
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(Error::Config(app::Error::InvalidStartupQuorum(p))) => assert_eq!(p, *pct),
+            _ => panic!("accepted startupQuorumPercent: {}", pct),
+        }
+    }
+
+    let config = format!(
+        "startupConcurrency: 1\nstartupQuorumPercent: 50\n{}",
+        DURATIONS_CONFIG.trim_left()
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert_eq!(config.startup_concurrency, Some(1));
+    assert_eq!(config.startup_quorum_percent, Some(50));
+    let app = config.into_app().expect("rejected startup");
+    // Each namerd-backed proxy is started once its destination is resolved.
+    assert_eq!(app.admin.startup().progress(), (0, 1));
+    assert!(!app.admin.startup().is_ready());
+}
+
+#[test]
+fn rejects_invalid_endpoint_filter_cidrs() {
+    for cidr in &["10.0.0.0/33", "fd00::/129", "10.0.0/8", "10.0.0.0/", "bogus"] {
+        let filter = format!(
+            "connectTimeoutMs: 250\n      endpointFilter:\n        denyCidrs: [\"{}\"]\n",
+            cidr
+        );
+        let config = DURATIONS_CONFIG.replace("connectTimeoutMs: 250\n", &filter);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted CIDR {}", cidr);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      endpointFilter:\n        \
+         denyCidrs: [\"10.0.0.0/8\", \"fd00::/8\", \"192.168.1.1\"]\n        \
+         allowCidrs: [\"0.0.0.0/0\", \"::/0\"]\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid CIDRs");
+}
+
+#[test]
+fn rejects_invalid_metric_labels_from_meta() {
+    for labels in &["[a, b, c, d, e]", "[\"\"]", "[nodeName, nodeName]", "[\"a=b\"]"] {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!("connectTimeoutMs: 250\n      metricLabelsFromMeta: {}\n", labels),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", labels);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      metricLabelsFromMeta: [nodeName, zone]\n      \
+         endpointFilter:\n        requireMeta: {tier: ssd}\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid metadata labels");
+}
+
+#[test]
+fn rejects_conflicting_admin_listeners() {
+    let conflicts = &[
+        "  unixSocket: /tmp/admin.sock\n  port: 0\n",
+        "  unixSocket: /tmp/admin.sock\n  addr: 127.0.0.1:0\n",
+        "  unixSocket: /tmp/admin.sock\n  portFile: /tmp/admin.port\n",
+    ];
+    for admin in conflicts {
+        let config =
+            DURATIONS_CONFIG.replace("  port: 0\n  metrics", &format!("{}  metrics", admin));
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(Error::Config(app::Error::InvalidAdminListener(_))) => {}
+            Err(e) => panic!("unexpected error for {:?}: {}", admin, e),
+            Ok(_) => panic!("accepted {:?}", admin),
+        }
+    }
+    // Disabled admin servers are not bound, so their listeners are not validated.
+    let admin = "  enabled: false\n  unixSocket: /tmp/admin.sock\n  port: 0\n";
+    let config = DURATIONS_CONFIG.replace("  port: 0\n  metrics", &format!("{}  metrics", admin));
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected a disabled admin server");
+}
+
+#[test]
+fn rejects_invalid_update_damping() {
+    for flap in &["windowSecs: 0", "maxTransitions: 0"] {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!(
+                "connectTimeoutMs: 250\n      updateDamping:\n        flapDetection:\n          \
+                 {}\n",
+                flap
+            ),
+        );
+        let valid = config.parse::<AppConfig>().ok().and_then(|c| c.into_app().ok());
+        assert!(valid.is_none(), "accepted flapDetection {}", flap);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      updateDamping:\n        minApplyIntervalSecs: 1\n        \
+         flapDetection: {windowSecs: 30, maxTransitions: 4}\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid update damping");
+}
+
+#[test]
+fn rejects_invalid_stickiness() {
+    for stickiness in &["ttlSecs: 0", "maxEntries: 0", "maxLoadFactor: 0.5", "key: destIp"] {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!("connectTimeoutMs: 250\n      stickiness:\n        {}\n", stickiness),
+        );
+        let valid = config.parse::<AppConfig>().ok().and_then(|c| c.into_app().ok());
+        assert!(valid.is_none(), "accepted stickiness {}", stickiness);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      stickiness:\n        key: sourceIp\n        \
+         ttlSecs: 5m\n        maxEntries: 10\n        maxLoadFactor: 1.5\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid stickiness");
+}
+
+#[test]
+fn rejects_invalid_selection_trace_sample_rates() {
+    for rate in &["0", "-0.5", "1.5"] {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!(
+                "connectTimeoutMs: 250\n      selectionTrace:\n        sampleRate: {}\n",
+                rate
+            ),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted sampleRate {}", rate);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      selectionTrace: {}\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected the default sampleRate");
+}
+
+#[test]
+fn rejects_invalid_connect_preambles() {
+    for preamble in &[
+        "hexBytes: \"\"",
+        "hexBytes: \"0x\"",
+        "hexBytes: \"0xCAF\"",
+        "hexBytes: \"0xCAFEZZ\"",
+        "hexBytes: \"CAFE\"\n        timeoutMs: 0",
+    ]
+    {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!(
+                "connectTimeoutMs: 250\n      connectPreamble:\n        {}\n",
+                preamble
+            ),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", preamble);
+    }
+    for hex in &["CAFE0001", "0xcafe0001"] {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!(
+                "connectTimeoutMs: 250\n      connectPreamble:\n        hexBytes: \"{}\"\n",
+                hex
+            ),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        if let Err(e) = config.into_app() {
+            panic!("rejected hexBytes {}: {:?}", hex, e);
+        }
+    }
+}
+
+#[test]
+fn rejects_invalid_metrics_scopes() {
+    for scope in &["\"\"", "team-a", "team.a", "1team"] {
+        let router = format!("- label: test\n    metricsScope: {}\n", scope);
+        let config = DURATIONS_CONFIG.replace("- label: test\n", &router);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted metricsScope {}", scope);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "- label: test\n",
+        "- label: test\n    metricsScope: _team_a2\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected a valid metricsScope");
+}
+
+#[test]
+fn rejects_invalid_size_buckets() {
+    for buckets in &["[0, 1024]", "[1024, 1024]", "[65536, 1024]"] {
+        let router = format!("- label: test\n    sizeBuckets: {}\n", buckets);
+        let config = DURATIONS_CONFIG.replace("- label: test\n", &router);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted sizeBuckets {}", buckets);
+    }
+    for buckets in &["[]", "[1, 1024]"] {
+        let router = format!("- label: test\n    sizeBuckets: {}\n", buckets);
+        let config = DURATIONS_CONFIG.replace("- label: test\n", &router);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        config.into_app().expect("rejected valid sizeBuckets");
+    }
+}
+
+#[test]
+fn rejects_connection_handoff_without_socket() {
+    let config = format!("{}experimental:\n  connectionHandoff: true\n", DURATIONS_CONFIG);
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted connectionHandoff without a socket");
+
+    let config = DURATIONS_CONFIG.replace(
+        "routers:\n",
+        "experimental:\n  connectionHandoff: false\nrouters:\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected disabled connectionHandoff");
+}
+
+#[test]
+fn rejects_empty_slow_start_windows() {
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      slowStart:\n        windowSecs: 0\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted empty slow start window");
+}
+
+#[test]
+fn rejects_invalid_dscps() {
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      dscp: 64\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted DSCP 64");
+}
+
+fn connect_timeout(ms: Option<u64>) -> ConnectorConfig {
+    ConnectorConfig {
+        connect_timeout_ms: ms.map(|ms| duration::Millis(Duration::from_millis(ms))),
+        ..ConnectorConfig::default()
+    }
+}
+
+#[test]
+fn defaults_connect_timeouts() {
+    let connector = connect_timeout(None).mk_connector().unwrap();
+    assert_eq!(connector.connect_timeout(), Some(Duration::from_secs(10)));
+
+    // A zero timeout disables connect timeouts.
+    let connector = connect_timeout(Some(0)).mk_connector().unwrap();
+    assert_eq!(connector.connect_timeout(), None);
+
+    let connector = connect_timeout(Some(250)).mk_connector().unwrap();
+    assert_eq!(connector.connect_timeout(), Some(Duration::from_millis(250)));
+}
+
+#[test]
+fn validates_stats_windows() {
+    let connector = ConnectorConfig::default().mk_connector().unwrap();
+    assert_eq!(connector.stats_window(), Duration::from_secs(60));
+
+    let mut config = ConnectorConfig::default();
+    config.update(&ConnectorConfig {
+        stats_window_secs: Some(duration::Secs(Duration::from_secs(5))),
+        ..ConnectorConfig::default()
+    });
+    let connector = config.mk_connector().unwrap();
+    assert_eq!(connector.stats_window(), Duration::from_secs(5));
+
+    let config = ConnectorConfig {
+        stats_window_secs: Some(duration::Secs(Duration::from_secs(0))),
+        ..ConnectorConfig::default()
+    };
+    assert!(config.mk_connector().is_err(), "accepted an empty stats window");
+}
+
+#[test]
+fn configures_failure_log_suppression() {
+    let connector = ConnectorConfig::default().mk_connector().unwrap();
+    assert_eq!(connector.log_suppress(), Duration::from_secs(60));
+
+    let mut config = ConnectorConfig::default();
+    config.update(&ConnectorConfig {
+        log_suppress_secs: Some(duration::Secs(Duration::from_secs(0))),
+        ..ConnectorConfig::default()
+    });
+    let connector = config.mk_connector().unwrap();
+    assert_eq!(connector.log_suppress(), Duration::from_secs(0));
+}
+
+#[test]
+fn validates_subsetting() {
+    let client = |subsetting: &str| {
+        DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!("connectTimeoutMs: 250\n      subsetting: {}\n", subsetting),
+        )
+    };
+
+    let config: AppConfig = client("{size: 50, seed: 7}").parse().expect("failed to parse");
+    config.into_app().expect("rejected a fixed seed");
+    let config: AppConfig = client("{size: 50, seed: fromHostname}").parse().expect(
+        "failed to parse",
+    );
+    config.into_app().expect("rejected a hostname seed");
+    let config: AppConfig = client("{size: 50}").parse().expect("failed to parse");
+    config.into_app().expect("rejected the default seed");
+
+    let config: AppConfig = client("{size: 0, seed: 7}").parse().expect("failed to parse");
+    assert!(config.into_app().is_err(), "accepted an empty subset");
+    for seed in &["bogus", "-1"] {
+        let config = client(&format!("{{size: 50, seed: {}}}", seed));
+        assert!(config.parse::<AppConfig>().is_err(), "parsed seed {}", seed);
+    }
+}
+
+#[test]
+fn validates_fallback_addrs_without_resolving_them() {
+    let client = |addrs: &str| {
+        DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 250\n",
+            &format!("connectTimeoutMs: 250\n      fallback: {{addrs: {}}}\n", addrs),
+        )
+    };
+
+    // Hostnames are resolved by balancers, so names that don't (yet) resolve are valid.
+    for addrs in &[
+        "[\"10.0.0.1:80\"]",
+        "[\"[::1]:80\"]",
+        "[\"fallback.invalid:80\", \"127.0.0.1:80\"]",
+    ]
+    {
+        let config: AppConfig = client(addrs).parse().expect("failed to parse");
+        config.into_app().expect(&format!("rejected {}", addrs));
+    }
+
+    for addrs in &["[]", "[\"fallback.invalid\"]", "[\"::1:80\"]", "[\"host:http\"]"] {
+        let config: AppConfig = client(addrs).parse().expect("failed to parse");
+        assert!(config.into_app().is_err(), "accepted {}", addrs);
+    }
+}
+
+#[test]
+fn overrides_connect_timeouts_by_prefix() {
+    let mut config = connect_timeout(Some(250));
+    config.update(&connect_timeout(Some(0)));
+    assert_eq!(config.mk_connector().unwrap().connect_timeout(), None);
+
+    let mut config = connect_timeout(Some(0));
+    config.update(&connect_timeout(Some(1000)));
+    let timeout = config.mk_connector().unwrap().connect_timeout();
+    assert_eq!(timeout, Some(Duration::from_secs(1)));
+
+    // Prefixes that don't set a timeout keep the one they override.
+    let mut config = connect_timeout(Some(250));
+    config.update(&connect_timeout(None));
+    let timeout = config.mk_connector().unwrap().connect_timeout();
+    assert_eq!(timeout, Some(Duration::from_millis(250)));
+}
+
+#[test]
+fn rejects_empty_ewma_decays() {
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      loadBalancer:\n        kind: io.l5d.ewma\n        \
+         decaySecs: 0\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted empty EWMA decay");
+}
+
+#[test]
+fn rejects_static_names_without_slashes() {
+    let mut names = HashMap::new();
+    names.insert("svc/echo".to_owned(), vec![]);
+    let app = AppBuilder::new()
+        .router(RouterBuilder::new("test", Interpreter::Static(names)))
+        .build();
+    match app {
+        Err(Error::Config(app::Error::InvalidStaticName(ref name))) if name == "svc/echo" => {}
+        _ => panic!("accepted static name without a slash"),
+    }
+}
+
+#[test]
+fn rejects_invalid_udp_servers() {
+    for udp in &[
+        "kind: io.l5d.udp\n        maxDatagramBytes: 0\n",
+        "kind: io.l5d.udp\n        maxDatagramBytes: 65508\n",
+        "kind: io.l5d.udp\n        sessionTimeoutSecs: 0\n",
+        "kind: io.l5d.tcpp\n",
+    ]
+    {
+        let server = format!("dstName: /svc/echo\n        {}", udp);
+        let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", &server);
+        let valid = config.parse::<AppConfig>().ok().map(|c| c.into_app().is_ok());
+        assert_ne!(valid, Some(true), "accepted {}", udp);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "dstName: /svc/echo\n",
+        "dstName: /svc/echo\n        kind: io.l5d.udp\n        sessionTimeoutSecs: 5m\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid UDP server");
+}
+
+#[test]
+fn rejects_invalid_probe_filters() {
+    for filter in &[
+        "fromCidrs: [\"10.0.0.0/33\"]",
+        "fromCidrs: [\"169.254.0.0/16\"]\n          idleMs: 0",
+    ]
+    {
+        let server = format!("dstName: /svc/echo\n        probeFilter:\n          {}\n", filter);
+        let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", &server);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", filter);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "dstName: /svc/echo\n",
+        "dstName: /svc/echo\n        probeFilter:\n          fromCidrs: [\"169.254.0.0/16\"]\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid probe filter");
+}
+
+#[test]
+fn rejects_invalid_mirrors() {
+    for mirror in &[
+        "path: svc/shadow",
+        "path: /svc/shadow\n          sampleRate: 1.5",
+        "path: /svc/shadow\n          sampleRate: -0.1",
+        "path: /svc/shadow\n          maxBufferedBytes: 0",
+        "sampleRate: 0.05",
+    ]
+    {
+        let server = format!("dstName: /svc/echo\n        mirror:\n          {}\n", mirror);
+        let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", &server);
+        let valid = config.parse::<AppConfig>().ok().map(|c| c.into_app().is_ok());
+        assert_ne!(valid, Some(true), "accepted {}", mirror);
+    }
+    let server = "dstName: /svc/echo\n        kind: io.l5d.udp\n        mirror:\n          \
+                  path: /svc/shadow\n";
+    let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", server);
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted a UDP mirror");
+
+    let config = DURATIONS_CONFIG.replace(
+        "dstName: /svc/echo\n",
+        "dstName: /svc/echo\n        mirror:\n          path: /svc/shadow\n          \
+         sampleRate: 0.05\n          maxBufferedBytes: 65536\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid mirror");
+}
+
+#[test]
+fn rejects_invalid_transparent_retries() {
+    for retry in &["retryBufferBytes: 0", "maxRetries: 0", "maxRetries: -1"] {
+        let server = format!(
+            "dstName: /svc/echo\n        transparentRetry:\n          {}\n",
+            retry
+        );
+        let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", &server);
+        let valid = config.parse::<AppConfig>().ok().map(|c| c.into_app().is_ok());
+        assert_ne!(valid, Some(true), "accepted {}", retry);
+    }
+    let server = "dstName: /svc/echo\n        kind: io.l5d.udp\n        transparentRetry: {}\n";
+    let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", server);
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted a UDP transparent retry");
+
+    let config = DURATIONS_CONFIG.replace(
+        "dstName: /svc/echo\n",
+        "dstName: /svc/echo\n        transparentRetry:\n          \
+         retryBufferBytes: 4096\n          maxRetries: 3\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid transparent retry");
+}
+
+#[test]
+fn rejects_invalid_rebalance_policies() {
+    for policy in &[
+        "checkIntervalSecs: 0",
+        "maxSkewRatio: 1.0",
+        "maxCloseRatio: 0",
+        "maxCloseRatio: 1.5",
+    ]
+    {
+        let client = format!("connectTimeoutMs: 250\n      rebalance:\n        {}\n", policy);
+        let config = DURATIONS_CONFIG.replace("connectTimeoutMs: 250\n", &client);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", policy);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 250\n",
+        "connectTimeoutMs: 250\n      rebalance:\n        checkIntervalSecs: 30s\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid rebalance policy");
+}
+
+#[test]
+fn rejects_invalid_dispatch_queues() {
+    for queue in &[
+        "maxDepth: 0",
+        "maxDepth: 10\n          shedPolicy: byAge",
+        "maxDepth: 10\n          shedPolicy: byAge\n          maxQueueDelayMs: 0",
+        "maxDepth: 10\n          shedPolicy: oldest\n          maxQueueDelayMs: 100",
+    ]
+    {
+        let server = format!("dstName: /svc/echo\n        dispatchQueue:\n          {}\n", queue);
+        let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", &server);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", queue);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "dstName: /svc/echo\n",
+        "dstName: /svc/echo\n        dispatchQueue:\n          maxDepth: 10\n          \
+         shedPolicy: byAge\n          maxQueueDelayMs: 100\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid dispatch queue");
+}
+
+#[test]
+fn rejects_invalid_source_port_reuse() {
+    for reuse in &["reuseWindowMs: 0", "maxTracked: 0"] {
+        let server = format!("dstName: /svc/echo\n        sourcePortReuse:\n          {}\n", reuse);
+        let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", &server);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", reuse);
+    }
+    let config = DURATIONS_CONFIG.replace(
+        "dstName: /svc/echo\n",
+        "dstName: /svc/echo\n        sourcePortReuse:\n          reuseWindowMs: 500\n          \
+         maxTracked: 100\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid sourcePortReuse");
+}
+
+fn resolution_cache_config(cache: &str) -> String {
+    DURATIONS_CONFIG.replace(
+        "periodSecs: 500ms\n",
+        &format!("periodSecs: 500ms\n      resolutionCache:\n        {}\n", cache),
+    )
+}
+
+#[test]
+fn rejects_invalid_resolution_caches() {
+    for cache in &[
+        "path: \"\"",
+        "path: /tmp/cache.json\n        maxAgeSecs: 0",
+        "path: /tmp/cache.json\n        bootstrapTimeoutSecs: 0",
+    ]
+    {
+        let config: AppConfig = resolution_cache_config(cache).parse().expect(
+            "failed to parse config",
+        );
+        assert!(config.into_app().is_err(), "accepted {}", cache);
+    }
+
+    let config = resolution_cache_config("path: /tmp/cache.json\n        maxAgeSecs: 10m");
+    let parsed: AppConfig = config.parse().expect("failed to parse config");
+    parsed.into_app().expect("rejected valid resolution cache");
+
+    // Routers may not share a cache file.
+    let router = &config[config.find("  - label: test").unwrap()..];
+    let second = router.replace("label: test", "label: b");
+    let config: AppConfig = format!("{}{}", config, second).parse().expect(
+        "failed to parse config",
+    );
+    match config.into_app() {
+        Err(Error::Config(app::Error::DuplicateResolutionCache(ref p))) => {
+            assert_eq!(p, "/tmp/cache.json")
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted a shared resolution cache"),
+    }
+}
+
+#[test]
+fn rejects_invalid_namerd_namespaces() {
+    for namespace in &["\"\"", "\" default\"", "\"default \""] {
+        let config = DURATIONS_CONFIG.replace(
+            "namespace: default",
+            &format!("namespace: {}", namespace),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted namespace {}", namespace);
+    }
+    for namespace in &["a/b", "100%", "a#b", "\"a b\"", "名前"] {
+        let config = DURATIONS_CONFIG.replace(
+            "namespace: default",
+            &format!("namespace: {}", namespace),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        if let Err(e) = config.into_app() {
+            panic!("rejected namespace {}: {:?}", namespace, e);
+        }
+    }
+}
+
+#[test]
+fn rejects_zero_max_addrs() {
+    let config = DURATIONS_CONFIG.replace(
+        "periodSecs: 500ms\n",
+        "periodSecs: 500ms\n      maxAddrs: 0\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted maxAddrs: 0");
+}
+
+#[test]
+fn rejects_invalid_dst_names() {
+    for name in &["\"\"", "svc/echo", "\" /svc/echo\"", "\"/svc/echo \""] {
+        let config = DURATIONS_CONFIG.replace(
+            "dstName: /svc/echo",
+            &format!("dstName: {}", name),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(Error::Config(app::Error::InvalidDstName { router: 0, server: 0, .. })) => {}
+            Err(e) => panic!("unexpected error for {}: {:?}", name, e),
+            Ok(_) => panic!("accepted dstName {}", name),
+        }
+    }
+}
+
+#[test]
+fn hashes_configs_regardless_of_field_order() {
+    let config: AppConfig = DURATIONS_CONFIG.parse().expect("failed to parse config");
+    let hash = config.hash().unwrap();
+    assert_eq!(hash.len(), 16);
+    assert_eq!(config.hash().unwrap(), hash);
+
+    let reordered: AppConfig = REORDERED_DURATIONS_CONFIG.parse().expect(
+        "failed to parse config",
+    );
+    assert_eq!(reordered.hash().unwrap(), hash);
+
+    let changed = DURATIONS_CONFIG.replace("graceSecs: 30", "graceSecs: 31");
+    let changed: AppConfig = changed.parse().expect("failed to parse config");
+    assert_ne!(changed.hash().unwrap(), hash);
+}
+
+/// A configuration with a single router, labeled `label`, whose server listens on `port`.
+fn router_config(label: &str, port: u16, globals: &str) -> String {
+    format!(
+        "{}
+routers:
+  - label: {}
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: http://127.0.0.1:4180
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: {}
+        dstName: /svc/{}
+",
+        globals,
+        label,
+        port,
+        label
+    )
+}
+
+/// Creates a new directory, unique to this test, containing `files`.
+fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let dir = ::std::env::temp_dir().join(format!("linkerd-tcp-{}-{}.d", name, nanos));
+    fs::create_dir(&dir).unwrap();
+    for &(file, contents) in files {
+        fs::File::create(dir.join(file)).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn loads_config_directories_in_lexical_order() {
+    let json = r#"{"routers": [{"label": "a",
+        "interpreter": {"kind": "io.l5d.namerd.http", "baseUrl": "http://127.0.0.1:4180",
+                        "namespace": "default", "periodSecs": 1},
+        "servers": [{"port": 0, "dstName": "/svc/a"}]}]}"#;
+    let b = router_config("b", 0, "");
+    let c = router_config("c", 0, "");
+    let dir = config_dir(
+        "ordered",
+        &[("20-b.yml", &b), ("10-a.json", json), ("30-c.yaml", &c), ("README.md", "# c")],
+    );
+    let extra = dir.join("99-d.conf");
+    fs::File::create(&extra)
+        .unwrap()
+        .write_all(router_config("d", 0, "").as_bytes())
+        .unwrap();
+
+    let config = app::load_paths(&[dir.clone(), extra]).expect("failed to load configs");
+    let labels: Vec<&str> = config.routers.iter().map(|r| r.label.as_str()).collect();
+    assert_eq!(labels, vec!["a", "b", "c", "d"]);
+    config.into_app().expect("failed to load merged config");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn later_configs_override_globals() {
+    let first = router_config("a", 0, "bufferSizeBytes: 1024\nmaxBufferedBytes: 4096");
+    let second = router_config("b", 0, "bufferSizeBytes: 2048");
+    let dir = config_dir("globals", &[("a.yml", &first), ("b.yml", &second)]);
+
+    let config = app::load_paths(&[dir.clone()]).expect("failed to load configs");
+    assert_eq!(config.buffer_size_bytes, Some(2048));
+    assert_eq!(config.max_buffered_bytes, Some(4096));
+    assert_eq!(config.routers.len(), 2);
+
+    let config = app::load_paths(&[dir.join("b.yml"), dir.join("a.yml")]).unwrap();
+    assert_eq!(config.buffer_size_bytes, Some(1024));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn empty_config_directories_contribute_nothing() {
+    let empty = config_dir("empty", &[]);
+    let e = app::load_paths(&[empty.clone()]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert_eq!(app::load_paths(&[]).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    let dir = config_dir("nonempty", &[("a.yml", &router_config("a", 0, ""))]);
+    let config = app::load_paths(&[empty.clone(), dir.clone()]).expect("failed to load");
+    assert_eq!(config.routers.len(), 1);
+    fs::remove_dir_all(&empty).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rejects_listeners_configured_by_more_than_one_file() {
+    let dir = config_dir(
+        "listeners",
+        &[
+            ("a.yml", &router_config("a", 7474, "")),
+            ("b.yml", &router_config("b", 7474, "")),
+        ],
+    );
+    let e = app::load_paths(&[dir.clone()]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("127.0.0.1:7474"), "{}", e);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let config = format!(
+        "{}      - port: 7474\n        ip: 0.0.0.0\n        dstName: /svc/b\n",
+        router_config("a", 7474, "")
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::DuplicateListener(addr))) => assert_eq!(addr.port(), 7474),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("accepted duplicate listeners"),
+    }
+}
+
+#[test]
+fn validates_write_coalescing() {
+    for coalescing in &["maxDelayMicros: 0", "maxBytes: 0"] {
+        let config = DURATIONS_CONFIG.replace(
+            "connectTimeoutMs: 1s\n",
+            &format!("connectTimeoutMs: 1s\n        writeCoalescing: {{ {} }}\n", coalescing),
+        );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", coalescing);
+    }
+
+    let config = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 1s\n",
+        "connectTimeoutMs: 1s\n        writeCoalescing: {}\n",
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected default write coalescing");
+
+    let udp = DURATIONS_CONFIG.replace(
+        "connectTimeoutMs: 1s\n",
+        "connectTimeoutMs: 1s\n        kind: io.l5d.udp\n        writeCoalescing: {}\n",
+    );
+    let udp: AppConfig = udp.parse().expect("failed to parse config");
+    assert!(udp.into_app().is_err(), "accepted write coalescing for a UDP server");
+}
+
+#[test]
+fn validates_weight_overrides() {
+    for overrides in &["\"10.0.0.5:8080\": -1", "\"10.0.0.5:8080\": .nan", "bogus: 0.5"] {
+        let config = format!("{}    weightOverrides: {{ {} }}\n", DURATIONS_CONFIG, overrides);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", overrides);
+    }
+
+    let config = format!(
+        "{}    weightOverrides: {{ \"10.0.0.5:8080\": 0.1, \"[::1]:8080\": 2 }}\n",
+        DURATIONS_CONFIG
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid weight overrides");
+}
+
+#[test]
+fn validates_security() {
+    let chroot = config_dir("chroot", &[]);
+    let invalid = [
+        "runAsUser: linkerd-tcp-no-such-user".to_owned(),
+        "runAsGroup: linkerd-tcp-no-such-group".to_owned(),
+        format!("chrootDir: {}", chroot.join("missing").display()),
+    ];
+    for security in &invalid {
+        let config = format!("security: {{ {} }}\n{}", security, DURATIONS_CONFIG);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(Error::Config(app::Error::Security(_))) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("accepted {}", security),
+        }
+    }
+    let unknown = format!("security: {{ runAsUid: 65534 }}\n{}", DURATIONS_CONFIG);
+    assert!(unknown.parse::<AppConfig>().is_err(), "accepted an unknown security field");
+
+    // Numeric ids need not exist in the user database.
+    let config = format!(
+        "security: {{ runAsUser: \"65534\", runAsGroup: \"65534\", chrootDir: {} }}\n{}",
+        chroot.display(),
+        DURATIONS_CONFIG
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    let app = config.into_app().expect("rejected valid security");
+    assert!(app.privileges.is_some());
+
+    let config: AppConfig = DURATIONS_CONFIG.parse().unwrap();
+    assert!(config.into_app().unwrap().privileges.is_none());
+    fs::remove_dir_all(&chroot).unwrap();
+}
+
+#[test]
+fn validates_max_concurrent_dispatches() {
+    let connector = ConnectorConfig::default().mk_connector().unwrap();
+    assert_eq!(connector.max_concurrent_dispatches(), None);
+
+    let mut config = ConnectorConfig::default();
+    config.update(&ConnectorConfig {
+        max_concurrent_dispatches: Some(8),
+        ..ConnectorConfig::default()
+    });
+    let connector = config.mk_connector().unwrap();
+    assert_eq!(connector.max_concurrent_dispatches(), Some(8));
+
+    let config = ConnectorConfig {
+        max_concurrent_dispatches: Some(0),
+        ..ConnectorConfig::default()
+    };
+    assert!(config.mk_connector().is_err(), "accepted a dispatch limit of 0");
+}
+
+#[test]
+fn validates_chaos() {
+    let config: AppConfig = DURATIONS_CONFIG.parse().unwrap();
+    match config.into_builder().chaos().build() {
+        Err(Error::Config(app::Error::ChaosNotAllowed)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("enabled chaos without allowChaos"),
+    }
+    let config = format!("allowChaos: true\n{}", DURATIONS_CONFIG);
+    let config: AppConfig = config.parse().unwrap();
+    assert!(config.into_builder().chaos().build().is_ok());
+
+    // Chaos is validated even when it is not enabled.
+    for &p in &[-1.0, 100.5] {
+        let chaos = ChaosConfig {
+            drop_connect_percent: Some(p),
+            ..ChaosConfig::default()
+        };
+        let router = RouterBuilder::new("test", Interpreter::Static(HashMap::new())).chaos(chaos);
+        match AppBuilder::new().router(router).build() {
+            Err(Error::Config(app::Error::Connector(_))) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("accepted a drop percentage of {}", p),
+        }
+    }
+}
+
+#[test]
+fn rejects_invalid_tcp_info_sampling() {
+    let server = "dstName: /svc/echo\n        tcpInfo:\n          sampleIntervalSecs: 0\n";
+    let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", server);
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted a zero sample interval");
+
+    let server = "dstName: /svc/echo\n        kind: io.l5d.udp\n        tcpInfo: {}\n";
+    let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", server);
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted a UDP server sampling tcpInfo");
+
+    let server = "dstName: /svc/echo\n        tcpInfo:\n          sampleIntervalSecs: 10\n";
+    let config = DURATIONS_CONFIG.replace("dstName: /svc/echo\n", server);
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected valid tcpInfo");
+}
+
+#[test]
+fn rejects_invalid_admin_auth() {
+    let admin = |auth: &str| {
+        let auth = format!("graceSecs: 30\n  auth:\n{}", auth);
+        let config = DURATIONS_CONFIG.replace("graceSecs: 30\n", &auth);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        config.into_app()
+    };
+    std::env::set_var("L5D_TEST_CONFIG_ADMIN_TOKEN", "s3cret");
+    std::env::remove_var("L5D_TEST_CONFIG_ADMIN_TOKEN_UNSET");
+
+    assert!(admin("    allowUnauthenticatedReads: true\n").is_err(), "accepted no credentials");
+    let unset = "    bearerTokenEnv: L5D_TEST_CONFIG_ADMIN_TOKEN_UNSET\n";
+    assert!(admin(unset).is_err(), "accepted an unset bearer token");
+
+    let token = "    bearerTokenEnv: L5D_TEST_CONFIG_ADMIN_TOKEN\n";
+    let zero = format!("{}    maxFailuresPerMinute: 0\n", token);
+    assert!(admin(&zero).is_err(), "accepted a zero failure limit");
+    admin(token).expect("rejected valid auth");
+}
+
+#[test]
+fn rejects_invalid_local_resolvers() {
+    let local = |socket: &str| {
+        DURATIONS_CONFIG.replace(
+            "periodSecs: 500ms\n",
+            &format!("periodSecs: 500ms\n      localResolver:\n        socketPath: {}\n", socket),
+        )
+    };
+    let config: AppConfig = local("\"\"").parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted an empty socketPath");
+
+    let config = local("/tmp/l5d-resolver.sock");
+    let parsed: AppConfig = config.parse().expect("failed to parse config");
+    parsed.into_app().expect("rejected valid local resolver");
+
+    // Routers may not share a socket.
+    let router = &config[config.find("  - label: test").unwrap()..];
+    let second = router.replace("label: test", "label: b");
+    let config: AppConfig = format!("{}{}", config, second).parse().expect(
+        "failed to parse config",
+    );
+    match config.into_app() {
+        Err(Error::Config(app::Error::DuplicateLocalResolver(ref p))) => {
+            assert_eq!(p, "/tmp/l5d-resolver.sock")
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted a shared local resolver"),
+    }
+}
+
+#[test]
+fn rejects_unknown_remote_policy_keys() {
+    let accept = |keys: &str| {
+        DURATIONS_CONFIG.replace(
+            "periodSecs: 500ms\n",
+            &format!(
+                "periodSecs: 500ms\n      acceptRemotePolicy:\n        allowedKeys: {}\n",
+                keys
+            ),
+        )
+    };
+    let config: AppConfig = accept("[loadBalancer, failureAccrual.maxFailures]")
+        .parse()
+        .expect("failed to parse config");
+    config.into_app().expect("rejected valid remote policy keys");
+
+    let config: AppConfig = accept("[loadBalancer, tls]").parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::Interpreter(ref e))) => {
+            assert_eq!(format!("{:?}", e), "UnknownRemotePolicyKey(\"tls\")")
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted an unknown remote policy key"),
+    }
+}
@@ -0,0 +1,197 @@
+extern crate futures;
+extern crate hyper;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_timer;
+extern crate url;
+
+mod harness;
+
+use harness::Harness;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_io::io as aio;
+
+static CONFIG: &'static str = "
+admin:
+  port: 0
+experimental:
+  connectionHandoff: true
+  handoffSocket: {socket}
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+";
+
+/// A unique path for a handoff socket.
+fn socket_path() -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    ::std::env::temp_dir().join(format!("linkerd-tcp-handoff-{}.sock", nanos))
+}
+
+/// Reads a handoff from `sock`, without taking its sockets, and acknowledges it. Returns
+/// whether the handoff was committed.
+fn acknowledge(mut sock: UnixStream) -> bool {
+    let mut len = [0u8; 4];
+    if sock.read_exact(&mut len).is_err() {
+        return false;
+    }
+    let len = (len[0] as usize) << 24 | (len[1] as usize) << 16 | (len[2] as usize) << 8 |
+        len[3] as usize;
+    let mut state = vec![0; len];
+    if sock.read_exact(&mut state).is_err() || sock.write_all(&[1]).is_err() {
+        return false;
+    }
+    let mut commit = [0u8; 1];
+    sock.read_exact(&mut commit).is_ok() && commit[0] == 2
+}
+
+/// Hands a live stream off from one proxy to another, which runs its own reactor. If
+/// `stalled_peer` is set, another peer connects to the new proxy's handoff socket first
+/// and stops in the middle of a handoff.
+fn hand_off_live_stream(stalled_peer: bool) {
+    let socket = socket_path();
+    let config = CONFIG.replace("{socket}", socket.to_str().unwrap());
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let old = h.proxy(&config);
+
+    let conn = h.connect(&old.addr());
+    let (conn, rsp) = h.echo(conn, b"before");
+    assert_eq!(rsp, b"before".to_vec());
+
+    // The new process runs its own reactor on another thread, taking over the handoff
+    // socket as it starts.
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let echo_addr = echo.addr();
+    let new = thread::spawn(move || {
+        let mut h = Harness::new();
+        h.namerd().bind("/svc/echo", &[(echo_addr, 1.0)]);
+        let proxy = h.proxy(&config);
+        ready_tx.send(()).unwrap();
+        while stop_rx.try_recv().is_err() {
+            h.sleep(Duration::from_millis(100));
+        }
+        (proxy.metric("handoff_received"), proxy.metric("handoff_resumed"))
+    });
+    ready_rx.recv_timeout(Duration::from_secs(10)).expect(
+        "new process did not start",
+    );
+    let _stalled = if stalled_peer {
+        let mut sock = UnixStream::connect(&socket).expect("failed to connect");
+        sock.write_all(&[0, 0]).unwrap();
+        Some(sock)
+    } else {
+        None
+    };
+
+    // Bytes that are in flight as the stream is handed off arrive exactly once.
+    let (conn, _) = h.run(aio::write_all(conn, b"in flight".to_vec())).expect(
+        "write failed",
+    );
+    h.hand_off(&old);
+    assert_eq!(old.metric("handoff_sent"), 1);
+    assert_eq!(
+        old.labeled_metric("close_reasons", "reason=\"handed_off\""),
+        1
+    );
+
+    let (conn, rsp) = h.read_exact(conn, 9);
+    assert_eq!(rsp, b"in flight".to_vec());
+    let (conn, rsp) = h.echo(conn, b"after");
+    assert_eq!(rsp, b"after".to_vec());
+
+    stop_tx.send(()).unwrap();
+    let (received, resumed) = new.join().expect("new process failed");
+    assert_eq!(received, 1);
+    assert_eq!(resumed, 1);
+    drop(conn);
+    let _ = fs::remove_file(&socket);
+}
+
+#[test]
+fn hands_off_live_stream_between_reactors() {
+    hand_off_live_stream(false);
+}
+
+#[test]
+fn receives_handoffs_while_another_peer_stalls() {
+    hand_off_live_stream(true);
+}
+
+#[test]
+fn serves_other_connections_while_handoffs_are_acknowledged() {
+    let socket = socket_path();
+    let config = CONFIG.replace("{socket}", socket.to_str().unwrap());
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&config);
+
+    let conn = h.connect(&proxy.addr());
+    let (conn, _) = h.echo(conn, b"before");
+
+    // The proxy's handoff socket is replaced by one whose receiver relies on the
+    // proxy's reactor, which also serves the echo server, before it acknowledges the
+    // handoff.
+    fs::remove_file(&socket).expect("failed to remove handoff socket");
+    let listener = UnixListener::bind(&socket).expect("failed to bind handoff socket");
+    let echo_addr = echo.addr();
+    let receiver = thread::spawn(move || {
+        let (sock, _) = listener.accept().expect("failed to accept");
+        let mut tcp = TcpStream::connect(echo_addr).expect("failed to connect");
+        tcp.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        tcp.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        let echoed = tcp.read_exact(&mut buf).is_ok();
+        (echoed, acknowledge(sock))
+    });
+
+    h.hand_off(&proxy);
+    let (echoed, received) = receiver.join().expect("receiver failed");
+    assert!(echoed, "the reactor was blocked while the handoff was sent");
+    assert!(received);
+    assert_eq!(proxy.metric("handoff_sent"), 1);
+    assert_eq!(proxy.metric("handoff_failures"), 0);
+    drop(conn);
+    let _ = fs::remove_file(&socket);
+}
+
+#[test]
+fn streams_continue_when_handoff_fails() {
+    let socket = socket_path();
+    let config = CONFIG.replace("{socket}", socket.to_str().unwrap());
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&config);
+
+    let conn = h.connect(&proxy.addr());
+    let (conn, _) = h.echo(conn, b"before");
+
+    // No other process has replaced this one, so the proxy reaches its own socket, which
+    // stops receiving as it hands off. The stream is not acknowledged and continues.
+    h.hand_off(&proxy);
+    assert_eq!(proxy.metric("handoff_sent"), 0);
+    assert_eq!(proxy.metric("handoff_failures"), 1);
+    let (_, rsp) = h.echo(conn, b"after");
+    assert_eq!(rsp, b"after".to_vec());
+    let _ = fs::remove_file(&socket);
+}
@@ -0,0 +1,852 @@
+//! An in-process environment for exercising linkerd-tcp end-to-end.
+//!
+//! A `Harness` drives a fake namerd, echo servers, and proxies built from configuration
+//! strings (or assembled in code), all on a single reactor.
+
+#![allow(dead_code)]
+
+use futures::{Async, Future, Stream, future, stream};
+use hyper::{self, Get, StatusCode};
+use hyper::header::{ContentLength, ETag, EntityTag, IfNoneMatch};
+use hyper::server::{Http, Request, Response, Service};
+use linkerd_tcp::{self, Ejections, Registry, WeightOverrides, WeightedAddr};
+use linkerd_tcp::app::{self, App, AppConfig, ConnectorConfig, MetricsExporter, Startup};
+use linkerd_tcp::info::Info;
+use linkerd_tcp::lb::{self, Balancer, Scope};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio_core::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncRead;
+use tokio_io::io as aio;
+use tokio_timer::Timer;
+use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
+
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(feature = "tls")]
+pub use self::tls::TlsEchoServer;
+
+const IO_TIMEOUT_SECS: u64 = 10;
+
+const RESOLVE_PREFIX: &'static str = "/api/1/resolve/";
+
+pub struct Harness {
+    core: Core,
+    timer: Timer,
+    namerd: Namerd,
+    closed: Vec<app::Closed>,
+}
+
+impl Harness {
+    pub fn new() -> Harness {
+        let core = Core::new().expect("failed to initialize reactor");
+        let namerd = Namerd::spawn(&core.handle());
+        Harness {
+            core,
+            timer: Timer::default(),
+            namerd,
+            closed: Vec::new(),
+        }
+    }
+
+    pub fn namerd(&self) -> &Namerd {
+        &self.namerd
+    }
+
+    pub fn echo_server(&self) -> EchoServer {
+        EchoServer::spawn(&self.core.handle())
+    }
+
+    /// Spawns an echo server that terminates TLS with the identity whose private key and
+    /// certificates are the PEM files at `key` and `certs`.
+    #[cfg(feature = "tls")]
+    pub fn tls_echo_server(&self, key: &str, certs: &str) -> TlsEchoServer {
+        TlsEchoServer::spawn(key, certs)
+    }
+
+    pub fn udp_echo_server(&self) -> UdpEchoServer {
+        UdpEchoServer::spawn(&self.core.handle())
+    }
+
+    /// Relays connections to `target`, connecting to it only after `delay`.
+    ///
+    /// The harness's timer has a coarse granularity (100ms), so small delays are not
+    /// honored precisely. A zero delay connects immediately.
+    pub fn delayed_relay(&self, target: SocketAddr, delay: Duration) -> Relay {
+        Relay::spawn(&self.core.handle(), &self.timer, target, delay)
+    }
+
+    /// Builds and spawns a proxy from a YAML or JSON configuration.
+    ///
+    /// Occurrences of `{namerd}` in the configuration are replaced with the fake
+    /// namerd's base URL.
+    pub fn proxy(&mut self, config: &str) -> Proxy {
+        let config = self.config(config);
+        self.spawn(config.into_app().expect("failed to load configuration"))
+    }
+
+    /// Builds and spawns a proxy as `proxy` does, injecting the faults configured by
+    /// its routers' `chaos` as `--chaos` does.
+    pub fn chaos_proxy(&mut self, config: &str) -> Proxy {
+        let config = self.config(config);
+        let app = config.into_builder().chaos().build();
+        self.spawn(app.expect("failed to load configuration"))
+    }
+
+    fn config(&self, config: &str) -> AppConfig {
+        let config = config.replace("{namerd}", &self.namerd.base_url());
+        config.parse().expect("failed to parse configuration")
+    }
+
+    /// Spawns a proxy from an App, e.g. as built by an `AppBuilder`.
+    pub fn spawn(&mut self, app: App) -> Proxy {
+        let App {
+            mut routers,
+            admin,
+            readiness,
+            handoff,
+            ..
+        } = app;
+
+        let handle = self.core.handle();
+        let mut addrs = Vec::new();
+        while let Some(r) = routers.pop_front() {
+            let bound = r.spawn(&handle, &self.timer).expect("failed to spawn router");
+            addrs.extend(bound);
+        }
+        if let Some(readiness) = readiness {
+            readiness.spawn(&handle, &self.timer);
+        }
+        if let Some(ref handoff) = handoff {
+            handoff.receive(&handle, &self.timer).expect(
+                "failed to receive handed off streams",
+            );
+        }
+
+        let (closer, closed) = app::closer();
+        self.closed.push(closed);
+        let ejections = admin.ejections();
+        let weight_overrides = admin.weight_overrides();
+        let state = admin.state();
+        let info = admin.info();
+        let startup = admin.startup();
+        let metrics = admin.spawn(closer, &handle, &self.timer).expect(
+            "failed to spawn admin",
+        );
+        Proxy {
+            addrs,
+            metrics,
+            ejections,
+            weight_overrides,
+            state,
+            info,
+            startup,
+            handoff,
+        }
+    }
+
+    /// Hands `proxy`'s streams off to the process receiving on its handoff socket, as
+    /// the proxy would as it shuts down, driving the reactor until every stream has
+    /// been handed off or refused.
+    pub fn hand_off(&mut self, proxy: &Proxy) {
+        let handoff = proxy.handoff.as_ref().expect("connection handoff is not enabled");
+        let handing_off = handoff.hand_off().expect("failed to hand off");
+        let handing_off = self.timer.timeout(
+            handing_off.map_err(|_| io::Error::new(io::ErrorKind::Other, "handoff failed")),
+            Duration::from_secs(IO_TIMEOUT_SECS),
+        );
+        self.core.run(handing_off).expect("handoff timed out");
+    }
+
+    /// Drives the reactor until `f` completes.
+    pub fn run<F: Future>(&mut self, f: F) -> Result<F::Item, F::Error> {
+        self.core.run(f)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.core.handle()
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// Drives the reactor for the given amount of time.
+    pub fn sleep(&mut self, duration: Duration) {
+        let sleep = self.timer.sleep(duration);
+        self.core.run(sleep).expect("timer failed");
+    }
+
+    pub fn connect(&mut self, addr: &SocketAddr) -> TcpStream {
+        let handle = self.core.handle();
+        self.core.run(TcpStream::connect(addr, &handle)).expect(
+            "failed to connect",
+        )
+    }
+
+    /// Writes `msg` on `conn` and reads back as many bytes as were written.
+    /// Registers a connection that was established outside of the reactor, e.g. so that
+    /// it could be written to before the proxy ran.
+    pub fn register(&self, conn: net::TcpStream) -> TcpStream {
+        TcpStream::from_stream(conn, &self.core.handle()).expect("failed to register")
+    }
+
+    pub fn echo(&mut self, conn: TcpStream, msg: &[u8]) -> (TcpStream, Vec<u8>) {
+        self.try_echo(conn, msg).expect("echo failed")
+    }
+
+    pub fn try_echo(&mut self, conn: TcpStream, msg: &[u8]) -> io::Result<(TcpStream, Vec<u8>)> {
+        let len = msg.len();
+        let echo = aio::write_all(conn, msg.to_vec()).and_then(move |(conn, _)| {
+            aio::read_exact(conn, vec![0u8; len])
+        });
+        let echo = self.timer.timeout(echo, Duration::from_secs(IO_TIMEOUT_SECS));
+        self.core.run(echo)
+    }
+
+    /// Reads exactly `len` bytes from `conn`.
+    pub fn read_exact(&mut self, conn: TcpStream, len: usize) -> (TcpStream, Vec<u8>) {
+        let read = aio::read_exact(conn, vec![0u8; len]);
+        let read = self.timer.timeout(read, Duration::from_secs(IO_TIMEOUT_SECS));
+        self.core.run(read).expect("read failed")
+    }
+
+    /// Echoes `msg` over a new connection to `addr`, closing it afterwards.
+    pub fn roundtrip(&mut self, addr: &SocketAddr, msg: &[u8]) -> Vec<u8> {
+        self.try_roundtrip(addr, msg).expect("echo failed")
+    }
+
+    pub fn try_roundtrip(&mut self, addr: &SocketAddr, msg: &[u8]) -> io::Result<Vec<u8>> {
+        let conn = self.connect(addr);
+        self.try_echo(conn, msg).map(|(_, rsp)| rsp)
+    }
+
+    /// Binds a UDP socket from which datagrams may be echoed.
+    pub fn udp_client(&self) -> UdpSocket {
+        UdpSocket::bind(&"127.0.0.1:0".parse().unwrap(), &self.core.handle())
+            .expect("failed to bind UDP client")
+    }
+
+    /// Sends `msg` to `addr` from `sock` and returns the first datagram received.
+    pub fn udp_echo(
+        &mut self,
+        sock: UdpSocket,
+        addr: &SocketAddr,
+        msg: &[u8],
+    ) -> (UdpSocket, Vec<u8>) {
+        let echo = sock.send_dgram(msg.to_vec(), *addr)
+            .and_then(|(sock, _)| sock.recv_dgram(vec![0u8; 65536]))
+            .map(|(sock, mut rsp, sz, _)| {
+                rsp.truncate(sz);
+                (sock, rsp)
+            });
+        let echo = self.timer.timeout(echo, Duration::from_secs(IO_TIMEOUT_SECS));
+        self.core.run(echo).expect("UDP echo failed")
+    }
+
+    /// Echoes `msg` from a new UDP socket to `addr`.
+    pub fn udp_roundtrip(&mut self, addr: &SocketAddr, msg: &[u8]) -> Vec<u8> {
+        let sock = self.udp_client();
+        self.udp_echo(sock, addr, msg).1
+    }
+
+    /// Spawns a server that closes each connection as soon as it is accepted, resetting
+    /// it if `reset` is set.
+    pub fn closing_server(&self, reset: bool) -> SocketAddr {
+        let handle = self.core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle)
+            .expect("failed to bind closing server");
+        let addr = listener.local_addr().unwrap();
+        let serve = listener
+            .incoming()
+            .for_each(move |(tcp, _)| {
+                if reset {
+                    tcp.set_linger(Some(Duration::from_secs(0)))?;
+                }
+                Ok(())
+            })
+            .map_err(|_| ());
+        handle.spawn(serve);
+        addr
+    }
+
+    /// Connects to `addr` and reads until the connection is closed.
+    pub fn read_to_end(&mut self, addr: &SocketAddr) -> io::Result<Vec<u8>> {
+        let conn = self.connect(addr);
+        let read = aio::read_to_end(conn, Vec::new()).map(|(_, buf)| buf);
+        let read = self.timer.timeout(read, Duration::from_secs(IO_TIMEOUT_SECS));
+        self.core.run(read)
+    }
+
+    /// Sends `req`, an HTTP/1.1 request, to `addr` and returns the response's status
+    /// code. The connection is closed after the response.
+    pub fn http_status(&mut self, addr: &SocketAddr, req: &str) -> u16 {
+        let conn = self.connect(addr);
+        let req = req.replace("\r\n\r\n", "\r\nConnection: close\r\n\r\n");
+        let rsp = aio::write_all(conn, req.into_bytes()).and_then(|(conn, _)| {
+            aio::read_to_end(conn, Vec::new())
+        });
+        let rsp = self.timer.timeout(rsp, Duration::from_secs(IO_TIMEOUT_SECS));
+        let (_, rsp) = self.core.run(rsp).expect("request failed");
+        let rsp = String::from_utf8_lossy(&rsp);
+        rsp.split(' ').nth(1).and_then(|s| s.parse().ok()).expect(
+            "invalid response",
+        )
+    }
+
+    /// Sends `req`, an HTTP/1.1 request, to the unix socket at `path` and returns the
+    /// response's status code, as `http_status` does.
+    ///
+    /// The request is made from another thread while the reactor is driven.
+    pub fn unix_http_status(&mut self, path: &Path, req: &str) -> u16 {
+        let (tx, rx) = mpsc::channel();
+        let path = path.to_owned();
+        let req = req.replace("\r\n\r\n", "\r\nConnection: close\r\n\r\n");
+        thread::spawn(move || {
+            let mut conn = UnixStream::connect(&path).expect("failed to connect");
+            conn.write_all(req.as_bytes()).expect("failed to write request");
+            let mut rsp = String::new();
+            conn.read_to_string(&mut rsp).expect("failed to read response");
+            let _ = tx.send(rsp);
+        });
+        for _ in 0..(IO_TIMEOUT_SECS * 10) {
+            if let Ok(rsp) = rx.try_recv() {
+                return rsp.split(' ').nth(1).and_then(|s| s.parse().ok()).expect(
+                    "invalid response",
+                );
+            }
+            self.sleep(Duration::from_millis(100));
+        }
+        panic!("request timed out");
+    }
+
+    /// Returns a local address on which nothing is listening.
+    pub fn unused_addr(&self) -> SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        listener.local_addr().unwrap()
+    }
+
+    /// Embeds a balancer for `dst` over `addrs`, configured by `config`.
+    pub fn balancer(
+        &self,
+        dst: &str,
+        addrs: Vec<WeightedAddr>,
+        config: &ConnectorConfig,
+    ) -> linkerd_tcp::Result<Balancer> {
+        // The endpoints never change.
+        let addrs = stream::once(Ok(addrs)).chain(future::empty().into_stream());
+        self.balancer_over(dst, addrs, config)
+    }
+
+    /// Embeds a balancer for `dst` over the endpoints produced by `addrs`, configured by
+    /// `config`.
+    pub fn balancer_over<S>(
+        &self,
+        dst: &str,
+        addrs: S,
+        config: &ConnectorConfig,
+    ) -> linkerd_tcp::Result<Balancer>
+    where
+        S: Stream<Item = Vec<WeightedAddr>, Error = ()> + 'static,
+    {
+        lb::with_config(
+            &self.core.handle(),
+            &self.timer,
+            dst,
+            addrs,
+            config,
+            &Scope::noop(),
+        )
+    }
+}
+
+/// A proxy spawned by the harness.
+pub struct Proxy {
+    addrs: Vec<SocketAddr>,
+    metrics: MetricsExporter,
+    ejections: Ejections,
+    weight_overrides: WeightOverrides,
+    state: Registry,
+    info: Info,
+    startup: Startup,
+    handoff: Option<app::ConnectionHandoff>,
+}
+
+impl Proxy {
+    /// The address of the first configured server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Manually overrides endpoint health, as is done via the admin server.
+    pub fn ejections(&self) -> &Ejections {
+        &self.ejections
+    }
+
+    /// Manually scales endpoint weights, as is done via the admin server.
+    pub fn weight_overrides(&self) -> &WeightOverrides {
+        &self.weight_overrides
+    }
+
+    /// The balancers' states, as served by the admin server's `/state` endpoint.
+    pub fn state(&self) -> String {
+        self.state.to_json()
+    }
+
+    /// The routers' golden signals, as served by the admin server's `/admin/summary`
+    /// endpoint.
+    pub fn summary(&self) -> String {
+        self.state.summary().to_json()
+    }
+
+    /// The proxies that were skipped because they failed to start, as served by the
+    /// admin server's `/admin/config/errors` endpoint.
+    pub fn config_errors(&self) -> String {
+        self.state.proxy_errors().to_json()
+    }
+
+    /// The most recently sampled endpoint selections, as served by the admin server's
+    /// `/admin/selection-trace` endpoint.
+    pub fn selection_trace(&self) -> String {
+        self.state.selection_traces().to_json()
+    }
+
+    /// The process's description, as served by the admin server's `/admin/info` endpoint.
+    pub fn info(&self) -> String {
+        self.info.to_json()
+    }
+
+    /// The progress of the proxies' startup, as reported by the admin server's `/ready`
+    /// endpoint.
+    pub fn startup(&self) -> &Startup {
+        &self.startup
+    }
+
+    /// Snapshots the process's metrics, formatted for prometheus.
+    pub fn prometheus(&self) -> String {
+        self.metrics.export();
+        self.metrics.prometheus()
+    }
+
+    /// Sums the values of all exported metrics whose names end with `suffix`.
+    pub fn metric(&self, suffix: &str) -> u64 {
+        self.metrics.export();
+        sum_metric(&self.metrics.prometheus(), suffix, "")
+    }
+
+    /// Sums the values of all exported metrics whose names end with `suffix` and that
+    /// carry `label` (e.g. `alpn="h2"`).
+    pub fn labeled_metric(&self, suffix: &str, label: &str) -> u64 {
+        self.metrics.export();
+        sum_metric(&self.metrics.prometheus(), suffix, label)
+    }
+}
+
+fn sum_metric(prometheus: &str, suffix: &str, label: &str) -> u64 {
+    let mut sum = 0.0;
+    for line in prometheus.lines() {
+        if line.starts_with('#') || !line.contains(label) {
+            continue;
+        }
+        let name_end = line.find(|c: char| c == '{' || c == ' ').unwrap_or(
+            line.len(),
+        );
+        if !line[..name_end].ends_with(suffix) {
+            continue;
+        }
+        if let Some(v) = line.rsplit(' ').next().and_then(|v| v.parse::<f64>().ok()) {
+            sum += v;
+        }
+    }
+    sum as u64
+}
+
+/// Serves programmable namerd HTTP resolutions.
+#[derive(Clone)]
+pub struct Namerd {
+    addr: SocketAddr,
+    state: Rc<RefCell<NamerdState>>,
+}
+
+/// A way in which the fake namerd fails to resolve a path.
+#[derive(Clone, Debug)]
+pub enum NamerdFailure {
+    /// Responds with the given status.
+    Status(StatusCode),
+    /// Responds successfully with the given body.
+    Body(&'static str),
+    /// Never responds.
+    Stall,
+}
+
+#[derive(Default)]
+struct NamerdState {
+    bound: HashMap<String, Vec<WeightedAddr>>,
+    meta: HashMap<String, Vec<(String, String)>>,
+    failures: HashMap<String, NamerdFailure>,
+    requests: usize,
+    requests_by_path: HashMap<String, usize>,
+    requests_by_namespace: HashMap<String, usize>,
+    etags: bool,
+    not_modified: usize,
+}
+
+impl Namerd {
+    fn spawn(handle: &Handle) -> Namerd {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle)
+            .expect("failed to bind namerd");
+        let addr = listener.local_addr().unwrap();
+        let state = Rc::new(RefCell::new(NamerdState::default()));
+
+        let serve = {
+            let svc = NamerdService(state.clone());
+            let handle = handle.clone();
+            let http = Http::<hyper::Chunk>::new();
+            listener
+                .incoming()
+                .for_each(move |(tcp, _)| {
+                    let conn = http.serve_connection(tcp, svc.clone())
+                        .map(|_| ())
+                        .map_err(|_| ());
+                    handle.spawn(conn);
+                    Ok(())
+                })
+                .map_err(|_| ())
+        };
+        handle.spawn(serve);
+
+        Namerd { addr, state }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Binds `path` to the given weighted addresses for all subsequent requests.
+    pub fn bind(&self, path: &str, addrs: &[(SocketAddr, f64)]) {
+        let addrs: Vec<WeightedAddr> = addrs.iter().map(|&a| WeightedAddr::from(a)).collect();
+        self.bind_addrs(path, &addrs);
+    }
+
+    /// Binds `path` to the given addresses, including their metadata, for all
+    /// subsequent requests.
+    pub fn bind_addrs(&self, path: &str, addrs: &[WeightedAddr]) {
+        self.state.borrow_mut().bound.insert(
+            path.into(),
+            addrs.to_vec(),
+        );
+    }
+
+    /// Responds to subsequent requests for `path` with the given response metadata,
+    /// which may carry remote policy.
+    pub fn set_meta(&self, path: &str, meta: &[(&str, &str)]) {
+        let meta = meta.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+        self.state.borrow_mut().meta.insert(path.into(), meta);
+    }
+
+    /// Stops binding `path`, so that subsequent requests fail.
+    pub fn unbind(&self, path: &str) {
+        self.state.borrow_mut().bound.remove(path);
+    }
+
+    /// Fails all subsequent requests for `path` as described, whether or not it is bound.
+    pub fn fail(&self, path: &str, failure: NamerdFailure) {
+        self.state.borrow_mut().failures.insert(path.into(), failure);
+    }
+
+    /// Tags each resolution with an entity tag, and responds to requests that send the
+    /// current tag with 304 Not Modified.
+    pub fn serve_etags(&self) {
+        self.state.borrow_mut().etags = true;
+    }
+
+    /// The number of 304 Not Modified responses sent.
+    pub fn not_modified(&self) -> usize {
+        self.state.borrow().not_modified
+    }
+
+    /// The number of resolution requests received.
+    pub fn requests(&self) -> usize {
+        self.state.borrow().requests
+    }
+
+    /// The number of resolution requests received for `path`.
+    pub fn requests_for(&self, path: &str) -> usize {
+        self.state.borrow().requests_by_path.get(path).cloned().unwrap_or(0)
+    }
+
+    /// The number of resolution requests received in `namespace`.
+    pub fn requests_in(&self, namespace: &str) -> usize {
+        self.state.borrow().requests_by_namespace.get(namespace).cloned().unwrap_or(0)
+    }
+}
+
+#[derive(Clone)]
+struct NamerdService(Rc<RefCell<NamerdState>>);
+
+impl Service for NamerdService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let mut state = self.0.borrow_mut();
+        state.requests += 1;
+
+        let path = req.query().and_then(|q| {
+            form_urlencoded::parse(q.as_bytes())
+                .find(|&(ref k, _)| k == "path")
+                .map(|(_, v)| v.into_owned())
+        });
+        if let Some(ref p) = path {
+            *state.requests_by_path.entry(p.clone()).or_insert(0) += 1;
+        }
+        if req.path().starts_with(RESOLVE_PREFIX) {
+            let ns = percent_decode(req.path()[RESOLVE_PREFIX.len()..].as_bytes())
+                .decode_utf8_lossy()
+                .into_owned();
+            *state.requests_by_namespace.entry(ns).or_insert(0) += 1;
+        }
+        match path.as_ref().and_then(|p| state.failures.get(p)) {
+            None => {}
+            Some(&NamerdFailure::Stall) => return Box::new(future::empty::<Response, _>()),
+            Some(&NamerdFailure::Status(status)) => {
+                return Box::new(future::ok(Response::new().with_status(status)));
+            }
+            Some(&NamerdFailure::Body(body)) => {
+                let rsp = Response::new()
+                    .with_status(StatusCode::Ok)
+                    .with_header(ContentLength(body.len() as u64))
+                    .with_body(body);
+                return Box::new(future::ok(rsp));
+            }
+        }
+        let bound = match *req.method() {
+            Get => {
+                path.and_then(|p| {
+                    let meta = state.meta.get(&p).map(|m| m.as_slice()).unwrap_or(&[]);
+                    state.bound.get(&p).map(|a| bound_json(a, meta))
+                })
+            }
+            _ => None,
+        };
+
+        let rsp = match bound {
+            None => Response::new().with_status(StatusCode::NotFound),
+            Some(body) => {
+                let etag = if state.etags {
+                    let mut hasher = DefaultHasher::new();
+                    body.hash(&mut hasher);
+                    Some(EntityTag::strong(format!("{:x}", hasher.finish())))
+                } else {
+                    None
+                };
+                let unchanged = match (etag.as_ref(), req.headers().get::<IfNoneMatch>()) {
+                    (Some(etag), Some(&IfNoneMatch::Items(ref tags))) => {
+                        tags.iter().any(|t| t.strong_eq(etag))
+                    }
+                    _ => false,
+                };
+                if unchanged {
+                    state.not_modified += 1;
+                    Response::new().with_status(StatusCode::NotModified)
+                } else {
+                    let mut rsp = Response::new()
+                        .with_status(StatusCode::Ok)
+                        .with_header(ContentLength(body.len() as u64));
+                    if let Some(etag) = etag {
+                        rsp = rsp.with_header(ETag(etag));
+                    }
+                    rsp.with_body(body)
+                }
+            }
+        };
+        Box::new(future::ok(rsp))
+    }
+}
+
+fn bound_json(addrs: &[WeightedAddr], meta: &[(String, String)]) -> String {
+    let addrs: Vec<String> = addrs
+        .iter()
+        .map(|wa| {
+            let meta: String = wa.meta
+                .iter()
+                .map(|(k, v)| format!(",{:?}:{:?}", k, v))
+                .collect();
+            format!(
+                r#"{{"ip":"{}","port":{},"meta":{{"endpoint_addr_weight":{}{}}}}}"#,
+                wa.addr.ip(),
+                wa.addr.port(),
+                wa.weight,
+                meta
+            )
+        })
+        .collect();
+    let meta: Vec<String> = meta.iter().map(|&(ref k, ref v)| format!("{:?}:{:?}", k, v)).collect();
+    format!(
+        r#"{{"type":"bound","addrs":[{}],"meta":{{{}}}}}"#,
+        addrs.join(","),
+        meta.join(",")
+    )
+}
+
+/// Relays each connection to a target once a delay has elapsed, so that the target's
+/// responses (e.g. to a TLS handshake) are delayed.
+pub struct Relay {
+    addr: SocketAddr,
+    accepts: Rc<Cell<usize>>,
+}
+
+impl Relay {
+    fn spawn(handle: &Handle, timer: &Timer, target: SocketAddr, delay: Duration) -> Relay {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle)
+            .expect("failed to bind relay");
+        let addr = listener.local_addr().unwrap();
+        let accepts = Rc::new(Cell::new(0));
+
+        let serve = {
+            let accepts = accepts.clone();
+            let handle = handle.clone();
+            let timer = timer.clone();
+            listener
+                .incoming()
+                .for_each(move |(downstream, _)| {
+                    accepts.set(accepts.get() + 1);
+                    let connect = if delay == Duration::from_secs(0) {
+                        future::Either::A(TcpStream::connect(&target, &handle))
+                    } else {
+                        let handle = handle.clone();
+                        let delayed = timer
+                            .sleep(delay)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+                            .and_then(move |_| TcpStream::connect(&target, &handle));
+                        future::Either::B(delayed)
+                    };
+                    let relay = connect.and_then(move |upstream| {
+                        let (dr, dw) = downstream.split();
+                        let (ur, uw) = upstream.split();
+                        aio::copy(dr, uw).join(aio::copy(ur, dw))
+                    });
+                    handle.spawn(relay.map(|_| ()).map_err(|_| ()));
+                    Ok(())
+                })
+                .map_err(|_| ())
+        };
+        handle.spawn(serve);
+
+        Relay { addr, accepts }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of connections accepted by this relay.
+    pub fn accepts(&self) -> usize {
+        self.accepts.get()
+    }
+}
+
+/// Echoes all bytes it receives back to the sender.
+pub struct EchoServer {
+    addr: SocketAddr,
+    accepts: Rc<Cell<usize>>,
+}
+
+impl EchoServer {
+    fn spawn(handle: &Handle) -> EchoServer {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), handle)
+            .expect("failed to bind echo server");
+        let addr = listener.local_addr().unwrap();
+        let accepts = Rc::new(Cell::new(0));
+
+        let serve = {
+            let accepts = accepts.clone();
+            let handle = handle.clone();
+            listener
+                .incoming()
+                .for_each(move |(tcp, _)| {
+                    accepts.set(accepts.get() + 1);
+                    let (r, w) = tcp.split();
+                    handle.spawn(aio::copy(r, w).map(|_| ()).map_err(|_| ()));
+                    Ok(())
+                })
+                .map_err(|_| ())
+        };
+        handle.spawn(serve);
+
+        EchoServer { addr, accepts }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of connections accepted by this server.
+    pub fn accepts(&self) -> usize {
+        self.accepts.get()
+    }
+}
+
+/// Echoes each UDP datagram it receives back to its sender.
+pub struct UdpEchoServer {
+    addr: SocketAddr,
+    datagrams: Rc<Cell<usize>>,
+}
+
+impl UdpEchoServer {
+    fn spawn(handle: &Handle) -> UdpEchoServer {
+        let sock = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap(), handle)
+            .expect("failed to bind UDP echo server");
+        let addr = sock.local_addr().unwrap();
+        let datagrams = Rc::new(Cell::new(0));
+
+        let serve = {
+            let datagrams = datagrams.clone();
+            let mut buf = vec![0u8; 65536];
+            future::poll_fn(move || -> Result<Async<()>, ()> {
+                loop {
+                    let (sz, from) = match sock.recv_from(&mut buf) {
+                        Ok(recvd) => recvd,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(Async::NotReady);
+                        }
+                        Err(_) => return Err(()),
+                    };
+                    datagrams.set(datagrams.get() + 1);
+                    let _ = sock.send_to(&buf[..sz], &from);
+                }
+            })
+        };
+        handle.spawn(serve);
+
+        UdpEchoServer { addr, datagrams }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of datagrams received by this server.
+    pub fn datagrams(&self) -> usize {
+        self.datagrams.get()
+    }
+}
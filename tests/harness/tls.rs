@@ -0,0 +1,116 @@
+//! Echo servers that terminate TLS, so that proxies' TLS origination may be exercised
+//! without a TLS-terminating proxy in front of the echo server.
+//!
+//! Each connection is served by its own thread with blocking I/O, as TLS clients are.
+
+extern crate rustls;
+
+use self::rustls::{ServerConfig, ServerSession, Session};
+use self::rustls::internal::pemfile;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Echoes all bytes it receives over TLS back to the sender.
+pub struct TlsEchoServer {
+    addr: SocketAddr,
+    accepts: Arc<AtomicUsize>,
+    sni: Arc<Mutex<Option<String>>>,
+}
+
+impl TlsEchoServer {
+    /// Serves the identity whose private key and certificate chain are the PEM files at
+    /// `key` and `certs`.
+    pub(super) fn spawn(key: &str, certs: &str) -> TlsEchoServer {
+        let mut config = ServerConfig::new();
+        config.set_single_cert(load_certs(certs), load_key(key));
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind TLS echo server");
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let sni = Arc::new(Mutex::new(None));
+        {
+            let accepts = accepts.clone();
+            let sni = sni.clone();
+            thread::spawn(move || for tcp in listener.incoming() {
+                let tcp = match tcp {
+                    Ok(tcp) => tcp,
+                    Err(_) => continue,
+                };
+                accepts.fetch_add(1, Ordering::SeqCst);
+                let config = config.clone();
+                let sni = sni.clone();
+                thread::spawn(move || {
+                    let _ = echo(&config, tcp, &sni);
+                });
+            });
+        }
+
+        TlsEchoServer { addr, accepts, sni }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of connections accepted by this server, whether or not their
+    /// handshakes completed.
+    pub fn accepts(&self) -> usize {
+        self.accepts.load(Ordering::SeqCst)
+    }
+
+    /// The server name requested by the most recent client to complete a handshake.
+    pub fn sni(&self) -> Option<String> {
+        self.sni.lock().unwrap().clone()
+    }
+}
+
+fn echo(
+    config: &Arc<ServerConfig>,
+    mut tcp: TcpStream,
+    sni: &Mutex<Option<String>>,
+) -> io::Result<()> {
+    tcp.set_read_timeout(Some(Duration::from_secs(super::IO_TIMEOUT_SECS)))?;
+    let mut session = ServerSession::new(config);
+    let mut handshaking = true;
+    let mut buf = [0u8; 1024];
+    loop {
+        while session.wants_write() {
+            session.write_tls(&mut tcp)?;
+        }
+        if session.read_tls(&mut tcp)? == 0 {
+            return Ok(());
+        }
+        session.process_new_packets().map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+        })?;
+        if handshaking && !session.is_handshaking() {
+            handshaking = false;
+            *sni.lock().unwrap() = session.get_sni_hostname().map(|s| s.to_owned());
+        }
+        loop {
+            let sz = session.read(&mut buf)?;
+            if sz == 0 {
+                break;
+            }
+            session.write_all(&buf[..sz])?;
+        }
+    }
+}
+
+fn load_certs(path: &str) -> Vec<rustls::Certificate> {
+    let file = File::open(path).expect("failed to open certificates");
+    pemfile::certs(&mut BufReader::new(file)).expect("invalid certificates")
+}
+
+fn load_key(path: &str) -> rustls::PrivateKey {
+    let file = File::open(path).expect("failed to open private key");
+    let mut keys = pemfile::rsa_private_keys(&mut BufReader::new(file)).expect("invalid key");
+    assert_eq!(keys.len(), 1, "expected a single private key");
+    keys.remove(0)
+}
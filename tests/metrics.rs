@@ -0,0 +1,143 @@
+extern crate futures;
+extern crate linkerd_tcp;
+extern crate tacho;
+extern crate tokio_core;
+extern crate tokio_timer;
+
+use futures::{Future, Stream};
+use futures::unsync::mpsc;
+use linkerd_tcp::WeightedAddr;
+use linkerd_tcp::lb::{self, NoopMetrics, Scope};
+use std::collections::BTreeSet;
+use std::time::Instant;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+/// A metric's prefix, label, and name.
+type Metric = (Option<&'static str>, Option<(&'static str, &'static str)>, &'static str);
+
+/// Some of the balancer's counters, as they were created from a `tacho::Scope` before
+/// balancers reported through `Metrics`.
+static COUNTERS: &'static [Metric] = &[
+    (None, None, "unavailable"),
+    (None, Some(("locality", "local")), "selections"),
+    (None, Some(("locality", "remote")), "selections"),
+    (Some("connection"), None, "connects"),
+    (Some("connection"), Some(("cause", "timeout")), "failure"),
+    (Some("connection"), Some(("cause", "refused")), "failure"),
+    (Some("pool"), Some(("cause", "idle_timeout")), "closes"),
+    (Some("pool"), Some(("result", "closed")), "validations"),
+];
+
+/// Some of the balancer's gauges.
+static GAUGES: &'static [Metric] = &[
+    (None, None, "waiters"),
+    (Some("endpoint"), None, "available"),
+    (Some("endpoint"), None, "failed"),
+    (Some("connection"), None, "open"),
+];
+
+fn report(reporter: &mut tacho::Reporter) -> String {
+    let mut prometheus = String::new();
+    tacho::prometheus::write(&mut prometheus, &reporter.take()).unwrap();
+    prometheus
+}
+
+/// The exported series, without their values. Histogram buckets, whose bounds depend on
+/// the recorded latencies, are omitted.
+fn series(prometheus: &str) -> BTreeSet<String> {
+    prometheus
+        .lines()
+        .filter(|l| !l.contains("le=\""))
+        .map(|l| l[..l.rfind(' ').unwrap()].to_owned())
+        .collect()
+}
+
+/// The exported counters and gauges, with their values.
+fn values(prometheus: &str) -> BTreeSet<String> {
+    prometheus
+        .lines()
+        .filter(|l| !l.contains("latency_") && !l.contains("poll_time_"))
+        .map(|l| l.to_owned())
+        .collect()
+}
+
+#[test]
+fn reports_into_tacho_under_the_names_and_labels_it_did_directly() {
+    let (direct, mut direct_reporter) = tacho::new();
+    let direct = direct
+        .prefixed("balancer")
+        .labeled("rt", "test")
+        .labeled("dst", "/svc/echo");
+    let (adapted, mut adapted_reporter) = tacho::new();
+    let adapted = Scope::from(adapted)
+        .prefixed("balancer")
+        .labeled("rt", "test")
+        .labeled("dst", "/svc/echo");
+
+    let scopes = |&(prefix, label, _): &Metric| {
+        let (mut d, mut a) = (direct.clone(), adapted.clone());
+        if let Some(p) = prefix {
+            d = d.prefixed(p);
+            a = a.prefixed(p);
+        }
+        if let Some((k, v)) = label {
+            d = d.labeled(k, v);
+            a = a.labeled(k, v);
+        }
+        (d, a)
+    };
+    for (i, metric) in COUNTERS.iter().enumerate() {
+        let (d, a) = scopes(metric);
+        d.counter(metric.2).incr(i + 1);
+        a.counter(metric.2).incr(i + 1);
+    }
+    for (i, metric) in GAUGES.iter().enumerate() {
+        let (d, a) = scopes(metric);
+        let (d, a) = (d.gauge(metric.2), a.gauge(metric.2));
+        d.set(i + 3);
+        a.set(i + 3);
+        d.decr(2);
+        a.decr(2);
+    }
+
+    let t0 = Instant::now();
+    direct.timer_us("poll_time_us").record_since(t0);
+    adapted.timer_us("poll_time_us").record_since(t0);
+    let (direct_conn, adapted_conn) = (
+        direct.clone().prefixed("connection"),
+        adapted.clone().prefixed("connection"),
+    );
+    direct_conn.timer_us("latency_us").record_since(t0);
+    adapted_conn.timer_us("latency_us").record_since(t0);
+    direct.clone().prefixed("resolver").timer_ms("request_latency_ms").record_since(t0);
+    adapted.clone().prefixed("resolver").timer_ms("request_latency_ms").record_since(t0);
+
+    let direct = report(&mut direct_reporter);
+    let adapted = report(&mut adapted_reporter);
+    assert!(direct.contains("selections{"), "missing metrics:\n{}", direct);
+    assert_eq!(series(&adapted), series(&direct));
+    assert_eq!(values(&adapted), values(&direct));
+}
+
+#[test]
+fn balances_without_reporting_metrics() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let timer = tokio_timer::Timer::default();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    handle.spawn(listener.incoming().for_each(|_| Ok(())).map_err(|_| {}));
+
+    // The sender is held so that the balancer's endpoints are never cleared.
+    let (addrs_tx, addrs_rx) = mpsc::unbounded();
+    addrs_tx.unbounded_send(vec![WeightedAddr::new(addr, 1.0)]).unwrap();
+    let metrics = Scope::new(NoopMetrics).prefixed("balancer");
+    let balancer = lb::new(&handle, &timer, "/svc/echo", addrs_rx, &metrics);
+
+    for _ in 0..3 {
+        let conn = core.run(balancer.connect()).expect("failed to connect");
+        assert_eq!(conn.peer_addr(), addr);
+    }
+}
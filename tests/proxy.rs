@@ -0,0 +1,4258 @@
+extern crate futures;
+extern crate hyper;
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate linkerd_tcp;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_timer;
+extern crate url;
+
+mod harness;
+
+use futures::{Async, Poll, Stream};
+use harness::{EchoServer, Harness, NamerdFailure, Proxy};
+use linkerd_tcp::{ConnectErrorKind, Error, WeightedAddr};
+use linkerd_tcp::app::{AppBuilder, AppConfig, ConnectorConfig, Interpreter, Millis, Notifier,
+                      RouterBuilder, ServerConfig};
+use linkerd_tcp::dns::{Dns, HostPort, Name, Resolution};
+use linkerd_tcp::lb::{ConnectionHook, ConnectionSummary, Decision, DecisionFuture, RejectCidrs};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{self, IpAddr, Ipv4Addr, Shutdown, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_io::io as aio;
+
+static CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+";
+
+static CIRCUIT_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 500
+    client:
+      kind: io.l5d.global
+      circuitBreaker:
+        minRequests: 3
+        openSecs: 60
+";
+
+static CHAOS_CONFIG: &'static str = "
+admin:
+  port: 0
+allowChaos: true
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 500
+    client:
+      kind: io.l5d.global
+      circuitBreaker:
+        minRequests: 3
+        failureRateThreshold: 0.5
+        openSecs: 60
+    chaos:
+      dropConnectPercent: 100
+";
+
+static REJECT_TLS_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+        detectMisdirectedTls: reject
+";
+
+static BACKOFF_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+    client:
+      kind: io.l5d.global
+      connectBackoff:
+        baseBackoffMs: 60000
+";
+
+static BACKOFF_TIMEOUT_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 300
+    client:
+      kind: io.l5d.global
+      connectBackoff:
+        baseBackoffMs: 60000
+";
+
+static SEEDED_CONFIG: &'static str = "
+admin:
+  port: 0
+rngSeed: 7
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+";
+
+static FALLBACK_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+    client:
+      kind: io.l5d.global
+      fallback:
+        addrs: [\"{fallback}\"]
+        activateAfterSecs: 200ms
+";
+
+static PERIOD_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: {label}
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: {period}
+    servers:
+      - port: 0
+        dstName: /svc/{label}
+        connectTimeoutMs: 5000
+";
+
+static UDP_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - kind: io.l5d.udp
+        port: 0
+        dstName: /svc/echo
+        maxDatagramBytes: 1024
+";
+
+#[test]
+fn proxies_bytes() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    assert_eq!(echo.accepts(), 1);
+    assert_eq!(proxy.metric("accepts"), 1);
+}
+
+#[test]
+fn proxies_apps_built_in_code() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let mut names = HashMap::new();
+    names.insert("/svc/echo".to_owned(), vec![WeightedAddr::new(echo.addr(), 1.0)]);
+    let server = ServerConfig {
+        dst_name: Some("/svc/echo".to_owned()),
+        connect_timeout_ms: Some(Millis(Duration::from_secs(5))),
+        ..ServerConfig::default()
+    };
+    let app = AppBuilder::new()
+        .admin_addr("127.0.0.1:0".parse().unwrap())
+        .router(RouterBuilder::new("test", Interpreter::Static(names)).server(server))
+        .build()
+        .expect("failed to build app");
+    let proxy = h.spawn(app);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    assert_eq!(echo.accepts(), 1);
+    assert_eq!(h.namerd().requests(), 0);
+}
+
+#[test]
+fn prefers_heavier_endpoints() {
+    let mut h = Harness::new();
+    let heavy = h.echo_server();
+    let light = h.echo_server();
+    h.namerd().bind(
+        "/svc/echo",
+        &[(heavy.addr(), 9.0), (light.addr(), 1.0)],
+    );
+    let proxy = h.proxy(CONFIG);
+
+    for _ in 0..50 {
+        let rsp = h.roundtrip(&proxy.addr(), b"ping");
+        assert_eq!(rsp, b"ping".to_vec());
+    }
+    assert_eq!(heavy.accepts() + light.accepts(), 50);
+    assert!(
+        heavy.accepts() > 3 * light.accepts(),
+        "heavy={} light={}",
+        heavy.accepts(),
+        light.accepts()
+    );
+}
+
+#[test]
+fn drains_removed_endpoints() {
+    let mut h = Harness::new();
+    let old = h.echo_server();
+    let new = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(old.addr(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    // Hold a connection open to the endpoint that is about to be removed.
+    let conn = h.connect(&proxy.addr());
+    let (conn, rsp) = h.echo(conn, b"before");
+    assert_eq!(rsp, b"before".to_vec());
+    assert_eq!(old.accepts(), 1);
+
+    h.namerd().bind("/svc/echo", &[(new.addr(), 1.0)]);
+    let polls = h.namerd().requests();
+    h.sleep(Duration::from_millis(2500));
+    assert!(h.namerd().requests() > polls);
+
+    // New connections are only sent to the new endpoint.
+    for _ in 0..5 {
+        let rsp = h.roundtrip(&proxy.addr(), b"new");
+        assert_eq!(rsp, b"new".to_vec());
+    }
+    assert_eq!(new.accepts(), 5);
+    assert_eq!(old.accepts(), 1);
+
+    // The existing connection continues to be served by the removed endpoint.
+    let (_, rsp) = h.echo(conn, b"after");
+    assert_eq!(rsp, b"after".to_vec());
+}
+
+#[test]
+fn opens_circuit_when_connects_fail() {
+    let mut h = Harness::new();
+    let dead = h.unused_addr();
+    h.namerd().bind("/svc/echo", &[(dead, 1.0)]);
+    let proxy = h.proxy(CIRCUIT_CONFIG);
+
+    for _ in 0..5 {
+        assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    }
+    assert!(proxy.metric("circuit_rejections") > 0);
+    assert_eq!(proxy.metric("circuit_state"), 2);
+}
+
+#[test]
+fn opens_circuit_when_chaos_drops_connects() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.chaos_proxy(CHAOS_CONFIG);
+
+    for _ in 0..5 {
+        assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    }
+    // Connects were dropped before reaching the endpoint, until the circuit opened.
+    assert_eq!(echo.accepts(), 0);
+    assert!(proxy.labeled_metric("chaos_faults", "fault=\"drop\"") >= 3);
+    assert!(proxy.metric("circuit_rejections") > 0);
+    assert_eq!(proxy.metric("circuit_state"), 2);
+}
+
+#[test]
+fn ignores_chaos_unless_enabled() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(CHAOS_CONFIG);
+
+    for _ in 0..5 {
+        let rsp = h.roundtrip(&proxy.addr(), b"ping");
+        assert_eq!(rsp, b"ping".to_vec());
+    }
+    assert_eq!(proxy.labeled_metric("chaos_faults", "fault=\"drop\""), 0);
+    assert_eq!(proxy.metric("circuit_state"), 0);
+}
+
+#[test]
+fn rejects_misdirected_tls() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(REJECT_TLS_CONFIG);
+
+    // A TLS 1.0 record header followed by a ClientHello handshake type.
+    let hello = [22, 3, 1, 0, 64, 1, 0, 0];
+    assert!(h.try_roundtrip(&proxy.addr(), &hello).is_err());
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("refused"), 1);
+    assert_eq!(
+        proxy.labeled_metric("close_reasons", "reason=\"misdirected_tls\""),
+        1
+    );
+
+    let rsp = h.roundtrip(&proxy.addr(), b"GET / HTTP/1.1\r\n");
+    assert_eq!(rsp, b"GET / HTTP/1.1\r\n".to_vec());
+    assert_eq!(proxy.metric("refused"), 1);
+    assert_eq!(
+        proxy.labeled_metric("sniffed", "protocol=\"looks_like_http\""),
+        1
+    );
+}
+
+#[test]
+fn classifies_streams_whose_servers_speak_first_once_their_clients_do() {
+    let mut h = Harness::new();
+    let greeter = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    h.namerd().bind("/svc/echo", &[(greeter.local_addr().unwrap(), 1.0)]);
+    thread::spawn(move || {
+        for conn in greeter.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+            conn.write_all(b"hello").unwrap();
+            let mut buf = [0u8; 16];
+            while conn.read(&mut buf).map(|sz| sz > 0).unwrap_or(false) {}
+        }
+    });
+    let proxy = h.proxy(REJECT_TLS_CONFIG);
+
+    // The client waits for the server's greeting, which is not held for it to speak.
+    let conn = h.connect(&proxy.addr());
+    let (conn, greeting) = h.read_exact(conn, 5);
+    assert_eq!(greeting, b"hello".to_vec());
+    assert_eq!(proxy.labeled_metric("sniffed", "protocol=\"other\""), 0);
+
+    let (conn, _) = h.run(aio::write_all(conn, b"GET / HTTP/1.1\r\n".to_vec())).expect(
+        "write failed",
+    );
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(
+        proxy.labeled_metric("sniffed", "protocol=\"looks_like_http\""),
+        1
+    );
+    assert_eq!(proxy.labeled_metric("sniffed", "protocol=\"other\""), 0);
+    drop(conn);
+}
+
+#[test]
+fn ejects_and_reinstates_endpoints() {
+    let mut h = Harness::new();
+    let bad = h.echo_server();
+    let good = h.echo_server();
+    h.namerd().bind(
+        "/svc/echo",
+        &[(bad.addr(), 1.0), (good.addr(), 1.0)],
+    );
+    let proxy = h.proxy(CONFIG);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"warmup");
+    assert_eq!(rsp, b"warmup".to_vec());
+
+    assert!(proxy.ejections().eject(Some("test"), bad.addr()));
+    let ejected_accepts = bad.accepts();
+    for _ in 0..20 {
+        let rsp = h.roundtrip(&proxy.addr(), b"ejected");
+        assert_eq!(rsp, b"ejected".to_vec());
+    }
+    assert_eq!(bad.accepts(), ejected_accepts);
+    assert_eq!(proxy.metric("endpoint_ejected"), 1);
+
+    // Ejections persist across service discovery updates.
+    let polls = h.namerd().requests();
+    h.sleep(Duration::from_millis(2500));
+    assert!(h.namerd().requests() > polls);
+    for _ in 0..5 {
+        h.roundtrip(&proxy.addr(), b"ejected");
+    }
+    assert_eq!(bad.accepts(), ejected_accepts);
+
+    assert!(proxy.ejections().reinstate(None, bad.addr()));
+    for _ in 0..50 {
+        h.roundtrip(&proxy.addr(), b"reinstated");
+        if bad.accepts() > ejected_accepts {
+            break;
+        }
+    }
+    assert!(bad.accepts() > ejected_accepts);
+}
+
+#[test]
+fn backs_off_failing_endpoints() {
+    let mut h = Harness::new();
+    let dead = h.unused_addr();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(dead, 1.0), (echo.addr(), 1.0)]);
+    let proxy = h.proxy(BACKOFF_CONFIG);
+
+    for _ in 0..20 {
+        let rsp = h.roundtrip(&proxy.addr(), b"ping");
+        assert_eq!(rsp, b"ping".to_vec());
+    }
+    assert_eq!(echo.accepts(), 20);
+    // Once the dead endpoint has failed, it is not dialed again until its backoff
+    // elapses.
+    assert!(proxy.metric("connection_failure") <= 1);
+}
+
+#[test]
+fn counts_each_period_without_endpoints_to_dial_once() {
+    let mut h = Harness::new();
+    let dead = h.unused_addr();
+    h.namerd().bind("/svc/echo", &[(dead, 1.0)]);
+    let proxy = h.proxy(BACKOFF_TIMEOUT_CONFIG);
+
+    // Once the only endpoint has failed, connections wait out their timeout while it
+    // backs off, however often the balancer is polled in the meantime.
+    for _ in 0..3 {
+        assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    }
+    assert!(proxy.metric("connection_failure") <= 1);
+    assert_eq!(proxy.metric("unavailable"), 1);
+}
+
+/// Returns the index of the endpoint selected for each of `n` sequential connections.
+fn selections(config: &str, n: usize) -> Vec<usize> {
+    let mut h = Harness::new();
+    let echos = vec![h.echo_server(), h.echo_server(), h.echo_server()];
+    let addrs: Vec<_> = echos.iter().map(|e| (e.addr(), 1.0)).collect();
+    h.namerd().bind("/svc/echo", &addrs);
+    let proxy = h.proxy(config);
+
+    let mut selected = Vec::with_capacity(n);
+    for _ in 0..n {
+        let before: Vec<_> = echos.iter().map(|e| e.accepts()).collect();
+        h.roundtrip(&proxy.addr(), b"ping");
+        let i = (0..echos.len())
+            .find(|&i| echos[i].accepts() > before[i])
+            .expect("no endpoint selected");
+        selected.push(i);
+    }
+    selected
+}
+
+#[test]
+fn seeded_selection_is_reproducible() {
+    let first = selections(SEEDED_CONFIG, 30);
+    let second = selections(SEEDED_CONFIG, 30);
+    assert_eq!(first, second);
+
+    // Every endpoint is eventually selected.
+    for i in 0..3 {
+        assert!(first.contains(&i), "endpoint {} never selected: {:?}", i, first);
+    }
+}
+
+#[test]
+fn falls_back_while_no_endpoints_are_available() {
+    let mut h = Harness::new();
+    let primary = h.echo_server();
+    let dr = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(primary.addr(), 1.0)]);
+    let config = FALLBACK_CONFIG.replace("{fallback}", &dr.addr().to_string());
+    let proxy = h.proxy(&config);
+
+    for _ in 0..5 {
+        h.roundtrip(&proxy.addr(), b"primary");
+    }
+    assert_eq!(primary.accepts(), 5);
+    assert_eq!(dr.accepts(), 0);
+
+    // Once every primary endpoint is out of service, connections are sent to the
+    // fallback after it activates.
+    assert!(proxy.ejections().eject(Some("test"), primary.addr()));
+    for _ in 0..5 {
+        let rsp = h.roundtrip(&proxy.addr(), b"fallback");
+        assert_eq!(rsp, b"fallback".to_vec());
+    }
+    assert_eq!(primary.accepts(), 5);
+    assert_eq!(dr.accepts(), 5);
+
+    // As soon as a primary endpoint is available, the fallback is no longer used.
+    assert!(proxy.ejections().reinstate(None, primary.addr()));
+    for _ in 0..5 {
+        let rsp = h.roundtrip(&proxy.addr(), b"recovered");
+        assert_eq!(rsp, b"recovered".to_vec());
+    }
+    assert_eq!(primary.accepts(), 10);
+    assert_eq!(dr.accepts(), 5);
+    assert_eq!(proxy.metric("fallback_transitions"), 2);
+    assert_eq!(proxy.metric("fallback_active"), 0);
+}
+
+static STARTUP_CONFIG: &'static str = "
+startupConcurrency: 1
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+      - port: 0
+        dstName: /svc/other
+";
+
+#[test]
+fn reports_startup_progress() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(STARTUP_CONFIG);
+    assert_eq!(proxy.startup().progress(), (0, 2));
+    assert!(!proxy.startup().is_ready());
+
+    // Destinations are resolved before any connection is accepted.
+    h.sleep(Duration::from_millis(500));
+    assert_eq!(proxy.startup().progress(), (1, 2));
+    assert!(!proxy.startup().is_ready());
+    assert_eq!(proxy.metric("startup_ready_proxies"), 1);
+
+    h.namerd().bind("/svc/other", &[(echo.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    assert_eq!(proxy.startup().progress(), (2, 2));
+    assert!(proxy.startup().is_ready());
+    assert_eq!(proxy.metric("startup_proxies"), 2);
+    assert_eq!(proxy.metric("startup_resolutions_in_flight"), 0);
+}
+
+#[test]
+fn polls_namerd_at_each_routers_period() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/fast", &[(echo.addr(), 1.0)]);
+    h.namerd().bind("/svc/slow", &[(echo.addr(), 1.0)]);
+    let fast = h.proxy(&PERIOD_CONFIG.replace("{label}", "fast").replace(
+        "{period}",
+        "100ms",
+    ));
+    let slow = h.proxy(&PERIOD_CONFIG.replace("{label}", "slow").replace(
+        "{period}",
+        "1s",
+    ));
+
+    // Each destination is resolved as its proxy starts.
+    h.roundtrip(&fast.addr(), b"ping");
+    h.roundtrip(&slow.addr(), b"ping");
+    h.sleep(Duration::from_secs(2));
+
+    let fast_requests = h.namerd().requests_for("/svc/fast");
+    let slow_requests = h.namerd().requests_for("/svc/slow");
+    assert!(slow_requests >= 2, "slow: {}", slow_requests);
+    assert!(
+        fast_requests >= 4 * slow_requests,
+        "fast: {}, slow: {}",
+        fast_requests,
+        slow_requests
+    );
+
+    // Each path's requests are reported separately.
+    let fast_successes = fast.labeled_metric("success_count", "path=\"/svc/fast\"");
+    assert!(fast_successes >= 4 * slow_requests);
+    assert_eq!(fast.labeled_metric("success_count", "path=\"/svc/slow\""), 0);
+}
+
+#[test]
+fn records_close_reasons() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let closing = h.closing_server(false);
+    let resetting = h.closing_server(true);
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    h.namerd().bind("/svc/closing", &[(closing, 1.0)]);
+    h.namerd().bind("/svc/resetting", &[(resetting, 1.0)]);
+    let echo_proxy = h.proxy(CONFIG);
+    let closing_proxy = h.proxy(&CONFIG.replace("/svc/echo", "/svc/closing"));
+    let resetting_proxy = h.proxy(&CONFIG.replace("/svc/echo", "/svc/resetting"));
+
+    // The client closes once its message has been echoed.
+    h.roundtrip(&echo_proxy.addr(), b"ping");
+    // The server closes before the client sends anything.
+    assert_eq!(h.read_to_end(&closing_proxy.addr()).unwrap(), Vec::<u8>::new());
+    // The server resets the connection before the client sends anything.
+    let _ = h.read_to_end(&resetting_proxy.addr());
+    h.sleep(Duration::from_millis(100));
+
+    let reason = |p: &harness::Proxy, r: &str| {
+        p.labeled_metric("close_reasons", &format!("reason=\"{}\"", r))
+    };
+    assert_eq!(reason(&echo_proxy, "client_eof"), 1);
+    assert_eq!(reason(&closing_proxy, "server_eof"), 1);
+    assert_eq!(reason(&resetting_proxy, "server_reset"), 1);
+}
+
+#[test]
+fn records_one_close_reason_when_both_peers_close() {
+    let mut h = Harness::new();
+    let closing = h.closing_server(false);
+    h.namerd().bind("/svc/echo", &[(closing, 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    // Clients close immediately, racing the server to close first. Whichever close
+    // is observed first determines each connection's reason.
+    for _ in 0..5 {
+        drop(h.connect(&proxy.addr()));
+    }
+    h.sleep(Duration::from_millis(500));
+
+    let client_eof = proxy.labeled_metric("close_reasons", "reason=\"client_eof\"");
+    let server_eof = proxy.labeled_metric("close_reasons", "reason=\"server_eof\"");
+    assert_eq!(client_eof + server_eof, 5);
+    assert_eq!(proxy.metric("close_reasons"), 5);
+}
+
+#[test]
+fn resolves_namerd_by_hostname() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let base_url = h.namerd().base_url().replace("http://127.0.0.1", "localhost");
+    let proxy = h.proxy(&CONFIG.replace("{namerd}", &base_url));
+
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    assert!(h.namerd().requests() > 0);
+}
+
+#[test]
+fn reports_open_connections() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = format!(
+        "{}    client:\n      kind: io.l5d.global\n      endpointMetrics: true\n",
+        CONFIG
+    );
+    let proxy = h.proxy(&config);
+    let addr_label = format!("addr=\"{}\"", echo.addr());
+
+    let mut conns = Vec::new();
+    for _ in 0..3 {
+        let conn = h.connect(&proxy.addr());
+        let (conn, _) = h.echo(conn, b"ping");
+        conns.push(conn);
+    }
+    assert_eq!(proxy.metric("connection_open"), 3);
+    assert_eq!(proxy.labeled_metric("endpoint_open_conns", &addr_label), 3);
+
+    drop(conns);
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("connection_open"), 0);
+    assert_eq!(proxy.labeled_metric("endpoint_open_conns", &addr_label), 0);
+    assert_eq!(proxy.metric("connection_pending"), 0);
+    assert_eq!(proxy.metric("state_accounting_errors"), 0);
+}
+
+#[test]
+fn releases_open_connections_closed_by_endpoints() {
+    let mut h = Harness::new();
+    let resetting = h.closing_server(true);
+    h.namerd().bind("/svc/echo", &[(resetting, 1.0)]);
+    let config = format!(
+        "{}    client:\n      kind: io.l5d.global\n      endpointMetrics: true\n",
+        CONFIG
+    );
+    let proxy = h.proxy(&config);
+    let addr_label = format!("addr=\"{}\"", resetting);
+
+    for _ in 0..3 {
+        let _ = h.read_to_end(&proxy.addr());
+    }
+    h.sleep(Duration::from_millis(100));
+    assert!(proxy.metric("connection_connects") > 0);
+    assert_eq!(proxy.labeled_metric("endpoint_open_conns", &addr_label), 0);
+    assert_eq!(proxy.labeled_metric("endpoint_pending_conns", &addr_label), 0);
+    assert_eq!(proxy.metric("state_accounting_errors"), 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn releases_pending_connections_that_time_out() {
+    let mut h = Harness::new();
+    let (listener, _queued) = blackhole();
+    let addr = listener.local_addr().unwrap();
+    h.namerd().bind("/svc/echo", &[(addr, 1.0)]);
+    let config = format!(
+        "{}    client:\n      kind: io.l5d.global\n      endpointMetrics: true\n      \
+         connectTimeoutMs: 300\n",
+        CONFIG
+    );
+    let proxy = h.proxy(&config);
+    let addr_label = format!("addr=\"{}\"", addr);
+
+    let conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.labeled_metric("endpoint_pending_conns", &addr_label), 1);
+
+    // The endpoint never accepts the connection, so the attempt times out.
+    drop(conn);
+    h.sleep(Duration::from_millis(500));
+    assert!(proxy.labeled_metric("connection_failure", "cause=\"timeout\"") > 0);
+    assert_eq!(proxy.labeled_metric("endpoint_pending_conns", &addr_label), 0);
+    assert_eq!(proxy.labeled_metric("endpoint_open_conns", &addr_label), 0);
+    assert_eq!(proxy.metric("state_accounting_errors"), 0);
+}
+
+/// Addresses on which nothing listens, standing in for a large namespace.
+fn unused_addrs(n: usize) -> Vec<(SocketAddr, f64)> {
+    (0..n)
+        .map(|i| {
+            let ip = Ipv4Addr::new(127, 1, (i / 250) as u8, (i % 250) as u8 + 1);
+            (SocketAddr::new(IpAddr::V4(ip), 9), 1.0)
+        })
+        .collect()
+}
+
+#[test]
+fn resolves_large_namerd_responses() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let mut addrs = unused_addrs(5000);
+    addrs.push((echo.addr(), 1.0));
+    h.namerd().bind("/svc/echo", &addrs);
+    let proxy = h.proxy(CONFIG);
+
+    // The destination is resolved as the proxy starts.
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+
+    assert!(proxy.labeled_metric("success_count", "path=\"/svc/echo\"") > 0);
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 5001);
+}
+
+#[test]
+fn rejects_oversized_namerd_responses() {
+    let mut h = Harness::new();
+    h.namerd().bind("/svc/echo", &unused_addrs(100));
+    let config = CONFIG.replace("periodSecs: 1\n", "periodSecs: 1\n      maxResponseBytes: 1024\n");
+    let proxy = h.proxy(&config);
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+
+    assert!(proxy.labeled_metric("failure_count", "path=\"/svc/echo\"") > 0);
+    assert_eq!(proxy.labeled_metric("success_count", "path=\"/svc/echo\""), 0);
+    assert_eq!(proxy.metric("endpoint_available"), 0);
+}
+
+#[test]
+fn merges_duplicate_namerd_addrs() {
+    let mut h = Harness::new();
+    let a = h.echo_server();
+    let b = h.echo_server();
+    h.namerd().bind(
+        "/svc/echo",
+        &[(a.addr(), 0.5), (b.addr(), 1.0), (a.addr(), 0.5)],
+    );
+    let proxy = h.proxy(CONFIG);
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 2);
+    assert_eq!(proxy.labeled_metric("duplicate_addrs", "path=\"/svc/echo\""), 1);
+    // The duplicate's weight is added to the first entry's.
+    let a_weight = endpoint_state(&proxy, &a.addr())["weight"].as_f64();
+    let b_weight = endpoint_state(&proxy, &b.addr())["weight"].as_f64();
+    assert!(a_weight.is_some());
+    assert_eq!(a_weight, b_weight);
+}
+
+#[test]
+fn skips_invalid_namerd_addrs() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind(
+        "/svc/echo",
+        &[
+            ("127.0.0.1:0".parse().unwrap(), 1.0),
+            (echo.addr(), 1.0),
+            ("0.0.0.0:9".parse().unwrap(), 1.0),
+            ("224.0.0.1:9".parse().unwrap(), 1.0),
+        ],
+    );
+    let proxy = h.proxy(CONFIG);
+
+    for _ in 0..4 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 1);
+    assert_eq!(proxy.labeled_metric("invalid_addrs", "path=\"/svc/echo\""), 3);
+    assert_eq!(echo.accepts(), 4);
+}
+
+#[test]
+fn rejects_namerd_responses_with_too_many_addrs() {
+    let mut h = Harness::new();
+    h.namerd().bind("/svc/echo", &unused_addrs(11));
+    let config = CONFIG.replace("periodSecs: 1\n", "periodSecs: 1\n      maxAddrs: 10\n");
+    let proxy = h.proxy(&config);
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+
+    assert!(proxy.labeled_metric("too_many_addrs", "path=\"/svc/echo\"") > 0);
+    assert!(proxy.labeled_metric("error_count", "cause=\"parse\"") > 0);
+    assert_eq!(proxy.labeled_metric("success_count", "path=\"/svc/echo\""), 0);
+    assert_eq!(proxy.metric("endpoint_available"), 0);
+
+    // Duplicates do not count against the limit.
+    let mut addrs = unused_addrs(10);
+    addrs.extend(unused_addrs(10));
+    h.namerd().bind("/svc/echo", &addrs);
+    h.sleep(Duration::from_millis(1500));
+    assert!(proxy.labeled_metric("success_count", "path=\"/svc/echo\"") > 0);
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 10);
+}
+
+/// Resolves `/svc/echo` through a proxy built from `config`, with namerd failing as
+/// described, and checks that only `category`'s resolver error counter moves.
+fn assert_resolver_error(config: &str, failure: Option<NamerdFailure>, category: &str) {
+    let mut h = Harness::new();
+    if let Some(f) = failure {
+        h.namerd().fail("/svc/echo", f);
+    }
+    let proxy = h.proxy(config);
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+
+    assert!(proxy.labeled_metric("failure_count", "path=\"/svc/echo\"") > 0);
+    assert_eq!(proxy.labeled_metric("success_count", "path=\"/svc/echo\""), 0);
+    for c in &["http_4xx", "http_5xx", "transport", "timeout", "parse", "not_bound"] {
+        let errors = proxy.labeled_metric("error_count", &format!("cause=\"{}\"", c));
+        if *c == category {
+            assert!(errors > 0, "{} errors not counted", c);
+        } else {
+            assert_eq!(errors, 0, "{} errors counted", c);
+        }
+    }
+}
+
+#[test]
+fn counts_resolver_errors_by_category() {
+    // Paths that are not bound are not found.
+    assert_resolver_error(CONFIG, None, "http_4xx");
+    let unavailable = NamerdFailure::Status(hyper::StatusCode::ServiceUnavailable);
+    assert_resolver_error(CONFIG, Some(unavailable), "http_5xx");
+    assert_resolver_error(CONFIG, Some(NamerdFailure::Body("{\"type\":")), "parse");
+    let neg = NamerdFailure::Body("{\"type\":\"neg\",\"addrs\":[],\"meta\":{}}");
+    assert_resolver_error(CONFIG, Some(neg), "not_bound");
+
+    let config = CONFIG.replace("periodSecs: 1\n", "periodSecs: 1\n      requestTimeoutMs: 200\n");
+    assert_resolver_error(&config, Some(NamerdFailure::Stall), "timeout");
+
+    let unused = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = CONFIG.replace("{namerd}", &format!("http://{}", unused));
+    assert_resolver_error(&config, None, "transport");
+}
+
+/// Binds a socket to which spans may be exported, and configures `config` to export
+/// spans sampled at `sample_rate` to it.
+fn span_collector(config: &str, sample_rate: &str) -> (UdpSocket, String) {
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_nonblocking(true).unwrap();
+    let config = format!(
+        "tracing:\n  export:\n    udp: {}\n  sampleRate: {}\n{}",
+        collector.local_addr().unwrap(),
+        sample_rate,
+        config.trim_left()
+    );
+    (collector, config)
+}
+
+/// Receives the spans that have been exported to `collector`.
+fn recv_spans(collector: &UdpSocket) -> Vec<serde_json::Value> {
+    let mut spans = Vec::new();
+    let mut buf = [0u8; 4096];
+    while let Ok(sz) = collector.recv(&mut buf) {
+        spans.push(serde_json::from_slice(&buf[..sz]).expect("invalid span"));
+    }
+    spans
+}
+
+/// Proxies a single roundtrip with tracing sampled at `sample_rate`, returning the
+/// exported spans.
+fn traced_roundtrip(sample_rate: &str) -> (SocketAddr, Vec<serde_json::Value>) {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let (collector, config) = span_collector(CONFIG, sample_rate);
+    let proxy = h.proxy(&config);
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(100));
+    (echo.addr(), recv_spans(&collector))
+}
+
+#[test]
+fn exports_sampled_connection_spans() {
+    let (echo_addr, spans) = traced_roundtrip("1.0");
+    assert_eq!(spans.len(), 1);
+    let span = &spans[0];
+
+    assert_eq!(span["dstName"], "/svc/echo");
+    assert_eq!(span["dstAddr"], echo_addr.to_string());
+    assert_eq!(span["closeReason"], "client_eof");
+    assert!(span["parentId"].is_null());
+    let t = |k: &str| span[k].as_u64().expect(&format!("missing {}", k));
+    assert!(t("readyUs") <= t("connectedUs"));
+    assert!(t("connectedUs") <= t("firstServerByteUs"));
+    assert!(t("firstClientByteUs") <= t("firstServerByteUs"));
+    assert!(t("firstServerByteUs") <= t("closedUs"));
+}
+
+/// Proxies a roundtrip and returns its span.
+fn traced_span(h: &mut Harness, proxy: &Proxy, collector: &UdpSocket) -> serde_json::Value {
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(100));
+    let mut spans = recv_spans(collector);
+    assert_eq!(spans.len(), 1);
+    spans.pop().unwrap()
+}
+
+#[test]
+fn stamps_spans_with_the_generation_of_their_endpoints() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = filter_config("{allowCidrs: [\"127.0.0.1/32\"]}");
+    let (collector, config) = span_collector(&config, "1.0");
+    let proxy = h.proxy(&config);
+
+    let first = traced_span(&mut h, &proxy, &collector);
+    assert_eq!(first["resolutionGeneration"], 1);
+    let updated_at = first["resolutionUpdatedAtMs"].as_u64().expect("missing update time");
+    assert!(first["resolutionAgeMs"].is_u64());
+    assert_eq!(proxy.metric("endpoint_generation"), 1);
+
+    // Resolutions that do not change the endpoints, here because the addresses they
+    // add are filtered, do not begin a new generation.
+    let updates = proxy.metric("endpoint_updates");
+    let mut addrs = unused_addrs(2);
+    addrs.push((echo.addr(), 1.0));
+    h.namerd().bind("/svc/echo", &addrs);
+    h.sleep(Duration::from_millis(1500));
+    assert!(proxy.metric("endpoint_updates") > updates);
+    let unchanged = traced_span(&mut h, &proxy, &collector);
+    assert_eq!(unchanged["resolutionGeneration"], 1);
+    assert_eq!(unchanged["resolutionUpdatedAtMs"], updated_at);
+    assert!(unchanged["resolutionAgeMs"].as_u64().unwrap() >= 1_000);
+
+    let other = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0), (other.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    let changed = traced_span(&mut h, &proxy, &collector);
+    assert_eq!(changed["resolutionGeneration"], 2);
+    assert!(changed["resolutionUpdatedAtMs"].as_u64().unwrap() > updated_at);
+    assert_eq!(proxy.metric("endpoint_generation"), 2);
+}
+
+#[test]
+fn exports_no_spans_for_unsampled_connections() {
+    let (_, spans) = traced_roundtrip("0.0");
+    assert!(spans.is_empty());
+}
+
+#[test]
+fn sizes_transfer_buffers_for_each_direction() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = format!(
+        "bufferSizeBytes: 1024\nclientToServerBufferBytes: 7\n{}",
+        CONFIG.trim_left()
+    );
+    let proxy = h.proxy(&config);
+    assert_eq!(proxy.metric("transfer_buffer_bytes"), 1024 + 7);
+
+    // Each direction is copied in chunks no larger than its own buffer.
+    let msg: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    assert_eq!(h.roundtrip(&proxy.addr(), &msg), msg);
+}
+
+/// Proxies a roundtrip whose streams are checked with `integrity`, returning the
+/// response and the number of mismatches counted.
+fn checked_roundtrip(integrity: &str, msg: &[u8]) -> (Vec<u8>, u64) {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = CONFIG.replace(
+        "connectTimeoutMs: 5000\n",
+        &format!("connectTimeoutMs: 5000\n        integrityCheck: {}\n", integrity),
+    );
+    let proxy = h.proxy(&config);
+
+    let rsp = h.roundtrip(&proxy.addr(), msg);
+    h.sleep(Duration::from_millis(100));
+    (rsp, proxy.metric("integrity_mismatch"))
+}
+
+#[test]
+fn checks_stream_integrity() {
+    let msg: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    let (rsp, mismatches) = checked_roundtrip("{enabled: true, algorithm: crc32}", &msg);
+    assert_eq!(rsp, msg);
+    assert_eq!(mismatches, 0);
+}
+
+#[test]
+fn counts_corrupted_streams() {
+    let config = "{enabled: true, algorithm: crc32, corruptForTesting: true}";
+    let (rsp, mismatches) = checked_roundtrip(config, b"ping");
+    assert_eq!(rsp, b"\x8fing".to_vec());
+    // Only the client's stream to the server was corrupted.
+    assert_eq!(mismatches, 1);
+}
+
+/// Configures the global client with an endpoint filter.
+fn filter_config(filter: &str) -> String {
+    format!(
+        "{}    client:\n      kind: io.l5d.global\n      endpointFilter: {}\n",
+        CONFIG,
+        filter
+    )
+}
+
+#[test]
+fn filters_denied_endpoints() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let mut addrs = unused_addrs(10);
+    addrs.push(("[fd00::1]:9".parse().unwrap(), 1.0));
+    addrs.push((echo.addr(), 1.0));
+    h.namerd().bind("/svc/echo", &addrs);
+    let proxy = h.proxy(&filter_config(
+        "{denyCidrs: [\"127.1.0.0/16\", \"fd00::/8\"]}",
+    ));
+
+    for _ in 0..5 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    assert_eq!(echo.accepts(), 5);
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 1);
+    assert!(proxy.metric("endpoint_filtered") >= 11);
+}
+
+#[test]
+fn filters_endpoints_outside_allowed_ranges_on_each_update() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&filter_config("{allowCidrs: [\"127.0.0.1/32\"]}"));
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(proxy.metric("endpoint_filtered"), 0);
+
+    // Addresses added by later resolutions are filtered as well.
+    let mut addrs = unused_addrs(10);
+    addrs.push(("[fd00::1]:9".parse().unwrap(), 1.0));
+    addrs.push((echo.addr(), 1.0));
+    h.namerd().bind("/svc/echo", &addrs);
+    h.sleep(Duration::from_millis(1500));
+
+    for _ in 0..5 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"pong"), b"pong".to_vec());
+    }
+    assert_eq!(echo.accepts(), 6);
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 1);
+    assert!(proxy.metric("endpoint_filtered") >= 11);
+}
+
+/// Holds `n` connections through the proxy open at once, returning the number that
+/// were dispatched to `echo`.
+fn held_accepts(h: &mut Harness, proxy: &Proxy, echo: &EchoServer, n: usize) -> usize {
+    let before = echo.accepts();
+    let mut conns = Vec::with_capacity(n);
+    for _ in 0..n {
+        let conn = h.connect(&proxy.addr());
+        let (conn, rsp) = h.echo(conn, b"ping");
+        assert_eq!(rsp, b"ping".to_vec());
+        conns.push(conn);
+    }
+    drop(conns);
+    h.sleep(Duration::from_millis(100));
+    echo.accepts() - before
+}
+
+#[test]
+fn ramps_up_new_endpoints_during_slow_start() {
+    let mut h = Harness::new();
+    let a = h.echo_server();
+    let b = h.echo_server();
+    let c = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    let config = format!(
+        "{}    client:\n      kind: io.l5d.global\n      slowStart:\n        windowSecs: 8\n",
+        CONFIG
+    );
+    let proxy = h.proxy(&config);
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+
+    // Once warm, the new endpoint carries most of the load.
+    let bound = &[(a.addr(), 1.0), (b.addr(), 1.0), (c.addr(), 8.0)];
+    h.namerd().bind("/svc/echo", bound);
+    let added = Instant::now();
+    h.sleep(Duration::from_millis(1100));
+
+    let cold = held_accepts(&mut h, &proxy, &c, 100);
+    h.sleep(Duration::from_secs(3));
+    let warming = held_accepts(&mut h, &proxy, &c, 100);
+    h.sleep(Duration::from_secs(9) - added.elapsed());
+    let warm = held_accepts(&mut h, &proxy, &c, 100);
+    assert!(
+        cold < warming && warming < warm && cold < 50 && warm > 60,
+        "cold={} warming={} warm={}",
+        cold,
+        warming,
+        warm
+    );
+
+    // An endpoint that returns soon after it was removed is not warmed up again.
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    h.namerd().bind("/svc/echo", bound);
+    h.sleep(Duration::from_millis(1500));
+    let returned = held_accepts(&mut h, &proxy, &c, 100);
+    assert!(returned > 60, "returned={}", returned);
+}
+
+/// Connects to `addr` and writes to it continuously from another thread, without ever
+/// reading what is echoed back.
+fn slow_reader(addr: &SocketAddr) -> net::TcpStream {
+    let conn = net::TcpStream::connect(addr).expect("failed to connect");
+    let mut writer = conn.try_clone().expect("failed to clone connection");
+    thread::spawn(move || {
+        let chunk = vec![0u8; 64 * 1024];
+        while writer.write_all(&chunk).is_ok() {}
+    });
+    conn
+}
+
+#[test]
+fn bounds_data_buffered_for_slow_readers() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = format!(
+        "bufferSizeBytes: 16384\nmaxBufferedBytes: 65536\n{}",
+        CONFIG.trim_left()
+    );
+    let proxy = h.proxy(&config);
+
+    // Usage plateaus at the budget, however many slow readers there are.
+    let mut conns = Vec::new();
+    for &n in &[8, 32] {
+        while conns.len() < n {
+            conns.push(slow_reader(&proxy.addr()));
+        }
+        let mut exhausted = 0;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while exhausted == 0 && Instant::now() < deadline {
+            h.sleep(Duration::from_millis(200));
+            exhausted += proxy.metric("buffer_budget_exhausted");
+            let used = proxy.metric("buffered_bytes");
+            assert!(used <= 65536, "{} readers buffered {} bytes", n, used);
+        }
+        assert!(exhausted > 0, "{} readers never exhausted the budget", n);
+    }
+
+    // Budget held by connections is returned when they are torn down.
+    for c in &conns {
+        let _ = c.shutdown(Shutdown::Both);
+    }
+    drop(conns);
+    h.sleep(Duration::from_secs(2));
+    assert_eq!(proxy.metric("buffered_bytes"), 0);
+}
+
+#[test]
+fn balances_udp_sessions() {
+    let mut h = Harness::new();
+    let a = h.udp_echo_server();
+    let b = h.udp_echo_server();
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    let proxy = h.proxy(UDP_CONFIG);
+
+    // Each client socket is a session, balanced across the endpoints.
+    for i in 0..10 {
+        let msg = format!("ping {}", i);
+        let rsp = h.udp_roundtrip(&proxy.addr(), msg.as_bytes());
+        assert_eq!(rsp, msg.into_bytes());
+    }
+    assert_eq!(proxy.metric("session_opens"), 10);
+    assert!(a.datagrams() > 0 && b.datagrams() > 0);
+
+    // A client's datagrams are all sent on its session.
+    let mut sock = h.udp_client();
+    for _ in 0..3 {
+        let (s, rsp) = h.udp_echo(sock, &proxy.addr(), b"hello");
+        assert_eq!(rsp, b"hello".to_vec());
+        sock = s;
+    }
+    assert_eq!(proxy.metric("session_opens"), 11);
+    assert_eq!(proxy.metric("active_sessions"), 11);
+    assert_eq!(proxy.metric("tx_datagrams"), 13);
+}
+
+/// Omits the server's and the client's connect timeouts.
+#[cfg(target_os = "linux")]
+static UNTIMED_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+";
+
+/// Binds a listener that never completes connections.
+///
+/// Its accept queue is filled by the returned stream, so that the kernel drops further
+/// connection attempts rather than refusing them.
+#[cfg(target_os = "linux")]
+fn blackhole() -> (net::TcpListener, net::TcpStream) {
+    use std::os::unix::io::AsRawFd;
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+    let queued = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    (listener, queued)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn times_out_connections_to_blackholed_endpoints_by_default() {
+    let mut h = Harness::new();
+    let (listener, _queued) = blackhole();
+    h.namerd().bind("/svc/echo", &[(listener.local_addr().unwrap(), 1.0)]);
+    let proxy = h.proxy(UNTIMED_CONFIG);
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_secs(9));
+    assert_eq!(proxy.labeled_metric("connection_failure", "cause=\"timeout\""), 0);
+    h.sleep(Duration::from_secs(2));
+    assert!(proxy.labeled_metric("connection_failure", "cause=\"timeout\"") > 0);
+}
+
+#[cfg(target_os = "linux")]
+static MARKING_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+      - port: 0
+        dstName: /svc/bulk
+    client:
+      kind: io.l5d.static
+      configs:
+        - prefix: /svc
+          soMark: 7
+        - prefix: /svc/echo
+          dscp: 46
+        - prefix: /svc/bulk
+          dscp: 8
+";
+
+/// Finds this process's socket connected to `peer`, returning its `SO_MARK` and
+/// `IP_TOS` options.
+#[cfg(target_os = "linux")]
+fn socket_marks(peer: &SocketAddr) -> Option<(u32, u8)> {
+    use std::fs;
+    use std::mem;
+
+    fn getsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "getsockopt failed");
+        value
+    }
+
+    for entry in fs::read_dir("/proc/self/fd").unwrap() {
+        let name = entry.unwrap().file_name();
+        let fd: libc::c_int = match name.to_str().and_then(|s| s.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getpeername(
+                fd,
+                &mut addr as *mut libc::sockaddr_in as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if ret != 0 || addr.sin_family != libc::AF_INET as libc::sa_family_t ||
+            u16::from_be(addr.sin_port) != peer.port()
+        {
+            continue;
+        }
+        let mark = getsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK);
+        let tos = getsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS);
+        return Some((mark as u32, tos as u8));
+    }
+    None
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn marks_upstream_sockets_by_prefix() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let bulk = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    h.namerd().bind("/svc/bulk", &[(bulk.addr(), 1.0)]);
+    let proxy = h.proxy(MARKING_CONFIG);
+
+    let conn = h.connect(&proxy.addrs()[0]);
+    let (_echo_conn, rsp) = h.echo(conn, b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+    let conn = h.connect(&proxy.addrs()[1]);
+    let (_bulk_conn, rsp) = h.echo(conn, b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+
+    let (echo_mark, echo_tos) = socket_marks(&echo.addr()).expect("no upstream socket");
+    let (bulk_mark, bulk_tos) = socket_marks(&bulk.addr()).expect("no upstream socket");
+    assert_eq!(echo_tos >> 2, 46);
+    assert_eq!(bulk_tos >> 2, 8);
+    // Without CAP_NET_ADMIN, sockets are not marked rather than failing to connect.
+    assert!(echo_mark == 7 || echo_mark == 0, "unexpected mark {}", echo_mark);
+    assert_eq!(bulk_mark, echo_mark);
+}
+
+#[test]
+fn drops_oversized_datagrams() {
+    let mut h = Harness::new();
+    let echo = h.udp_echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(UDP_CONFIG);
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(&[0u8; 1025], &proxy.addr()).unwrap();
+    h.sleep(Duration::from_millis(500));
+    assert_eq!(echo.datagrams(), 0);
+    assert_eq!(
+        proxy.labeled_metric("dropped_datagrams", "cause=\"oversize\""),
+        1
+    );
+    assert_eq!(proxy.metric("session_opens"), 0);
+
+    let rsp = h.udp_roundtrip(&proxy.addr(), &[0u8; 1024]);
+    assert_eq!(rsp.len(), 1024);
+}
+
+fn probe_config(from: &str) -> String {
+    CONFIG.replace(
+        "connectTimeoutMs: 5000",
+        &format!(
+            "connectTimeoutMs: 5000\n        probeFilter:\n          fromCidrs: [{}]\n          \
+             idleMs: 100",
+            from
+        ),
+    )
+}
+
+#[test]
+fn closes_idle_probes_without_dispatching() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&probe_config("127.0.0.0/8"));
+
+    // One probe closes immediately and another waits to be closed by the proxy.
+    drop(net::TcpStream::connect(&proxy.addr()).unwrap());
+    let mut idle = net::TcpStream::connect(&proxy.addr()).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    h.sleep(Duration::from_millis(500));
+    let mut buf = [0u8; 1];
+    assert_eq!(idle.read(&mut buf).unwrap(), 0);
+    assert_eq!(proxy.metric("probe_connections"), 2);
+    assert_eq!(echo.accepts(), 0);
+
+    // Clients in the probed range that send data are proxied normally.
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    assert_eq!(proxy.metric("probe_connections"), 2);
+    assert_eq!(echo.accepts(), 1);
+}
+
+#[test]
+fn dispatches_idle_connections_from_other_sources() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&probe_config("169.254.0.0/16"));
+
+    let _idle = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+    assert_eq!(echo.accepts(), 1);
+    assert_eq!(proxy.metric("probe_connections"), 0);
+}
+
+/// Opens a connection to `addr` and waits until a message has been echoed on it.
+fn persistent(h: &mut Harness, addr: &SocketAddr) -> net::TcpStream {
+    let mut conn = net::TcpStream::connect(addr).expect("failed to connect");
+    conn.write_all(b"ping").unwrap();
+    conn.set_nonblocking(true).unwrap();
+    let mut rsp = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while rsp.len() < 4 && Instant::now() < deadline {
+        h.sleep(Duration::from_millis(50));
+        let mut buf = [0u8; 4];
+        if let Ok(sz) = conn.read(&mut buf) {
+            rsp.extend_from_slice(&buf[..sz]);
+        }
+    }
+    assert_eq!(rsp, b"ping".to_vec());
+    conn
+}
+
+/// Determines whether the proxy has closed a nonblocking connection.
+fn is_closed(conn: &mut net::TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    match conn.read(&mut buf) {
+        Ok(0) => true,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => false,
+        Err(_) => true,
+        Ok(_) => false,
+    }
+}
+
+#[test]
+fn rebalances_long_lived_connections_after_reweighting() {
+    let mut h = Harness::new();
+    let a = h.echo_server();
+    let b = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0)]);
+    let config = format!(
+        "{}    client:\n      kind: io.l5d.global\n      rebalance:\n        \
+         checkIntervalSecs: 1\n        maxSkewRatio: 1.5\n        maxCloseRatio: 0.2\n",
+        CONFIG
+    );
+    let proxy = h.proxy(&config);
+
+    let mut conns = Vec::new();
+    for _ in 0..10 {
+        conns.push(persistent(&mut h, &proxy.addr()));
+    }
+    assert_eq!(a.accepts(), 10);
+
+    // Once the endpoints are weighted equally, clients whose connections are closed
+    // reconnect to the new endpoint, until neither endpoint is skewed.
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    let mut closures = 0;
+    let deadline = Instant::now() + Duration::from_secs(6);
+    while Instant::now() < deadline {
+        h.sleep(Duration::from_millis(200));
+        let closed = proxy.metric("rebalance_closures");
+        assert!(closed - closures <= 2, "closed {} connections at once", closed - closures);
+        closures = closed;
+        for conn in &mut conns {
+            if is_closed(conn) {
+                *conn = persistent(&mut h, &proxy.addr());
+            }
+        }
+    }
+    assert!(closures >= 3 && closures <= 5, "closures={}", closures);
+    assert_eq!(b.accepts() as u64, closures);
+    assert_eq!(a.accepts(), 10);
+    assert_eq!(
+        proxy.labeled_metric("close_reasons", "reason=\"rebalanced\""),
+        closures
+    );
+}
+
+#[test]
+fn fails_connects_while_circuits_are_open() {
+    let mut h = Harness::new();
+    let config: ConnectorConfig = serde_json::from_str(
+        r#"{"circuitBreaker": {"minRequests": 1, "failureRateThreshold": 0.5, "openSecs": 60}}"#,
+    ).unwrap();
+    let addrs = vec![WeightedAddr::new(h.unused_addr(), 1.0)];
+    let balancer = h.balancer("/svc/refused", addrs, &config).expect(
+        "failed to build balancer",
+    );
+
+    // The first connection is refused, which opens the circuit.
+    let _pending = balancer.connect();
+    h.sleep(Duration::from_millis(500));
+
+    match h.run(balancer.connect()) {
+        Err(Error::Connect { kind: ConnectErrorKind::CircuitOpen, .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to an unused address"),
+    }
+}
+
+#[test]
+fn classifies_refused_connections() {
+    let h = Harness::new();
+    let err = net::TcpStream::connect(h.unused_addr())
+        .map_err(Error::from)
+        .err()
+        .expect("connected to an unused address");
+    match err {
+        Error::Connect { kind: ConnectErrorKind::Refused, .. } => {}
+        ref e => panic!("unexpected error: {}", e),
+    }
+
+    // The original error is recovered where tokio requires an io::Error.
+    let err: ::std::io::Error = err.into();
+    assert_eq!(err.kind(), ::std::io::ErrorKind::ConnectionRefused);
+}
+
+#[cfg(target_os = "linux")]
+fn dispatch_queue_config(shed: &str) -> String {
+    CONFIG.replace(
+        "connectTimeoutMs: 5000",
+        &format!(
+            "connectTimeoutMs: 5000\n        dispatchQueue:\n          maxDepth: 2\n          {}",
+            shed
+        ),
+    )
+}
+
+/// Opens a connection to a proxy whose endpoint is blackholed for each of `waits_ms`,
+/// waiting that long after each, and returns whether each has been shed.
+#[cfg(target_os = "linux")]
+fn shed_connections(h: &mut Harness, proxy: &Proxy, waits_ms: &[u64]) -> Vec<bool> {
+    let mut conns = Vec::with_capacity(waits_ms.len());
+    for wait in waits_ms {
+        let conn = net::TcpStream::connect(proxy.addr()).expect("failed to connect");
+        conn.set_nonblocking(true).unwrap();
+        conns.push(conn);
+        h.sleep(Duration::from_millis(*wait));
+    }
+    conns.iter_mut().map(is_closed).collect()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sheds_newest_connections_from_full_dispatch_queues() {
+    let mut h = Harness::new();
+    let (listener, _queued) = blackhole();
+    h.namerd().bind("/svc/echo", &[(listener.local_addr().unwrap(), 1.0)]);
+    let proxy = h.proxy(&dispatch_queue_config("shedPolicy: newest"));
+
+    let shed = shed_connections(&mut h, &proxy, &[300, 300, 300]);
+    assert_eq!(shed, vec![false, false, true]);
+    assert_eq!(proxy.labeled_metric("dispatch_sheds", "cause=\"newest\""), 1);
+    assert_eq!(proxy.metric("dispatch_sheds"), 1);
+    assert_eq!(proxy.metric("dispatch_queue_depth"), 2);
+    assert_eq!(proxy.labeled_metric("close_reasons", "reason=\"shed\""), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sheds_oldest_connections_from_full_dispatch_queues() {
+    let mut h = Harness::new();
+    let (listener, _queued) = blackhole();
+    h.namerd().bind("/svc/echo", &[(listener.local_addr().unwrap(), 1.0)]);
+    let proxy = h.proxy(&dispatch_queue_config("shedPolicy: oldest"));
+
+    let shed = shed_connections(&mut h, &proxy, &[300, 300, 300]);
+    assert_eq!(shed, vec![true, false, false]);
+    assert_eq!(proxy.labeled_metric("dispatch_sheds", "cause=\"oldest\""), 1);
+    assert_eq!(proxy.metric("dispatch_sheds"), 1);
+    assert_eq!(proxy.metric("dispatch_queue_depth"), 2);
+    assert!(proxy.metric("dispatch_queue_max_age_ms") >= 200);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sheds_expired_connections_from_full_dispatch_queues() {
+    let mut h = Harness::new();
+    let (listener, _queued) = blackhole();
+    h.namerd().bind("/svc/echo", &[(listener.local_addr().unwrap(), 1.0)]);
+    let proxy = h.proxy(&dispatch_queue_config(
+        "shedPolicy: byAge\n          maxQueueDelayMs: 500",
+    ));
+
+    // The third connection arrives before the first has waited 500ms, so it is shed.
+    // By the time the fourth arrives, the first has waited too long.
+    let shed = shed_connections(&mut h, &proxy, &[100, 100, 1000, 300]);
+    assert_eq!(shed, vec![true, false, true, false]);
+    assert_eq!(proxy.labeled_metric("dispatch_sheds", "cause=\"newest\""), 1);
+    assert_eq!(proxy.labeled_metric("dispatch_sheds", "cause=\"expired\""), 1);
+    assert_eq!(proxy.metric("dispatch_queue_depth"), 2);
+}
+
+#[test]
+fn encodes_namespaces_and_names_in_namerd_requests() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let name = "/svc/a b#c%d/é";
+    h.namerd().bind(name, &[(echo.addr(), 1.0)]);
+    let config = CONFIG.replace("namespace: default", "namespace: \"a/b%c#d é\"").replace(
+        "dstName: /svc/echo",
+        &format!("dstName: \"{}\"", name),
+    );
+    let proxy = h.proxy(&config);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    assert!(h.namerd().requests_for(name) > 0);
+    assert_eq!(h.namerd().requests_in("a/b%c#d é"), h.namerd().requests());
+}
+
+#[cfg(target_os = "linux")]
+fn source_port_reuse_config(reuse: &str) -> String {
+    CONFIG.replace(
+        "connectTimeoutMs: 5000",
+        &format!("connectTimeoutMs: 5000\n        sourcePortReuse:\n          {}", reuse),
+    )
+}
+
+/// Connects to `addr` from `port` (or from an ephemeral port, if 0), and resets the
+/// connection once the proxy has dispatched it. Returns the connection's local port.
+///
+/// A reset connection leaves no TIME_WAIT state, so its port may be bound again at once.
+#[cfg(target_os = "linux")]
+fn reset_connection_from(h: &mut Harness, addr: &SocketAddr, port: u16) -> u16 {
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    fn sockaddr(ip: Ipv4Addr, port: u16) -> libc::sockaddr_in {
+        let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_port = port.to_be();
+        sin.sin_addr.s_addr = u32::from(ip).to_be();
+        sin
+    }
+
+    fn setsockopt<T>(fd: libc::c_int, name: libc::c_int, value: &T) {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0, "setsockopt failed: {}", ::std::io::Error::last_os_error());
+    }
+
+    let ip = match *addr {
+        SocketAddr::V4(ref a) => *a.ip(),
+        SocketAddr::V6(_) => panic!("expected an IPv4 address: {}", addr),
+    };
+    let len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+    assert!(fd >= 0, "failed to open socket");
+    let conn = unsafe { net::TcpStream::from_raw_fd(fd) };
+    setsockopt(fd, libc::SO_REUSEADDR, &(1 as libc::c_int));
+    if port != 0 {
+        let local = sockaddr(Ipv4Addr::new(127, 0, 0, 1), port);
+        let ret = unsafe {
+            libc::bind(fd, &local as *const libc::sockaddr_in as *const libc::sockaddr, len)
+        };
+        assert_eq!(ret, 0, "failed to bind port {}", port);
+    }
+    let remote = sockaddr(ip, addr.port());
+    let ret = unsafe {
+        libc::connect(fd, &remote as *const libc::sockaddr_in as *const libc::sockaddr, len)
+    };
+    assert_eq!(ret, 0, "failed to connect to {}", addr);
+    let local_port = conn.local_addr().unwrap().port();
+
+    h.sleep(Duration::from_millis(200));
+    setsockopt(fd, libc::SO_LINGER, &libc::linger { l_onoff: 1, l_linger: 0 });
+    drop(conn);
+    h.sleep(Duration::from_millis(200));
+    local_port
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn counts_sources_reconnecting_within_the_reuse_window() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&source_port_reuse_config("reuseWindowMs: 1000"));
+
+    let port = reset_connection_from(&mut h, &proxy.addr(), 0);
+    assert_eq!(proxy.metric("source_ports_tracked"), 1);
+
+    // Reconnects ~200ms after the previous connection closed.
+    reset_connection_from(&mut h, &proxy.addr(), port);
+    assert_eq!(proxy.metric("source_port_reuses"), 1);
+
+    // Reconnects well after the window.
+    h.sleep(Duration::from_millis(1500));
+    reset_connection_from(&mut h, &proxy.addr(), port);
+    assert_eq!(proxy.metric("source_port_reuses"), 1);
+    assert_eq!(proxy.metric("source_ports_tracked"), 1);
+    assert_eq!(proxy.metric("source_port_evictions"), 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn forgets_least_recently_closed_sources_beyond_max_tracked() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&source_port_reuse_config(
+        "reuseWindowMs: 10000\n          maxTracked: 2",
+    ));
+
+    let a = reset_connection_from(&mut h, &proxy.addr(), 0);
+    let b = reset_connection_from(&mut h, &proxy.addr(), 0);
+    let c = reset_connection_from(&mut h, &proxy.addr(), 0);
+    assert!(a != b && b != c && a != c, "ports were reused: {} {} {}", a, b, c);
+    assert_eq!(proxy.metric("source_ports_tracked"), 2);
+    assert_eq!(proxy.metric("source_port_evictions"), 1);
+
+    // `a` was forgotten, and closing it again forgets `b`.
+    reset_connection_from(&mut h, &proxy.addr(), a);
+    assert_eq!(proxy.metric("source_port_reuses"), 0);
+    assert_eq!(proxy.metric("source_port_evictions"), 2);
+
+    reset_connection_from(&mut h, &proxy.addr(), c);
+    assert_eq!(proxy.metric("source_port_reuses"), 1);
+    assert_eq!(proxy.metric("source_ports_tracked"), 2);
+}
+
+/// Builds an app that routes `/svc/echo` to `echo`, configured further by `f`.
+fn hooked_app<F>(h: &mut Harness, echo: &EchoServer, f: F) -> Proxy
+where
+    F: FnOnce(AppBuilder) -> AppBuilder,
+{
+    let mut names = HashMap::new();
+    names.insert("/svc/echo".to_owned(), vec![WeightedAddr::new(echo.addr(), 1.0)]);
+    let server = ServerConfig {
+        dst_name: Some("/svc/echo".to_owned()),
+        connect_timeout_ms: Some(Millis(Duration::from_secs(5))),
+        ..ServerConfig::default()
+    };
+    let builder = AppBuilder::new()
+        .admin_addr("127.0.0.1:0".parse().unwrap())
+        .router(RouterBuilder::new("test", Interpreter::Static(names)).server(server));
+    let app = f(builder).build().expect("failed to build app");
+    h.spawn(app)
+}
+
+/// Connects to `addr` and determines whether the connection has been closed after
+/// `wait`.
+fn closed_after(h: &mut Harness, addr: &SocketAddr, wait: Duration) -> bool {
+    let mut conn = net::TcpStream::connect(addr).expect("failed to connect");
+    conn.set_nonblocking(true).unwrap();
+    h.sleep(wait);
+    is_closed(&mut conn)
+}
+
+#[test]
+fn rejects_connections_from_hooks_before_dispatching() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let reject = RejectCidrs::new(&["127.0.0.0/8"]).expect("invalid cidr");
+    let proxy = hooked_app(&mut h, &echo, |app| app.connection_hook(reject));
+
+    assert!(closed_after(&mut h, &proxy.addr(), Duration::from_millis(300)));
+    assert_eq!(echo.accepts(), 0);
+    assert_eq!(proxy.labeled_metric("hook_rejects", "cause=\"rejected\""), 1);
+    assert_eq!(proxy.metric("accepts"), 0);
+}
+
+#[test]
+fn accepts_connections_outside_rejected_ranges() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let reject = RejectCidrs::new(&["10.0.0.0/8", "::1/128"]).expect("invalid cidr");
+    let proxy = hooked_app(&mut h, &echo, |app| app.connection_hook(reject));
+
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    assert_eq!(proxy.metric("hook_rejects"), 0);
+    assert!(RejectCidrs::new(&["10.0.0.0/33"]).is_err());
+}
+
+/// Never decides.
+struct Undecided;
+
+impl ConnectionHook for Undecided {
+    fn on_accept(&self, _client_addr: &SocketAddr) -> DecisionFuture {
+        Box::new(::futures::future::empty())
+    }
+}
+
+#[test]
+fn rejects_connections_when_hooks_time_out() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let proxy = hooked_app(&mut h, &echo, |app| {
+        app.connection_hook(Undecided).hook_timeout(Duration::from_millis(200))
+    });
+
+    assert!(!closed_after(&mut h, &proxy.addr(), Duration::from_millis(100)));
+    assert_eq!(proxy.labeled_metric("hook_rejects", "cause=\"timeout\""), 0);
+    h.sleep(Duration::from_millis(300));
+    assert_eq!(proxy.labeled_metric("hook_rejects", "cause=\"timeout\""), 1);
+    assert_eq!(echo.accepts(), 0);
+}
+
+#[test]
+fn rejects_zero_hook_timeouts() {
+    let app = AppBuilder::new()
+        .connection_hook(Undecided)
+        .hook_timeout(Duration::from_secs(0))
+        .build();
+    match app {
+        Err(Error::Config(linkerd_tcp::app::Error::InvalidHookTimeout)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted a zero hook timeout"),
+    }
+}
+
+/// Records each callback.
+#[derive(Clone, Default)]
+struct Recorder {
+    dispatched: Rc<RefCell<Vec<(SocketAddr, SocketAddr)>>>,
+    closed: Rc<RefCell<Vec<ConnectionSummary>>>,
+}
+
+impl ConnectionHook for Recorder {
+    fn on_accept(&self, _client_addr: &SocketAddr) -> DecisionFuture {
+        Box::new(::futures::future::ok(Decision::Accept))
+    }
+
+    fn on_dispatch(&self, client_addr: &SocketAddr, endpoint_addr: &SocketAddr) {
+        self.dispatched.borrow_mut().push((*client_addr, *endpoint_addr));
+    }
+
+    fn on_close(&self, summary: &ConnectionSummary) {
+        self.closed.borrow_mut().push(summary.clone());
+    }
+}
+
+#[test]
+fn reports_dispatched_and_closed_connections_to_hooks() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let recorder = Recorder::default();
+    let proxy = hooked_app(&mut h, &echo, |app| app.connection_hook(recorder.clone()));
+
+    let rsp = h.roundtrip(&proxy.addr(), b"hello");
+    assert_eq!(rsp, b"hello".to_vec());
+    h.sleep(Duration::from_millis(100));
+
+    let dispatched = recorder.dispatched.borrow();
+    assert_eq!(dispatched.len(), 1);
+    assert_eq!(dispatched[0].1, echo.addr());
+    let closed = recorder.closed.borrow();
+    assert_eq!(closed.len(), 1);
+    assert_eq!(closed[0].client_addr, dispatched[0].0);
+    assert_eq!(closed[0].server_addr, proxy.addr());
+    assert_eq!(closed[0].dst_name, "/svc/echo");
+    assert_eq!(closed[0].endpoint_addr, Some(echo.addr()));
+    assert_eq!(closed[0].resolution.map(|g| g.number), Some(1));
+    assert_eq!(closed[0].rx_bytes, 5);
+    assert_eq!(closed[0].tx_bytes, 5);
+}
+
+/// Panics as connections are dispatched while armed.
+#[derive(Clone, Default)]
+struct Panicker {
+    armed: Rc<Cell<bool>>,
+}
+
+impl ConnectionHook for Panicker {
+    fn on_dispatch(&self, _client_addr: &SocketAddr, _endpoint_addr: &SocketAddr) {
+        if self.armed.get() {
+            panic!("injected by test");
+        }
+    }
+}
+
+#[test]
+fn isolates_panics_to_their_connections() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let panicker = Panicker::default();
+    let proxy = hooked_app(&mut h, &echo, |app| app.connection_hook(panicker.clone()));
+
+    let open = h.connect(&proxy.addr());
+    let (open, rsp) = h.echo(open, b"before");
+    assert_eq!(rsp, b"before".to_vec());
+
+    panicker.armed.set(true);
+    assert!(h.try_roundtrip(&proxy.addr(), b"hello").is_err());
+    panicker.armed.set(false);
+    assert_eq!(proxy.metric("connection_panics"), 1);
+    assert_eq!(proxy.metric("listener_panics"), 0);
+
+    // The connection that was open when the other panicked is unaffected, and new
+    // connections are served.
+    let (_, rsp) = h.echo(open, b"after");
+    assert_eq!(rsp, b"after".to_vec());
+    assert_eq!(h.roundtrip(&proxy.addr(), b"hello"), b"hello".to_vec());
+}
+
+/// Produces its endpoints once, and then panics when it is next polled while armed.
+struct PanickingAddrs {
+    addrs: Option<Vec<WeightedAddr>>,
+    armed: Rc<Cell<bool>>,
+}
+
+impl Stream for PanickingAddrs {
+    type Item = Vec<WeightedAddr>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Vec<WeightedAddr>>, ()> {
+        if self.armed.get() {
+            self.armed.set(false);
+            panic!("injected by test");
+        }
+        match self.addrs.take() {
+            Some(addrs) => Ok(Async::Ready(Some(addrs))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[test]
+fn rebuilds_balancers_that_panic() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let armed = Rc::new(Cell::new(false));
+    let addrs = PanickingAddrs {
+        addrs: Some(vec![WeightedAddr::new(echo.addr(), 1.0)]),
+        armed: armed.clone(),
+    };
+    let balancer = h.balancer_over("/svc/echo", addrs, &ConnectorConfig::default())
+        .expect("failed to build balancer");
+    h.run(balancer.connect()).expect("failed to connect");
+
+    // The dispatcher panics as it polls its resolution. The connection it was asked for
+    // may be dropped with it.
+    armed.set(true);
+    let _ = h.run(balancer.connect());
+    assert!(!armed.get(), "dispatcher did not panic");
+
+    // Once it has been rebuilt, the dispatcher connects to the endpoints it had last
+    // resolved, though they are not resolved again.
+    h.sleep(Duration::from_millis(500));
+    h.run(balancer.connect()).expect("failed to connect after panicking");
+}
+
+/// A path in the temporary directory that is unique to this test.
+fn temp_path(name: &str) -> ::std::path::PathBuf {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    ::std::env::temp_dir().join(format!("linkerd-tcp-{}-{}.json", name, nanos))
+}
+
+fn resolution_cache_config(path: &::std::path::Path) -> String {
+    CONFIG.replace(
+        "periodSecs: 1\n",
+        &format!(
+            "periodSecs: 1\n      resolutionCache:\n        path: {}\n        \
+             bootstrapTimeoutSecs: 1\n",
+            path.display()
+        ),
+    )
+}
+
+#[test]
+fn serves_cached_resolutions_after_restarts_while_namerd_fails() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let path = temp_path("resolution-cache");
+    let config = resolution_cache_config(&path);
+
+    let first = h.proxy(&config);
+    assert_eq!(h.roundtrip(&first.addr(), b"hello"), b"hello".to_vec());
+    assert!(path.exists(), "resolution cache was not written");
+    assert_eq!(first.metric("cache_hits"), 0);
+
+    // A restarted proxy is served its cached resolution once namerd has failed to
+    // respond for the bootstrap timeout.
+    let unavailable = NamerdFailure::Status(hyper::StatusCode::ServiceUnavailable);
+    h.namerd().fail("/svc/echo", unavailable);
+    let second = h.proxy(&config);
+    let start = Instant::now();
+    assert_eq!(h.roundtrip(&second.addr(), b"hello"), b"hello".to_vec());
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert_eq!(second.metric("cache_hits"), 1);
+    assert_eq!(second.metric("cache_misses"), 0);
+    assert_eq!(echo.accepts(), 2);
+
+    h.sleep(Duration::from_millis(1500));
+    assert!(second.metric("cache_stale_serves") > 0);
+    let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn ignores_expired_cached_resolutions() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let path = temp_path("expired-resolution-cache");
+    let cached = format!(
+        "{{\"resolutions\":{{\"/svc/echo\":{{\"savedAtSecs\":1,\"addrs\":[{{\"addr\":\"{}\",\
+         \"weight\":1.0,\"meta\":{{}}}}]}}}}}}",
+        echo.addr()
+    );
+    ::std::fs::File::create(&path).unwrap().write_all(cached.as_bytes()).unwrap();
+    let unavailable = NamerdFailure::Status(hyper::StatusCode::ServiceUnavailable);
+    h.namerd().fail("/svc/echo", unavailable);
+    let proxy = h.proxy(&resolution_cache_config(&path));
+
+    let mut conn = net::TcpStream::connect(proxy.addr()).expect("failed to connect");
+    conn.set_nonblocking(true).unwrap();
+    h.sleep(Duration::from_millis(1500));
+    assert!(!is_closed(&mut conn));
+    assert_eq!(proxy.metric("cache_misses"), 1);
+    assert_eq!(proxy.metric("cache_hits"), 0);
+    assert_eq!(echo.accepts(), 0);
+    let _ = ::std::fs::remove_file(&path);
+}
+
+/// Returns the state reported for `addr` by the proxy's balancer for `/svc/echo`.
+fn endpoint_state(proxy: &Proxy, addr: &SocketAddr) -> serde_json::Value {
+    let state: serde_json::Value = serde_json::from_str(&proxy.state()).expect("invalid state");
+    let endpoints = state["routers"]["test"]["/svc/echo"]["endpoints"].as_array().cloned();
+    endpoints
+        .expect("balancer not reported")
+        .into_iter()
+        .find(|ep| ep["addr"] == addr.to_string())
+        .expect("endpoint not reported")
+}
+
+#[test]
+fn applies_weight_overrides_across_resolutions() {
+    let mut h = Harness::new();
+    let bad = h.echo_server();
+    let good = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(bad.addr(), 0.5), (good.addr(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+    assert_eq!(h.roundtrip(&proxy.addr(), b"warmup"), b"warmup".to_vec());
+
+    // An endpoint whose weight is scaled to 0 is never chosen over one with full weight.
+    assert!(proxy.weight_overrides().set(None, bad.addr(), 0.0));
+    let accepts = bad.accepts();
+    for _ in 0..20 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"scaled"), b"scaled".to_vec());
+    }
+    assert_eq!(bad.accepts(), accepts);
+
+    // Overrides persist across service discovery updates, and are reported alongside
+    // the resolved weight.
+    assert!(proxy.weight_overrides().set(None, bad.addr(), 0.1));
+    h.namerd().bind("/svc/echo", &[(bad.addr(), 0.8), (good.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    h.roundtrip(&proxy.addr(), b"resolved");
+    h.sleep(Duration::from_millis(1100));
+    h.roundtrip(&proxy.addr(), b"resolved");
+    let state = endpoint_state(&proxy, &bad.addr());
+    assert_eq!(state["weight"].as_f64(), Some(0.8));
+    assert_eq!(state["weightMultiplier"].as_f64(), Some(0.1));
+    assert!((state["effectiveWeight"].as_f64().unwrap() - 0.08).abs() < 1e-9);
+
+    // Once cleared, the resolved weight applies again.
+    assert!(proxy.weight_overrides().clear(None, bad.addr()));
+    assert!(!proxy.weight_overrides().clear(None, bad.addr()));
+    h.roundtrip(&proxy.addr(), b"cleared");
+    h.sleep(Duration::from_millis(1100));
+    h.roundtrip(&proxy.addr(), b"cleared");
+    let state = endpoint_state(&proxy, &bad.addr());
+    assert!(state.get("weightMultiplier").is_none());
+    assert_eq!(state["effectiveWeight"].as_f64(), Some(0.8));
+}
+
+#[test]
+fn reports_endpoint_connect_stats_over_a_window() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let dead = h.unused_addr();
+    h.namerd().bind("/svc/echo", &[(dead, 1.0), (echo.addr(), 1.0)]);
+    let config = CONFIG.replace(
+        "connectTimeoutMs: 5000\n",
+        "connectTimeoutMs: 500\n    client:\n      kind: io.l5d.global\n      statsWindowSecs: 2\n",
+    );
+    let proxy = h.proxy(&config);
+
+    assert!(proxy.ejections().eject(None, echo.addr()));
+    assert!(h.try_roundtrip(&proxy.addr(), b"dead").is_err());
+    assert!(proxy.ejections().reinstate(None, echo.addr()));
+    assert!(proxy.ejections().eject(None, dead));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"live"), b"live".to_vec());
+    // State is reported at most once a second.
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"live"), b"live".to_vec());
+
+    let live = endpoint_state(&proxy, &echo.addr());
+    assert!(live["connectAttempts"].as_u64().unwrap() >= 1);
+    assert_eq!(live["connectSuccessRate"].as_f64(), Some(1.0));
+    assert_eq!(live["failureAccrual"], "healthy");
+    assert!(live.get("lastFailure").is_none());
+
+    let failing = endpoint_state(&proxy, &dead);
+    assert!(failing["connectAttempts"].as_u64().unwrap() >= 1);
+    assert_eq!(failing["connectSuccessRate"].as_f64(), Some(0.0));
+    assert_ne!(failing["failureAccrual"], "healthy");
+    assert!(!failing["lastFailure"]["error"].as_str().unwrap().is_empty());
+    assert!(failing["lastFailure"]["atMs"].as_u64().unwrap() > 0);
+}
+
+/// Configures the global client to use `size` endpoints chosen with a fixed seed.
+fn subset_config(size: usize) -> String {
+    let config = CONFIG.replace("connectTimeoutMs: 5000\n", "connectTimeoutMs: 500\n");
+    format!(
+        "{}    client:\n      kind: io.l5d.global\n      subsetting: {{size: {}, seed: 7}}\n",
+        config,
+        size
+    )
+}
+
+/// The addresses of the endpoints that the proxy's balancer uses.
+fn subset_addrs(proxy: &Proxy) -> HashSet<String> {
+    let state: serde_json::Value = serde_json::from_str(&proxy.state()).expect("invalid state");
+    let endpoints = state["routers"]["test"]["/svc/echo"]["endpoints"].as_array().cloned();
+    endpoints
+        .expect("balancer not reported")
+        .into_iter()
+        .filter(|ep| ep["status"] != "retired")
+        .map(|ep| ep["addr"].as_str().expect("endpoint without addr").to_owned())
+        .collect()
+}
+
+#[test]
+fn subsets_endpoints_stably_across_identical_updates() {
+    let mut h = Harness::new();
+    let mut addrs = unused_addrs(40);
+    h.namerd().bind("/svc/echo", &addrs);
+    let proxy = h.proxy(&subset_config(5));
+
+    // The balancer is created by the first connection, which no endpoint can serve.
+    assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    h.sleep(Duration::from_millis(1500));
+    let subset = subset_addrs(&proxy);
+    assert_eq!(subset.len(), 5);
+    assert_eq!(proxy.metric("endpoint_subset_size"), 5);
+
+    // Resolutions with the same members, in any order, choose the same subset.
+    for _ in 0..3 {
+        addrs.reverse();
+        h.namerd().bind("/svc/echo", &addrs);
+        h.sleep(Duration::from_millis(1500));
+        assert_eq!(subset_addrs(&proxy), subset);
+    }
+    assert_eq!(proxy.metric("endpoint_subset_additions"), 0);
+    assert_eq!(proxy.metric("endpoint_subset_removals"), 0);
+
+    // A removed member is replaced by the next-ranked address, and the others remain.
+    let removed = subset.iter().next().unwrap().clone();
+    addrs.retain(|&(addr, _)| addr.to_string() != removed);
+    h.namerd().bind("/svc/echo", &addrs);
+    h.sleep(Duration::from_millis(1500));
+    let replaced = subset_addrs(&proxy);
+    assert_eq!(replaced.len(), 5);
+    assert!(!replaced.contains(&removed));
+    assert_eq!(replaced.intersection(&subset).count(), 4);
+    assert_eq!(proxy.metric("endpoint_subset_size"), 5);
+    assert_eq!(proxy.metric("endpoint_subset_additions"), 1);
+    assert_eq!(proxy.metric("endpoint_subset_removals"), 1);
+}
+
+#[test]
+fn uses_all_endpoints_when_fewer_than_the_subset_size() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let mut addrs = unused_addrs(2);
+    addrs.push((echo.addr(), 1.0));
+    h.namerd().bind("/svc/echo", &addrs);
+    let proxy = h.proxy(&subset_config(50));
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(proxy.metric("endpoint_subset_size"), 3);
+    assert_eq!(subset_addrs(&proxy).len(), 3);
+    assert!(subset_addrs(&proxy).contains(&echo.addr().to_string()));
+}
+
+fn dns_addr(n: u8, port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)), port)
+}
+
+/// Drives `name` until its addresses next change.
+fn next_addrs(h: &mut Harness, name: Name) -> (Vec<SocketAddr>, Name) {
+    match h.run(name.into_future()) {
+        Ok((Some(addrs), name)) => (addrs, name),
+        _ => panic!("name stream ended"),
+    }
+}
+
+#[test]
+fn resolves_multi_address_names_without_duplicates() {
+    let mut h = Harness::new();
+    let dns = Dns::new(|host: &str, port: u16| -> io::Result<Resolution> {
+        assert_eq!(host, "svc.test");
+        Ok(Resolution {
+            addrs: vec![dns_addr(1, port), dns_addr(2, port), dns_addr(1, port)],
+            ttl: None,
+        })
+    });
+    let name = dns.name(&HostPort::parse("svc.test:8080").unwrap(), h.timer());
+    let (addrs, name) = next_addrs(&mut h, name);
+    assert_eq!(addrs, vec![dns_addr(1, 8080), dns_addr(2, 8080)]);
+    assert_eq!(name.addrs(), Some(&addrs[..]));
+
+    // IP addresses are not looked up.
+    let dns = Dns::new(|_: &str, _: u16| -> io::Result<Resolution> { panic!("looked up") });
+    let name = dns.name(&HostPort::parse("[::1]:80").unwrap(), h.timer());
+    let (addrs, _) = next_addrs(&mut h, name);
+    assert_eq!(addrs, vec!["[::1]:80".parse::<SocketAddr>().unwrap()]);
+
+    for invalid in &["svc.test", "svc.test:http", "::1:80", "[svc.test]:80", ":80"] {
+        assert_eq!(HostPort::parse(invalid), None, "parsed {}", invalid);
+    }
+}
+
+#[test]
+fn keeps_last_good_addresses_when_dns_lookups_fail() {
+    let mut h = Harness::new();
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let dns = {
+        let lookups = lookups.clone();
+        Dns::new(move |_: &str, port: u16| -> io::Result<Resolution> {
+            match lookups.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(Resolution {
+                    addrs: vec![dns_addr(1, port)],
+                    ttl: None,
+                }),
+                1 => Err(io::Error::new(io::ErrorKind::Other, "SERVFAIL")),
+                _ => Ok(Resolution {
+                    addrs: vec![dns_addr(2, port)],
+                    ttl: None,
+                }),
+            }
+        }).with_default_ttl(Duration::from_millis(200))
+    };
+    let name = dns.name(&HostPort::parse("svc.test:80").unwrap(), h.timer());
+    let (addrs, mut name) = next_addrs(&mut h, name);
+    assert_eq!(addrs, vec![dns_addr(1, 80)]);
+
+    // Once the addresses expire, the failed lookup leaves them in place.
+    h.run(::futures::future::poll_fn(|| -> Result<Async<()>, ()> {
+        if let Async::Ready(addrs) = name.poll()? {
+            panic!("addresses changed after a failure: {:?}", addrs);
+        }
+        if name.failures() > 0 {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    })).unwrap();
+    assert_eq!(name.addrs(), Some(&[dns_addr(1, 80)][..]));
+
+    // The lookup is retried, and its addresses replace the previous ones.
+    let (addrs, name) = next_addrs(&mut h, name);
+    assert_eq!(addrs, vec![dns_addr(2, 80)]);
+    assert_eq!(name.failures(), 0);
+    assert_eq!(lookups.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn re_resolves_names_as_their_ttls_expire() {
+    let mut h = Harness::new();
+
+    // TTLs reported by the lookup are honored, and otherwise the default TTL is used.
+    for &(ttl, default_ttl) in &[(Some(300), 60_000), (None, 300)] {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let dns = {
+            let lookups = lookups.clone();
+            Dns::new(move |_: &str, port: u16| -> io::Result<Resolution> {
+                let n = lookups.fetch_add(1, Ordering::SeqCst);
+                Ok(Resolution {
+                    addrs: vec![dns_addr(n as u8 + 1, port)],
+                    ttl: ttl.map(Duration::from_millis),
+                })
+            }).with_default_ttl(Duration::from_millis(default_ttl))
+        };
+        let name = dns.name(&HostPort::parse("svc.test:80").unwrap(), h.timer());
+        let (addrs, name) = next_addrs(&mut h, name);
+        assert_eq!(addrs, vec![dns_addr(1, 80)]);
+        let resolved_at = Instant::now();
+
+        let (addrs, _) = next_addrs(&mut h, name);
+        assert_eq!(addrs, vec![dns_addr(2, 80)]);
+        let elapsed = resolved_at.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "re-resolved after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "re-resolved after {:?}", elapsed);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+}
+
+#[test]
+fn describes_the_process_and_its_config() {
+    let mut h = Harness::new();
+    let config = CONFIG.replace("{namerd}", &h.namerd().base_url());
+    let hash = config.parse::<AppConfig>().unwrap().hash().unwrap();
+    let proxy = h.proxy(CONFIG);
+
+    let info: serde_json::Value = serde_json::from_str(&proxy.info()).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["proxies"], 1);
+    assert_eq!(info["configHash"], hash.as_str());
+    assert!(info["uptimeSecs"].as_u64().is_some());
+    assert!(info["startTimeSecs"].as_u64().unwrap() > 0);
+
+    let version = format!("version=\"{}\"", env!("CARGO_PKG_VERSION"));
+    assert_eq!(proxy.labeled_metric("build_info", &version), 1);
+
+    // Apps built in code have no configuration to identify.
+    let app = AppBuilder::new()
+        .admin_addr("127.0.0.1:0".parse().unwrap())
+        .build()
+        .expect("failed to build app");
+    let info: serde_json::Value = serde_json::from_str(&h.spawn(app).info()).unwrap();
+    assert_eq!(info["proxies"], 0);
+    assert!(info["configHash"].is_null());
+}
+
+/// Waits for the peer to acknowledge everything written on `conn`, and then resets it.
+#[cfg(target_os = "linux")]
+fn reset_once_acked(conn: net::TcpStream) {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let fd = conn.as_raw_fd();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let mut unacked: libc::c_int = 0;
+        assert_eq!(unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut unacked) }, 0);
+        if unacked == 0 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "{} bytes never acknowledged", unacked);
+        thread::sleep(Duration::from_millis(10));
+    }
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(ret, 0);
+    drop(conn);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn keeps_streaming_one_direction_after_the_other_is_reset() {
+    let mut h = Harness::new();
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    h.namerd().bind("/svc/echo", &[(listener.local_addr().unwrap(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    // Once the client has reset its connection, the server writes to it (which the proxy
+    // fails to relay) before reading everything the client sent.
+    let (reset_tx, reset_rx) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        reset_rx.recv().unwrap();
+        conn.write_all(b"!").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).map(|_| received)
+    });
+
+    let upload = vec![7u8; 64 * 1024];
+    let mut client = net::TcpStream::connect(proxy.addr()).unwrap();
+    client.write_all(&upload).unwrap();
+    reset_once_acked(client);
+    reset_tx.send(()).unwrap();
+    h.sleep(Duration::from_secs(1));
+
+    // The upload is delivered in full and the server's connection is closed cleanly,
+    // though the client reset its connection before the upload was relayed.
+    let received = server.join().unwrap().expect("server's connection failed");
+    assert_eq!(received.len(), upload.len());
+    assert!(received == upload);
+
+    let direction = |d: &str| {
+        proxy.labeled_metric("close_reasons_by_direction", &format!("direction=\"{}\"", d))
+    };
+    assert_eq!(direction("client_to_server"), 1);
+    assert_eq!(direction("server_to_client"), 1);
+    assert!(proxy.labeled_metric("close_reasons_by_direction", "reason=\"client_reset\"") >= 1);
+    assert_eq!(proxy.metric("close_reasons"), 1);
+}
+
+/// Spawns a server that writes `banner` on each connection it accepts and then echoes
+/// what it reads. Connections are held open, silently, when `banner` is empty.
+fn banner_server(banner: &'static [u8]) -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || for conn in listener.incoming() {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        thread::spawn(move || {
+            if conn.write_all(banner).is_err() {
+                return;
+            }
+            let mut buf = [0u8; 1024];
+            loop {
+                match conn.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(sz) => {
+                        if conn.write_all(&buf[..sz]).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    });
+    addr
+}
+
+fn readiness_probe_config(probe: &str) -> String {
+    format!(
+        "{}    client:\n      kind: io.l5d.global\n      readinessProbe:\n        {}\n        \
+         timeoutMs: 200\n",
+        CONFIG,
+        probe
+    )
+}
+
+#[test]
+fn fails_connections_to_endpoints_that_never_send_data() {
+    let mut h = Harness::new();
+    let silent = banner_server(b"");
+    h.namerd().bind("/svc/echo", &[(silent, 1.0)]);
+    let proxy = h.proxy(&readiness_probe_config("expectFirstByte: true"));
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(1000));
+    assert!(proxy.labeled_metric("connection_failure", "cause=\"timeout\"") > 0);
+    assert_eq!(proxy.metric("connection_connects"), 0);
+}
+
+#[test]
+fn replays_the_first_bytes_sent_by_ready_endpoints() {
+    let mut h = Harness::new();
+    let server = banner_server(b"+OK ready\r\n");
+    h.namerd().bind("/svc/echo", &[(server, 1.0)]);
+
+    for probe in &["expectFirstByte: true", "expectBanner: \"+OK\""] {
+        let proxy = h.proxy(&readiness_probe_config(probe));
+        let conn = h.connect(&proxy.addr());
+        let (conn, banner) = h.read_exact(conn, 11);
+        assert_eq!(banner, b"+OK ready\r\n".to_vec(), "{}", probe);
+        let (_, pong) = h.echo(conn, b"ping");
+        assert_eq!(pong, b"ping".to_vec(), "{}", probe);
+        assert_eq!(proxy.metric("connection_connects"), 1, "{}", probe);
+    }
+}
+
+#[test]
+fn fails_connections_to_endpoints_that_send_unexpected_banners() {
+    let mut h = Harness::new();
+    let server = banner_server(b"-ERR starting\r\n");
+    h.namerd().bind("/svc/echo", &[(server, 1.0)]);
+    let proxy = h.proxy(&readiness_probe_config("expectBanner: \"+OK\""));
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+    assert!(proxy.metric("connection_failure") > 0);
+    assert_eq!(proxy.metric("connection_connects"), 0);
+}
+
+/// Serves connections that begin with `0xCAFE0001`, responding with `response` and then
+/// echoing. Other connections are closed.
+fn hello_server(response: &'static [u8]) -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || for conn in listener.incoming() {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        thread::spawn(move || {
+            let mut hello = [0u8; 4];
+            if conn.read_exact(&mut hello).is_err() || hello != [0xCA, 0xFE, 0x00, 0x01] {
+                return;
+            }
+            if conn.write_all(response).is_err() {
+                return;
+            }
+            let mut buf = [0u8; 1024];
+            loop {
+                match conn.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(sz) => {
+                        if conn.write_all(&buf[..sz]).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    });
+    addr
+}
+
+fn connect_preamble_config(preamble: &str) -> String {
+    format!(
+        "{}    client:\n      kind: io.l5d.global\n      connectPreamble:\n        \
+         hexBytes: \"0xCAFE0001\"\n        {}\n        timeoutMs: 200\n",
+        CONFIG,
+        preamble
+    )
+}
+
+#[test]
+fn sends_connect_preambles_before_client_data() {
+    let mut h = Harness::new();
+    let server = hello_server(b"");
+    h.namerd().bind("/svc/echo", &[(server, 1.0)]);
+
+    // Without the preamble, the endpoint closes connections.
+    let proxy = h.proxy(CONFIG);
+    assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+
+    let proxy = h.proxy(&connect_preamble_config("awaitResponseBytes: 0"));
+    for _ in 0..3 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    assert_eq!(proxy.metric("connection_connects"), 3);
+}
+
+#[test]
+fn awaits_responses_to_connect_preambles() {
+    let mut h = Harness::new();
+    let server = hello_server(b"OK");
+    h.namerd().bind("/svc/echo", &[(server, 1.0)]);
+
+    // The response is discarded by default.
+    let proxy = h.proxy(&connect_preamble_config("awaitResponseBytes: 2"));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+
+    // Or sent to the client ahead of the endpoint's other data.
+    let proxy = h.proxy(&connect_preamble_config(
+        "awaitResponseBytes: 2\n        forwardResponse: true",
+    ));
+    let conn = h.connect(&proxy.addr());
+    let (conn, response) = h.read_exact(conn, 2);
+    assert_eq!(response, b"OK".to_vec());
+    let (_, pong) = h.echo(conn, b"ping");
+    assert_eq!(pong, b"ping".to_vec());
+}
+
+#[test]
+fn fails_connections_whose_preambles_are_not_answered() {
+    let mut h = Harness::new();
+    let server = hello_server(b"");
+    h.namerd().bind("/svc/echo", &[(server, 1.0)]);
+    let proxy = h.proxy(&connect_preamble_config("awaitResponseBytes: 2"));
+
+    let _conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(1000));
+    assert!(proxy.labeled_metric("connection_failure", "cause=\"timeout\"") > 0);
+    assert_eq!(proxy.metric("connection_connects"), 0);
+}
+
+fn write_coalescing_config(coalescing: &str) -> String {
+    format!("{}        writeCoalescing: {{ {} }}\n", CONFIG, coalescing)
+}
+
+#[test]
+fn flushes_coalesced_writes_in_order_when_connections_close() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    // Held data would not otherwise be written for a minute.
+    let proxy = h.proxy(&write_coalescing_config("maxDelayMicros: 60000000"));
+
+    let mut conn = h.connect(&proxy.addr());
+    conn.set_nodelay(true).unwrap();
+    let mut sent = Vec::new();
+    for i in 0..200u32 {
+        let frame = format!("frame {:08}\n", i).into_bytes();
+        sent.extend_from_slice(&frame);
+        conn = h.run(aio::write_all(conn, frame)).unwrap().0;
+    }
+    conn.shutdown(Shutdown::Write).unwrap();
+    let timer = h.timer().clone();
+    let (_, received) = h.run(timer.timeout(
+        aio::read_to_end(conn, Vec::new()),
+        Duration::from_secs(10),
+    )).expect("held data was not flushed");
+    assert_eq!(received.len(), sent.len());
+    assert!(received == sent);
+}
+
+#[test]
+fn holds_small_writes_until_the_coalescing_delay_elapses() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&write_coalescing_config("maxDelayMicros: 300000"));
+
+    let conn = h.connect(&proxy.addr());
+    let start = Instant::now();
+    let (_, pong) = h.echo(conn, b"ping");
+    assert_eq!(pong, b"ping".to_vec());
+    // The message is held in each direction.
+    assert!(start.elapsed() >= Duration::from_millis(500));
+}
+
+#[test]
+fn writes_immediately_once_enough_data_is_held() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&write_coalescing_config("maxDelayMicros: 60000000, maxBytes: 4"));
+
+    let conn = h.connect(&proxy.addr());
+    let (_, pong) = h.echo(conn, b"pingpong");
+    assert_eq!(pong, b"pingpong".to_vec());
+}
+
+/// Spawns a server that reads from each connection and, after `delay`, echoes what it
+/// read. If `delay` is `None`, connections are closed as soon as anything is read.
+fn slow_server(delay: Option<Duration>) -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || for conn in listener.incoming() {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                let sz = match conn.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(sz) => sz,
+                };
+                match delay {
+                    None => return,
+                    Some(d) => thread::sleep(d),
+                }
+                if conn.write_all(&buf[..sz]).is_err() {
+                    return;
+                }
+            }
+        });
+    });
+    addr
+}
+
+#[test]
+fn reports_endpoint_time_to_first_byte() {
+    let mut h = Harness::new();
+    let slow = slow_server(Some(Duration::from_millis(300)));
+    h.namerd().bind("/svc/echo", &[(slow, 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+
+    // Latencies are reported by the upper bound of their histogram bucket.
+    let state = endpoint_state(&proxy, &slow);
+    assert_eq!(state["firstByteP95Ms"].as_u64(), Some(500));
+    assert_eq!(proxy.metric("connection_no_response"), 0);
+}
+
+#[test]
+fn counts_connections_closed_before_endpoints_respond() {
+    let mut h = Harness::new();
+    let silent = slow_server(None);
+    h.namerd().bind("/svc/echo", &[(silent, 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    h.sleep(Duration::from_millis(1100));
+    // Another connection publishes the balancer's state.
+    assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    h.sleep(Duration::from_millis(200));
+
+    assert!(proxy.metric("connection_no_response") >= 1);
+    let state = endpoint_state(&proxy, &silent);
+    assert!(state.get("firstByteP95Ms").is_none());
+}
+
+/// Replaces each number in `v` with 0, so that only its structure is compared.
+fn json_shape(v: &serde_json::Value) -> serde_json::Value {
+    match *v {
+        serde_json::Value::Number(_) => serde_json::Value::from(0),
+        serde_json::Value::Array(ref vs) => vs.iter().map(json_shape).collect(),
+        serde_json::Value::Object(ref fields) => {
+            let shaped = fields.iter().map(|(k, v)| (k.clone(), json_shape(v))).collect();
+            serde_json::Value::Object(shaped)
+        }
+        ref v => v.clone(),
+    }
+}
+
+static SUMMARY_SHAPE: &'static str = r#"{
+  "apiVersion": 0,
+  "windowSecs": 0,
+  "routers": {
+    "test": {
+      "openConnections": 0,
+      "connectsPerSec": 0,
+      "failureRate": 0,
+      "dispatchP95Ms": 0,
+      "namerdStalenessSecs": 0,
+      "endpoints": {"healthy": 0, "failing": 0, "failed": 0, "probation": 0},
+      "rxBytesPerSec": 0,
+      "txBytesPerSec": 0
+    }
+  }
+}"#;
+
+#[test]
+fn summarizes_golden_signals() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    for _ in 0..4 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    // Balancers publish their endpoints' health at most once a second, after which the
+    // summary is rendered within a second.
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(1100));
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&proxy.summary()).expect("invalid summary");
+    let shape: serde_json::Value = serde_json::from_str(SUMMARY_SHAPE).unwrap();
+    assert_eq!(json_shape(&summary), shape);
+
+    assert_eq!(summary["apiVersion"].as_u64(), Some(1));
+    assert_eq!(summary["windowSecs"].as_u64(), Some(60));
+    let rt = &summary["routers"]["test"];
+    assert_eq!(rt["openConnections"].as_u64(), Some(0));
+    assert!(rt["connectsPerSec"].as_f64().unwrap() > 0.0);
+    assert_eq!(rt["failureRate"].as_f64(), Some(0.0));
+    assert!(rt["dispatchP95Ms"].as_u64().unwrap() <= 1_000);
+    assert!(rt["namerdStalenessSecs"].as_u64().unwrap() <= 2);
+    assert_eq!(rt["endpoints"]["healthy"].as_u64(), Some(1));
+    assert!(rt["rxBytesPerSec"].as_f64().unwrap() > 0.0);
+    assert!(rt["txBytesPerSec"].as_f64().unwrap() > 0.0);
+
+    // Failed connections are reflected in the failure rate.
+    let dead = h.unused_addr();
+    h.namerd().bind("/svc/echo", &[(dead, 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    assert!(h.try_roundtrip(&proxy.addr(), b"ping").is_err());
+    h.sleep(Duration::from_millis(1100));
+    let summary: serde_json::Value = serde_json::from_str(&proxy.summary()).unwrap();
+    assert!(summary["routers"]["test"]["failureRate"].as_f64().unwrap() > 0.0);
+}
+
+static DISPATCH_LIMIT_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/stalled
+        connectTimeoutMs: 5000
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+    client:
+      kind: io.l5d.global
+      maxConcurrentDispatches: 2
+";
+
+#[test]
+fn limits_concurrent_dispatches_per_destination() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    // Connections to a destination without endpoints wait to be dispatched.
+    h.namerd().bind("/svc/stalled", &[]);
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(DISPATCH_LIMIT_CONFIG);
+    let (stalled, healthy) = (proxy.addrs()[0], proxy.addrs()[1]);
+
+    let waiting = vec![h.connect(&stalled), h.connect(&stalled)];
+    h.sleep(Duration::from_millis(200));
+
+    // Further connections to the stalled destination are shed immediately.
+    let t0 = Instant::now();
+    assert!(h.try_roundtrip(&stalled, b"ping").is_err());
+    assert!(t0.elapsed() < Duration::from_secs(2));
+    assert_eq!(proxy.metric("dispatch_shed"), 1);
+
+    // Other destinations are unaffected, even with more connections in flight than the
+    // stalled destination's limit.
+    let conns: Vec<_> = (0..3).map(|_| h.connect(&healthy)).collect();
+    for conn in conns {
+        let (_conn, rsp) = h.echo(conn, b"healthy");
+        assert_eq!(rsp, b"healthy".to_vec());
+    }
+    for _ in 0..5 {
+        assert_eq!(h.roundtrip(&healthy, b"ping"), b"ping".to_vec());
+    }
+    assert_eq!(proxy.metric("dispatch_shed"), 1);
+    assert_eq!(proxy.labeled_metric("dispatching", "dst=\"/svc/stalled\""), 2);
+    drop(waiting);
+}
+
+static GLOBAL_LIMIT_CONFIG: &'static str = "
+admin:
+  port: 0
+maxUpstreamConnections: 1
+routers:
+  - label: a
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+  - label: b
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+";
+
+#[test]
+fn limits_upstream_connections_across_routers() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(GLOBAL_LIMIT_CONFIG);
+    let (a, b) = (proxy.addrs()[0], proxy.addrs()[1]);
+
+    let conn = h.connect(&a);
+    let (first, rsp) = h.echo(conn, b"first");
+    assert_eq!(rsp, b"first".to_vec());
+    assert_eq!(proxy.metric("upstream_connections"), 1);
+
+    // Another router's connection waits in its queue rather than failing.
+    let second = h.connect(&b);
+    h.sleep(Duration::from_millis(300));
+    assert!(proxy.metric("global_limit_waits") > 0);
+    assert_eq!(proxy.metric("upstream_connections"), 1);
+
+    // It is connected once the first connection closes.
+    drop(first);
+    let (second, rsp) = h.echo(second, b"second");
+    assert_eq!(rsp, b"second".to_vec());
+    assert_eq!(proxy.metric("upstream_connections"), 1);
+    drop(second);
+    h.sleep(Duration::from_millis(200));
+    assert_eq!(proxy.metric("upstream_connections"), 0);
+    assert_eq!(proxy.metric("failure_count"), 0);
+}
+
+#[test]
+fn suppresses_unchanged_resolutions() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+
+    // Namerd is polled several more times with an identical response.
+    let polls = h.namerd().requests();
+    h.sleep(Duration::from_millis(2500));
+    assert!(h.namerd().requests() >= polls + 2);
+    let rsp = h.roundtrip(&proxy.addr(), b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert_eq!(proxy.metric("endpoint_updates"), 1);
+    assert!(proxy.metric("resolutions_unchanged") >= 2);
+
+    // Changes are still applied.
+    let other = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(other.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    let rsp = h.roundtrip(&proxy.addr(), b"pong");
+    assert_eq!(rsp, b"pong".to_vec());
+    assert_eq!(proxy.metric("endpoint_updates"), 2);
+}
+
+#[test]
+fn revalidates_resolutions_with_etags() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().serve_etags();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(CONFIG);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+    h.sleep(Duration::from_millis(2500));
+    assert!(h.namerd().not_modified() >= 2);
+    assert!(proxy.metric("resolutions_unchanged") >= 2);
+    assert_eq!(proxy.metric("endpoint_updates"), 1);
+
+    let rsp = h.roundtrip(&proxy.addr(), b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert_eq!(proxy.metric("failure_count"), 0);
+}
+
+fn stickiness_config(ttl_secs: u64) -> String {
+    format!(
+        "{}    client:\n      kind: io.l5d.global\n      stickiness:\n        \
+         key: sourceIp\n        ttlSecs: {}\n",
+        CONFIG,
+        ttl_secs
+    )
+}
+
+#[test]
+fn reconnects_clients_to_their_previous_endpoints() {
+    let mut h = Harness::new();
+    let (a, b) = (h.echo_server(), h.echo_server());
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    let proxy = h.proxy(&stickiness_config(300));
+
+    // All of the test's clients share an address.
+    for _ in 0..10 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    assert!(a.accepts() == 10 || b.accepts() == 10);
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"miss\""), 1);
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"hit\""), 9);
+    assert_eq!(proxy.metric("sticky_entries"), 1);
+}
+
+#[test]
+fn falls_back_when_previous_endpoints_are_unavailable() {
+    let mut h = Harness::new();
+    let (a, b) = (h.echo_server(), h.echo_server());
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    let proxy = h.proxy(&stickiness_config(300));
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    let (used, other) = if a.accepts() == 1 { (a, b) } else { (b, a) };
+
+    // Once the client's endpoint is removed, it is balanced to the remaining endpoint,
+    // which it then sticks to.
+    h.namerd().bind("/svc/echo", &[(other.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    for _ in 0..3 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    assert_eq!(used.accepts(), 1);
+    assert_eq!(other.accepts(), 3);
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"fallback\""), 1);
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"hit\""), 2);
+}
+
+#[test]
+fn forgets_previous_endpoints_after_their_ttl() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&stickiness_config(1));
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"hit\""), 1);
+
+    h.sleep(Duration::from_millis(1500));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"miss\""), 2);
+    assert_eq!(proxy.labeled_metric("sticky_lookups", "outcome=\"hit\""), 1);
+}
+
+#[test]
+fn notifies_systemd_of_readiness_and_shutdown() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+
+    let path = temp_path("notify").with_extension("sock");
+    let socket = UnixDatagram::bind(&path).expect("failed to bind notify socket");
+    socket.set_nonblocking(true).unwrap();
+    let notifier = Notifier::new(path.clone(), Some(Duration::from_millis(400))).unwrap();
+    let config: AppConfig = CONFIG.replace("{namerd}", &h.namerd().base_url()).parse().unwrap();
+    let mut app = config.into_builder().notifier(notifier).build().unwrap();
+    let admin = app.admin.bind().expect("failed to bind admin").expect("admin not bound");
+    h.spawn(app);
+
+    // The destination is resolved before any connection is accepted.
+    h.sleep(Duration::from_millis(1000));
+    assert!(h.namerd().requests() > 0);
+    let mut admin_conn = net::TcpStream::connect(&admin).unwrap();
+    admin_conn
+        .write_all(b"POST /shutdown HTTP/1.1\r\nHost: admin\r\nContent-Length: 0\r\n\r\n")
+        .unwrap();
+    h.sleep(Duration::from_millis(200));
+
+    let mut buf = [0; 64];
+    let mut states = Vec::new();
+    while let Ok(sz) = socket.recv(&mut buf) {
+        states.push(String::from_utf8_lossy(&buf[..sz]).into_owned());
+    }
+    let _ = ::std::fs::remove_file(&path);
+    let lifecycle: Vec<&str> = states
+        .iter()
+        .map(|s| s.as_str())
+        .filter(|s| *s != "WATCHDOG=1")
+        .collect();
+    assert_eq!(lifecycle, vec!["READY=1", "STOPPING=1"]);
+    assert!(states.iter().filter(|s| *s == "WATCHDOG=1").count() >= 3);
+    assert_eq!(echo.accepts(), 0);
+}
+
+fn selection_trace_config(sample_rate: &str) -> String {
+    format!(
+        "{}    client:\n      kind: io.l5d.global\n      selectionTrace:\n        \
+         sampleRate: {}\n",
+        CONFIG,
+        sample_rate
+    )
+}
+
+#[test]
+fn traces_sampled_endpoint_selections() {
+    let mut h = Harness::new();
+    let (a, b) = (h.echo_server(), h.echo_server());
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    let proxy = h.proxy(&selection_trace_config("1.0"));
+
+    for _ in 0..5 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    let traces: serde_json::Value = serde_json::from_str(&proxy.selection_trace()).unwrap();
+    let traces = traces.as_array().expect("traces must be an array");
+    assert_eq!(traces.len(), 5);
+    let addrs = vec![a.addr().to_string(), b.addr().to_string()];
+    for trace in traces {
+        assert_eq!(trace["dst"], "/svc/echo");
+        let selection = &trace["selection"];
+        assert_eq!(selection["strategy"], "leastLoaded");
+        assert_eq!(selection["omittedCandidates"], 0);
+        let candidates = selection["candidates"].as_array().unwrap();
+        assert_eq!(candidates.len(), 2);
+        for c in candidates {
+            assert!(addrs.contains(&c["addr"].as_str().unwrap().to_owned()));
+            assert_eq!(c["backingOff"], false);
+            // With two endpoints, both are compared.
+            assert!(c["score"].is_number());
+        }
+        assert!(addrs.contains(&selection["chosen"].as_str().unwrap().to_owned()));
+        assert!(selection["chosenScore"].is_number());
+    }
+
+    // Only the most recent selections are retained.
+    for _ in 0..110 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    let traces: serde_json::Value = serde_json::from_str(&proxy.selection_trace()).unwrap();
+    assert_eq!(traces.as_array().unwrap().len(), 100);
+
+    // Nothing is traced unless tracing is configured.
+    let untraced = h.proxy(CONFIG);
+    assert_eq!(h.roundtrip(&untraced.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(untraced.selection_trace(), "[]");
+}
+
+/// Traces every selection, and accepts remote policy for the load balancer and failure
+/// accrual.
+fn remote_policy_config() -> String {
+    selection_trace_config("1.0").replace(
+        "      periodSecs: 1\n",
+        "      periodSecs: 1\n      acceptRemotePolicy:\n        \
+         allowedKeys: [loadBalancer, failureAccrual.maxFailures]\n",
+    )
+}
+
+/// The state reported by the proxy's balancer for `/svc/echo`.
+fn balancer_state(proxy: &Proxy) -> serde_json::Value {
+    let state: serde_json::Value = serde_json::from_str(&proxy.state()).expect("invalid state");
+    state["routers"]["test"]["/svc/echo"].clone()
+}
+
+/// The strategy by which the most recently traced endpoint was selected.
+fn last_strategy(proxy: &Proxy) -> serde_json::Value {
+    let traces: serde_json::Value = serde_json::from_str(&proxy.selection_trace()).unwrap();
+    let last = traces.as_array().and_then(|t| t.last().cloned());
+    last.expect("no selections traced")["selection"]["strategy"].clone()
+}
+
+#[test]
+fn applies_and_reverts_remote_policy() {
+    let mut h = Harness::new();
+    let (a, b) = (h.echo_server(), h.echo_server());
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0), (b.addr(), 1.0)]);
+    h.namerd().set_meta(
+        "/svc/echo",
+        &[
+            ("loadBalancer", "io.l5d.ewma"),
+            ("failureAccrual.maxFailures", "1"),
+            ("connectTimeoutMs", "10"),
+            ("owner", "team-a"),
+        ],
+    );
+    let proxy = h.proxy(&remote_policy_config());
+    assert_eq!(h.roundtrip(&proxy.addr(), b"warmup"), b"warmup".to_vec());
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ewma"), b"ewma".to_vec());
+
+    // Only allowed keys are applied. Others are counted once, rather than on every poll.
+    assert_eq!(last_strategy(&proxy), "ewma");
+    let policy = balancer_state(&proxy)["remotePolicy"].clone();
+    assert_eq!(policy["loadBalancer"], "io.l5d.ewma");
+    assert_eq!(policy["failureAccrual.maxFailures"], "1");
+    assert_eq!(policy.as_object().map(|p| p.len()), Some(2));
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(proxy.metric("remote_policy_ignored_keys"), 2);
+    assert_eq!(proxy.metric("remote_policy_updates"), 1);
+
+    // Policy that is no longer published reverts to the configured settings, even though
+    // the addresses are unchanged.
+    h.namerd().set_meta("/svc/echo", &[("owner", "team-a")]);
+    h.sleep(Duration::from_millis(1500));
+    // The balancer is woken to apply the change, rather than waiting for a connection.
+    assert_eq!(proxy.metric("remote_policy_updates"), 2);
+    assert_eq!(h.roundtrip(&proxy.addr(), b"reverted"), b"reverted".to_vec());
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"reverted"), b"reverted".to_vec());
+    assert_eq!(last_strategy(&proxy), "leastLoaded");
+    assert!(balancer_state(&proxy).get("remotePolicy").is_none());
+    assert_eq!(proxy.metric("remote_policy_ignored_keys"), 3);
+    assert_eq!(proxy.metric("remote_policy_updates"), 2);
+
+    // Policy is only honored when it is accepted.
+    h.namerd().set_meta("/svc/echo", &[("loadBalancer", "io.l5d.ewma")]);
+    let unaccepted = h.proxy(&selection_trace_config("1.0"));
+    assert_eq!(h.roundtrip(&unaccepted.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(last_strategy(&unaccepted), "leastLoaded");
+    assert_eq!(unaccepted.metric("remote_policy_ignored_keys"), 0);
+}
+
+#[test]
+fn fails_endpoints_as_remote_policy_accrues_failures() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let dead = h.unused_addr();
+    h.namerd().bind("/svc/echo", &[(dead, 1.0), (echo.addr(), 1.0)]);
+    h.namerd().set_meta("/svc/echo", &[("failureAccrual.maxFailures", "1")]);
+    let proxy = h.proxy(&remote_policy_config());
+
+    for _ in 0..20 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    h.sleep(Duration::from_millis(1100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+
+    // A single failure fails the endpoint, rather than the configured default of 5.
+    let failed = endpoint_state(&proxy, &dead);
+    assert_eq!(failed["status"], "failed");
+    assert_eq!(failed["consecutiveFailures"].as_u64(), Some(1));
+    assert_eq!(
+        balancer_state(&proxy)["remotePolicy"]["failureAccrual.maxFailures"],
+        "1"
+    );
+}
+
+/// Two routers that route `/svc/echo`, reporting metrics under `team_a` and `team_b`.
+static METRICS_SCOPE_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: a
+    metricsScope: team_a
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+  - label: b
+    metricsScope: team_b
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+";
+
+#[test]
+fn scopes_routers_metrics() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(METRICS_SCOPE_CONFIG);
+    for addr in proxy.addrs().to_vec() {
+        assert_eq!(h.roundtrip(&addr, b"ping"), b"ping".to_vec());
+    }
+
+    let mut names: HashMap<&str, HashSet<String>> = HashMap::new();
+    let prometheus = proxy.prometheus();
+    for line in prometheus.lines().filter(|l| !l.starts_with('#')) {
+        let name = &line[..line.find(|c: char| c == '{' || c == ' ').unwrap()];
+        for &(rt, scope) in &[("a", "team_a_l5d_"), ("b", "team_b_l5d_")] {
+            if line.contains(&format!("rt=\"{}\"", rt)) {
+                assert!(name.starts_with(scope), "{} is not scoped: {}", rt, line);
+                names.entry(rt).or_insert_with(HashSet::new).insert(
+                    name[scope.len()..].to_owned(),
+                );
+            } else {
+                assert!(!name.starts_with(scope), "unexpected metric: {}", line);
+            }
+        }
+    }
+
+    // Each router's servers, balancer, and resolver report the same metrics, each in
+    // its own namespace.
+    assert_eq!(names["a"], names["b"]);
+    for suffix in &["stream_rx_bytes", "request_latency_ms"] {
+        assert!(
+            names["a"].iter().any(|n| n.contains(suffix)),
+            "missing {}: {:?}",
+            suffix,
+            names["a"]
+        );
+    }
+    assert_eq!(proxy.labeled_metric("stream_rx_bytes", "rt=\"a\""), 4);
+    assert_eq!(proxy.labeled_metric("stream_rx_bytes", "rt=\"b\""), 4);
+}
+
+fn mirror_config(mirror: &str) -> String {
+    format!("{}        mirror: {{ {} }}\n", CONFIG, mirror)
+}
+
+/// Reads each accepted connection to its end, and sends what was read.
+fn recording_server() -> (SocketAddr, mpsc::Receiver<Vec<u8>>) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || for conn in listener.incoming() {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            if conn.read_to_end(&mut received).is_ok() {
+                let _ = tx.send(received);
+            }
+        });
+    });
+    (addr, rx)
+}
+
+/// Accepts connections but never reads from them.
+fn stalled_server() -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut held = Vec::new();
+        for conn in listener.incoming() {
+            match conn {
+                Ok(conn) => held.push(conn),
+                Err(_) => return,
+            }
+        }
+    });
+    addr
+}
+
+#[test]
+fn mirrors_client_bytes_to_shadow_destinations() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let (shadow, mirrored) = recording_server();
+    h.namerd().bind("/svc/shadow", &[(shadow, 1.0)]);
+    let proxy = h.proxy(&mirror_config("path: /svc/shadow"));
+
+    let conn = h.connect(&proxy.addr());
+    let (conn, rsp) = h.echo(conn, b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+    let (conn, rsp) = h.echo(conn, b"pong");
+    assert_eq!(rsp, b"pong".to_vec());
+    drop(conn);
+
+    // The mirror is shut down once the client has finished.
+    let received = mirrored.recv_timeout(Duration::from_secs(5)).expect("not mirrored");
+    assert_eq!(received, b"pingpong".to_vec());
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("mirror_sampled"), 1);
+    assert_eq!(proxy.metric("mirror_completed"), 1);
+    assert_eq!(proxy.metric("mirror_dropped"), 0);
+
+    // The mirror is not counted as one of the server's streams.
+    assert_eq!(proxy.metric("srv_accepts"), 1);
+    assert_eq!(proxy.metric("srv_stream_rx_bytes"), 8);
+    assert_eq!(proxy.metric("srv_stream_tx_bytes"), 8);
+    assert_eq!(echo.accepts(), 1);
+}
+
+#[test]
+fn mirrors_only_sampled_streams() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let (shadow, mirrored) = recording_server();
+    h.namerd().bind("/svc/shadow", &[(shadow, 1.0)]);
+    let proxy = h.proxy(&mirror_config("path: /svc/shadow, sampleRate: 0.0"));
+
+    for _ in 0..10 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("mirror_sampled"), 0);
+    assert!(mirrored.try_recv().is_err());
+}
+
+#[test]
+fn drops_mirrors_that_fall_behind_without_slowing_streams() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    h.namerd().bind("/svc/shadow", &[(stalled_server(), 1.0)]);
+    let proxy = h.proxy(&mirror_config("path: /svc/shadow, maxBufferedBytes: 16384"));
+
+    // Far more is sent than the stalled mirror's socket buffers can hold.
+    let chunk = vec![7u8; 64 * 1024];
+    let mut conn = h.connect(&proxy.addr());
+    let start = Instant::now();
+    for _ in 0..64 {
+        let (c, rsp) = h.echo(conn, &chunk);
+        assert!(rsp == chunk);
+        conn = c;
+    }
+    assert!(start.elapsed() < Duration::from_secs(10), "streaming was slowed");
+    drop(conn);
+
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("mirror_sampled"), 1);
+    assert_eq!(proxy.labeled_metric("mirror_dropped", "cause=\"overflow\""), 1);
+    assert_eq!(proxy.metric("mirror_completed"), 0);
+    assert_eq!(proxy.metric("srv_stream_rx_bytes"), 64 * 64 * 1024);
+}
+
+#[test]
+fn drops_mirrors_that_cannot_connect() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let unused = h.unused_addr();
+    h.namerd().bind("/svc/shadow", &[(unused, 1.0)]);
+    let proxy = h.proxy(&mirror_config("path: /svc/shadow"));
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    // The mirror may wait for the shadow endpoint until the connect timeout.
+    for _ in 0..30 {
+        if proxy.metric("mirror_dropped") > 0 {
+            break;
+        }
+        h.sleep(Duration::from_millis(250));
+    }
+    assert_eq!(proxy.metric("mirror_sampled"), 1);
+    assert_eq!(proxy.labeled_metric("mirror_dropped", "cause=\"connect\""), 1);
+    assert_eq!(proxy.metric("srv_failures"), 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn samples_tcp_info_of_loopback_connections() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    client.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).unwrap();
+    server.write_all(b"world").unwrap();
+    client.read_exact(&mut buf).unwrap();
+
+    for conn in &[&client, &server] {
+        let info = linkerd_tcp::TcpInfo::sample(*conn).expect("failed to sample tcp_info");
+        // Loopback round trips are measured, and take well under a second.
+        assert!(info.rtt_us > 0, "{:?}", info);
+        assert!(info.rtt_us < 1_000_000, "{:?}", info);
+        assert_eq!(info.total_retransmits, 0, "{:?}", info);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_info_config() -> String {
+    format!("{}        tcpInfo: {{ sampleIntervalSecs: 1 }}\n", CONFIG)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn records_tcp_info_of_proxied_streams() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&tcp_info_config());
+
+    let conn = h.connect(&proxy.addr());
+    let (conn, rsp) = h.echo(conn, b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+
+    // Open streams are sampled periodically.
+    h.sleep(Duration::from_millis(1_500));
+    for peer in &["peer=\"client\"", "peer=\"endpoint\""] {
+        assert!(proxy.labeled_metric("tcp_rtt_us_count", peer) >= 1, "{}", peer);
+        assert_eq!(proxy.labeled_metric("tcp_retransmits_count", peer), 0, "{}", peer);
+    }
+
+    // Retransmissions are recorded once, as the stream closes.
+    drop(conn);
+    h.sleep(Duration::from_millis(100));
+    for peer in &["peer=\"client\"", "peer=\"endpoint\""] {
+        assert_eq!(proxy.labeled_metric("tcp_retransmits_count", peer), 1, "{}", peer);
+        assert_eq!(proxy.labeled_metric("tcp_retransmits_sum", peer), 0, "{}", peer);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn summarizes_tcp_info_of_closed_connections() {
+    use linkerd_tcp::app::TcpInfoConfig;
+
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let recorder = Recorder::default();
+    let mut names = HashMap::new();
+    names.insert("/svc/echo".to_owned(), vec![WeightedAddr::new(echo.addr(), 1.0)]);
+    let server = ServerConfig {
+        dst_name: Some("/svc/echo".to_owned()),
+        tcp_info: Some(TcpInfoConfig::default()),
+        ..ServerConfig::default()
+    };
+    let app = AppBuilder::new()
+        .admin_addr("127.0.0.1:0".parse().unwrap())
+        .router(RouterBuilder::new("test", Interpreter::Static(names)).server(server))
+        .connection_hook(recorder.clone())
+        .build()
+        .expect("failed to build app");
+    let proxy = h.spawn(app);
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"hello"), b"hello".to_vec());
+    h.sleep(Duration::from_millis(100));
+
+    // Streams are sampled as they close, even if they close before their first
+    // periodic sample.
+    let closed = recorder.closed.borrow();
+    assert_eq!(closed.len(), 1);
+    let client = closed[0].client_tcp_info.expect("client was not sampled");
+    let endpoint = closed[0].endpoint_tcp_info.expect("endpoint was not sampled");
+    assert!(client.rtt_us > 0, "{:?}", client);
+    assert!(endpoint.rtt_us > 0, "{:?}", endpoint);
+}
+
+/// Spawns a proxy whose admin server is configured with `auth`, returning the admin
+/// server's address.
+fn spawn_with_admin_auth(h: &mut Harness, auth: &str) -> (Proxy, SocketAddr) {
+    let auth = format!("  port: 0\n  auth:\n{}routers:", auth);
+    let config = CONFIG.replace("  port: 0\nrouters:", &auth);
+    let config: AppConfig = config.replace("{namerd}", &h.namerd().base_url()).parse().unwrap();
+    let mut app = config.into_builder().build().unwrap();
+    let admin = app.admin.bind().expect("failed to bind admin").expect("admin not bound");
+    (h.spawn(app), admin)
+}
+
+fn admin_request(method: &str, path: &str, token: Option<&str>) -> String {
+    let auth = token.map(|t| format!("Authorization: Bearer {}\r\n", t)).unwrap_or_default();
+    format!("{} {} HTTP/1.1\r\nHost: admin\r\nContent-Length: 0\r\n{}\r\n", method, path, auth)
+}
+
+#[test]
+fn authorizes_admin_requests_with_bearer_tokens() {
+    let mut h = Harness::new();
+    ::std::env::set_var("L5D_TEST_ADMIN_TOKEN", "s3cret");
+    let auth = "    bearerTokenEnv: L5D_TEST_ADMIN_TOKEN\n";
+    let (proxy, admin) = spawn_with_admin_auth(&mut h, auth);
+
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", None)), 401);
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", Some("nope"))), 403);
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", Some("s3cret"))), 200);
+
+    let eject = "/admin/endpoints/127.0.0.1:1/eject";
+    assert_eq!(h.http_status(&admin, &admin_request("POST", eject, None)), 401);
+    assert_eq!(h.http_status(&admin, &admin_request("POST", eject, Some("s3cret"))), 200);
+
+    assert_eq!(proxy.labeled_metric("admin_auth_failures", "cause=\"missing\""), 2);
+    assert_eq!(proxy.labeled_metric("admin_auth_failures", "cause=\"invalid\""), 1);
+}
+
+#[test]
+fn serves_unauthenticated_admin_reads_when_allowed() {
+    let mut h = Harness::new();
+    ::std::env::set_var("L5D_TEST_ADMIN_READS_TOKEN", "s3cret");
+    let auth = "    bearerTokenEnv: L5D_TEST_ADMIN_READS_TOKEN\n    \
+                allowUnauthenticatedReads: true\n";
+    let (_proxy, admin) = spawn_with_admin_auth(&mut h, auth);
+
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", None)), 200);
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/metrics", None)), 200);
+    // Writes still require a token.
+    let eject = "/admin/endpoints/127.0.0.1:1/eject";
+    assert_eq!(h.http_status(&admin, &admin_request("POST", eject, None)), 401);
+    assert_eq!(h.http_status(&admin, &admin_request("POST", "/shutdown", Some("nope"))), 403);
+}
+
+#[test]
+fn rate_limits_clients_presenting_wrong_admin_tokens() {
+    let mut h = Harness::new();
+    ::std::env::set_var("L5D_TEST_ADMIN_LIMIT_TOKEN", "s3cret");
+    let auth = "    bearerTokenEnv: L5D_TEST_ADMIN_LIMIT_TOKEN\n    maxFailuresPerMinute: 2\n";
+    let (proxy, admin) = spawn_with_admin_auth(&mut h, auth);
+
+    // Requests without credentials are not counted against the client.
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", None)), 401);
+    for _ in 0..2 {
+        assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", Some("nope"))), 403);
+    }
+    // Once limited, even the right token is refused.
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", Some("s3cret"))), 429);
+    assert_eq!(proxy.labeled_metric("admin_auth_failures", "cause=\"rate_limited\""), 1);
+}
+
+fn local_resolver_config(namerd: &str, socket: &::std::path::Path) -> String {
+    let local = format!(
+        "periodSecs: 1\n      localResolver:\n        socketPath: {}\n",
+        socket.display()
+    );
+    CONFIG.replace("periodSecs: 1\n", &local).replace("{namerd}", namerd)
+}
+
+#[test]
+fn shares_resolutions_through_the_local_resolver() {
+    let mut h = Harness::new();
+    let (a, b, c) = (h.echo_server(), h.echo_server(), h.echo_server());
+    h.namerd().bind("/svc/echo", &[(a.addr(), 1.0)]);
+    let socket = temp_path("local-resolver").with_extension("sock");
+    let config = local_resolver_config(&h.namerd().base_url(), &socket);
+
+    // The leader runs on a reactor of its own, as a sibling process would. It binds the
+    // socket as it is spawned.
+    let (elected_tx, elected_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = futures::sync::oneshot::channel::<()>();
+    let leader = {
+        let config = config.clone();
+        thread::spawn(move || {
+            let mut leader = Harness::new();
+            let _proxy = leader.proxy(&config);
+            elected_tx.send(()).unwrap();
+            let _ = leader.run(stop_rx);
+        })
+    };
+    elected_rx.recv().unwrap();
+    let follower = h.proxy(&config);
+
+    assert_eq!(h.roundtrip(&follower.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(a.accepts(), 1);
+    assert!(follower.metric("resolver_local_updates") >= 1);
+
+    // Updates polled by the leader reach the follower.
+    h.namerd().bind("/svc/echo", &[(b.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(2500));
+    assert_eq!(h.roundtrip(&follower.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(b.accepts(), 1);
+    assert_eq!(follower.metric("resolver_local_fallbacks"), 0);
+
+    // Once the leader is gone, the follower polls namerd itself.
+    stop_tx.send(()).unwrap();
+    leader.join().unwrap();
+    h.sleep(Duration::from_millis(500));
+    assert_eq!(follower.metric("resolver_local_fallbacks"), 1);
+    h.namerd().bind("/svc/echo", &[(c.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(2500));
+    assert_eq!(h.roundtrip(&follower.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(c.accepts(), 1);
+    let _ = ::std::fs::remove_file(&socket);
+}
+
+static READ_AHEAD_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+    client:
+      kind: io.l5d.global
+      minConnections: 2
+      preconnectReadAhead: true
+";
+
+const BANNER: &'static [u8] = b"220 ready\r\n";
+const BANNER_DELAY_MS: u64 = 300;
+
+/// Spawns a server that greets each connection with `BANNER` after `BANNER_DELAY_MS`,
+/// and then echoes it.
+fn banner_server() -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || for conn in listener.incoming() {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(BANNER_DELAY_MS));
+            if conn.write_all(BANNER).is_err() {
+                return;
+            }
+            let mut buf = [0u8; 1024];
+            while let Ok(sz) = conn.read(&mut buf) {
+                if sz == 0 || conn.write_all(&buf[..sz]).is_err() {
+                    return;
+                }
+            }
+        });
+    });
+    addr
+}
+
+#[test]
+fn reads_ahead_on_pooled_connections_to_servers_that_speak_first() {
+    let mut h = Harness::new();
+    let server = banner_server();
+    h.namerd().bind("/svc/echo", &[(server, 1.0)]);
+    let proxy = h.proxy(READ_AHEAD_CONFIG);
+
+    // The first connection waits for its endpoint's greeting.
+    let t0 = Instant::now();
+    let conn = h.connect(&proxy.addr());
+    let (conn, banner) = h.read_exact(conn, BANNER.len());
+    let cold = t0.elapsed();
+    assert_eq!(banner, BANNER.to_vec());
+    assert!(cold >= Duration::from_millis(BANNER_DELAY_MS), "{:?}", cold);
+    drop(conn);
+
+    // Pooled connections are established once the balancer exists, and their greetings
+    // are read ahead of demand.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while proxy.metric("read_ahead_bytes") < BANNER.len() as u64 {
+        assert!(Instant::now() < deadline, "greetings were not read ahead");
+        h.sleep(Duration::from_millis(50));
+    }
+
+    // A client dispatched a pooled connection is greeted as soon as it is dispatched,
+    // and then proxies as usual.
+    let t0 = Instant::now();
+    let conn = h.connect(&proxy.addr());
+    let (conn, banner) = h.read_exact(conn, BANNER.len());
+    let warm = t0.elapsed();
+    assert_eq!(banner, BANNER.to_vec());
+    assert!(warm < Duration::from_millis(BANNER_DELAY_MS), "{:?}", warm);
+    let (_, rsp) = h.echo(conn, b"EHLO");
+    assert_eq!(rsp, b"EHLO".to_vec());
+}
+
+/// Maintains two pooled connections, held as `{pool}` configures.
+static POOL_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: test
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 5000
+    client:
+      kind: io.l5d.global
+      minConnections: 2
+      pool: {pool}
+";
+
+/// An echo server whose connections may be closed by the test.
+struct ClosableEchoServer {
+    addr: SocketAddr,
+    accepts: Arc<AtomicUsize>,
+    conns: Arc<Mutex<Vec<net::TcpStream>>>,
+}
+
+impl ClosableEchoServer {
+    fn spawn() -> ClosableEchoServer {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        {
+            let accepts = accepts.clone();
+            let conns = conns.clone();
+            thread::spawn(move || for conn in listener.incoming() {
+                let mut conn = match conn {
+                    Ok(conn) => conn,
+                    Err(_) => return,
+                };
+                accepts.fetch_add(1, Ordering::SeqCst);
+                conns.lock().unwrap().push(conn.try_clone().unwrap());
+                thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(sz) = conn.read(&mut buf) {
+                        if sz == 0 || conn.write_all(&buf[..sz]).is_err() {
+                            return;
+                        }
+                    }
+                });
+            });
+        }
+        ClosableEchoServer {
+            addr,
+            accepts,
+            conns,
+        }
+    }
+
+    fn accepts(&self) -> usize {
+        self.accepts.load(Ordering::SeqCst)
+    }
+
+    /// Closes every connection that has been accepted.
+    fn close_all(&self) {
+        for conn in self.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Waits until `accepts` reports that `n` connections have been accepted, and then
+/// gives the proxy a moment to pool them.
+fn await_accepts<F: Fn() -> usize>(h: &mut Harness, accepts: F, n: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while accepts() < n {
+        assert!(Instant::now() < deadline, "{} of {} connections", accepts(), n);
+        h.sleep(Duration::from_millis(50));
+    }
+    h.sleep(Duration::from_millis(100));
+}
+
+#[test]
+fn reaps_idle_pooled_connections() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&POOL_CONFIG.replace("{pool}", "{idleTimeoutSecs: 500ms}"));
+
+    // The balancer, created by the first connection, then pools two more.
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    await_accepts(&mut h, || echo.accepts(), 3);
+    let label = "cause=\"idle_timeout\"";
+    assert_eq!(proxy.labeled_metric("pool_closes", label), 0);
+
+    // Idle connections are reaped by a sweep each second, and replaced.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while proxy.labeled_metric("pool_closes", label) < 2 {
+        assert!(Instant::now() < deadline, "idle connections were not reaped");
+        h.sleep(Duration::from_millis(100));
+    }
+    await_accepts(&mut h, || echo.accepts(), 5);
+    assert_eq!(h.roundtrip(&proxy.addr(), b"pong"), b"pong".to_vec());
+    assert_eq!(proxy.labeled_metric("pool_closes", "cause=\"max_lifetime\""), 0);
+}
+
+#[test]
+fn closes_pooled_connections_that_outlive_their_maximum_lifetime() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&POOL_CONFIG.replace("{pool}", "{maxLifetimeSecs: 500ms}"));
+    let label = "cause=\"max_lifetime\"";
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    await_accepts(&mut h, || echo.accepts(), 3);
+
+    // Pooled connections are dispatched while they are young.
+    assert_eq!(h.roundtrip(&proxy.addr(), b"young"), b"young".to_vec());
+    assert_eq!(proxy.labeled_metric("pool_closes", label), 0);
+    await_accepts(&mut h, || echo.accepts(), 4);
+
+    // Expired connections are closed, rather than dispatched, and are not reaped while
+    // no idle timeout is configured.
+    h.sleep(Duration::from_millis(600));
+    assert_eq!(proxy.labeled_metric("pool_closes", label), 0);
+    let accepts = echo.accepts();
+    assert_eq!(h.roundtrip(&proxy.addr(), b"old"), b"old".to_vec());
+    assert_eq!(proxy.labeled_metric("pool_closes", label), 2);
+    assert!(echo.accepts() > accepts, "dispatched an expired connection");
+    assert_eq!(proxy.labeled_metric("pool_closes", "cause=\"idle_timeout\""), 0);
+}
+
+#[test]
+fn validates_pooled_connections_before_reuse() {
+    let mut h = Harness::new();
+    let server = ClosableEchoServer::spawn();
+    h.namerd().bind("/svc/echo", &[(server.addr, 1.0)]);
+    let proxy = h.proxy(&POOL_CONFIG.replace("{pool}", "{validateBeforeReuse: true}"));
+    let (closed, ok) = ("result=\"closed\"", "result=\"ok\"");
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    await_accepts(&mut h, || server.accepts(), 3);
+
+    // Connections that the endpoint has closed are closed, rather than dispatched.
+    server.close_all();
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(h.roundtrip(&proxy.addr(), b"closed"), b"closed".to_vec());
+    assert_eq!(proxy.labeled_metric("pool_validations", closed), 2);
+    assert_eq!(proxy.labeled_metric("pool_validations", ok), 0);
+
+    // Open connections pass validation.
+    await_accepts(&mut h, || server.accepts(), 6);
+    assert_eq!(h.roundtrip(&proxy.addr(), b"open"), b"open".to_vec());
+    assert_eq!(proxy.labeled_metric("pool_validations", closed), 2);
+    assert_eq!(proxy.labeled_metric("pool_validations", ok), 1);
+}
+
+#[test]
+fn labels_endpoint_metrics_by_meta() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let labeled = WeightedAddr::new(echo.addr(), 1.0)
+        .with_meta("nodeName", "echo")
+        .with_meta("tier", "ssd");
+    h.namerd().bind_addrs("/svc/echo", &[labeled.clone()]);
+    let config = format!(
+        "{}    client:\n      kind: io.l5d.global\n      \
+         metricLabelsFromMeta: [nodeName, tier]\n",
+        CONFIG
+    );
+    let proxy = h.proxy(&config);
+    let label = "meta=\"nodeName=echo,tier=ssd\"";
+
+    let conn = h.connect(&proxy.addr());
+    let (conn, _) = h.echo(conn, b"ping");
+    assert_eq!(proxy.labeled_metric("endpoint_open_conns", label), 1);
+    assert_eq!(proxy.metric("endpoint_meta_label_overflow"), 0);
+
+    // Endpoints with more distinct labels than are reported at once are labeled `other`,
+    // while labels that are already reported are kept.
+    let mut addrs: Vec<WeightedAddr> = unused_addrs(101)
+        .into_iter()
+        .enumerate()
+        .map(|(i, a)| WeightedAddr::from(a).with_meta("nodeName", format!("node-{}", i)))
+        .collect();
+    addrs.push(labeled);
+    h.namerd().bind_addrs("/svc/echo", &addrs);
+    h.sleep(Duration::from_millis(1500));
+    assert_eq!(proxy.labeled_metric("endpoint_open_conns", label), 1);
+    assert_eq!(proxy.metric("endpoint_meta_label_overflow"), 2);
+    drop(conn);
+}
+
+#[test]
+fn filters_endpoints_lacking_required_meta() {
+    let mut h = Harness::new();
+    let ssd = h.echo_server();
+    let hdd = h.echo_server();
+    h.namerd().bind_addrs(
+        "/svc/echo",
+        &[
+            WeightedAddr::new(ssd.addr(), 1.0).with_meta("tier", "ssd"),
+            WeightedAddr::new(hdd.addr(), 1.0).with_meta("tier", "hdd"),
+            WeightedAddr::new(h.unused_addr(), 1.0),
+        ],
+    );
+    let proxy = h.proxy(&filter_config("{requireMeta: {tier: ssd}}"));
+
+    for _ in 0..5 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    assert_eq!(ssd.accepts(), 5);
+    assert_eq!(hdd.accepts(), 0);
+    let endpoints = proxy.metric("endpoint_available") + proxy.metric("endpoint_failed");
+    assert_eq!(endpoints, 1);
+    assert!(proxy.labeled_metric("endpoint_filtered", "cause=\"meta\"") >= 2);
+    assert_eq!(proxy.labeled_metric("endpoint_filtered", "cause=\"cidr\""), 0);
+}
+
+/// Builds an app from `CONFIG` with its admin server configured by `admin`.
+fn admin_config(h: &mut Harness, admin: &str) -> AppConfig {
+    let admin = format!("admin:\n{}routers:", admin);
+    let config = CONFIG.replace("admin:\n  port: 0\nrouters:", &admin);
+    config.replace("{namerd}", &h.namerd().base_url()).parse().unwrap()
+}
+
+#[test]
+fn proxies_without_an_admin_server() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = admin_config(&mut h, "  enabled: false\n  port: 0\n");
+    let mut app = config.into_app().expect("failed to build app");
+    assert_eq!(app.admin.bind().expect("failed to bind admin"), None);
+    let proxy = h.spawn(app);
+
+    // Resolvers and metrics are unaffected.
+    assert_eq!(h.roundtrip(&proxy.addr(), b"hello"), b"hello".to_vec());
+    assert!(proxy.startup().is_ready());
+    assert_eq!(proxy.metric("accepts"), 1);
+}
+
+#[test]
+fn writes_ephemeral_admin_ports_to_a_port_file() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let path = temp_path("admin-port");
+    let admin = format!("  addr: 127.0.0.1:0\n  portFile: {}\n", path.display());
+    let mut app = admin_config(&mut h, &admin).into_app().expect("failed to build app");
+    let admin = app.admin.bind().expect("failed to bind admin").expect("admin not bound");
+    h.spawn(app);
+
+    assert_ne!(admin.port(), 0);
+    let mut port = String::new();
+    ::std::fs::File::open(&path).unwrap().read_to_string(&mut port).unwrap();
+    let _ = ::std::fs::remove_file(&path);
+    assert_eq!(port, format!("{}\n", admin.port()));
+    assert_eq!(h.http_status(&admin, &admin_request("GET", "/admin/info", None)), 200);
+}
+
+#[test]
+fn serves_the_admin_server_over_a_unix_socket() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let path = temp_path("admin").with_extension("sock");
+    let admin = format!("  unixSocket: {}\n", path.display());
+    let mut app = admin_config(&mut h, &admin).into_app().expect("failed to build app");
+    assert_eq!(app.admin.bind().expect("failed to bind admin"), None);
+    h.spawn(app);
+
+    let status = h.unix_http_status(&path, &admin_request("GET", "/admin/info", None));
+    assert_eq!(status, 200);
+    let status = h.unix_http_status(&path, &admin_request("GET", "/metrics", None));
+    let _ = ::std::fs::remove_file(&path);
+    assert_eq!(status, 200);
+}
+
+#[test]
+fn normalizes_weights_unless_they_are_absolute() {
+    for &(mode, heavy, light) in &[("normalized", 0.8, 0.2), ("absolute", 200.0, 50.0)] {
+        let mut h = Harness::new();
+        let a = h.echo_server();
+        let b = h.echo_server();
+        h.namerd().bind("/svc/echo", &[(a.addr(), 200.0), (b.addr(), 50.0)]);
+        let config = format!(
+            "{}    client:\n      kind: io.l5d.global\n      weightMode: {}\n",
+            CONFIG,
+            mode
+        );
+        let proxy = h.proxy(&config);
+        assert_eq!(h.roundtrip(&proxy.addr(), b"weigh"), b"weigh".to_vec());
+
+        let weight = |addr| endpoint_state(&proxy, &addr)["weight"].as_f64().unwrap();
+        assert!((weight(a.addr()) - heavy).abs() < 1e-9, "{}: {}", mode, weight(a.addr()));
+        assert!((weight(b.addr()) - light).abs() < 1e-9, "{}: {}", mode, weight(b.addr()));
+    }
+}
+
+fn transparent_retry_config(retry: &str) -> String {
+    format!("{}        transparentRetry: {{ {} }}\n", CONFIG, retry)
+}
+
+#[test]
+fn retries_streams_reset_before_any_bytes_are_forwarded() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let resetting = h.closing_server(true);
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0), (resetting, 1.0)]);
+    let proxy = h.proxy(&transparent_retry_config("maxRetries: 10"));
+
+    // Each client waits for its stream to be reset (and retried) before it speaks.
+    for _ in 0..10 {
+        let conn = h.connect(&proxy.addr());
+        h.sleep(Duration::from_millis(100));
+        let (_, rsp) = h.echo(conn, b"ping");
+        assert_eq!(rsp, b"ping".to_vec());
+    }
+    assert!(proxy.metric("transparent_retries") > 0);
+    assert_eq!(proxy.metric("srv_failures"), 0);
+}
+
+#[test]
+fn retries_streams_against_other_endpoints_than_those_that_reset_them() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    let resetting = h.closing_server(true);
+    h.namerd().bind("/svc/echo", &[(resetting, 1.0)]);
+    // The client is stuck to the resetting endpoint, so that it would otherwise be
+    // retried against it.
+    let config = stickiness_config(300).replacen(
+        CONFIG,
+        &transparent_retry_config("maxRetries: 1"),
+        1,
+    );
+    let proxy = h.proxy(&config);
+
+    // While it is the only endpoint, the resetting endpoint is retried.
+    let conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+    assert!(h.try_echo(conn, b"ping").is_err());
+    assert_eq!(proxy.metric("transparent_retries"), 1);
+
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0), (resetting, 1.0)]);
+    h.sleep(Duration::from_millis(1500));
+    let conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(100));
+    let (_, rsp) = h.echo(conn, b"ping");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert_eq!(proxy.metric("transparent_retries"), 2);
+    assert_eq!(echo.accepts(), 1);
+}
+
+#[test]
+fn replays_retained_bytes_to_retried_endpoints() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    // The first endpoint completes connections but never accepts them.
+    let unaccepting = net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    h.namerd().bind("/svc/echo", &[(unaccepting.local_addr().unwrap(), 1.0)]);
+    let proxy = h.proxy(&transparent_retry_config("retryBufferBytes: 16, maxRetries: 2"));
+
+    // The stream is dispatched to the first endpoint before the client sends anything,
+    // and is only retried to the echo server.
+    let mut client = net::TcpStream::connect(proxy.addr()).expect("failed to connect");
+    h.sleep(Duration::from_millis(100));
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    h.sleep(Duration::from_millis(2500));
+
+    // While the proxy is not running, the first endpoint resets the stream without
+    // having written anything, and the client sends fewer bytes than are retained. The
+    // client's bytes are read before the reset is discovered, so they must be replayed.
+    drop(unaccepting);
+    let msg = b"replay me";
+    client.write_all(msg).expect("failed to write");
+    let client = h.register(client);
+    let (_, rsp) = h.read_exact(client, msg.len());
+    assert_eq!(rsp, msg.to_vec());
+    assert_eq!(proxy.metric("transparent_retries"), 1);
+    assert_eq!(echo.accepts(), 1);
+}
+
+#[test]
+fn caps_transparent_retries_per_stream() {
+    let mut h = Harness::new();
+    let resetting = h.closing_server(true);
+    h.namerd().bind("/svc/echo", &[(resetting, 1.0)]);
+    let proxy = h.proxy(&transparent_retry_config("maxRetries: 2"));
+
+    let conn = h.connect(&proxy.addr());
+    h.sleep(Duration::from_millis(500));
+    assert!(h.try_echo(conn, b"ping").is_err());
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("transparent_retries"), 2);
+    assert_eq!(proxy.labeled_metric("close_reasons", "reason=\"server_reset\""), 1);
+}
+
+#[test]
+fn does_not_retry_streams_that_overflow_the_retry_buffer() {
+    let mut h = Harness::new();
+    let resetting = h.closing_server(true);
+    h.namerd().bind("/svc/echo", &[(resetting, 1.0)]);
+    let proxy = h.proxy(&transparent_retry_config("retryBufferBytes: 16, maxRetries: 2"));
+
+    // The client's bytes are read, and released, before the stream is reset.
+    let conn = h.connect(&proxy.addr());
+    assert!(h.try_echo(conn, &[b'x'; 64]).is_err());
+    h.sleep(Duration::from_millis(100));
+    assert_eq!(proxy.metric("transparent_retries"), 0);
+    assert_eq!(proxy.metric("srv_failures") + proxy.metric("srv_closes"), 1);
+}
+
+#[test]
+fn counts_connections_by_transfer_size() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = CONFIG.replace("    servers:\n", "    sizeBuckets: [16, 1024]\n    servers:\n");
+    let proxy = h.proxy(&config);
+    for &len in &[8, 100, 2000] {
+        let msg = vec![b'x'; len];
+        assert_eq!(h.roundtrip(&proxy.addr(), &msg), msg);
+    }
+    h.sleep(Duration::from_millis(100));
+
+    // Each connection is counted once in each direction.
+    for &(bucket, bytes) in &[("16", 8), ("1024", 100), ("inf", 2000)] {
+        let label = format!("bucket=\"{}\"", bucket);
+        assert_eq!(proxy.labeled_metric("transfer_size_bucket", &label), 2);
+        assert_eq!(proxy.labeled_metric("transfer_size_bucket_bytes", &label), 2 * bytes);
+    }
+    for direction in &["client_to_server", "server_to_client"] {
+        let label = format!("direction=\"{}\"", direction);
+        assert_eq!(proxy.labeled_metric("transfer_size_bucket", &label), 3);
+        assert_eq!(proxy.labeled_metric("transfer_size_bucket_bytes", &label), 2108);
+    }
+}
+
+#[test]
+fn disables_transfer_size_buckets() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = CONFIG.replace("    servers:\n", "    sizeBuckets: []\n    servers:\n");
+    let proxy = h.proxy(&config);
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    h.sleep(Duration::from_millis(100));
+    assert!(!proxy.prometheus().contains("transfer_size_bucket"));
+}
@@ -0,0 +1,147 @@
+extern crate futures;
+extern crate linkerd_tcp;
+extern crate tokio_core;
+extern crate tokio_timer;
+
+use futures::{Async, Future, Poll, Stream, future, stream};
+use linkerd_tcp::app::Startup;
+use linkerd_tcp::lb::Scope;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::Core;
+use tokio_timer::{Sleep, Timer};
+
+/// Records the requests made by `Resolution`s.
+#[derive(Clone, Default)]
+struct Requests {
+    started: usize,
+    in_flight: usize,
+    max_in_flight: usize,
+}
+
+/// A resolver that answers each request after a delay, recording how many requests it is
+/// serving at once.
+struct Resolution {
+    requests: Rc<RefCell<Requests>>,
+    timer: Timer,
+    rsp: Option<Result<u32, String>>,
+    pending: Option<Sleep>,
+}
+
+impl Resolution {
+    fn new(requests: &Rc<RefCell<Requests>>, timer: &Timer, ok: bool) -> Resolution {
+        Resolution {
+            requests: requests.clone(),
+            timer: timer.clone(),
+            rsp: Some(if ok { Ok(1) } else { Err("not bound".into()) }),
+            pending: None,
+        }
+    }
+}
+
+impl Stream for Resolution {
+    type Item = Result<u32, String>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        if self.rsp.is_none() {
+            return Ok(Async::Ready(None));
+        }
+        if self.pending.is_none() {
+            let mut requests = self.requests.borrow_mut();
+            requests.started += 1;
+            requests.in_flight += 1;
+            requests.max_in_flight = std::cmp::max(requests.max_in_flight, requests.in_flight);
+            self.pending = Some(self.timer.sleep(Duration::from_millis(20)));
+        }
+        if let Ok(Async::NotReady) = self.pending.as_mut().unwrap().poll() {
+            return Ok(Async::NotReady);
+        }
+        self.requests.borrow_mut().in_flight -= 1;
+        Ok(Async::Ready(self.rsp.take()))
+    }
+}
+
+/// Resolves each of `dsts` through `startup`, failing those that are not `ok`.
+fn resolve_all(startup: &Startup, dsts: &[(&str, bool)]) -> Requests {
+    let mut core = Core::new().unwrap();
+    let timer = Timer::default();
+    let requests = Rc::new(RefCell::new(Requests::default()));
+    let mut resolutions = Vec::new();
+    for &(dst, ok) in dsts {
+        let resolution = Resolution::new(&requests, &timer, ok);
+        resolutions.push(startup.initial("default", dst, resolution).for_each(|_| Ok(())));
+    }
+    core.run(future::join_all(resolutions)).unwrap();
+    let requests = requests.borrow();
+    requests.clone()
+}
+
+#[test]
+fn bounds_concurrent_initial_resolutions() {
+    let startup = Startup::new(2, 100, &Scope::noop());
+    let dsts = ["/svc/a", "/svc/b", "/svc/c", "/svc/d", "/svc/e", "/svc/f"];
+    for dst in &dsts {
+        startup.expect_proxy("default", dst);
+    }
+    assert_eq!(startup.progress(), (0, 6));
+    assert!(!startup.is_ready());
+
+    let dsts: Vec<(&str, bool)> = dsts.iter().map(|d| (*d, true)).collect();
+    let requests = resolve_all(&startup, &dsts);
+    assert_eq!(requests.started, 6);
+    assert_eq!(requests.max_in_flight, 2);
+    assert_eq!(startup.in_flight(), 0);
+    assert_eq!(startup.progress(), (6, 6));
+    assert!(startup.is_ready());
+}
+
+#[test]
+fn releases_permits_when_initial_resolutions_fail() {
+    let startup = Startup::new(1, 100, &Scope::noop());
+    startup.expect_proxy("default", "/svc/a");
+    startup.expect_proxy("default", "/svc/b");
+
+    let requests = resolve_all(&startup, &[("/svc/a", false), ("/svc/b", true)]);
+    assert_eq!(requests.started, 2);
+    assert_eq!(requests.max_in_flight, 1);
+    assert_eq!(startup.in_flight(), 0);
+    // A proxy whose destination could not be resolved is not ready.
+    assert_eq!(startup.progress(), (1, 2));
+    assert!(!startup.is_ready());
+}
+
+#[test]
+fn becomes_ready_at_quorum() {
+    let startup = Startup::new(5, 50, &Scope::noop());
+    for dst in &["/svc/a", "/svc/b", "/svc/c", "/svc/d"] {
+        startup.expect_proxy("default", dst);
+    }
+    // Proxies are identified by their router as well as their destination.
+    startup.expect_proxy("other", "/svc/a");
+
+    let resolve = |dst: &str| {
+        let rsp = stream::once::<Result<u32, String>, ()>(Ok(Ok(1)));
+        startup.initial("default", dst, rsp).for_each(|_| Ok(())).wait().unwrap();
+    };
+    resolve("/svc/a");
+    resolve("/svc/b");
+    assert_eq!(startup.progress(), (2, 5));
+    assert!(!startup.is_ready());
+
+    // Resolving a proxy again does not count it twice.
+    resolve("/svc/b");
+    assert_eq!(startup.progress(), (2, 5));
+    assert!(!startup.is_ready());
+
+    resolve("/svc/c");
+    assert_eq!(startup.progress(), (3, 5));
+    assert!(startup.is_ready());
+}
+
+#[test]
+fn is_ready_without_proxies() {
+    let startup = Startup::new(5, 100, &Scope::noop());
+    assert_eq!(startup.progress(), (0, 0));
+    assert!(startup.is_ready());
+}
@@ -0,0 +1,1382 @@
+#![cfg(feature = "tls")]
+
+extern crate futures;
+extern crate hyper;
+extern crate linkerd_tcp;
+extern crate rustls;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_timer;
+extern crate url;
+extern crate webpki;
+
+mod harness;
+
+use futures::sync::oneshot;
+use harness::{Harness, Proxy, TlsEchoServer};
+use linkerd_tcp::{ClientHello, Error, HandshakeFailure};
+use linkerd_tcp::app::{self, AppConfig};
+use linkerd_tcp::lb::{ConnectionHook, ConnectionSummary};
+use rustls::{Session, TLSError};
+use rustls::internal::msgs::enums::AlertDescription;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Terminates TLS as `a.test` and `b.test` and forwards plaintext to echo servers.
+static GATEWAYS_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: gateways
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo-a
+        tls:
+          defaultIdentity:
+            privateKey: {certs}/a.test.key
+            certs: [{certs}/a.test.pem]
+      - port: 0
+        dstName: /svc/echo-b
+        tls:
+          defaultIdentity:
+            privateKey: {certs}/b.test.key
+            certs: [{certs}/b.test.pem]
+";
+
+/// Terminates TLS for both names, and originates TLS to the gateways with the
+/// downstream client's server name.
+static FRONT_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: front
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/a
+        connectTimeoutMs: 1000
+        tls:
+          identities:
+            a.test:
+              privateKey: {certs}/a.test.key
+              certs: [{certs}/a.test.pem]
+            b.test:
+              privateKey: {certs}/b.test.key
+              certs: [{certs}/b.test.pem]
+      - port: 0
+        dstName: /svc/b
+        connectTimeoutMs: 1000
+        tls:
+          identities:
+            a.test:
+              privateKey: {certs}/a.test.key
+              certs: [{certs}/a.test.pem]
+            b.test:
+              privateKey: {certs}/b.test.key
+              certs: [{certs}/b.test.pem]
+    client:
+      kind: io.l5d.global
+      tls:
+        dnsName: static.test
+        trustCerts: [{certs}/ca.pem]
+        tlsNameFrom: downstreamSni
+        verification: {verification}
+";
+
+/// Requires that clients negotiate h2 or http/1.1.
+static ALPN_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: alpn
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 1000
+        tls:
+          alpnProtocols: [h2, http/1.1]
+          requireAlpn: true
+          defaultIdentity:
+            privateKey: {certs}/a.test.key
+            certs: [{certs}/a.test.pem]
+";
+
+/// Terminates TLS with the given `sessionResumption` configuration.
+static RESUMPTION_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: resumption
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 1000
+        tls:
+          sessionResumption: {resumption}
+          defaultIdentity:
+            privateKey: {certs}/a.test.key
+            certs: [{certs}/a.test.pem]
+";
+
+/// Terminates TLS as `chain.test`, whose certificate is issued by an intermediate, with
+/// the given certificate files.
+static CHAIN_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: chain
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        connectTimeoutMs: 1000
+        tls:
+          defaultIdentity:
+            privateKey: {key}
+            certs: [{chain}]
+            allowIncompleteChain: {allow}
+";
+
+/// Originates TLS to the gateways, choosing them by their connect latencies.
+static EWMA_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: ewma
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/gateway
+        connectTimeoutMs: 5000
+    client:
+      kind: io.l5d.global
+      tls:
+        dnsName: a.test
+        trustCerts: [{certs}/ca.pem]
+      loadBalancer:
+        kind: io.l5d.ewma
+";
+
+fn certs_dir() -> &'static str {
+    concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls")
+}
+
+/// Spawns an echo server that terminates TLS as `a.test`.
+fn a_test_echo_server(h: &Harness) -> TlsEchoServer {
+    h.tls_echo_server(
+        &format!("{}/a.test.key", certs_dir()),
+        &format!("{}/a.test.pem", certs_dir()),
+    )
+}
+
+/// Spawns the gateways and a front proxy that verifies upstream certificates with
+/// `verification`, returning the front proxy.
+fn front_proxy(h: &mut Harness, verification: &str) -> Proxy {
+    let echo_a = h.echo_server();
+    let echo_b = h.echo_server();
+    h.namerd().bind("/svc/echo-a", &[(echo_a.addr(), 1.0)]);
+    h.namerd().bind("/svc/echo-b", &[(echo_b.addr(), 1.0)]);
+    let gateways = h.proxy(&GATEWAYS_CONFIG.replace("{certs}", certs_dir()));
+    h.namerd().bind("/svc/a", &[(gateways.addrs()[0], 1.0)]);
+    h.namerd().bind("/svc/b", &[(gateways.addrs()[1], 1.0)]);
+
+    let config = FRONT_CONFIG.replace("{certs}", certs_dir()).replace(
+        "{verification}",
+        verification,
+    );
+    h.proxy(&config)
+}
+
+/// Echoes `msg` over a TLS connection to `addr` for the server name `sni`.
+///
+/// The client blocks, so it runs on its own thread while the harness drives the
+/// proxies.
+fn tls_roundtrip(
+    h: &mut Harness,
+    addr: SocketAddr,
+    sni: &str,
+    msg: &[u8],
+) -> io::Result<Vec<u8>> {
+    alpn_roundtrip(h, addr, sni, &[], msg)
+}
+
+/// Like `tls_roundtrip`, offering the given ALPN protocols.
+fn alpn_roundtrip(
+    h: &mut Harness,
+    addr: SocketAddr,
+    sni: &str,
+    alpn: &[&str],
+    msg: &[u8],
+) -> io::Result<Vec<u8>> {
+    let (tx, rx) = oneshot::channel();
+    let sni = sni.to_owned();
+    let alpn = alpn.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    let msg = msg.to_vec();
+    thread::spawn(move || {
+        let _ = tx.send(tls_echo(addr, &sni, &alpn, &msg));
+    });
+    h.run(rx).expect("client thread failed")
+}
+
+/// Echoes `msg` over `n` TLS connections to `addr` from a client that resumes sessions
+/// with TLS 1.2.
+fn resuming_roundtrips(
+    h: &mut Harness,
+    addr: SocketAddr,
+    n: usize,
+    msg: &[u8],
+) -> io::Result<Vec<Vec<u8>>> {
+    let (tx, rx) = oneshot::channel();
+    let msg = msg.to_vec();
+    thread::spawn(move || {
+        let rsps: io::Result<Vec<Vec<u8>>> = client_config(&[]).and_then(|mut config| {
+            config.versions = vec![rustls::ProtocolVersion::TLSv1_2];
+            config.set_persistence(rustls::ClientSessionMemoryCache::new(32));
+            let config = Arc::new(config);
+            (0..n).map(|_| echo_with(&config, addr, "a.test", &msg)).collect()
+        });
+        let _ = tx.send(rsps);
+    });
+    h.run(rx).expect("client thread failed")
+}
+
+fn client_config(alpn: &[String]) -> io::Result<rustls::ClientConfig> {
+    let mut config = rustls::ClientConfig::new();
+    if !alpn.is_empty() {
+        config.set_protocols(alpn);
+    }
+    let ca = File::open(format!("{}/ca.pem", certs_dir()))?;
+    config.root_store.add_pem_file(&mut BufReader::new(ca)).expect(
+        "invalid ca certificate",
+    );
+    Ok(config)
+}
+
+fn tls_echo(addr: SocketAddr, sni: &str, alpn: &[String], msg: &[u8]) -> io::Result<Vec<u8>> {
+    let config = client_config(alpn)?;
+    echo_with(&Arc::new(config), addr, sni, msg)
+}
+
+fn echo_with(
+    config: &Arc<rustls::ClientConfig>,
+    addr: SocketAddr,
+    sni: &str,
+    msg: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut session = rustls::ClientSession::new(config, sni);
+
+    let mut tcp = TcpStream::connect(addr)?;
+    tcp.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    // The message is written once the handshake completes.
+    session.write_all(msg)?;
+    let mut rsp = Vec::new();
+    while rsp.len() < msg.len() {
+        while session.wants_write() {
+            session.write_tls(&mut tcp)?;
+        }
+        if session.read_tls(&mut tcp)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed"));
+        }
+        session.process_new_packets().map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+        })?;
+        let mut buf = [0u8; 64];
+        let sz = session.read(&mut buf)?;
+        rsp.extend_from_slice(&buf[..sz]);
+    }
+    Ok(rsp)
+}
+
+#[test]
+fn propagates_downstream_sni() {
+    let mut h = Harness::new();
+    let front = front_proxy(&mut h, "name");
+    let a = front.addrs()[0];
+    let b = front.addrs()[1];
+
+    let rsp = tls_roundtrip(&mut h, a, "a.test", b"ping").expect("a.test failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    let rsp = tls_roundtrip(&mut h, b, "b.test", b"pong").expect("b.test failed");
+    assert_eq!(rsp, b"pong".to_vec());
+
+    // The gateway for /svc/a presents a certificate for a.test only.
+    assert!(tls_roundtrip(&mut h, a, "b.test", b"ping").is_err());
+}
+
+#[test]
+fn verifies_only_ca_when_configured() {
+    let mut h = Harness::new();
+    let front = front_proxy(&mut h, "caOnly");
+    let a = front.addrs()[0];
+
+    let rsp = tls_roundtrip(&mut h, a, "b.test", b"ping").expect("b.test failed");
+    assert_eq!(rsp, b"ping".to_vec());
+}
+
+#[test]
+fn requires_alpn_and_labels_streams_by_protocol() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&ALPN_CONFIG.replace("{certs}", certs_dir()));
+    let addr = proxy.addr();
+
+    let rsp = alpn_roundtrip(&mut h, addr, "a.test", &["h2"], b"ping").expect("h2 failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    let rsp = alpn_roundtrip(&mut h, addr, "a.test", &["http/1.1"], b"pong!")
+        .expect("http/1.1 failed");
+    assert_eq!(rsp, b"pong!".to_vec());
+
+    assert!(alpn_roundtrip(&mut h, addr, "a.test", &["spdy/3"], b"ping").is_err());
+    assert!(tls_roundtrip(&mut h, addr, "a.test", b"ping").is_err());
+    assert_eq!(proxy.labeled_metric("refused", "cause=\"alpn\""), 2);
+
+    assert_eq!(proxy.labeled_metric("stream_rx_bytes", "alpn=\"h2\""), 4);
+    assert_eq!(proxy.labeled_metric("stream_rx_bytes", "alpn=\"http/1.1\""), 5);
+}
+
+/// Connects twice with a client that resumes sessions, returning the number of
+/// resumptions counted for each of `ticket` and `session_id`.
+fn resumptions(resumption: &str) -> (u64, u64) {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = RESUMPTION_CONFIG.replace("{certs}", certs_dir()).replace(
+        "{resumption}",
+        resumption,
+    );
+    let proxy = h.proxy(&config);
+
+    let rsps = resuming_roundtrips(&mut h, proxy.addr(), 2, b"ping").expect("echo failed");
+    assert_eq!(rsps, vec![b"ping".to_vec(), b"ping".to_vec()]);
+    assert_eq!(proxy.metric("tls_handshakes"), 2);
+    (
+        proxy.labeled_metric("tls_resumptions", "via=\"ticket\""),
+        proxy.labeled_metric("tls_resumptions", "via=\"session_id\""),
+    )
+}
+
+#[test]
+fn resumes_sessions_with_tickets() {
+    let config = "{tickets: true, ticketRotationSecs: 1h, sessionCacheSize: 0}";
+    assert_eq!(resumptions(config), (1, 0));
+}
+
+#[test]
+fn resumes_sessions_from_cache() {
+    assert_eq!(resumptions("{tickets: false, sessionCacheSize: 16}"), (0, 1));
+}
+
+#[test]
+fn disables_session_resumption() {
+    assert_eq!(resumptions("{tickets: false, sessionCacheSize: 0}"), (0, 0));
+}
+
+#[test]
+fn classifies_tls_errors() {
+    let incompatible = |msg: &str| TLSError::PeerIncompatibleError(msg.to_owned());
+    let alert = TLSError::AlertReceived;
+    let cases = vec![
+        (incompatible("no ciphersuites in common"), HandshakeFailure::NoSharedCipher),
+        (incompatible("client does not support TLSv1_2"), HandshakeFailure::UnsupportedVersion),
+        (alert(AlertDescription::ProtocolVersion), HandshakeFailure::UnsupportedVersion),
+        (alert(AlertDescription::HandshakeFailure), HandshakeFailure::NoSharedCipher),
+        (alert(AlertDescription::BadCertificate), HandshakeFailure::BadCertificate),
+        (alert(AlertDescription::CertificateExpired), HandshakeFailure::BadCertificate),
+        (alert(AlertDescription::UnknownCA), HandshakeFailure::UnknownCa),
+        (alert(AlertDescription::DecryptError), HandshakeFailure::DecryptError),
+        (alert(AlertDescription::InternalError), HandshakeFailure::Other),
+        (TLSError::NoCertificatesPresented, HandshakeFailure::BadCertificate),
+        (TLSError::WebPKIError(webpki::Error::UnknownIssuer), HandshakeFailure::UnknownCa),
+        (TLSError::WebPKIError(webpki::Error::CertExpired), HandshakeFailure::BadCertificate),
+        (TLSError::DecryptError, HandshakeFailure::DecryptError),
+        (TLSError::CorruptMessage, HandshakeFailure::NotTls),
+        (TLSError::General("boom".to_owned()), HandshakeFailure::Other),
+    ];
+    for (err, cause) in cases {
+        assert_eq!(HandshakeFailure::from_tls_error(&err), cause, "{:?}", err);
+
+        // Handshakes surface rustls errors as I/O errors.
+        let msg = format!("{:?}", err);
+        let err = io::Error::new(io::ErrorKind::Other, err);
+        assert_eq!(HandshakeFailure::classify(&err, Some(22)), cause, "{}", msg);
+    }
+}
+
+#[test]
+fn classifies_handshake_io_errors() {
+    let timeout = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+    assert_eq!(HandshakeFailure::classify(&timeout, Some(22)), HandshakeFailure::Timeout);
+    assert_eq!(HandshakeFailure::classify(&timeout, None), HandshakeFailure::Timeout);
+
+    // Clients that send something other than a handshake record don't speak TLS,
+    // however their handshakes end.
+    assert_eq!(HandshakeFailure::classify(&timeout, Some(b'G')), HandshakeFailure::NotTls);
+
+    // Port scanners close connections without sending anything.
+    let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "closed");
+    assert_eq!(HandshakeFailure::classify(&eof, None), HandshakeFailure::NotTls);
+    assert_eq!(HandshakeFailure::classify(&eof, Some(22)), HandshakeFailure::Other);
+
+    let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+    assert_eq!(HandshakeFailure::classify(&reset, Some(22)), HandshakeFailure::Other);
+}
+
+#[test]
+fn counts_handshake_failures_by_cause() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&ALPN_CONFIG.replace("{certs}", certs_dir()));
+    let addr = proxy.addr();
+    let failures = |cause: &str| {
+        proxy.labeled_metric("tls_handshake_failures", &format!("cause=\"{}\"", cause))
+    };
+
+    // A plaintext client, a client that sends nothing, and a scanner that closes
+    // immediately.
+    let mut plaintext = TcpStream::connect(&addr).unwrap();
+    plaintext.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let _silent = TcpStream::connect(&addr).unwrap();
+    drop(TcpStream::connect(&addr).unwrap());
+    h.sleep(Duration::from_millis(1500));
+
+    assert_eq!(failures("not_tls"), 2);
+    assert_eq!(failures("timeout"), 1);
+    assert_eq!(proxy.metric("tls_handshake_failures"), 3);
+
+    // Successful handshakes are not counted.
+    let rsp = alpn_roundtrip(&mut h, addr, "a.test", &["h2"], b"ping").expect("h2 failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert_eq!(proxy.metric("tls_handshake_failures"), 3);
+}
+
+/// Like `ALPN_CONFIG`, bounding handshakes with `handshakeTimeoutMs` and the given
+/// `maxConcurrentHandshakes`, if any.
+fn handshake_limit_proxy(h: &mut Harness, max: Option<usize>) -> Proxy {
+    let mut limits = "requireAlpn: true\n          handshakeTimeoutMs: 500\n".to_owned();
+    if let Some(max) = max {
+        limits.push_str(&format!("          maxConcurrentHandshakes: {}\n", max));
+    }
+    let config = ALPN_CONFIG.replace("{certs}", certs_dir()).replace(
+        "requireAlpn: true\n",
+        &limits,
+    );
+    h.proxy(&config)
+}
+
+/// Opens `n` connections that never send a ClientHello.
+fn silent_clients(addr: SocketAddr, n: usize) -> Vec<TcpStream> {
+    (0..n)
+        .map(|_| {
+            let sock = TcpStream::connect(&addr).unwrap();
+            sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            sock
+        })
+        .collect()
+}
+
+#[test]
+fn reaps_stalled_handshakes_at_the_handshake_timeout() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = handshake_limit_proxy(&mut h, None);
+    let addr = proxy.addr();
+
+    let silent = silent_clients(addr, 10);
+    h.sleep(Duration::from_millis(200));
+    assert_eq!(proxy.metric("tls_handshakes_in_flight"), 10);
+    assert_eq!(proxy.metric("tls_handshake_failures"), 0);
+
+    // Handshakes time out well before the server's 1s connect timeout.
+    h.sleep(Duration::from_millis(600));
+    assert_eq!(proxy.metric("tls_handshakes_in_flight"), 0);
+    assert_eq!(
+        proxy.labeled_metric("tls_handshake_failures", "cause=\"timeout\""),
+        10
+    );
+    for mut sock in silent {
+        let mut buf = [0u8; 16];
+        match sock.read(&mut buf) {
+            Ok(0) => {}
+            Ok(n) => panic!("read {} bytes from a stalled handshake", n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {
+                panic!("stalled handshake was not closed")
+            }
+            Err(_) => {}
+        }
+    }
+
+    let rsp = alpn_roundtrip(&mut h, addr, "a.test", &["h2"], b"ping").expect("h2 failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert_eq!(proxy.metric("tls_handshakes_in_flight"), 0);
+}
+
+#[test]
+fn defers_accepts_while_handshakes_are_in_flight() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = handshake_limit_proxy(&mut h, Some(2));
+    let addr = proxy.addr();
+
+    // Connections beyond the limit wait in the listen backlog until handshakes time
+    // out.
+    let _silent = silent_clients(addr, 5);
+    h.sleep(Duration::from_millis(200));
+    assert_eq!(proxy.metric("tls_handshakes_in_flight"), 2);
+    assert_eq!(proxy.metric("accepts"), 2);
+    assert!(proxy.metric("tls_handshake_deferrals") >= 1);
+
+    h.sleep(Duration::from_millis(2500));
+    assert_eq!(proxy.metric("tls_handshakes_in_flight"), 0);
+    assert_eq!(proxy.metric("accepts"), 5);
+    assert_eq!(
+        proxy.labeled_metric("tls_handshake_failures", "cause=\"timeout\""),
+        5
+    );
+
+    let rsp = alpn_roundtrip(&mut h, addr, "a.test", &["h2"], b"ping").expect("h2 failed");
+    assert_eq!(rsp, b"ping".to_vec());
+}
+
+#[test]
+fn negotiates_alpn_protocols_in_configured_order() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&ALPN_CONFIG.replace("{certs}", certs_dir()));
+    let addr = proxy.addr();
+
+    // The server's preference wins over the client's.
+    let rsp = alpn_roundtrip(&mut h, addr, "a.test", &["http/1.1", "h2"], b"ping")
+        .expect("roundtrip failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert_eq!(proxy.labeled_metric("stream_rx_bytes", "alpn=\"h2\""), 4);
+    assert_eq!(proxy.labeled_metric("stream_rx_bytes", "alpn=\"http/1.1\""), 0);
+}
+
+#[test]
+fn rejects_invalid_alpn_protocols() {
+    let long = format!("[{}]", "x".repeat(256));
+    for protocols in &[
+        "[]",
+        "[\"\"]",
+        "[\"http 1.1\"]",
+        long.as_str(),
+        "[h2, http/1.1, h2]",
+    ]
+    {
+        let config = ALPN_CONFIG
+            .replace("{namerd}", "http://127.0.0.1:4180")
+            .replace("{certs}", certs_dir())
+            .replace("[h2, http/1.1]", protocols);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(e) => {
+                let msg = e.to_string();
+                assert!(msg.contains("127.0.0.1:0"), "listener not named: {}", msg);
+            }
+            Ok(_) => panic!("accepted {}", protocols),
+        }
+    }
+
+    // Unregistered protocols are only warned about.
+    for tls in &["", "allowUnknownAlpn: true\n          "] {
+        let config = ALPN_CONFIG
+            .replace("{namerd}", "http://127.0.0.1:4180")
+            .replace("{certs}", certs_dir())
+            .replace("[h2, http/1.1]", "[h2, http1.1]")
+            .replace("requireAlpn: true\n", &format!("{}requireAlpn: true\n", tls));
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        config.into_app().expect("rejected an unregistered protocol");
+    }
+}
+
+#[test]
+fn rejects_invalid_handshake_limits() {
+    for limit in &["handshakeTimeoutMs: 0", "maxConcurrentHandshakes: 0"] {
+        let config = ALPN_CONFIG
+            .replace("{namerd}", "http://127.0.0.1:4180")
+            .replace("{certs}", certs_dir())
+            .replace(
+                "requireAlpn: true\n",
+                &format!("requireAlpn: true\n          {}\n", limit),
+            );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", limit);
+    }
+}
+
+#[test]
+fn prefers_endpoints_with_lower_connect_latencies() {
+    let mut h = Harness::new();
+    let echo = a_test_echo_server(&h);
+
+    // Both endpoints reach the same echo server, but the slow endpoint's handshakes are
+    // delayed.
+    let fast = h.delayed_relay(echo.addr(), Duration::from_millis(0));
+    let slow = h.delayed_relay(echo.addr(), Duration::from_millis(200));
+    h.namerd().bind("/svc/gateway", &[(fast.addr(), 1.0), (slow.addr(), 1.0)]);
+    let proxy = h.proxy(&EWMA_CONFIG.replace("{certs}", certs_dir()));
+
+    for _ in 0..40 {
+        assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    }
+    assert_eq!(fast.accepts() + slow.accepts(), 40);
+    assert!(
+        fast.accepts() >= 4 * slow.accepts(),
+        "fast: {}, slow: {}",
+        fast.accepts(),
+        slow.accepts()
+    );
+}
+
+#[test]
+fn originates_tls_with_the_configured_name() {
+    let mut h = Harness::new();
+    let echo = a_test_echo_server(&h);
+    h.namerd().bind("/svc/gateway", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&EWMA_CONFIG.replace("{certs}", certs_dir()));
+
+    assert_eq!(h.roundtrip(&proxy.addr(), b"ping"), b"ping".to_vec());
+    assert_eq!(h.roundtrip(&proxy.addr(), b"pong"), b"pong".to_vec());
+    assert_eq!(echo.accepts(), 2);
+    assert_eq!(echo.sni(), Some("a.test".to_owned()));
+}
+
+/// Originates TLS to `/svc/gateway` as `a.test`, trusting the roots in `{trust}`, which
+/// are reloaded as they change.
+static RELOAD_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: reload
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/gateway
+        connectTimeoutMs: 1000
+    client:
+      kind: io.l5d.global
+      tls:
+        dnsName: a.test
+        trustCerts: [{trust}]
+        reloadIntervalSecs: 200ms
+";
+
+fn copy_file(from: &str, to: &::std::path::Path) {
+    let mut contents = Vec::new();
+    File::open(from).unwrap().read_to_end(&mut contents).unwrap();
+    File::create(to).unwrap().write_all(&contents).unwrap();
+}
+
+#[test]
+fn reloads_trust_certs_as_they_change() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let trust = ::std::env::temp_dir().join(format!("linkerd-tcp-trust-{}.pem", nanos));
+    // The echo server's certificate is not issued by the chain fixtures' root.
+    copy_file(&format!("{}/chain/root.pem", certs_dir()), &trust);
+
+    let mut h = Harness::new();
+    let echo = a_test_echo_server(&h);
+    h.namerd().bind("/svc/gateway", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&RELOAD_CONFIG.replace("{trust}", &trust.display().to_string()));
+    let addr = proxy.addr();
+
+    let rsp = h.try_roundtrip(&addr, b"ping").ok();
+    assert_ne!(rsp, Some(b"ping".to_vec()), "connected without a trusted root");
+
+    // Once the echo server's CA is trusted, new connections verify it without a restart.
+    copy_file(&format!("{}/ca.pem", certs_dir()), &trust);
+    let mut rsp = None;
+    for _ in 0..20 {
+        h.sleep(Duration::from_millis(250));
+        rsp = h.try_roundtrip(&addr, b"ping").ok();
+        if rsp == Some(b"ping".to_vec()) {
+            break;
+        }
+    }
+    assert_eq!(rsp, Some(b"ping".to_vec()), "did not reload trusted roots");
+    assert_eq!(proxy.metric("tls_reloads"), 1);
+    assert_eq!(proxy.metric("tls_reload_failures"), 0);
+
+    // A file that fails to load is retried, and the loaded roots remain in use.
+    File::create(&trust).unwrap().write_all(b"not a certificate").unwrap();
+    h.sleep(Duration::from_millis(250));
+    assert_eq!(h.roundtrip(&addr, b"pong"), b"pong".to_vec());
+    h.sleep(Duration::from_millis(250));
+    assert_eq!(h.roundtrip(&addr, b"ping"), b"ping".to_vec());
+    assert_eq!(proxy.metric("tls_reloads"), 1);
+    assert_eq!(proxy.metric("tls_reload_failures"), 2);
+
+    ::std::fs::remove_file(&trust).unwrap();
+}
+
+/// Reloaded files are re-opened by their paths, which a chroot would hide.
+#[test]
+fn rejects_reloads_within_chroots() {
+    let config = RELOAD_CONFIG
+        .replace("{namerd}", "http://127.0.0.1:4180")
+        .replace("{trust}", &format!("{}/ca.pem", certs_dir()));
+    let config = format!(
+        "security: {{ chrootDir: {} }}{}",
+        ::std::env::temp_dir().display(),
+        config
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::Security(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted reloadIntervalSecs within a chroot"),
+    }
+}
+
+#[test]
+fn rejects_invalid_client_tls() {
+    let certs = format!("{}/a.test.pem", certs_dir());
+    let key = format!("{}/a.test.key", certs_dir());
+    for tls in &[
+        format!("clientCerts: [{}]", certs),
+        format!("clientKey: {}", key),
+        format!("clientCerts: [{}]\n        clientKey: {}", certs, certs),
+        "reloadIntervalSecs: 0".to_owned(),
+    ]
+    {
+        let config = EWMA_CONFIG
+            .replace("{namerd}", "http://127.0.0.1:4180")
+            .replace("{certs}", certs_dir())
+            .replace(
+                "trustCerts: [",
+                &format!("{}\n        trustCerts: [", tls),
+            );
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        match config.into_app() {
+            Err(Error::Config(app::Error::Connector(_))) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("accepted {}", tls),
+        }
+    }
+
+    let config = EWMA_CONFIG
+        .replace("{namerd}", "http://127.0.0.1:4180")
+        .replace("{certs}", certs_dir())
+        .replace(
+            "trustCerts: [",
+            &format!("clientCerts: [{}]\n        clientKey: {}\n        trustCerts: [", certs, key),
+        );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    config.into_app().expect("rejected a client certificate");
+}
+
+#[test]
+fn rejects_unreadable_trust_certs() {
+    let config = EWMA_CONFIG
+        .replace("{namerd}", "http://127.0.0.1:4180")
+        .replace("{certs}", "/nonexistent");
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::Connector(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted unreadable trustCerts"),
+    }
+}
+
+/// Terminates TLS with an identity provided by an agent.
+#[cfg(unix)]
+static AGENT_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: agent
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        tls:
+          defaultIdentity:
+            identitySource:
+              kind: agent
+              socketPath: {socket}
+              audience: gateway
+              startWithoutIdentity: {start}
+";
+
+#[cfg(unix)]
+mod agent {
+    use serde_json;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// A unique path for an agent's socket.
+    pub fn socket_path() -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        ::std::env::temp_dir().join(format!("linkerd-tcp-agent-{}.sock", nanos))
+    }
+
+    /// Serves a single client over the agent protocol, sending each identity that is
+    /// provided to it.
+    pub struct MockAgent {
+        identities: mpsc::Sender<Vec<u8>>,
+        audience: Arc<Mutex<Option<String>>>,
+    }
+
+    impl MockAgent {
+        pub fn spawn(path: &Path) -> MockAgent {
+            let listener = UnixListener::bind(path).expect("failed to bind agent");
+            let (tx, rx) = mpsc::channel::<Vec<u8>>();
+            let audience = Arc::new(Mutex::new(None));
+            let seen = audience.clone();
+            thread::spawn(move || {
+                let (mut sock, _) = listener.accept().expect("agent failed to accept");
+                let req: serde_json::Value =
+                    serde_json::from_slice(&read_message(&mut sock)).expect("invalid request");
+                *seen.lock().unwrap() = req["audience"].as_str().map(String::from);
+                for msg in rx {
+                    let len = msg.len() as u32;
+                    let len = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+                    if sock.write_all(&len).and_then(|_| sock.write_all(&msg)).is_err() {
+                        return;
+                    }
+                }
+            });
+            MockAgent {
+                identities: tx,
+                audience,
+            }
+        }
+
+        /// Provides the identity of `name` from the test certificates.
+        pub fn provide(&self, name: &str) {
+            let read = |ext: &str| {
+                let mut s = String::new();
+                let path = format!("{}/{}.{}", super::certs_dir(), name, ext);
+                File::open(path).unwrap().read_to_string(&mut s).unwrap();
+                s
+            };
+            let mut identity = HashMap::new();
+            identity.insert("certs", read("pem"));
+            identity.insert("privateKey", read("key"));
+            let msg = serde_json::to_vec(&identity).unwrap();
+            self.identities.send(msg).expect("agent stopped");
+        }
+
+        pub fn audience(&self) -> Option<String> {
+            self.audience.lock().unwrap().clone()
+        }
+    }
+
+    fn read_message<R: Read>(r: &mut R) -> Vec<u8> {
+        let mut len = [0u8; 4];
+        r.read_exact(&mut len).expect("failed to read request");
+        let len = len.iter().fold(0usize, |n, b| (n << 8) | *b as usize);
+        let mut msg = vec![0; len];
+        r.read_exact(&mut msg).expect("failed to read request");
+        msg
+    }
+}
+
+#[cfg(unix)]
+fn agent_proxy(h: &mut Harness, socket: &std::path::Path, start: bool) -> Proxy {
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = AGENT_CONFIG
+        .replace("{socket}", &socket.display().to_string())
+        .replace("{start}", &start.to_string());
+    h.proxy(&config)
+}
+
+/// Retries a roundtrip for `sni` until it succeeds, as identities are replaced
+/// asynchronously.
+#[cfg(unix)]
+fn eventual_roundtrip(h: &mut Harness, addr: SocketAddr, sni: &str) -> io::Result<Vec<u8>> {
+    let mut rsp = tls_roundtrip(h, addr, sni, b"ping");
+    for _ in 0..40 {
+        if rsp.is_ok() {
+            break;
+        }
+        h.sleep(Duration::from_millis(250));
+        rsp = tls_roundtrip(h, addr, sni, b"ping");
+    }
+    rsp
+}
+
+#[cfg(unix)]
+#[test]
+fn presents_and_replaces_identities_from_agents() {
+    let mut h = Harness::new();
+    let socket = agent::socket_path();
+    let agent = agent::MockAgent::spawn(&socket);
+    agent.provide("b.test");
+    let proxy = agent_proxy(&mut h, &socket, false);
+    assert_eq!(agent.audience(), Some("gateway".to_owned()));
+
+    let rsp = tls_roundtrip(&mut h, proxy.addr(), "b.test", b"ping").expect("b.test failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    assert!(tls_roundtrip(&mut h, proxy.addr(), "a.test", b"ping").is_err());
+
+    // Rotated identities are used for new handshakes.
+    agent.provide("a.test");
+    let rsp = eventual_roundtrip(&mut h, proxy.addr(), "a.test").expect("a.test failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    let _ = std::fs::remove_file(&socket);
+}
+
+#[cfg(unix)]
+#[test]
+fn refuses_handshakes_until_agents_provide_identities() {
+    let mut h = Harness::new();
+    let socket = agent::socket_path();
+    let proxy = agent_proxy(&mut h, &socket, true);
+    assert!(tls_roundtrip(&mut h, proxy.addr(), "a.test", b"ping").is_err());
+
+    let agent = agent::MockAgent::spawn(&socket);
+    agent.provide("a.test");
+    let rsp = eventual_roundtrip(&mut h, proxy.addr(), "a.test").expect("a.test failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    let _ = std::fs::remove_file(&socket);
+}
+
+/// Agents are re-dialed by the paths of their sockets, which a chroot would hide.
+#[cfg(unix)]
+#[test]
+fn rejects_agent_identities_within_chroots() {
+    let config = AGENT_CONFIG
+        .replace("{namerd}", "http://127.0.0.1:4180")
+        .replace("{socket}", &agent::socket_path().display().to_string())
+        .replace("{start}", "true");
+    let config = format!(
+        "security: {{ chrootDir: {} }}{}",
+        std::env::temp_dir().display(),
+        config
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    match config.into_app() {
+        Err(Error::Config(app::Error::Security(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted an agent identity within a chroot"),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn fails_to_start_without_required_agent_identities() {
+    let config = AGENT_CONFIG
+        .replace("{namerd}", "http://127.0.0.1:4180")
+        .replace("{socket}", &agent::socket_path().display().to_string())
+        .replace("{start}", "false");
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    let mut app = config.into_app().expect("failed to load configuration");
+    let core = tokio_core::reactor::Core::new().unwrap();
+    let router = app.routers.pop_front().unwrap();
+    let timer = tokio_timer::Timer::default();
+    assert!(router.spawn(&core.handle(), &timer).is_err());
+}
+
+#[test]
+fn rejects_conflicting_identity_sources() {
+    let config = GATEWAYS_CONFIG
+        .replace("{namerd}", "http://127.0.0.1:4180")
+        .replace("{certs}", certs_dir())
+        .replacen(
+            "certs: [",
+            "identitySource: {kind: agent, socketPath: /tmp/agent.sock}\n            certs: [",
+            1,
+        );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    assert!(config.into_app().is_err(), "accepted files and an agent");
+}
+
+/// A TLS 1.0 ClientHello without SNI or any other extension, offering six obsolete
+/// cipher suites.
+fn obsolete_client_hello() -> Vec<u8> {
+    let mut body = vec![0x03, 0x01];
+    body.extend_from_slice(&[0u8; 32]);
+    body.push(0);
+    body.extend_from_slice(&[0, 12, 0x00, 0x2f, 0x00, 0x35, 0x00, 0x0a, 0x00, 0x05, 0x00, 0x04,
+                             0x00, 0xff]);
+    body.extend_from_slice(&[1, 0]);
+
+    let mut msg = vec![1, 0, (body.len() >> 8) as u8, body.len() as u8];
+    msg.extend_from_slice(&body);
+    let mut record = vec![22, 0x03, 0x01, (msg.len() >> 8) as u8, msg.len() as u8];
+    record.extend_from_slice(&msg);
+    record
+}
+
+#[test]
+fn describes_obsolete_client_hellos() {
+    let bytes = obsolete_client_hello();
+    let hello = ClientHello::parse(&bytes).expect("hello not parsed");
+    assert_eq!(hello.versions, vec!["TLSv1.0".to_owned()]);
+    assert_eq!(hello.sni, None);
+    assert!(hello.alpn.is_empty());
+    assert_eq!(hello.cipher_count, 6);
+    assert_eq!(
+        hello.ciphers,
+        vec![
+            "TLS_RSA_WITH_AES_128_CBC_SHA",
+            "TLS_RSA_WITH_AES_256_CBC_SHA",
+            "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+            "TLS_RSA_WITH_RC4_128_SHA",
+            "TLS_RSA_WITH_RC4_128_MD5",
+        ]
+    );
+    assert_eq!(
+        format!("{}", hello),
+        "versions=[TLSv1.0] sni=- alpn=[] ciphers=6:[TLS_RSA_WITH_AES_128_CBC_SHA,\
+         TLS_RSA_WITH_AES_256_CBC_SHA,TLS_RSA_WITH_3DES_EDE_CBC_SHA,TLS_RSA_WITH_RC4_128_SHA,\
+         TLS_RSA_WITH_RC4_128_MD5,...]"
+    );
+
+    // A truncated hello is described as far as it was received.
+    let truncated = ClientHello::parse(&bytes[..5 + 4 + 2 + 32 + 1 + 2 + 4]).unwrap();
+    assert_eq!(truncated.versions, vec!["TLSv1.0".to_owned()]);
+    assert_eq!(truncated.cipher_count, 2);
+
+    assert_eq!(ClientHello::parse(b"GET / HTTP/1.1\r\n\r\n"), None);
+    assert_eq!(ClientHello::parse(&[]), None);
+}
+
+#[test]
+fn describes_rustls_client_hellos() {
+    let config = Arc::new(client_config(&["h2".to_owned()]).unwrap());
+    let mut session = rustls::ClientSession::new(&config, "a.test");
+    let mut bytes = Vec::new();
+    while session.wants_write() {
+        session.write_tls(&mut bytes).unwrap();
+    }
+
+    let hello = ClientHello::parse(&bytes).expect("hello not parsed");
+    assert_eq!(hello.sni, Some("a.test".to_owned()));
+    assert_eq!(hello.alpn, vec!["h2".to_owned()]);
+    assert!(hello.versions.contains(&"TLSv1.2".to_owned()), "{}", hello);
+    assert!(hello.cipher_count > 0);
+    assert_eq!(hello.ciphers.len(), hello.cipher_count.min(5));
+}
+
+#[test]
+fn counts_obsolete_client_hellos_as_unsupported_versions() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let proxy = h.proxy(&ALPN_CONFIG.replace("{certs}", certs_dir()));
+
+    let mut tcp = TcpStream::connect(&proxy.addr()).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    tcp.write_all(&obsolete_client_hello()).unwrap();
+    let mut rsp = Vec::new();
+    let _ = tcp.read_to_end(&mut rsp);
+    h.sleep(Duration::from_millis(100));
+
+    assert_eq!(
+        proxy.labeled_metric(
+            "tls_handshake_failures",
+            "cause=\"unsupported_version\"",
+        ),
+        1
+    );
+    assert_eq!(proxy.metric("tls_handshake_failures"), 1);
+}
+
+#[test]
+fn reads_certificates_before_changing_root_directories() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let chroot = ::std::env::temp_dir().join(format!("linkerd-tcp-chroot-{}.d", nanos));
+    ::std::fs::create_dir_all(&chroot).unwrap();
+    let config = format!(
+        "security:\n  chrootDir: {}\n{}",
+        chroot.display(),
+        GATEWAYS_CONFIG.replace("{namerd}", "http://127.0.0.1:4180").replace(
+            "{certs}",
+            certs_dir(),
+        )
+    );
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    // Identities outside of the chroot are loaded as the app is built.
+    let app = config.into_app().expect("failed to load identities");
+    assert!(app.privileges.is_some());
+    ::std::fs::remove_dir_all(&chroot).unwrap();
+}
+
+fn chain_config(key: &str, chain: &[&str], allow: bool, namerd: &str) -> AppConfig {
+    let dir = format!("{}/chain", certs_dir());
+    let chain: Vec<String> = chain.iter().map(|c| format!("{}/{}", dir, c)).collect();
+    let config = CHAIN_CONFIG
+        .replace("{namerd}", namerd)
+        .replace("{key}", &format!("{}/{}", dir, key))
+        .replace("{chain}", &chain.join(", "))
+        .replace("{allow}", &allow.to_string());
+    config.parse().expect("failed to parse config")
+}
+
+/// Echoes `msg` over a TLS connection to `addr` from a client that trusts only the
+/// chain fixtures' root.
+fn chain_roundtrip(h: &mut Harness, addr: SocketAddr, msg: &[u8]) -> io::Result<Vec<u8>> {
+    let (tx, rx) = oneshot::channel();
+    let msg = msg.to_vec();
+    thread::spawn(move || {
+        let rsp = File::open(format!("{}/chain/root.pem", certs_dir())).and_then(|root| {
+            let mut config = rustls::ClientConfig::new();
+            config.root_store.add_pem_file(&mut BufReader::new(root)).expect(
+                "invalid root certificate",
+            );
+            echo_with(&Arc::new(config), addr, "chain.test", &msg)
+        });
+        let _ = tx.send(rsp);
+    });
+    h.run(rx).expect("client thread failed")
+}
+
+#[test]
+fn orders_certificate_chains() {
+    let ordered = ["chain.test.pem", "intermediate.pem"];
+    let reversed = ["intermediate.pem", "chain.test.pem"];
+    for chain in &[ordered, reversed] {
+        let mut h = Harness::new();
+        let echo = h.echo_server();
+        h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+        let config = chain_config("chain.test.key", chain, false, &h.namerd().base_url());
+        let proxy = h.spawn(config.into_app().expect("rejected a valid chain"));
+        let rsp = chain_roundtrip(&mut h, proxy.addr(), b"ping").expect("handshake failed");
+        assert_eq!(rsp, b"ping".to_vec());
+    }
+}
+
+#[test]
+fn rejects_broken_certificate_chains() {
+    let namerd = "http://127.0.0.1:4180";
+    let missing = chain_config("chain.test.key", &["chain.test.pem", "root.pem"], false, namerd);
+    match missing.into_app() {
+        Err(Error::Config(app::Error::Server(e))) => {
+            let e = format!("{:?}", e);
+            assert!(e.contains("linkerd-tcp chain test intermediate"), "{}", e);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted a chain without its intermediate"),
+    }
+
+    // The chain must begin with the private key's certificate.
+    let chain = ["chain.test.pem", "intermediate.pem"];
+    let config = chain_config("../a.test.key", &chain, false, namerd);
+    match config.into_app() {
+        Err(Error::Config(app::Error::Server(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("accepted a certificate that does not match the private key"),
+    }
+
+    let allowed = chain_config("chain.test.key", &["chain.test.pem", "root.pem"], true, namerd);
+    assert!(allowed.into_app().is_ok(), "rejected an allowed incomplete chain");
+}
+
+#[test]
+fn skips_proxies_that_fail_to_start() {
+    let config = GATEWAYS_CONFIG.replace("{certs}", certs_dir()).replace(
+        "b.test.pem",
+        "missing.pem",
+    );
+
+    // By default, one bad proxy prevents the process from starting.
+    let abort = config.replace("{namerd}", "http://127.0.0.1:4180");
+    let abort: AppConfig = abort.parse().unwrap();
+    match abort.into_app() {
+        Err(Error::Config(app::Error::Server(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("started with a missing certificate"),
+    }
+
+    let mut h = Harness::new();
+    let proxy = h.proxy(&format!("onProxyError: skip\n{}", config));
+    assert_eq!(proxy.addrs().len(), 1);
+    assert_eq!(proxy.metric("failed_proxies"), 1);
+    let errors: serde_json::Value = serde_json::from_str(&proxy.config_errors()).unwrap();
+    let errors = errors.as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["router"], "gateways");
+    assert_eq!(errors[0]["server"], 1);
+    assert!(errors[0]["error"].as_str().unwrap().contains("missing.pem"));
+}
+
+/// Terminates TLS on a server that denies a cipher suite and on one that requires TLS
+/// 1.3.
+static TLS_POLICY_CONFIG: &'static str = "
+admin:
+  port: 0
+routers:
+  - label: policy
+    interpreter:
+      kind: io.l5d.namerd.http
+      baseUrl: {namerd}
+      namespace: default
+      periodSecs: 1
+    servers:
+      - port: 0
+        dstName: /svc/echo
+        tls:
+          tlsPolicy:
+            denyCiphers: [TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256]
+          defaultIdentity:
+            privateKey: {certs}/a.test.key
+            certs: [{certs}/a.test.pem]
+      - port: 0
+        dstName: /svc/echo
+        tls:
+          tlsPolicy:
+            minVersion: \"1.3\"
+          defaultIdentity:
+            privateKey: {certs}/a.test.key
+            certs: [{certs}/a.test.pem]
+";
+
+/// Records the summary of each closed connection.
+#[derive(Clone, Default)]
+struct Summaries(Rc<RefCell<Vec<ConnectionSummary>>>);
+
+impl ConnectionHook for Summaries {
+    fn on_close(&self, summary: &ConnectionSummary) {
+        self.0.borrow_mut().push(summary.clone());
+    }
+}
+
+/// Echoes `msg` over a TLS 1.2 connection to `addr` for `a.test`, offering only the
+/// cipher suite named `suite`.
+fn cipher_roundtrip(
+    h: &mut Harness,
+    addr: SocketAddr,
+    suite: &str,
+    msg: &[u8],
+) -> io::Result<Vec<u8>> {
+    let (tx, rx) = oneshot::channel();
+    let suite = suite.to_owned();
+    let msg = msg.to_vec();
+    thread::spawn(move || {
+        let rsp = client_config(&[]).and_then(|mut config| {
+            config.versions = vec![rustls::ProtocolVersion::TLSv1_2];
+            config.ciphersuites = rustls::ALL_CIPHERSUITES
+                .iter()
+                .cloned()
+                .filter(|s| format!("{:?}", s.suite) == suite)
+                .collect();
+            assert_eq!(config.ciphersuites.len(), 1, "unknown cipher suite {}", suite);
+            echo_with(&Arc::new(config), addr, "a.test", &msg)
+        });
+        let _ = tx.send(rsp);
+    });
+    h.run(rx).expect("client thread failed")
+}
+
+#[test]
+fn refuses_clients_denied_by_tls_policy() {
+    let mut h = Harness::new();
+    let echo = h.echo_server();
+    h.namerd().bind("/svc/echo", &[(echo.addr(), 1.0)]);
+    let config = TLS_POLICY_CONFIG
+        .replace("{namerd}", &h.namerd().base_url())
+        .replace("{certs}", certs_dir());
+    let config: AppConfig = config.parse().expect("failed to parse config");
+    let summaries = Summaries::default();
+    let app = config
+        .into_builder()
+        .connection_hook(summaries.clone())
+        .build()
+        .expect("failed to build app");
+    let proxy = h.spawn(app);
+    let (deny_cipher, min_version) = (proxy.addrs()[0], proxy.addrs()[1]);
+
+    let denied = "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256";
+    let allowed = "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384";
+    assert!(cipher_roundtrip(&mut h, deny_cipher, denied, b"ping").is_err());
+    assert!(cipher_roundtrip(&mut h, min_version, allowed, b"ping").is_err());
+    let rsp = cipher_roundtrip(&mut h, deny_cipher, allowed, b"ping").expect("allowed failed");
+    assert_eq!(rsp, b"ping".to_vec());
+    h.sleep(Duration::from_millis(100));
+
+    assert_eq!(proxy.labeled_metric("refused", "cause=\"tls_policy\""), 2);
+    assert_eq!(proxy.labeled_metric("tls_versions", "version=\"1.2\""), 3);
+    assert_eq!(proxy.labeled_metric("tls_ciphers", &format!("cipher=\"{}\"", denied)), 1);
+    assert_eq!(proxy.labeled_metric("tls_ciphers", &format!("cipher=\"{}\"", allowed)), 2);
+
+    // Refused connections fail before their properties are recorded.
+    let summaries = summaries.0.borrow();
+    assert_eq!(summaries.len(), 3);
+    let refused: Vec<_> = summaries.iter().filter(|s| s.error.is_some()).collect();
+    assert_eq!(refused.len(), 2);
+    assert!(refused.iter().all(|s| s.tls.is_none()));
+    let accepted = summaries.iter().find(|s| s.error.is_none()).expect("no accepted client");
+    let tls = accepted.tls.clone().expect("TLS properties were not recorded");
+    assert_eq!(tls.version, "1.2");
+    assert_eq!(tls.cipher_suite, allowed);
+    assert_eq!(tls.sni, Some("a.test".to_owned()));
+    assert_eq!(tls.alpn, None);
+    assert_eq!(tls.client_subject, None);
+}
+
+#[test]
+fn rejects_invalid_tls_policies() {
+    for policy in &[
+        "minVersion: \"1.4\"",
+        "minVersion: \"TLSv1.2\"",
+        "denyCiphers: [TLS_RSA_WITH_RC4_128_MD5]",
+    ]
+    {
+        let config = TLS_POLICY_CONFIG
+            .replace("{namerd}", "http://127.0.0.1:4180")
+            .replace("{certs}", certs_dir())
+            .replace("minVersion: \"1.3\"", policy);
+        let config: AppConfig = config.parse().expect("failed to parse config");
+        assert!(config.into_app().is_err(), "accepted {}", policy);
+    }
+}